@@ -0,0 +1,243 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident, LitStr};
+
+// `#[derive(FromRow)]` reads every field of a struct from the column of the
+// same name, converted as by `Row::get`. `#[derive(ToRow)]` gives the fields
+// back by column name. Both take `#[rsqlite(...)]` attributes:
+//
+// - `rename_all = "..."` on the struct names the columns after the fields in
+//   PascalCase, camelCase, lowercase, UPPERCASE, snake_case or
+//   SCREAMING_SNAKE_CASE.
+// - `rename = "..."` on a field names its column.
+// - `default` on a field uses `Default::default()` when the row has no such
+//   column.
+// - `flatten` on a field reads it as a FromRow from the same row, or gives
+//   its columns along with the others.
+// - `skip` on a field leaves it out: it is always `Default::default()`.
+#[proc_macro_derive(FromRow, attributes(rsqlite))]
+pub fn derive_from_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_row(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(ToRow, attributes(rsqlite))]
+pub fn derive_to_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    to_row(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn from_row(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    let mut initializers = Vec::new();
+    for field in fields(input)? {
+        let ident = &field.ident;
+        let column = &field.column;
+        let value = if field.skip {
+            quote!(::std::default::Default::default())
+        } else if field.flatten {
+            quote!(::rsqlite::FromRow::from_row(row)?)
+        } else if field.default {
+            quote! {
+                match row.column_index(#column) {
+                    Some(i) => row.get(i)?,
+                    None => ::std::default::Default::default(),
+                }
+            }
+        } else {
+            quote!(row.get(#column)?)
+        };
+        initializers.push(quote!(#ident: #value));
+    }
+    Ok(quote! {
+        impl #impl_generics ::rsqlite::FromRow for #name #type_generics #where_clause {
+            fn from_row(row: &::rsqlite::Row) -> ::std::result::Result<Self, ::std::io::Error> {
+                ::std::result::Result::Ok(#name {
+                    #(#initializers,)*
+                })
+            }
+        }
+    })
+}
+
+fn to_row(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    let mut pushes = Vec::new();
+    for field in fields(input)? {
+        let ident = &field.ident;
+        let column = &field.column;
+        if field.skip {
+            continue;
+        }
+        pushes.push(if field.flatten {
+            quote!(row.extend(::rsqlite::ToRow::to_row(&self.#ident));)
+        } else {
+            quote! {
+                row.push((
+                    #column,
+                    ::rsqlite::SqlValue::from(::std::clone::Clone::clone(&self.#ident)),
+                ));
+            }
+        });
+    }
+    Ok(quote! {
+        impl #impl_generics ::rsqlite::ToRow for #name #type_generics #where_clause {
+            fn to_row(&self) -> ::std::vec::Vec<(&'static str, ::rsqlite::SqlValue)> {
+                let mut row = ::std::vec::Vec::new();
+                #(#pushes)*
+                row
+            }
+        }
+    })
+}
+
+struct Field {
+    ident: Ident,
+    column: LitStr,
+    default: bool,
+    flatten: bool,
+    skip: bool,
+}
+
+// The fields of a struct with named fields and what their attributes say.
+fn fields(input: &DeriveInput) -> Result<Vec<Field>, Error> {
+    let named = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(named) => named,
+            _ => return Err(only_named_fields(input)),
+        },
+        _ => return Err(only_named_fields(input)),
+    };
+    let mut rename_all = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("rsqlite")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename_all") {
+                let case: LitStr = meta.value()?.parse()?;
+                if rename(&case.value(), "a").is_none() {
+                    return Err(Error::new(case.span(), "unknown case"));
+                }
+                rename_all = Some(case.value());
+                Ok(())
+            } else {
+                Err(meta.error("unknown rsqlite attribute"))
+            }
+        })?;
+    }
+
+    let mut fields = Vec::new();
+    for field in &named.named {
+        let ident = field.ident.clone().unwrap();
+        let name = ident.unraw().to_string();
+        let mut column = match &rename_all {
+            Some(case) => rename(case, &name).unwrap(),
+            None => name,
+        };
+        let mut default = false;
+        let mut flatten = false;
+        let mut skip = false;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("rsqlite")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    column = meta.value()?.parse::<LitStr>()?.value();
+                } else if meta.path.is_ident("default") {
+                    default = true;
+                } else if meta.path.is_ident("flatten") {
+                    flatten = true;
+                } else if meta.path.is_ident("skip") {
+                    skip = true;
+                } else {
+                    return Err(meta.error("unknown rsqlite attribute"));
+                }
+                Ok(())
+            })?;
+        }
+        fields.push(Field {
+            ident,
+            column: LitStr::new(&column, Span::call_site()),
+            default,
+            flatten,
+            skip,
+        });
+    }
+    Ok(fields)
+}
+
+fn only_named_fields(input: &DeriveInput) -> Error {
+    Error::new_spanned(
+        &input.ident,
+        "FromRow and ToRow can only be derived for structs with named fields",
+    )
+}
+
+// A snake_case field name in the given case, None for an unknown case.
+fn rename(case: &str, name: &str) -> Option<String> {
+    let words = name.split('_').filter(|w| !w.is_empty());
+    let capitalized = |word: &str| {
+        let mut chars = word.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+            None => String::new(),
+        }
+    };
+    Some(match case {
+        "PascalCase" => words.map(capitalized).collect(),
+        "camelCase" => {
+            let pascal: String = words.map(capitalized).collect();
+            let mut chars = pascal.chars();
+            match chars.next() {
+                Some(first) => first.to_lowercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        }
+        "lowercase" => name.replace('_', "").to_lowercase(),
+        "UPPERCASE" => name.replace('_', "").to_uppercase(),
+        "snake_case" => name.to_string(),
+        "SCREAMING_SNAKE_CASE" => name.to_uppercase(),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rename() {
+        let renamed: Vec<Option<String>> = [
+            "PascalCase",
+            "camelCase",
+            "lowercase",
+            "UPPERCASE",
+            "snake_case",
+            "SCREAMING_SNAKE_CASE",
+            "kebab-case",
+        ]
+        .iter()
+        .map(|case| rename(case, "unit_price"))
+        .collect();
+        let expected = [
+            Some("UnitPrice"),
+            Some("unitPrice"),
+            Some("unitprice"),
+            Some("UNITPRICE"),
+            Some("unit_price"),
+            Some("UNIT_PRICE"),
+            None,
+        ];
+        assert_eq!(
+            renamed,
+            expected
+                .iter()
+                .map(|r| r.map(String::from))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,839 @@
+use crate::expr::{
+    compare_values, evaluate_operand, Collation, Function, Functions, GroupKey, Operand, Scope,
+};
+use crate::json;
+use crate::scalar;
+use crate::value::{Affinity, SqlValue};
+use sqlparser::ast;
+use sqlparser::ast::{Expr, FunctionArg};
+use std::any::Any;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::rc::Rc;
+
+// An aggregate function defined by the application. Each group starts from
+// `init`, `step` takes in the arguments of each of its rows, and `finalize`
+// gives the result. `finalize` may be called more than once, as window
+// frames grow, so it only looks at the state.
+pub trait Aggregate {
+    type State: Clone + 'static;
+
+    fn init(&self) -> Self::State;
+
+    fn step(&self, state: &mut Self::State, args: &[SqlValue]) -> Result<(), Error>;
+
+    fn finalize(&self, state: &Self::State) -> Result<SqlValue, Error>;
+}
+
+// An `Aggregate` with its state type erased, so that aggregates of any
+// state can be registered side by side.
+pub trait AnyAggregate {
+    fn init(&self) -> Box<dyn Any>;
+
+    fn step(&self, state: &mut dyn Any, args: &[SqlValue]) -> Result<(), Error>;
+
+    fn finalize(&self, state: &dyn Any) -> Result<SqlValue, Error>;
+
+    fn clone_state(&self, state: &dyn Any) -> Box<dyn Any>;
+}
+
+impl<A: Aggregate> AnyAggregate for A {
+    fn init(&self) -> Box<dyn Any> {
+        Box::new(Aggregate::init(self))
+    }
+
+    fn step(&self, state: &mut dyn Any, args: &[SqlValue]) -> Result<(), Error> {
+        Aggregate::step(self, state.downcast_mut().unwrap(), args)
+    }
+
+    fn finalize(&self, state: &dyn Any) -> Result<SqlValue, Error> {
+        Aggregate::finalize(self, state.downcast_ref().unwrap())
+    }
+
+    fn clone_state(&self, state: &dyn Any) -> Box<dyn Any> {
+        Box::new(state.downcast_ref::<A::State>().unwrap().clone())
+    }
+}
+
+// The state of a registered aggregate in one group.
+struct Custom {
+    aggregate: Rc<dyn AnyAggregate>,
+    state: Box<dyn Any>,
+}
+
+impl Clone for Custom {
+    fn clone(&self) -> Custom {
+        Custom {
+            aggregate: self.aggregate.clone(),
+            state: self.aggregate.clone_state(self.state.as_ref()),
+        }
+    }
+}
+
+impl fmt::Debug for Custom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Custom")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    CountStar,
+    Count,
+    Sum,
+    Total,
+    Avg,
+    Min,
+    Max,
+    GroupConcat,
+    JsonGroupArray,
+    JsonGroupObject,
+    Custom,
+}
+
+fn kind_of(function: &ast::Function) -> Option<Kind> {
+    let name = function.name.to_string().to_ascii_lowercase();
+    let args = function.args.len();
+    match name.as_str() {
+        "count" if args == 0 || is_star(function) => Some(Kind::CountStar),
+        "count" => Some(Kind::Count),
+        "sum" => Some(Kind::Sum),
+        "total" => Some(Kind::Total),
+        "avg" => Some(Kind::Avg),
+        // with more than one argument these are the scalar min() and max()
+        "min" if args == 1 => Some(Kind::Min),
+        "max" if args == 1 => Some(Kind::Max),
+        "group_concat" | "string_agg" => Some(Kind::GroupConcat),
+        "json_group_array" | "jsonb_group_array" => Some(Kind::JsonGroupArray),
+        "json_group_object" | "jsonb_group_object" => Some(Kind::JsonGroupObject),
+        _ => None,
+    }
+}
+
+fn is_star(function: &ast::Function) -> bool {
+    matches!(
+        function.args.as_slice(),
+        [FunctionArg::Unnamed(Expr::Wildcard)]
+    )
+}
+
+// Whether the function is a built-in aggregate. See also
+// `Functions::is_aggregate`, which knows about registered ones.
+pub fn is_aggregate(function: &ast::Function) -> bool {
+    function.over.is_none() && kind_of(function).is_some()
+}
+
+// Whether the function is min() or max(), whose row provides the values of
+// bare columns in an aggregate query.
+pub fn is_min_or_max(function: &ast::Function) -> bool {
+    matches!(kind_of(function), Some(Kind::Min) | Some(Kind::Max))
+}
+
+pub fn is_count_star(function: &ast::Function) -> bool {
+    kind_of(function) == Some(Kind::CountStar) && !function.distinct
+}
+
+// Whether the function builds JSON text, which has SQLite's JSON subtype.
+pub fn is_json(function: &ast::Function) -> bool {
+    matches!(
+        kind_of(function),
+        Some(Kind::JsonGroupArray) | Some(Kind::JsonGroupObject)
+    )
+}
+
+// Evaluates the arguments of an aggregate function for one row.
+pub fn arguments(function: &ast::Function, scope: &dyn Scope) -> Result<Vec<SqlValue>, Error> {
+    if is_count_star(function) {
+        return Ok(Vec::new());
+    }
+    let mut args = Vec::with_capacity(function.args.len());
+    for arg in &function.args {
+        args.push(match arg {
+            FunctionArg::Unnamed(arg) | FunctionArg::Named { arg, .. } => {
+                evaluate_operand(arg, scope)?
+            }
+        });
+    }
+    Ok(argument_values(function, args))
+}
+
+// The values an aggregate function steps with, from its evaluated
+// arguments. JSON text from a JSON function is passed to the JSON
+// aggregates as JSONB, so that they embed it rather than quote it.
+pub fn argument_values(function: &ast::Function, args: Vec<Operand>) -> Vec<SqlValue> {
+    let value = match kind_of(function) {
+        Some(Kind::JsonGroupArray) => Some(0),
+        Some(Kind::JsonGroupObject) => Some(1),
+        _ => None,
+    };
+    args.into_iter()
+        .enumerate()
+        .map(|(i, operand)| {
+            if value == Some(i) {
+                json::embed(operand)
+            } else {
+                operand.value
+            }
+        })
+        .collect()
+}
+
+// The running state of one aggregate function over the rows of a group.
+#[derive(Debug, Clone)]
+pub struct Accumulator {
+    kind: Kind,
+    collation: Collation,
+    // Values seen so far by a DISTINCT aggregate, told apart by the
+    // collation of the argument, which the keys share.
+    seen: Option<(Rc<[Collation]>, BTreeSet<GroupKey>)>,
+    count: i64,
+    // Sums stay exact integers until a non-integer value shows up or they
+    // overflow, and then continue as Kahan-Babuska-Neumaier sums of reals.
+    integer_sum: i64,
+    real_sum: f64,
+    real_error: f64,
+    approximate: bool,
+    overflow: bool,
+    extreme: Option<SqlValue>,
+    text: Option<String>,
+    // The lengths of the separator and value each group_concat() value
+    // added to `text`, so that `inverse` can take them out again.
+    lengths: VecDeque<(usize, usize)>,
+    // The JSON text built by json_group_array() and json_group_object(),
+    // returned as JSONB by their jsonb_ variants, and the first error.
+    json: Option<Vec<u8>>,
+    binary: bool,
+    error: Option<String>,
+    custom: Option<Custom>,
+}
+
+impl Accumulator {
+    // `collation` orders the values of min() and max(), and decides which
+    // values DISTINCT takes for the same. Aggregates the
+    // application registered in `functions` take the place of built-in ones.
+    pub fn new(
+        function: &ast::Function,
+        collation: Collation,
+        functions: &Functions,
+    ) -> Result<Accumulator, Error> {
+        let name = function.name.to_string().to_ascii_lowercase();
+        let args = function.args.len();
+        let custom = match functions.get(&name, args) {
+            Some(Function::Aggregate(aggregate, _)) => Some(aggregate.clone()),
+            _ => None,
+        };
+        let kind = match (&custom, kind_of(function)) {
+            (Some(_), _) => Kind::Custom,
+            (None, Some(kind)) => kind,
+            (None, None) if functions.contains(&name) => {
+                return Err(scalar::wrong_number_of_arguments(&name))
+            }
+            (None, None) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("no such aggregate function: {}", function.name),
+                ))
+            }
+        };
+        let valid = match kind {
+            Kind::CountStar => !function.distinct,
+            Kind::GroupConcat => args == 1 || args == 2,
+            Kind::JsonGroupObject => args == 2,
+            Kind::Custom => true,
+            _ => args == 1,
+        };
+        if !valid {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("wrong number of arguments to function {}()", function.name),
+            ));
+        }
+        if function.distinct && args != 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "DISTINCT aggregates must have exactly one argument",
+            ));
+        }
+        let seen = if function.distinct {
+            Some((Rc::from(vec![collation.clone()]), BTreeSet::new()))
+        } else {
+            None
+        };
+        Ok(Accumulator {
+            kind,
+            collation,
+            seen,
+            count: 0,
+            integer_sum: 0,
+            real_sum: 0.0,
+            real_error: 0.0,
+            approximate: false,
+            overflow: false,
+            extreme: None,
+            text: None,
+            lengths: VecDeque::new(),
+            json: None,
+            binary: name.starts_with("jsonb"),
+            error: None,
+            custom: custom.map(|aggregate| Custom {
+                state: aggregate.init(),
+                aggregate,
+            }),
+        })
+    }
+
+    // Counts rows without looking at them, for count(*).
+    pub fn add_rows(&mut self, rows: i64) {
+        debug_assert_eq!(self.kind, Kind::CountStar);
+        self.count += rows;
+    }
+
+    // Feeds the arguments of one row. Returns whether min() or max() took
+    // its new value from this row.
+    pub fn step(&mut self, args: &[SqlValue]) -> bool {
+        if self.kind == Kind::CountStar {
+            self.count += 1;
+            return false;
+        }
+        if self.kind == Kind::Custom {
+            self.step_custom(args);
+            return false;
+        }
+        let value = &args[0];
+        if matches!(self.kind, Kind::JsonGroupArray | Kind::JsonGroupObject) {
+            // NULLs are JSON values, and so count for DISTINCT too
+            if !self.first_sight(value) {
+                return false;
+            }
+            self.step_json(args);
+            return false;
+        }
+        if value.is_null() {
+            return false;
+        }
+        if !self.first_sight(value) {
+            return false;
+        }
+        self.count += 1;
+        match self.kind {
+            Kind::Sum | Kind::Total | Kind::Avg => self.add(value),
+            Kind::Min | Kind::Max => {
+                let replace = match &self.extreme {
+                    None => true,
+                    Some(extreme) => {
+                        let ordering = compare_values(value, extreme, &self.collation);
+                        if self.kind == Kind::Min {
+                            ordering == Ordering::Less
+                        } else {
+                            ordering == Ordering::Greater
+                        }
+                    }
+                };
+                if replace {
+                    self.extreme = Some(value.clone());
+                }
+                return replace;
+            }
+            Kind::GroupConcat => {
+                let separator = match args.get(1) {
+                    Some(separator) => separator.to_text().unwrap_or_default(),
+                    None => ",".to_string(),
+                };
+                let value = value.to_text().unwrap();
+                match &mut self.text {
+                    Some(text) => {
+                        text.push_str(&separator);
+                        text.push_str(&value);
+                        self.lengths.push_back((separator.len(), value.len()));
+                    }
+                    None => {
+                        self.lengths.push_back((0, value.len()));
+                        self.text = Some(value);
+                    }
+                }
+            }
+            _ => (),
+        }
+        false
+    }
+
+    // Whether a DISTINCT aggregate has not seen `value` before. Without
+    // DISTINCT every value counts.
+    fn first_sight(&mut self, value: &SqlValue) -> bool {
+        match &mut self.seen {
+            Some((collation, seen)) => {
+                seen.insert(GroupKey::new(vec![value.clone()], collation.clone()))
+            }
+            None => true,
+        }
+    }
+
+    // Registered aggregates see NULLs too. After an error the remaining rows
+    // are skipped, and the error is the result.
+    fn step_custom(&mut self, args: &[SqlValue]) {
+        if self.error.is_some() {
+            return;
+        }
+        if !self.first_sight(&args[0]) {
+            return;
+        }
+        let custom = self.custom.as_mut().unwrap();
+        if let Err(e) = custom.aggregate.step(custom.state.as_mut(), args) {
+            self.error = Some(e.to_string());
+        }
+    }
+
+    fn step_json(&mut self, args: &[SqlValue]) {
+        let object = self.kind == Kind::JsonGroupObject;
+        let out = self
+            .json
+            .get_or_insert_with(|| vec![if object { b'{' } else { b'[' }]);
+        let value = if object {
+            let label = match args[0].to_text() {
+                Some(label) => label,
+                None => return,
+            };
+            let label = label.split('\0').next().unwrap_or_default();
+            if out.len() > 1 {
+                out.push(b',');
+            }
+            json::append_string(out, label.as_bytes());
+            out.push(b':');
+            &args[1]
+        } else {
+            if out.len() > 1 {
+                out.push(b',');
+            }
+            &args[0]
+        };
+        if let Err(e) = json::append_value(out, value, false) {
+            self.error.get_or_insert_with(|| e.to_string());
+        }
+    }
+
+    // Whether `inverse` can take rows out again. min() and max() would have
+    // to remember every value for that.
+    pub fn can_inverse(&self) -> bool {
+        !matches!(self.kind, Kind::Min | Kind::Max | Kind::Custom) && self.seen.is_none()
+    }
+
+    // Takes out the arguments of the oldest row fed to `step`, for window
+    // frames whose start moves on. As in SQLite, a sum that has become
+    // approximate stays that way.
+    pub fn inverse(&mut self, args: &[SqlValue]) {
+        debug_assert!(self.can_inverse());
+        if self.kind == Kind::CountStar {
+            self.count -= 1;
+            return;
+        }
+        let value = &args[0];
+        if value.is_null() && !matches!(self.kind, Kind::JsonGroupArray | Kind::JsonGroupObject) {
+            return;
+        }
+        self.count -= 1;
+        match self.kind {
+            Kind::Sum | Kind::Total | Kind::Avg => self.subtract(value),
+            // as in SQLite, the first element goes, whatever the row was
+            Kind::JsonGroupArray | Kind::JsonGroupObject => {
+                let out = match &mut self.json {
+                    Some(out) => out,
+                    None => return,
+                };
+                let (mut in_string, mut nesting) = (false, 0);
+                let mut i = 1;
+                while i < out.len() && (out[i] != b',' || in_string || nesting > 0) {
+                    match out[i] {
+                        b'"' => in_string = !in_string,
+                        b'\\' => i += 1,
+                        b'{' | b'[' if !in_string => nesting += 1,
+                        b'}' | b']' if !in_string => nesting -= 1,
+                        _ => (),
+                    }
+                    i += 1;
+                }
+                out.drain(1..(i + 1).min(out.len()));
+            }
+            Kind::GroupConcat => {
+                let (_, first) = self.lengths.pop_front().unwrap_or_default();
+                let mut removed = first;
+                if let Some((separator, _)) = self.lengths.front_mut() {
+                    removed += *separator;
+                    *separator = 0;
+                }
+                if self.lengths.is_empty() {
+                    self.text = None;
+                } else if let Some(text) = &mut self.text {
+                    text.drain(..removed);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn subtract(&mut self, value: &SqlValue) {
+        match value.clone().apply_affinity(Affinity::Numeric) {
+            SqlValue::Integer(i) if !self.approximate => match self.integer_sum.checked_sub(i) {
+                Some(sum) => self.integer_sum = sum,
+                None => {
+                    self.overflow = true;
+                    self.start_approximating();
+                    self.subtract_integer(i);
+                }
+            },
+            SqlValue::Integer(i) => self.subtract_integer(i),
+            v => {
+                if !self.approximate {
+                    self.start_approximating();
+                }
+                self.add_real(-v.to_f64().unwrap_or(0.0));
+            }
+        }
+    }
+
+    fn subtract_integer(&mut self, i: i64) {
+        match i.checked_neg() {
+            Some(i) => self.add_integer(i),
+            None => {
+                self.add_integer(i64::MAX);
+                self.add_integer(1);
+            }
+        }
+    }
+
+    fn add(&mut self, value: &SqlValue) {
+        match value.clone().apply_affinity(Affinity::Numeric) {
+            SqlValue::Integer(i) if !self.approximate => match self.integer_sum.checked_add(i) {
+                Some(sum) => self.integer_sum = sum,
+                None => {
+                    self.overflow = true;
+                    self.start_approximating();
+                    self.add_integer(i);
+                }
+            },
+            SqlValue::Integer(i) => self.add_integer(i),
+            v => {
+                if !self.approximate {
+                    self.start_approximating();
+                }
+                self.add_real(v.to_f64().unwrap_or(0.0));
+            }
+        }
+    }
+
+    fn start_approximating(&mut self) {
+        self.approximate = true;
+        self.real_sum = 0.0;
+        self.real_error = 0.0;
+        self.add_integer(self.integer_sum);
+    }
+
+    // Large integers are added in two parts so no precision is lost.
+    fn add_integer(&mut self, i: i64) {
+        if !(-4503599627370496..4503599627370496).contains(&i) {
+            let small = i % 16384;
+            self.add_real((i - small) as f64);
+            self.add_real(small as f64);
+        } else {
+            self.add_real(i as f64);
+        }
+    }
+
+    fn add_real(&mut self, r: f64) {
+        let sum = self.real_sum;
+        let t = sum + r;
+        if sum.abs() > r.abs() {
+            self.real_error += (sum - t) + r;
+        } else {
+            self.real_error += (r - t) + sum;
+        }
+        self.real_sum = t;
+    }
+
+    fn real_total(&self) -> f64 {
+        if self.approximate {
+            self.real_sum + self.real_error
+        } else {
+            self.integer_sum as f64
+        }
+    }
+
+    pub fn finish(&self) -> Result<SqlValue, Error> {
+        Ok(match self.kind {
+            Kind::CountStar | Kind::Count => SqlValue::Integer(self.count),
+            Kind::Sum if self.count == 0 => SqlValue::Null,
+            Kind::Sum if self.overflow => {
+                return Err(Error::new(ErrorKind::InvalidData, "integer overflow"))
+            }
+            Kind::Sum if self.approximate => SqlValue::Real(self.real_total()),
+            Kind::Sum => SqlValue::Integer(self.integer_sum),
+            Kind::Total => SqlValue::Real(self.real_total()),
+            Kind::Avg if self.count == 0 => SqlValue::Null,
+            Kind::Avg => SqlValue::Real(self.real_total() / self.count as f64),
+            Kind::Min | Kind::Max => self.extreme.clone().unwrap_or(SqlValue::Null),
+            Kind::GroupConcat => self
+                .text
+                .clone()
+                .map(SqlValue::Text)
+                .unwrap_or(SqlValue::Null),
+            Kind::Custom => {
+                if let Some(error) = &self.error {
+                    return Err(Error::new(ErrorKind::InvalidInput, error.clone()));
+                }
+                let custom = self.custom.as_ref().unwrap();
+                custom.aggregate.finalize(custom.state.as_ref())?
+            }
+            Kind::JsonGroupArray | Kind::JsonGroupObject => {
+                if let Some(error) = &self.error {
+                    return Err(Error::new(ErrorKind::InvalidInput, error.clone()));
+                }
+                let object = self.kind == Kind::JsonGroupObject;
+                match &self.json {
+                    // even the jsonb_ variants return text when there were
+                    // no rows
+                    None => SqlValue::from(if object { "{}" } else { "[]" }),
+                    Some(json) => {
+                        let mut json = json.clone();
+                        json.push(if object { b'}' } else { b']' });
+                        json::text_result(json, self.binary)?.0
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser;
+    use sqlparser::ast::{SelectItem, SetExpr, Statement};
+
+    fn function(sql: &str) -> ast::Function {
+        let statement = &sql_parser::parse_sql(&format!("SELECT {}", sql)).unwrap()[0];
+        match statement {
+            Statement::Query(query) => match &query.body {
+                SetExpr::Select(select) => match &select.projection[0] {
+                    SelectItem::UnnamedExpr(Expr::Function(f)) => f.clone(),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn aggregate(sql: &str, values: &[SqlValue]) -> Result<SqlValue, Error> {
+        let function = function(sql);
+        let mut accumulator = Accumulator::new(&function, Collation::Binary, &Functions::new())?;
+        for value in values {
+            let args = [value.clone(), SqlValue::from(";")];
+            accumulator.step(&args[..function.args.len().max(1)]);
+        }
+        accumulator.finish()
+    }
+
+    #[test]
+    fn test_sum_total_avg() {
+        let values = vec![
+            SqlValue::Integer(1),
+            SqlValue::Real(2.5),
+            SqlValue::from("3"),
+            SqlValue::from("a"),
+            SqlValue::Null,
+        ];
+        assert_eq!(aggregate("sum(x)", &values).unwrap(), SqlValue::Real(6.5));
+        assert_eq!(aggregate("avg(x)", &values).unwrap(), SqlValue::Real(1.625));
+        assert_eq!(
+            aggregate("count(x)", &values).unwrap(),
+            SqlValue::Integer(4)
+        );
+        assert_eq!(
+            aggregate("count(*)", &values).unwrap(),
+            SqlValue::Integer(5)
+        );
+
+        let integers = vec![SqlValue::from("3"), SqlValue::Integer(4)];
+        assert!(aggregate("sum(x)", &integers).unwrap().is_integer());
+
+        assert!(aggregate("sum(x)", &[]).unwrap().is_null());
+        assert!(aggregate("avg(x)", &[SqlValue::Null]).unwrap().is_null());
+        assert_eq!(aggregate("total(x)", &[]).unwrap(), SqlValue::Real(0.0));
+    }
+
+    #[test]
+    fn test_sum_overflow() {
+        let values = vec![SqlValue::Integer(i64::MAX), SqlValue::Integer(1)];
+        assert!(aggregate("sum(x)", &values).is_err());
+        assert_eq!(
+            aggregate("total(x)", &values).unwrap(),
+            SqlValue::Real(9223372036854775808.0)
+        );
+        let values = vec![SqlValue::Integer(i64::MAX), SqlValue::Real(1.0)];
+        assert_eq!(
+            aggregate("sum(x)", &values).unwrap(),
+            SqlValue::Real(9223372036854775808.0)
+        );
+    }
+
+    #[test]
+    fn test_distinct_min_max_group_concat() {
+        let values = vec![
+            SqlValue::Integer(2),
+            SqlValue::Integer(1),
+            SqlValue::Null,
+            SqlValue::Real(1.0),
+            SqlValue::Integer(3),
+        ];
+        assert_eq!(
+            aggregate("count(DISTINCT x)", &values).unwrap(),
+            SqlValue::Integer(3)
+        );
+        assert_eq!(
+            aggregate("sum(DISTINCT x)", &values).unwrap(),
+            SqlValue::Integer(6)
+        );
+        assert_eq!(aggregate("min(x)", &values).unwrap(), SqlValue::Integer(1));
+        assert_eq!(aggregate("max(x)", &values).unwrap(), SqlValue::Integer(3));
+        assert_eq!(
+            aggregate("group_concat(x)", &values).unwrap(),
+            SqlValue::from("2,1,1.0,3")
+        );
+        assert_eq!(
+            aggregate("group_concat(x, ';')", &values).unwrap(),
+            SqlValue::from("2;1;1.0;3")
+        );
+        assert!(aggregate("group_concat(x)", &[SqlValue::Null])
+            .unwrap()
+            .is_null());
+        assert!(
+            Accumulator::new(&function("sum(x, y)"), Collation::Binary, &Functions::new()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_distinct_with_collation() {
+        let values: Vec<SqlValue> = ["a", "A", "b ", "b", "B"]
+            .iter()
+            .map(|s| SqlValue::from(*s))
+            .collect();
+        let count = |collation: Collation| {
+            let mut count =
+                Accumulator::new(&function("count(DISTINCT x)"), collation, &Functions::new())
+                    .unwrap();
+            for value in &values {
+                count.step(std::slice::from_ref(value));
+            }
+            count.finish().unwrap()
+        };
+        assert_eq!(count(Collation::Binary), SqlValue::Integer(5));
+        assert_eq!(count(Collation::NoCase), SqlValue::Integer(3));
+        assert_eq!(count(Collation::RTrim), SqlValue::Integer(4));
+
+        // the first of the values taken for the same is the one kept
+        let mut concat = Accumulator::new(
+            &function("group_concat(DISTINCT x)"),
+            Collation::NoCase,
+            &Functions::new(),
+        )
+        .unwrap();
+        for value in &values {
+            concat.step(std::slice::from_ref(value));
+        }
+        assert_eq!(concat.finish().unwrap(), SqlValue::from("a,b ,b"));
+    }
+
+    #[test]
+    fn test_inverse() {
+        let mut sum =
+            Accumulator::new(&function("sum(x)"), Collation::Binary, &Functions::new()).unwrap();
+        let mut concat = Accumulator::new(
+            &function("group_concat(x, y)"),
+            Collation::Binary,
+            &Functions::new(),
+        )
+        .unwrap();
+        for (value, separator) in &[(1, "-"), (2, "+"), (3, "*")] {
+            sum.step(&[SqlValue::Integer(*value)]);
+            concat.step(&[SqlValue::Integer(*value), SqlValue::from(*separator)]);
+        }
+        sum.inverse(&[SqlValue::Integer(1)]);
+        concat.inverse(&[SqlValue::Integer(1), SqlValue::from("-")]);
+        assert_eq!(sum.finish().unwrap(), SqlValue::Integer(5));
+        // the separator before the next value goes with the first one
+        assert_eq!(concat.finish().unwrap(), SqlValue::from("2*3"));
+
+        // an overflow stays one after its values are taken out
+        let mut sum =
+            Accumulator::new(&function("sum(x)"), Collation::Binary, &Functions::new()).unwrap();
+        sum.step(&[SqlValue::Integer(i64::MAX)]);
+        sum.step(&[SqlValue::Integer(1)]);
+        sum.inverse(&[SqlValue::Integer(i64::MAX)]);
+        assert!(sum.finish().is_err());
+
+        assert!(
+            !Accumulator::new(&function("max(x)"), Collation::Binary, &Functions::new())
+                .unwrap()
+                .can_inverse()
+        );
+    }
+
+    // The product of the non-NULL values, and an error for a zero.
+    struct Product;
+
+    impl Aggregate for Product {
+        type State = Option<i64>;
+
+        fn init(&self) -> Option<i64> {
+            None
+        }
+
+        fn step(&self, state: &mut Option<i64>, args: &[SqlValue]) -> Result<(), Error> {
+            match args[0].as_i64() {
+                Some(0) => Err(Error::new(ErrorKind::InvalidInput, "zero factor")),
+                Some(i) => {
+                    *state = Some(state.unwrap_or(1) * i);
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        }
+
+        fn finalize(&self, state: &Option<i64>) -> Result<SqlValue, Error> {
+            Ok(state.map_or(SqlValue::Null, SqlValue::Integer))
+        }
+    }
+
+    #[test]
+    fn test_registered_aggregate() {
+        let mut functions = Functions::new();
+        functions.register_aggregate("product", Some(1), Default::default(), Product);
+        let new = |sql: &str| Accumulator::new(&function(sql), Collation::Binary, &functions);
+
+        let mut product = new("product(x)").unwrap();
+        assert_eq!(product.finish().unwrap(), SqlValue::Null);
+        let mut copy = product.clone();
+        for value in [SqlValue::Integer(2), SqlValue::Null, SqlValue::Integer(3)] {
+            product.step(&[value]);
+        }
+        copy.step(&[SqlValue::Integer(5)]);
+        assert_eq!(product.finish().unwrap(), SqlValue::Integer(6));
+        assert_eq!(copy.finish().unwrap(), SqlValue::Integer(5));
+        assert!(!product.can_inverse());
+
+        let mut distinct = new("product(DISTINCT x)").unwrap();
+        for i in [2, 2, 3] {
+            distinct.step(&[SqlValue::Integer(i)]);
+        }
+        assert_eq!(distinct.finish().unwrap(), SqlValue::Integer(6));
+
+        product.step(&[SqlValue::Integer(0)]);
+        assert_eq!(product.finish().unwrap_err().to_string(), "zero factor");
+
+        assert_eq!(
+            new("product(x, y)").unwrap_err().to_string(),
+            "wrong number of arguments to function product()"
+        );
+    }
+}
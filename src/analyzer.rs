@@ -0,0 +1,348 @@
+use crate::btree::{child_page, BTreeCursor};
+use crate::db_page::DBPageType;
+use crate::pager::{Freelist, Pager};
+use crate::util;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+// What a page of a database file is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageKind {
+    TableInterior,
+    TableLeaf,
+    IndexInterior,
+    IndexLeaf,
+    Overflow,
+    FreelistTrunk,
+    FreelistLeaf,
+    PointerMap,
+    LockByte,
+    // Neither in a b-tree nor on the freelist, so lost to the file until
+    // it is vacuumed.
+    Unreachable,
+}
+
+impl PageKind {
+    pub const ALL: [PageKind; 10] = [
+        PageKind::TableInterior,
+        PageKind::TableLeaf,
+        PageKind::IndexInterior,
+        PageKind::IndexLeaf,
+        PageKind::Overflow,
+        PageKind::FreelistTrunk,
+        PageKind::FreelistLeaf,
+        PageKind::PointerMap,
+        PageKind::LockByte,
+        PageKind::Unreachable,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PageKind::TableInterior => "table interior",
+            PageKind::TableLeaf => "table leaf",
+            PageKind::IndexInterior => "index interior",
+            PageKind::IndexLeaf => "index leaf",
+            PageKind::Overflow => "overflow",
+            PageKind::FreelistTrunk => "freelist trunk",
+            PageKind::FreelistLeaf => "freelist leaf",
+            PageKind::PointerMap => "pointer-map",
+            PageKind::LockByte => "lock-byte",
+            PageKind::Unreachable => "unreachable",
+        }
+    }
+}
+
+// A page of the file, and the b-tree it belongs to as an index into
+// `PageUsage::trees`.
+#[derive(Debug, Clone, Copy)]
+pub struct Page {
+    pub kind: PageKind,
+    pub tree: Option<usize>,
+}
+
+// How a table or index b-tree, with the overflow pages of its cells, uses
+// its pages.
+#[derive(Debug, Clone)]
+pub struct TreeUsage {
+    pub name: String,
+    pub table_name: String,
+    pub is_index: bool,
+    pub root_page: u32,
+    pub entries: u64,
+    pub interior_pages: u64,
+    pub leaf_pages: u64,
+    pub overflow_pages: u64,
+    pub payload_bytes: u64,
+    // Bytes of the pages no cell uses, including the unused ends of the
+    // last overflow page of each cell.
+    pub unused_bytes: u64,
+    // Leaves that do not directly follow the leaf before them in the file,
+    // taking the leaves in key order.
+    pub gaps: u64,
+    usable_size: u64,
+}
+
+impl TreeUsage {
+    pub fn pages(&self) -> u64 {
+        self.interior_pages + self.leaf_pages + self.overflow_pages
+    }
+
+    // The share of the bytes of its pages the tree uses.
+    pub fn fill_factor(&self) -> f64 {
+        let total = self.pages() * self.usable_size;
+        if total == 0 {
+            return 0.0;
+        }
+        1.0 - self.unused_bytes as f64 / total as f64
+    }
+
+    // The share of the leaves a scan has to jump to rather than read next.
+    pub fn fragmentation(&self) -> f64 {
+        if self.leaf_pages < 2 {
+            return 0.0;
+        }
+        self.gaps as f64 / (self.leaf_pages - 1) as f64
+    }
+}
+
+// What every page of a database file is used for, in the manner of
+// sqlite3_analyzer.
+#[derive(Debug, Clone)]
+pub struct PageUsage {
+    pub page_size: usize,
+    pub usable_size: usize,
+    // The pages of the file, the first being page 1.
+    pub pages: Vec<Page>,
+    // The b-trees in the order of sqlite_master, which comes first.
+    pub trees: Vec<TreeUsage>,
+    pub freelist: Freelist,
+}
+
+impl PageUsage {
+    pub fn page(&self, page_no: u32) -> Option<Page> {
+        let i = (page_no as usize).checked_sub(1)?;
+        self.pages.get(i).copied()
+    }
+
+    pub fn count(&self, kind: PageKind) -> usize {
+        self.pages.iter().filter(|p| p.kind == kind).count()
+    }
+}
+
+// Classifies every page of the file, walking the freelist and every table
+// and index b-tree sqlite_master names.
+pub fn page_usage(pager: &Pager) -> Result<PageUsage, Error> {
+    let unreachable = Page {
+        kind: PageKind::Unreachable,
+        tree: None,
+    };
+    let mut pages = vec![unreachable; pager.page_count() as usize];
+    if pager.lock_byte_page() <= pager.page_count() {
+        claim(&mut pages, pager.lock_byte_page(), PageKind::LockByte, None)?;
+    }
+    if pager.is_auto_vacuum()? {
+        for page_no in 2..=pager.page_count() {
+            if pager.is_pointer_map_page(page_no) {
+                claim(&mut pages, page_no, PageKind::PointerMap, None)?;
+            }
+        }
+    }
+    let freelist = pager.freelist()?;
+    for page_no in &freelist.trunks {
+        claim(&mut pages, *page_no, PageKind::FreelistTrunk, None)?;
+    }
+    for page_no in &freelist.leaves {
+        claim(&mut pages, *page_no, PageKind::FreelistLeaf, None)?;
+    }
+
+    let mut trees = vec![new_tree("sqlite_master", "sqlite_master", false, 1, pager)];
+    for entry in BTreeCursor::new(pager, 1) {
+        let entry = entry?;
+        let values = entry.record.values();
+        let text = |i: usize| values.get(i).and_then(|v| v.as_str()).unwrap_or("");
+        let root_page = values.get(3).and_then(|v| v.as_i64()).unwrap_or(0);
+        let kind = text(0);
+        if root_page > 0 && (kind == "table" || kind == "index") {
+            trees.push(new_tree(
+                text(1),
+                text(2),
+                kind == "index",
+                root_page as u32,
+                pager,
+            ));
+        }
+    }
+    for (i, tree) in trees.iter_mut().enumerate() {
+        measure_tree(pager, tree, i, &mut pages)?;
+    }
+    Ok(PageUsage {
+        page_size: pager.page_size(),
+        usable_size: pager.usable_size(),
+        pages,
+        trees,
+        freelist,
+    })
+}
+
+fn new_tree(
+    name: &str,
+    table_name: &str,
+    is_index: bool,
+    root_page: u32,
+    pager: &Pager,
+) -> TreeUsage {
+    TreeUsage {
+        name: name.to_string(),
+        table_name: table_name.to_string(),
+        is_index,
+        root_page,
+        entries: 0,
+        interior_pages: 0,
+        leaf_pages: 0,
+        overflow_pages: 0,
+        payload_bytes: 0,
+        unused_bytes: 0,
+        gaps: 0,
+        usable_size: pager.usable_size() as u64,
+    }
+}
+
+// Records what a page is used for. A page used twice means the file is
+// corrupt, and would make a walk of a b-tree go round in circles.
+fn claim(
+    pages: &mut [Page],
+    page_no: u32,
+    kind: PageKind,
+    tree: Option<usize>,
+) -> Result<(), Error> {
+    let page = match (page_no as usize)
+        .checked_sub(1)
+        .and_then(|i| pages.get_mut(i))
+    {
+        Some(page) => page,
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("page {} is out of range", page_no),
+            ))
+        }
+    };
+    if page.kind != PageKind::Unreachable {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "page {} is used as both {} and {}",
+                page_no,
+                page.kind.name(),
+                kind.name()
+            ),
+        ));
+    }
+    *page = Page { kind, tree };
+    Ok(())
+}
+
+fn measure_tree(
+    pager: &Pager,
+    tree: &mut TreeUsage,
+    index: usize,
+    pages: &mut [Page],
+) -> Result<(), Error> {
+    let usable_size = pager.usable_size();
+    let mut last_leaf = None;
+    // children go on the stack last first, so leaves come in key order
+    let mut stack = vec![tree.root_page];
+    while let Some(page_no) = stack.pop() {
+        let page = pager.get_page(page_no)?;
+        let kind = match page.page_type {
+            DBPageType::TableInteriorPage => PageKind::TableInterior,
+            DBPageType::TableLeafPage => PageKind::TableLeaf,
+            DBPageType::IndexInteriorPage => PageKind::IndexInterior,
+            DBPageType::IndexLeafPage => PageKind::IndexLeaf,
+        };
+        claim(pages, page_no, kind, Some(index))?;
+        tree.unused_bytes += page.unused_bytes() as u64;
+        if page.page_type.is_leaf() {
+            tree.leaf_pages += 1;
+            if last_leaf.is_some_and(|last| last + 1 != page_no) {
+                tree.gaps += 1;
+            }
+            last_leaf = Some(page_no);
+        } else {
+            tree.interior_pages += 1;
+        }
+        // interior cells of table b-trees only hold keys for finding rows
+        if page.page_type.is_leaf() || page.page_type.is_index() {
+            tree.entries += u64::from(page.number_of_cells);
+        }
+
+        for idx in 0..page.number_of_cells {
+            let layout = page.get_cell_layout(idx, usable_size);
+            tree.payload_bytes += layout.payload_size as u64;
+            let mut remaining = layout.payload_size - layout.local_size;
+            let mut overflow = layout.first_overflow_page;
+            while let Some(overflow_page) = overflow {
+                if remaining == 0 {
+                    break;
+                }
+                claim(pages, overflow_page, PageKind::Overflow, Some(index))?;
+                tree.overflow_pages += 1;
+                let stored = remaining.min(usable_size - 4);
+                tree.unused_bytes += (usable_size - 4 - stored) as u64;
+                remaining -= stored;
+                let raw = pager.read_raw_page(overflow_page)?;
+                overflow = match util::as_u32_be(&raw[0..4].try_into().unwrap()) {
+                    0 => None,
+                    n => Some(n),
+                };
+            }
+        }
+        if !page.page_type.is_leaf() {
+            for idx in (0..=page.number_of_cells).rev() {
+                stack.push(child_page(&page, idx, usable_size));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn line(f: &mut fmt::Formatter, label: &str, value: impl fmt::Display) -> fmt::Result {
+    writeln!(f, "{:.<40} {}", format!("{} ", label), value)
+}
+
+fn percent(share: f64) -> String {
+    format!("{:.1}%", share * 100.0)
+}
+
+// The report `rsqlite-run analyze` prints: the pages of the file by kind,
+// then the use each b-tree makes of its pages.
+impl fmt::Display for PageUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        line(f, "Page size", self.page_size)?;
+        line(f, "Usable bytes per page", self.usable_size)?;
+        line(f, "Pages in the file", self.pages.len())?;
+        for kind in &PageKind::ALL {
+            let name = kind.name();
+            let label = format!("{}{} pages", name[..1].to_uppercase(), &name[1..]);
+            line(f, &label, self.count(*kind))?;
+        }
+        for tree in &self.trees {
+            writeln!(f)?;
+            if tree.is_index {
+                writeln!(f, "*** {} (index on {}) ***", tree.name, tree.table_name)?;
+            } else {
+                writeln!(f, "*** {} (table) ***", tree.name)?;
+            }
+            line(f, "Entries", tree.entries)?;
+            line(f, "Interior pages", tree.interior_pages)?;
+            line(f, "Leaf pages", tree.leaf_pages)?;
+            line(f, "Overflow pages", tree.overflow_pages)?;
+            line(f, "Payload bytes", tree.payload_bytes)?;
+            line(f, "Unused bytes", tree.unused_bytes)?;
+            line(f, "Fill factor", percent(tree.fill_factor()))?;
+            line(f, "Fragmentation", percent(tree.fragmentation()))?;
+        }
+        Ok(())
+    }
+}
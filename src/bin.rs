@@ -1,3 +1,33 @@
+use rsqlite::analyzer;
+use rsqlite::pager::Pager;
+use std::env;
+use std::io::{self, Error, ErrorKind, Write};
+use std::process;
+
+const USAGE: &str = "usage: rsqlite-run analyze DATABASE";
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.as_slice() {
+        [command, path] if command == "analyze" => analyze(path),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("rsqlite-run: {}", e);
+        process::exit(1);
+    }
+}
+
+// Prints what the pages of a database file are used for.
+fn analyze(path: &str) -> Result<(), Error> {
+    let pager = Pager::open(path)?;
+    let report = analyzer::page_usage(&pager)?.to_string();
+    match io::stdout().write_all(report.as_bytes()) {
+        // the reader stopped early, as `head` does
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
 }
@@ -0,0 +1,622 @@
+use crate::db_page::{local_payload_size, DBPage, DBPageType};
+use crate::pager::{Pager, Transaction};
+use crate::record::{LazyRecord, Record};
+use crate::value::{SqlValue, Value};
+use crate::varint;
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+use std::rc::Rc;
+
+// One entry of a b-tree. Table b-trees carry a rowid next to the record,
+// index b-trees (including WITHOUT ROWID tables) only have the key record.
+#[derive(Debug)]
+pub struct BTreeEntry {
+    pub row_id: Option<i64>,
+    pub record: Record,
+}
+
+struct Frame {
+    page: Rc<DBPage>,
+    // On leaf pages this is the current cell. On interior pages it is the
+    // child we descended into, where `number_of_cells` means the right-most
+    // pointer.
+    idx: u16,
+}
+
+#[derive(PartialEq)]
+enum State {
+    Unpositioned,
+    Valid,
+    Eof,
+}
+
+// Walks a table or index b-tree in key order. For index b-trees the keys
+// stored in interior cells are visited in between their children.
+//
+// Used as an iterator, the cursor starts at the first entry unless it has
+// already been positioned by one of the seek methods.
+pub struct BTreeCursor<'a> {
+    pager: &'a Pager,
+    root_page: u32,
+    stack: Vec<Frame>,
+    on_interior: bool,
+    state: State,
+}
+
+impl<'a> BTreeCursor<'a> {
+    pub fn new(pager: &'a Pager, root_page: u32) -> BTreeCursor<'a> {
+        BTreeCursor {
+            pager,
+            root_page,
+            stack: Vec::new(),
+            on_interior: false,
+            state: State::Unpositioned,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.state == State::Valid
+    }
+
+    pub fn first(&mut self) -> Result<bool, Error> {
+        self.reset();
+        self.descend_leftmost(self.root_page)?;
+        self.settle_leaf()
+    }
+
+    // Moves to the next entry. A failure leaves the cursor at the end, as
+    // the entries after it cannot be found.
+    pub fn advance(&mut self) -> Result<bool, Error> {
+        let advanced = self.step();
+        if advanced.is_err() {
+            self.state = State::Eof;
+        }
+        advanced
+    }
+
+    fn step(&mut self) -> Result<bool, Error> {
+        match self.state {
+            State::Unpositioned => return self.first(),
+            State::Eof => return Ok(false),
+            State::Valid => (),
+        }
+        if self.on_interior {
+            self.on_interior = false;
+            let top = self.stack.last_mut().unwrap();
+            top.idx += 1;
+            let child = child_page(&top.page, top.idx, self.pager.usable_size());
+            self.descend_leftmost(child)?;
+        } else {
+            self.stack.last_mut().unwrap().idx += 1;
+        }
+        self.settle_leaf()
+    }
+
+    // Positions the cursor on the first row whose rowid is >= `row_id`.
+    pub fn seek_row_id(&mut self, row_id: i64) -> Result<bool, Error> {
+        self.reset();
+        let mut page_no = self.root_page;
+        loop {
+            let page = self.pager.get_page(page_no)?;
+            if page.page_type.is_index() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "cannot seek a rowid in an index b-tree",
+                ));
+            }
+            let usable_size = self.pager.usable_size();
+            let idx = lower_bound(page.number_of_cells, |i| {
+                let cell_row_id = page.get_cell_layout(i, usable_size).row_id.unwrap();
+                Ok(cell_row_id.cmp(&row_id))
+            })?;
+            let is_leaf = page.page_type.is_leaf();
+            let child = if is_leaf {
+                0
+            } else {
+                child_page(&page, idx, self.pager.usable_size())
+            };
+            self.stack.push(Frame { page, idx });
+            if is_leaf {
+                return self.settle_leaf();
+            }
+            page_no = child;
+        }
+    }
+
+    // Positions the cursor on the first entry for which `compare` does not
+    // return `Ordering::Less`. `compare` orders an entry's key relative to
+    // the key being looked for.
+    pub fn seek_by<F>(&mut self, compare: F) -> Result<bool, Error>
+    where
+        F: Fn(&Record) -> Ordering,
+    {
+        self.reset();
+        let mut page_no = self.root_page;
+        loop {
+            let page = self.pager.get_page(page_no)?;
+            if !page.page_type.is_index() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "cannot seek a key in a table b-tree",
+                ));
+            }
+            let idx = lower_bound(page.number_of_cells, |i| {
+                Ok(compare(&self.record_at(&page, i)?))
+            })?;
+            let is_leaf = page.page_type.is_leaf();
+            let child = if is_leaf {
+                0
+            } else {
+                child_page(&page, idx, self.pager.usable_size())
+            };
+            self.stack.push(Frame { page, idx });
+            if is_leaf {
+                return self.settle_leaf();
+            }
+            page_no = child;
+        }
+    }
+
+    // Positions the cursor on the first index entry whose leading columns
+    // are >= `key`.
+    pub fn seek_key(&mut self, key: &[SqlValue]) -> Result<bool, Error> {
+        self.seek_by(|record| compare_prefix(record.values(), key))
+    }
+
+    pub fn row_id(&self) -> Option<i64> {
+        if !self.is_valid() || self.on_interior {
+            return None;
+        }
+        let top = self.stack.last().unwrap();
+        top.page
+            .get_cell_layout(top.idx, self.pager.usable_size())
+            .row_id
+    }
+
+    pub fn record(&self) -> Result<Record, Error> {
+        if !self.is_valid() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "cursor is not positioned on an entry",
+            ));
+        }
+        let top = self.stack.last().unwrap();
+        self.record_at(&top.page, top.idx)
+    }
+
+    // The record of the current entry, of which nothing is decoded yet.
+    pub fn lazy_record(&self) -> Result<LazyRecord<'_>, Error> {
+        if !self.is_valid() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "cursor is not positioned on an entry",
+            ));
+        }
+        let top = self.stack.last().unwrap();
+        let layout = top.page.get_cell_layout(top.idx, self.pager.usable_size());
+        LazyRecord::new(self.pager.payload(&top.page, &layout))
+    }
+
+    // Whether the cursor is on an entry, moving it to the first one if it
+    // has not been positioned yet, as the iterator does.
+    pub fn current(&mut self) -> Result<bool, Error> {
+        let positioned = match self.state {
+            State::Unpositioned => self.first(),
+            State::Valid => Ok(true),
+            State::Eof => Ok(false),
+        };
+        if positioned.is_err() {
+            self.state = State::Eof;
+        }
+        positioned
+    }
+
+    fn reset(&mut self) {
+        self.stack.clear();
+        self.on_interior = false;
+        self.state = State::Valid;
+    }
+
+    fn record_at(&self, page: &DBPage, cell_no: u16) -> Result<Record, Error> {
+        let layout = page.get_cell_layout(cell_no, self.pager.usable_size());
+        let payload = self.pager.read_payload(page, &layout)?;
+        Record::from_cell_bytes(&payload)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "malformed record"))
+    }
+
+    fn descend_leftmost(&mut self, mut page_no: u32) -> Result<(), Error> {
+        loop {
+            let page = self.pager.get_page(page_no)?;
+            let is_leaf = page.page_type.is_leaf();
+            let child = if is_leaf {
+                0
+            } else {
+                child_page(&page, 0, self.pager.usable_size())
+            };
+            self.stack.push(Frame { page, idx: 0 });
+            if is_leaf {
+                return Ok(());
+            }
+            page_no = child;
+        }
+    }
+
+    // Makes sure a leaf frame on top of the stack points at a real cell,
+    // moving on to the next entry of the tree if it ran off the end.
+    fn settle_leaf(&mut self) -> Result<bool, Error> {
+        let top = self.stack.last().unwrap();
+        if top.idx < top.page.number_of_cells {
+            self.state = State::Valid;
+            return Ok(true);
+        }
+        self.ascend()
+    }
+
+    fn ascend(&mut self) -> Result<bool, Error> {
+        loop {
+            self.stack.pop();
+            let top = match self.stack.last_mut() {
+                Some(top) => top,
+                None => {
+                    self.state = State::Eof;
+                    return Ok(false);
+                }
+            };
+            let number_of_cells = top.page.number_of_cells;
+            if top.page.page_type.is_index() && top.idx < number_of_cells {
+                self.on_interior = true;
+                self.state = State::Valid;
+                return Ok(true);
+            }
+            if top.idx < number_of_cells {
+                top.idx += 1;
+                let child = child_page(&top.page, top.idx, self.pager.usable_size());
+                self.descend_leftmost(child)?;
+                return self.settle_leaf();
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for BTreeCursor<'a> {
+    type Item = Result<BTreeEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.current() {
+            Ok(true) => (),
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e)),
+        }
+
+        let entry = self.record().map(|record| BTreeEntry {
+            row_id: self.row_id(),
+            record,
+        });
+        if let Err(e) = self.advance() {
+            return Some(entry.and(Err(e)));
+        }
+        Some(entry)
+    }
+}
+
+// Counts the entries of a b-tree from the number of cells on its pages,
+// without decoding any records. Interior cells of index b-trees hold
+// entries of their own.
+pub fn count_entries(pager: &Pager, root_page: u32) -> Result<i64, Error> {
+    let mut count = 0;
+    let mut pages = vec![root_page];
+    while let Some(page_no) = pages.pop() {
+        let page = pager.get_page(page_no)?;
+        if page.page_type.is_leaf() || page.page_type.is_index() {
+            count += page.number_of_cells as i64;
+        }
+        if !page.page_type.is_leaf() {
+            for idx in 0..=page.number_of_cells {
+                pages.push(child_page(&page, idx, pager.usable_size()));
+            }
+        }
+    }
+    Ok(count)
+}
+
+// The shape of a b-tree, read from its interior pages and two of its leaves.
+#[derive(Debug, Clone, Copy)]
+pub struct TreeSize {
+    // The number of pages on the path from the root to a leaf.
+    pub depth: u32,
+    pub leaf_pages: u64,
+    // Estimated from the cells of the first and the last leaf, which are
+    // usually full and partly full; interior cells of index b-trees are
+    // counted exactly.
+    pub entries: u64,
+}
+
+impl TreeSize {
+    pub fn entries_per_page(&self) -> f64 {
+        (self.entries as f64 / self.leaf_pages as f64).max(1.0)
+    }
+}
+
+// Measures a b-tree one level at a time, without reading its leaves.
+pub fn tree_size(pager: &Pager, root_page: u32) -> Result<TreeSize, Error> {
+    let mut level = vec![root_page];
+    let mut depth = 1;
+    let mut interior_entries = 0;
+    loop {
+        let first = pager.get_page(level[0])?;
+        if first.page_type.is_leaf() {
+            let last = pager.get_page(*level.last().unwrap())?;
+            let leaf_pages = level.len() as u64;
+            let entries = interior_entries
+                + (leaf_pages - 1) * first.number_of_cells as u64
+                + last.number_of_cells as u64;
+            return Ok(TreeSize {
+                depth,
+                leaf_pages,
+                entries,
+            });
+        }
+        let mut children = Vec::new();
+        for page_no in level {
+            let page = pager.get_page(page_no)?;
+            if page.page_type.is_index() {
+                interior_entries += page.number_of_cells as u64;
+            }
+            for idx in 0..=page.number_of_cells {
+                children.push(child_page(&page, idx, pager.usable_size()));
+            }
+        }
+        level = children;
+        depth += 1;
+    }
+}
+
+// The pages of a b-tree, including the overflow pages of its cells.
+pub fn tree_pages(pager: &Pager, root_page: u32) -> Result<Vec<u32>, Error> {
+    let mut result = Vec::new();
+    let mut pages = vec![root_page];
+    while let Some(page_no) = pages.pop() {
+        result.push(page_no);
+        let page = pager.get_page(page_no)?;
+        for idx in 0..page.number_of_cells {
+            let layout = page.get_cell_layout(idx, pager.usable_size());
+            let mut overflow = layout.first_overflow_page;
+            while let Some(overflow_page) = overflow {
+                result.push(overflow_page);
+                let raw = pager.read_raw_page(overflow_page)?;
+                overflow = match u32::from_be_bytes(raw[0..4].try_into().unwrap()) {
+                    0 => None,
+                    n => Some(n),
+                };
+            }
+        }
+        if !page.page_type.is_leaf() {
+            for idx in 0..=page.number_of_cells {
+                pages.push(child_page(&page, idx, pager.usable_size()));
+            }
+        }
+    }
+    Ok(result)
+}
+
+// Frees every page of a b-tree but its root, so that the tree can be
+// written anew.
+pub fn truncate_tree(transaction: &mut Transaction, root_page: u32) -> Result<(), Error> {
+    for page_no in tree_pages(transaction.pager(), root_page)? {
+        if page_no != root_page {
+            transaction.free(page_no);
+        }
+    }
+    Ok(())
+}
+
+// Writes a table b-tree holding `rows`, which are rowids and records in
+// rowid order, with its root on `root_page`. Leaves are filled one after
+// the other, and then the interior pages above them, up to the root.
+pub fn write_table(
+    transaction: &mut Transaction,
+    root_page: u32,
+    rows: &[(i64, Vec<u8>)],
+) -> Result<(), Error> {
+    let pager = transaction.pager();
+    let usable_size = pager.usable_size();
+    let mut cells = Vec::with_capacity(rows.len());
+    for (row_id, record) in rows {
+        let mut cell = varint::serialize_to_varint(record.len() as i64);
+        cell.extend(varint::serialize_to_varint(*row_id));
+        let local = local_payload_size(DBPageType::TableLeafPage, usable_size, record.len());
+        cell.extend_from_slice(&record[..local]);
+        if local < record.len() {
+            let first = write_overflow(transaction, &record[local..]);
+            cell.extend_from_slice(&first.to_be_bytes());
+        }
+        cells.push((*row_id, cell));
+    }
+
+    // each page is the cells of a range of them, the last of which gives
+    // the right-most pointer of an interior page rather than a cell
+    let mut page_type = DBPageType::TableLeafPage;
+    loop {
+        let interior = !page_type.is_leaf();
+        let header_size = if interior { 12 } else { 8 };
+        let root_capacity = page_capacity(pager, root_page) - header_size;
+        let capacity = usable_size - header_size;
+        let sizes: Vec<usize> = cells.iter().map(|(_, cell)| cell.len() + 2).collect();
+        let mut ranges = pack(&sizes, capacity, interior);
+        if ranges.len() == 1 && used_space(&sizes, &ranges[0], interior) > root_capacity {
+            let (start, end) = (ranges[0].start, ranges[0].end);
+            let middle = start + (end - start) / 2;
+            ranges = vec![start..middle.max(start + 1), middle.max(start + 1)..end];
+        }
+        if ranges.len() <= 1 {
+            let page = page_image(pager, root_page, page_type, &cells);
+            transaction.write(root_page, page);
+            return Ok(());
+        }
+
+        let mut parents = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            let page_no = transaction.allocate();
+            let page = page_image(pager, page_no, page_type, &cells[range.clone()]);
+            transaction.write(page_no, page);
+            let max_row_id = cells[range.end - 1].0;
+            let mut cell = page_no.to_be_bytes().to_vec();
+            cell.extend(varint::serialize_to_varint(max_row_id));
+            parents.push((max_row_id, cell));
+        }
+        cells = parents;
+        page_type = DBPageType::TableInteriorPage;
+    }
+}
+
+// Writes the part of a payload that does not fit on its b-tree page to a
+// chain of overflow pages, and returns the first of them.
+fn write_overflow(transaction: &mut Transaction, mut payload: &[u8]) -> u32 {
+    let pager = transaction.pager();
+    let first = transaction.allocate();
+    let mut page_no = first;
+    loop {
+        let take = payload.len().min(pager.usable_size() - 4);
+        let mut page = vec![0; pager.page_size()];
+        page[4..4 + take].copy_from_slice(&payload[..take]);
+        payload = &payload[take..];
+        let next = if payload.is_empty() {
+            0
+        } else {
+            transaction.allocate()
+        };
+        page[0..4].copy_from_slice(&next.to_be_bytes());
+        transaction.write(page_no, page);
+        if next == 0 {
+            return first;
+        }
+        page_no = next;
+    }
+}
+
+// The space on a page for its header, cell pointers and cells, which on
+// page 1 comes after the database header.
+fn page_capacity(pager: &Pager, page_no: u32) -> usize {
+    if page_no == 1 {
+        pager.usable_size() - 100
+    } else {
+        pager.usable_size()
+    }
+}
+
+// Splits cells of the given sizes into ranges that each fit on a page.
+fn pack(sizes: &[usize], capacity: usize, interior: bool) -> Vec<std::ops::Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut used = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        // on interior pages the last cell only adds the right-most pointer
+        let adds = if interior && i > start {
+            sizes[i - 1]
+        } else if interior {
+            0
+        } else {
+            *size
+        };
+        if i > start && used + adds > capacity {
+            ranges.push(start..i);
+            start = i;
+            used = if interior { 0 } else { *size };
+        } else {
+            used += adds;
+        }
+    }
+    if start < sizes.len() || ranges.is_empty() {
+        ranges.push(start..sizes.len());
+    }
+    // an interior page needs a cell besides its right-most pointer, which
+    // the page before can spare
+    let n = ranges.len();
+    if interior && n > 1 && ranges[n - 1].len() == 1 {
+        ranges[n - 2].end -= 1;
+        ranges[n - 1].start -= 1;
+    }
+    ranges
+}
+
+fn used_space(sizes: &[usize], range: &std::ops::Range<usize>, interior: bool) -> usize {
+    let end = if interior && range.end > range.start {
+        range.end - 1
+    } else {
+        range.end
+    };
+    sizes[range.start..end].iter().sum()
+}
+
+// Lays out a b-tree page with the given cells, the last of which becomes
+// the right-most pointer of an interior page.
+fn page_image(
+    pager: &Pager,
+    page_no: u32,
+    page_type: DBPageType,
+    cells: &[(i64, Vec<u8>)],
+) -> Vec<u8> {
+    let mut page = vec![0; pager.page_size()];
+    let start = if page_no == 1 { 100 } else { 0 };
+    let (cells, header_size) = if page_type.is_leaf() {
+        (cells, 8)
+    } else {
+        let (last, cells) = cells.split_last().unwrap();
+        page[start + 8..start + 12].copy_from_slice(&last.1[0..4]);
+        (cells, 12)
+    };
+    page[start] = page_type as u8;
+    page[start + 3..start + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+    let mut content = pager.usable_size();
+    for (i, (_, cell)) in cells.iter().enumerate() {
+        content -= cell.len();
+        page[content..content + cell.len()].copy_from_slice(cell);
+        let pointer = start + header_size + i * 2;
+        page[pointer..pointer + 2].copy_from_slice(&(content as u16).to_be_bytes());
+    }
+    // a content area starting at 65536 is written as 0
+    page[start + 5..start + 7].copy_from_slice(&(content as u16).to_be_bytes());
+    page
+}
+
+// The child an interior page points to at `idx`, where `number_of_cells`
+// means the right-most pointer.
+pub fn child_page(page: &DBPage, idx: u16, usable_size: usize) -> u32 {
+    if idx < page.number_of_cells {
+        page.get_cell_layout(idx, usable_size)
+            .left_child_page
+            .unwrap()
+    } else {
+        page.right_most_pointer.unwrap()
+    }
+}
+
+// Compares the leading columns of an index key with a (possibly shorter)
+// search key.
+pub fn compare_prefix(values: &[Value], key: &[SqlValue]) -> Ordering {
+    for (v, k) in values.iter().zip(key.iter()) {
+        match SqlValue::from(v).compare(k) {
+            Ordering::Equal => continue,
+            o => return o,
+        }
+    }
+    Ordering::Equal
+}
+
+// Index of the first cell that does not compare less than the target.
+fn lower_bound<F>(number_of_cells: u16, mut compare: F) -> Result<u16, Error>
+where
+    F: FnMut(u16) -> Result<Ordering, Error>,
+{
+    let (mut low, mut high) = (0, number_of_cells);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if compare(mid)? == Ordering::Less {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(low)
+}
@@ -0,0 +1,680 @@
+use crate::aggregate::Aggregate;
+use crate::analyzer::{self, PageUsage};
+use crate::executor::{Executor, QueryRows};
+use crate::expr::{Collations, Functions};
+use crate::pager::Pager;
+use crate::scalar::FunctionFlags;
+use crate::schema::Schema;
+use crate::sorter::DEFAULT_SORT_BUDGET;
+use crate::sql_parser::{self, Statement as Ast};
+use crate::value::SqlValue;
+use std::cell::{OnceCell, RefCell};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::rc::Rc;
+
+// How many parsed statements a connection keeps by default.
+const DEFAULT_CACHE_CAPACITY: usize = 16;
+
+// An open database file, which prepares and runs statements on it.
+pub struct Connection {
+    pager: Pager,
+    schema: Schemas,
+    collations: Rc<Collations>,
+    functions: Rc<Functions>,
+    sort_budget: usize,
+    cache: RefCell<StatementCache>,
+}
+
+impl Connection {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Connection, Error> {
+        let pager = Pager::open(path)?;
+        let schema = Schemas::read(&pager)?;
+        Ok(Connection {
+            pager,
+            schema,
+            collations: Rc::new(Collations::new()),
+            functions: Rc::new(Functions::new()),
+            sort_budget: DEFAULT_SORT_BUDGET,
+            cache: RefCell::new(StatementCache::new(DEFAULT_CACHE_CAPACITY)),
+        })
+    }
+
+    // Makes a user-defined collation available to the statements of this
+    // connection, as `Executor::register_collation` does.
+    pub fn register_collation<F>(&mut self, name: &str, compare: F)
+    where
+        F: Fn(&str, &str) -> Ordering + 'static,
+    {
+        Rc::make_mut(&mut self.collations).register(name, compare);
+    }
+
+    pub fn register_scalar<F>(
+        &mut self,
+        name: &str,
+        arguments: Option<usize>,
+        flags: FunctionFlags,
+        function: F,
+    ) where
+        F: Fn(&[SqlValue]) -> Result<SqlValue, Error> + 'static,
+    {
+        Rc::make_mut(&mut self.functions).register_scalar(name, arguments, flags, function);
+    }
+
+    pub fn register_aggregate<A>(
+        &mut self,
+        name: &str,
+        arguments: Option<usize>,
+        flags: FunctionFlags,
+        aggregate: A,
+    ) where
+        A: Aggregate + 'static,
+    {
+        Rc::make_mut(&mut self.functions).register_aggregate(name, arguments, flags, aggregate);
+    }
+
+    // Sets how many bytes of rows a sort keeps in memory before it spills
+    // sorted runs to temporary files.
+    pub fn set_sort_budget(&mut self, bytes: usize) {
+        self.sort_budget = bytes;
+    }
+
+    // Parses a single statement, which can then be run any number of times
+    // with different values bound to its parameters. Statements prepared
+    // from the same SQL text share their parse through a cache of the most
+    // recently used ones.
+    pub fn prepare(&self, sql: &str) -> Result<Statement<'_>, Error> {
+        let cached = self.cache.borrow_mut().get(sql);
+        let prepared = match cached {
+            Some(prepared) => prepared,
+            None => {
+                let prepared = Rc::new(Prepared::parse(sql)?);
+                self.cache.borrow_mut().insert(sql, prepared.clone());
+                prepared
+            }
+        };
+        Ok(Statement {
+            connection: self,
+            values: vec![SqlValue::Null; prepared.parameters.len()],
+            prepared,
+        })
+    }
+
+    // Sets how many parsed statements are kept, dropping the least recently
+    // used ones beyond that. Zero turns the cache off.
+    pub fn set_statement_cache_capacity(&self, capacity: usize) {
+        self.cache.borrow_mut().set_capacity(capacity);
+    }
+
+    // Forgets every parsed statement.
+    pub fn flush_statement_cache(&self) {
+        self.cache.borrow_mut().entries.clear();
+    }
+
+    // What every page of the file is used for, and how full the pages of
+    // each table and index are.
+    pub fn page_usage(&self) -> Result<PageUsage, Error> {
+        self.pager.refresh()?;
+        analyzer::page_usage(&self.pager)
+    }
+
+    // Runs a statement to the end and returns the number of rows it
+    // changed, which is always 0 as only queries and ANALYZE can be run.
+    pub fn execute<P: Params>(&self, sql: &str, params: P) -> Result<usize, Error> {
+        self.prepare(sql)?.execute(params)
+    }
+
+    pub fn query<P: Params>(&self, sql: &str, params: P) -> Result<Rows<'_>, Error> {
+        self.prepare(sql)?.query(params)
+    }
+
+    // Runs a query and passes its first row to `f`. A query without rows
+    // is an error of kind NotFound.
+    pub fn query_row<P, T, F>(&self, sql: &str, params: P, f: F) -> Result<T, Error>
+    where
+        P: Params,
+        F: FnOnce(&Row) -> Result<T, Error>,
+    {
+        self.prepare(sql)?.query_row(params, f)
+    }
+
+    // Runs a query and maps each row with `f` as it is read.
+    pub fn query_map<P, T, F>(&self, sql: &str, params: P, f: F) -> Result<MappedRows<'_, F>, Error>
+    where
+        P: Params,
+        F: FnMut(&Row) -> Result<T, Error>,
+    {
+        self.prepare(sql)?.query_map(params, f)
+    }
+
+    // The schema as it is in the file now, read again when a statement such
+    // as ANALYZE, or another connection, has changed sqlite_master. Pages
+    // cached before another connection wrote the file are dropped first.
+    fn schema(&self) -> Result<&Schema, Error> {
+        self.pager.refresh()?;
+        self.schema.current(&self.pager)
+    }
+
+    fn executor(&self) -> Result<Executor<'_>, Error> {
+        let mut executor = Executor::new(&self.pager, self.schema()?);
+        executor.set_collations(self.collations.clone());
+        executor.set_functions(self.functions.clone());
+        executor.set_sort_budget(self.sort_budget);
+        Ok(executor)
+    }
+}
+
+// The schema of a connection, and those read after it as the file changed.
+// Rows of statements still running may refer to an older schema, so each
+// is kept until the connection is closed.
+struct Schemas {
+    schema: Schema,
+    cookie: u32,
+    next: OnceCell<Box<Schemas>>,
+}
+
+impl Schemas {
+    fn read(pager: &Pager) -> Result<Schemas, Error> {
+        Ok(Schemas {
+            schema: Schema::read(pager)?,
+            cookie: pager.schema_cookie()?,
+            next: OnceCell::new(),
+        })
+    }
+
+    fn current(&self, pager: &Pager) -> Result<&Schema, Error> {
+        let mut latest = self;
+        while let Some(next) = latest.next.get() {
+            latest = next;
+        }
+        if pager.schema_cookie()? == latest.cookie {
+            return Ok(&latest.schema);
+        }
+        let next = Box::new(Schemas::read(pager)?);
+        Ok(&latest.next.get_or_init(|| next).schema)
+    }
+}
+
+// A parsed statement and the names of its parameters.
+#[derive(Debug)]
+struct Prepared {
+    statement: Ast,
+    parameters: Vec<Option<String>>,
+}
+
+impl Prepared {
+    fn parse(sql: &str) -> Result<Prepared, Error> {
+        let (mut statements, parameters) = sql_parser::parse_sql_with_parameters(sql)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        if statements.len() != 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "expected exactly one statement",
+            ));
+        }
+        Ok(Prepared {
+            statement: statements.remove(0),
+            parameters,
+        })
+    }
+}
+
+// The parsed statements of a connection by SQL text, the most recently used
+// last.
+struct StatementCache {
+    capacity: usize,
+    entries: VecDeque<(String, Rc<Prepared>)>,
+}
+
+impl StatementCache {
+    fn new(capacity: usize) -> StatementCache {
+        StatementCache {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn get(&mut self, sql: &str) -> Option<Rc<Prepared>> {
+        let i = self.entries.iter().position(|(s, _)| s == sql)?;
+        let entry = self.entries.remove(i)?;
+        let prepared = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(prepared)
+    }
+
+    fn insert(&mut self, sql: &str, prepared: Rc<Prepared>) {
+        self.entries.push_back((sql.to_string(), prepared));
+        self.evict();
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+}
+
+// A prepared statement and the values bound to its parameters. Values are
+// bound by number, ?1 being the first, or by name including its prefix,
+// such as `:id`. Parameters that were never bound are NULL.
+pub struct Statement<'c> {
+    connection: &'c Connection,
+    prepared: Rc<Prepared>,
+    values: Vec<SqlValue>,
+}
+
+impl<'c> Statement<'c> {
+    // The largest parameter number in the statement.
+    pub fn parameter_count(&self) -> usize {
+        self.prepared.parameters.len()
+    }
+
+    // The name of a parameter by number, None for a bare `?`.
+    pub fn parameter_name(&self, number: usize) -> Option<&str> {
+        self.prepared
+            .parameters
+            .get(number.checked_sub(1)?)?
+            .as_deref()
+    }
+
+    // The number of the parameter with the given name.
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        let i = self
+            .prepared
+            .parameters
+            .iter()
+            .position(|n| n.as_deref() == Some(name))?;
+        Some(i + 1)
+    }
+
+    pub fn bind<T: Into<SqlValue>>(&mut self, number: usize, value: T) -> Result<(), Error> {
+        if number == 0 || number > self.values.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("parameter index out of range: {}", number),
+            ));
+        }
+        self.values[number - 1] = value.into();
+        Ok(())
+    }
+
+    pub fn bind_named<T: Into<SqlValue>>(&mut self, name: &str, value: T) -> Result<(), Error> {
+        match self.parameter_index(name) {
+            Some(number) => self.bind(number, value),
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("no such parameter: {}", name),
+            )),
+        }
+    }
+
+    // Binds values to the parameters in order, from ?1 on.
+    pub fn bind_all<T: Into<SqlValue>, I: IntoIterator<Item = T>>(
+        &mut self,
+        values: I,
+    ) -> Result<(), Error> {
+        for (i, value) in values.into_iter().enumerate() {
+            self.bind(i + 1, value)?;
+        }
+        Ok(())
+    }
+
+    // Sets every parameter back to NULL.
+    pub fn clear_bindings(&mut self) {
+        for value in &mut self.values {
+            *value = SqlValue::Null;
+        }
+    }
+
+    pub fn execute<P: Params>(&mut self, params: P) -> Result<usize, Error> {
+        for row in self.query(params)? {
+            row?;
+        }
+        Ok(0)
+    }
+
+    // Runs the statement with `params` bound, reading its rows as they are
+    // asked for. Binding `()` runs it with the values bound before, so it
+    // can be run again as is or after binding some of them anew.
+    pub fn query<P: Params>(&mut self, params: P) -> Result<Rows<'c>, Error> {
+        params.bind_to(self)?;
+        let mut executor = self.connection.executor()?;
+        executor.set_parameters(self.values.clone());
+        let rows = executor.query_rows(&self.prepared.statement)?;
+        Ok(Rows {
+            columns: rows.columns.clone().into(),
+            rows,
+        })
+    }
+
+    pub fn query_row<P, T, F>(&mut self, params: P, f: F) -> Result<T, Error>
+    where
+        P: Params,
+        F: FnOnce(&Row) -> Result<T, Error>,
+    {
+        match self.query(params)?.next() {
+            Some(row) => f(&row?),
+            None => Err(Error::new(ErrorKind::NotFound, "query returned no rows")),
+        }
+    }
+
+    pub fn query_map<P, T, F>(&mut self, params: P, f: F) -> Result<MappedRows<'c, F>, Error>
+    where
+        P: Params,
+        F: FnMut(&Row) -> Result<T, Error>,
+    {
+        Ok(MappedRows {
+            rows: self.query(params)?,
+            f,
+        })
+    }
+}
+
+// Values for the parameters of a statement: `()` keeps the values bound
+// before, arrays and vectors of values bind ?1 on, and arrays of name and
+// value pairs bind by name. The `params!` and `named_params!` macros build
+// them from values of different types.
+pub trait Params {
+    fn bind_to(self, statement: &mut Statement) -> Result<(), Error>;
+}
+
+fn bind_positional<I>(statement: &mut Statement, values: I) -> Result<(), Error>
+where
+    I: ExactSizeIterator,
+    I::Item: Into<SqlValue>,
+{
+    if values.len() != statement.parameter_count() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "wrong number of parameters: {} given, {} expected",
+                values.len(),
+                statement.parameter_count()
+            ),
+        ));
+    }
+    statement.bind_all(values)
+}
+
+impl Params for () {
+    fn bind_to(self, _statement: &mut Statement) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<T: Into<SqlValue>, const N: usize> Params for [T; N] {
+    fn bind_to(self, statement: &mut Statement) -> Result<(), Error> {
+        bind_positional(statement, IntoIterator::into_iter(self))
+    }
+}
+
+impl<T: Into<SqlValue>> Params for Vec<T> {
+    fn bind_to(self, statement: &mut Statement) -> Result<(), Error> {
+        bind_positional(statement, self.into_iter())
+    }
+}
+
+impl Params for &[SqlValue] {
+    fn bind_to(self, statement: &mut Statement) -> Result<(), Error> {
+        bind_positional(statement, self.iter().cloned())
+    }
+}
+
+impl Params for &[(&str, SqlValue)] {
+    fn bind_to(self, statement: &mut Statement) -> Result<(), Error> {
+        for (name, value) in self {
+            statement.bind_named(name, value.clone())?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Into<SqlValue>, const N: usize> Params for [(&str, T); N] {
+    fn bind_to(self, statement: &mut Statement) -> Result<(), Error> {
+        for (name, value) in self {
+            statement.bind_named(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+// The fields of a ToRow bind the parameters named after their columns,
+// such as `:name` for the column `name`, or else bind ?1 on in order if
+// the statement has no named parameters. Fields the statement has no
+// parameter for are left out.
+impl<T: ToRow> Params for &T {
+    fn bind_to(self, statement: &mut Statement) -> Result<(), Error> {
+        let values = self.to_row();
+        let named = statement
+            .prepared
+            .parameters
+            .iter()
+            .flatten()
+            .any(|name| !name.starts_with('?'));
+        if !named {
+            return bind_positional(statement, values.into_iter().map(|(_, value)| value));
+        }
+        for (column, value) in values {
+            let number = [":", "@", "$"]
+                .iter()
+                .find_map(|prefix| statement.parameter_index(&format!("{}{}", prefix, column)));
+            if let Some(number) = number {
+                statement.bind(number, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// A type built from a result row, usually by `#[derive(FromRow)]`, which
+// reads each field from the column of the same name.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, Error>;
+}
+
+// A type that gives the values of a row by column name, usually by
+// `#[derive(ToRow)]`. A reference to it binds the parameters of a
+// statement, such as the values of an INSERT.
+pub trait ToRow {
+    fn to_row(&self) -> Vec<(&'static str, SqlValue)>;
+}
+
+// Positional parameters of any types, as in `params![1, "two", 3.0]`.
+#[macro_export]
+macro_rules! params {
+    ($($value:expr),* $(,)?) => {{
+        let values: Vec<$crate::value::SqlValue> =
+            vec![$($crate::value::SqlValue::from($value)),*];
+        values
+    }};
+}
+
+// Named parameters of any types, as in `named_params! { ":id": 1 }`.
+#[macro_export]
+macro_rules! named_params {
+    ($($name:literal : $value:expr),* $(,)?) => {
+        &[$(($name, $crate::value::SqlValue::from($value))),*] as &[(&str, $crate::value::SqlValue)]
+    };
+}
+
+// The rows of a statement, read as they are asked for.
+pub struct Rows<'c> {
+    columns: Rc<[String]>,
+    rows: QueryRows<'c>,
+}
+
+impl<'c> Rows<'c> {
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    // Builds a `T` from each row as it is read.
+    pub fn typed<T: FromRow>(self) -> TypedRows<'c, T> {
+        MappedRows {
+            rows: self,
+            f: T::from_row,
+        }
+    }
+}
+
+impl<'c> Iterator for Rows<'c> {
+    type Item = Result<Row, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let values = self.rows.next()?;
+        Some(values.map(|values| Row::new(self.columns.clone(), values)))
+    }
+}
+
+// The rows of a statement, each built into a FromRow as it is read.
+pub type TypedRows<'c, T> = MappedRows<'c, fn(&Row) -> Result<T, Error>>;
+
+// The rows of a statement, each mapped by a function as it is read.
+pub struct MappedRows<'c, F> {
+    rows: Rows<'c>,
+    f: F,
+}
+
+impl<'c, T, F> Iterator for MappedRows<'c, F>
+where
+    F: FnMut(&Row) -> Result<T, Error>,
+{
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = self.rows.next()?;
+        Some(row.and_then(|row| (self.f)(&row)))
+    }
+}
+
+// A result row, whose values are read by column number, from 0, or by
+// column name.
+#[derive(Debug, Clone)]
+pub struct Row {
+    columns: Rc<[String]>,
+    values: Vec<SqlValue>,
+}
+
+impl Row {
+    pub fn new(columns: Rc<[String]>, values: Vec<SqlValue>) -> Row {
+        Row { columns, values }
+    }
+
+    // The value of a column converted to `T`, as by its TryFrom<SqlValue>.
+    pub fn get<I, T>(&self, index: I) -> Result<T, Error>
+    where
+        I: RowIndex,
+        T: TryFrom<SqlValue, Error = Error>,
+    {
+        T::try_from(self.get_ref(index)?.clone())
+    }
+
+    pub fn get_ref<I: RowIndex>(&self, index: I) -> Result<&SqlValue, Error> {
+        Ok(&self.values[index.index(&self.columns)?])
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    // The number of the column with the given name, if the row has one.
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        name.index(&self.columns).ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn values(&self) -> &[SqlValue] {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Vec<SqlValue> {
+        self.values
+    }
+}
+
+// A column of a row, by number or by name.
+pub trait RowIndex {
+    fn index(&self, columns: &[String]) -> Result<usize, Error>;
+}
+
+impl RowIndex for usize {
+    fn index(&self, columns: &[String]) -> Result<usize, Error> {
+        if *self < columns.len() {
+            Ok(*self)
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("column index out of range: {}", self),
+            ))
+        }
+    }
+}
+
+impl RowIndex for &str {
+    fn index(&self, columns: &[String]) -> Result<usize, Error> {
+        columns
+            .iter()
+            .position(|c| c.eq_ignore_ascii_case(self))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("no such column: {}", self)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(cache: &StatementCache) -> Vec<&str> {
+        cache.entries.iter().map(|(sql, _)| sql.as_str()).collect()
+    }
+
+    #[test]
+    fn test_statement_cache() {
+        let mut cache = StatementCache::new(2);
+        for sql in ["SELECT 1", "SELECT 2"] {
+            cache.insert(sql, Rc::new(Prepared::parse(sql).unwrap()));
+        }
+        assert!(cache.get("SELECT 1").is_some());
+        assert!(cache.get("SELECT 3").is_none());
+        cache.insert("SELECT 3", Rc::new(Prepared::parse("SELECT 3").unwrap()));
+        assert_eq!(cached(&cache), vec!["SELECT 1", "SELECT 3"]);
+        cache.set_capacity(1);
+        assert_eq!(cached(&cache), vec!["SELECT 3"]);
+    }
+
+    #[test]
+    fn test_prepared_parameters() {
+        let prepared = Prepared::parse("SELECT ?, :a, ?5, @b, :a, $c, ?").unwrap();
+        let names: Vec<Option<&str>> = prepared.parameters.iter().map(|n| n.as_deref()).collect();
+        assert_eq!(
+            names,
+            vec![
+                None,
+                Some(":a"),
+                None,
+                None,
+                Some("?5"),
+                Some("@b"),
+                Some("$c"),
+                None
+            ]
+        );
+        assert!(Prepared::parse("SELECT 1; SELECT 2").is_err());
+        assert!(Prepared::parse("SELECT ?0").is_err());
+        assert!(Prepared::parse("SELECT ?32767").is_err());
+    }
+}
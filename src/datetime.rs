@@ -0,0 +1,1132 @@
+use crate::printf;
+use crate::value::{parse_numeric_exact, SqlValue};
+use std::io::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The date and time functions, a port of SQLite's date.c so that results
+// match SQLite exactly. See https://www.sqlite.org/lang_datefunc.html.
+//
+// Times are held as a julian day number in milliseconds and/or as the
+// broken down year, month, day, hour, minute and second, computing one from
+// the other on demand.
+
+// The julian day of 1970-01-01 00:00:00 in milliseconds.
+const UNIX_EPOCH_JD: i64 = 210_866_760_000_000;
+
+// The julian day of 9999-12-31 23:59:59.999 in milliseconds.
+const MAX_JD: i64 = 464_269_060_799_999;
+
+#[derive(Clone, Copy, Default)]
+struct DateTime {
+    // julian day number times 86400000
+    jd: i64,
+    year: i32,
+    month: i32,
+    day: i32,
+    hour: i32,
+    minute: i32,
+    // timezone offset in minutes
+    tz: i32,
+    second: f64,
+    valid_jd: bool,
+    valid_ymd: bool,
+    valid_hms: bool,
+    // days to subtract to resolve a day-of-month overflow with "floor"
+    floor: i32,
+    // `second` holds the raw numeric argument, not yet interpreted
+    raw: bool,
+    error: bool,
+    subsec: bool,
+    utc: bool,
+    local: bool,
+}
+
+// The byte at `i`, or 0 past the end, mimicking a NUL terminated string.
+fn at(z: &[u8], i: usize) -> u8 {
+    z.get(i).copied().unwrap_or(0)
+}
+
+fn tail(z: &[u8], i: usize) -> &[u8] {
+    z.get(i..).unwrap_or(&[])
+}
+
+// SQLite's isspace(), which unlike Rust's includes vertical tab.
+fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r')
+}
+
+fn skip_spaces(mut z: &[u8]) -> &[u8] {
+    while !z.is_empty() && is_space(z[0]) {
+        z = &z[1..];
+    }
+    z
+}
+
+// Parses a number that makes up all of `z`, apart from surrounding spaces.
+fn atof(z: &[u8]) -> Option<f64> {
+    let value = parse_numeric_exact(std::str::from_utf8(z).ok()?)?;
+    value.to_f64()
+}
+
+// Reads fixed width integers from `z` into `out`. Each field of `format` is
+// four characters: the number of digits, the minimum value, a letter for
+// the maximum value and the separator that must follow, or '\0' for none.
+// Returns the number of fields read.
+fn get_digits(mut z: &[u8], format: &[u8], out: &mut [i32]) -> usize {
+    const MAXIMUMS: [i32; 6] = [12, 14, 24, 31, 59, 14712];
+    let mut count = 0;
+    for field in format.chunks(4) {
+        let next = at(field, 3);
+        let mut value = 0;
+        for _ in 0..field[0] - b'0' {
+            if !at(z, 0).is_ascii_digit() {
+                return count;
+            }
+            value = value * 10 + (z[0] - b'0') as i32;
+            z = &z[1..];
+        }
+        let (min, max) = (
+            (field[1] - b'0') as i32,
+            MAXIMUMS[(field[2] - b'a') as usize],
+        );
+        if value < min || value > max || (next != 0 && next != at(z, 0)) {
+            return count;
+        }
+        out[count] = value;
+        z = tail(z, 1);
+        count += 1;
+    }
+    count
+}
+
+impl DateTime {
+    fn set_error(&mut self) {
+        *self = DateTime {
+            error: true,
+            ..DateTime::default()
+        };
+    }
+
+    // Parses a "+HH:MM", "-HH:MM" or "Z" suffix. Returns false on error.
+    fn parse_timezone(&mut self, z: &[u8]) -> bool {
+        let mut z = skip_spaces(z);
+        self.tz = 0;
+        let sign = match at(z, 0) {
+            b'-' => -1,
+            b'+' => 1,
+            b'Z' | b'z' => {
+                self.local = false;
+                self.utc = true;
+                return skip_spaces(&z[1..]).is_empty();
+            }
+            c => return c == 0,
+        };
+        z = &z[1..];
+        let mut hm = [0; 2];
+        if get_digits(z, b"20b:20e", &mut hm) != 2 {
+            return false;
+        }
+        self.tz = sign * (hm[1] + hm[0] * 60);
+        skip_spaces(tail(z, 5)).is_empty()
+    }
+
+    // Parses "HH:MM", "HH:MM:SS" or "HH:MM:SS.FFF" with an optional
+    // timezone. Returns false on error.
+    fn parse_hh_mm_ss(&mut self, z: &[u8]) -> bool {
+        let mut hm = [0; 2];
+        if get_digits(z, b"20c:20e", &mut hm) != 2 {
+            return false;
+        }
+        let mut z = tail(z, 5);
+        let mut second = 0;
+        let mut fraction = 0.0;
+        if at(z, 0) == b':' {
+            let mut s = [0];
+            if get_digits(&z[1..], b"20e", &mut s) != 1 {
+                return false;
+            }
+            second = s[0];
+            z = tail(z, 3);
+            if at(z, 0) == b'.' && at(z, 1).is_ascii_digit() {
+                let mut scale = 1.0;
+                z = &z[1..];
+                while at(z, 0).is_ascii_digit() {
+                    fraction = fraction * 10.0 + (z[0] - b'0') as f64;
+                    scale *= 10.0;
+                    z = &z[1..];
+                }
+                fraction /= scale;
+                // truncated to avoid rounding up to the next second
+                if fraction > 0.999 {
+                    fraction = 0.999;
+                }
+            }
+        }
+        self.valid_jd = false;
+        self.raw = false;
+        self.valid_hms = true;
+        self.hour = hm[0];
+        self.minute = hm[1];
+        self.second = second as f64 + fraction;
+        self.parse_timezone(z)
+    }
+
+    // Parses "YYYY-MM-DD" with an optional time. Returns false on error.
+    fn parse_yyyy_mm_dd(&mut self, z: &[u8]) -> bool {
+        let (z, negative) = match z.first() {
+            Some(b'-') => (&z[1..], true),
+            _ => (z, false),
+        };
+        let mut ymd = [0; 3];
+        if get_digits(z, b"40f-21a-21d", &mut ymd) != 3 {
+            return false;
+        }
+        let mut z = tail(z, 10);
+        while !z.is_empty() && (is_space(z[0]) || z[0] == b'T') {
+            z = &z[1..];
+        }
+        if !self.parse_hh_mm_ss(z) {
+            if !z.is_empty() {
+                return false;
+            }
+            self.valid_hms = false;
+        }
+        self.valid_jd = false;
+        self.valid_ymd = true;
+        self.year = if negative { -ymd[0] } else { ymd[0] };
+        self.month = ymd[1];
+        self.day = ymd[2];
+        self.compute_floor();
+        if self.tz != 0 {
+            self.compute_jd();
+        }
+        true
+    }
+
+    fn set_now(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        self.jd = now + UNIX_EPOCH_JD;
+        self.valid_jd = true;
+        self.utc = true;
+        self.local = false;
+        self.clear_ymd_hms_tz();
+    }
+
+    // A number, to be interpreted as a julian day unless a modifier says
+    // otherwise.
+    fn set_raw_number(&mut self, r: f64) {
+        self.second = r;
+        self.raw = true;
+        if (0.0..5373484.5).contains(&r) {
+            self.jd = (r * 86400000.0 + 0.5) as i64;
+            self.valid_jd = true;
+        }
+    }
+
+    fn parse(&mut self, z: &[u8]) -> bool {
+        if self.parse_yyyy_mm_dd(z) || self.parse_hh_mm_ss(z) {
+            return true;
+        }
+        if z.eq_ignore_ascii_case(b"now") {
+            self.set_now();
+        } else if let Some(r) = atof(z) {
+            self.set_raw_number(r);
+        } else if z.eq_ignore_ascii_case(b"subsec") || z.eq_ignore_ascii_case(b"subsecond") {
+            self.subsec = true;
+            self.set_now();
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn compute_jd(&mut self) {
+        if self.valid_jd {
+            return;
+        }
+        let (mut y, mut m, d) = if self.valid_ymd {
+            (self.year, self.month, self.day)
+        } else {
+            (2000, 1, 1)
+        };
+        if !(-4713..=9999).contains(&y) || self.raw {
+            self.set_error();
+            return;
+        }
+        if m <= 2 {
+            y -= 1;
+            m += 12;
+        }
+        let a = (y + 4800) / 100;
+        let b = 38 - a + (a / 4);
+        let x1 = 36525 * (y + 4716) / 100;
+        let x2 = 306001 * (m + 1) / 10000;
+        self.jd = (((x1 + x2 + d + b) as f64 - 1524.5) * 86400000.0) as i64;
+        self.valid_jd = true;
+        if self.valid_hms {
+            self.jd += (self.hour * 3600000 + self.minute * 60000) as i64
+                + (self.second * 1000.0 + 0.5) as i64;
+            if self.tz != 0 {
+                self.jd -= self.tz as i64 * 60000;
+                self.valid_ymd = false;
+                self.valid_hms = false;
+                self.tz = 0;
+                self.utc = true;
+                self.local = false;
+            }
+        }
+    }
+
+    // Works out how many days past the end of the month the day is.
+    fn compute_floor(&mut self) {
+        self.floor = if self.day <= 28 || (1 << self.month) & 0x15aa != 0 {
+            0
+        } else if self.month != 2 {
+            (self.day == 31) as i32
+        } else if self.year % 4 != 0 || (self.year % 100 == 0 && self.year % 400 != 0) {
+            self.day - 28
+        } else {
+            self.day - 29
+        };
+    }
+
+    fn compute_ymd(&mut self) {
+        if self.valid_ymd {
+            return;
+        }
+        if !self.valid_jd {
+            self.year = 2000;
+            self.month = 1;
+            self.day = 1;
+        } else if !valid_julian_day(self.jd) {
+            self.set_error();
+            return;
+        } else {
+            let z = ((self.jd + 43200000) / 86400000) as i32;
+            let alpha = ((z as f64 + 32044.75) / 36524.25) as i32 - 52;
+            let a = z + 1 + alpha - ((alpha + 100) / 4) + 25;
+            let b = a + 1524;
+            let c = ((b as f64 - 122.1) / 365.25) as i32;
+            let d = (36525 * (c & 32767)) / 100;
+            let e = ((b - d) as f64 / 30.6001) as i32;
+            let x1 = (30.6001 * e as f64) as i32;
+            self.day = b - d - x1;
+            self.month = if e < 14 { e - 1 } else { e - 13 };
+            self.year = if self.month > 2 { c - 4716 } else { c - 4715 };
+        }
+        self.valid_ymd = true;
+    }
+
+    fn compute_hms(&mut self) {
+        if self.valid_hms {
+            return;
+        }
+        self.compute_jd();
+        let day_ms = ((self.jd + 43200000) % 86400000) as i32;
+        self.second = (day_ms % 60000) as f64 / 1000.0;
+        let day_minute = day_ms / 60000;
+        self.minute = day_minute % 60;
+        self.hour = day_minute / 60;
+        self.raw = false;
+        self.valid_hms = true;
+    }
+
+    fn compute_ymd_hms(&mut self) {
+        self.compute_ymd();
+        self.compute_hms();
+    }
+
+    fn clear_ymd_hms_tz(&mut self) {
+        self.valid_ymd = false;
+        self.valid_hms = false;
+        self.tz = 0;
+    }
+
+    // Converts from UTC to local time. Years outside of 1970..2037 are
+    // mapped to an equivalent year inside that range, as localtime() may
+    // not handle them.
+    fn localize(&mut self) -> Result<(), Error> {
+        self.compute_jd();
+        let (t, year_diff) = if self.jd < 210866760000000 || self.jd > 213014145600000 {
+            let mut x = *self;
+            x.compute_ymd_hms();
+            let year_diff = (2000 + x.year % 4) - x.year;
+            x.year += year_diff;
+            x.valid_jd = false;
+            x.compute_jd();
+            (x.jd / 1000 - 210866760000, year_diff)
+        } else {
+            (self.jd / 1000 - 210866760000, 0)
+        };
+        let local = local_time(t).ok_or_else(|| Error::other("local time unavailable"))?;
+        self.year = local.year - year_diff;
+        self.month = local.month;
+        self.day = local.day;
+        self.hour = local.hour;
+        self.minute = local.minute;
+        self.second = local.second as f64 + (self.jd % 1000) as f64 * 0.001;
+        self.valid_ymd = true;
+        self.valid_hms = true;
+        self.valid_jd = false;
+        self.raw = false;
+        self.tz = 0;
+        self.error = false;
+        Ok(())
+    }
+
+    // Interprets a raw number as a julian day or a unix timestamp,
+    // depending on its magnitude.
+    fn auto_adjust(&mut self) {
+        if !self.raw || self.valid_jd {
+            self.raw = false;
+        } else if self.second >= -210866760000.0 && self.second <= 253402300799.0 {
+            let r = self.second * 1000.0 + 210866760000000.0;
+            self.clear_ymd_hms_tz();
+            self.jd = (r + 0.5) as i64;
+            self.valid_jd = true;
+            self.raw = false;
+        }
+    }
+
+    // Applies the modifier `z`, the `index`th argument. Returns false if it
+    // is not a valid modifier.
+    fn modify(&mut self, z: &[u8], index: usize) -> Result<bool, Error> {
+        let is = |name: &str| z.eq_ignore_ascii_case(name.as_bytes());
+        match at(z, 0).to_ascii_lowercase() {
+            b'a' if is("auto") => {
+                if index > 1 {
+                    return Ok(false);
+                }
+                self.auto_adjust();
+            }
+            // rolling forward into the next month is the default anyway
+            b'c' if is("ceiling") => {
+                self.compute_jd();
+                self.clear_ymd_hms_tz();
+                self.floor = 0;
+            }
+            b'f' if is("floor") => {
+                self.compute_jd();
+                self.jd -= self.floor as i64 * 86400000;
+                self.clear_ymd_hms_tz();
+            }
+            b'j' if is("julianday") => {
+                if index > 1 || !(self.valid_jd && self.raw) {
+                    return Ok(false);
+                }
+                self.raw = false;
+            }
+            b'l' if is("localtime") => {
+                if !self.local {
+                    self.localize()?;
+                }
+                self.utc = false;
+                self.local = true;
+            }
+            b'u' if is("unixepoch") && self.raw => {
+                if index > 1 {
+                    return Ok(false);
+                }
+                let r = self.second * 1000.0 + 210866760000000.0;
+                if !(0.0..464269060800000.0).contains(&r) {
+                    return Ok(false);
+                }
+                self.clear_ymd_hms_tz();
+                self.jd = (r + 0.5) as i64;
+                self.valid_jd = true;
+                self.raw = false;
+            }
+            b'u' if is("utc") => {
+                if !self.utc {
+                    self.compute_jd();
+                    let original = self.jd;
+                    let mut guess = original;
+                    let mut error = 0;
+                    let mut count = 0;
+                    loop {
+                        guess -= error;
+                        let mut local = DateTime {
+                            jd: guess,
+                            valid_jd: true,
+                            ..DateTime::default()
+                        };
+                        local.localize()?;
+                        local.compute_jd();
+                        error = local.jd - original;
+                        let done = error == 0 || count >= 3;
+                        count += 1;
+                        if done {
+                            break;
+                        }
+                    }
+                    *self = DateTime {
+                        jd: guess,
+                        valid_jd: true,
+                        utc: true,
+                        ..DateTime::default()
+                    };
+                }
+            }
+            // "weekday N" moves forward to the next day that is weekday N,
+            // where 0 is Sunday
+            b'w' if z.len() >= 8 && z[..8].eq_ignore_ascii_case(b"weekday ") => {
+                let n = match atof(&z[8..]) {
+                    Some(r) if (0.0..7.0).contains(&r) && r.fract() == 0.0 => r as i64,
+                    _ => return Ok(false),
+                };
+                self.compute_ymd_hms();
+                self.tz = 0;
+                self.valid_jd = false;
+                self.compute_jd();
+                let mut weekday = ((self.jd + 129600000) / 86400000) % 7;
+                if weekday > n {
+                    weekday -= 7;
+                }
+                self.jd += (n - weekday) * 86400000;
+                self.clear_ymd_hms_tz();
+            }
+            b's' if z.len() >= 9 && z[..9].eq_ignore_ascii_case(b"start of ") => {
+                if !self.valid_jd && !self.valid_ymd && !self.valid_hms {
+                    return Ok(false);
+                }
+                self.compute_ymd();
+                self.valid_hms = true;
+                self.hour = 0;
+                self.minute = 0;
+                self.second = 0.0;
+                self.raw = false;
+                self.tz = 0;
+                self.valid_jd = false;
+                let unit = &z[9..];
+                if unit.eq_ignore_ascii_case(b"month") {
+                    self.day = 1;
+                } else if unit.eq_ignore_ascii_case(b"year") {
+                    self.month = 1;
+                    self.day = 1;
+                } else if !unit.eq_ignore_ascii_case(b"day") {
+                    return Ok(false);
+                }
+            }
+            b's' if is("subsec") || is("subsecond") => self.subsec = true,
+            b'+' | b'-' | b'0'..=b'9' => return Ok(self.shift(z)),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Applies one of the modifiers "±NNN units", "±HH:MM:SS.SSS" and
+    // "±YYYY-MM-DD HH:MM:SS.SSS". Returns false if `z` is none of them.
+    fn shift(&mut self, z: &[u8]) -> bool {
+        let sign = z[0];
+        let mut year = [0];
+        let mut n = 1;
+        while n < z.len() {
+            match z[n] {
+                b':' => break,
+                c if is_space(c) => break,
+                b'-' if n == 5 && get_digits(&z[1..], b"40f", &mut year) == 1 => break,
+                b'-' if n == 6 && get_digits(&z[1..], b"50f", &mut year) == 1 => break,
+                _ => n += 1,
+            }
+        }
+        let mut r = match atof(&z[..n]) {
+            Some(r) => r,
+            None => return false,
+        };
+        let mut time = z;
+        if at(z, n) == b'-' {
+            // ±YYYY-MM-DD adds whole years, months (0-11) and days (0-30)
+            if sign != b'+' && sign != b'-' {
+                return false;
+            }
+            let mut ymd = [0; 3];
+            let format: &[u8] = if n == 5 {
+                b"40f-20a-20d"
+            } else {
+                b"50f-20a-20d"
+            };
+            if get_digits(&z[1..], format, &mut ymd) != 3 {
+                return false;
+            }
+            let z = if n == 5 { z } else { &z[1..] };
+            let [years, months, mut days] = ymd;
+            if months >= 12 || days >= 31 {
+                return false;
+            }
+            self.compute_ymd_hms();
+            self.valid_jd = false;
+            if sign == b'-' {
+                self.year -= years;
+                self.month -= months;
+                days = -days;
+            } else {
+                self.year += years;
+                self.month += months;
+            }
+            self.normalize_month();
+            self.compute_floor();
+            self.compute_jd();
+            self.valid_hms = false;
+            self.valid_ymd = false;
+            self.jd += days as i64 * 86400000;
+            if at(z, 11) == 0 {
+                return true;
+            }
+            let mut hm = [0; 2];
+            if !is_space(at(z, 11)) || get_digits(tail(z, 12), b"20c:20e", &mut hm) != 2 {
+                return false;
+            }
+            time = &z[12..];
+            n = 2;
+        }
+        if at(time, n) == b':' {
+            // ±HH:MM:SS.SSS
+            let time = if time[0].is_ascii_digit() {
+                time
+            } else {
+                &time[1..]
+            };
+            let mut offset = DateTime::default();
+            if !offset.parse_hh_mm_ss(time) {
+                return false;
+            }
+            offset.compute_jd();
+            offset.jd -= 43200000;
+            offset.jd -= offset.jd / 86400000 * 86400000;
+            if sign == b'-' {
+                offset.jd = -offset.jd;
+            }
+            self.compute_jd();
+            self.clear_ymd_hms_tz();
+            self.jd += offset.jd;
+            return true;
+        }
+
+        // ±NNN units
+        const UNITS: [(&str, f32, f64); 6] = [
+            ("second", 4.6427e+14, 1.0),
+            ("minute", 7.7379e+12, 60.0),
+            ("hour", 1.2897e+11, 3600.0),
+            ("day", 5373485.0, 86400.0),
+            ("month", 176546.0, 2592000.0),
+            ("year", 14713.0, 31536000.0),
+        ];
+        let mut unit = skip_spaces(&z[n..]);
+        if unit.len() < 3 || unit.len() > 10 {
+            return false;
+        }
+        if unit[unit.len() - 1].eq_ignore_ascii_case(&b's') {
+            unit = &unit[..unit.len() - 1];
+        }
+        self.compute_jd();
+        let rounder = if r < 0.0 { -0.5 } else { 0.5 };
+        self.floor = 0;
+        let mut valid = false;
+        for (name, limit, seconds) in UNITS.iter() {
+            let limit = *limit as f64;
+            if !unit.eq_ignore_ascii_case(name.as_bytes()) || r <= -limit || r >= limit {
+                continue;
+            }
+            match *name {
+                "month" => {
+                    self.compute_ymd_hms();
+                    self.month += r as i32;
+                    self.normalize_month();
+                    self.compute_floor();
+                    self.valid_jd = false;
+                    r -= (r as i32) as f64;
+                }
+                "year" => {
+                    self.compute_ymd_hms();
+                    self.year += r as i32;
+                    self.compute_floor();
+                    self.valid_jd = false;
+                    r -= (r as i32) as f64;
+                }
+                _ => {}
+            }
+            self.compute_jd();
+            self.jd += (r * 1000.0 * seconds + rounder) as i64;
+            valid = true;
+            break;
+        }
+        self.clear_ymd_hms_tz();
+        valid
+    }
+
+    // Carries months outside of 1..=12 into the year.
+    fn normalize_month(&mut self) {
+        let x = if self.month > 0 {
+            (self.month - 1) / 12
+        } else {
+            (self.month - 12) / 12
+        };
+        self.year += x;
+        self.month -= x * 12;
+    }
+
+    fn days_after_jan01(&self) -> i64 {
+        let mut jan01 = *self;
+        jan01.valid_jd = false;
+        jan01.month = 1;
+        jan01.day = 1;
+        jan01.compute_jd();
+        (self.jd - jan01.jd + 43200000) / 86400000
+    }
+
+    fn days_after_monday(&self) -> i64 {
+        ((self.jd + 43200000) / 86400000) % 7
+    }
+
+    fn days_after_sunday(&self) -> i64 {
+        ((self.jd + 129600000) / 86400000) % 7
+    }
+
+    // The Thursday of the same ISO 8601 week.
+    fn thursday(&self) -> DateTime {
+        let mut thursday = *self;
+        thursday.jd += (3 - self.days_after_monday()) * 86400000;
+        thursday.valid_ymd = false;
+        thursday.compute_ymd();
+        thursday
+    }
+
+    fn unix_seconds(&self) -> SqlValue {
+        if self.subsec {
+            SqlValue::Real((self.jd - UNIX_EPOCH_JD) as f64 / 1000.0)
+        } else {
+            SqlValue::Integer(self.jd / 1000 - UNIX_EPOCH_JD / 1000)
+        }
+    }
+
+    fn date(&self) -> String {
+        let sign = if self.year < 0 { "-" } else { "" };
+        format!(
+            "{}{:04}-{:02}-{:02}",
+            sign,
+            self.year.abs() % 10000,
+            self.month,
+            self.day
+        )
+    }
+
+    fn time(&self) -> String {
+        let hm = format!("{:02}:{:02}:", self.hour, self.minute);
+        if self.subsec {
+            let ms = (1000.0 * self.second + 0.5) as i32;
+            format!("{}{:02}.{:03}", hm, (ms / 1000) % 100, ms % 1000)
+        } else {
+            format!("{}{:02}", hm, self.second as i32)
+        }
+    }
+}
+
+fn valid_julian_day(jd: i64) -> bool {
+    (0..=MAX_JD).contains(&jd)
+}
+
+struct LocalTime {
+    year: i32,
+    month: i32,
+    day: i32,
+    hour: i32,
+    minute: i32,
+    second: i32,
+}
+
+#[cfg(unix)]
+fn local_time(t: i64) -> Option<LocalTime> {
+    let t = t as libc::time_t;
+    // SAFETY: localtime_r() only writes to the tm it is given
+    let tm = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&t, &mut tm).is_null() {
+            return None;
+        }
+        tm
+    };
+    Some(LocalTime {
+        year: tm.tm_year + 1900,
+        month: tm.tm_mon + 1,
+        day: tm.tm_mday,
+        hour: tm.tm_hour,
+        minute: tm.tm_min,
+        second: tm.tm_sec,
+    })
+}
+
+// Without localtime() local time is taken to be UTC.
+#[cfg(not(unix))]
+fn local_time(t: i64) -> Option<LocalTime> {
+    let mut utc = DateTime {
+        jd: t * 1000 + UNIX_EPOCH_JD,
+        valid_jd: true,
+        ..DateTime::default()
+    };
+    utc.compute_ymd_hms();
+    Some(LocalTime {
+        year: utc.year,
+        month: utc.month,
+        day: utc.day,
+        hour: utc.hour,
+        minute: utc.minute,
+        second: utc.second as i32,
+    })
+}
+
+// The bytes of a text argument up to the first NUL, or None for NULL.
+fn text(value: &SqlValue) -> Option<Vec<u8>> {
+    let mut text = value.to_text()?.into_bytes();
+    if let Some(nul) = text.iter().position(|c| *c == 0) {
+        text.truncate(nul);
+    }
+    Some(text)
+}
+
+// Evaluates a time value followed by modifiers, or returns None if the
+// result is NULL. With no arguments the time is now.
+fn evaluate(args: &[SqlValue]) -> Result<Option<DateTime>, Error> {
+    let mut p = DateTime::default();
+    match args.first() {
+        None => p.set_now(),
+        Some(SqlValue::Null) => return Ok(None),
+        Some(SqlValue::Integer(i)) => p.set_raw_number(*i as f64),
+        Some(SqlValue::Real(f)) => p.set_raw_number(*f),
+        Some(value) => {
+            if !p.parse(&text(value).unwrap()) {
+                return Ok(None);
+            }
+        }
+    }
+    for (i, modifier) in args.iter().enumerate().skip(1) {
+        match text(modifier) {
+            Some(modifier) if p.modify(&modifier, i)? => {}
+            _ => return Ok(None),
+        }
+    }
+    p.compute_jd();
+    if p.error || !valid_julian_day(p.jd) {
+        return Ok(None);
+    }
+    // a lone YYYY-MM-DD is normalized, so 2023-02-31 becomes 2023-03-03
+    if args.len() == 1 && p.valid_ymd && p.day > 28 {
+        p.valid_ymd = false;
+    }
+    Ok(Some(p))
+}
+
+fn strftime(format: &[u8], mut x: DateTime) -> Option<String> {
+    x.compute_jd();
+    x.compute_ymd_hms();
+    let mut out = Vec::with_capacity(format.len() * 2);
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' {
+            out.push(format[i]);
+            i += 1;
+            continue;
+        }
+        let conversion = at(format, i + 1);
+        i += 2;
+        let converted = match conversion {
+            b'd' => format!("{:02}", x.day),
+            b'e' => format!("{:2}", x.day),
+            b'f' => printf::format("%06.3f", &[SqlValue::Real(x.second.min(59.999))]),
+            b'F' => format!("{:04}-{:02}-{:02}", x.year, x.month, x.day),
+            b'G' => format!("{:04}", x.thursday().year),
+            b'g' => format!("{:02}", x.thursday().year % 100),
+            b'H' => format!("{:02}", x.hour),
+            b'k' => format!("{:2}", x.hour),
+            b'I' | b'l' => {
+                let hour = match x.hour {
+                    0 => 12,
+                    h if h > 12 => h - 12,
+                    h => h,
+                };
+                if conversion == b'I' {
+                    format!("{:02}", hour)
+                } else {
+                    format!("{:2}", hour)
+                }
+            }
+            b'j' => format!("{:03}", x.days_after_jan01() + 1),
+            b'J' => printf::format("%.16g", &[SqlValue::Real(x.jd as f64 / 86400000.0)]),
+            b'm' => format!("{:02}", x.month),
+            b'M' => format!("{:02}", x.minute),
+            b'p' => (if x.hour >= 12 { "PM" } else { "AM" }).to_string(),
+            b'P' => (if x.hour >= 12 { "pm" } else { "am" }).to_string(),
+            b'R' => format!("{:02}:{:02}", x.hour, x.minute),
+            b's' => match x.unix_seconds() {
+                SqlValue::Real(s) => printf::format("%.3f", &[SqlValue::Real(s)]),
+                seconds => seconds.to_text().unwrap(),
+            },
+            b'S' => format!("{:02}", x.second as i32),
+            b'T' => format!("{:02}:{:02}:{:02}", x.hour, x.minute, x.second as i32),
+            b'u' => match x.days_after_sunday() {
+                0 => "7".to_string(),
+                d => d.to_string(),
+            },
+            b'w' => x.days_after_sunday().to_string(),
+            b'U' => format!(
+                "{:02}",
+                (x.days_after_jan01() - x.days_after_sunday() + 7) / 7
+            ),
+            b'V' => format!("{:02}", x.thursday().days_after_jan01() / 7 + 1),
+            b'W' => format!(
+                "{:02}",
+                (x.days_after_jan01() - x.days_after_monday() + 7) / 7
+            ),
+            b'Y' => format!("{:04}", x.year),
+            b'%' => "%".to_string(),
+            _ => return None,
+        };
+        out.extend_from_slice(converted.as_bytes());
+    }
+    Some(String::from_utf8_lossy(&out).into_owned())
+}
+
+// The time from `from` to `to` as "±YYYY-MM-DD HH:MM:SS.SSS".
+fn timediff(mut to: DateTime, mut from: DateTime) -> String {
+    to.compute_ymd_hms();
+    from.compute_ymd_hms();
+    let sign;
+    let mut years;
+    let mut months;
+    if to.jd >= from.jd {
+        sign = '+';
+        years = to.year - from.year;
+        if years != 0 {
+            from.year = to.year;
+            from.valid_jd = false;
+            from.compute_jd();
+        }
+        months = to.month - from.month;
+        if months < 0 {
+            years -= 1;
+            months += 12;
+        }
+        if months != 0 {
+            from.month = to.month;
+            from.valid_jd = false;
+            from.compute_jd();
+        }
+        while to.jd < from.jd {
+            months -= 1;
+            if months < 0 {
+                months = 11;
+                years -= 1;
+            }
+            from.month -= 1;
+            if from.month < 1 {
+                from.month = 12;
+                from.year -= 1;
+            }
+            from.valid_jd = false;
+            from.compute_jd();
+        }
+        to.jd -= from.jd;
+    } else {
+        sign = '-';
+        years = from.year - to.year;
+        if years != 0 {
+            from.year = to.year;
+            from.valid_jd = false;
+            from.compute_jd();
+        }
+        months = from.month - to.month;
+        if months < 0 {
+            years -= 1;
+            months += 12;
+        }
+        if months != 0 {
+            from.month = to.month;
+            from.valid_jd = false;
+            from.compute_jd();
+        }
+        while to.jd > from.jd {
+            months -= 1;
+            if months < 0 {
+                months = 11;
+                years -= 1;
+            }
+            from.month += 1;
+            if from.month > 12 {
+                from.month = 1;
+                from.year += 1;
+            }
+            from.valid_jd = false;
+            from.compute_jd();
+        }
+        to.jd = from.jd - to.jd;
+    }
+    // the difference as a time on 0000-01-01, so the day counts from 1
+    to.jd += 148699540800000;
+    to.clear_ymd_hms_tz();
+    to.compute_ymd_hms();
+    let seconds = printf::format("%06.3f", &[SqlValue::Real(to.second)]);
+    format!(
+        "{}{:04}-{:02}-{:02} {:02}:{:02}:{}",
+        sign,
+        years,
+        months,
+        to.day - 1,
+        to.hour,
+        to.minute,
+        seconds
+    )
+}
+
+// The number of arguments each function takes.
+pub fn arity(name: &str) -> Option<std::ops::RangeInclusive<usize>> {
+    Some(match name {
+        "date" | "time" | "datetime" | "julianday" | "unixepoch" | "strftime" => 0..=usize::MAX,
+        "timediff" => 2..=2,
+        _ => return None,
+    })
+}
+
+// Calls the date and time function `name`, whose arguments have been
+// checked against arity().
+pub fn call(name: &str, args: &[SqlValue]) -> Result<SqlValue, Error> {
+    if name == "strftime" {
+        let format = match args.first().and_then(text) {
+            Some(format) => format,
+            None => return Ok(SqlValue::Null),
+        };
+        return Ok(match evaluate(&args[1..])? {
+            Some(x) => strftime(&format, x).map_or(SqlValue::Null, SqlValue::Text),
+            None => SqlValue::Null,
+        });
+    }
+    if name == "timediff" {
+        return Ok(match (evaluate(&args[..1])?, evaluate(&args[1..])?) {
+            (Some(to), Some(from)) => SqlValue::Text(timediff(to, from)),
+            _ => SqlValue::Null,
+        });
+    }
+    let mut x = match evaluate(args)? {
+        Some(x) => x,
+        None => return Ok(SqlValue::Null),
+    };
+    x.compute_ymd_hms();
+    Ok(match name {
+        "date" => SqlValue::Text(x.date()),
+        "time" => SqlValue::Text(x.time()),
+        "datetime" => SqlValue::Text(format!("{} {}", x.date(), x.time())),
+        "julianday" => SqlValue::Real(x.jd as f64 / 86400000.0),
+        _ => x.unix_seconds(),
+    })
+}
+
+// The value of the CURRENT_DATE, CURRENT_TIME and CURRENT_TIMESTAMP
+// keywords, or None if `keyword` is none of them.
+pub fn current(keyword: &str) -> Option<SqlValue> {
+    let name = match keyword.to_ascii_lowercase().as_str() {
+        "current_date" => "date",
+        "current_time" => "time",
+        "current_timestamp" => "datetime",
+        _ => return None,
+    };
+    call(name, &[]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_of(name: &str, args: &[&str]) -> Option<String> {
+        let args: Vec<_> = args.iter().map(|a| SqlValue::from(*a)).collect();
+        call(name, &args).unwrap().to_text()
+    }
+
+    #[test]
+    fn test_parse() {
+        let date = |s: &str| text_of("datetime", &[s]);
+        assert_eq!(date("2009-01-01 00:00:00").unwrap(), "2009-01-01 00:00:00");
+        assert_eq!(date("2009-01-01T10:20").unwrap(), "2009-01-01 10:20:00");
+        assert_eq!(date("2023-02-31").unwrap(), "2023-03-03 00:00:00");
+        assert_eq!(
+            date("2000-01-01 12:00 +05:30").unwrap(),
+            "2000-01-01 06:30:00"
+        );
+        assert_eq!(date("12:30:45.5Z").unwrap(), "2000-01-01 12:30:45");
+        assert_eq!(date("2451545").unwrap(), "2000-01-01 12:00:00");
+        assert_eq!(date("2009-13-01"), None);
+        assert_eq!(date("2009-01-01 25:00"), None);
+        assert_eq!(date("tomorrow"), None);
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let date = |args: &[&str]| text_of("datetime", args);
+        assert_eq!(
+            date(&["2009-01-31", "+1 month"]).unwrap(),
+            "2009-03-03 00:00:00"
+        );
+        assert_eq!(
+            date(&["2009-01-31", "+1 month", "floor"]).unwrap(),
+            "2009-02-28 00:00:00"
+        );
+        assert_eq!(
+            date(&["2009-05-17 13:14:15", "start of month", "-1 day"]).unwrap(),
+            "2009-04-30 00:00:00"
+        );
+        assert_eq!(
+            date(&["2009-05-17", "weekday 0"]).unwrap(),
+            "2009-05-17 00:00:00"
+        );
+        assert_eq!(
+            date(&["2009-05-17", "weekday 1"]).unwrap(),
+            "2009-05-18 00:00:00"
+        );
+        assert_eq!(
+            date(&["2009-05-17", "-0001-02-03 04:05"]).unwrap(),
+            "2008-03-13 19:55:00"
+        );
+        assert_eq!(
+            date(&["2009-05-17", "+12:30:15.5", "subsec"]).unwrap(),
+            "2009-05-17 12:30:15.500"
+        );
+        assert_eq!(
+            date(&["1234567890", "unixepoch"]).unwrap(),
+            "2009-02-13 23:31:30"
+        );
+        assert_eq!(
+            date(&["1234567890", "auto"]).unwrap(),
+            "2009-02-13 23:31:30"
+        );
+        assert_eq!(date(&["2009-05-17", "unixepoch"]), None);
+        assert_eq!(date(&["2009-05-17", "+1 fortnight"]), None);
+    }
+
+    #[test]
+    fn test_strftime() {
+        let strftime = |format: &str| text_of("strftime", &[format, "2009-01-04 15:06:07.25"]);
+        assert_eq!(
+            strftime("%Y-%m-%d %H:%M:%f %j %w %u").unwrap(),
+            "2009-01-04 15:06:07.250 004 0 7"
+        );
+        assert_eq!(strftime("%U %W %V %G %g").unwrap(), "01 00 01 2009 09");
+        assert_eq!(
+            strftime("%I%p %l%P %e %k %s").unwrap(),
+            "03PM  3pm  4 15 1231081567"
+        );
+        assert_eq!(strftime("%J").unwrap(), "2454836.129250579");
+        assert_eq!(strftime("%Q"), None);
+        assert_eq!(strftime("100%"), None);
+    }
+
+    #[test]
+    fn test_timediff() {
+        assert_eq!(
+            text_of("timediff", &["2024-03-01", "2023-02-28 12:00"]).unwrap(),
+            "+0001-00-01 12:00:00.000"
+        );
+        assert_eq!(
+            text_of("timediff", &["2023-02-28 12:00", "2024-03-01"]).unwrap(),
+            "-0001-00-00 12:00:00.000"
+        );
+    }
+}
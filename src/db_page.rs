@@ -13,6 +13,13 @@ use std::io::{prelude::*, SeekFrom};
 pub struct DBHeader {
     pub page_size_in_bytes: u16,
     pub size_of_db_in_pages: u32,
+    pub reserved_space: u8,
+    // Bumped by every write, so readers can tell their cached pages are
+    // stale.
+    pub change_counter: u32,
+    // The change counter of the write that last set the page count. The
+    // page count is only valid when the two agree.
+    pub version_valid_for: u32,
 }
 
 impl DBHeader {
@@ -29,12 +36,20 @@ impl DBHeader {
                 let header = DBHeader {
                     page_size_in_bytes: util::as_u16_be(&page_size_array),
                     size_of_db_in_pages: util::as_u32_be(&page_count_array),
+                    reserved_space: header[20],
+                    change_counter: util::as_u32_be(&header[24..28].try_into().unwrap()),
+                    version_valid_for: util::as_u32_be(&header[92..96].try_into().unwrap()),
                 };
                 Ok(header)
             }
             Err(e) => Err(e),
         }
     }
+
+    // The usable size of a page excludes the reserved space at the end of it.
+    pub fn usable_size(&self) -> usize {
+        usize::from(self.page_size_in_bytes) - usize::from(self.reserved_space)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -46,6 +61,17 @@ pub enum DBPageType {
 }
 
 impl DBPageType {
+    pub fn is_leaf(&self) -> bool {
+        matches!(self, DBPageType::IndexLeafPage | DBPageType::TableLeafPage)
+    }
+
+    pub fn is_index(&self) -> bool {
+        matches!(
+            self,
+            DBPageType::IndexLeafPage | DBPageType::IndexInteriorPage
+        )
+    }
+
     pub fn from_u8(b: u8) -> Result<DBPageType, Error> {
         match b {
             0x02 => Ok(DBPageType::IndexInteriorPage),
@@ -62,6 +88,7 @@ pub struct DBPage {
     pub page_type: DBPageType,
     pub number_of_cells: u16,
     pub cell_pointer_array: Vec<u16>,
+    pub right_most_pointer: Option<u32>,
     pub raw_bytes: Vec<u8>,
 }
 
@@ -72,9 +99,24 @@ pub enum PageCell {
         row_id: u64,
         values: record::Record,
     },
+    TableInteriorPageCell {
+        left_child_page: u32,
+        row_id: u64,
+    },
+    IndexLeafPageCell {
+        length: u64,
+        values: record::Record,
+    },
+    IndexInteriorPageCell {
+        left_child_page: u32,
+        length: u64,
+        values: record::Record,
+    },
 }
 
 impl PageCell {
+    // Decodes a cell whose payload is stored entirely on the page. Cells that
+    // spill onto overflow pages have to be assembled through the pager instead.
     pub fn from_bytes(page_type: &DBPageType, bytes: &[u8]) -> Option<PageCell> {
         match page_type {
             DBPageType::TableLeafPage => {
@@ -90,11 +132,67 @@ impl PageCell {
                     values: r,
                 })
             }
-            _ => None,
+            DBPageType::TableInteriorPage => {
+                let left_child_page = util::as_u32_be(&bytes[0..4].try_into().unwrap());
+                let (row_id, _) = varint::read_varint(&bytes[4..]);
+                Some(PageCell::TableInteriorPageCell {
+                    left_child_page,
+                    row_id: row_id as u64,
+                })
+            }
+            DBPageType::IndexLeafPage => {
+                let (length, length_size_in_bytes) = varint::read_varint(bytes);
+                let record = record::Record::from_cell_bytes(&bytes[length_size_in_bytes..]);
+                record.map(|r| PageCell::IndexLeafPageCell {
+                    length: length as u64,
+                    values: r,
+                })
+            }
+            DBPageType::IndexInteriorPage => {
+                let left_child_page = util::as_u32_be(&bytes[0..4].try_into().unwrap());
+                let (length, length_size_in_bytes) = varint::read_varint(&bytes[4..]);
+                let record = record::Record::from_cell_bytes(&bytes[4 + length_size_in_bytes..]);
+                record.map(|r| PageCell::IndexInteriorPageCell {
+                    left_child_page,
+                    length: length as u64,
+                    values: r,
+                })
+            }
         }
     }
 }
 
+// Where the pieces of a cell live on its page, see "B-tree Pages" in
+// https://www.sqlite.org/fileformat2.html
+#[derive(Debug)]
+pub struct CellLayout {
+    pub left_child_page: Option<u32>,
+    pub row_id: Option<i64>,
+    pub payload_size: usize,
+    pub payload_start: usize,
+    pub local_size: usize,
+    pub first_overflow_page: Option<u32>,
+}
+
+// Number of payload bytes kept on the b-tree page itself; the rest goes to
+// the overflow chain.
+pub fn local_payload_size(page_type: DBPageType, usable_size: usize, payload_size: usize) -> usize {
+    let max_local = match page_type {
+        DBPageType::TableLeafPage => usable_size - 35,
+        _ => ((usable_size - 12) * 64 / 255) - 23,
+    };
+    if payload_size <= max_local {
+        return payload_size;
+    }
+    let min_local = ((usable_size - 12) * 32 / 255) - 23;
+    let k = min_local + ((payload_size - min_local) % (usable_size - 4));
+    if k <= max_local {
+        k
+    } else {
+        min_local
+    }
+}
+
 impl fmt::Debug for DBPage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -136,7 +234,7 @@ impl DBPage {
             let end = usize::from(start_offset + ((i + 1) * 2));
 
             let cell_pointer_array_array: &[u8; 2] = raw_data[start..end].try_into().unwrap();
-            let cell_pointer_array_value = util::as_u16_be(&cell_pointer_array_array);
+            let cell_pointer_array_value = util::as_u16_be(cell_pointer_array_array);
             cell_pointer_array[usize::from(i)] = cell_pointer_array_value;
         }
 
@@ -162,11 +260,18 @@ impl DBPage {
         let cell_pointer_array =
             DBPage::get_cell_pointer_array(&raw_bytes, page_type, number_of_cells, page_no == 1);
 
+        let right_most_pointer = if page_type.is_leaf() {
+            None
+        } else {
+            Some(util::as_u32_be(&page_header[8..12].try_into().unwrap()))
+        };
+
         Ok(DBPage {
             page_no,
             page_type,
             number_of_cells,
             cell_pointer_array,
+            right_most_pointer,
             raw_bytes,
         })
     }
@@ -180,11 +285,7 @@ impl DBPage {
                 ErrorKind::InvalidData,
                 "Table interior page does not have cell length",
             )),
-            DBPageType::IndexLeafPage => Err(Error::new(
-                ErrorKind::InvalidData,
-                "Index leaf page does not have cell length",
-            )),
-            DBPageType::TableLeafPage => {
+            DBPageType::IndexLeafPage | DBPageType::TableLeafPage => {
                 let cell_length_start = self.cell_pointer_array[usize::from(cell_no)];
                 let (result, _) =
                     varint::read_varint(&self.raw_bytes[usize::from(cell_length_start)..]);
@@ -193,6 +294,94 @@ impl DBPage {
         }
     }
 
+    pub fn get_cell_layout(&self, cell_no: u16, usable_size: usize) -> CellLayout {
+        let start = usize::from(self.cell_pointer_array[usize::from(cell_no)]);
+        let bytes = &self.raw_bytes[start..];
+        let mut cursor = 0;
+
+        let left_child_page = if self.page_type.is_leaf() {
+            None
+        } else {
+            cursor += 4;
+            Some(util::as_u32_be(&bytes[0..4].try_into().unwrap()))
+        };
+
+        if let DBPageType::TableInteriorPage = self.page_type {
+            let (row_id, _) = varint::read_varint(&bytes[cursor..]);
+            return CellLayout {
+                left_child_page,
+                row_id: Some(row_id),
+                payload_size: 0,
+                payload_start: start + cursor,
+                local_size: 0,
+                first_overflow_page: None,
+            };
+        }
+
+        let (payload_size, payload_size_in_bytes) = varint::read_varint(&bytes[cursor..]);
+        cursor += payload_size_in_bytes;
+        let row_id = if let DBPageType::TableLeafPage = self.page_type {
+            let (row_id, row_id_size_in_bytes) = varint::read_varint(&bytes[cursor..]);
+            cursor += row_id_size_in_bytes;
+            Some(row_id)
+        } else {
+            None
+        };
+
+        let payload_size = payload_size as usize;
+        let local_size = local_payload_size(self.page_type, usable_size, payload_size);
+        let first_overflow_page = if local_size < payload_size {
+            let at = cursor + local_size;
+            Some(util::as_u32_be(&bytes[at..at + 4].try_into().unwrap()))
+        } else {
+            None
+        };
+
+        CellLayout {
+            left_child_page,
+            row_id,
+            payload_size,
+            payload_start: start + cursor,
+            local_size,
+            first_overflow_page,
+        }
+    }
+
+    // The bytes of the page no cell uses: the gap between the cell pointers
+    // and the cell content, the freeblocks left by deleted cells, and the
+    // fragments too small to be freeblocks.
+    pub fn unused_bytes(&self) -> usize {
+        let header = if self.page_no == 1 { 100 } else { 0 };
+        let header_size = if self.page_type.is_leaf() { 8 } else { 12 };
+        let bytes = &self.raw_bytes;
+        let pointers_end = header + header_size + 2 * usize::from(self.number_of_cells);
+        let content_start =
+            match util::as_u16_be(&bytes[header + 5..header + 7].try_into().unwrap()) {
+                0 => 65536,
+                n => usize::from(n),
+            };
+        let mut unused =
+            content_start.saturating_sub(pointers_end) + usize::from(bytes[header + 7]);
+
+        // freeblocks come in the order of their offsets
+        let mut freeblock = usize::from(util::as_u16_be(
+            &bytes[header + 1..header + 3].try_into().unwrap(),
+        ));
+        while freeblock != 0 && freeblock + 4 <= bytes.len() {
+            unused += usize::from(util::as_u16_be(
+                &bytes[freeblock + 2..freeblock + 4].try_into().unwrap(),
+            ));
+            let next = usize::from(util::as_u16_be(
+                &bytes[freeblock..freeblock + 2].try_into().unwrap(),
+            ));
+            if next <= freeblock {
+                break;
+            }
+            freeblock = next;
+        }
+        unused
+    }
+
     pub fn get_cell(&self, cell_no: u16) -> Option<PageCell> {
         if cell_no >= self.number_of_cells {
             return None;
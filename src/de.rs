@@ -0,0 +1,389 @@
+use crate::connection::{Connection, Params, Row, Statement};
+use crate::value::SqlValue;
+use serde::de::value::{SeqDeserializer, StrDeserializer};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+// Reads a result row into any type that implements Deserialize: structs and
+// maps by column name, tuples and sequences by column order, and a single
+// column into a plain value. NULL is None, and a BLOB reads as bytes.
+pub fn from_row<T: DeserializeOwned>(row: &Row) -> Result<T, io::Error> {
+    T::deserialize(RowDeserializer { row }).map_err(|e| io::Error::new(ErrorKind::InvalidData, e.0))
+}
+
+impl Row {
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, io::Error> {
+        from_row(self)
+    }
+}
+
+impl Connection {
+    // Runs a query and reads every row into a `T`.
+    pub fn query_as<T: DeserializeOwned>(&self, sql: &str) -> Result<Vec<T>, io::Error> {
+        self.prepare(sql)?.query_as(())
+    }
+}
+
+impl<'c> Statement<'c> {
+    pub fn query_as<T, P>(&mut self, params: P) -> Result<Vec<T>, io::Error>
+    where
+        T: DeserializeOwned,
+        P: Params,
+    {
+        self.query_map(params, from_row)?.collect()
+    }
+}
+
+// serde's errors only need to carry a message, which becomes an io::Error
+// of kind InvalidData.
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Error {
+        Error(message.to_string())
+    }
+}
+
+pub struct RowDeserializer<'r> {
+    row: &'r Row,
+}
+
+impl<'r> RowDeserializer<'r> {
+    pub fn new(row: &'r Row) -> RowDeserializer<'r> {
+        RowDeserializer { row }
+    }
+
+    fn single(&self) -> Result<ValueDeserializer<'r>, Error> {
+        match self.row.values() {
+            [value] => Ok(ValueDeserializer { value }),
+            values => Err(Error(format!(
+                "expected a single column, found {}",
+                values.len()
+            ))),
+        }
+    }
+}
+
+// Plain values come from the only column of the row.
+macro_rules! forward_to_single_column {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                self.single()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de, 'r> de::Deserializer<'de> for RowDeserializer<'r> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(ColumnAccess {
+            columns: self.row.columns(),
+            values: self.row.values(),
+            next: 0,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let values = self
+            .row
+            .values()
+            .iter()
+            .map(|value| ValueDeserializer { value });
+        visitor.visit_seq(SeqDeserializer::new(values))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.single()?.deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_single_column! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_i128 deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_u128 deserialize_f32 deserialize_f64 deserialize_char deserialize_str
+        deserialize_string deserialize_bytes deserialize_byte_buf deserialize_option
+        deserialize_unit deserialize_identifier deserialize_ignored_any
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.single()?.deserialize_unit(visitor)
+    }
+}
+
+// The columns of a row as the entries of a map.
+struct ColumnAccess<'r> {
+    columns: &'r [String],
+    values: &'r [SqlValue],
+    next: usize,
+}
+
+impl<'de, 'r> de::MapAccess<'de> for ColumnAccess<'r> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.columns.get(self.next) {
+            Some(column) => seed.deserialize(ColumnName(column)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = &self.values[self.next];
+        self.next += 1;
+        seed.deserialize(ValueDeserializer { value })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.columns.len() - self.next)
+    }
+}
+
+// A column name as a map key. Names live as long as the row rather than the
+// input, so they cannot be borrowed by the result.
+struct ColumnName<'r>(&'r str);
+
+impl<'de, 'r> de::Deserializer<'de> for ColumnName<'r> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_str(self.0)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+// A value of a column: INTEGER, REAL, TEXT and BLOB read as i64, f64, str
+// and bytes, and NULL as unit or None.
+pub struct ValueDeserializer<'r> {
+    value: &'r SqlValue,
+}
+
+impl<'r> ValueDeserializer<'r> {
+    pub fn new(value: &'r SqlValue) -> ValueDeserializer<'r> {
+        ValueDeserializer { value }
+    }
+}
+
+impl<'de, 'r> IntoDeserializer<'de, Error> for ValueDeserializer<'r> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de, 'r> de::Deserializer<'de> for ValueDeserializer<'r> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            SqlValue::Null => visitor.visit_unit(),
+            SqlValue::Integer(i) => visitor.visit_i64(*i),
+            SqlValue::Real(f) => visitor.visit_f64(*f),
+            SqlValue::Text(s) => visitor.visit_str(s),
+            SqlValue::Blob(b) => visitor.visit_bytes(b),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            SqlValue::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    // SQLite has no booleans; they are stored as the integers 0 and 1
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            SqlValue::Integer(i) => visitor.visit_bool(*i != 0),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    // a BLOB also reads as a sequence of bytes, such as a Vec<u8>
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            SqlValue::Blob(b) => visitor.visit_seq(SeqDeserializer::new(b.iter().copied())),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // unit variants of an enum are read from their names
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.value {
+            SqlValue::Text(s) => visitor.visit_enum(StrDeserializer::<Error>::new(s)),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    fn row(columns: &[&str], values: Vec<SqlValue>) -> Row {
+        let columns: Vec<String> = columns.iter().map(|c| c.to_string()).collect();
+        Row::new(Rc::from(columns), values)
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum Kind {
+        Audio,
+        Video,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Media {
+        id: u32,
+        #[serde(rename = "Title")]
+        title: String,
+        length: f64,
+        kind: Kind,
+        cover: Option<Vec<u8>>,
+        explicit: bool,
+        #[serde(default)]
+        rating: Option<i64>,
+    }
+
+    #[test]
+    fn test_struct_from_row() {
+        let media: Media = from_row(&row(
+            &["id", "Title", "length", "kind", "cover", "explicit"],
+            vec![
+                SqlValue::Integer(7),
+                SqlValue::Text("Intro".into()),
+                SqlValue::Integer(90),
+                SqlValue::Text("Video".into()),
+                SqlValue::Blob(vec![1, 2]),
+                SqlValue::Integer(0),
+            ],
+        ))
+        .unwrap();
+        assert_eq!(
+            media,
+            Media {
+                id: 7,
+                title: "Intro".into(),
+                length: 90.0,
+                kind: Kind::Video,
+                cover: Some(vec![1, 2]),
+                explicit: false,
+                rating: None,
+            }
+        );
+
+        let error = from_row::<Media>(&row(&["id"], vec![SqlValue::Integer(-1)])).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("-1"), "{}", error);
+        let error = from_row::<Media>(&row(&["id"], vec![SqlValue::Integer(1)])).unwrap_err();
+        assert_eq!(error.to_string(), "missing field `Title`");
+        assert_eq!(
+            Kind::Audio,
+            from_row(&row(&["k"], vec![SqlValue::Text("Audio".into())])).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_other_shapes() {
+        let r = row(&["a", "b"], vec![SqlValue::Null, SqlValue::Real(1.5)]);
+        let tuple: (Option<String>, f64) = from_row(&r).unwrap();
+        assert_eq!(tuple, (None, 1.5));
+        let map: BTreeMap<String, Option<f64>> = from_row(&r).unwrap();
+        assert_eq!(map["b"], Some(1.5));
+        assert_eq!(
+            from_row::<f64>(&r).unwrap_err().to_string(),
+            "expected a single column, found 2"
+        );
+        let count: i64 = from_row(&row(&["count(*)"], vec![SqlValue::Integer(3)])).unwrap();
+        assert_eq!(count, 3);
+    }
+}
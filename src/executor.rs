@@ -0,0 +1,5863 @@
+use crate::aggregate;
+use crate::aggregate::{Accumulator, Aggregate};
+use crate::btree;
+use crate::btree::{count_entries, BTreeCursor};
+use crate::expr;
+use crate::expr::{Collation, Collations, Functions, GroupKey, Operand, Scope};
+use crate::json;
+use crate::pager::Pager;
+use crate::planner;
+use crate::planner::{Access, Estimate, KeyRange, QueryPlan, Statistics};
+use crate::record::Record;
+use crate::scalar::FunctionFlags;
+use crate::schema::{Column, Index, Schema, Table};
+use crate::sorter::{compare_rows, SortKey, Sorter, DEFAULT_SORT_BUDGET};
+use crate::sql_parser;
+use crate::value::{Affinity, SqlValue, Value};
+use crate::vdbe;
+use crate::vdbe::{Builder, Opcode, Program, Vm, P4};
+use crate::window;
+use crate::window::WindowRow;
+use sqlparser::ast;
+use sqlparser::ast::{
+    BinaryOperator, Expr, FunctionArg, Ident, JoinConstraint, JoinOperator, SelectItem, SetExpr,
+    Statement, TableFactor,
+};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::rc::Rc;
+
+#[derive(Debug)]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<SqlValue>>,
+}
+
+// The result of a query used inside another one. Expressions that use its
+// columns also need their affinity and collation.
+#[derive(Debug)]
+pub struct Relation {
+    pub columns: Vec<String>,
+    pub types: Vec<(Affinity, Option<Collation>)>,
+    pub rows: Vec<Vec<SqlValue>>,
+}
+
+// A row of a table in declaration order, together with its rowid.
+#[derive(Debug, Clone)]
+pub struct TableRow {
+    pub row_id: Option<i64>,
+    pub values: Vec<SqlValue>,
+}
+
+pub struct Executor<'a> {
+    pager: &'a Pager,
+    schema: &'a Schema,
+    sort_budget: usize,
+    // The scope of the enclosing query when running a subquery, which
+    // resolves the column references the subquery cannot.
+    outer: Option<&'a dyn Scope>,
+    // The results of the subqueries of the current statement that do not
+    // depend on an enclosing query.
+    subqueries: Rc<RefCell<HashMap<ast::Query, Rc<Relation>>>>,
+    // The innermost common table expression in scope.
+    ctes: Option<Rc<CommonTable>>,
+    collations: Rc<Collations>,
+    functions: Rc<Functions>,
+    // The values bound to the parameters of the statement, from ?1 on.
+    parameters: Rc<Vec<SqlValue>>,
+    statistics: Rc<Statistics<'a>>,
+}
+
+pub type Rows<'a> = Box<dyn Iterator<Item = Result<TableRow, Error>> + 'a>;
+
+// `column = value` pairs, where a column of None is the rowid, and the
+// collation the comparison uses, which a key must be ordered by to find the
+// rows.
+type Constraints = Vec<(Option<usize>, SqlValue, Collation)>;
+
+// Receives the rows of a join, one row per table, and returns whether it
+// wants more.
+type Visit<'v> = dyn FnMut(&[Option<TableRow>]) -> Result<bool, Error> + 'v;
+
+// One term of an ORDER BY clause. Terms naming a result column, by alias or
+// by position, reuse the value computed for it.
+struct OrderTerm {
+    expr: Expr,
+    result_column: Option<usize>,
+    key: SortKey,
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("unsupported: {}", what))
+}
+
+fn is_rowid_name(name: &str) -> bool {
+    ["rowid", "_rowid_", "oid"]
+        .iter()
+        .any(|n| n.eq_ignore_ascii_case(name))
+}
+
+impl<'a> Executor<'a> {
+    pub fn new(pager: &'a Pager, schema: &'a Schema) -> Executor<'a> {
+        Executor {
+            pager,
+            schema,
+            sort_budget: DEFAULT_SORT_BUDGET,
+            outer: None,
+            subqueries: Rc::new(RefCell::new(HashMap::new())),
+            ctes: None,
+            collations: Rc::new(Collations::new()),
+            functions: Rc::new(Functions::new()),
+            parameters: Rc::new(Vec::new()),
+            statistics: Rc::new(Statistics::new(pager, schema)),
+        }
+    }
+
+    // Sets how many bytes of rows a sort keeps in memory before it spills
+    // sorted runs to temporary files.
+    pub fn set_sort_budget(&mut self, bytes: usize) {
+        self.sort_budget = bytes;
+    }
+
+    // Makes a user-defined collation available to COLLATE clauses and to
+    // the columns and indexes of the schema that name it.
+    pub fn register_collation<F>(&mut self, name: &str, compare: F)
+    where
+        F: Fn(&str, &str) -> Ordering + 'static,
+    {
+        Rc::make_mut(&mut self.collations).register(name, compare);
+    }
+
+    // Makes a scalar function available to queries. `arguments` is the
+    // number of arguments it takes, or None for any number.
+    pub fn register_scalar<F>(
+        &mut self,
+        name: &str,
+        arguments: Option<usize>,
+        flags: FunctionFlags,
+        function: F,
+    ) where
+        F: Fn(&[SqlValue]) -> Result<SqlValue, Error> + 'static,
+    {
+        Rc::make_mut(&mut self.functions).register_scalar(name, arguments, flags, function);
+    }
+
+    pub fn register_aggregate<A>(
+        &mut self,
+        name: &str,
+        arguments: Option<usize>,
+        flags: FunctionFlags,
+        aggregate: A,
+    ) where
+        A: Aggregate + 'static,
+    {
+        Rc::make_mut(&mut self.functions).register_aggregate(name, arguments, flags, aggregate);
+    }
+
+    // Uses the collations of another executor, or of a connection.
+    pub fn set_collations(&mut self, collations: Rc<Collations>) {
+        self.collations = collations;
+    }
+
+    // Uses the functions of another executor, or of a connection.
+    pub fn set_functions(&mut self, functions: Rc<Functions>) {
+        self.functions = functions;
+    }
+
+    // Binds values to the parameters of the statements this executes, the
+    // first to ?1.
+    pub fn set_parameters(&mut self, values: Vec<SqlValue>) {
+        self.parameters = Rc::new(values);
+    }
+
+    pub fn execute(&self, statement: &Statement) -> Result<ResultSet, Error> {
+        if let Some(statement) = sql_parser::query_plan(statement) {
+            return Ok(ResultSet {
+                columns: QueryPlan::columns(),
+                rows: self.query_plan(statement)?.rows(),
+            });
+        }
+        if let Some(statement) = sql_parser::explain(statement) {
+            return Ok(ResultSet {
+                columns: Program::columns(),
+                rows: self.program(statement)?.rows(),
+            });
+        }
+        match statement {
+            Statement::Query(query) => {
+                self.subqueries.borrow_mut().clear();
+                let relation = self.query(query)?;
+                Ok(ResultSet {
+                    columns: relation.columns,
+                    rows: relation.rows,
+                })
+            }
+            Statement::Analyze { table_name, .. } => {
+                self.analyze(table_name)?;
+                Ok(ResultSet {
+                    columns: Vec::new(),
+                    rows: Vec::new(),
+                })
+            }
+            _ => Err(unsupported(
+                "only SELECT and ANALYZE statements can be executed",
+            )),
+        }
+    }
+
+    // Runs a statement, producing its rows as they are asked for. A
+    // compiled SELECT runs a step of its program for each row; other
+    // statements produce all their rows up front.
+    pub fn query_rows(self, statement: &Statement) -> Result<QueryRows<'a>, Error> {
+        let query = match statement {
+            Statement::Query(query) => query,
+            _ => {
+                let result = self.execute(statement)?;
+                return Ok(QueryRows::materialized(Relation {
+                    columns: result.columns,
+                    types: Vec::new(),
+                    rows: result.rows,
+                }));
+            }
+        };
+        self.subqueries.borrow_mut().clear();
+        let select = match &query.body {
+            SetExpr::Select(select) if query.with.is_none() => select,
+            _ => return Ok(QueryRows::materialized(self.query(query)?)),
+        };
+        let plan = self.plan_select(query, select)?;
+        let program = match self.compile_select(select, &plan)? {
+            Some(program) => program,
+            None => return Ok(QueryRows::materialized(self.run_select(select, plan)?)),
+        };
+        let rows = ProgramRows {
+            vm: Vm::new(&program),
+            program,
+            executor: self,
+            index_cursors: index_cursors(&plan.from),
+            from: plan.from,
+            aggregates: plan.aggregates,
+            failed: false,
+        };
+        Ok(QueryRows {
+            columns: plan.columns,
+            rows: RowSource::Program(Box::new(rows)),
+        })
+    }
+
+    // Runs ANALYZE on a table, on an index, or on every table of the
+    // database: counts the entries of each index, and how many of them share
+    // each value of the first one, two and more of its columns, and records
+    // that in `sqlite_stat1` as SQLite does, creating the table if needed.
+    // Tables without an index get their number of rows instead. SQLite's
+    // optional `sqlite_stat4` samples are not collected.
+    fn analyze(&self, name: &ast::ObjectName) -> Result<(), Error> {
+        let name = match name.0.as_slice() {
+            [database] if database.value.eq_ignore_ascii_case("main") => None,
+            [name] => Some(&name.value),
+            [database, name] if database.value.eq_ignore_ascii_case("main") => Some(&name.value),
+            [database, _] => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown database {}", database.value),
+                ))
+            }
+            _ => return Err(unsupported("ANALYZE of such a name")),
+        };
+        let (tables, only_index) = match name {
+            None => (self.schema.tables.iter().collect(), None),
+            Some(name) => match self.schema.table(name) {
+                Some(table) => (vec![table], None),
+                None => {
+                    let index = self
+                        .schema
+                        .indexes
+                        .iter()
+                        .find(|i| i.name.eq_ignore_ascii_case(name))
+                        .ok_or_else(|| {
+                            Error::new(ErrorKind::NotFound, format!("no such table: {}", name))
+                        })?;
+                    (
+                        self.schema.table(&index.table_name).into_iter().collect(),
+                        Some(index),
+                    )
+                }
+            },
+        };
+
+        let collation = |name: &Option<String>| match name {
+            Some(name) => self.collations.get(name),
+            None => Ok(Collation::Binary),
+        };
+        let text = |s: &str| SqlValue::Text(s.to_string());
+        let mut analyzed = Vec::new();
+        for table in &tables {
+            if table.name.to_lowercase().starts_with("sqlite_") {
+                continue;
+            }
+            // b-trees with a key: the name that goes in the idx column, the
+            // root page and the collations of the key columns
+            let mut trees = Vec::new();
+            if table.without_rowid {
+                let collations = table
+                    .primary_key
+                    .iter()
+                    .map(|c| collation(&table.columns[*c].collation))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                trees.push((&table.name, table.root_page, collations));
+            }
+            for index in self.schema.indexes_of(&table.name) {
+                let collations = index
+                    .columns
+                    .iter()
+                    .map(|c| collation(&c.collation))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                trees.push((&index.name, index.root_page, collations));
+            }
+            // the rows of a table are counted where no index counts them all
+            let counted =
+                table.without_rowid || self.schema.indexes_of(&table.name).any(|i| !i.partial);
+            if let Some(index) = only_index {
+                trees.retain(|(name, _, _)| name.eq_ignore_ascii_case(&index.name));
+            } else if !counted {
+                let rows = count_entries(self.pager, table.root_page)?;
+                if rows > 0 {
+                    analyzed.push(vec![
+                        text(&table.name),
+                        SqlValue::Null,
+                        text(&rows.to_string()),
+                    ]);
+                }
+            }
+            for (name, root_page, collations) in trees {
+                let (entries, distinct) = planner::key_counts(self.pager, root_page, &collations)?;
+                if entries > 0 {
+                    let stat = planner::format_stat(entries, &distinct);
+                    analyzed.push(vec![text(&table.name), text(name), text(&stat)]);
+                }
+            }
+        }
+
+        // the rows for what was analyzed replace those recorded before
+        let stat1_root_page = self.statistics.stat1_root_page()?;
+        let mut rows = Vec::new();
+        if let (Some(root_page), Some(name)) = (stat1_root_page, name) {
+            for entry in BTreeCursor::new(self.pager, root_page) {
+                let values: Vec<SqlValue> = entry?
+                    .record
+                    .into_values()
+                    .iter()
+                    .map(SqlValue::from)
+                    .collect();
+                let matches = |i: usize| {
+                    values
+                        .get(i)
+                        .and_then(SqlValue::as_str)
+                        .is_some_and(|s| s.eq_ignore_ascii_case(name))
+                };
+                let replaced = match only_index {
+                    Some(_) => matches(1),
+                    None => matches(0),
+                };
+                if !replaced {
+                    rows.push(values);
+                }
+            }
+        }
+        rows.extend(analyzed);
+        let records: Vec<(i64, Vec<u8>)> = rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| (i as i64 + 1, Record::encode(row)))
+            .collect();
+
+        let mut transaction = self.pager.begin()?;
+        match stat1_root_page {
+            Some(root_page) => {
+                btree::truncate_tree(&mut transaction, root_page)?;
+                btree::write_table(&mut transaction, root_page, &records)?;
+            }
+            None => {
+                let root_page = transaction.allocate();
+                btree::write_table(&mut transaction, root_page, &records)?;
+                // sqlite_master gets a row for the new table after its last one
+                let mut schema_rows = Vec::new();
+                for entry in BTreeCursor::new(self.pager, 1) {
+                    let entry = entry?;
+                    let values: Vec<SqlValue> =
+                        entry.record.values().iter().map(SqlValue::from).collect();
+                    schema_rows.push((entry.row_id.unwrap_or(0), Record::encode(&values)));
+                }
+                let row_id = schema_rows.last().map_or(1, |(row_id, _)| row_id + 1);
+                let row = [
+                    text("table"),
+                    text("sqlite_stat1"),
+                    text("sqlite_stat1"),
+                    SqlValue::Integer(root_page.into()),
+                    text("CREATE TABLE sqlite_stat1(tbl,idx,stat)"),
+                ];
+                schema_rows.push((row_id, Record::encode(&row)));
+                btree::truncate_tree(&mut transaction, 1)?;
+                btree::write_table(&mut transaction, 1, &schema_rows)?;
+                transaction.schema_changed();
+            }
+        }
+        transaction.commit()?;
+        self.statistics.forget();
+        Ok(())
+    }
+
+    // Runs a subquery for an expression evaluated in `scope`. A subquery
+    // that never looks at the scope gives the same rows every time, so they
+    // are kept for the rest of the statement.
+    fn subquery(&self, query: &ast::Query, scope: &dyn Scope) -> Result<Rc<Relation>, Error> {
+        if let Some(relation) = self.subqueries.borrow().get(query) {
+            return Ok(relation.clone());
+        }
+        let outer = OuterScope {
+            scope,
+            used: Cell::new(false),
+        };
+        let relation = Rc::new(self.nested(&outer).query(query)?);
+        if !outer.used.get() {
+            self.subqueries
+                .borrow_mut()
+                .insert(query.clone(), relation.clone());
+        }
+        Ok(relation)
+    }
+
+    // An executor for a subquery in an expression, whose names may refer to
+    // the columns of `outer`.
+    fn nested<'b>(&'b self, outer: &'b dyn Scope) -> Executor<'b> {
+        Executor {
+            pager: self.pager,
+            schema: self.schema,
+            sort_budget: self.sort_budget,
+            outer: Some(outer),
+            subqueries: self.subqueries.clone(),
+            ctes: self.ctes.clone(),
+            collations: self.collations.clone(),
+            functions: self.functions.clone(),
+            parameters: self.parameters.clone(),
+            statistics: self.statistics.clone(),
+        }
+    }
+
+    // An executor that sees the given common table expressions. Subqueries
+    // may name other tables there, so it keeps its own subquery results.
+    fn with_ctes(&self, ctes: Option<Rc<CommonTable>>) -> Executor<'a> {
+        Executor {
+            pager: self.pager,
+            schema: self.schema,
+            sort_budget: self.sort_budget,
+            outer: self.outer,
+            subqueries: Rc::new(RefCell::new(HashMap::new())),
+            ctes,
+            collations: self.collations.clone(),
+            functions: self.functions.clone(),
+            parameters: self.parameters.clone(),
+            statistics: self.statistics.clone(),
+        }
+    }
+
+    fn query(&self, query: &ast::Query) -> Result<Relation, Error> {
+        if let Some(with) = &query.with {
+            return self.with_query(with, query);
+        }
+        let select = match &query.body {
+            SetExpr::Select(select) => select,
+            _ => return self.compound(query),
+        };
+        let plan = self.plan_select(query, select)?;
+        match self.compile_select(select, &plan)? {
+            Some(program) => self.run_program(&program, plan),
+            None => self.run_select(select, plan),
+        }
+    }
+
+    // Compiles a planned SELECT into a program, or returns None if it has
+    // window functions, which the tree-walking executor runs instead and
+    // EXPLAIN rejects.
+    fn compile_select(
+        &self,
+        select: &ast::Select,
+        plan: &SelectPlan<'a>,
+    ) -> Result<Option<Program>, Error> {
+        if !plan.windows.is_empty() {
+            return Ok(None);
+        }
+        let select = plan.resolved.as_ref().unwrap_or(select);
+        let from = &plan.from;
+        let index_cursors = index_cursors(from);
+        let mut names = ProgramNames {
+            executor: self,
+            from,
+            index_cursors: &index_cursors,
+            aggregates: &plan.aggregates,
+            results: None,
+        };
+        let mut b = Builder::new();
+        let init = b.emit(Opcode::Init, 0, 0, 0);
+        b.jump_here(init);
+        // jumps to the end of the program
+        let mut halts = Vec::new();
+        let limit = plan.limit.map(|limit| {
+            let r = b.register();
+            b.integer(limit, r);
+            halts.push(b.emit(Opcode::IfNot, r, 0, 1));
+            r
+        });
+        let offset = if plan.offset > 0 {
+            let r = b.register();
+            b.integer(plan.offset, r);
+            Some(r)
+        } else {
+            None
+        };
+        for (source, access) in from.sources.iter().zip(&from.access) {
+            let cursor = b.cursor();
+            let (opcode, root_page, name) = match (source.stored(), access) {
+                (None, _) => (Opcode::OpenRows, 0, &source.name),
+                (
+                    Some(_),
+                    Access::Index {
+                        index,
+                        covering: true,
+                        ..
+                    },
+                ) => (Opcode::OpenRead, index.root_page, &index.name),
+                (Some(table), _) => (Opcode::OpenRead, table.root_page, &table.name),
+            };
+            let addr = b.emit(opcode, cursor, root_page as usize, 0);
+            b.op(addr).p4 = P4::Name(name.clone());
+        }
+        for access in &from.access {
+            if let Access::Index {
+                index,
+                covering: false,
+                ..
+            } = access
+            {
+                let cursor = b.cursor();
+                let addr = b.emit(Opcode::OpenRead, cursor, index.root_page as usize, 0);
+                b.op(addr).p4 = P4::Name(index.name.clone());
+            }
+        }
+        // the cursors of the join, whose rows a sorter of GROUP BY keeps
+        let join_cursors = from.sources.len() + index_cursors.iter().flatten().count();
+        // the rows SELECT DISTINCT has returned
+        let distinct = if select.distinct {
+            let cursor = b.cursor();
+            let collations: Rc<[Collation]> = plan
+                .types
+                .iter()
+                .map(|(_, collation)| collation.clone().unwrap_or(Collation::Binary))
+                .collect();
+            let addr = b.emit(Opcode::OpenEphemeral, cursor, collations.len(), 0);
+            b.op(addr).p4 = P4::KeyInfo(collations);
+            Some(cursor)
+        } else {
+            None
+        };
+        let sorter = if plan.sort {
+            let cursor = b.cursor();
+            let keys: Rc<[SortKey]> = plan.order.iter().map(|term| term.key.clone()).collect();
+            let addr = b.emit(Opcode::SorterOpen, cursor, keys.len(), 0);
+            b.op(addr).p4 = P4::SortKeys(keys);
+            Some(cursor)
+        } else {
+            None
+        };
+        let output = ResultOutput {
+            projection: &plan.projection,
+            order: &plan.order,
+            distinct,
+            sorter,
+            offset,
+            limit,
+        };
+        let mut accumulators = Vec::with_capacity(plan.aggregates.len());
+        for function in &plan.aggregates {
+            let collation = argument_collation(&self.collations, &from.sources, function)?;
+            let accumulator = Accumulator::new(function, collation, &self.functions)?;
+            accumulators.push(b.accumulator(accumulator));
+        }
+
+        let group_by = if plan.is_aggregate {
+            group_terms(
+                &from.sources,
+                &select.group_by,
+                &plan.columns,
+                &plan.projection,
+            )?
+        } else {
+            Vec::new()
+        };
+        let count_only = plan.is_aggregate
+            && AggregateQuery {
+                from,
+                select,
+                projection: &plan.projection,
+                group_by: &group_by,
+                aggregates: &plan.aggregates,
+                grouped: false,
+            }
+            .is_count_only();
+        let grouping = if plan.is_aggregate {
+            let mut collations = Vec::with_capacity(group_by.len());
+            for expr in &group_by {
+                collations.push(expr_collation(&self.collations, &from.sources, expr)?);
+            }
+            Some(self.grouping(&mut b, plan, select, collations))
+        } else {
+            None
+        };
+        // rows come in the order of GROUP BY either from the tables or from
+        // a sorter
+        let group_sorter = match &grouping {
+            Some(grouping) if !group_by.is_empty() && !plan.grouped => {
+                let cursor = b.cursor();
+                let keys: Rc<[SortKey]> = grouping
+                    .collations
+                    .iter()
+                    .map(|collation| SortKey::new(false, collation.clone()))
+                    .collect();
+                let addr = b.emit(Opcode::SorterOpen, cursor, keys.len(), 0);
+                b.op(addr).p4 = P4::SortKeys(keys);
+                Some(cursor)
+            }
+            _ => None,
+        };
+        // the Gosubs to the subroutine producing the row of a group
+        let mut gosubs = Vec::new();
+
+        let mut loops: Vec<JoinLoop> = Vec::new();
+        if count_only {
+            for accumulator in &accumulators {
+                b.emit(Opcode::Count, 0, *accumulator, 0);
+            }
+        } else {
+            for (level, filters) in from.filters.iter().enumerate() {
+                loops.push(compile_loop(&mut b, from, &index_cursors, level, &names));
+                let next = filters
+                    .iter()
+                    .map(|filter| compile_condition(&mut b, filter, &names))
+                    .collect();
+                let matched = loops.last().unwrap().matched;
+                if let Some(r) = matched {
+                    b.integer(1, r);
+                }
+                let resume = b.address();
+                let join_loop = loops.last_mut().unwrap();
+                join_loop.next = next;
+                join_loop.resume = resume;
+            }
+            let mut skips: Vec<usize> = from
+                .residual
+                .iter()
+                .map(|term| compile_condition(&mut b, term, &names))
+                .collect();
+            match &grouping {
+                None => skips.extend(compile_result(&mut b, &output, &names, &mut halts)),
+                Some(grouping) if group_by.is_empty() => {
+                    compile_step(&mut b, grouping, &plan.aggregates, &accumulators, &names)
+                }
+                Some(grouping) => {
+                    for (i, expr) in group_by.iter().enumerate() {
+                        b.expression(expr, &names, grouping.keys + i);
+                    }
+                    match group_sorter {
+                        Some(cursor) => {
+                            let addr =
+                                b.emit(Opcode::SorterInsert, cursor, grouping.keys, group_by.len());
+                            b.op(addr).p5 = join_cursors as u16;
+                        }
+                        None => {
+                            gosubs.push(compile_group_break(&mut b, grouping, &accumulators));
+                            compile_step(&mut b, grouping, &plan.aggregates, &accumulators, &names);
+                        }
+                    }
+                }
+            }
+            match loops.last_mut() {
+                Some(join_loop) => join_loop.next.extend(skips),
+                None => skips.into_iter().for_each(|skip| b.jump_here(skip)),
+            }
+        }
+        for (level, join_loop) in loops.into_iter().enumerate().rev() {
+            for skip in join_loop.next {
+                b.jump_here(skip);
+            }
+            b.emit(Opcode::Next, join_loop.cursor, join_loop.top, 0);
+            b.jump_here(join_loop.seek);
+            // a LEFT JOIN without a matching row pairs the earlier tables
+            // with a row of NULLs
+            if let Some(r) = join_loop.matched {
+                let matched = b.emit(Opcode::IfPos, r, 0, 0);
+                b.emit(Opcode::NullRow, level, 0, 0);
+                if join_loop.cursor != level {
+                    b.emit(Opcode::NullRow, join_loop.cursor, 0, 0);
+                }
+                b.integer(1, r);
+                b.emit(Opcode::Goto, 0, join_loop.resume, 0);
+                b.jump_here(matched);
+            }
+        }
+
+        if let (Some(grouping), Some(cursor)) = (&grouping, group_sorter) {
+            let sort = b.emit(Opcode::SorterSort, cursor, 0, 0);
+            let top = b.address();
+            let addr = b.emit(Opcode::SorterData, cursor, grouping.keys, group_by.len());
+            b.op(addr).p5 = join_cursors as u16;
+            gosubs.push(compile_group_break(&mut b, grouping, &accumulators));
+            compile_step(&mut b, grouping, &plan.aggregates, &accumulators, &names);
+            b.emit(Opcode::SorterNext, cursor, top, 0);
+            b.jump_here(sort);
+        }
+        if let Some(grouping) = &grouping {
+            let results = b.registers(accumulators.len());
+            names.results = Some(results);
+            let having = select.having.as_ref();
+            if group_by.is_empty() {
+                // there is always exactly one group
+                if grouping.keep_row {
+                    b.emit(Opcode::RestoreRows, 0, 0, 0);
+                }
+                let skips = compile_group_row(
+                    &mut b,
+                    plan,
+                    &accumulators,
+                    results,
+                    having,
+                    &output,
+                    &names,
+                    &mut halts,
+                );
+                skips.into_iter().for_each(|skip| b.jump_here(skip));
+            } else {
+                // the last group, then the subroutine producing the row of a
+                // group, which keeps the rows of the cursors meanwhile
+                let none = b.emit(Opcode::IfNot, grouping.started, 0, 0);
+                gosubs.push(b.emit(Opcode::Gosub, grouping.ret, 0, 0));
+                b.jump_here(none);
+                let end = b.emit(Opcode::Goto, 0, 0, 0);
+                gosubs.into_iter().for_each(|gosub| b.jump_here(gosub));
+                if grouping.keep_row {
+                    b.emit(Opcode::SaveRows, 1, 0, 0);
+                    b.emit(Opcode::RestoreRows, 0, 0, 0);
+                }
+                let skips = compile_group_row(
+                    &mut b,
+                    plan,
+                    &accumulators,
+                    results,
+                    having,
+                    &output,
+                    &names,
+                    &mut halts,
+                );
+                skips.into_iter().for_each(|skip| b.jump_here(skip));
+                if grouping.keep_row {
+                    b.emit(Opcode::RestoreRows, 1, 0, 0);
+                }
+                b.emit(Opcode::Return, grouping.ret, 0, 0);
+                b.jump_here(end);
+            }
+        }
+        if output.sorter.is_some() {
+            compile_sorted(&mut b, &output, &mut halts);
+        }
+        for halt in halts {
+            b.jump_here(halt);
+        }
+        b.emit(Opcode::Halt, 0, 0, 0);
+        Ok(Some(b.finish()))
+    }
+
+    // Allocates the registers grouping the rows of a SELECT with aggregates.
+    fn grouping(
+        &self,
+        b: &mut Builder,
+        plan: &SelectPlan<'a>,
+        select: &ast::Select,
+        collations: Vec<Collation>,
+    ) -> Grouping {
+        let n = collations.len();
+        let keys = b.registers(2 * n);
+        // with a single min() or max(), bare columns come from its row,
+        // otherwise from the first row of the group
+        let extremes: Vec<usize> = (0..plan.aggregates.len())
+            .filter(|i| aggregate::is_min_or_max(&plan.aggregates[*i]))
+            .collect();
+        let extreme = match extremes.as_slice() {
+            [i] => Some(*i),
+            _ => None,
+        };
+        let keep_row = plan
+            .projection
+            .iter()
+            .chain(&select.having)
+            .chain(plan.order.iter().map(|term| &term.expr))
+            .any(|expr| uses_columns(&self.functions, expr));
+        let grouping = Grouping {
+            keys,
+            previous: keys + n,
+            collations: collations.into(),
+            started: b.register(),
+            first: b.register(),
+            took: b.register(),
+            extreme,
+            keep_row,
+            ret: b.register(),
+        };
+        b.integer(0, grouping.started);
+        // without GROUP BY, the only group starts with the first row
+        b.integer(1, grouping.first);
+        grouping
+    }
+
+    // Runs a compiled SELECT to its last row.
+    fn run_program(&self, program: &Program, plan: SelectPlan<'a>) -> Result<Relation, Error> {
+        let index_cursors = index_cursors(&plan.from);
+        let environment = ProgramEnvironment {
+            executor: self,
+            from: &plan.from,
+            aggregates: &plan.aggregates,
+            index_cursors: &index_cursors,
+        };
+        let mut vm = Vm::new(program);
+        let mut rows = Vec::new();
+        while let Some(row) = vm.step(program, &environment)? {
+            rows.push(row);
+        }
+        Ok(Relation {
+            columns: plan.columns,
+            types: plan.types,
+            rows,
+        })
+    }
+
+    // The program of a SELECT, for EXPLAIN.
+    pub fn program(&self, statement: &Statement) -> Result<Program, Error> {
+        let program = match statement {
+            Statement::Query(query) => match &query.body {
+                SetExpr::Select(select) if query.with.is_none() => {
+                    let plan = self.plan_select(query, select)?;
+                    self.compile_select(select, &plan)?
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        program.ok_or_else(|| unsupported("EXPLAIN of a statement that is not compiled"))
+    }
+
+    // Reads the rows of a planned SELECT with window functions, which are
+    // computed once all the rows, or all the groups, are known.
+    fn run_select(&self, select: &ast::Select, plan: SelectPlan<'a>) -> Result<Relation, Error> {
+        let SelectPlan {
+            resolved,
+            from,
+            columns,
+            types,
+            projection,
+            order,
+            limit,
+            offset,
+            aggregates,
+            is_aggregate,
+            windows,
+            sort,
+            grouped,
+        } = plan;
+        let select = resolved.as_ref().unwrap_or(select);
+        let mut output = Output::new(&order, sort, self.sort_budget, offset, limit);
+        if select.distinct {
+            output.distinct(
+                types
+                    .iter()
+                    .map(|(_, collation)| collation.clone().unwrap_or(Collation::Binary)),
+            );
+        }
+
+        let groups = if is_aggregate {
+            let group_by = group_terms(&from.sources, &select.group_by, &columns, &projection)?;
+            let query = AggregateQuery {
+                from: &from,
+                select,
+                projection: &projection,
+                group_by: &group_by,
+                aggregates: &aggregates,
+                grouped,
+            };
+            self.aggregate(&query)?
+        } else {
+            // each row is a group of its own
+            let mut groups = Vec::new();
+            self.join(&from, &mut |row| {
+                let scope = RowScope {
+                    executor: self,
+                    sources: &from.sources,
+                    row: Some(row),
+                };
+                if from.satisfies_residual(&scope)? {
+                    groups.push(Group {
+                        accumulators: Vec::new(),
+                        row: Some(row.to_vec()),
+                    });
+                }
+                Ok(true)
+            })?;
+            groups
+        };
+        self.windows(
+            &from.sources,
+            &projection,
+            &aggregates,
+            &windows,
+            groups,
+            &mut output,
+        )?;
+        Ok(Relation {
+            columns,
+            types,
+            rows: output.finish()?,
+        })
+    }
+
+    // Plans a SELECT: its tables, the way each is read, and what is done
+    // with the rows.
+    fn plan_select(
+        &self,
+        query: &ast::Query,
+        select: &ast::Select,
+    ) -> Result<SelectPlan<'a>, Error> {
+        let mut from = self.plan_from(select)?;
+
+        let mut columns = Vec::new();
+        let mut projection = Vec::new();
+        for item in &select.projection {
+            match item {
+                SelectItem::Wildcard => {
+                    if from.sources.is_empty() {
+                        return Err(Error::new(ErrorKind::InvalidInput, "no tables specified"));
+                    }
+                    for source in &from.sources {
+                        for (i, c) in source.table.columns.iter().enumerate() {
+                            if !source.merged.contains(&i) {
+                                columns.push(c.name.clone());
+                                projection.push(source.column_expr(c));
+                            }
+                        }
+                    }
+                }
+                SelectItem::QualifiedWildcard(name) => {
+                    let name = &name.0.last().unwrap().value;
+                    let source = from
+                        .sources
+                        .iter()
+                        .find(|s| s.name.eq_ignore_ascii_case(name))
+                        .ok_or_else(|| {
+                            Error::new(ErrorKind::InvalidInput, format!("no such table: {}", name))
+                        })?;
+                    for c in &source.table.columns {
+                        columns.push(c.name.clone());
+                        projection.push(source.column_expr(c));
+                    }
+                }
+                SelectItem::UnnamedExpr(expr) => {
+                    columns.push(column_name(expr));
+                    projection.push(expr.clone());
+                }
+                SelectItem::ExprWithAlias { expr, alias } => {
+                    columns.push(alias.value.clone());
+                    projection.push(expr.clone());
+                }
+            }
+        }
+
+        let resolved = with_result_aliases(&from.sources, select);
+        let select = resolved.as_ref().unwrap_or(select);
+        from.conditions = select
+            .selection
+            .iter()
+            .flat_map(conjuncts)
+            .cloned()
+            .collect();
+        self.place_conditions(&mut from)?;
+
+        let types = projection
+            .iter()
+            .map(|expr| expr_type(&self.collations, &from.sources, expr))
+            .collect();
+        let order = order_terms(
+            &self.collations,
+            &from.sources,
+            &query.order_by,
+            &columns,
+            &projection,
+        )?;
+        let (limit, offset) = limit_offset(query, &self.empty_scope())?;
+        let aggregates = collect_aggregates(&self.functions, select, &projection, &order);
+        let is_aggregate = !select.group_by.is_empty() || !aggregates.is_empty();
+        if select.having.is_some() && !is_aggregate {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a GROUP BY clause is required before HAVING",
+            ));
+        }
+        let windows = collect_windows(&projection, &order);
+        for function in &windows {
+            window::validate(function, &self.functions)?;
+        }
+        // grouped rows are sorted after grouping, so a scan only helps by
+        // giving the rows of each group one after another
+        let mut group_order = Vec::new();
+        if windows.is_empty() {
+            for expr in group_terms(&from.sources, &select.group_by, &columns, &projection)? {
+                let collation = expr_collation(&self.collations, &from.sources, &expr)?;
+                group_order.push(OrderTerm {
+                    expr,
+                    result_column: None,
+                    key: SortKey::new(false, collation),
+                });
+            }
+        }
+        let scan_order: &[OrderTerm] = if is_aggregate {
+            &group_order
+        } else if !windows.is_empty() {
+            &[]
+        } else {
+            &order
+        };
+        let used = self.used_columns(&from, select, &projection, &order);
+        for (source, used) in from.sources.iter_mut().zip(&used) {
+            source.columns = Some(used.as_slice().into());
+        }
+        self.plan_join(&mut from, &used, scan_order)?;
+        // a join keeps the order of its first table, so the rows need no
+        // sorting when that table gives them in the order of ORDER BY
+        let ordered = match from.sources.first() {
+            // without FROM, the join produces a single empty row
+            None => true,
+            Some(source) => match source.stored() {
+                None => false,
+                Some(table) => match self.constraints(&from, 0, &[])? {
+                    Some(keys) => {
+                        let access = self.available_access(table, &from.access[0], &keys);
+                        self.access_order(&from.sources, 0, &access, scan_order)
+                    }
+                    None => true,
+                },
+            },
+        };
+        let scanned_in_order = !is_aggregate && !scan_order.is_empty() && ordered;
+        let sort = !order.is_empty() && !scanned_in_order;
+        let grouped = !group_order.is_empty() && ordered;
+        Ok(SelectPlan {
+            resolved,
+            from,
+            columns,
+            types,
+            projection,
+            order,
+            limit,
+            offset,
+            aggregates,
+            is_aggregate,
+            windows,
+            sort,
+            grouped,
+        })
+    }
+
+    // Runs the grouping part of a query with aggregates or GROUP BY for its
+    // windows: rows are put into groups ordered by their GROUP BY values,
+    // and the groups that satisfy HAVING are returned. Rows that come in
+    // that order already make their groups one after another.
+    fn aggregate(&self, query: &AggregateQuery) -> Result<Vec<Group>, Error> {
+        let sources = &query.from.sources;
+        let mut collations = Vec::with_capacity(query.group_by.len());
+        for expr in query.group_by {
+            collations.push(expr_collation(&self.collations, sources, expr)?);
+        }
+        let collations: Rc<[Collation]> = collations.into();
+        let mut argument_collations = Vec::with_capacity(query.aggregates.len());
+        for function in query.aggregates {
+            argument_collations.push(argument_collation(&self.collations, sources, function)?);
+        }
+        let new_group = || -> Result<Group, Error> {
+            let mut accumulators = Vec::with_capacity(query.aggregates.len());
+            for (function, collation) in query.aggregates.iter().zip(&argument_collations) {
+                accumulators.push(Accumulator::new(
+                    function,
+                    collation.clone(),
+                    &self.functions,
+                )?);
+            }
+            Ok(Group {
+                accumulators,
+                row: None,
+            })
+        };
+        // with a single min() or max(), bare columns come from its row,
+        // otherwise from the first row of the group
+        let extremes: Vec<usize> = (0..query.aggregates.len())
+            .filter(|i| aggregate::is_min_or_max(&query.aggregates[*i]))
+            .collect();
+        let extreme = match extremes.as_slice() {
+            [i] => Some(*i),
+            _ => None,
+        };
+
+        let mut groups: BTreeMap<GroupKey, Group> = BTreeMap::new();
+        let mut adjacent: Vec<(GroupKey, Group)> = Vec::new();
+        if query.is_count_only() {
+            let mut group = new_group()?;
+            let count = count_entries(self.pager, sources[0].table.root_page)?;
+            for accumulator in &mut group.accumulators {
+                accumulator.add_rows(count);
+            }
+            groups.insert(GroupKey::new(Vec::new(), collations.clone()), group);
+        } else {
+            self.join(query.from, &mut |row| {
+                let scope = RowScope {
+                    executor: self,
+                    sources,
+                    row: Some(row),
+                };
+                if !query.from.satisfies_residual(&scope)? {
+                    return Ok(true);
+                }
+                let mut key = Vec::with_capacity(query.group_by.len());
+                for expr in query.group_by {
+                    key.push(expr::evaluate(expr, &scope)?);
+                }
+                let key = GroupKey::new(key, collations.clone());
+                let group = if query.grouped {
+                    if adjacent.last().is_none_or(|(last, _)| *last != key) {
+                        adjacent.push((key, new_group()?));
+                    }
+                    &mut adjacent.last_mut().unwrap().1
+                } else {
+                    match groups.entry(key) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => entry.insert(new_group()?),
+                    }
+                };
+                let mut take_row = group.row.is_none();
+                for (i, function) in query.aggregates.iter().enumerate() {
+                    let args = aggregate::arguments(function, &scope)?;
+                    let took_value = group.accumulators[i].step(&args);
+                    take_row |= took_value && extreme == Some(i);
+                }
+                if take_row {
+                    group.row = Some(row.to_vec());
+                }
+                Ok(true)
+            })?;
+        }
+        // without GROUP BY there is always exactly one group
+        if groups.is_empty() && query.group_by.is_empty() {
+            groups.insert(GroupKey::new(Vec::new(), collations), new_group()?);
+        }
+
+        // only one of the two has groups
+        let groups = adjacent
+            .into_iter()
+            .map(|(_, group)| group)
+            .chain(groups.into_values());
+        let mut kept = Vec::new();
+        for group in groups {
+            let scope = GroupScope::new(self, sources, query.aggregates, &group);
+            if let Some(having) = &query.select.having {
+                if !expr::is_true(having, &scope)? {
+                    continue;
+                }
+            }
+            kept.push(group);
+        }
+        Ok(kept)
+    }
+
+    // Computes the window functions for the rows of a query, given as groups
+    // of at most one row without GROUP BY, and projects the rows. They come
+    // out partitioned and sorted for the first window.
+    fn windows(
+        &self,
+        sources: &[Source],
+        projection: &[Expr],
+        aggregates: &[ast::Function],
+        windows: &[ast::Function],
+        groups: Vec<Group>,
+        output: &mut Output,
+    ) -> Result<(), Error> {
+        let mut values = vec![Vec::with_capacity(windows.len()); groups.len()];
+        let mut first_order = None;
+        for function in windows {
+            let spec = function.over.as_ref().unwrap();
+            let (_, partition_by) = sql_parser::frame_exclusion(spec);
+            let mut collations = Vec::with_capacity(partition_by.len());
+            for expr in partition_by {
+                collations.push(expr_collation(&self.collations, sources, expr)?);
+            }
+            let collations: Rc<[Collation]> = collations.into();
+            let mut keys = Vec::with_capacity(spec.order_by.len());
+            for term in &spec.order_by {
+                let mut key = SortKey::new(
+                    term.asc == Some(false),
+                    expr_collation(&self.collations, sources, &term.expr)?,
+                );
+                if let Some(nulls_first) = term.nulls_first {
+                    key.nulls_first = nulls_first;
+                }
+                keys.push(key);
+            }
+
+            let mut partitions: BTreeMap<GroupKey, Vec<(usize, WindowRow)>> = BTreeMap::new();
+            for (i, group) in groups.iter().enumerate() {
+                let scope = GroupScope::new(self, sources, aggregates, group);
+                let mut key = Vec::with_capacity(partition_by.len());
+                for expr in partition_by {
+                    key.push(expr::evaluate(expr, &scope)?);
+                }
+                let mut order = Vec::with_capacity(keys.len());
+                for term in &spec.order_by {
+                    order.push(expr::evaluate(&term.expr, &scope)?);
+                }
+                let args = aggregate::arguments(function, &scope)?;
+                partitions
+                    .entry(GroupKey::new(key, collations.clone()))
+                    .or_default()
+                    .push((i, WindowRow { order, args }));
+            }
+
+            let collation = argument_collation(&self.collations, sources, function)?;
+            let mut window_order = Vec::with_capacity(groups.len());
+            for (_, mut rows) in partitions {
+                rows.sort_by(|a, b| compare_rows(&keys, &a.1.order, &b.1.order));
+                let (indexes, rows): (Vec<usize>, Vec<WindowRow>) = rows.into_iter().unzip();
+                let results =
+                    window::evaluate(function, &keys, collation.clone(), &self.functions, &rows)?;
+                for (i, value) in indexes.iter().zip(results) {
+                    values[*i].push(value);
+                }
+                window_order.extend(indexes);
+            }
+            first_order.get_or_insert(window_order);
+        }
+
+        for i in first_order.unwrap() {
+            if output.is_full() {
+                break;
+            }
+            let scope = WindowScope {
+                group: GroupScope::new(self, sources, aggregates, &groups[i]),
+                windows,
+                values: &values[i],
+            };
+            let mut row = Vec::with_capacity(projection.len());
+            for expr in projection {
+                row.push(expr::evaluate(expr, &scope)?);
+            }
+            output.push(row, &scope)?;
+        }
+        Ok(())
+    }
+
+    // Resolves the tables of the FROM clause, in the order they are written
+    // until the planner picks another. The conditions of WHERE are placed
+    // once the result column aliases they may name are known.
+    fn plan_from(&self, select: &ast::Select) -> Result<FromClause<'a>, Error> {
+        let mut sources: Vec<Source<'a>> = Vec::new();
+        let mut cross_join = false;
+        for item in &select.from {
+            // tables separated by commas are cross joined
+            sources.push(self.source(&item.relation, false)?);
+            for join in &item.joins {
+                let (left_join, constraint) = match &join.join_operator {
+                    JoinOperator::Inner(constraint) => (false, Some(constraint)),
+                    JoinOperator::LeftOuter(constraint) => (true, Some(constraint)),
+                    JoinOperator::CrossJoin => {
+                        cross_join = true;
+                        (false, None)
+                    }
+                    JoinOperator::RightOuter(_) => return Err(unsupported("RIGHT JOIN")),
+                    JoinOperator::FullOuter(_) => return Err(unsupported("FULL JOIN")),
+                    _ => return Err(unsupported("APPLY")),
+                };
+                let mut source = self.source(&join.relation, left_join)?;
+                match constraint {
+                    Some(JoinConstraint::On(expr)) => source.on.push(expr.clone()),
+                    Some(JoinConstraint::Using(names)) => {
+                        for name in names {
+                            if !join_using(&sources, &mut source, &name.value)? {
+                                return Err(Error::new(
+                                    ErrorKind::InvalidInput,
+                                    format!(
+                                        "cannot join using column {} - column not present in both tables",
+                                        name.value
+                                    ),
+                                ));
+                            }
+                        }
+                    }
+                    Some(JoinConstraint::Natural) => {
+                        let names: Vec<String> = source
+                            .table
+                            .columns
+                            .iter()
+                            .map(|c| c.name.clone())
+                            .collect();
+                        for name in &names {
+                            join_using(&sources, &mut source, name)?;
+                        }
+                    }
+                    Some(JoinConstraint::None) | None => (),
+                }
+                sources.push(source);
+            }
+        }
+        if sources.len() > 64 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "at most 64 tables in a join",
+            ));
+        }
+        let fixed_order = cross_join
+            || sources
+                .iter()
+                .any(|source| source.left_join || source.function.is_some());
+        let n = sources.len();
+        Ok(FromClause {
+            sources,
+            conditions: Vec::new(),
+            fixed_order,
+            filters: Vec::new(),
+            equalities: Vec::new(),
+            lists: Vec::new(),
+            ranges: Vec::new(),
+            residual: Vec::new(),
+            access: vec![Access::Scan; n],
+        })
+    }
+
+    // Works out at which table of the join each condition can first be
+    // checked, and which of them can find the rows of their table.
+    fn place_conditions(&self, from: &mut FromClause<'a>) -> Result<(), Error> {
+        let sources = &from.sources;
+        // conditions are checked as early as the tables they use allow, but
+        // not on the right side of a LEFT JOIN, where that would turn rows
+        // they reject into NULL rows; only the LEFT JOIN's own ON clause
+        // decides which rows match there
+        let mut filters = vec![Vec::new(); sources.len()];
+        let mut residual = Vec::new();
+        let earliest = |term: &Expr, after: usize| {
+            referenced_sources(sources, self.outer, term)
+                .map(|tables| highest_source(tables).max(after))
+                .and_then(|level| (level..sources.len()).find(|l| !sources[*l].left_join))
+        };
+        for (level, source) in sources.iter().enumerate() {
+            for term in source.on.iter().flat_map(conjuncts) {
+                if !source.left_join {
+                    // a name that does not resolve fails as soon as possible
+                    match earliest(term, level) {
+                        Some(level) => filters[level].push(term.clone()),
+                        None if referenced_sources(sources, self.outer, term).is_none() => {
+                            filters[level].push(term.clone())
+                        }
+                        None => residual.push(term.clone()),
+                    }
+                    continue;
+                }
+                if let Some(tables) = referenced_sources(sources, self.outer, term) {
+                    if tables >> level > 1 {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "ON clause references tables to its right",
+                        ));
+                    }
+                }
+                filters[level].push(term.clone());
+            }
+        }
+        for term in &from.conditions {
+            match earliest(term, 0) {
+                Some(level) => filters[level].push(term.clone()),
+                None => residual.push(term.clone()),
+            }
+        }
+
+        let mut equalities = Vec::with_capacity(sources.len());
+        let mut lists = Vec::with_capacity(sources.len());
+        let mut ranges = Vec::with_capacity(sources.len());
+        for (level, terms) in filters.iter().enumerate() {
+            let earlier = (1u64 << level) - 1;
+            let mut seekable = Vec::new();
+            let mut listed = Vec::new();
+            let mut bounded = Vec::new();
+            for term in terms {
+                if let Some(equality) =
+                    seek_equality(&self.functions, sources, self.outer, level, earlier, term)
+                {
+                    seekable.push(equality);
+                }
+                if let Some(column) = self.seek_list(sources, level, earlier, term) {
+                    listed.push((column, term.clone()));
+                }
+                bounded.extend(seek_bounds(
+                    &self.functions,
+                    sources,
+                    self.outer,
+                    level,
+                    earlier,
+                    term,
+                ));
+            }
+            equalities.push(seekable);
+            lists.push(listed);
+            ranges.push(bounded);
+        }
+        from.filters = filters;
+        from.equalities = equalities;
+        from.lists = lists;
+        from.ranges = ranges;
+        from.residual = residual;
+        Ok(())
+    }
+
+    // Picks the order the tables are joined in and how the rows of each are
+    // found, by the estimated cost of the whole join, where the rows of a
+    // table are found once for every row of the tables before it. Orders
+    // are built up one table at a time, keeping the cheapest few.
+    fn plan_join(
+        &self,
+        from: &mut FromClause<'a>,
+        used: &[Vec<bool>],
+        order: &[OrderTerm],
+    ) -> Result<(), Error> {
+        let n = from.sources.len();
+        let best = {
+            let sources = &from.sources;
+            // without a fixed order, each condition is checked at whichever
+            // of its tables comes last
+            let terms: Vec<(u64, &Expr)> = from
+                .filters
+                .iter()
+                .flatten()
+                .filter_map(|term| referenced_sources(sources, self.outer, term).map(|t| (t, term)))
+                .collect();
+            let mut paths = vec![JoinPath {
+                tables: Vec::new(),
+                access: Vec::new(),
+                known: 0,
+                cost: 0.0,
+                rows: 1.0,
+                ordered: true,
+            }];
+            for _ in 0..n {
+                let mut next: Vec<JoinPath> = Vec::new();
+                for path in &paths {
+                    let candidates: Vec<usize> = if from.fixed_order {
+                        vec![path.tables.len()]
+                    } else {
+                        (0..n).filter(|t| path.known & 1 << t == 0).collect()
+                    };
+                    for t in candidates {
+                        let applicable: Vec<&Expr> = if from.fixed_order {
+                            from.filters[t].iter().collect()
+                        } else {
+                            let known = path.known | 1 << t;
+                            terms
+                                .iter()
+                                .filter(|(tables, _)| tables & 1 << t != 0 && tables & !known == 0)
+                                .map(|(_, term)| *term)
+                                .collect()
+                        };
+                        // only the first table decides the order of the rows
+                        let first = path.tables.is_empty();
+                        let wanted = if first { order } else { &[] };
+                        let accesses = self.access_paths(
+                            sources,
+                            t,
+                            path.known,
+                            &applicable,
+                            &used[t],
+                            wanted,
+                        )?;
+                        for (access, estimate) in accesses {
+                            let ordered = if first {
+                                order.is_empty() || self.access_order(sources, t, &access, order)
+                            } else {
+                                path.ordered
+                            };
+                            let mut extended = path.clone();
+                            extended.tables.push(t);
+                            extended.access.push(access);
+                            extended.known |= 1 << t;
+                            extended.cost += path.rows * estimate.cost;
+                            extended.rows *= estimate.rows;
+                            extended.ordered = ordered;
+                            // of equal costs, the later index wins, as in SQLite
+                            match next
+                                .iter_mut()
+                                .find(|p| p.known == extended.known && p.ordered == ordered)
+                            {
+                                Some(p) if p.cost < extended.cost => (),
+                                Some(p) => *p = extended,
+                                None => next.push(extended),
+                            }
+                        }
+                    }
+                }
+                next.sort_by(|a, b| a.total(order).total_cmp(&b.total(order)));
+                next.truncate(JOIN_PATHS);
+                paths = next;
+            }
+            paths
+                .into_iter()
+                .min_by(|a, b| a.total(order).total_cmp(&b.total(order)))
+        };
+        let best = match best {
+            Some(best) => best,
+            None => return Ok(()),
+        };
+        if best.tables.iter().enumerate().any(|(level, t)| level != *t) {
+            let mut sources: Vec<Option<Source<'a>>> = std::mem::take(&mut from.sources)
+                .into_iter()
+                .map(Some)
+                .collect();
+            from.sources = best
+                .tables
+                .iter()
+                .map(|t| sources[*t].take().unwrap())
+                .collect();
+            self.place_conditions(from)?;
+        }
+        from.access = best.access;
+        Ok(())
+    }
+
+    // The ways of finding the rows of the table at position `t` once the
+    // rows of the tables in `known` are, with what each costs. `terms` are
+    // the conditions that can be checked on its rows then, and `order` the
+    // ORDER BY terms its rows may be read in the order of.
+    fn access_paths(
+        &self,
+        sources: &[Source<'a>],
+        t: usize,
+        known: u64,
+        terms: &[&Expr],
+        used: &[bool],
+        order: &[OrderTerm],
+    ) -> Result<Vec<(Access<'a>, Estimate)>, Error> {
+        let equal_terms = terms
+            .iter()
+            .filter(|term| {
+                matches!(
+                    term,
+                    Expr::BinaryOp {
+                        op: BinaryOperator::Eq,
+                        ..
+                    } | Expr::InList { negated: false, .. }
+                        | Expr::InSubquery { negated: false, .. }
+                )
+            })
+            .count();
+        // the conditions the access does not use are checked on its rows
+        let filtered = |estimate: Estimate, equal: usize, bounds: usize| {
+            estimate.filtered(
+                equal_terms.saturating_sub(equal),
+                (terms.len() - equal_terms).saturating_sub(bounds),
+            )
+        };
+        let source = &sources[t];
+        let table = match source.stored() {
+            Some(table) => table,
+            None if source.function.is_some() => {
+                return Ok(vec![(
+                    Access::Scan,
+                    filtered(planner::table_function(), 0, 0),
+                )])
+            }
+            // how many rows a recursive table has is not known until it is
+            // read
+            None if source.queued.is_some() => {
+                return Ok(vec![(
+                    Access::Scan,
+                    filtered(planner::table_function(), 0, 0),
+                )])
+            }
+            None => {
+                let rows = source.rows.as_ref().map_or(0, |rows| rows.len());
+                return Ok(vec![(
+                    Access::Scan,
+                    filtered(planner::materialized(rows), 0, 0),
+                )]);
+            }
+        };
+
+        let mut equalities = Vec::new();
+        let mut lists = Vec::new();
+        let mut bounds = Vec::new();
+        for term in terms {
+            let equality = seek_equality(&self.functions, sources, self.outer, t, known, term);
+            if let Some((column, other)) = equality {
+                if let Some(collation) = self.key_collation(sources, table, column, &other) {
+                    equalities.push((column, collation));
+                }
+            }
+            if let Some(column) = self.seek_list(sources, t, known, term) {
+                if let Some(collation) = self.list_collation(sources, table, column, term) {
+                    let values = match term {
+                        Expr::InList { list, .. } => list.len(),
+                        _ => planner::SUBQUERY_VALUES,
+                    };
+                    lists.push((column, collation, values));
+                }
+            }
+            for (column, bound, other) in
+                seek_bounds(&self.functions, sources, self.outer, t, known, term)
+            {
+                if let Some(collation) = self.key_collation(sources, table, column, &other) {
+                    bounds.push((column, bound, collation));
+                }
+            }
+        }
+
+        let mut accesses = vec![Access::Scan];
+        let rowid_list = lists
+            .iter()
+            .filter(|(c, _, _)| is_row_id(table, *c))
+            .map(|(_, _, values)| *values)
+            .min();
+        if table.without_rowid {
+            let range = key_range(
+                &primary_key_columns(&self.collations, table),
+                &equalities,
+                &lists,
+                &bounds,
+            );
+            if !range.is_full() {
+                accesses.push(Access::PrimaryKey(range));
+            }
+        } else if equalities.iter().any(|(c, _)| is_row_id(table, *c)) {
+            accesses.push(Access::RowId);
+        } else if let Some(values) = rowid_list {
+            accesses.push(Access::RowIds(values));
+        } else {
+            let bounded = |which| {
+                bounds
+                    .iter()
+                    .any(|(c, bound, _)| is_row_id(table, *c) && *bound == which)
+            };
+            let (lower, upper) = (bounded(Bound::Lower), bounded(Bound::Upper));
+            if lower || upper {
+                accesses.push(Access::RowIdRange { lower, upper });
+            }
+        }
+        for index in self.schema.indexes_of(&table.name) {
+            // the entries of a partial index are not all rows of its table
+            if index.partial {
+                continue;
+            }
+            let columns = match index_columns(&self.collations, index) {
+                Some(columns) => columns,
+                None => continue,
+            };
+            let access = Access::Index {
+                index,
+                range: key_range(&columns, &equalities, &lists, &bounds),
+                covering: covers(table, index, used),
+            };
+            // a whole index is only worth reading instead of the table if it
+            // is smaller or in the order wanted
+            let useful = match &access {
+                Access::Index {
+                    range, covering, ..
+                } => !range.is_full() || *covering,
+                _ => unreachable!(),
+            };
+            if useful || !order.is_empty() && self.access_order(sources, t, &access, order) {
+                accesses.push(access);
+            }
+        }
+
+        let mut paths = Vec::with_capacity(accesses.len());
+        for access in accesses {
+            let estimate = self.statistics.estimate(table, &access)?;
+            let (equal, bounds) = match &access {
+                Access::Scan => (0, 0),
+                Access::RowId | Access::RowIds(_) => (1, 0),
+                Access::RowIdRange { lower, upper } => (0, *lower as usize + *upper as usize),
+                Access::PrimaryKey(range) | Access::Index { range, .. } => {
+                    (range.equal, range.lower as usize + range.upper as usize)
+                }
+            };
+            paths.push((access, filtered(estimate, equal, bounds)));
+        }
+        Ok(paths)
+    }
+
+    // The collation comparing a column with `other` uses, as `seek_key`
+    // works it out once `other` is evaluated, or None if that gives no key:
+    // when another column's collation applies instead of the column's.
+    fn key_collation(
+        &self,
+        sources: &[Source],
+        table: &Table,
+        column: Option<usize>,
+        other: &Expr,
+    ) -> Option<Collation> {
+        if is_row_id(table, column) {
+            return Some(Collation::Binary);
+        }
+        let own = column
+            .and_then(|i| column_collation(&self.collations, &table.columns[i]))
+            .unwrap_or(Collation::Binary);
+        match other {
+            Expr::Collate { collation, .. } => {
+                self.collations.get(&collation.0.last().unwrap().value).ok()
+            }
+            Expr::Nested(other) => self.key_collation(sources, table, column, other),
+            _ => match referenced_column(sources, other) {
+                Some((s, Some(i))) => {
+                    match column_collation(&self.collations, &sources[s].table.columns[i]) {
+                        Some(theirs) if theirs != own => None,
+                        _ => Some(own),
+                    }
+                }
+                _ => Some(own),
+            },
+        }
+    }
+
+    // The collation an IN term compares the column with, which all its
+    // values must agree on. A subquery's values are columns of it, so only
+    // an explicit COLLATE on them could differ, which is not looked into.
+    fn list_collation(
+        &self,
+        sources: &[Source],
+        table: &Table,
+        column: Option<usize>,
+        term: &Expr,
+    ) -> Option<Collation> {
+        match term {
+            Expr::InList { list, .. } => {
+                let mut collations = list
+                    .iter()
+                    .map(|item| self.key_collation(sources, table, column, item));
+                let first = collations.next()??;
+                collations
+                    .all(|c| c.as_ref() == Some(&first))
+                    .then_some(first)
+            }
+            _ => self.key_collation(sources, table, column, &Expr::Value(ast::Value::Null)),
+        }
+    }
+
+    // Matches a `column IN (...)` term where the column belongs to the
+    // table at `level` and the values only use the tables in `known`, or a
+    // `column IN (SELECT ...)` term whose subquery uses no table of the
+    // query. The column is then looked up once for every value.
+    fn seek_list(
+        &self,
+        sources: &[Source<'a>],
+        level: usize,
+        known: u64,
+        term: &Expr,
+    ) -> Option<Option<usize>> {
+        match term {
+            Expr::InList {
+                expr,
+                list,
+                negated: false,
+            } if !list.is_empty() => {
+                let mut found = None;
+                for item in list {
+                    let (column, other, left) =
+                        seek_operands(sources, self.outer, level, known, expr, item)?;
+                    if !left
+                        || calls_nondeterministic(&self.functions, other)
+                        || found.is_some_and(|c| c != column)
+                    {
+                        return None;
+                    }
+                    found = Some(column);
+                }
+                found
+            }
+            Expr::InSubquery {
+                expr,
+                subquery,
+                negated: false,
+            } => {
+                let (s, column) = referenced_column(sources, expr)?;
+                if s != level {
+                    return None;
+                }
+                let scope = RowScope {
+                    executor: self,
+                    sources,
+                    row: None,
+                };
+                let outer = OuterScope {
+                    scope: &scope,
+                    used: Cell::new(false),
+                };
+                let planned =
+                    self.nested(&outer)
+                        .explain_query(subquery, 0, &mut QueryPlan::default());
+                (planned.is_ok() && !outer.used.get()).then_some(column)
+            }
+            _ => None,
+        }
+    }
+
+    // Which columns of each table the query reads, all of which an index
+    // must hold to be read instead of its table. Subqueries are not
+    // resolved here, so any column whose name appears in one counts.
+    fn used_columns(
+        &self,
+        from: &FromClause<'a>,
+        select: &ast::Select,
+        projection: &[Expr],
+        order: &[OrderTerm],
+    ) -> Vec<Vec<bool>> {
+        let sources = &from.sources;
+        let mut used: Vec<Vec<bool>> = sources
+            .iter()
+            .map(|source| vec![false; source.table.columns.len()])
+            .collect();
+        let mut exprs: Vec<&Expr> = projection
+            .iter()
+            .chain(order.iter().map(|term| &term.expr))
+            .chain(&select.selection)
+            .chain(&select.group_by)
+            .chain(&select.having)
+            .collect();
+        for source in sources {
+            exprs.extend(&source.on);
+            if let Some((_, args)) = &source.function {
+                exprs.extend(args);
+            }
+        }
+        let mut subqueries = Vec::new();
+        for expr in exprs {
+            expr::walk(expr, &mut |e| {
+                let name = match e {
+                    Expr::Identifier(ident) => std::slice::from_ref(ident),
+                    Expr::CompoundIdentifier(idents) => idents.as_slice(),
+                    Expr::Subquery(query) | Expr::Exists(query) => {
+                        subqueries.push(query.to_string().to_lowercase());
+                        return true;
+                    }
+                    Expr::InSubquery { subquery, .. } => {
+                        subqueries.push(subquery.to_string().to_lowercase());
+                        return true;
+                    }
+                    _ => return true,
+                };
+                if let Ok(Some((s, Some(c)))) = lookup_column(sources, name) {
+                    used[s][c] = true;
+                }
+                false
+            });
+        }
+        for text in &subqueries {
+            for (source, used) in sources.iter().zip(&mut used) {
+                for (column, used) in source.table.columns.iter().zip(used) {
+                    *used |= text.contains(&column.name.to_lowercase());
+                }
+            }
+        }
+        used
+    }
+
+    fn source(&self, relation: &TableFactor, left_join: bool) -> Result<Source<'a>, Error> {
+        let (name, alias) = match relation {
+            TableFactor::Table {
+                name, alias, args, ..
+            } if !args.is_empty()
+                || json::TableFunction::from_name(&name.to_string()).is_some() =>
+            {
+                return self.function_source(name, alias.as_ref(), args, left_join)
+            }
+            TableFactor::Table { name, alias, .. } => (name, alias),
+            TableFactor::Derived {
+                lateral: false,
+                subquery,
+                alias,
+            } => return self.derived_source(subquery, alias.as_ref(), left_join),
+            TableFactor::NestedJoin(_) => return Err(unsupported("parenthesized joins")),
+            _ => return Err(unsupported("table functions")),
+        };
+        if let [name] = name.0.as_slice() {
+            if let Some(cte) = self.common_table(&name.value) {
+                let name = alias.as_ref().map_or(&cte.name, |a| &a.name.value);
+                if cte.recursive && !cte.materialize {
+                    return self.queued_source(&cte, name.clone(), left_join);
+                }
+                let relation = self.common_table_relation(&cte)?;
+                return Ok(relation_source(&relation, name.clone(), &[], left_join));
+            }
+        }
+        let table = self.table(&name.0.last().unwrap().value)?;
+        Ok(Source {
+            table: Cow::Borrowed(table),
+            rows: None,
+            queued: None,
+            name: match alias {
+                Some(alias) => alias.name.value.clone(),
+                None => table.name.clone(),
+            },
+            merged: Vec::new(),
+            left_join,
+            on: Vec::new(),
+            function: None,
+            columns: None,
+        })
+    }
+
+    // A table-valued function in FROM. Its rows are produced anew for each
+    // row of the earlier tables, whose columns its arguments may use.
+    fn function_source(
+        &self,
+        name: &ast::ObjectName,
+        alias: Option<&ast::TableAlias>,
+        args: &[FunctionArg],
+        left_join: bool,
+    ) -> Result<Source<'a>, Error> {
+        let function = json::TableFunction::from_name(&name.to_string()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("no such table-valued function: {}", name),
+            )
+        })?;
+        let columns = function
+            .columns()
+            .iter()
+            .map(|name| Column {
+                name: name.to_string(),
+                declared_type: String::new(),
+                not_null: false,
+                collation: None,
+                default: None,
+            })
+            .collect();
+        let name = match alias {
+            Some(alias) => alias.name.value.clone(),
+            None => name.to_string(),
+        };
+        let mut table = Table::transient(name.clone(), columns);
+        table.without_rowid = false;
+        let args = args
+            .iter()
+            .map(|arg| match arg {
+                FunctionArg::Unnamed(arg) | FunctionArg::Named { arg, .. } => arg.clone(),
+            })
+            .collect();
+        Ok(Source {
+            table: Cow::Owned(table),
+            rows: None,
+            queued: None,
+            name,
+            merged: Vec::new(),
+            left_join,
+            on: Vec::new(),
+            function: Some((function, args)),
+            columns: None,
+        })
+    }
+
+    // The rows of the table-valued function at `level` for the rows of the
+    // earlier tables.
+    fn function_rows(
+        &self,
+        from: &FromClause<'a>,
+        level: usize,
+        row: &[Option<TableRow>],
+    ) -> Result<Rows<'a>, Error> {
+        let source = &from.sources[level];
+        let (_, args) = source.function.as_ref().unwrap();
+        let scope = RowScope {
+            executor: self,
+            sources: &from.sources,
+            row: Some(row),
+        };
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(expr::evaluate(arg, &scope)?);
+        }
+        source.function_rows(&values)
+    }
+
+    // A subquery in FROM is run once, and its rows are kept for the join.
+    fn derived_source(
+        &self,
+        query: &ast::Query,
+        alias: Option<&ast::TableAlias>,
+        left_join: bool,
+    ) -> Result<Source<'a>, Error> {
+        let relation = self.query(query)?;
+        let name = alias.map(|a| a.name.value.clone()).unwrap_or_default();
+        let columns = alias.map_or(&[][..], |a| &a.columns);
+        Ok(relation_source(&relation, name, columns, left_join))
+    }
+
+    // Runs a compound SELECT or VALUES, then sorts and limits its rows.
+    fn compound(&self, query: &ast::Query) -> Result<Relation, Error> {
+        let mut relation = self.set_expr(&query.body)?;
+        let order = compound_order(&self.collations, query, &relation)?;
+        if !order.is_empty() {
+            let keys: Vec<SortKey> = order.iter().map(|(_, key)| key.clone()).collect();
+            let mut rows: Vec<(Vec<SqlValue>, Vec<SqlValue>)> = relation
+                .rows
+                .into_iter()
+                .map(|row| (order.iter().map(|(i, _)| row[*i].clone()).collect(), row))
+                .collect();
+            rows.sort_by(|a, b| compare_rows(&keys, &a.0, &b.0));
+            relation.rows = rows.into_iter().map(|(_, row)| row).collect();
+        }
+        let (limit, offset) = limit_offset(query, &self.empty_scope())?;
+        if offset > 0 || limit.is_some() {
+            relation.rows = std::mem::take(&mut relation.rows)
+                .into_iter()
+                .skip(offset as usize)
+                .take(limit.map_or(usize::MAX, |l| l as usize))
+                .collect();
+        }
+        Ok(relation)
+    }
+
+    // Runs one part of a compound SELECT. UNION ALL keeps every row, and
+    // the other operators give distinct rows in sorted order, compared with
+    // the first collation either side of each column has.
+    fn set_expr(&self, body: &SetExpr) -> Result<Relation, Error> {
+        let (op, all, left, right) = match body {
+            SetExpr::Select(_) => return self.query(&plain_query(body)),
+            SetExpr::Query(query) => return self.query(query),
+            SetExpr::Values(values) => return self.values(values),
+            SetExpr::Insert(_) => return Err(unsupported("INSERT in a query")),
+            SetExpr::SetOperation {
+                op,
+                all,
+                left,
+                right,
+            } => (op, *all, left, right),
+        };
+        let mut left = self.set_expr(left)?;
+        let right = self.set_expr(right)?;
+        if left.columns.len() != right.columns.len() {
+            let name = match (op, all) {
+                (ast::SetOperator::Union, true) => "UNION ALL".to_string(),
+                (op, _) => op.to_string(),
+            };
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "SELECTs to the left and right of {} do not have the same number of result columns",
+                    name
+                ),
+            ));
+        }
+        for (left, right) in left.types.iter_mut().zip(&right.types) {
+            left.1 = left.1.take().or_else(|| right.1.clone());
+        }
+        if all && *op == ast::SetOperator::Union {
+            left.rows.extend(right.rows);
+            return Ok(left);
+        }
+        let collations: Rc<[Collation]> = left
+            .types
+            .iter()
+            .map(|(_, collation)| collation.clone().unwrap_or(Collation::Binary))
+            .collect();
+        let key = |row: &Vec<SqlValue>| GroupKey::new(row.clone(), collations.clone());
+        // of equal rows, the last one is kept
+        let mut rows = BTreeMap::new();
+        match op {
+            ast::SetOperator::Union => {
+                for row in left.rows.into_iter().chain(right.rows) {
+                    rows.insert(key(&row), row);
+                }
+            }
+            ast::SetOperator::Intersect | ast::SetOperator::Except => {
+                let right: BTreeSet<GroupKey> = right.rows.iter().map(key).collect();
+                let intersect = *op == ast::SetOperator::Intersect;
+                for row in left.rows {
+                    if right.contains(&key(&row)) == intersect {
+                        rows.insert(key(&row), row);
+                    }
+                }
+            }
+        }
+        left.rows = rows.into_values().collect();
+        Ok(left)
+    }
+
+    fn values(&self, values: &ast::Values) -> Result<Relation, Error> {
+        let width = values.0.first().map_or(0, |row| row.len());
+        let scope = self.empty_scope();
+        let mut rows = Vec::with_capacity(values.0.len());
+        for row in &values.0 {
+            if row.len() != width {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "all VALUES must have the same number of terms",
+                ));
+            }
+            rows.push(
+                row.iter()
+                    .map(|expr| expr::evaluate(expr, &scope))
+                    .collect::<Result<_, _>>()?,
+            );
+        }
+        let types = match values.0.first() {
+            Some(row) => row
+                .iter()
+                .map(|expr| expr_type(&self.collations, &[], expr))
+                .collect(),
+            None => Vec::new(),
+        };
+        Ok(Relation {
+            columns: (1..=width).map(|i| format!("column{}", i)).collect(),
+            types,
+            rows,
+        })
+    }
+
+    fn common_table(&self, name: &str) -> Option<Rc<CommonTable>> {
+        let mut cte = self.ctes.as_ref();
+        while let Some(table) = cte {
+            if table.name.eq_ignore_ascii_case(name) {
+                return Some(table.clone());
+            }
+            cte = table.parent.as_ref();
+        }
+        None
+    }
+
+    // Runs a query with common table expressions, which the query and the
+    // expressions after each one can use as tables.
+    fn with_query(&self, with: &ast::With, query: &ast::Query) -> Result<Relation, Error> {
+        let body = ast::Query {
+            with: None,
+            ..query.clone()
+        };
+        self.common_tables(with, &body).query(&body)
+    }
+
+    // An executor that sees the common table expressions of WITH, for the
+    // query `body` that follows it.
+    fn common_tables(&self, with: &ast::With, body: &ast::Query) -> Executor<'a> {
+        let mut ctes = self.ctes.clone();
+        for (i, cte) in with.cte_tables.iter().enumerate() {
+            let name = &cte.alias.name.value;
+            let recursive = with.recursive && table_references(&cte.query, name) > 0;
+            let uses = table_references(body, name)
+                + with.cte_tables[i + 1..]
+                    .iter()
+                    .map(|c| table_references(&c.query, name))
+                    .sum::<usize>();
+            // a table used once is run where it is used, like a subquery in
+            // FROM, unless asked otherwise. A recursive one is then read as
+            // its rows are produced, so the query may stop it early.
+            let materialize = sql_parser::materialization_hint(cte).unwrap_or(uses > 1);
+            ctes = Some(Rc::new(CommonTable {
+                name: name.clone(),
+                columns: cte.alias.columns.iter().map(|c| c.value.clone()).collect(),
+                query: Rc::new(cte.query.clone()),
+                recursive,
+                materialize,
+                relation: RefCell::new(None),
+                parent: ctes,
+            }));
+        }
+        self.with_ctes(ctes)
+    }
+
+    // The rows of a common table expression, run the first time it is used
+    // if it is materialized, and every time otherwise.
+    fn common_table_relation(&self, cte: &Rc<CommonTable>) -> Result<Rc<Relation>, Error> {
+        if let Some(relation) = cte.relation.borrow().as_ref() {
+            return Ok(relation.clone());
+        }
+        let mut relation = if cte.recursive {
+            self.recursive_table(cte)?
+        } else {
+            self.with_ctes(cte.parent.clone()).query(&cte.query)?
+        };
+        check_columns(cte, relation.columns.len())?;
+        if !cte.columns.is_empty() {
+            relation.columns = cte.columns.clone();
+        }
+        let relation = Rc::new(relation);
+        if cte.materialize {
+            *cte.relation.borrow_mut() = Some(relation.clone());
+        }
+        Ok(relation)
+    }
+
+    // A recursive table used in one place, read as its rows are produced.
+    fn queued_source(
+        &self,
+        cte: &Rc<CommonTable>,
+        name: String,
+        left_join: bool,
+    ) -> Result<Source<'a>, Error> {
+        let queue = RecursiveQueue::new(self, cte)?;
+        check_columns(cte, queue.columns.len())?;
+        let relation = Relation {
+            columns: queue.columns.clone(),
+            types: queue.types.clone(),
+            rows: Vec::new(),
+        };
+        let mut source = relation_source(&relation, name, &[], left_join);
+        source.rows = None;
+        source.queued = Some(Rc::new(QueuedRows {
+            read: RefCell::new(Vec::new()),
+            queue: RefCell::new(queue),
+        }));
+        Ok(source)
+    }
+
+    // Runs `initial UNION [ALL] recursive` to the end, for a table that is
+    // kept for every use.
+    fn recursive_table(&self, cte: &Rc<CommonTable>) -> Result<Relation, Error> {
+        let mut queue = RecursiveQueue::new(self, cte)?;
+        let mut rows = Vec::new();
+        while let Some(values) = queue.next_row()? {
+            rows.push(values);
+        }
+        Ok(Relation {
+            columns: queue.columns,
+            types: queue.types,
+            rows,
+        })
+    }
+
+    // Describes how a query would be run, as EXPLAIN QUERY PLAN does.
+    // Planning runs the subqueries and common table expressions in FROM,
+    // whose rows the rest of the query is planned around.
+    pub fn query_plan(&self, statement: &Statement) -> Result<QueryPlan, Error> {
+        let query = match statement {
+            Statement::Query(query) => query,
+            _ => {
+                return Err(unsupported(
+                    "EXPLAIN QUERY PLAN of statements other than SELECT",
+                ))
+            }
+        };
+        self.subqueries.borrow_mut().clear();
+        let mut plan = QueryPlan::default();
+        self.explain_query(query, 0, &mut plan)?;
+        Ok(plan)
+    }
+
+    fn explain_query(
+        &self,
+        query: &ast::Query,
+        parent: usize,
+        plan: &mut QueryPlan,
+    ) -> Result<(), Error> {
+        if let Some(with) = &query.with {
+            let body = ast::Query {
+                with: None,
+                ..query.clone()
+            };
+            return self
+                .common_tables(with, &body)
+                .explain_query(&body, parent, plan);
+        }
+        if let SetExpr::Select(select) = &query.body {
+            return self.explain_select(query, select, parent, plan);
+        }
+        self.explain_set_expr(&query.body, parent, plan)?;
+        if !query.order_by.is_empty() {
+            plan.add(parent, "USE TEMP B-TREE FOR ORDER BY".to_string());
+        }
+        Ok(())
+    }
+
+    fn explain_select(
+        &self,
+        query: &ast::Query,
+        select: &ast::Select,
+        parent: usize,
+        plan: &mut QueryPlan,
+    ) -> Result<(), Error> {
+        // the rows of subqueries and common tables in FROM come first
+        let mut listed: Vec<Rc<CommonTable>> = Vec::new();
+        for item in &select.from {
+            let factors =
+                std::iter::once(&item.relation).chain(item.joins.iter().map(|j| &j.relation));
+            for factor in factors {
+                match factor {
+                    TableFactor::Derived {
+                        subquery, alias, ..
+                    } => {
+                        let name = alias.as_ref().map_or("", |a| a.name.value.as_str());
+                        let id = plan.add(parent, format!("CO-ROUTINE {}", name));
+                        self.explain_query(subquery, id, plan)?;
+                    }
+                    TableFactor::Table { name, args, .. } if args.is_empty() => {
+                        let cte = match name.0.as_slice() {
+                            [name] => self.common_table(&name.value),
+                            _ => None,
+                        };
+                        // the working table of a recursive step is known
+                        if let Some(cte) = cte.filter(|cte| cte.relation.borrow().is_none()) {
+                            if !listed.iter().any(|c| Rc::ptr_eq(c, &cte)) {
+                                // named as SQLite names them, by whether
+                                // they are kept for every use
+                                let kind = if cte.materialize && !cte.recursive {
+                                    "MATERIALIZE"
+                                } else {
+                                    "CO-ROUTINE"
+                                };
+                                let id = plan.add(parent, format!("{} {}", kind, cte.name));
+                                self.explain_common_table(&cte, id, plan)?;
+                                listed.push(cte);
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let planned = self.plan_select(query, select)?;
+        let from = &planned.from;
+        if from.sources.is_empty() {
+            plan.add(parent, "SCAN CONSTANT ROW".to_string());
+        }
+        for (level, source) in from.sources.iter().enumerate() {
+            let mut detail = match (source.stored(), &source.function) {
+                (Some(table), _) => planner::describe(&source.name, table, &from.access[level]),
+                // numbered as SQLite's json_each numbers its arguments
+                (None, Some((_, args))) if args.len() > 1 => {
+                    format!("SCAN {} VIRTUAL TABLE INDEX 3:", source.name)
+                }
+                (None, Some(_)) => format!("SCAN {} VIRTUAL TABLE INDEX 1:", source.name),
+                (None, None) => format!("SCAN {}", source.name),
+            };
+            if source.left_join {
+                detail.push_str(" LEFT-JOIN");
+            }
+            plan.add(parent, detail);
+        }
+
+        // then the subqueries in expressions, run for every row they are
+        // used on if they use its columns
+        let mut exprs: Vec<&Expr> = select
+            .projection
+            .iter()
+            .filter_map(|item| match item {
+                SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                    Some(expr)
+                }
+                _ => None,
+            })
+            .chain(&select.selection)
+            .chain(&select.group_by)
+            .chain(&select.having)
+            .chain(query.order_by.iter().map(|term| &term.expr))
+            .collect();
+        for source in &from.sources {
+            exprs.extend(&source.on);
+        }
+        let mut subqueries = Vec::new();
+        for expr in exprs {
+            expr::walk(expr, &mut |e| {
+                match e {
+                    Expr::Subquery(query) | Expr::Exists(query) => {
+                        subqueries.push(("SCALAR", query.as_ref().clone()))
+                    }
+                    Expr::InSubquery { subquery, .. } => {
+                        subqueries.push(("LIST", subquery.as_ref().clone()))
+                    }
+                    _ => (),
+                }
+                true
+            });
+        }
+        let scope = RowScope {
+            executor: self,
+            sources: &from.sources,
+            row: None,
+        };
+        for (kind, subquery) in subqueries {
+            let outer = OuterScope {
+                scope: &scope,
+                used: Cell::new(false),
+            };
+            let number = plan.next_subquery();
+            let id = plan.add(parent, format!("{} SUBQUERY {}", kind, number));
+            self.nested(&outer).explain_query(&subquery, id, plan)?;
+            if outer.used.get() {
+                plan.steps[id - 1].2.insert_str(0, "CORRELATED ");
+            }
+        }
+
+        // a query using the enclosing one is correlated
+        if self.outer.is_some() {
+            for expr in select
+                .projection
+                .iter()
+                .filter_map(|item| match item {
+                    SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                        Some(expr)
+                    }
+                    _ => None,
+                })
+                .chain(&select.group_by)
+                .chain(&select.having)
+            {
+                referenced_sources(&from.sources, self.outer, expr);
+            }
+        }
+
+        if !select.group_by.is_empty() && !planned.grouped {
+            plan.add(parent, "USE TEMP B-TREE FOR GROUP BY".to_string());
+        }
+        if select.distinct {
+            plan.add(parent, "USE TEMP B-TREE FOR DISTINCT".to_string());
+        }
+        if planned.sort {
+            plan.add(parent, "USE TEMP B-TREE FOR ORDER BY".to_string());
+        }
+        Ok(())
+    }
+
+    // Describes a compound SELECT as a list of its parts, or a single
+    // SELECT or VALUES.
+    fn explain_set_expr(
+        &self,
+        body: &SetExpr,
+        parent: usize,
+        plan: &mut QueryPlan,
+    ) -> Result<(), Error> {
+        match body {
+            SetExpr::Select(_) => return self.explain_query(&plain_query(body), parent, plan),
+            SetExpr::Query(query) => return self.explain_query(query, parent, plan),
+            SetExpr::Values(values) => {
+                let detail = match values.0.len() {
+                    1 => "SCAN CONSTANT ROW".to_string(),
+                    n => format!("SCAN {}-ROW VALUES CLAUSE", n),
+                };
+                plan.add(parent, detail);
+                return Ok(());
+            }
+            SetExpr::Insert(_) => return Err(unsupported("INSERT in a query")),
+            SetExpr::SetOperation { .. } => (),
+        }
+        let mut parts = Vec::new();
+        let mut rest = body;
+        while let SetExpr::SetOperation {
+            op,
+            all,
+            left,
+            right,
+        } = rest
+        {
+            let detail = match (op, all) {
+                (ast::SetOperator::Union, true) => "UNION ALL".to_string(),
+                (op, _) => format!("{} USING TEMP B-TREE", op),
+            };
+            parts.push((detail, right.as_ref()));
+            rest = left;
+        }
+        parts.push(("LEFT-MOST SUBQUERY".to_string(), rest));
+        let compound = plan.add(parent, "COMPOUND QUERY".to_string());
+        for (detail, part) in parts.into_iter().rev() {
+            let id = plan.add(compound, detail);
+            self.explain_set_expr(part, id, plan)?;
+        }
+        Ok(())
+    }
+
+    // Describes the query of a common table expression. A recursive one
+    // has an initial query and a step that runs on each row so far.
+    fn explain_common_table(
+        &self,
+        cte: &Rc<CommonTable>,
+        parent: usize,
+        plan: &mut QueryPlan,
+    ) -> Result<(), Error> {
+        let outer = self.with_ctes(cte.parent.clone());
+        let (initial, recursive) = match &cte.query.body {
+            SetExpr::SetOperation { left, right, .. } if cte.recursive => (left, right),
+            _ => return outer.explain_query(&cte.query, parent, plan),
+        };
+        let setup = plan.add(parent, "SETUP".to_string());
+        outer.explain_set_expr(initial, setup, plan)?;
+        // the step sees the table as the rows of the initial query, without
+        // any of them, which is enough to plan it
+        let mut working = outer.query(&plain_query(initial))?;
+        working.rows.clear();
+        if !cte.columns.is_empty() && cte.columns.len() == working.columns.len() {
+            working.columns = cte.columns.clone();
+        }
+        let working = CommonTable {
+            name: cte.name.clone(),
+            columns: Vec::new(),
+            query: cte.query.clone(),
+            recursive: false,
+            materialize: true,
+            relation: RefCell::new(Some(Rc::new(working))),
+            parent: cte.parent.clone(),
+        };
+        let step = plan.add(parent, "RECURSIVE STEP".to_string());
+        self.with_ctes(Some(Rc::new(working)))
+            .explain_set_expr(recursive, step, plan)
+    }
+
+    // The keys that narrow down the rows of the table at `level` of the
+    // join, given the rows of the tables before it. Returns None if the
+    // conditions cannot match any row.
+    fn constraints(
+        &self,
+        from: &FromClause<'a>,
+        level: usize,
+        row: &[Option<TableRow>],
+    ) -> Result<Option<Keys>, Error> {
+        let scope = RowScope {
+            executor: self,
+            sources: &from.sources,
+            row: Some(row),
+        };
+        // errors surface when the condition itself is evaluated
+        let operands = key_exprs(from, level)
+            .into_iter()
+            .map(|expr| expr::evaluate_operand(expr, &scope).ok())
+            .collect();
+        self.seek_keys(from, level, operands, &scope)
+    }
+
+    // The keys the values of the expressions `key_exprs` lists give for the
+    // table at `level` of the join, skipping those without a value. The
+    // values of IN subqueries come from `scope`. Returns None if the
+    // conditions cannot match any row.
+    fn seek_keys(
+        &self,
+        from: &FromClause<'a>,
+        level: usize,
+        operands: Vec<Option<Operand>>,
+        scope: &dyn Scope,
+    ) -> Result<Option<Keys>, Error> {
+        let table = &from.sources[level].table;
+        let mut operands = operands.into_iter();
+        let mut keys = Keys::default();
+        for (column, _) in &from.equalities[level] {
+            let operand = match operands.next().flatten() {
+                Some(operand) => operand,
+                None => continue,
+            };
+            match seek_key(&self.collations, table, *column, operand) {
+                Seek::Key(value, collation) => keys.equal.push((*column, value, collation)),
+                Seek::NoRows => return Ok(None),
+                Seek::Scan => (),
+            }
+        }
+        for (column, term) in &from.lists[level] {
+            let items = match term {
+                Expr::InList { list, .. } => {
+                    let items: Vec<Option<Operand>> = operands.by_ref().take(list.len()).collect();
+                    items.into_iter().collect()
+                }
+                Expr::InSubquery { subquery, .. } => subquery_operands(subquery, scope),
+                _ => None,
+            };
+            let keys_of_list = items.and_then(|items| self.list_keys(table, *column, items));
+            let (mut values, collation) = match keys_of_list {
+                Some(values) => values,
+                None => continue,
+            };
+            if values.is_empty() {
+                return Ok(None);
+            }
+            if keys.equal_value(*column, &collation).is_some() {
+                continue;
+            }
+            keys.lists.push((keys.equal.len(), values.split_off(1)));
+            keys.equal.push((*column, values.pop().unwrap(), collation));
+        }
+        for (column, bound, _) in &from.ranges[level] {
+            let operand = match operands.next().flatten() {
+                Some(operand) => operand,
+                None => continue,
+            };
+            match bound_key(&self.collations, table, *column, operand) {
+                Seek::Key(value, collation) => {
+                    keys.bounds.push((*column, *bound, value, collation))
+                }
+                Seek::NoRows => return Ok(None),
+                Seek::Scan => (),
+            }
+        }
+        Ok(Some(keys))
+    }
+
+    // The distinct keys the values of an IN term give, in order, and the
+    // collation they compare with. No keys means no value can match, and
+    // None that some value gives no key, so the term cannot be seeked.
+    fn list_keys(
+        &self,
+        table: &Table,
+        column: Option<usize>,
+        operands: Vec<Operand>,
+    ) -> Option<(Vec<SqlValue>, Collation)> {
+        let mut values = Vec::with_capacity(operands.len());
+        let mut collation: Option<Collation> = None;
+        for operand in operands {
+            match seek_key(&self.collations, table, column, operand) {
+                Seek::Key(value, key) => {
+                    if collation.as_ref().is_some_and(|c| *c != key) {
+                        return None;
+                    }
+                    collation = Some(key);
+                    values.push(value);
+                }
+                Seek::NoRows => (),
+                Seek::Scan => return None,
+            }
+        }
+        let collation = collation.unwrap_or(Collation::Binary);
+        values.sort_by(|a, b| expr::compare_values(a, b, &collation));
+        values.dedup_by(|a, b| expr::compare_values(a, b, &collation) == Ordering::Equal);
+        Some((values, collation))
+    }
+
+    // Runs the nested loops of a join, calling `visit` with every combined
+    // row that passes the join conditions until it returns false.
+    fn join(&self, from: &FromClause<'a>, visit: &mut Visit) -> Result<(), Error> {
+        let mut row = vec![None; from.sources.len()];
+        self.next_level(from, 0, &mut row, visit)?;
+        Ok(())
+    }
+
+    fn join_level(
+        &self,
+        from: &FromClause<'a>,
+        level: usize,
+        candidates: Rows<'a>,
+        row: &mut [Option<TableRow>],
+        visit: &mut Visit,
+    ) -> Result<bool, Error> {
+        let mut matched = false;
+        for candidate in candidates {
+            row[level] = Some(candidate?);
+            let scope = RowScope {
+                executor: self,
+                sources: &from.sources,
+                row: Some(row),
+            };
+            let mut passes = true;
+            for filter in &from.filters[level] {
+                if !expr::is_true(filter, &scope)? {
+                    passes = false;
+                    break;
+                }
+            }
+            if !passes {
+                continue;
+            }
+            matched = true;
+            if !self.next_level(from, level + 1, row, visit)? {
+                return Ok(false);
+            }
+        }
+        // a LEFT JOIN without a matching row pairs the earlier tables with a
+        // row of NULLs
+        row[level] = None;
+        if !matched
+            && from.sources[level].left_join
+            && !self.next_level(from, level + 1, row, visit)?
+        {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn next_level(
+        &self,
+        from: &FromClause<'a>,
+        level: usize,
+        row: &mut [Option<TableRow>],
+        visit: &mut Visit,
+    ) -> Result<bool, Error> {
+        if level == from.sources.len() {
+            return visit(row);
+        }
+        let source = &from.sources[level];
+        let candidates = match source.stored() {
+            Some(table) => match self.constraints(from, level, row)? {
+                Some(keys) => {
+                    let access = self.available_access(table, &from.access[level], &keys);
+                    self.rows_by(table, &access, &keys, &source.columns, false)?
+                }
+                None => Box::new(std::iter::empty()),
+            },
+            None if source.function.is_some() => self.function_rows(from, level, row)?,
+            None => source.materialized(),
+        };
+        self.join_level(from, level, candidates, row, visit)
+    }
+
+    pub fn table(&self, name: &str) -> Result<&'a Table, Error> {
+        self.schema
+            .table(name)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("no such table: {}", name)))
+    }
+
+    // Iterates over all rows of a table. Rowid tables are walked in rowid
+    // order, WITHOUT ROWID tables in primary key order.
+    pub fn scan(&self, table: &'a Table) -> TableScan<'a> {
+        TableScan {
+            table,
+            cursor: BTreeCursor::new(self.pager, table.root_page),
+            bounds: None,
+            last_row_id: None,
+            columns: None,
+        }
+    }
+
+    // Finds the row with the given rowid, or the given primary key for
+    // WITHOUT ROWID tables.
+    pub fn lookup(&self, table: &'a Table, key: &[SqlValue]) -> Result<Option<TableRow>, Error> {
+        self.lookup_columns(table, key, None)
+    }
+
+    // Like `lookup`, but decodes only the columns flagged in `columns`.
+    fn lookup_columns(
+        &self,
+        table: &'a Table,
+        key: &[SqlValue],
+        columns: Option<&[bool]>,
+    ) -> Result<Option<TableRow>, Error> {
+        if !table.without_rowid {
+            let row_id = match key {
+                [value] => value.as_i64(),
+                _ => None,
+            };
+            let row_id = match row_id {
+                Some(row_id) => row_id,
+                None => return Ok(None),
+            };
+            let mut cursor = BTreeCursor::new(self.pager, table.root_page);
+            if !cursor.seek_row_id(row_id)? || cursor.row_id() != Some(row_id) {
+                return Ok(None);
+            }
+            let record = cursor.lazy_record()?;
+            return Ok(Some(TableRow {
+                row_id: Some(row_id),
+                values: table.row_from_lazy_record(Some(row_id), &record, columns)?,
+            }));
+        }
+        let mut scan = self.scan_key(table, key.to_vec())?;
+        scan.columns = columns.map(Rc::from);
+        scan.next().transpose()
+    }
+
+    // Iterates over the rows of a WITHOUT ROWID table whose primary key
+    // starts with `key`, compared with the collations of the key columns.
+    pub fn scan_key(&self, table: &'a Table, key: Vec<SqlValue>) -> Result<TableScan<'a>, Error> {
+        let mut collations = primary_key_collations(&self.collations, table);
+        collations.truncate(key.len());
+        self.scan_bounds(table, KeyBounds::prefix(key, collations))
+    }
+
+    // Iterates over the rows of a WITHOUT ROWID table whose primary key lies
+    // within the bounds.
+    fn scan_bounds(&self, table: &'a Table, bounds: KeyBounds) -> Result<TableScan<'a>, Error> {
+        let mut cursor = BTreeCursor::new(self.pager, table.root_page);
+        bounds.seek(&mut cursor)?;
+        Ok(TableScan {
+            table,
+            cursor,
+            bounds: Some(bounds),
+            last_row_id: None,
+            columns: None,
+        })
+    }
+
+    // Iterates over the rows of a rowid table whose rowid lies between the
+    // bounds, both included.
+    pub fn scan_row_ids(
+        &self,
+        table: &'a Table,
+        first: Option<i64>,
+        last: Option<i64>,
+    ) -> Result<TableScan<'a>, Error> {
+        let mut cursor = BTreeCursor::new(self.pager, table.root_page);
+        if let Some(first) = first {
+            cursor.seek_row_id(first)?;
+        }
+        Ok(TableScan {
+            table,
+            cursor,
+            bounds: None,
+            last_row_id: last,
+            columns: None,
+        })
+    }
+
+    // Finds the rows of a table the way the plan says, as far as the keys
+    // the conditions give allow. A value that does not fit the column, such
+    // as text compared with a rowid, gives no key, and then a shorter key or
+    // a scan has to do. An index is still read from its first entry, as the
+    // program reading the rows expects its entries.
+    fn available_access(&self, table: &'a Table, planned: &Access<'a>, keys: &Keys) -> Access<'a> {
+        match planned {
+            Access::Scan => Access::Scan,
+            Access::RowId | Access::RowIds(_) | Access::RowIdRange { .. } => {
+                if let Some(list) = keys.row_id_list(table) {
+                    return Access::RowIds(keys.lists[list].1.len() + 1);
+                }
+                if keys.row_id(table).is_some() {
+                    return Access::RowId;
+                }
+                let (first, last) = keys.row_id_range(table);
+                match (first.is_some(), last.is_some()) {
+                    (false, false) => Access::Scan,
+                    (lower, upper) => Access::RowIdRange { lower, upper },
+                }
+            }
+            Access::PrimaryKey(range) => {
+                let columns = primary_key_columns(&self.collations, table);
+                match keys.available(&columns, range) {
+                    range if range.is_full() => Access::Scan,
+                    range => Access::PrimaryKey(range),
+                }
+            }
+            Access::Index {
+                index,
+                range,
+                covering,
+            } => {
+                let available = match index_columns(&self.collations, index) {
+                    Some(columns) => keys.available(&columns, range),
+                    None => KeyRange::default(),
+                };
+                Access::Index {
+                    index,
+                    range: available,
+                    covering: *covering,
+                }
+            }
+        }
+    }
+
+    // The rows of a table found one way, with the keys the conditions give.
+    // Only the columns flagged in `used` are decoded. With `entries`, an
+    // index gives its entries rather than rows.
+    fn rows_by(
+        &self,
+        table: &'a Table,
+        access: &Access<'a>,
+        keys: &Keys,
+        used: &Option<Rc<[bool]>>,
+        entries: bool,
+    ) -> Result<Rows<'a>, Error> {
+        // an IN term the seek uses is one seek for each of its values
+        let list = match access {
+            Access::RowIds(_) => keys.row_id_list(table),
+            Access::PrimaryKey(range) => primary_key_columns(&self.collations, table)
+                [..range.equal]
+                .iter()
+                .find_map(|(column, _, collation)| keys.list(*column, collation)),
+            Access::Index { index, range, .. } => index_columns(&self.collations, index)
+                .unwrap_or_default()[..range.equal]
+                .iter()
+                .find_map(|(column, _, collation)| keys.list(*column, collation)),
+            _ => None,
+        };
+        if let Some(list) = list {
+            let access = match access {
+                Access::RowIds(_) => &Access::RowId,
+                access => access,
+            };
+            let mut parts = Vec::new();
+            for keys in keys.each_value(list) {
+                parts.push(self.rows_by(table, access, &keys, used, entries)?);
+            }
+            return Ok(Box::new(parts.into_iter().flatten()));
+        }
+        let with_columns = |mut scan: TableScan<'a>| {
+            scan.columns = used.clone();
+            Box::new(scan) as Rows<'a>
+        };
+        Ok(match access {
+            Access::Scan => with_columns(self.scan(table)),
+            Access::RowId | Access::RowIds(_) => {
+                let row_id = keys.row_id(table).unwrap_or(SqlValue::Null);
+                let row = self.lookup_columns(table, &[row_id], used.as_deref())?;
+                Box::new(row.into_iter().map(Ok))
+            }
+            Access::RowIdRange { .. } => {
+                let (first, last) = keys.row_id_range(table);
+                with_columns(self.scan_row_ids(table, first, last)?)
+            }
+            Access::PrimaryKey(range) => {
+                let columns = primary_key_columns(&self.collations, table);
+                with_columns(self.scan_bounds(table, keys.bounds(&columns, range))?)
+            }
+            Access::Index {
+                index,
+                range,
+                covering,
+            } => {
+                let columns = index_columns(&self.collations, index).unwrap_or_default();
+                let mut scan = self.index_range(table, index, keys.bounds(&columns, range))?;
+                scan.covering = *covering;
+                scan.entries = entries;
+                scan.columns = used.clone();
+                Box::new(scan)
+            }
+        })
+    }
+
+    // Whether finding the rows of the table at position `s` one way gives
+    // them in the order of the ORDER BY terms, which must all be columns of
+    // that table.
+    fn access_order(
+        &self,
+        sources: &[Source<'a>],
+        s: usize,
+        access: &Access,
+        order: &[OrderTerm],
+    ) -> bool {
+        let (table, columns) = match (sources[s].stored(), order_columns(sources, s, order)) {
+            (Some(table), Some(columns)) => (table, columns),
+            _ => return false,
+        };
+        match access {
+            // a lookup gives at most one row, which is trivially in order
+            Access::RowId => true,
+            // so do scans, and seeks for each value of a list, which are
+            // looked up in order
+            Access::Scan | Access::RowIds(_) | Access::RowIdRange { .. }
+                if !table.without_rowid =>
+            {
+                match columns.as_slice() {
+                    [(column, key)] => {
+                        (column.is_none() || *column == table.rowid_alias)
+                            && !key.descending
+                            && key.nulls_first
+                    }
+                    _ => false,
+                }
+            }
+            Access::Scan | Access::RowIds(_) | Access::RowIdRange { .. } => {
+                sorted_by(&primary_key_columns(&self.collations, table), 0, &columns)
+            }
+            Access::PrimaryKey(range) => sorted_by(
+                &primary_key_columns(&self.collations, table),
+                fixed_columns(range),
+                &columns,
+            ),
+            Access::Index { index, range, .. } => {
+                let mut keys = match index_columns(&self.collations, index) {
+                    Some(keys) => keys,
+                    None => return false,
+                };
+                // entries with equal values are in the order of the rowid or
+                // primary key they end with
+                if table.without_rowid {
+                    let primary_key = primary_key_columns(&self.collations, table);
+                    keys.extend(
+                        primary_key
+                            .into_iter()
+                            .filter(|(c, _, _)| !index.columns.iter().any(|ic| ic.column == *c)),
+                    );
+                } else {
+                    keys.push((table.rowid_alias, false, Collation::Binary));
+                }
+                sorted_by(&keys, fixed_columns(range), &columns)
+            }
+        }
+    }
+
+    // Iterates over the rows of a table in the order of one of its indexes.
+    pub fn index_scan(&self, table: &'a Table, index: &'a Index) -> IndexScan<'a> {
+        IndexScan {
+            executor: self.row_finder(),
+            table,
+            index,
+            cursor: BTreeCursor::new(self.pager, index.root_page),
+            bounds: None,
+            covering: false,
+            entries: false,
+            columns: None,
+        }
+    }
+
+    // Iterates over the rows of a table whose entries in an index start
+    // with `key`, in index order. Keys compare with the index's own
+    // collations, so a NOCASE index finds every spelling of a name.
+    pub fn index_seek(
+        &self,
+        table: &'a Table,
+        index: &'a Index,
+        key: Vec<SqlValue>,
+    ) -> Result<IndexScan<'a>, Error> {
+        let mut collations = Vec::with_capacity(key.len());
+        for ic in &index.columns[..key.len()] {
+            collations.push(match &ic.collation {
+                Some(name) => self.collations.get(name)?,
+                None => Collation::Binary,
+            });
+        }
+        self.index_range(table, index, KeyBounds::prefix(key, collations))
+    }
+
+    // Iterates over the rows of a table whose entries in an index lie
+    // within the bounds, in index order.
+    fn index_range(
+        &self,
+        table: &'a Table,
+        index: &'a Index,
+        bounds: KeyBounds,
+    ) -> Result<IndexScan<'a>, Error> {
+        let mut cursor = BTreeCursor::new(self.pager, index.root_page);
+        bounds.seek(&mut cursor)?;
+        Ok(IndexScan {
+            executor: self.row_finder(),
+            table,
+            index,
+            cursor,
+            bounds: Some(bounds),
+            covering: false,
+            entries: false,
+            columns: None,
+        })
+    }
+
+    // A scope without tables, for expressions such as LIMIT that may only
+    // use constants, parameters and the enclosing query.
+    fn empty_scope(&self) -> RowScope<'_> {
+        RowScope {
+            executor: self,
+            sources: &[],
+            row: None,
+        }
+    }
+
+    // An executor for looking up the rows index entries point at, which for
+    // WITHOUT ROWID tables needs the collations of the primary key.
+    fn row_finder(&self) -> Executor<'a> {
+        Executor {
+            collations: self.collations.clone(),
+            functions: self.functions.clone(),
+            parameters: self.parameters.clone(),
+            ..Executor::new(self.pager, self.schema)
+        }
+    }
+}
+
+// Visits the entries of an index and looks up the table row each of them
+// points at, unless the index covers every column the query uses.
+pub struct IndexScan<'a> {
+    executor: Executor<'a>,
+    table: &'a Table,
+    index: &'a Index,
+    cursor: BTreeCursor<'a>,
+    // Only entries within these bounds are visited.
+    bounds: Option<KeyBounds>,
+    // Whether rows are made from the index entries alone, with NULL for the
+    // columns the index does not have.
+    covering: bool,
+    // Whether the entries themselves are given, with the rowid they end
+    // with for rowid tables.
+    entries: bool,
+    // The columns decoded of the rows looked up in the table.
+    columns: Option<Rc<[bool]>>,
+}
+
+impl<'a> Iterator for IndexScan<'a> {
+    type Item = Result<TableRow, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.cursor.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            let values = entry.record.into_values();
+            if let Some(bounds) = &self.bounds {
+                if !bounds.contains(&values) {
+                    return None;
+                }
+            }
+            let values: Vec<SqlValue> = values.into_iter().map(From::from).collect();
+            // the entries of a rowid table's index end with the rowid
+            if self.entries {
+                let row_id = if self.table.without_rowid {
+                    None
+                } else {
+                    values
+                        .get(self.index.columns.len())
+                        .and_then(SqlValue::as_i64)
+                };
+                return Some(Ok(TableRow { row_id, values }));
+            }
+            if self.covering {
+                return Some(Ok(covered_row(self.table, self.index, &values)));
+            }
+            let key = index_row_key(self.table, self.index, &values);
+            match self
+                .executor
+                .lookup_columns(self.table, &key, self.columns.as_deref())
+            {
+                Ok(Some(row)) => return Some(Ok(row)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+pub struct TableScan<'a> {
+    table: &'a Table,
+    cursor: BTreeCursor<'a>,
+    // Only rows whose key lies within these bounds are returned.
+    bounds: Option<KeyBounds>,
+    // The rowid the scan of a rowid table ends at.
+    last_row_id: Option<i64>,
+    // The columns decoded of each row, the others being left NULL. Without
+    // them every column is.
+    columns: Option<Rc<[bool]>>,
+}
+
+impl<'a> TableScan<'a> {
+    // The row the cursor is on, or None if it lies past the end of the scan.
+    fn row(&self) -> Result<Option<TableRow>, Error> {
+        let row_id = self.cursor.row_id();
+        if let (Some(last), Some(row_id)) = (self.last_row_id, row_id) {
+            if row_id > last {
+                return Ok(None);
+            }
+        }
+        let record = self.cursor.lazy_record()?;
+        if let Some(bounds) = &self.bounds {
+            let key = (0..record.len().min(bounds.prefix.len() + 1))
+                .map(|i| record.value(i))
+                .collect::<Result<Vec<_>, Error>>()?;
+            if !bounds.contains(&key) {
+                return Ok(None);
+            }
+        }
+        let values = self
+            .table
+            .row_from_lazy_record(row_id, &record, self.columns.as_deref())?;
+        Ok(Some(TableRow { row_id, values }))
+    }
+}
+
+impl<'a> Iterator for TableScan<'a> {
+    type Item = Result<TableRow, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.cursor.current() {
+            Ok(true) => (),
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        let row = self.row().transpose()?;
+        if let Err(e) = self.cursor.advance() {
+            return Some(row.and(Err(e)));
+        }
+        Some(row)
+    }
+}
+
+// The entries of a b-tree a seek visits: those whose leading values equal
+// `prefix` and whose next value lies between `lower` and `upper`, both
+// included, compared with the collations of the key columns.
+struct KeyBounds {
+    prefix: Vec<SqlValue>,
+    lower: Option<SqlValue>,
+    upper: Option<SqlValue>,
+    collations: Vec<Collation>,
+}
+
+impl KeyBounds {
+    fn prefix(prefix: Vec<SqlValue>, collations: Vec<Collation>) -> KeyBounds {
+        KeyBounds {
+            prefix,
+            lower: None,
+            upper: None,
+            collations,
+        }
+    }
+
+    // Positions a cursor on the first entry within the bounds, if any.
+    fn seek(&self, cursor: &mut BTreeCursor) -> Result<(), Error> {
+        let mut key = self.prefix.clone();
+        key.extend(self.lower.clone());
+        cursor.seek_by(|record| compare_key(record.values(), &key, &self.collations))?;
+        Ok(())
+    }
+
+    // Whether an entry at or after the first one within the bounds is still
+    // within them; the first one that is not ends the visit.
+    fn contains(&self, values: &[Value]) -> bool {
+        if compare_key(values, &self.prefix, &self.collations) != Ordering::Equal {
+            return false;
+        }
+        match (&self.upper, values.get(self.prefix.len())) {
+            (Some(upper), Some(value)) => {
+                let collation = &self.collations[self.prefix.len()];
+                expr::compare_values(&SqlValue::from(value), upper, collation) != Ordering::Greater
+            }
+            _ => true,
+        }
+    }
+}
+
+// The keys the conditions on a table give for finding its rows, once the
+// rows of the tables before it are known.
+#[derive(Clone, Default)]
+struct Keys {
+    equal: Constraints,
+    // Bounds on the values of a column, with the collation the comparison
+    // uses.
+    bounds: Vec<(Option<usize>, Bound, SqlValue, Collation)>,
+    // The other values of an IN term, by the position in `equal` of the
+    // first one, which the rows are also looked up by.
+    lists: Vec<(usize, Vec<SqlValue>)>,
+}
+
+impl Keys {
+    // The list that gives the equality on a column, if any.
+    fn list(&self, column: Option<usize>, collation: &Collation) -> Option<usize> {
+        let position = self
+            .equal
+            .iter()
+            .position(|(c, _, k)| column.is_some() && *c == column && k == collation)?;
+        self.lists.iter().position(|(p, _)| *p == position)
+    }
+
+    fn row_id_list(&self, table: &Table) -> Option<usize> {
+        let position = self
+            .equal
+            .iter()
+            .position(|(c, _, _)| is_row_id(table, *c))?;
+        self.lists.iter().position(|(p, _)| *p == position)
+    }
+
+    // The keys for each value of a list, without the list.
+    fn each_value(&self, list: usize) -> Vec<Keys> {
+        let (position, values) = &self.lists[list];
+        let mut first = self.clone();
+        first.lists.remove(list);
+        let mut all = vec![first.clone()];
+        for value in values {
+            let mut keys = first.clone();
+            keys.equal[*position].1 = value.clone();
+            all.push(keys);
+        }
+        all
+    }
+
+    fn row_id(&self, table: &Table) -> Option<SqlValue> {
+        self.equal
+            .iter()
+            .find(|(c, _, _)| is_row_id(table, *c))
+            .map(|(_, value, _)| value.clone())
+    }
+
+    // The first and the last rowid the bounds on the rowid allow, widened
+    // to whole numbers.
+    fn row_id_range(&self, table: &Table) -> (Option<i64>, Option<i64>) {
+        let bound = |which: Bound| {
+            self.bounds
+                .iter()
+                .find(|(c, bound, _, _)| is_row_id(table, *c) && *bound == which)
+                .map(|(_, _, value, _)| match value {
+                    SqlValue::Integer(i) => *i,
+                    value => {
+                        let r = value.as_f64().unwrap_or(0.0);
+                        match which {
+                            Bound::Lower => r.floor() as i64,
+                            Bound::Upper => r.ceil() as i64,
+                        }
+                    }
+                })
+        };
+        (bound(Bound::Lower), bound(Bound::Upper))
+    }
+
+    fn equal_value(&self, column: Option<usize>, collation: &Collation) -> Option<&SqlValue> {
+        self.equal
+            .iter()
+            .find(|(c, _, k)| column.is_some() && *c == column && k == collation)
+            .map(|(_, value, _)| value)
+    }
+
+    fn bound_value(
+        &self,
+        column: Option<usize>,
+        which: Bound,
+        collation: &Collation,
+    ) -> Option<&SqlValue> {
+        self.bounds
+            .iter()
+            .find(|(c, bound, _, k)| {
+                column.is_some() && *c == column && *bound == which && k == collation
+            })
+            .map(|(_, _, value, _)| value)
+    }
+
+    // How much of a planned seek on b-tree keys with the given columns the
+    // keys allow.
+    fn available(&self, columns: &[KeyColumn], planned: &KeyRange) -> KeyRange {
+        let mut range = KeyRange::default();
+        for (column, descending, collation) in columns.iter().take(planned.equal) {
+            if *descending || self.equal_value(*column, collation).is_none() {
+                return range;
+            }
+            if let Some(list) = self.list(*column, collation) {
+                if planned.in_values == 0 {
+                    return range;
+                }
+                range.in_values = range.in_values.max(1) * (self.lists[list].1.len() + 1);
+            }
+            range.equal += 1;
+        }
+        if let Some((column, false, collation)) = columns.get(range.equal) {
+            range.lower =
+                planned.lower && self.bound_value(*column, Bound::Lower, collation).is_some();
+            range.upper =
+                planned.upper && self.bound_value(*column, Bound::Upper, collation).is_some();
+        }
+        range
+    }
+
+    // The bounds of a seek the keys allow.
+    fn bounds(&self, columns: &[KeyColumn], range: &KeyRange) -> KeyBounds {
+        let mut prefix = Vec::with_capacity(range.equal);
+        let mut collations = Vec::with_capacity(range.equal + 1);
+        for (column, _, collation) in &columns[..range.equal] {
+            prefix.push(self.equal_value(*column, collation).cloned().unwrap());
+            collations.push(collation.clone());
+        }
+        let mut bounds = KeyBounds::prefix(prefix, collations);
+        if let Some((column, _, collation)) = columns.get(range.equal) {
+            if range.lower {
+                bounds.lower = self.bound_value(*column, Bound::Lower, collation).cloned();
+            }
+            if range.upper {
+                bounds.upper = self.bound_value(*column, Bound::Upper, collation).cloned();
+            }
+            bounds.collations.push(collation.clone());
+        }
+        bounds
+    }
+}
+
+// The result rows of a statement, which are read as they are asked for
+// where the query allows it.
+pub struct QueryRows<'a> {
+    pub columns: Vec<String>,
+    rows: RowSource<'a>,
+}
+
+enum RowSource<'a> {
+    Materialized(std::vec::IntoIter<Vec<SqlValue>>),
+    Program(Box<ProgramRows<'a>>),
+}
+
+impl<'a> QueryRows<'a> {
+    fn materialized(relation: Relation) -> QueryRows<'a> {
+        QueryRows {
+            columns: relation.columns,
+            rows: RowSource::Materialized(relation.rows.into_iter()),
+        }
+    }
+}
+
+impl<'a> Iterator for QueryRows<'a> {
+    type Item = Result<Vec<SqlValue>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.rows {
+            RowSource::Materialized(rows) => rows.next().map(Ok),
+            RowSource::Program(rows) => rows.next(),
+        }
+    }
+}
+
+// The rows of a compiled SELECT, which its program produces one at a time.
+struct ProgramRows<'a> {
+    executor: Executor<'a>,
+    from: FromClause<'a>,
+    aggregates: Vec<ast::Function>,
+    index_cursors: Vec<Option<usize>>,
+    program: Program,
+    vm: Vm<'a>,
+    failed: bool,
+}
+
+impl<'a> Iterator for ProgramRows<'a> {
+    type Item = Result<Vec<SqlValue>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let environment = ProgramEnvironment {
+            executor: &self.executor,
+            from: &self.from,
+            aggregates: &self.aggregates,
+            index_cursors: &self.index_cursors,
+        };
+        let row = self.vm.step(&self.program, &environment);
+        self.failed = row.is_err();
+        row.transpose()
+    }
+}
+
+// What the program of a SELECT runs against: the rows of its tables, found
+// the way the plan says, and the evaluation of the expressions it has no
+// instructions for.
+struct ProgramEnvironment<'e, 'a> {
+    executor: &'e Executor<'a>,
+    from: &'e FromClause<'a>,
+    aggregates: &'e [ast::Function],
+    // The cursor on the entries of the index each table is found through,
+    // for the tables that also have a cursor of their own.
+    index_cursors: &'e [Option<usize>],
+}
+
+impl<'e, 'a> ProgramEnvironment<'e, 'a> {
+    // The table of a cursor, and whether the cursor reads index entries.
+    fn source(&self, cursor: usize) -> (usize, bool) {
+        match self.index_cursors.iter().position(|c| *c == Some(cursor)) {
+            Some(s) => (s, true),
+            None => (
+                cursor,
+                matches!(
+                    self.from.access[cursor],
+                    Access::Index { covering: true, .. }
+                ),
+            ),
+        }
+    }
+
+    // The rows of the tables for the evaluator, with the entries of the
+    // indexes that cover their tables made into rows.
+    fn table_rows<'r>(&self, rows: &'r [Option<TableRow>]) -> Cow<'r, [Option<TableRow>]> {
+        let mut table_rows = Cow::Borrowed(rows);
+        for (s, source) in self.from.sources.iter().enumerate() {
+            if let (
+                Access::Index {
+                    index,
+                    covering: true,
+                    ..
+                },
+                Some(entry),
+            ) = (&self.from.access[s], &rows[s])
+            {
+                table_rows.to_mut()[s] = Some(covered_row(&source.table, index, &entry.values));
+            }
+        }
+        table_rows
+    }
+}
+
+impl<'e, 'a> vdbe::Environment<'a> for ProgramEnvironment<'e, 'a> {
+    fn open(&self, cursor: usize, keys: Option<&[Operand]>) -> Result<Rows<'a>, Error> {
+        let (s, entries) = self.source(cursor);
+        let source = &self.from.sources[s];
+        let table = match source.stored() {
+            Some(table) => table,
+            None if source.function.is_some() => {
+                let args: Vec<SqlValue> = keys
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|key| key.value.clone())
+                    .collect();
+                return source.function_rows(&args);
+            }
+            None => return Ok(source.materialized()),
+        };
+        let keys = match keys {
+            // the values of IN subqueries are the only keys left out of the
+            // registers, and need no row
+            Some(operands) => {
+                let operands = operands.iter().cloned().map(Some).collect();
+                let scope = self.executor.empty_scope();
+                match self.executor.seek_keys(self.from, s, operands, &scope)? {
+                    Some(keys) => keys,
+                    None => return Ok(Box::new(std::iter::empty())),
+                }
+            }
+            None => Keys::default(),
+        };
+        let access = self
+            .executor
+            .available_access(table, &self.from.access[s], &keys);
+        self.executor
+            .rows_by(table, &access, &keys, &source.columns, entries)
+    }
+
+    fn seek(&self, cursor: usize, entry: &TableRow) -> Result<TableRow, Error> {
+        let source = &self.from.sources[cursor];
+        let (table, index) = match (source.stored(), &self.from.access[cursor]) {
+            (Some(table), Access::Index { index, .. }) => (table, *index),
+            _ => unreachable!(),
+        };
+        let key = index_row_key(table, index, &entry.values);
+        self.executor
+            .lookup_columns(table, &key, source.columns.as_deref())?
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "database disk image is malformed"))
+    }
+
+    fn count(&self, cursor: usize) -> Result<i64, Error> {
+        let table = &self.from.sources[cursor].table;
+        count_entries(self.executor.pager, table.root_page)
+    }
+
+    fn evaluate(
+        &self,
+        expr: &Expr,
+        rows: &[Option<TableRow>],
+        aggregates: &[SqlValue],
+    ) -> Result<Operand, Error> {
+        let rows = self.table_rows(rows);
+        let scope = ProgramScope {
+            row: RowScope {
+                executor: self.executor,
+                sources: &self.from.sources,
+                row: Some(&rows),
+            },
+            aggregates: self.aggregates,
+            values: aggregates,
+        };
+        expr::evaluate_operand(expr, &scope)
+    }
+
+    fn parameter(&self, number: usize) -> SqlValue {
+        self.executor.empty_scope().parameter(number)
+    }
+
+    fn functions(&self) -> &Functions {
+        &self.executor.functions
+    }
+
+    fn sort_budget(&self) -> usize {
+        self.executor.sort_budget
+    }
+}
+
+// The names of a compiled SELECT: the columns of its tables, whose cursors
+// are numbered in join order, and once the rows are all seen, its
+// aggregates. The columns an index entry holds are read from the entry.
+struct ProgramNames<'e, 'a> {
+    executor: &'e Executor<'a>,
+    from: &'e FromClause<'a>,
+    index_cursors: &'e [Option<usize>],
+    aggregates: &'e [ast::Function],
+    // The first of the registers holding the results of the aggregates.
+    results: Option<usize>,
+}
+
+impl<'e, 'a> vdbe::Names for ProgramNames<'e, 'a> {
+    fn column(&self, name: &[Ident]) -> Option<vdbe::ColumnRef> {
+        let (s, column) = lookup_column(&self.from.sources, name).ok()??;
+        let table = &self.from.sources[s].table;
+        let (name, affinity, collation) = match column {
+            Some(i) => {
+                let c = &table.columns[i];
+                let collation = column_collation(&self.executor.collations, c);
+                (
+                    format!("{}.{}", table.name, c.name),
+                    column_affinity(c),
+                    collation,
+                )
+            }
+            None => (format!("{}.rowid", table.name), Affinity::Integer, None),
+        };
+        if let Access::Index {
+            index, covering, ..
+        } = &self.from.access[s]
+        {
+            let cursor = match (entry_column(table, index, column), covering) {
+                (Some(position), _) => Some((position, self.index_cursors[s].unwrap_or(s))),
+                // a covering index holds every column the query reads
+                (None, true) => return None,
+                (None, false) => None,
+            };
+            if let Some((position, cursor)) = cursor {
+                return Some(vdbe::ColumnRef {
+                    cursor,
+                    column: position,
+                    affinity: position.map_or(Affinity::Integer, |_| affinity),
+                    collation,
+                    name,
+                    index: true,
+                    real: position.is_some() && column.is_some_and(|c| table.is_real(c)),
+                });
+            }
+        }
+        Some(vdbe::ColumnRef {
+            cursor: s,
+            column,
+            affinity,
+            collation,
+            name,
+            index: false,
+            real: false,
+        })
+    }
+
+    fn aggregate(&self, function: &ast::Function) -> Option<usize> {
+        let i = self.aggregates.iter().position(|f| f == function)?;
+        Some(self.results? + i)
+    }
+
+    fn functions(&self) -> &Functions {
+        &self.executor.functions
+    }
+}
+
+// The scope of the expressions a program leaves to the evaluator: the rows
+// its cursors are on, and the results of its aggregates.
+struct ProgramScope<'a> {
+    row: RowScope<'a>,
+    aggregates: &'a [ast::Function],
+    values: &'a [SqlValue],
+}
+
+impl<'a> Scope for ProgramScope<'a> {
+    fn column(&self, name: &[Ident]) -> Result<Operand, Error> {
+        self.row.column(name)
+    }
+
+    fn aggregate(&self, function: &ast::Function) -> Option<Result<SqlValue, Error>> {
+        let i = self.aggregates.iter().position(|f| f == function)?;
+        Some(Ok(self.values[i].clone()))
+    }
+
+    fn subquery(&self, query: &ast::Query) -> Result<Rc<Relation>, Error> {
+        self.row.executor.subquery(query, self)
+    }
+
+    fn collation(&self, name: &str) -> Result<Collation, Error> {
+        self.row.collation(name)
+    }
+
+    fn functions(&self) -> Option<&Functions> {
+        self.row.functions()
+    }
+
+    fn parameter(&self, number: usize) -> SqlValue {
+        self.row.parameter(number)
+    }
+}
+
+// The cursor on the entries of the index each table of a join is found
+// through, where the table is also read by its own cursor. Those come after
+// the cursors of the tables, in join order; a table that an index covers is
+// read by a cursor on the index alone.
+fn index_cursors(from: &FromClause) -> Vec<Option<usize>> {
+    let mut next = from.sources.len();
+    from.access
+        .iter()
+        .map(|access| match access {
+            Access::Index {
+                covering: false, ..
+            } => {
+                next += 1;
+                Some(next - 1)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Whether a table found one way is read whole, so that its loop needs no
+// keys.
+fn reads_all(access: &Access) -> bool {
+    match access {
+        Access::Scan => true,
+        Access::PrimaryKey(range) | Access::Index { range, .. } => range.is_full(),
+        Access::RowId | Access::RowIds(_) | Access::RowIdRange { .. } => false,
+    }
+}
+
+// A loop of a compiled join over the rows of one table.
+struct JoinLoop {
+    // The cursor Next moves, on the table or on the entries of its index.
+    cursor: usize,
+    // The seek that jumps past the loop when there are no rows.
+    seek: usize,
+    // The first instruction of the body, and the jumps to the next row.
+    top: usize,
+    next: Vec<usize>,
+    // For a LEFT JOIN, the register telling whether a row matched, and the
+    // instruction a row of NULLs resumes at once the conditions of the join
+    // are passed.
+    matched: Option<usize>,
+    resume: usize,
+}
+
+// Emits the start of the loop over the rows of a table of a join: the seek
+// with its keys in registers, and for a table found through an index that
+// does not cover it, the pointing of the table at the row of each entry.
+fn compile_loop(
+    b: &mut Builder,
+    from: &FromClause,
+    index_cursors: &[Option<usize>],
+    level: usize,
+    names: &dyn vdbe::Names,
+) -> JoinLoop {
+    let source = &from.sources[level];
+    let cursor = index_cursors[level].unwrap_or(level);
+    let matched = if source.left_join {
+        let r = b.register();
+        b.integer(0, r);
+        Some(r)
+    } else {
+        None
+    };
+    let (opcode, keys) = match (source.stored(), &from.access[level]) {
+        (None, _) => match &source.function {
+            Some((_, args)) => (Opcode::VFilter, args.iter().collect()),
+            None => (Opcode::Rewind, Vec::new()),
+        },
+        (Some(_), access) if reads_all(access) => (Opcode::Rewind, Vec::new()),
+        (Some(_), Access::RowId) => (Opcode::SeekRowid, key_exprs(from, level)),
+        (Some(_), _) => (Opcode::SeekGE, key_exprs(from, level)),
+    };
+    let seek = if opcode == Opcode::Rewind {
+        b.emit(opcode, cursor, 0, 0)
+    } else {
+        let first = b.registers(keys.len());
+        for (i, key) in keys.iter().enumerate() {
+            b.expression(key, names, first + i);
+        }
+        let seek = b.emit(opcode, cursor, 0, first);
+        b.op(seek).p4 = P4::Int64(keys.len() as i64);
+        seek
+    };
+    let top = b.address();
+    if cursor != level {
+        b.emit(Opcode::DeferredSeek, cursor, 0, level);
+    }
+    JoinLoop {
+        cursor,
+        seek,
+        top,
+        next: Vec::new(),
+        matched,
+        resume: top,
+    }
+}
+
+// The registers and flags grouping the rows of a compiled SELECT with
+// aggregates.
+struct Grouping {
+    // The GROUP BY values of the current row, and those of its group.
+    keys: usize,
+    previous: usize,
+    collations: Rc<[Collation]>,
+    // Whether a group has started.
+    started: usize,
+    // Whether the group has no row yet, and whether the step of min() or
+    // max() took its value from the current row.
+    first: usize,
+    took: usize,
+    // The single min() or max() whose row the bare columns come from.
+    extreme: Option<usize>,
+    // Whether bare columns need the rows of a group kept.
+    keep_row: bool,
+    // The return address of the subroutine producing the row of a group.
+    ret: usize,
+}
+
+// Emits the steps of the aggregates for a row, and keeps the row for bare
+// columns if it is the first of its group, or the one min() or max() took
+// its value from.
+fn compile_step(
+    b: &mut Builder,
+    grouping: &Grouping,
+    aggregates: &[ast::Function],
+    accumulators: &[usize],
+    names: &dyn vdbe::Names,
+) {
+    for (i, function) in aggregates.iter().enumerate() {
+        let (args, n) = if aggregate::is_count_star(function) {
+            (0, 0)
+        } else {
+            (b.arguments(function, names), function.args.len())
+        };
+        let took = match grouping.extreme {
+            Some(extreme) if extreme == i && grouping.keep_row => grouping.took,
+            _ => 0,
+        };
+        let addr = b.emit(Opcode::AggStep, took, args, accumulators[i]);
+        b.op(addr).p4 = P4::Function(function.clone());
+        b.op(addr).p5 = n as u16;
+    }
+    if !grouping.keep_row {
+        return;
+    }
+    let mut saves = vec![b.emit(Opcode::If, grouping.first, 0, 0)];
+    if grouping.extreme.is_some() {
+        saves.push(b.emit(Opcode::If, grouping.took, 0, 0));
+    }
+    let skip = b.emit(Opcode::Goto, 0, 0, 0);
+    saves.into_iter().for_each(|save| b.jump_here(save));
+    b.emit(Opcode::SaveRows, 0, 0, 0);
+    b.integer(0, grouping.first);
+    b.jump_here(skip);
+}
+
+// Emits the start of a new group when the GROUP BY values of the row
+// differ from those of its group, after producing the row of the group
+// before. Returns the Gosub to the subroutine that produces it.
+fn compile_group_break(b: &mut Builder, grouping: &Grouping, accumulators: &[usize]) -> usize {
+    let n = grouping.collations.len();
+    let new = b.emit(Opcode::IfNot, grouping.started, 0, 0);
+    let compare = b.emit(Opcode::Compare, grouping.previous, grouping.keys, n);
+    b.op(compare).p4 = P4::KeyInfo(grouping.collations.clone());
+    // a different group goes on to the Gosub that follows
+    let other = b.address() + 1;
+    let jump = b.emit(Opcode::Jump, other, 0, other);
+    let gosub = b.emit(Opcode::Gosub, grouping.ret, 0, 0);
+    b.jump_here(new);
+    for i in 0..n {
+        b.emit(Opcode::Copy, grouping.keys + i, grouping.previous + i, 0);
+    }
+    b.integer(1, grouping.started);
+    for accumulator in accumulators {
+        b.emit(Opcode::AggReset, *accumulator, 0, 0);
+    }
+    b.integer(1, grouping.first);
+    b.jump_here(jump);
+    gosub
+}
+
+// Emits the row of a group: the results of its aggregates, the check of
+// HAVING, and the result row. Returns the jumps that skip the row.
+#[allow(clippy::too_many_arguments)]
+fn compile_group_row(
+    b: &mut Builder,
+    plan: &SelectPlan,
+    accumulators: &[usize],
+    results: usize,
+    having: Option<&Expr>,
+    output: &ResultOutput,
+    names: &dyn vdbe::Names,
+    halts: &mut Vec<usize>,
+) -> Vec<usize> {
+    for (i, function) in plan.aggregates.iter().enumerate() {
+        let addr = b.emit(Opcode::AggFinal, accumulators[i], 0, results + i);
+        b.op(addr).p4 = P4::Function(function.clone());
+    }
+    let mut skips: Vec<usize> = having
+        .map(|having| compile_condition(b, having, names))
+        .into_iter()
+        .collect();
+    skips.extend(compile_result(b, output, names, halts));
+    skips
+}
+
+// Emits a condition of WHERE or ON and returns the jump taken when a row
+// does not satisfy it, which the caller points at the next row.
+fn compile_condition(b: &mut Builder, expr: &Expr, names: &dyn vdbe::Names) -> usize {
+    let r = b.register();
+    b.expression(expr, names, r);
+    b.emit(Opcode::IfNot, r, 0, 1)
+}
+
+// Where the result rows of a compiled SELECT go.
+struct ResultOutput<'q> {
+    projection: &'q [Expr],
+    order: &'q [OrderTerm],
+    // The temporary set of the rows SELECT DISTINCT has returned.
+    distinct: Option<usize>,
+    // The sorter of ORDER BY, whose rows are the sort keys followed by the
+    // result columns.
+    sorter: Option<usize>,
+    // The registers counting down OFFSET and LIMIT.
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+// Emits the result row of a SELECT, after skipping the rows DISTINCT has
+// returned before and the rows of OFFSET, and counts it against LIMIT,
+// adding the jump to the end of the program once LIMIT is reached to
+// `halts`. With ORDER BY to sort for, the row goes to the sorter instead.
+// Returns the jumps that skip the row.
+fn compile_result(
+    b: &mut Builder,
+    output: &ResultOutput,
+    names: &dyn vdbe::Names,
+    halts: &mut Vec<usize>,
+) -> Vec<usize> {
+    let mut skips = Vec::new();
+    let n = output.projection.len();
+    let keys = if output.sorter.is_some() {
+        output.order.len()
+    } else {
+        0
+    };
+    let block = b.registers(keys + n);
+    let first = block + keys;
+    for (i, expr) in output.projection.iter().enumerate() {
+        b.expression(expr, names, first + i);
+    }
+    // a row seen before is not a result, and does not count for OFFSET
+    if let Some(cursor) = output.distinct {
+        let found = b.emit(Opcode::Found, cursor, 0, first);
+        b.op(found).p4 = P4::Int64(n as i64);
+        skips.push(found);
+        b.emit(Opcode::IdxInsert, cursor, first, n);
+    }
+    if let Some(sorter) = output.sorter {
+        for (i, term) in output.order.iter().enumerate() {
+            match term.result_column {
+                Some(column) => {
+                    b.emit(Opcode::Copy, first + column, block + i, 0);
+                }
+                None => b.expression(&term.expr, names, block + i),
+            }
+        }
+        b.emit(Opcode::SorterInsert, sorter, block, keys + n);
+        return skips;
+    }
+    skips.extend(output.offset.map(|r| b.emit(Opcode::IfPos, r, 0, 1)));
+    b.emit(Opcode::ResultRow, first, n, 0);
+    if let Some(r) = output.limit {
+        halts.push(b.emit(Opcode::DecrJumpZero, r, 0, 0));
+    }
+    skips
+}
+
+// Emits the loop over the rows the sorter of ORDER BY sorted, which
+// returns each after skipping the rows of OFFSET, and counts it against
+// LIMIT.
+fn compile_sorted(b: &mut Builder, output: &ResultOutput, halts: &mut Vec<usize>) {
+    let sorter = output.sorter.unwrap();
+    let keys = output.order.len();
+    let n = output.projection.len();
+    let block = b.registers(keys + n);
+    let sort = b.emit(Opcode::SorterSort, sorter, 0, 0);
+    let top = b.address();
+    b.emit(Opcode::SorterData, sorter, block, keys + n);
+    let skip = output.offset.map(|r| b.emit(Opcode::IfPos, r, 0, 1));
+    b.emit(Opcode::ResultRow, block + keys, n, 0);
+    if let Some(r) = output.limit {
+        halts.push(b.emit(Opcode::DecrJumpZero, r, 0, 0));
+    }
+    if let Some(skip) = skip {
+        b.jump_here(skip);
+    }
+    b.emit(Opcode::SorterNext, sorter, top, 0);
+    b.jump_here(sort);
+}
+
+// Collects result rows, applying ORDER BY, OFFSET and LIMIT.
+struct Output<'q> {
+    order: &'q [OrderTerm],
+    sorter: Option<Sorter>,
+    offset: usize,
+    limit: Option<usize>,
+    skipped: usize,
+    rows: Vec<Vec<SqlValue>>,
+    // For SELECT DISTINCT, the rows pushed so far, compared with the
+    // collations of the result columns.
+    seen: Option<(Rc<[Collation]>, BTreeSet<GroupKey>)>,
+}
+
+impl<'q> Output<'q> {
+    fn new(
+        order: &'q [OrderTerm],
+        sort: bool,
+        sort_budget: usize,
+        offset: i64,
+        limit: Option<i64>,
+    ) -> Output<'q> {
+        let sorter = if sort {
+            let keys = order.iter().map(|term| term.key.clone()).collect();
+            Some(Sorter::new(keys, sort_budget))
+        } else {
+            None
+        };
+        Output {
+            order,
+            sorter,
+            offset: offset as usize,
+            limit: limit.map(|l| l as usize),
+            skipped: 0,
+            rows: Vec::new(),
+            seen: None,
+        }
+    }
+
+    // Drops the rows equal to one pushed before, under the collation of
+    // each column. OFFSET and LIMIT count the rows that are left.
+    fn distinct(&mut self, collations: impl Iterator<Item = Collation>) {
+        self.seen = Some((collations.collect(), BTreeSet::new()));
+    }
+
+    // Whether no more rows are wanted.
+    fn is_full(&self) -> bool {
+        self.sorter.is_none() && Some(self.rows.len()) == self.limit
+    }
+
+    // Adds a result row. `scope` evaluates ORDER BY terms that are not
+    // result columns.
+    fn push(&mut self, values: Vec<SqlValue>, scope: &dyn Scope) -> Result<(), Error> {
+        if let Some((collations, seen)) = &mut self.seen {
+            if !seen.insert(GroupKey::new(values.clone(), collations.clone())) {
+                return Ok(());
+            }
+        }
+        match &mut self.sorter {
+            Some(sorter) => {
+                let mut sorted = Vec::with_capacity(self.order.len() + values.len());
+                for term in self.order {
+                    sorted.push(match term.result_column {
+                        Some(i) => values[i].clone(),
+                        None => expr::evaluate(&term.expr, scope)?,
+                    });
+                }
+                sorted.extend(values);
+                sorter.push(sorted)?;
+            }
+            None if self.skipped < self.offset => self.skipped += 1,
+            None => self.rows.push(values),
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Vec<Vec<SqlValue>>, Error> {
+        let sorter = match self.sorter {
+            Some(sorter) => sorter,
+            None => return Ok(self.rows),
+        };
+        let mut rows = Vec::new();
+        let sorted = sorter.finish()?.skip(self.offset);
+        for row in sorted.take(self.limit.unwrap_or(usize::MAX)) {
+            rows.push(row?.split_off(self.order.len()));
+        }
+        Ok(rows)
+    }
+}
+
+// A SELECT planned up to reading its rows.
+struct SelectPlan<'a> {
+    // The SELECT with the result column aliases that WHERE and HAVING name
+    // replaced by their expressions, if they name any.
+    resolved: Option<ast::Select>,
+    from: FromClause<'a>,
+    columns: Vec<String>,
+    types: Vec<(Affinity, Option<Collation>)>,
+    projection: Vec<Expr>,
+    order: Vec<OrderTerm>,
+    limit: Option<i64>,
+    offset: i64,
+    aggregates: Vec<ast::Function>,
+    is_aggregate: bool,
+    windows: Vec<ast::Function>,
+    // Whether the rows need sorting for ORDER BY.
+    sort: bool,
+    // Whether the rows come in the order of GROUP BY.
+    grouped: bool,
+}
+
+struct AggregateQuery<'q> {
+    from: &'q FromClause<'q>,
+    select: &'q ast::Select,
+    projection: &'q [Expr],
+    group_by: &'q [Expr],
+    aggregates: &'q [ast::Function],
+    grouped: bool,
+}
+
+impl<'q> AggregateQuery<'q> {
+    // Whether the query only counts all rows of the table, which the b-tree
+    // pages tell without reading the rows.
+    fn is_count_only(&self) -> bool {
+        if self.from.sources.len() != 1
+            || self.from.sources[0].stored().is_none()
+            || self.select.selection.is_some()
+            || !self.group_by.is_empty()
+            || !self.aggregates.iter().all(aggregate::is_count_star)
+        {
+            return false;
+        }
+        let mut uses_columns = false;
+        let mut visit = |e: &Expr| match e {
+            Expr::Function(f) if aggregate::is_aggregate(f) => false,
+            Expr::Identifier(_)
+            | Expr::CompoundIdentifier(_)
+            | Expr::Subquery(_)
+            | Expr::Exists(_)
+            | Expr::InSubquery { .. } => {
+                uses_columns = true;
+                false
+            }
+            _ => true,
+        };
+        for expr in self.projection.iter().chain(&self.select.having) {
+            expr::walk(expr, &mut visit);
+        }
+        !uses_columns
+    }
+}
+
+struct Group {
+    accumulators: Vec<Accumulator>,
+    // The row bare columns are taken from.
+    row: Option<Vec<Option<TableRow>>>,
+}
+
+// Evaluates expressions for a group: aggregate calls give the aggregate's
+// value, and bare columns refer to the group's chosen row.
+struct GroupScope<'a> {
+    row: RowScope<'a>,
+    aggregates: &'a [ast::Function],
+    accumulators: &'a [Accumulator],
+}
+
+impl<'a> GroupScope<'a> {
+    fn new(
+        executor: &'a Executor<'a>,
+        sources: &'a [Source<'a>],
+        aggregates: &'a [ast::Function],
+        group: &'a Group,
+    ) -> GroupScope<'a> {
+        GroupScope {
+            row: RowScope {
+                executor,
+                sources,
+                row: group.row.as_deref(),
+            },
+            aggregates,
+            accumulators: &group.accumulators,
+        }
+    }
+}
+
+impl<'a> Scope for GroupScope<'a> {
+    fn column(&self, name: &[Ident]) -> Result<Operand, Error> {
+        self.row.column(name)
+    }
+
+    fn aggregate(&self, function: &ast::Function) -> Option<Result<SqlValue, Error>> {
+        let i = self.aggregates.iter().position(|f| f == function)?;
+        Some(self.accumulators[i].finish())
+    }
+
+    fn subquery(&self, query: &ast::Query) -> Result<Rc<Relation>, Error> {
+        self.row.executor.subquery(query, self)
+    }
+
+    fn collation(&self, name: &str) -> Result<Collation, Error> {
+        self.row.collation(name)
+    }
+
+    fn functions(&self) -> Option<&Functions> {
+        self.row.functions()
+    }
+
+    fn parameter(&self, number: usize) -> SqlValue {
+        self.row.parameter(number)
+    }
+}
+
+// Evaluates expressions for a row of a query with windows, whose window
+// functions have already been computed.
+struct WindowScope<'a> {
+    group: GroupScope<'a>,
+    windows: &'a [ast::Function],
+    values: &'a [SqlValue],
+}
+
+impl<'a> Scope for WindowScope<'a> {
+    fn column(&self, name: &[Ident]) -> Result<Operand, Error> {
+        self.group.column(name)
+    }
+
+    fn aggregate(&self, function: &ast::Function) -> Option<Result<SqlValue, Error>> {
+        self.group.aggregate(function)
+    }
+
+    fn window(&self, function: &ast::Function) -> Option<Result<SqlValue, Error>> {
+        let i = self.windows.iter().position(|f| f == function)?;
+        Some(Ok(self.values[i].clone()))
+    }
+
+    fn subquery(&self, query: &ast::Query) -> Result<Rc<Relation>, Error> {
+        self.group.row.executor.subquery(query, self)
+    }
+
+    fn collation(&self, name: &str) -> Result<Collation, Error> {
+        self.group.collation(name)
+    }
+
+    fn functions(&self) -> Option<&Functions> {
+        self.group.functions()
+    }
+
+    fn parameter(&self, number: usize) -> SqlValue {
+        self.group.parameter(number)
+    }
+}
+
+// A table defined by WITH, and the tables defined before it.
+struct CommonTable {
+    name: String,
+    // The column names given after the table name, if any.
+    columns: Vec<String>,
+    query: Rc<ast::Query>,
+    recursive: bool,
+    materialize: bool,
+    // The rows once materialized.
+    relation: RefCell<Option<Rc<Relation>>>,
+    parent: Option<Rc<CommonTable>>,
+}
+
+// Runs `initial UNION [ALL] recursive` one row at a time. The rows of the
+// initial query go into a queue, and every row taken from the queue is the
+// next row of the table and then the whole table for a run of the
+// recursive query, whose rows go into the queue in turn. ORDER BY picks the
+// row taken from the queue, and LIMIT stops once the table is full. A
+// table without LIMIT ends only when the query reading it stops asking.
+struct RecursiveQueue<'a> {
+    executor: Executor<'a>,
+    cte: Rc<CommonTable>,
+    step: ast::Query,
+    all: bool,
+    columns: Vec<String>,
+    types: Vec<(Affinity, Option<Collation>)>,
+    // The table as a source, for ORDER BY to see the columns of a row.
+    sources: [Source<'a>; 1],
+    order: Vec<OrderTerm>,
+    keys: Vec<SortKey>,
+    queue: VecDeque<(Vec<SqlValue>, Vec<SqlValue>)>,
+    seen: HashSet<Vec<SqlValue>>,
+    limit: Option<i64>,
+    offset: i64,
+    skipped: i64,
+    produced: i64,
+    // The row taken last, whose run of the recursive query is still due.
+    last: Option<Vec<SqlValue>>,
+}
+
+impl<'a> RecursiveQueue<'a> {
+    fn new(executor: &Executor<'a>, cte: &Rc<CommonTable>) -> Result<RecursiveQueue<'a>, Error> {
+        let (initial, recursive, all) = match &cte.query.body {
+            SetExpr::SetOperation {
+                op: ast::SetOperator::Union,
+                all,
+                left,
+                right,
+            } if set_table_references(left, &cte.name) == 0 => (left, right, *all),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("circular reference: {}", cte.name),
+                ))
+            }
+        };
+        let executor = executor.with_ctes(cte.parent.clone());
+        let mut table = executor.query(&plain_query(initial))?;
+        if !cte.columns.is_empty() && cte.columns.len() == table.columns.len() {
+            table.columns = cte.columns.clone();
+        }
+        let (limit, offset) = limit_offset(&cte.query, &executor.empty_scope())?;
+
+        // ORDER BY sees the columns of the queued row
+        let columns = table
+            .columns
+            .iter()
+            .zip(&table.types)
+            .map(|(name, (affinity, collation))| Column {
+                name: name.clone(),
+                declared_type: affinity.type_name().to_string(),
+                not_null: false,
+                collation: collation.as_ref().map(|c| c.name().to_string()),
+                default: None,
+            })
+            .collect();
+        let sources = [Source {
+            table: Cow::Owned(Table::transient(cte.name.clone(), columns)),
+            rows: None,
+            queued: None,
+            name: cte.name.clone(),
+            merged: Vec::new(),
+            left_join: false,
+            on: Vec::new(),
+            function: None,
+            columns: None,
+        }];
+        let projection: Vec<Expr> = sources[0]
+            .table
+            .columns
+            .iter()
+            .map(|c| sources[0].column_expr(c))
+            .collect();
+        let order = order_terms(
+            &executor.collations,
+            &sources,
+            &cte.query.order_by,
+            &table.columns,
+            &projection,
+        )?;
+        let keys = order.iter().map(|term| term.key.clone()).collect();
+        let mut queue = RecursiveQueue {
+            executor,
+            cte: cte.clone(),
+            step: plain_query(recursive),
+            all,
+            columns: table.columns,
+            types: table.types,
+            sources,
+            order,
+            keys,
+            queue: VecDeque::new(),
+            seen: HashSet::new(),
+            limit,
+            offset,
+            skipped: 0,
+            produced: 0,
+            last: None,
+        };
+        queue.push(table.rows)?;
+        Ok(queue)
+    }
+
+    // The next row of the table, or None once it is complete.
+    fn next_row(&mut self) -> Result<Option<Vec<SqlValue>>, Error> {
+        loop {
+            if self.limit.is_some_and(|limit| self.produced >= limit) {
+                return Ok(None);
+            }
+            if let Some(values) = self.last.take() {
+                self.run_step(values)?;
+            }
+            // without ORDER BY, the queue is first in, first out
+            let next = if self.keys.is_empty() {
+                self.queue.pop_front()
+            } else {
+                let queue = &self.queue;
+                let first = (0..queue.len())
+                    .min_by(|&a, &b| compare_rows(&self.keys, &queue[a].0, &queue[b].0));
+                first.and_then(|i| self.queue.remove(i))
+            };
+            let (_, values) = match next {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+            self.last = Some(values.clone());
+            if self.skipped < self.offset {
+                self.skipped += 1;
+                continue;
+            }
+            self.produced += 1;
+            return Ok(Some(values));
+        }
+    }
+
+    // Runs the recursive query with `values` as the whole table.
+    fn run_step(&mut self, values: Vec<SqlValue>) -> Result<(), Error> {
+        let working = CommonTable {
+            name: self.cte.name.clone(),
+            columns: Vec::new(),
+            query: self.cte.query.clone(),
+            recursive: false,
+            materialize: true,
+            relation: RefCell::new(Some(Rc::new(Relation {
+                columns: self.columns.clone(),
+                types: self.types.clone(),
+                rows: vec![values],
+            }))),
+            parent: self.cte.parent.clone(),
+        };
+        let step = self
+            .executor
+            .with_ctes(Some(Rc::new(working)))
+            .query(&self.step)?;
+        if step.columns.len() != self.columns.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "SELECTs to the left and right of UNION do not have the same number of result columns",
+            ));
+        }
+        self.push(step.rows)
+    }
+
+    fn push(&mut self, rows: Vec<Vec<SqlValue>>) -> Result<(), Error> {
+        for values in rows {
+            if self.all || self.seen.insert(values.clone()) {
+                let row = [Some(TableRow {
+                    row_id: None,
+                    values,
+                })];
+                let scope = RowScope {
+                    executor: &self.executor,
+                    sources: &self.sources,
+                    row: Some(&row),
+                };
+                let sort_values = self
+                    .order
+                    .iter()
+                    .map(|term| expr::evaluate(&term.expr, &scope))
+                    .collect::<Result<_, _>>()?;
+                let [row] = row;
+                self.queue.push_back((sort_values, row.unwrap().values));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The rows of a table that are produced as they are first read, and kept
+// for reading again. Sources hold them as this trait, which unlike the
+// queue behind it does not tie down the lifetime of the source.
+trait LazyRows {
+    // The row at position `i`, or None past the last one.
+    fn row(&self, i: usize) -> Result<Option<TableRow>, Error>;
+}
+
+// The rows of a recursive table read in place.
+struct QueuedRows<'a> {
+    read: RefCell<Vec<TableRow>>,
+    queue: RefCell<RecursiveQueue<'a>>,
+}
+
+impl<'a> LazyRows for QueuedRows<'a> {
+    fn row(&self, i: usize) -> Result<Option<TableRow>, Error> {
+        if let Some(row) = self.read.borrow().get(i) {
+            return Ok(Some(row.clone()));
+        }
+        let row = self.queue.borrow_mut().next_row()?.map(|values| TableRow {
+            row_id: None,
+            values,
+        });
+        self.read.borrow_mut().extend(row.clone());
+        Ok(row)
+    }
+}
+
+// A table of the FROM clause, known by its alias or else its name.
+struct Source<'a> {
+    table: Cow<'a, Table>,
+    // The rows of a subquery in FROM, which has no b-tree to read them from.
+    rows: Option<Rc<Vec<TableRow>>>,
+    // Or those of a recursive table, as it produces them.
+    queued: Option<Rc<dyn LazyRows + 'a>>,
+    name: String,
+    // Columns that USING or NATURAL merged into a column of an earlier
+    // table, which unqualified names and `*` do not see.
+    merged: Vec<usize>,
+    // Whether this is the right-hand table of a LEFT JOIN.
+    left_join: bool,
+    // The conditions of ON, USING and NATURAL.
+    on: Vec<Expr>,
+    // The table-valued function producing the rows, and its arguments.
+    function: Option<(json::TableFunction, Vec<Expr>)>,
+    // The columns the query reads, which are all that is decoded of the
+    // stored rows. Without them every column is.
+    columns: Option<Rc<[bool]>>,
+}
+
+impl<'a> Source<'a> {
+    // The table of the schema the rows are read from, if any.
+    fn stored(&self) -> Option<&'a Table> {
+        match self.table {
+            Cow::Borrowed(table) => Some(table),
+            Cow::Owned(_) => None,
+        }
+    }
+
+    fn materialized(&self) -> Rows<'a> {
+        if let Some(queued) = self.queued.clone() {
+            let mut i = 0;
+            return Box::new(std::iter::from_fn(move || {
+                i += 1;
+                queued.row(i - 1).transpose()
+            }));
+        }
+        let rows = self.rows.clone().unwrap();
+        Box::new((0..rows.len()).map(move |i| Ok(rows[i].clone())))
+    }
+
+    // The rows of a table-valued function given its arguments.
+    fn function_rows(&self, args: &[SqlValue]) -> Result<Rows<'a>, Error> {
+        let (function, _) = self.function.as_ref().unwrap();
+        let rows = function.rows(args)?;
+        Ok(Box::new(rows.into_iter().map(|(row_id, values)| {
+            Ok(TableRow {
+                row_id: Some(row_id),
+                values,
+            })
+        })))
+    }
+
+    fn column_expr(&self, column: &Column) -> Expr {
+        Expr::CompoundIdentifier(vec![
+            Ident::new(self.name.clone()),
+            Ident::new(column.name.clone()),
+        ])
+    }
+}
+
+// The tables of a query, joined left to right in nested loops. Every
+// condition is checked at the first table of the join where the rows of all
+// tables it uses are known.
+struct FromClause<'a> {
+    sources: Vec<Source<'a>>,
+    // The terms of WHERE.
+    conditions: Vec<Expr>,
+    // Whether the tables must be joined in the order they are written: LEFT
+    // JOIN and table-valued functions depend on the tables before them, and
+    // CROSS JOIN asks for it.
+    fixed_order: bool,
+    // The conditions checked at each table.
+    filters: Vec<Vec<Expr>>,
+    // The `column = expr` filters of each table whose expr only uses earlier
+    // tables, which can be used to seek rather than scan.
+    equalities: Vec<Vec<(Option<usize>, Expr)>>,
+    // Likewise the `column IN (...)` filters, each kept whole, whose values
+    // are looked up one after another.
+    lists: Vec<Vec<(Option<usize>, Expr)>>,
+    // Likewise the filters that bound a column from one side, such as
+    // `column > expr`.
+    ranges: Vec<Vec<(Option<usize>, Bound, Expr)>>,
+    // WHERE terms checked on the complete row.
+    residual: Vec<Expr>,
+    // How the rows of each table are found.
+    access: Vec<Access<'a>>,
+}
+
+impl<'a> FromClause<'a> {
+    fn satisfies_residual(&self, scope: &dyn Scope) -> Result<bool, Error> {
+        for term in &self.residual {
+            if !expr::is_true(term, scope)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+// The number of join orders the planner keeps extending.
+const JOIN_PATHS: usize = 8;
+
+// The first tables of a join order, and what joining them costs.
+#[derive(Clone)]
+struct JoinPath<'a> {
+    tables: Vec<usize>,
+    access: Vec<Access<'a>>,
+    known: u64,
+    cost: f64,
+    rows: f64,
+    // Whether the rows come in the order of ORDER BY.
+    ordered: bool,
+}
+
+impl<'a> JoinPath<'a> {
+    // The cost including sorting the rows if they need it.
+    fn total(&self, order: &[OrderTerm]) -> f64 {
+        if order.is_empty() || self.ordered {
+            self.cost
+        } else {
+            self.cost + planner::sort_cost(self.rows)
+        }
+    }
+}
+
+// Resolves column references against a row of the joined tables, and then
+// against the enclosing query.
+struct RowScope<'a> {
+    executor: &'a Executor<'a>,
+    sources: &'a [Source<'a>],
+    // One row per table, None for the NULL row of a LEFT JOIN. The whole row
+    // is None when a group has no rows, which makes every column NULL.
+    row: Option<&'a [Option<TableRow>]>,
+}
+
+impl<'a> Scope for RowScope<'a> {
+    fn column(&self, name: &[Ident]) -> Result<Operand, Error> {
+        let (s, index) = match lookup_column(self.sources, name)? {
+            Some(found) => found,
+            None => {
+                return match self.executor.outer {
+                    Some(outer) => outer.column(name),
+                    None => Err(expr::no_such_column(name)),
+                }
+            }
+        };
+        let table = &self.sources[s].table;
+        let value = match self.row.and_then(|row| row[s].as_ref()) {
+            Some(row) => column_value(row, index),
+            None => SqlValue::Null,
+        };
+        let json = match (
+            &self.sources[s].function,
+            self.row.and_then(|row| row[s].as_ref()),
+        ) {
+            (Some(_), Some(row)) => {
+                index.is_some_and(|i| json::TableFunction::carries_json(i, &row.values))
+            }
+            _ => false,
+        };
+        Ok(match index {
+            Some(i) => {
+                let column = &table.columns[i];
+                Operand {
+                    json,
+                    ..Operand::column(
+                        value,
+                        column_affinity(column),
+                        column_collation(&self.executor.collations, column),
+                    )
+                }
+            }
+            None => Operand::column(value, Affinity::Integer, None),
+        })
+    }
+
+    fn subquery(&self, query: &ast::Query) -> Result<Rc<Relation>, Error> {
+        self.executor.subquery(query, self)
+    }
+
+    fn collation(&self, name: &str) -> Result<Collation, Error> {
+        self.executor.collations.get(name)
+    }
+
+    fn functions(&self) -> Option<&Functions> {
+        Some(&self.executor.functions)
+    }
+
+    fn parameter(&self, number: usize) -> SqlValue {
+        let value = self.executor.parameters.get(number - 1);
+        value.cloned().unwrap_or(SqlValue::Null)
+    }
+}
+
+// The scope of the query around a subquery. It records whether the
+// subquery used it, since only subqueries that did not can be reused.
+struct OuterScope<'a> {
+    scope: &'a dyn Scope,
+    used: Cell<bool>,
+}
+
+impl<'a> Scope for OuterScope<'a> {
+    fn column(&self, name: &[Ident]) -> Result<Operand, Error> {
+        let operand = self.scope.column(name)?;
+        self.used.set(true);
+        Ok(operand)
+    }
+
+    fn collation(&self, name: &str) -> Result<Collation, Error> {
+        self.scope.collation(name)
+    }
+
+    fn functions(&self) -> Option<&Functions> {
+        self.scope.functions()
+    }
+
+    fn parameter(&self, number: usize) -> SqlValue {
+        self.scope.parameter(number)
+    }
+}
+
+// Finds the table and the column a column reference names.
+fn resolve(sources: &[Source], name: &[Ident]) -> Result<(usize, Option<usize>), Error> {
+    lookup_column(sources, name)?.ok_or_else(|| expr::no_such_column(name))
+}
+
+// Like `resolve`, but a name that no table has is None rather than an
+// error, as it may still belong to an enclosing query. Unqualified names
+// must not belong to more than one table.
+fn lookup_column(
+    sources: &[Source],
+    name: &[Ident],
+) -> Result<Option<(usize, Option<usize>)>, Error> {
+    let (qualifier, column) = match name {
+        [column] => (None, &column.value),
+        [.., qualifier, column] => (Some(&qualifier.value), &column.value),
+        [] => unreachable!(),
+    };
+    if let Some(qualifier) = qualifier {
+        return Ok(sources
+            .iter()
+            .position(|s| s.name.eq_ignore_ascii_case(qualifier))
+            .and_then(|s| column_of(&sources[s].table, column).ok().map(|c| (s, c))));
+    }
+    let mut found = None;
+    for (s, source) in sources.iter().enumerate() {
+        match column_of(&source.table, column) {
+            Ok(Some(c)) if source.merged.contains(&c) => continue,
+            Ok(c) if found.is_none() => found = Some((s, c)),
+            Ok(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("ambiguous column name: {}", column),
+                ))
+            }
+            Err(_) => continue,
+        }
+    }
+    Ok(found)
+}
+
+// Adds the condition of `USING (name)` to the right-hand table of a join:
+// its column equals the column of the same name in the tables before it,
+// and stands in for it from then on. Returns false if one of the sides has
+// no such column.
+fn join_using(sources: &[Source], right: &mut Source, name: &str) -> Result<bool, Error> {
+    let column = match right.table.column_index(name) {
+        Some(column) => column,
+        None => return Ok(false),
+    };
+    let (s, left_column) = match lookup_column(sources, &[Ident::new(name)])? {
+        Some((s, Some(c))) => (s, c),
+        _ => return Ok(false),
+    };
+    let left = &sources[s];
+    right.on.push(Expr::BinaryOp {
+        left: Box::new(left.column_expr(&left.table.columns[left_column])),
+        op: BinaryOperator::Eq,
+        right: Box::new(right.column_expr(&right.table.columns[column])),
+    });
+    right.merged.push(column);
+    Ok(true)
+}
+
+// The terms of the top-level AND chain of a condition.
+fn conjuncts(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            let mut terms = conjuncts(left);
+            terms.extend(conjuncts(right));
+            terms
+        }
+        Expr::Nested(expr) => conjuncts(expr),
+        expr => vec![expr],
+    }
+}
+
+// The set of tables an expression uses, as a bit mask over the positions in
+// the FROM clause. Columns of an enclosing query are constants here, and
+// subqueries may use any table. None if a column does not resolve.
+fn referenced_sources(sources: &[Source], outer: Option<&dyn Scope>, expr: &Expr) -> Option<u64> {
+    let all = u64::MAX.checked_shr(64 - sources.len() as u32).unwrap_or(0);
+    let mut tables = 0u64;
+    let mut resolved = true;
+    expr::walk(expr, &mut |e| {
+        let name = match e {
+            Expr::Identifier(ident) => std::slice::from_ref(ident),
+            Expr::CompoundIdentifier(idents) => idents.as_slice(),
+            Expr::Subquery(_) | Expr::Exists(_) | Expr::InSubquery { .. } => {
+                tables |= all;
+                return true;
+            }
+            _ => return true,
+        };
+        match lookup_column(sources, name) {
+            Ok(Some((s, _))) => tables |= 1 << s,
+            Ok(None) if outer.is_some_and(|outer| outer.column(name).is_ok()) => (),
+            _ => resolved = false,
+        }
+        false
+    });
+    if resolved {
+        Some(tables)
+    } else {
+        None
+    }
+}
+
+// How many leading key columns a seek keeps at one value. Those an IN term
+// gives take each of its values in turn.
+fn fixed_columns(range: &KeyRange) -> usize {
+    if range.in_values > 0 {
+        0
+    } else {
+        range.equal
+    }
+}
+
+// The position of the last table in a set of tables, 0 for none.
+fn highest_source(tables: u64) -> usize {
+    (64 - tables.leading_zeros() as usize).saturating_sub(1)
+}
+
+// Matches a `column = expr` term where the column belongs to the table at
+// `level` and expr only uses the tables in `known`.
+fn seek_equality(
+    functions: &Functions,
+    sources: &[Source],
+    outer: Option<&dyn Scope>,
+    level: usize,
+    known: u64,
+    term: &Expr,
+) -> Option<(Option<usize>, Expr)> {
+    let (left, right) = match term {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } => (left, right),
+        _ => return None,
+    };
+    let (column, other, _) = seek_operands(sources, outer, level, known, left, right)?;
+    if calls_nondeterministic(functions, other) {
+        return None;
+    }
+    Some((column, other.clone()))
+}
+
+// Which end of a range of values a condition bounds. Bounds are inclusive;
+// the condition itself excludes the end where it has to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Bound {
+    Lower,
+    Upper,
+}
+
+// Matches `column < expr`, `column >= expr` and the like, and `column
+// BETWEEN low AND high`, where the column belongs to the table at `level`
+// and the other side only uses the tables in `known`.
+fn seek_bounds(
+    functions: &Functions,
+    sources: &[Source],
+    outer: Option<&dyn Scope>,
+    level: usize,
+    known: u64,
+    term: &Expr,
+) -> Vec<(Option<usize>, Bound, Expr)> {
+    let mut bounds = Vec::new();
+    let mut add = |column: Option<usize>, bound: Bound, other: &Expr| {
+        if !calls_nondeterministic(functions, other) {
+            bounds.push((column, bound, other.clone()));
+        }
+    };
+    match term {
+        Expr::BinaryOp { left, op, right } => {
+            let bound = match op {
+                BinaryOperator::Gt | BinaryOperator::GtEq => Bound::Lower,
+                BinaryOperator::Lt | BinaryOperator::LtEq => Bound::Upper,
+                _ => return bounds,
+            };
+            match seek_operands(sources, outer, level, known, left, right) {
+                Some((column, other, true)) => add(column, bound, other),
+                // `expr < column` bounds the column from the other side
+                Some((column, other, false)) => {
+                    let flipped = match bound {
+                        Bound::Lower => Bound::Upper,
+                        Bound::Upper => Bound::Lower,
+                    };
+                    add(column, flipped, other)
+                }
+                None => (),
+            }
+        }
+        Expr::Between {
+            expr,
+            negated: false,
+            low,
+            high,
+        } => {
+            let known_only =
+                |e: &Expr| referenced_sources(sources, outer, e).is_some_and(|t| t & !known == 0);
+            if let Some((s, column)) = referenced_column(sources, expr) {
+                if s == level {
+                    if known_only(low) {
+                        add(column, Bound::Lower, low);
+                    }
+                    if known_only(high) {
+                        add(column, Bound::Upper, high);
+                    }
+                }
+            }
+        }
+        _ => (),
+    }
+    bounds
+}
+
+// Matches the two sides of a comparison to a column of the table at `level`
+// and an expression of the tables in `known`, returning the column, the
+// expression, and whether the column is on the left.
+fn seek_operands<'e>(
+    sources: &[Source],
+    outer: Option<&dyn Scope>,
+    level: usize,
+    known: u64,
+    left: &'e Expr,
+    right: &'e Expr,
+) -> Option<(Option<usize>, &'e Expr, bool)> {
+    let known_only =
+        |e: &Expr| referenced_sources(sources, outer, e).is_some_and(|t| t & !known == 0);
+    match (
+        referenced_column(sources, left),
+        referenced_column(sources, right),
+    ) {
+        (Some((s, column)), _) if s == level && known_only(right) => Some((column, right, true)),
+        (_, Some((s, column))) if s == level && known_only(left) => Some((column, left, false)),
+        _ => None,
+    }
+}
+
+// Whether an expression calls a registered function that is not declared
+// deterministic. Such a call may give every row a different value, so it
+// cannot provide a key to look rows up by.
+// The expressions giving the keys of a seek on the table at `level` of the
+// join, in the order `Executor::seek_keys` takes their values: those of the
+// equalities, the items of the IN lists, then those of the bounds.
+fn key_exprs<'f>(from: &'f FromClause, level: usize) -> Vec<&'f Expr> {
+    let mut exprs: Vec<&Expr> = from.equalities[level].iter().map(|(_, e)| e).collect();
+    for (_, term) in &from.lists[level] {
+        if let Expr::InList { list, .. } = term {
+            exprs.extend(list);
+        }
+    }
+    exprs.extend(from.ranges[level].iter().map(|(_, _, e)| e));
+    exprs
+}
+
+// The values of an IN subquery, with the type of its column, or None if it
+// fails or has more than one column, which is reported when the condition
+// itself is evaluated.
+fn subquery_operands(subquery: &ast::Query, scope: &dyn Scope) -> Option<Vec<Operand>> {
+    let relation = scope.subquery(subquery).ok()?;
+    let (affinity, collation) = match relation.types.as_slice() {
+        [column] => column.clone(),
+        _ => return None,
+    };
+    let operands = relation
+        .rows
+        .iter()
+        .map(|row| Operand::column(row[0].clone(), affinity, collation.clone()))
+        .collect();
+    Some(operands)
+}
+
+fn calls_nondeterministic(functions: &Functions, expr: &Expr) -> bool {
+    let mut found = false;
+    expr::walk(expr, &mut |e| {
+        if let Expr::Function(f) = e {
+            let name = f.name.to_string().to_ascii_lowercase();
+            if let Some(function) = functions.get(&name, f.args.len()) {
+                found |= !function.flags().deterministic;
+            }
+        }
+        !found
+    });
+    found
+}
+
+enum Seek {
+    // The key, and the collation the comparison uses.
+    Key(SqlValue, Collation),
+    // The comparison is never true.
+    NoRows,
+    // The comparison converts the column's values, so rows cannot be found
+    // by key.
+    Scan,
+}
+
+// Converts the value a column is compared with the way the comparison
+// would, so that the rows where `column = operand` holds are exactly those
+// whose key equals the result under the comparison's collation: an explicit
+// COLLATE on the operand, or else the column's. When the operand brings a
+// collation of its own from another column, which one applies depends on
+// the side of the column, so rows are not looked up by key.
+fn seek_key(
+    collations: &Collations,
+    table: &Table,
+    column: Option<usize>,
+    operand: Operand,
+) -> Seek {
+    let column_collation = column
+        .and_then(|i| column_collation(collations, &table.columns[i]))
+        .unwrap_or(Collation::Binary);
+    let collation = match operand.collation {
+        Some((collation, true)) => collation,
+        Some((collation, false)) if collation != column_collation => return Seek::Scan,
+        _ => column_collation,
+    };
+    if operand.value.is_null() {
+        return Seek::NoRows;
+    }
+    let affinity = match column {
+        Some(i) => column_affinity(&table.columns[i]),
+        None => Affinity::Integer,
+    };
+    let value = if affinity.is_numeric() {
+        if operand.affinity.is_numeric() {
+            operand.value
+        } else {
+            operand.value.apply_affinity(Affinity::Numeric)
+        }
+    } else if operand.affinity.is_numeric() {
+        return Seek::Scan;
+    } else if operand.affinity == Affinity::Blob {
+        operand.value.apply_affinity(affinity)
+    } else if affinity == Affinity::Text {
+        operand.value
+    } else {
+        return Seek::Scan;
+    };
+    if column.is_none() || column == table.rowid_alias {
+        return match value.apply_affinity(Affinity::Integer) {
+            SqlValue::Integer(i) => Seek::Key(SqlValue::Integer(i), Collation::Binary),
+            _ => Seek::NoRows,
+        };
+    }
+    Seek::Key(value, collation)
+}
+
+// Converts the value a column is bounded by, as `seek_key` does for `=`.
+// Rowids compare as numbers with any number, so they are bounded by the
+// number, and text or blobs leave them unbounded.
+fn bound_key(
+    collations: &Collations,
+    table: &Table,
+    column: Option<usize>,
+    operand: Operand,
+) -> Seek {
+    if !is_row_id(table, column) {
+        return seek_key(collations, table, column, operand);
+    }
+    if operand.value.is_null() {
+        return Seek::NoRows;
+    }
+    match operand.value.apply_affinity(Affinity::Numeric) {
+        value if value.is_numeric() => Seek::Key(value, Collation::Binary),
+        _ => Seek::Scan,
+    }
+}
+
+// Whether a column of a rowid table is its rowid.
+fn is_row_id(table: &Table, column: Option<usize>) -> bool {
+    !table.without_rowid && (column.is_none() || column == table.rowid_alias)
+}
+
+fn column_affinity(column: &Column) -> Affinity {
+    Affinity::from_declared_type(&column.declared_type)
+}
+
+fn column_collation(collations: &Collations, column: &Column) -> Option<Collation> {
+    column
+        .collation
+        .as_ref()
+        .and_then(|c| collations.get(c).ok())
+}
+
+// None stands for the rowid.
+fn column_of(table: &Table, name: &str) -> Result<Option<usize>, Error> {
+    if let Some(i) = table.column_index(name) {
+        return Ok(Some(i));
+    }
+    if !table.without_rowid && is_rowid_name(name) {
+        return Ok(None);
+    }
+    Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!("no such column: {}", name),
+    ))
+}
+
+fn column_value(row: &TableRow, column: Option<usize>) -> SqlValue {
+    match column {
+        Some(i) => row.values[i].clone(),
+        None => row.row_id.map(SqlValue::Integer).unwrap_or(SqlValue::Null),
+    }
+}
+
+// Result columns are named after the column they show, or after the text of
+// the expression.
+fn column_name(expr: &Expr) -> String {
+    match expr {
+        Expr::Identifier(ident) => ident.value.clone(),
+        Expr::CompoundIdentifier(idents) => idents.last().unwrap().value.clone(),
+        _ => sql_parser::display(expr),
+    }
+}
+
+// The table and column an expression refers to, if it is a plain column
+// reference.
+fn referenced_column(sources: &[Source], expr: &Expr) -> Option<(usize, Option<usize>)> {
+    match expr {
+        Expr::Identifier(ident) => resolve(sources, std::slice::from_ref(ident)).ok(),
+        Expr::CompoundIdentifier(idents) => resolve(sources, idents).ok(),
+        Expr::Nested(expr) => referenced_column(sources, expr),
+        _ => None,
+    }
+}
+
+// The collations the primary key of a WITHOUT ROWID table is ordered by.
+fn primary_key_collations(collations: &Collations, table: &Table) -> Vec<Collation> {
+    table
+        .primary_key
+        .iter()
+        .map(|c| column_collation(collations, &table.columns[*c]).unwrap_or(Collation::Binary))
+        .collect()
+}
+
+// The key columns of a b-tree: the column, None for an expression, whether
+// it is in descending order, and the collation it is ordered by.
+type KeyColumn = (Option<usize>, bool, Collation);
+
+// The key columns of the primary key of a WITHOUT ROWID table.
+fn primary_key_columns(collations: &Collations, table: &Table) -> Vec<KeyColumn> {
+    table
+        .primary_key
+        .iter()
+        .zip(&table.primary_key_descending)
+        .zip(primary_key_collations(collations, table))
+        .map(|((c, descending), collation)| (Some(*c), *descending, collation))
+        .collect()
+}
+
+// The key columns of an index, or None if one of their collations is not
+// registered.
+fn index_columns(collations: &Collations, index: &Index) -> Option<Vec<KeyColumn>> {
+    index
+        .columns
+        .iter()
+        .map(|ic| {
+            let collation = match &ic.collation {
+                Some(name) => collations.get(name).ok()?,
+                None => Collation::Binary,
+            };
+            Some((ic.column, ic.descending, collation))
+        })
+        .collect()
+}
+
+// Whether entries in the order of the key columns are sorted by the given
+// columns, allowing for up to `fixed` leading key columns that a seek fixes
+// to a single value and that may be left out.
+fn sorted_by(keys: &[KeyColumn], fixed: usize, columns: &[(Option<usize>, SortKey)]) -> bool {
+    (0..=fixed).any(|skip| {
+        columns.len() + skip <= keys.len()
+            && columns.iter().zip(&keys[skip..]).all(
+                |((column, key), (key_column, descending, collation))| {
+                    column.is_some()
+                        && column == key_column
+                        && key.descending == *descending
+                        && key.nulls_first != key.descending
+                        && key.collation == *collation
+                },
+            )
+    })
+}
+
+// How far conditions reach into b-tree keys with the given columns: the
+// leading columns `=` or IN fixes, and bounds on the next one. Conditions
+// only count under the collation the keys are ordered by. IN lists come
+// with the number of their values.
+fn key_range(
+    columns: &[KeyColumn],
+    equalities: &[(Option<usize>, Collation)],
+    lists: &[(Option<usize>, Collation, usize)],
+    bounds: &[(Option<usize>, Bound, Collation)],
+) -> KeyRange {
+    let mut range = KeyRange::default();
+    for (column, descending, collation) in columns {
+        if column.is_none() || *descending {
+            break;
+        }
+        if equalities
+            .iter()
+            .any(|(c, k)| c == column && k == collation)
+        {
+            range.equal += 1;
+            continue;
+        }
+        let values = lists
+            .iter()
+            .filter(|(c, k, _)| c == column && k == collation)
+            .map(|(_, _, values)| *values)
+            .min();
+        if let Some(values) = values {
+            range.equal += 1;
+            range.in_values = range.in_values.max(1) * values;
+            continue;
+        }
+        let bounded = |which| {
+            bounds
+                .iter()
+                .any(|(c, bound, k)| c == column && *bound == which && k == collation)
+        };
+        range.lower = bounded(Bound::Lower);
+        range.upper = bounded(Bound::Upper);
+        break;
+    }
+    range
+}
+
+// Whether an index holds every column of its table that the query uses,
+// counting the rowid or primary key its entries point at the row by.
+fn covers(table: &Table, index: &Index, used: &[bool]) -> bool {
+    used.iter().enumerate().all(|(c, used)| {
+        !used
+            || index.columns.iter().any(|ic| ic.column == Some(c))
+            || if table.without_rowid {
+                table.primary_key.contains(&c)
+            } else {
+                table.rowid_alias == Some(c)
+            }
+    })
+}
+
+// The key identifying the row of an index entry: the trailing rowid, or for
+// WITHOUT ROWID tables the primary key columns, which the entry only repeats
+// if the index does not contain them already.
+fn index_row_key(table: &Table, index: &Index, values: &[SqlValue]) -> Vec<SqlValue> {
+    let indexed = index.columns.len();
+    if !table.without_rowid {
+        return values[indexed..].to_vec();
+    }
+    let mut suffix = values[indexed..].iter();
+    table
+        .primary_key
+        .iter()
+        .map(
+            |c| match index.columns.iter().position(|ic| ic.column == Some(*c)) {
+                Some(i) => values[i].clone(),
+                None => suffix.next().cloned().unwrap_or(SqlValue::Null),
+            },
+        )
+        .collect()
+}
+
+// The row of an index entry, made of the columns the entry holds, with NULL
+// for the others.
+fn covered_row(table: &Table, index: &Index, values: &[SqlValue]) -> TableRow {
+    let key = index_row_key(table, index, values);
+    let mut row = vec![SqlValue::Null; table.columns.len()];
+    for (ic, value) in index.columns.iter().zip(values) {
+        if let Some(c) = ic.column {
+            row[c] = table.stored_value(c, value.clone());
+        }
+    }
+    if table.without_rowid {
+        for (c, value) in table.primary_key.iter().zip(key) {
+            row[*c] = table.stored_value(*c, value);
+        }
+        return TableRow {
+            row_id: None,
+            values: row,
+        };
+    }
+    let row_id = key.first().and_then(SqlValue::as_i64);
+    if let (Some(alias), Some(row_id)) = (table.rowid_alias, row_id) {
+        row[alias] = SqlValue::Integer(row_id);
+    }
+    TableRow {
+        row_id,
+        values: row,
+    }
+}
+
+// Where an index entry holds a column of its table: Some(None) for the
+// rowid, which rowid tables end their entries with, and None if it does
+// not hold it. WITHOUT ROWID tables end them with the primary key columns
+// the index does not have.
+fn entry_column(table: &Table, index: &Index, column: Option<usize>) -> Option<Option<usize>> {
+    if !table.without_rowid && (column.is_none() || column == table.rowid_alias) {
+        return Some(None);
+    }
+    let column = column?;
+    let indexed = |c: usize| index.columns.iter().position(|ic| ic.column == Some(c));
+    if let Some(i) = indexed(column) {
+        return Some(Some(i));
+    }
+    if !table.without_rowid {
+        return None;
+    }
+    table
+        .primary_key
+        .iter()
+        .filter(|c| indexed(**c).is_none())
+        .position(|c| *c == column)
+        .map(|k| Some(index.columns.len() + k))
+}
+
+// Orders the leading values of a b-tree entry against a key, comparing text
+// with the collation of each key column.
+fn compare_key(values: &[Value], key: &[SqlValue], collations: &[Collation]) -> Ordering {
+    for ((v, k), collation) in values.iter().zip(key).zip(collations) {
+        match expr::compare_values(&SqlValue::from(v), k, collation) {
+            Ordering::Equal => continue,
+            o => return o,
+        }
+    }
+    Ordering::Equal
+}
+
+// The columns of the table at position `s` the ORDER BY terms sort by, if
+// every term is a plain reference to one of them. The sort key has the
+// collation a COLLATE clause gives.
+fn order_columns(
+    sources: &[Source],
+    s: usize,
+    order: &[OrderTerm],
+) -> Option<Vec<(Option<usize>, SortKey)>> {
+    order
+        .iter()
+        .map(|term| {
+            let expr = match &term.expr {
+                Expr::Collate { expr, .. } => expr,
+                expr => expr,
+            };
+            match referenced_column(sources, expr) {
+                Some((t, column)) if t == s => Some((column, term.key.clone())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// Finds the result column a term of ORDER BY or GROUP BY refers to. An
+// integer constant K is the K-th result column, and a name can be the name of
+// a result column. ORDER BY looks at result column names before table
+// columns, GROUP BY only after them.
+fn result_column(
+    sources: &[Source],
+    clause: &str,
+    n: usize,
+    expr: &Expr,
+    columns: &[String],
+) -> Result<Option<usize>, Error> {
+    match expr {
+        Expr::Value(ast::Value::Number(k, _)) => {
+            let k = k.parse::<usize>().unwrap_or(0);
+            if k == 0 || k > columns.len() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} {} term out of range - should be between 1 and {}",
+                        ordinal(n + 1),
+                        clause,
+                        columns.len()
+                    ),
+                ));
+            }
+            Ok(Some(k - 1))
+        }
+        Expr::Identifier(ident)
+            if clause == "ORDER BY" || resolve(sources, std::slice::from_ref(ident)).is_err() =>
+        {
+            Ok(columns
+                .iter()
+                .position(|c| c.eq_ignore_ascii_case(&ident.value)))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn order_terms(
+    collations: &Collations,
+    sources: &[Source],
+    order_by: &[ast::OrderByExpr],
+    columns: &[String],
+    projection: &[Expr],
+) -> Result<Vec<OrderTerm>, Error> {
+    let mut terms = Vec::with_capacity(order_by.len());
+    for (n, term) in order_by.iter().enumerate() {
+        // result columns are also visible under a COLLATE, as in
+        // `ORDER BY t COLLATE NOCASE`
+        let (term_expr, collation) = match &term.expr {
+            Expr::Collate { expr, collation } => (expr.as_ref(), Some(collation)),
+            expr => (expr, None),
+        };
+        let result_column = result_column(sources, "ORDER BY", n, term_expr, columns)?;
+        let expr = match (result_column, collation) {
+            (Some(i), Some(collation)) => Expr::Collate {
+                expr: Box::new(projection[i].clone()),
+                collation: collation.clone(),
+            },
+            (Some(i), None) => projection[i].clone(),
+            (None, _) => term.expr.clone(),
+        };
+        let descending = term.asc == Some(false);
+        let mut key = SortKey::new(descending, expr_collation(collations, sources, &expr)?);
+        if let Some(nulls_first) = term.nulls_first {
+            key.nulls_first = nulls_first;
+        }
+        terms.push(OrderTerm {
+            expr,
+            result_column,
+            key,
+        });
+    }
+    Ok(terms)
+}
+
+// WHERE and HAVING may name a result column by its alias where no table
+// has a column of that name. Returns a copy of the SELECT with such names
+// replaced by the aliased expressions, or None if there are none.
+fn with_result_aliases(sources: &[Source], select: &ast::Select) -> Option<ast::Select> {
+    let aliases: Vec<(&Ident, &Expr)> = select
+        .projection
+        .iter()
+        .filter_map(|item| match item {
+            SelectItem::ExprWithAlias { expr, alias } => Some((alias, expr)),
+            _ => None,
+        })
+        .collect();
+    if aliases.is_empty() {
+        return None;
+    }
+    let mut resolved = select.clone();
+    let mut replaced = false;
+    for expr in resolved
+        .selection
+        .iter_mut()
+        .chain(resolved.having.iter_mut())
+    {
+        expr::walk_mut(expr, &mut |e| {
+            let ident = match e {
+                Expr::Identifier(ident) => ident,
+                _ => return true,
+            };
+            let aliased = aliases
+                .iter()
+                .find(|(alias, _)| alias.value.eq_ignore_ascii_case(&ident.value));
+            // an ambiguous name is left for evaluation to report
+            if let (Some((_, aliased)), Ok(None)) =
+                (aliased, lookup_column(sources, std::slice::from_ref(ident)))
+            {
+                *e = (*aliased).clone();
+                replaced = true;
+            }
+            false
+        });
+    }
+    if replaced {
+        Some(resolved)
+    } else {
+        None
+    }
+}
+
+fn group_terms(
+    sources: &[Source],
+    group_by: &[Expr],
+    columns: &[String],
+    projection: &[Expr],
+) -> Result<Vec<Expr>, Error> {
+    let mut terms = Vec::with_capacity(group_by.len());
+    for (n, expr) in group_by.iter().enumerate() {
+        terms.push(
+            match result_column(sources, "GROUP BY", n, expr, columns)? {
+                Some(i) => projection[i].clone(),
+                None => expr.clone(),
+            },
+        );
+    }
+    Ok(terms)
+}
+
+// The distinct aggregate function calls of the result columns, HAVING and
+// ORDER BY.
+fn collect_aggregates(
+    functions: &Functions,
+    select: &ast::Select,
+    projection: &[Expr],
+    order: &[OrderTerm],
+) -> Vec<ast::Function> {
+    let mut aggregates: Vec<ast::Function> = Vec::new();
+    let mut visit = |e: &Expr| match e {
+        Expr::Function(f) if functions.is_aggregate(f) => {
+            if !aggregates.contains(f) {
+                aggregates.push(f.clone());
+            }
+            false
+        }
+        _ => true,
+    };
+    let order = order.iter().map(|term| &term.expr);
+    for expr in projection.iter().chain(&select.having).chain(order) {
+        expr::walk(expr, &mut visit);
+    }
+    aggregates
+}
+
+// Whether an expression of a query with aggregates uses a column outside
+// their arguments, or a subquery that may.
+fn uses_columns(functions: &Functions, expr: &Expr) -> bool {
+    let mut uses = false;
+    expr::walk(expr, &mut |e| match e {
+        Expr::Function(f) if functions.is_aggregate(f) => false,
+        Expr::Identifier(_)
+        | Expr::CompoundIdentifier(_)
+        | Expr::Subquery(_)
+        | Expr::Exists(_)
+        | Expr::InSubquery { .. } => {
+            uses = true;
+            false
+        }
+        _ => true,
+    });
+    uses
+}
+
+// The distinct window function calls of the result columns and ORDER BY.
+fn collect_windows(projection: &[Expr], order: &[OrderTerm]) -> Vec<ast::Function> {
+    let mut windows: Vec<ast::Function> = Vec::new();
+    let mut visit = |e: &Expr| match e {
+        Expr::Function(f) if f.over.is_some() => {
+            if !windows.contains(f) {
+                windows.push(f.clone());
+            }
+            false
+        }
+        _ => true,
+    };
+    let order = order.iter().map(|term| &term.expr);
+    for expr in projection.iter().chain(order) {
+        expr::walk(expr, &mut visit);
+    }
+    windows
+}
+
+// The collation of a function's first argument, which min() and max()
+// compare with.
+fn argument_collation(
+    collations: &Collations,
+    sources: &[Source],
+    function: &ast::Function,
+) -> Result<Collation, Error> {
+    match function.args.first() {
+        Some(FunctionArg::Unnamed(arg)) => expr_collation(collations, sources, arg),
+        _ => Ok(Collation::Binary),
+    }
+}
+
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+// The affinity and collation a result column passes on: those of the
+// column it shows, the type of a CAST, or an explicit COLLATE.
+fn expr_type(
+    collations: &Collations,
+    sources: &[Source],
+    expr: &Expr,
+) -> (Affinity, Option<Collation>) {
+    match expr {
+        Expr::Nested(expr) => expr_type(collations, sources, expr),
+        Expr::Collate { expr, collation } => {
+            let name = &collation.0.last().unwrap().value;
+            (
+                expr_type(collations, sources, expr).0,
+                collations.get(name).ok(),
+            )
+        }
+        Expr::Cast { data_type, .. } => {
+            (Affinity::from_declared_type(&data_type.to_string()), None)
+        }
+        _ => match referenced_column(sources, expr) {
+            Some((s, Some(i))) => {
+                let column = &sources[s].table.columns[i];
+                (
+                    column_affinity(column),
+                    column_collation(collations, column),
+                )
+            }
+            Some((_, None)) => (Affinity::Integer, None),
+            None => (Affinity::Blob, None),
+        },
+    }
+}
+
+// The collation an expression sorts with: an explicit COLLATE, or that of
+// the column it refers to.
+fn expr_collation(
+    collations: &Collations,
+    sources: &[Source],
+    expr: &Expr,
+) -> Result<Collation, Error> {
+    match expr {
+        Expr::Collate { collation, .. } => collations.get(&collation.0.last().unwrap().value),
+        Expr::Nested(expr) => expr_collation(collations, sources, expr),
+        _ => Ok(match referenced_column(sources, expr) {
+            Some((s, Some(i))) => column_collation(collations, &sources[s].table.columns[i])
+                .unwrap_or(Collation::Binary),
+            _ => Collation::Binary,
+        }),
+    }
+}
+
+// A subquery or common table expression in FROM, whose rows are already
+// known. `aliases` renames its leading columns.
+fn relation_source<'a>(
+    relation: &Relation,
+    name: String,
+    aliases: &[Ident],
+    left_join: bool,
+) -> Source<'a> {
+    let columns = relation
+        .columns
+        .iter()
+        .enumerate()
+        .zip(&relation.types)
+        .map(|((i, name), (affinity, collation))| Column {
+            name: aliases.get(i).map_or(name, |a| &a.value).clone(),
+            declared_type: affinity.type_name().to_string(),
+            not_null: false,
+            collation: collation.as_ref().map(|c| c.name().to_string()),
+            default: None,
+        })
+        .collect();
+    let rows = relation
+        .rows
+        .iter()
+        .map(|values| TableRow {
+            row_id: None,
+            values: values.clone(),
+        })
+        .collect();
+    Source {
+        table: Cow::Owned(Table::transient(name.clone(), columns)),
+        rows: Some(Rc::new(rows)),
+        queued: None,
+        name,
+        merged: Vec::new(),
+        left_join,
+        on: Vec::new(),
+        function: None,
+        columns: None,
+    }
+}
+
+// Checks that a common table expression gives as many columns as its name
+// lists, if it lists any.
+fn check_columns(cte: &CommonTable, width: usize) -> Result<(), Error> {
+    if !cte.columns.is_empty() && cte.columns.len() != width {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "table {} has {} values for {} columns",
+                cte.name,
+                width,
+                cte.columns.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Counts the uses of `name` as a table in the FROM clauses of a query and
+// its subqueries.
+fn table_references(query: &ast::Query, name: &str) -> usize {
+    let ctes = query.with.iter().flat_map(|w| &w.cte_tables);
+    ctes.map(|cte| table_references(&cte.query, name))
+        .sum::<usize>()
+        + set_table_references(&query.body, name)
+        + query
+            .order_by
+            .iter()
+            .map(|term| expr_table_references(&term.expr, name))
+            .sum::<usize>()
+}
+
+fn set_table_references(body: &SetExpr, name: &str) -> usize {
+    let select = match body {
+        SetExpr::Select(select) => select,
+        SetExpr::Query(query) => return table_references(query, name),
+        SetExpr::SetOperation { left, right, .. } => {
+            return set_table_references(left, name) + set_table_references(right, name)
+        }
+        _ => return 0,
+    };
+    let mut count = 0;
+    let mut exprs: Vec<&Expr> = Vec::new();
+    let mut factors = Vec::new();
+    for table in &select.from {
+        factors.push(&table.relation);
+        for join in &table.joins {
+            factors.push(&join.relation);
+            if let JoinOperator::Inner(JoinConstraint::On(on))
+            | JoinOperator::LeftOuter(JoinConstraint::On(on)) = &join.join_operator
+            {
+                exprs.push(on);
+            }
+        }
+    }
+    while let Some(factor) = factors.pop() {
+        match factor {
+            TableFactor::Table { name: table, .. } => {
+                if let [table] = table.0.as_slice() {
+                    count += table.value.eq_ignore_ascii_case(name) as usize;
+                }
+            }
+            TableFactor::Derived { subquery, .. } => count += table_references(subquery, name),
+            TableFactor::NestedJoin(table) => {
+                factors.push(&table.relation);
+                factors.extend(table.joins.iter().map(|j| &j.relation));
+            }
+            _ => (),
+        }
+    }
+    for item in &select.projection {
+        match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                exprs.push(expr)
+            }
+            _ => (),
+        }
+    }
+    exprs.extend(&select.selection);
+    exprs.extend(&select.group_by);
+    exprs.extend(&select.having);
+    count
+        + exprs
+            .into_iter()
+            .map(|expr| expr_table_references(expr, name))
+            .sum::<usize>()
+}
+
+fn expr_table_references(expr: &Expr, name: &str) -> usize {
+    let mut count = 0;
+    expr::walk(expr, &mut |e| {
+        match e {
+            Expr::Subquery(query) | Expr::Exists(query) => count += table_references(query, name),
+            Expr::InSubquery { subquery, .. } => count += table_references(subquery, name),
+            _ => (),
+        }
+        true
+    });
+    count
+}
+
+// A query of a single SELECT or VALUES body, without ORDER BY or LIMIT.
+fn plain_query(body: &SetExpr) -> ast::Query {
+    ast::Query {
+        with: None,
+        body: body.clone(),
+        order_by: Vec::new(),
+        limit: None,
+        offset: None,
+        fetch: None,
+    }
+}
+
+// The ORDER BY of a compound SELECT can only sort by result columns: by
+// position, by name, or by an expression of one of the SELECTs, which are
+// tried left to right.
+fn compound_order(
+    collations: &Collations,
+    query: &ast::Query,
+    relation: &Relation,
+) -> Result<Vec<(usize, SortKey)>, Error> {
+    let mut selects = Vec::new();
+    let mut bodies = vec![&query.body];
+    while let Some(body) = bodies.pop() {
+        match body {
+            SetExpr::Select(select) => selects.push(select),
+            SetExpr::SetOperation { left, right, .. } => {
+                bodies.push(right);
+                bodies.push(left);
+            }
+            _ => (),
+        }
+    }
+    let mut terms = Vec::with_capacity(query.order_by.len());
+    for (n, term) in query.order_by.iter().enumerate() {
+        let (expr, collation) = match &term.expr {
+            Expr::Collate { expr, collation } => (expr.as_ref(), Some(collation)),
+            expr => (expr, None),
+        };
+        let column = match result_column(&[], "ORDER BY", n, expr, &relation.columns)? {
+            Some(i) => Some(i),
+            None => selects
+                .iter()
+                .find_map(|select| select_column(select, expr)),
+        };
+        let i = column
+            .filter(|i| *i < relation.columns.len())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} ORDER BY term does not match any column in the result set",
+                        ordinal(n + 1)
+                    ),
+                )
+            })?;
+        let collation = match collation {
+            Some(name) => collations.get(&name.0.last().unwrap().value)?,
+            None => relation.types[i].1.clone().unwrap_or(Collation::Binary),
+        };
+        let mut key = SortKey::new(term.asc == Some(false), collation);
+        if let Some(nulls_first) = term.nulls_first {
+            key.nulls_first = nulls_first;
+        }
+        terms.push((i, key));
+    }
+    Ok(terms)
+}
+
+// The result column of a SELECT that is `expr`, or named by it.
+fn select_column(select: &ast::Select, expr: &Expr) -> Option<usize> {
+    let name = match expr {
+        Expr::Identifier(ident) => Some(&ident.value),
+        _ => None,
+    };
+    for (i, item) in select.projection.iter().enumerate() {
+        let (item_expr, item_name) = match item {
+            SelectItem::UnnamedExpr(e) => (e, column_name(e)),
+            SelectItem::ExprWithAlias { expr, alias } => (expr, alias.value.clone()),
+            // later columns have unknown positions
+            _ => return None,
+        };
+        if item_expr == expr || name.is_some_and(|n| n.eq_ignore_ascii_case(&item_name)) {
+            return Some(i);
+        }
+    }
+    None
+}
+
+// The LIMIT of a query, None for no limit, and its OFFSET.
+fn limit_offset(query: &ast::Query, scope: &dyn Scope) -> Result<(Option<i64>, i64), Error> {
+    let limit = match &query.limit {
+        Some(limit) => Some(integer_constant(limit, scope)?).filter(|l| *l >= 0),
+        None => None,
+    };
+    let offset = match &query.offset {
+        Some(offset) => integer_constant(&offset.value, scope)?.max(0),
+        None => 0,
+    };
+    Ok((limit, offset))
+}
+
+// Evaluates the expression of a LIMIT or OFFSET clause.
+fn integer_constant(expr: &Expr, scope: &dyn Scope) -> Result<i64, Error> {
+    match expr::evaluate(expr, scope)?.apply_affinity(Affinity::Integer) {
+        SqlValue::Integer(i) => Ok(i),
+        _ => Err(Error::new(ErrorKind::InvalidInput, "datatype mismatch")),
+    }
+}
@@ -0,0 +1,1454 @@
+use crate::aggregate;
+use crate::aggregate::{Aggregate, AnyAggregate};
+use crate::datetime;
+use crate::executor::Relation;
+use crate::json;
+use crate::scalar;
+use crate::scalar::{FunctionFlags, ScalarFunction};
+use crate::sql_parser;
+use crate::sql_parser::Pattern;
+use crate::value::{Affinity, SqlValue};
+use crate::window;
+use sqlparser::ast;
+use sqlparser::ast::{BinaryOperator, Expr, FunctionArg, Ident, UnaryOperator};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::rc::Rc;
+
+// Supplies the values of column references while an expression is evaluated.
+pub trait Scope {
+    // Resolves a column reference such as `Name` or `t.Name`.
+    fn column(&self, name: &[Ident]) -> Result<Operand, Error>;
+
+    // The value of an aggregate function call, in scopes that stand for a
+    // group of rows.
+    fn aggregate(&self, _function: &ast::Function) -> Option<Result<SqlValue, Error>> {
+        None
+    }
+
+    // The value of a window function call, in scopes that stand for a row
+    // of a query with windows.
+    fn window(&self, _function: &ast::Function) -> Option<Result<SqlValue, Error>> {
+        None
+    }
+
+    // Runs a subquery, whose column references may refer to this scope.
+    fn subquery(&self, query: &ast::Query) -> Result<Rc<Relation>, Error> {
+        Err(unsupported(format!("subquery ({})", query)))
+    }
+
+    // Resolves the name in a COLLATE clause.
+    fn collation(&self, name: &str) -> Result<Collation, Error> {
+        Collation::from_name(name)
+    }
+
+    // The functions the application registered, if any.
+    fn functions(&self) -> Option<&Functions> {
+        None
+    }
+
+    // The value bound to a parameter, by number. Unbound parameters are
+    // NULL.
+    fn parameter(&self, _number: usize) -> SqlValue {
+        SqlValue::Null
+    }
+}
+
+// A scope without any columns, for expressions that stand on their own.
+pub struct EmptyScope;
+
+impl Scope for EmptyScope {
+    fn column(&self, name: &[Ident]) -> Result<Operand, Error> {
+        Err(no_such_column(name))
+    }
+}
+
+// Compares two strings for a user-defined collation.
+pub type CollationFunction = dyn Fn(&str, &str) -> Ordering;
+
+#[derive(Clone)]
+pub enum Collation {
+    Binary,
+    NoCase,
+    RTrim,
+    // A collation the application registered, under the name it was given.
+    Custom(Rc<str>, Rc<CollationFunction>),
+}
+
+impl Collation {
+    pub fn from_name(name: &str) -> Result<Collation, Error> {
+        match name.to_ascii_uppercase().as_str() {
+            "BINARY" => Ok(Collation::Binary),
+            "NOCASE" => Ok(Collation::NoCase),
+            "RTRIM" => Ok(Collation::RTrim),
+            _ => Err(no_such_collation(name)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Collation::Binary => "BINARY",
+            Collation::NoCase => "NOCASE",
+            Collation::RTrim => "RTRIM",
+            Collation::Custom(name, _) => name,
+        }
+    }
+
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            Collation::Binary => a.cmp(b),
+            Collation::NoCase => a
+                .bytes()
+                .map(|c| c.to_ascii_lowercase())
+                .cmp(b.bytes().map(|c| c.to_ascii_lowercase())),
+            Collation::RTrim => a.trim_end_matches(' ').cmp(b.trim_end_matches(' ')),
+            Collation::Custom(_, compare) => compare(a, b),
+        }
+    }
+}
+
+impl fmt::Debug for Collation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Two user-defined collations are the same only if they come from the same
+// registration, since a name can be registered again with another function.
+impl PartialEq for Collation {
+    fn eq(&self, other: &Collation) -> bool {
+        match (self, other) {
+            (Collation::Binary, Collation::Binary)
+            | (Collation::NoCase, Collation::NoCase)
+            | (Collation::RTrim, Collation::RTrim) => true,
+            (Collation::Custom(_, a), Collation::Custom(_, b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+fn no_such_collation(name: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("no such collation sequence: {}", name),
+    )
+}
+
+// The collations statements can name: the built-in ones, and those the
+// application registered, which take precedence over built-ins of the same
+// name as in SQLite.
+#[derive(Clone, Default)]
+pub struct Collations {
+    custom: HashMap<String, Collation>,
+}
+
+impl Collations {
+    pub fn new() -> Collations {
+        Collations::default()
+    }
+
+    // Registers `compare` under `name`, replacing any collation registered
+    // before under the same name. Names are case-insensitive. `compare` must
+    // be a total order for indexes built with it to be searched correctly.
+    pub fn register<F>(&mut self, name: &str, compare: F)
+    where
+        F: Fn(&str, &str) -> Ordering + 'static,
+    {
+        let collation = Collation::Custom(name.into(), Rc::new(compare));
+        self.custom.insert(name.to_ascii_uppercase(), collation);
+    }
+
+    pub fn get(&self, name: &str) -> Result<Collation, Error> {
+        match self.custom.get(&name.to_ascii_uppercase()) {
+            Some(collation) => Ok(collation.clone()),
+            None => Collation::from_name(name),
+        }
+    }
+}
+
+// A function the application registered.
+#[derive(Clone)]
+pub enum Function {
+    Scalar(Rc<ScalarFunction>, FunctionFlags),
+    Aggregate(Rc<dyn AnyAggregate>, FunctionFlags),
+}
+
+impl Function {
+    pub fn flags(&self) -> FunctionFlags {
+        match self {
+            Function::Scalar(_, flags) | Function::Aggregate(_, flags) => *flags,
+        }
+    }
+}
+
+// The functions the application registered, by name and number of
+// arguments. As in SQLite they take the place of built-in functions of the
+// same name, and a function registered for any number of arguments is only
+// used when none was for the exact number.
+#[derive(Clone, Default)]
+pub struct Functions {
+    functions: HashMap<String, Vec<(Option<usize>, Function)>>,
+}
+
+impl Functions {
+    pub fn new() -> Functions {
+        Functions::default()
+    }
+
+    // Registers a scalar function taking `arguments` arguments, or any
+    // number for None. Names are case-insensitive, and registering a name
+    // and number of arguments again replaces the function.
+    pub fn register_scalar<F>(
+        &mut self,
+        name: &str,
+        arguments: Option<usize>,
+        flags: FunctionFlags,
+        function: F,
+    ) where
+        F: Fn(&[SqlValue]) -> Result<SqlValue, Error> + 'static,
+    {
+        self.insert(name, arguments, Function::Scalar(Rc::new(function), flags));
+    }
+
+    pub fn register_aggregate<A>(
+        &mut self,
+        name: &str,
+        arguments: Option<usize>,
+        flags: FunctionFlags,
+        aggregate: A,
+    ) where
+        A: Aggregate + 'static,
+    {
+        self.insert(
+            name,
+            arguments,
+            Function::Aggregate(Rc::new(aggregate), flags),
+        );
+    }
+
+    fn insert(&mut self, name: &str, arguments: Option<usize>, function: Function) {
+        let overloads = self.functions.entry(name.to_ascii_lowercase()).or_default();
+        overloads.retain(|(n, _)| *n != arguments);
+        overloads.push((arguments, function));
+    }
+
+    // The function called for `name`, which must be in lower case, with
+    // `arguments` arguments.
+    pub fn get(&self, name: &str, arguments: usize) -> Option<&Function> {
+        let overloads = self.functions.get(name)?;
+        overloads
+            .iter()
+            .find(|(n, _)| *n == Some(arguments))
+            .or_else(|| overloads.iter().find(|(n, _)| n.is_none()))
+            .map(|(_, function)| function)
+    }
+
+    // Whether some function is registered under `name`, in lower case.
+    pub fn contains(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    // Whether a call is to an aggregate function, registered or built in.
+    pub fn is_aggregate(&self, function: &ast::Function) -> bool {
+        if function.over.is_some() {
+            return false;
+        }
+        let name = function.name.to_string().to_ascii_lowercase();
+        match self.get(&name, function.args.len()) {
+            Some(registered) => matches!(registered, Function::Aggregate(..)),
+            None => aggregate::is_aggregate(function),
+        }
+    }
+}
+
+// A value together with the properties SQLite tracks for comparisons: the
+// affinity of a column or CAST, and a collation that is either explicit
+// (COLLATE) or inherited from a column definition. `json` is SQLite's JSON
+// subtype, set on JSON text returned by the JSON functions so that other JSON
+// functions embed it rather than quote it.
+#[derive(Debug, Clone)]
+pub struct Operand {
+    pub value: SqlValue,
+    pub affinity: Affinity,
+    pub collation: Option<(Collation, bool)>,
+    pub json: bool,
+}
+
+impl Operand {
+    pub fn new(value: SqlValue) -> Operand {
+        Operand {
+            value,
+            affinity: Affinity::Blob,
+            collation: None,
+            json: false,
+        }
+    }
+
+    pub fn column(value: SqlValue, affinity: Affinity, collation: Option<Collation>) -> Operand {
+        Operand {
+            value,
+            affinity,
+            collation: collation.map(|c| (c, false)),
+            json: false,
+        }
+    }
+}
+
+pub fn no_such_column(name: &[Ident]) -> Error {
+    let name: Vec<&str> = name.iter().map(|i| i.value.as_str()).collect();
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("no such column: {}", name.join(".")),
+    )
+}
+
+fn unsupported(what: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("unsupported: {}", what))
+}
+
+pub fn evaluate(expr: &Expr, scope: &dyn Scope) -> Result<SqlValue, Error> {
+    Ok(evaluate_operand(expr, scope)?.value)
+}
+
+// Evaluates a WHERE or HAVING condition, where NULL counts as false.
+pub fn is_true(expr: &Expr, scope: &dyn Scope) -> Result<bool, Error> {
+    Ok(truth(&evaluate(expr, scope)?) == Some(true))
+}
+
+// The truth value of a value in a boolean context, None meaning NULL.
+pub fn truth(value: &SqlValue) -> Option<bool> {
+    match value {
+        SqlValue::Null => None,
+        SqlValue::Real(f) => Some(*f != 0.0),
+        SqlValue::Integer(i) => Some(*i != 0),
+        v => Some(v.to_f64() != Some(0.0)),
+    }
+}
+
+fn from_truth(truth: Option<bool>) -> SqlValue {
+    match truth {
+        Some(b) => SqlValue::from_bool(b),
+        None => SqlValue::Null,
+    }
+}
+
+pub fn evaluate_operand(expr: &Expr, scope: &dyn Scope) -> Result<Operand, Error> {
+    let value = match expr {
+        Expr::Identifier(ident) => {
+            if ident.quote_style.is_none() {
+                if let Some(now) = datetime::current(&ident.value) {
+                    return Ok(Operand::new(now));
+                }
+            }
+            return column_or_string(std::slice::from_ref(ident), scope);
+        }
+        Expr::CompoundIdentifier(idents) => return scope.column(idents),
+        Expr::Nested(expr) => return evaluate_operand(expr, scope),
+        Expr::Collate { expr, collation } => {
+            let mut operand = evaluate_operand(expr, scope)?;
+            let name = &collation.0.last().unwrap().value;
+            operand.collation = Some((scope.collation(name)?, true));
+            return Ok(operand);
+        }
+        Expr::Cast { expr, data_type } | Expr::TryCast { expr, data_type } => {
+            let affinity = Affinity::from_declared_type(&data_type.to_string());
+            let value = cast(evaluate(expr, scope)?, affinity);
+            return Ok(Operand {
+                value,
+                affinity,
+                collation: None,
+                json: false,
+            });
+        }
+        Expr::Value(literal) => match sql_parser::parameter(expr) {
+            Some(number) => scope.parameter(number),
+            None => literal_value(literal)?,
+        },
+        Expr::IsNull(expr) => SqlValue::from_bool(evaluate(expr, scope)?.is_null()),
+        Expr::IsNotNull(expr) => SqlValue::from_bool(!evaluate(expr, scope)?.is_null()),
+        Expr::UnaryOp { op, expr } => unary(op, expr, scope)?,
+        Expr::BinaryOp { left, op, right } => {
+            if let Some((arrow, left)) = sql_parser::arrow(expr) {
+                let args = [
+                    evaluate_operand(left, scope)?,
+                    evaluate_operand(right, scope)?,
+                ];
+                let (value, json) = json::call(arrow, &args)?;
+                return Ok(Operand {
+                    json,
+                    ..Operand::new(value)
+                });
+            }
+            binary(left, op, right, scope)?
+        }
+        Expr::Between {
+            expr,
+            negated,
+            low,
+            high,
+        } => {
+            let value = evaluate_operand(expr, scope)?;
+            let low = evaluate_operand(low, scope)?;
+            let high = evaluate_operand(high, scope)?;
+            let above = compare(&value, &low).map(|o| o != Ordering::Less);
+            let below = compare(&value, &high).map(|o| o != Ordering::Greater);
+            let between = and(above, below);
+            from_truth(if *negated {
+                between.map(|b| !b)
+            } else {
+                between
+            })
+        }
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let value = evaluate_operand(expr, scope)?;
+            let mut candidates = Vec::with_capacity(list.len());
+            for item in list {
+                candidates.push(evaluate_operand(item, scope)?);
+            }
+            let found = in_list(&value, &candidates);
+            from_truth(if *negated { found.map(|b| !b) } else { found })
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            let operand = match operand {
+                Some(operand) => Some(evaluate_operand(operand, scope)?),
+                None => None,
+            };
+            for (condition, result) in conditions.iter().zip(results) {
+                let matched = match &operand {
+                    Some(operand) => {
+                        compare(operand, &evaluate_operand(condition, scope)?)
+                            == Some(Ordering::Equal)
+                    }
+                    None => is_true(condition, scope)?,
+                };
+                if matched {
+                    return evaluate_operand(result, scope);
+                }
+            }
+            match else_result {
+                Some(result) => return evaluate_operand(result, scope),
+                None => SqlValue::Null,
+            }
+        }
+        Expr::Function(function) => return call_function(function, scope),
+        // a scalar subquery is the first column of its first row, or NULL
+        Expr::Subquery(query) => {
+            let relation = scope.subquery(query)?;
+            let (affinity, collation) = single_column(&relation)?;
+            let value = match relation.rows.first() {
+                Some(row) => row[0].clone(),
+                None => SqlValue::Null,
+            };
+            return Ok(Operand::column(value, affinity, collation));
+        }
+        Expr::Exists(query) => SqlValue::from_bool(!scope.subquery(query)?.rows.is_empty()),
+        Expr::InSubquery {
+            expr,
+            subquery,
+            negated,
+        } => {
+            let value = evaluate_operand(expr, scope)?;
+            let relation = scope.subquery(subquery)?;
+            let (affinity, collation) = single_column(&relation)?;
+            let candidates: Vec<Operand> = relation
+                .rows
+                .iter()
+                .map(|row| Operand::column(row[0].clone(), affinity, collation.clone()))
+                .collect();
+            let found = in_list(&value, &candidates);
+            from_truth(if *negated { found.map(|b| !b) } else { found })
+        }
+        _ => return Err(unsupported(format!("expression {}", expr))),
+    };
+    Ok(Operand::new(value))
+}
+
+// The affinity and collation of the only column of a subquery used as a
+// value.
+fn single_column(relation: &Relation) -> Result<(Affinity, Option<Collation>), Error> {
+    match relation.types.as_slice() {
+        [column] => Ok(column.clone()),
+        types => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("sub-select returns {} columns - expected 1", types.len()),
+        )),
+    }
+}
+
+// SQLite treats a double-quoted name that matches no column as a string.
+fn column_or_string(name: &[Ident], scope: &dyn Scope) -> Result<Operand, Error> {
+    match scope.column(name) {
+        Ok(operand) => Ok(operand),
+        Err(e) => match name {
+            [ident] if ident.quote_style == Some('"') => {
+                Ok(Operand::new(SqlValue::Text(ident.value.clone())))
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+pub fn literal_value(literal: &ast::Value) -> Result<SqlValue, Error> {
+    match literal {
+        ast::Value::Number(n, _) => Ok(parse_number_literal(n)),
+        ast::Value::SingleQuotedString(s) | ast::Value::NationalStringLiteral(s) => {
+            Ok(SqlValue::Text(s.clone()))
+        }
+        ast::Value::HexStringLiteral(h) => decode_hex(h)
+            .map(SqlValue::Blob)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "malformed hex literal")),
+        ast::Value::Boolean(b) => Ok(SqlValue::from_bool(*b)),
+        ast::Value::Null => Ok(SqlValue::Null),
+        _ => Err(unsupported(format!("literal {}", literal))),
+    }
+}
+
+fn parse_number_literal(n: &str) -> SqlValue {
+    if !n.contains(['.', 'e', 'E']) {
+        if let Ok(i) = n.parse::<i64>() {
+            return SqlValue::Integer(i);
+        }
+    }
+    SqlValue::Real(n.parse::<f64>().unwrap_or(0.0))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Implements CAST(value AS type), where the type has already been reduced to
+// its affinity.
+pub fn cast(value: SqlValue, affinity: Affinity) -> SqlValue {
+    if value.is_null() {
+        return value;
+    }
+    match affinity {
+        Affinity::Integer => match &value {
+            SqlValue::Real(f) => SqlValue::Integer(real_to_i64(*f)),
+            SqlValue::Text(s) => SqlValue::Integer(parse_integer_prefix(s)),
+            SqlValue::Blob(b) => {
+                SqlValue::Integer(parse_integer_prefix(&String::from_utf8_lossy(b)))
+            }
+            _ => value,
+        },
+        Affinity::Real => SqlValue::Real(value.to_f64().unwrap()),
+        Affinity::Numeric => match &value {
+            SqlValue::Text(_) | SqlValue::Blob(_) => match value.to_numeric() {
+                SqlValue::Real(f) if f.fract() == 0.0 && f.abs() < 9.2e18 => {
+                    SqlValue::Integer(f as i64)
+                }
+                v => v,
+            },
+            _ => value,
+        },
+        Affinity::Text => match value {
+            SqlValue::Blob(b) => SqlValue::Text(String::from_utf8_lossy(&b).into_owned()),
+            v => SqlValue::Text(v.to_text().unwrap()),
+        },
+        Affinity::Blob => match value {
+            SqlValue::Blob(b) => SqlValue::Blob(b),
+            v => SqlValue::Blob(v.to_text().unwrap().into_bytes()),
+        },
+    }
+}
+
+// Saturating conversion used by CAST(real AS INTEGER).
+fn real_to_i64(f: f64) -> i64 {
+    if f.is_nan() {
+        0
+    } else if f >= 9223372036854775807.0 {
+        i64::MAX
+    } else if f <= -9223372036854775808.0 {
+        i64::MIN
+    } else {
+        f as i64
+    }
+}
+
+// CAST(text AS INTEGER) only looks at the leading integer, so '3.5e2' is 3.
+fn parse_integer_prefix(s: &str) -> i64 {
+    let s = s.trim_start();
+    let bytes = s.as_bytes();
+    let mut end = 0;
+    if end < bytes.len() && (bytes[end] == b'+' || bytes[end] == b'-') {
+        end += 1;
+    }
+    let digits_start = end;
+    while end < bytes.len() && bytes[end].is_ascii_digit() {
+        end += 1;
+    }
+    if end == digits_start {
+        return 0;
+    }
+    match s[..end].parse::<i64>() {
+        Ok(i) => i,
+        Err(_) if bytes[0] == b'-' => i64::MIN,
+        Err(_) => i64::MAX,
+    }
+}
+
+fn unary(op: &UnaryOperator, expr: &Expr, scope: &dyn Scope) -> Result<SqlValue, Error> {
+    // -9223372036854775808 is an integer even though its absolute value is not
+    if let (UnaryOperator::Minus, Expr::Value(ast::Value::Number(n, _))) = (op, expr) {
+        if n == "9223372036854775808" {
+            return Ok(SqlValue::Integer(i64::MIN));
+        }
+    }
+    let value = evaluate(expr, scope)?;
+    match op {
+        UnaryOperator::Plus => Ok(value),
+        UnaryOperator::Minus => Ok(match value.to_numeric() {
+            SqlValue::Null => SqlValue::Null,
+            SqlValue::Integer(i) => match i.checked_neg() {
+                Some(i) => SqlValue::Integer(i),
+                None => SqlValue::Real(-(i as f64)),
+            },
+            SqlValue::Real(f) => SqlValue::Real(-f),
+            _ => unreachable!(),
+        }),
+        UnaryOperator::Not => Ok(from_truth(truth(&value).map(|b| !b))),
+        UnaryOperator::PGBitwiseNot => Ok(match integer_operand(&value) {
+            Some(i) => SqlValue::Integer(!i),
+            None => SqlValue::Null,
+        }),
+        _ => Err(unsupported(format!("operator {}", op))),
+    }
+}
+
+fn and(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+fn or(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None,
+    }
+}
+
+fn binary(
+    left: &Expr,
+    op: &BinaryOperator,
+    right: &Expr,
+    scope: &dyn Scope,
+) -> Result<SqlValue, Error> {
+    match op {
+        BinaryOperator::And => {
+            let l = truth(&evaluate(left, scope)?);
+            if l == Some(false) {
+                return Ok(SqlValue::from_bool(false));
+            }
+            Ok(from_truth(and(l, truth(&evaluate(right, scope)?))))
+        }
+        BinaryOperator::Or => {
+            let l = truth(&evaluate(left, scope)?);
+            if l == Some(true) {
+                return Ok(SqlValue::from_bool(true));
+            }
+            Ok(from_truth(or(l, truth(&evaluate(right, scope)?))))
+        }
+        BinaryOperator::Eq
+        | BinaryOperator::NotEq
+        | BinaryOperator::Lt
+        | BinaryOperator::LtEq
+        | BinaryOperator::Gt
+        | BinaryOperator::GtEq => {
+            let l = evaluate_operand(left, scope)?;
+            let r = evaluate_operand(right, scope)?;
+            Ok(from_truth(compare(&l, &r).map(|o| match op {
+                BinaryOperator::Eq => o == Ordering::Equal,
+                BinaryOperator::NotEq => o != Ordering::Equal,
+                BinaryOperator::Lt => o == Ordering::Less,
+                BinaryOperator::LtEq => o != Ordering::Greater,
+                BinaryOperator::Gt => o == Ordering::Greater,
+                _ => o != Ordering::Less,
+            })))
+        }
+        BinaryOperator::Like | BinaryOperator::NotLike => {
+            let string = evaluate(left, scope)?;
+            let matched = match sql_parser::pattern(right) {
+                Pattern::Glob(pattern) => {
+                    let pattern = evaluate(pattern, scope)?;
+                    match (pattern.to_text(), string.to_text()) {
+                        (Some(pattern), Some(string)) => Some(glob(&pattern, &string)),
+                        _ => None,
+                    }
+                }
+                Pattern::Like(pattern, escape) => {
+                    let pattern = evaluate(pattern, scope)?;
+                    let escape = match escape {
+                        Some(escape) => Some(evaluate(escape, scope)?),
+                        None => None,
+                    };
+                    match (pattern.to_text(), string.to_text(), escape) {
+                        (_, _, Some(SqlValue::Null)) => None,
+                        (Some(pattern), Some(string), escape) => {
+                            let escape = escape.as_ref().map(escape_character).transpose()?;
+                            Some(like(&pattern, &string, escape))
+                        }
+                        _ => None,
+                    }
+                }
+            };
+            let negated = *op == BinaryOperator::NotLike;
+            Ok(from_truth(matched.map(|m| m != negated)))
+        }
+        BinaryOperator::StringConcat => {
+            let l = evaluate(left, scope)?;
+            let r = evaluate(right, scope)?;
+            Ok(match (l.to_text(), r.to_text()) {
+                (Some(l), Some(r)) => SqlValue::Text(l + &r),
+                _ => SqlValue::Null,
+            })
+        }
+        BinaryOperator::Plus
+        | BinaryOperator::Minus
+        | BinaryOperator::Multiply
+        | BinaryOperator::Divide
+        | BinaryOperator::Modulus => {
+            let l = evaluate(left, scope)?;
+            let r = evaluate(right, scope)?;
+            Ok(arithmetic(op, &l, &r))
+        }
+        BinaryOperator::BitwiseAnd | BinaryOperator::BitwiseOr => {
+            let l = integer_operand(&evaluate(left, scope)?);
+            let r = integer_operand(&evaluate(right, scope)?);
+            Ok(match (l, r) {
+                (Some(l), Some(r)) if *op == BinaryOperator::BitwiseAnd => SqlValue::Integer(l & r),
+                (Some(l), Some(r)) => SqlValue::Integer(l | r),
+                _ => SqlValue::Null,
+            })
+        }
+        _ => Err(unsupported(format!("operator {}", op))),
+    }
+}
+
+fn integer_operand(value: &SqlValue) -> Option<i64> {
+    match value.to_numeric() {
+        SqlValue::Integer(i) => Some(i),
+        SqlValue::Real(f) => Some(real_to_i64(f)),
+        _ => None,
+    }
+}
+
+pub fn arithmetic(op: &BinaryOperator, l: &SqlValue, r: &SqlValue) -> SqlValue {
+    if l.is_null() || r.is_null() {
+        return SqlValue::Null;
+    }
+    let (l, r) = (l.to_numeric(), r.to_numeric());
+    if let (Some(a), Some(b)) = (l.as_i64(), r.as_i64()) {
+        let result = match op {
+            BinaryOperator::Plus => a.checked_add(b),
+            BinaryOperator::Minus => a.checked_sub(b),
+            BinaryOperator::Multiply => a.checked_mul(b),
+            BinaryOperator::Divide => {
+                if b == 0 {
+                    return SqlValue::Null;
+                }
+                a.checked_div(b)
+            }
+            _ => {
+                if b == 0 {
+                    return SqlValue::Null;
+                }
+                Some(a.checked_rem(b).unwrap_or(0))
+            }
+        };
+        if let Some(i) = result {
+            return SqlValue::Integer(i);
+        }
+        // integer overflow falls back to floating point
+    }
+
+    let (a, b) = (l.to_f64().unwrap(), r.to_f64().unwrap());
+    let result = match op {
+        BinaryOperator::Plus => a + b,
+        BinaryOperator::Minus => a - b,
+        BinaryOperator::Multiply => a * b,
+        BinaryOperator::Divide => {
+            if b == 0.0 {
+                return SqlValue::Null;
+            }
+            a / b
+        }
+        _ => {
+            // the remainder of reals is taken on their integer parts
+            let (a, b) = (real_to_i64(a), real_to_i64(b));
+            if b == 0 {
+                return SqlValue::Null;
+            }
+            a.checked_rem(b).unwrap_or(0) as f64
+        }
+    };
+    if result.is_nan() {
+        SqlValue::Null
+    } else {
+        SqlValue::Real(result)
+    }
+}
+
+// Compares two operands after applying SQLite's affinity rules for
+// comparisons. Returns None if either side is NULL.
+pub fn compare(left: &Operand, right: &Operand) -> Option<Ordering> {
+    if left.value.is_null() || right.value.is_null() {
+        return None;
+    }
+    let (l, r) = comparison_values(left, right);
+    Some(compare_values(&l, &r, comparison_collation(left, right)))
+}
+
+fn comparison_values(left: &Operand, right: &Operand) -> (SqlValue, SqlValue) {
+    let (mut l, mut r) = (left.value.clone(), right.value.clone());
+    let is_text_or_none = |a: Affinity| a == Affinity::Text || a == Affinity::Blob;
+    if left.affinity.is_numeric() && is_text_or_none(right.affinity) {
+        r = r.apply_affinity(Affinity::Numeric);
+    } else if right.affinity.is_numeric() && is_text_or_none(left.affinity) {
+        l = l.apply_affinity(Affinity::Numeric);
+    } else if left.affinity == Affinity::Text && right.affinity == Affinity::Blob {
+        r = r.apply_affinity(Affinity::Text);
+    } else if right.affinity == Affinity::Text && left.affinity == Affinity::Blob {
+        l = l.apply_affinity(Affinity::Text);
+    }
+    (l, r)
+}
+
+// An explicit COLLATE wins over a column's collation, and the left operand
+// wins over the right one.
+fn comparison_collation<'o>(left: &'o Operand, right: &'o Operand) -> &'o Collation {
+    match (&left.collation, &right.collation) {
+        (Some((c, true)), _) => c,
+        (_, Some((c, true))) => c,
+        (Some((c, false)), _) => c,
+        (_, Some((c, false))) => c,
+        _ => &Collation::Binary,
+    }
+}
+
+// Orders two values following https://www.sqlite.org/datatype3.html#comparisons:
+// NULL < INTEGER and REAL < TEXT < BLOB, TEXT ordered by the collation.
+pub fn compare_values(a: &SqlValue, b: &SqlValue, collation: &Collation) -> Ordering {
+    match (a, b) {
+        (SqlValue::Text(a), SqlValue::Text(b)) => collation.compare(a, b),
+        _ => a.compare(b),
+    }
+}
+
+// Values compared with a collation each, such as the GROUP BY values of a
+// group, or the rows a DISTINCT keeps.
+#[derive(Debug, Clone)]
+pub struct GroupKey {
+    values: Vec<SqlValue>,
+    collations: Rc<[Collation]>,
+}
+
+impl GroupKey {
+    pub fn new(values: Vec<SqlValue>, collations: Rc<[Collation]>) -> GroupKey {
+        GroupKey { values, collations }
+    }
+}
+
+impl Ord for GroupKey {
+    fn cmp(&self, other: &GroupKey) -> Ordering {
+        for ((a, b), collation) in self
+            .values
+            .iter()
+            .zip(&other.values)
+            .zip(self.collations.iter())
+        {
+            match compare_values(a, b, collation) {
+                Ordering::Equal => continue,
+                o => return o,
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+impl PartialOrd for GroupKey {
+    fn partial_cmp(&self, other: &GroupKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for GroupKey {
+    fn eq(&self, other: &GroupKey) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for GroupKey {}
+
+fn in_list(value: &Operand, list: &[Operand]) -> Option<bool> {
+    if value.value.is_null() {
+        return if list.is_empty() { Some(false) } else { None };
+    }
+    let mut saw_null = false;
+    for candidate in list {
+        match compare(value, candidate) {
+            Some(Ordering::Equal) => return Some(true),
+            None => saw_null = true,
+            _ => (),
+        }
+    }
+    if saw_null {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+// SQL LIKE: `%` matches any sequence, `_` any single character, and ASCII
+// letters match regardless of case.
+// The character an ESCAPE clause gives, which has to be just one.
+pub fn escape_character(escape: &SqlValue) -> Result<char, Error> {
+    let text = escape.to_text().unwrap_or_default();
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "ESCAPE expression must be a single character",
+        )),
+    }
+}
+
+pub fn like(pattern: &str, string: &str, escape: Option<char>) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let string: Vec<char> = string.chars().collect();
+    like_chars(&pattern, &string, escape)
+}
+
+fn like_chars(pattern: &[char], string: &[char], escape: Option<char>) -> bool {
+    let mut p = 0;
+    let mut s = 0;
+    while p < pattern.len() {
+        let c = pattern[p];
+        if Some(c) == escape && p + 1 < pattern.len() {
+            if s >= string.len() || !string[s].eq_ignore_ascii_case(&pattern[p + 1]) {
+                return false;
+            }
+            p += 2;
+            s += 1;
+            continue;
+        }
+        match c {
+            '%' => {
+                while p < pattern.len() && pattern[p] == '%' {
+                    p += 1;
+                }
+                if p == pattern.len() {
+                    return true;
+                }
+                return (s..=string.len()).any(|i| like_chars(&pattern[p..], &string[i..], escape));
+            }
+            '_' => {
+                if s >= string.len() {
+                    return false;
+                }
+            }
+            c => {
+                if s >= string.len() || !string[s].eq_ignore_ascii_case(&c) {
+                    return false;
+                }
+            }
+        }
+        p += 1;
+        s += 1;
+    }
+    s == string.len()
+}
+
+// Unix-style GLOB: `*`, `?` and `[...]` character classes, case sensitive.
+pub fn glob(pattern: &str, string: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let string: Vec<char> = string.chars().collect();
+    glob_chars(&pattern, &string)
+}
+
+fn glob_chars(pattern: &[char], string: &[char]) -> bool {
+    let mut p = 0;
+    let mut s = 0;
+    while p < pattern.len() {
+        match pattern[p] {
+            '*' => {
+                while p < pattern.len() && pattern[p] == '*' {
+                    p += 1;
+                }
+                if p == pattern.len() {
+                    return true;
+                }
+                return (s..=string.len()).any(|i| glob_chars(&pattern[p..], &string[i..]));
+            }
+            '?' => {
+                if s >= string.len() {
+                    return false;
+                }
+                p += 1;
+            }
+            '[' => {
+                if s >= string.len() {
+                    return false;
+                }
+                match glob_class(&pattern[p + 1..], string[s]) {
+                    Some((true, length)) => p += length + 1,
+                    _ => return false,
+                }
+            }
+            c => {
+                if s >= string.len() || string[s] != c {
+                    return false;
+                }
+                p += 1;
+            }
+        }
+        s += 1;
+    }
+    s == string.len()
+}
+
+// Matches `c` against the character class that follows a `[`. Returns
+// whether it matched and how many pattern characters the class used.
+fn glob_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = class.first() == Some(&'^');
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() {
+        let start = class[i];
+        if start == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        if i + 2 < class.len() && class[i + 1] == '-' && class[i + 2] != ']' {
+            if start <= c && c <= class[i + 2] {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if start == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    None
+}
+
+fn call_function(function: &ast::Function, scope: &dyn Scope) -> Result<Operand, Error> {
+    if function.over.is_some() || window::is_window_function(function) {
+        let value = scope.window(function).unwrap_or_else(|| {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("misuse of window function {}()", function.name),
+            ))
+        })?;
+        let json = aggregate::is_json(function) && matches!(value, SqlValue::Text(_));
+        return Ok(Operand {
+            json,
+            ..Operand::new(value)
+        });
+    }
+    let functions = scope.functions();
+    let is_aggregate = match functions {
+        Some(functions) => functions.is_aggregate(function),
+        None => aggregate::is_aggregate(function),
+    };
+    if is_aggregate {
+        let value = scope.aggregate(function).unwrap_or_else(|| {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("misuse of aggregate function {}()", function.name),
+            ))
+        })?;
+        let json = aggregate::is_json(function) && matches!(value, SqlValue::Text(_));
+        return Ok(Operand {
+            json,
+            ..Operand::new(value)
+        });
+    }
+    let mut args = Vec::with_capacity(function.args.len());
+    for arg in &function.args {
+        args.push(match arg {
+            FunctionArg::Unnamed(expr) => evaluate_operand(expr, scope)?,
+            FunctionArg::Named { arg, .. } => evaluate_operand(arg, scope)?,
+        });
+    }
+    call_scalar(function, args, functions)
+}
+
+// Calls a scalar function, one the application registered or a built-in
+// one, with its arguments.
+pub fn call_scalar(
+    function: &ast::Function,
+    args: Vec<Operand>,
+    functions: Option<&Functions>,
+) -> Result<Operand, Error> {
+    let name = function.name.to_string().to_ascii_lowercase();
+    if let Some(Function::Scalar(f, _)) = functions.and_then(|f| f.get(&name, args.len())) {
+        let args: Vec<SqlValue> = args.into_iter().map(|a| a.value).collect();
+        return Ok(Operand::new(f(&args)?));
+    }
+    if let Some(arity) = json::arity(&name) {
+        if !arity.contains(&args.len()) {
+            return Err(scalar::wrong_number_of_arguments(&name));
+        }
+        let (value, json) = json::call(&name, &args)?;
+        return Ok(Operand {
+            json,
+            ..Operand::new(value)
+        });
+    }
+    // min() and max() of several values compare them with the collation
+    // of the first argument that has one, as SQLite does
+    let collation = args.iter().find_map(|a| a.collation.clone());
+    let args: Vec<SqlValue> = args.into_iter().map(|a| a.value).collect();
+    if (name == "min" || name == "max") && args.len() > 1 {
+        let collation = collation.map_or(Collation::Binary, |(collation, _)| collation);
+        return Ok(Operand::new(scalar::extreme(&name, &args, &collation)));
+    }
+    let value = scalar::call(&name, &args).unwrap_or_else(|| {
+        if functions.is_some_and(|f| f.contains(&name)) {
+            return Err(scalar::wrong_number_of_arguments(&name));
+        }
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("no such function: {}", function.name),
+        ))
+    })?;
+    Ok(Operand::new(value))
+}
+
+// Calls `visit` on `expr` and its subexpressions, skipping the children of
+// expressions for which `visit` returns false. Subqueries are not entered.
+pub fn walk(expr: &Expr, visit: &mut dyn FnMut(&Expr) -> bool) {
+    if !visit(expr) {
+        return;
+    }
+    match expr {
+        Expr::IsNull(e)
+        | Expr::IsNotNull(e)
+        | Expr::Nested(e)
+        | Expr::UnaryOp { expr: e, .. }
+        | Expr::Cast { expr: e, .. }
+        | Expr::TryCast { expr: e, .. }
+        | Expr::Extract { expr: e, .. }
+        | Expr::Collate { expr: e, .. }
+        | Expr::InSubquery { expr: e, .. } => walk(e, visit),
+        Expr::InList { expr, list, .. } => {
+            walk(expr, visit);
+            list.iter().for_each(|e| walk(e, visit));
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            walk(expr, visit);
+            walk(low, visit);
+            walk(high, visit);
+        }
+        Expr::BinaryOp { left, right, .. } => {
+            walk(left, visit);
+            walk(right, visit);
+        }
+        Expr::Substring {
+            expr,
+            substring_from,
+            substring_for,
+        } => {
+            walk(expr, visit);
+            substring_from.iter().for_each(|e| walk(e, visit));
+            substring_for.iter().for_each(|e| walk(e, visit));
+        }
+        Expr::MapAccess { column, .. } => walk(column, visit),
+        Expr::Function(function) => {
+            for arg in &function.args {
+                match arg {
+                    FunctionArg::Unnamed(e) | FunctionArg::Named { arg: e, .. } => walk(e, visit),
+                }
+            }
+            if let Some(window) = &function.over {
+                window.partition_by.iter().for_each(|e| walk(e, visit));
+                window.order_by.iter().for_each(|o| walk(&o.expr, visit));
+            }
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            operand.iter().for_each(|e| walk(e, visit));
+            conditions.iter().for_each(|e| walk(e, visit));
+            results.iter().for_each(|e| walk(e, visit));
+            else_result.iter().for_each(|e| walk(e, visit));
+        }
+        _ => (),
+    }
+}
+
+// Like `walk`, but lets `visit` change the expressions it is called on.
+pub fn walk_mut(expr: &mut Expr, visit: &mut dyn FnMut(&mut Expr) -> bool) {
+    if !visit(expr) {
+        return;
+    }
+    match expr {
+        Expr::IsNull(e)
+        | Expr::IsNotNull(e)
+        | Expr::Nested(e)
+        | Expr::UnaryOp { expr: e, .. }
+        | Expr::Cast { expr: e, .. }
+        | Expr::TryCast { expr: e, .. }
+        | Expr::Extract { expr: e, .. }
+        | Expr::Collate { expr: e, .. }
+        | Expr::InSubquery { expr: e, .. } => walk_mut(e, visit),
+        Expr::InList { expr, list, .. } => {
+            walk_mut(expr, visit);
+            list.iter_mut().for_each(|e| walk_mut(e, visit));
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            walk_mut(expr, visit);
+            walk_mut(low, visit);
+            walk_mut(high, visit);
+        }
+        Expr::BinaryOp { left, right, .. } => {
+            walk_mut(left, visit);
+            walk_mut(right, visit);
+        }
+        Expr::Substring {
+            expr,
+            substring_from,
+            substring_for,
+        } => {
+            walk_mut(expr, visit);
+            substring_from.iter_mut().for_each(|e| walk_mut(e, visit));
+            substring_for.iter_mut().for_each(|e| walk_mut(e, visit));
+        }
+        Expr::MapAccess { column, .. } => walk_mut(column, visit),
+        Expr::Function(function) => {
+            for arg in &mut function.args {
+                match arg {
+                    FunctionArg::Unnamed(e) | FunctionArg::Named { arg: e, .. } => {
+                        walk_mut(e, visit)
+                    }
+                }
+            }
+            if let Some(window) = &mut function.over {
+                window
+                    .partition_by
+                    .iter_mut()
+                    .for_each(|e| walk_mut(e, visit));
+                window
+                    .order_by
+                    .iter_mut()
+                    .for_each(|o| walk_mut(&mut o.expr, visit));
+            }
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            operand.iter_mut().for_each(|e| walk_mut(e, visit));
+            conditions.iter_mut().for_each(|e| walk_mut(e, visit));
+            results.iter_mut().for_each(|e| walk_mut(e, visit));
+            else_result.iter_mut().for_each(|e| walk_mut(e, visit));
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser;
+    use sqlparser::ast::{SelectItem, SetExpr, Statement};
+
+    struct TestScope;
+
+    impl Scope for TestScope {
+        fn column(&self, name: &[Ident]) -> Result<Operand, Error> {
+            match name.last().unwrap().value.as_str() {
+                "int_col" => Ok(Operand::column(
+                    SqlValue::Integer(5),
+                    Affinity::Integer,
+                    None,
+                )),
+                "text_col" => Ok(Operand::column(
+                    SqlValue::Text("5".to_string()),
+                    Affinity::Text,
+                    None,
+                )),
+                "nocase_col" => Ok(Operand::column(
+                    SqlValue::Text("Abc".to_string()),
+                    Affinity::Text,
+                    Some(Collation::NoCase),
+                )),
+                "null_col" => Ok(Operand::column(SqlValue::Null, Affinity::Numeric, None)),
+                _ => Err(no_such_column(name)),
+            }
+        }
+    }
+
+    fn eval(sql: &str) -> SqlValue {
+        let statement = &sql_parser::parse_sql(&format!("SELECT {}", sql)).unwrap()[0];
+        let expr = match statement {
+            Statement::Query(query) => match &query.body {
+                SetExpr::Select(select) => match &select.projection[0] {
+                    SelectItem::UnnamedExpr(expr) => expr.clone(),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        evaluate(&expr, &TestScope).unwrap()
+    }
+
+    fn int(sql: &str) -> Option<i64> {
+        eval(sql).as_i64()
+    }
+
+    #[test]
+    fn three_valued_logic() {
+        assert!(eval("NULL AND 1").is_null());
+        assert_eq!(int("NULL AND 0"), Some(0));
+        assert_eq!(int("NULL OR 1"), Some(1));
+        assert!(eval("NOT NULL").is_null());
+        assert!(eval("NULL = NULL").is_null());
+        assert_eq!(int("null_col IS NULL"), Some(1));
+        assert!(eval("3 IN (1, NULL)").is_null());
+        assert_eq!(int("3 IN (1, 3)"), Some(1));
+        assert!(eval("1 BETWEEN 0 AND NULL").is_null());
+        assert_eq!(int("5 NOT BETWEEN 6 AND NULL"), Some(1));
+    }
+
+    #[test]
+    fn cross_type_comparisons() {
+        assert_eq!(int("2 < '1'"), Some(1));
+        assert_eq!(int("'5' = 5"), Some(0));
+        assert_eq!(int("'a' < x'00'"), Some(1));
+        assert_eq!(int("1 = 1.0"), Some(1));
+        // the column's affinity converts the other operand
+        assert_eq!(int("int_col = '5'"), Some(1));
+        assert_eq!(int("text_col = 5"), Some(1));
+        assert_eq!(int("int_col = text_col"), Some(1));
+    }
+
+    #[test]
+    fn collations() {
+        assert_eq!(int("nocase_col = 'ABC'"), Some(1));
+        assert_eq!(int("nocase_col = 'ABC' COLLATE BINARY"), Some(0));
+        assert_eq!(int("'a  ' = 'a' COLLATE RTRIM"), Some(1));
+    }
+
+    #[test]
+    fn registered_collations() {
+        let mut collations = Collations::new();
+        collations.register("reverse", |a: &str, b: &str| b.cmp(a));
+        let reverse = collations.get("REVERSE").unwrap();
+        assert_eq!(reverse.name(), "reverse");
+        assert_eq!(reverse.compare("a", "b"), Ordering::Greater);
+        assert_eq!(reverse, collations.get("reverse").unwrap());
+        assert_eq!(collations.get("nocase").unwrap(), Collation::NoCase);
+        assert_eq!(
+            collations.get("icu").unwrap_err().to_string(),
+            "no such collation sequence: icu"
+        );
+
+        // registering a name again replaces the collation, and a registered
+        // collation takes the place of a built-in one
+        collations.register("reverse", |a: &str, b: &str| a.cmp(b));
+        assert_ne!(reverse, collations.get("reverse").unwrap());
+        collations.register("nocase", |a: &str, b: &str| a.len().cmp(&b.len()));
+        let nocase = collations.get("NOCASE").unwrap();
+        assert_eq!(nocase.compare("abc", "B"), Ordering::Greater);
+    }
+
+    #[test]
+    fn arithmetic_follows_sqlite() {
+        assert_eq!(int("5 / 2"), Some(2));
+        assert_eq!(eval("5.0 / 2").as_f64(), Some(2.5));
+        assert!(eval("1 / 0").is_null());
+        assert_eq!(eval("5.5 % 2").as_f64(), Some(1.0));
+        assert_eq!(int("-7 % 3"), Some(-1));
+        assert_eq!(int("'12abc' + 1"), Some(13));
+        assert_eq!(int("int_col * 2"), Some(10));
+        assert_eq!(
+            eval("9223372036854775807 + 1").as_f64(),
+            Some(9223372036854775808.0)
+        );
+        assert_eq!(int("-9223372036854775808"), Some(i64::MIN));
+        assert_eq!(eval("'x' || 1.5").as_str(), Some("x1.5"));
+        assert!(eval("'x' || NULL").is_null());
+    }
+
+    #[test]
+    fn cast_and_case() {
+        assert_eq!(int("CAST('3.5e2xyz' AS INTEGER)"), Some(3));
+        assert_eq!(int("CAST(-9.9 AS INTEGER)"), Some(-9));
+        assert_eq!(int("CAST('1e3' AS NUMERIC)"), Some(1000));
+        assert_eq!(eval("CAST(3 AS REAL)").as_f64(), Some(3.0));
+        assert_eq!(eval("CAST(0.1 AS TEXT)").as_str(), Some("0.1"));
+        assert!(matches!(eval("CAST('ab' AS BLOB)"), SqlValue::Blob(_)));
+        assert_eq!(
+            eval("CASE 1 WHEN 1.0 THEN 'y' ELSE 'n' END").as_str(),
+            Some("y")
+        );
+        assert_eq!(eval("CASE WHEN NULL THEN 1 ELSE 2 END").as_i64(), Some(2));
+        assert!(eval("CASE 2 WHEN 1 THEN 1 END").is_null());
+        assert_eq!(int("COALESCE(NULL, null_col, 7)"), Some(7));
+    }
+
+    #[test]
+    fn like_and_glob() {
+        assert_eq!(int("'ABC' LIKE 'a%'"), Some(1));
+        assert_eq!(int("'ABC' NOT LIKE 'a_c'"), Some(0));
+        assert_eq!(int("'a%c' LIKE 'a%'"), Some(1));
+        assert_eq!(int("like('a\\%', 'a%', '\\')"), Some(1));
+        assert_eq!(int("like('a\\%', 'ab', '\\')"), Some(0));
+        assert_eq!(int("glob('A*', 'ABC')"), Some(1));
+        assert_eq!(int("glob('a*', 'ABC')"), Some(0));
+        assert_eq!(int("glob('[A-C]?[^x]', 'BQC')"), Some(1));
+        assert_eq!(int("glob('[]]', ']')"), Some(1));
+
+        // the operator forms of the same functions
+        assert_eq!(int("'ABC' GLOB 'A*'"), Some(1));
+        assert_eq!(int("'ABC' GLOB 'a' || '*'"), Some(0));
+        assert_eq!(int("'ABC' NOT GLOB 'a*'"), Some(1));
+        assert_eq!(int("NOT 'ABC' GLOB 'A?C' AND 1"), Some(0));
+        assert!(eval("null_col GLOB '*'").is_null());
+        assert_eq!(int("'a%' LIKE 'a\\%' ESCAPE '\\'"), Some(1));
+        assert_eq!(int("'ab' LIKE 'a\\' || '%' ESCAPE '\\'"), Some(0));
+        assert_eq!(int("'ab' NOT LIKE 'a!%' ESCAPE '!' AND 1"), Some(1));
+        assert!(eval("'a' LIKE 'a' ESCAPE NULL").is_null());
+        assert_eq!(int("'ABC' GLOB 'A*' = 1"), Some(1));
+        assert_eq!(int("glob('A*', 'ABC') + 1"), Some(2));
+    }
+}
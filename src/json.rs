@@ -0,0 +1,2494 @@
+use crate::expr::Operand;
+use crate::scalar;
+use crate::value::{format_real, SqlValue};
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+use std::ops::RangeInclusive;
+
+// The JSON functions, a port of SQLite's json.c so that results match SQLite
+// exactly. See https://www.sqlite.org/json1.html.
+//
+// Every function works on JSONB, SQLite's binary encoding of JSON, which is
+// also what the jsonb_*() variants return and what applications store in BLOB
+// columns. Each element starts with a header whose low nibble is the type and
+// whose high nibble is the payload size if at most 11, or else 12, 13, 14 or
+// 15 for a payload size in the following 1, 2, 4 or 8 bytes, big-endian.
+// Arrays and objects hold their elements as their payload, objects
+// alternating labels and values. Text and numbers hold the text of their JSON
+// literal, so JSON5 extensions are kept as they were written and only
+// converted when the JSONB is rendered as text.
+
+const NULL: u8 = 0;
+const TRUE: u8 = 1;
+const FALSE: u8 = 2;
+const INT: u8 = 3;
+// a hexadecimal integer
+const INT5: u8 = 4;
+const FLOAT: u8 = 5;
+// a real missing a digit before or after its '.'
+const FLOAT5: u8 = 6;
+// text needing no escapes
+const TEXT: u8 = 7;
+// text with JSON escapes
+const TEXTJ: u8 = 8;
+// text with JSON5 escapes or unescaped control characters
+const TEXT5: u8 = 9;
+// text that must be escaped when rendered
+const TEXTRAW: u8 = 10;
+const ARRAY: u8 = 11;
+const OBJECT: u8 = 12;
+
+const TYPE_NAMES: [&str; 16] = [
+    "null", "true", "false", "integer", "integer", "real", "real", "text", "text", "text", "text",
+    "array", "object", "", "", "",
+];
+
+// The deepest nesting of arrays and objects accepted.
+const MAX_DEPTH: usize = 1000;
+
+// What an invalid escape sequence decodes to.
+const INVALID_CHAR: u32 = 0x99999;
+
+fn malformed() -> Error {
+    Error::new(ErrorKind::InvalidData, "malformed JSON")
+}
+
+fn bad_path(path: &[u8]) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+            "bad JSON path: '{}'",
+            String::from_utf8_lossy(path).replace('\'', "''")
+        ),
+    )
+}
+
+fn cannot_hold_blob() -> Error {
+    Error::new(ErrorKind::InvalidInput, "JSON cannot hold BLOB values")
+}
+
+fn at(z: &[u8], i: usize) -> u8 {
+    z.get(i).copied().unwrap_or(0)
+}
+
+fn tail(z: &[u8], i: usize) -> &[u8] {
+    z.get(i..).unwrap_or(&[])
+}
+
+fn lossy(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+// Characters that may appear unescaped in a JSON string of either quote.
+fn is_ok(c: u8) -> bool {
+    c >= 0x20 && c != b'"' && c != b'\\' && c != b'\''
+}
+
+fn is_space(c: u8) -> bool {
+    matches!(c, b'\t' | b'\n' | b'\r' | b' ')
+}
+
+fn spaces(z: &[u8]) -> usize {
+    z.iter().take_while(|c| is_space(**c)).count()
+}
+
+// Characters that may start and continue a JSON5 identifier.
+fn is_id1(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_' || c == b'$' || c >= 0x80
+}
+
+fn is_id2(c: u8) -> bool {
+    is_id1(c) || c.is_ascii_digit()
+}
+
+fn starts_with_ignore_case(z: &[u8], prefix: &[u8]) -> bool {
+    z.len() >= prefix.len() && z[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+fn hex_to_int(c: u8) -> u32 {
+    (c as char).to_digit(16).unwrap_or(0)
+}
+
+fn is_2hex(z: &[u8], i: usize) -> bool {
+    at(z, i).is_ascii_hexdigit() && at(z, i + 1).is_ascii_hexdigit()
+}
+
+fn is_4hex(z: &[u8], i: usize) -> bool {
+    is_2hex(z, i) && is_2hex(z, i + 2)
+}
+
+fn hex4(z: &[u8]) -> u32 {
+    z[..4].iter().fold(0, |v, c| v << 4 | hex_to_int(*c))
+}
+
+// The number of bytes of JSON5 whitespace and comments at the start of `z`.
+fn json5_whitespace(z: &[u8]) -> usize {
+    let mut n = 0;
+    loop {
+        match at(z, n) {
+            0x09 | 0x0a | 0x0b | 0x0c | 0x0d | 0x20 => n += 1,
+            b'/' if at(z, n + 1) == b'*' && at(z, n + 2) != 0 => {
+                let mut j = n + 3;
+                while at(z, j) != b'/' || z[j - 1] != b'*' {
+                    if at(z, j) == 0 {
+                        return n;
+                    }
+                    j += 1;
+                }
+                n = j + 1;
+            }
+            b'/' if at(z, n + 1) == b'/' => {
+                let mut j = n + 2;
+                while at(z, j) != 0 {
+                    let c = z[j];
+                    if c == b'\n' || c == b'\r' {
+                        break;
+                    }
+                    if c == 0xe2 && at(z, j + 1) == 0x80 && matches!(at(z, j + 2), 0xa8 | 0xa9) {
+                        j += 2;
+                        break;
+                    }
+                    j += 1;
+                }
+                n = j;
+                if at(z, n) != 0 {
+                    n += 1;
+                }
+            }
+            0xc2 if at(z, n + 1) == 0xa0 => n += 2,
+            0xe1 if at(z, n + 1) == 0x9a && at(z, n + 2) == 0x80 => n += 3,
+            0xe2 if at(z, n + 1) == 0x80 => {
+                let c = at(z, n + 2);
+                if c < 0x80 || !(c <= 0x8a || c == 0xa8 || c == 0xa9 || c == 0xaf) {
+                    return n;
+                }
+                n += 3;
+            }
+            0xe2 if at(z, n + 1) == 0x81 && at(z, n + 2) == 0x9f => n += 3,
+            0xe3 if at(z, n + 1) == 0x80 && at(z, n + 2) == 0x80 => n += 3,
+            0xef if at(z, n + 1) == 0xbb && at(z, n + 2) == 0xbf => n += 3,
+            _ => return n,
+        }
+    }
+}
+
+// sqlite3Utf8ReadLimited(): the code point at the start of `z` and its
+// length, reading at most 4 bytes.
+fn utf8_read(z: &[u8]) -> (usize, u32) {
+    let c = z[0];
+    if c < 0xc0 {
+        return (1, c as u32);
+    }
+    let mut v = match c {
+        0xc0..=0xdf => c - 0xc0,
+        0xe0..=0xef => c - 0xe0,
+        0xf0..=0xf7 => c - 0xf0,
+        0xf8..=0xfb => c - 0xf8,
+        0xfc..=0xfd => c - 0xfc,
+        _ => 0,
+    } as u32;
+    let mut i = 1;
+    while i < z.len().min(4) && z[i] & 0xc0 == 0x80 {
+        v = (v << 6) + (z[i] & 0x3f) as u32;
+        i += 1;
+    }
+    (i, v)
+}
+
+// The length of the JSON5 line continuations at the start of `z`.
+fn bytes_to_bypass(z: &[u8]) -> usize {
+    let n = z.len();
+    let mut i = 0;
+    while i + 1 < n {
+        if z[i] != b'\\' {
+            return i;
+        }
+        if z[i + 1] == b'\n' {
+            i += 2;
+        } else if z[i + 1] == b'\r' {
+            i += if i + 2 < n && z[i + 2] == b'\n' { 3 } else { 2 };
+        } else if z[i + 1] == 0xe2
+            && i + 3 < n
+            && z[i + 2] == 0x80
+            && matches!(z[i + 3], 0xa8 | 0xa9)
+        {
+            i += 4;
+        } else {
+            break;
+        }
+    }
+    i
+}
+
+// Decodes the escape sequence at the start of `z`, returning its length and
+// the code point, which is INVALID_CHAR if the escape is not valid.
+fn unescape_one(z: &[u8]) -> (usize, u32) {
+    let n = z.len();
+    if n < 2 {
+        return (n, INVALID_CHAR);
+    }
+    match z[1] {
+        b'u' => {
+            if n < 6 {
+                return (n, INVALID_CHAR);
+            }
+            let v = hex4(&z[2..]);
+            if v & 0xfc00 == 0xd800 && n >= 12 && z[6] == b'\\' && z[7] == b'u' {
+                let low = hex4(&z[8..]);
+                if low & 0xfc00 == 0xdc00 {
+                    return (12, ((v & 0x3ff) << 10) + (low & 0x3ff) + 0x10000);
+                }
+            }
+            (6, v)
+        }
+        b'b' => (2, 0x08),
+        b'f' => (2, 0x0c),
+        b'n' => (2, b'\n' as u32),
+        b'r' => (2, b'\r' as u32),
+        b't' => (2, b'\t' as u32),
+        b'v' => (2, 0x0b),
+        b'0' if at(z, 2).is_ascii_digit() => (2, INVALID_CHAR),
+        b'0' => (2, 0),
+        b'\'' | b'"' | b'/' | b'\\' => (2, z[1] as u32),
+        b'x' => {
+            if n < 4 {
+                return (n, INVALID_CHAR);
+            }
+            (4, hex_to_int(z[2]) << 4 | hex_to_int(z[3]))
+        }
+        0xe2 | b'\r' | b'\n' => {
+            let skip = bytes_to_bypass(z);
+            if skip == 0 {
+                (n, INVALID_CHAR)
+            } else if skip == n {
+                (n, 0)
+            } else if z[skip] == b'\\' {
+                let (k, v) = unescape_one(&z[skip..]);
+                (skip + k, v)
+            } else {
+                let (k, v) = utf8_read(&z[skip..]);
+                (skip + k, v)
+            }
+        }
+        _ => (2, INVALID_CHAR),
+    }
+}
+
+fn push_utf8(out: &mut Vec<u8>, v: u32) {
+    if v <= 0x7f {
+        out.push(v as u8);
+    } else if v <= 0x7ff {
+        out.extend_from_slice(&[0xc0 | (v >> 6) as u8, 0x80 | (v & 0x3f) as u8]);
+    } else if v < 0x10000 {
+        out.extend_from_slice(&[
+            0xe0 | (v >> 12) as u8,
+            0x80 | (v >> 6 & 0x3f) as u8,
+            0x80 | (v & 0x3f) as u8,
+        ]);
+    } else if v != INVALID_CHAR {
+        out.extend_from_slice(&[
+            0xf0 | (v >> 18) as u8,
+            0x80 | (v >> 12 & 0x3f) as u8,
+            0x80 | (v >> 6 & 0x3f) as u8,
+            0x80 | (v & 0x3f) as u8,
+        ]);
+    }
+}
+
+// The text of a TEXTJ or TEXT5 payload with its escapes decoded, dropping
+// invalid ones.
+fn unescape(z: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(z.len());
+    let mut i = 0;
+    while i < z.len() {
+        if z[i] == b'\\' {
+            let (n, v) = unescape_one(&z[i..]);
+            push_utf8(&mut out, v);
+            i += n;
+        } else {
+            out.push(z[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+// Whether two object labels are equal once their escapes are decoded. Raw
+// labels have no escapes.
+fn label_equal(left: &[u8], raw_left: bool, right: &[u8], raw_right: bool) -> bool {
+    if raw_left && raw_right {
+        return left == right;
+    }
+    fn next(z: &mut &[u8], raw: bool) -> u32 {
+        let (n, c) = match z.first() {
+            None => return 0,
+            Some(b'\\') if !raw => unescape_one(z),
+            Some(c) if *c >= 0xc0 => utf8_read(z),
+            Some(c) => (1, *c as u32),
+        };
+        *z = &z[n..];
+        c
+    }
+    let (mut left, mut right) = (left, right);
+    loop {
+        let c = next(&mut left, raw_left);
+        if c != next(&mut right, raw_right) {
+            return false;
+        }
+        if c == 0 {
+            return true;
+        }
+    }
+}
+
+fn append_control(out: &mut Vec<u8>, c: u8) {
+    let special = match c {
+        0x08 => b'b',
+        b'\t' => b't',
+        b'\n' => b'n',
+        0x0c => b'f',
+        b'\r' => b'r',
+        _ => 0,
+    };
+    if special != 0 {
+        out.extend_from_slice(&[b'\\', special]);
+    } else {
+        out.extend_from_slice(format!("\\u{:04x}", c).as_bytes());
+    }
+}
+
+// Appends `z` as a quoted JSON string.
+pub fn append_string(out: &mut Vec<u8>, z: &[u8]) {
+    out.push(b'"');
+    for &c in z {
+        if is_ok(c) || c == b'\'' {
+            out.push(c);
+        } else if c == b'"' || c == b'\\' {
+            out.extend_from_slice(&[b'\\', c]);
+        } else {
+            append_control(out, c);
+        }
+    }
+    out.push(b'"');
+}
+
+// Appends a ',' unless the array or object being built is still empty.
+fn append_separator(out: &mut Vec<u8>) {
+    if !matches!(out.last(), Some(b'[') | Some(b'{')) {
+        out.push(b',');
+    }
+}
+
+// jsonAppendSqlValue(): appends an SQL value as JSON. Text is quoted unless
+// `json` says it is JSON text from another JSON function, and only JSONB
+// blobs are accepted.
+pub fn append_value(out: &mut Vec<u8>, value: &SqlValue, json: bool) -> Result<(), Error> {
+    match value {
+        SqlValue::Null => out.extend_from_slice(b"null"),
+        SqlValue::Integer(i) => out.extend_from_slice(i.to_string().as_bytes()),
+        SqlValue::Real(f) if f.is_nan() => out.extend_from_slice(b"null"),
+        SqlValue::Real(f) if f.is_infinite() => {
+            out.extend_from_slice(if *f > 0.0 { b"9.0e+999" } else { b"-9.0e+999" })
+        }
+        SqlValue::Real(f) => out.extend_from_slice(format_real(*f).as_bytes()),
+        SqlValue::Text(s) if json => out.extend_from_slice(s.as_bytes()),
+        SqlValue::Text(s) => append_string(out, s.as_bytes()),
+        SqlValue::Blob(b) if is_jsonb(b) => {
+            render(b, 0, out)?;
+        }
+        SqlValue::Blob(_) => return Err(cannot_hold_blob()),
+    }
+    Ok(())
+}
+
+// Appends the header of an element of type `kind` with a payload of `size`
+// bytes, using the smallest header that fits.
+fn append_header(out: &mut Vec<u8>, kind: u8, size: usize) {
+    if size <= 11 {
+        out.push(kind | (size as u8) << 4);
+    } else if size <= 0xff {
+        out.extend_from_slice(&[kind | 0xc0, size as u8]);
+    } else if size <= 0xffff {
+        out.push(kind | 0xd0);
+        out.extend_from_slice(&(size as u16).to_be_bytes());
+    } else {
+        out.push(kind | 0xe0);
+        out.extend_from_slice(&(size as u32).to_be_bytes());
+    }
+}
+
+fn append_node(out: &mut Vec<u8>, kind: u8, payload: &[u8]) {
+    append_header(out, kind, payload.len());
+    out.extend_from_slice(payload);
+}
+
+// Rewrites the header at `i` for a payload of `size` bytes, returning by how
+// much the blob grew.
+fn change_payload_size(blob: &mut Vec<u8>, i: usize, size: usize) -> isize {
+    let extra: isize = match blob[i] >> 4 {
+        0..=11 => 0,
+        12 => 1,
+        13 => 2,
+        14 => 4,
+        _ => 8,
+    };
+    let needed: isize = if size <= 11 {
+        0
+    } else if size <= 0xff {
+        1
+    } else if size <= 0xffff {
+        2
+    } else {
+        4
+    };
+    let delta = needed - extra;
+    if delta > 0 {
+        let _ = blob.splice(i + 1..i + 1, std::iter::repeat_n(0, delta as usize));
+    } else if delta < 0 {
+        blob.drain(i + 1..i + 1 + (-delta) as usize);
+    }
+    let mut header = Vec::with_capacity(5);
+    append_header(&mut header, blob[i] & 0x0f, size);
+    blob[i..i + header.len()].copy_from_slice(&header);
+    delta
+}
+
+// The header size and payload size of the element at `i`, unchecked.
+fn header(blob: &[u8], i: usize) -> (usize, usize) {
+    let size = |n: usize| (1..=n).fold(0usize, |sz, k| sz << 8 | at(blob, i + k) as usize);
+    match at(blob, i) >> 4 {
+        x @ 0..=11 => (1, x as usize),
+        12 => (2, size(1)),
+        13 => (3, size(2)),
+        14 => (5, size(4)),
+        _ => (9, size(8)),
+    }
+}
+
+// jsonbPayloadSize(): the header size and payload size of the element at
+// `i`, or a header size of 0 if the element does not fit in the blob. While
+// an edit is in progress the blob may have grown by `delta` bytes that the
+// enclosing headers do not count yet.
+fn payload_size_within(blob: &[u8], i: usize, delta: isize) -> (usize, usize) {
+    let (n, sz) = header(blob, i);
+    if n > 1 && i + n > blob.len() {
+        return (0, 0);
+    }
+    if n == 9 && blob[i + 1..i + 5].iter().any(|c| *c != 0) {
+        return (0, 0);
+    }
+    let end = (i + n).saturating_add(sz) as i128;
+    let len = blob.len() as i128;
+    if end > len && end > len - delta as i128 {
+        return (0, 0);
+    }
+    (n, sz)
+}
+
+fn payload_size(blob: &[u8], i: usize) -> (usize, usize) {
+    payload_size_within(blob, i, 0)
+}
+
+// The number of elements of the array at `i`.
+fn array_count(blob: &[u8], i: usize) -> u32 {
+    let (mut n, mut sz) = payload_size(blob, i);
+    let end = i + n + sz;
+    let mut j = i + n;
+    let mut count = 0u32;
+    while n > 0 && j < end {
+        let size = payload_size(blob, j);
+        n = size.0;
+        sz = size.1;
+        j += n + sz;
+        count = count.wrapping_add(1);
+    }
+    count
+}
+
+// jsonbValidityCheck(): 0 if the element at `i`, which must end at `end`, is
+// well-formed JSONB, or else one more than the offset of the first problem.
+fn validity_check(z: &[u8], i: usize, end: usize, depth: usize) -> usize {
+    if depth > MAX_DEPTH {
+        return i + 1;
+    }
+    let (n, sz) = payload_size(z, i);
+    if n == 0 || i + n + sz != end {
+        return i + 1;
+    }
+    let x = z[i] & 0x0f;
+    let k = end;
+    match x {
+        NULL | TRUE | FALSE => {
+            if n + sz == 1 {
+                0
+            } else {
+                i + 1
+            }
+        }
+        INT => {
+            if sz < 1 {
+                return i + 1;
+            }
+            let mut j = i + n;
+            if z[j] == b'-' {
+                j += 1;
+                if sz < 2 {
+                    return i + 1;
+                }
+            }
+            while j < k {
+                if !z[j].is_ascii_digit() {
+                    return j + 1;
+                }
+                j += 1;
+            }
+            0
+        }
+        INT5 => {
+            if sz < 3 {
+                return i + 1;
+            }
+            let mut j = i + n;
+            if z[j] == b'-' {
+                if sz < 4 {
+                    return i + 1;
+                }
+                j += 1;
+            }
+            if z[j] != b'0' {
+                return i + 1;
+            }
+            if z[j + 1] != b'x' && z[j + 1] != b'X' {
+                return j + 2;
+            }
+            j += 2;
+            while j < k {
+                if !z[j].is_ascii_hexdigit() {
+                    return j + 1;
+                }
+                j += 1;
+            }
+            0
+        }
+        FLOAT | FLOAT5 => {
+            // 0 initially, 1 once a '.' is seen and 2 once an 'e' is
+            let mut seen = 0;
+            if sz < 2 {
+                return i + 1;
+            }
+            let mut j = i + n;
+            if z[j] == b'-' {
+                j += 1;
+                if sz < 3 {
+                    return i + 1;
+                }
+            }
+            if z[j] == b'.' {
+                if x == FLOAT || !at(z, j + 1).is_ascii_digit() {
+                    return j + 1;
+                }
+                j += 2;
+                seen = 1;
+            } else if z[j] == b'0' && x == FLOAT {
+                if j + 3 > k {
+                    return j + 1;
+                }
+                if !matches!(z[j + 1], b'.' | b'e' | b'E') {
+                    return j + 1;
+                }
+                j += 1;
+            }
+            while j < k {
+                let c = z[j];
+                if c == b'.' {
+                    if seen > 0 {
+                        return j + 1;
+                    }
+                    if x == FLOAT && (j == k - 1 || !z[j + 1].is_ascii_digit()) {
+                        return j + 1;
+                    }
+                    seen = 1;
+                } else if c == b'e' || c == b'E' {
+                    if seen == 2 || j == k - 1 {
+                        return j + 1;
+                    }
+                    if z[j + 1] == b'+' || z[j + 1] == b'-' {
+                        j += 1;
+                        if j == k - 1 {
+                            return j + 1;
+                        }
+                    }
+                    seen = 2;
+                } else if !c.is_ascii_digit() {
+                    return j + 1;
+                }
+                j += 1;
+            }
+            if seen == 0 {
+                i + 1
+            } else {
+                0
+            }
+        }
+        TEXT => {
+            for (j, c) in z.iter().enumerate().take(k).skip(i + n) {
+                if !is_ok(*c) && *c != b'\'' {
+                    return j + 1;
+                }
+            }
+            0
+        }
+        TEXTJ | TEXT5 => {
+            let mut j = i + n;
+            while j < k {
+                let c = z[j];
+                if !is_ok(c) && c != b'\'' {
+                    if c == b'"' || c <= 0x1f {
+                        if x == TEXTJ {
+                            return j + 1;
+                        }
+                    } else if j + 1 >= k {
+                        return j + 1;
+                    } else if b"\"\\/bfnrt".contains(&z[j + 1]) {
+                        j += 1;
+                    } else if z[j + 1] == b'u' {
+                        if j + 5 >= k || !is_4hex(z, j + 2) {
+                            return j + 1;
+                        }
+                        j += 1;
+                    } else if x != TEXT5 {
+                        return j + 1;
+                    } else {
+                        let (n, c) = unescape_one(&z[j..k]);
+                        if c == INVALID_CHAR {
+                            return j + 1;
+                        }
+                        j += n - 1;
+                    }
+                }
+                j += 1;
+            }
+            0
+        }
+        TEXTRAW => 0,
+        ARRAY | OBJECT => {
+            let mut count = 0;
+            let mut j = i + n;
+            while j < k {
+                let (n, sz) = payload_size(z, j);
+                if n == 0 || j + n + sz > k {
+                    return j + 1;
+                }
+                if x == OBJECT && count & 1 == 0 && !(TEXT..=TEXTRAW).contains(&(z[j] & 0x0f)) {
+                    return j + 1;
+                }
+                let sub = validity_check(z, j, j + n + sz, depth + 1);
+                if sub != 0 {
+                    return sub;
+                }
+                count += 1;
+                j += n + sz;
+            }
+            if x == OBJECT && count & 1 != 0 {
+                j + 1
+            } else {
+                0
+            }
+        }
+        _ => i + 1,
+    }
+}
+
+// jsonArgIsJsonb(): whether a blob looks like JSONB. Only the outermost
+// header is checked unless the blob is short enough to be mistaken for JSON
+// text.
+pub fn is_jsonb(blob: &[u8]) -> bool {
+    let c = match blob.first() {
+        Some(c) => *c,
+        None => return false,
+    };
+    let (n, sz) = payload_size(blob, 0);
+    c & 0x0f <= OBJECT
+        && n > 0
+        && n + sz == blob.len()
+        && (c & 0x0f > FALSE || sz == 0)
+        && (sz > 7
+            || (c != b'{' && c != b'[' && !c.is_ascii_digit())
+            || validity_check(blob, 0, blob.len(), 1) == 0)
+}
+
+// Translates JSON text, including SQLite's JSON5 extensions, to JSONB.
+struct TextParser<'a> {
+    z: &'a [u8],
+    out: Vec<u8>,
+    depth: usize,
+    // where the last error or closing token was found
+    error_at: usize,
+    // whether any JSON5 extension was used
+    non_standard: bool,
+}
+
+impl TextParser<'_> {
+    // Translates the element starting at `i`, returning the index just past
+    // it, 0 at the end of the text, -1 for an error or -2, -3, -4 and -5 for
+    // a '}', ']', ',' or ':' found at `error_at`.
+    fn translate(&mut self, mut i: usize) -> isize {
+        let z = self.z;
+        loop {
+            return match at(z, i) {
+                b'{' => self.object(i),
+                b'[' => self.array(i),
+                b'\'' | b'"' => self.string(i),
+                b't' => self.keyword(i, b"true", TRUE),
+                b'f' => self.keyword(i, b"false", FALSE),
+                b'n' if tail(z, i).starts_with(b"null")
+                    && !at(z, i + 4).is_ascii_alphanumeric() =>
+                {
+                    self.out.push(NULL);
+                    (i + 4) as isize
+                }
+                b'+' | b'.' | b'-' | b'0'..=b'9' => self.number(i),
+                b'}' => self.token(i, -2),
+                b']' => self.token(i, -3),
+                b',' => self.token(i, -4),
+                b':' => self.token(i, -5),
+                0 => 0,
+                b'\t' | b'\n' | b'\r' | b' ' => {
+                    i += 1 + spaces(tail(z, i + 1));
+                    continue;
+                }
+                0x0b | 0x0c | b'/' | 0xc2 | 0xe1 | 0xe2 | 0xe3 | 0xef => {
+                    let n = json5_whitespace(tail(z, i));
+                    if n > 0 {
+                        i += n;
+                        self.non_standard = true;
+                        continue;
+                    }
+                    self.token(i, -1)
+                }
+                _ => self.nan_or_infinity(i),
+            };
+        }
+    }
+
+    fn token(&mut self, i: usize, code: isize) -> isize {
+        self.error_at = i;
+        code
+    }
+
+    fn keyword(&mut self, i: usize, word: &[u8], kind: u8) -> isize {
+        if tail(self.z, i).starts_with(word) && !at(self.z, i + word.len()).is_ascii_alphanumeric()
+        {
+            self.out.push(kind);
+            return (i + word.len()) as isize;
+        }
+        self.token(i, -1)
+    }
+
+    fn nan_or_infinity(&mut self, i: usize) -> isize {
+        const NAMES: [(&[u8], bool); 5] = [
+            (b"inf", true),
+            (b"infinity", true),
+            (b"nan", false),
+            (b"qnan", false),
+            (b"snan", false),
+        ];
+        for (name, infinity) in NAMES.iter() {
+            if !starts_with_ignore_case(tail(self.z, i), name)
+                || at(self.z, i + name.len()).is_ascii_alphanumeric()
+            {
+                continue;
+            }
+            if *infinity {
+                append_node(&mut self.out, FLOAT, b"9e999");
+            } else {
+                self.out.push(NULL);
+            }
+            self.non_standard = true;
+            return (i + name.len()) as isize;
+        }
+        self.token(i, -1)
+    }
+
+    fn object(&mut self, i: usize) -> isize {
+        let z = self.z;
+        let this = self.out.len();
+        append_header(&mut self.out, OBJECT, z.len() - i);
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return self.token(i, -1);
+        }
+        let start = self.out.len();
+        let mut j = i + 1;
+        loop {
+            let label = self.out.len();
+            let mut x = self.translate(j);
+            if x <= 0 {
+                if x == -2 {
+                    j = self.error_at;
+                    if self.out.len() != start {
+                        self.non_standard = true;
+                    }
+                    break;
+                }
+                // a JSON5 identifier as the label
+                j += json5_whitespace(tail(z, j));
+                let mut kind = TEXT;
+                let escape = |k: usize, kind: &mut u8| {
+                    at(z, k) == b'\\' && at(z, k + 1) == b'u' && is_4hex(z, k + 2) && {
+                        *kind = TEXT5;
+                        true
+                    }
+                };
+                if !(is_id1(at(z, j)) || escape(j, &mut kind)) {
+                    if x != -1 {
+                        self.error_at = j;
+                    }
+                    return -1;
+                }
+                let mut k = j + 1;
+                while (is_id2(at(z, k)) && json5_whitespace(tail(z, k)) == 0)
+                    || escape(k, &mut kind)
+                {
+                    k += 1;
+                }
+                append_node(&mut self.out, kind, &z[j..k]);
+                self.non_standard = true;
+                x = k as isize;
+            }
+            let t = self.out[label] & 0x0f;
+            if !(TEXT..=TEXTRAW).contains(&t) {
+                return self.token(j, -1);
+            }
+            j = x as usize;
+            if at(z, j) == b':' {
+                j += 1;
+            } else {
+                if is_space(at(z, j)) {
+                    j += spaces(tail(z, j));
+                }
+                if at(z, j) == b':' {
+                    j += 1;
+                } else {
+                    let x = self.translate(j);
+                    if x != -5 {
+                        if x != -1 {
+                            self.error_at = j;
+                        }
+                        return -1;
+                    }
+                    j = self.error_at + 1;
+                }
+            }
+            let x = self.translate(j);
+            if x <= 0 {
+                if x != -1 {
+                    self.error_at = j;
+                }
+                return -1;
+            }
+            j = x as usize;
+            if is_space(at(z, j)) {
+                j += spaces(tail(z, j));
+            }
+            match at(z, j) {
+                b',' => {
+                    j += 1;
+                    continue;
+                }
+                b'}' => break,
+                _ => {}
+            }
+            match self.translate(j) {
+                -4 => j = self.error_at + 1,
+                -2 => {
+                    j = self.error_at;
+                    break;
+                }
+                _ => return self.token(j, -1),
+            }
+        }
+        let size = self.out.len() - start;
+        change_payload_size(&mut self.out, this, size);
+        self.depth -= 1;
+        (j + 1) as isize
+    }
+
+    fn array(&mut self, i: usize) -> isize {
+        let z = self.z;
+        let this = self.out.len();
+        append_header(&mut self.out, ARRAY, z.len() - i);
+        let start = self.out.len();
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return self.token(i, -1);
+        }
+        let mut j = i + 1;
+        loop {
+            let x = self.translate(j);
+            if x <= 0 {
+                if x == -3 {
+                    j = self.error_at;
+                    if self.out.len() != start {
+                        self.non_standard = true;
+                    }
+                    break;
+                }
+                if x != -1 {
+                    self.error_at = j;
+                }
+                return -1;
+            }
+            j = x as usize;
+            if is_space(at(z, j)) {
+                j += spaces(tail(z, j));
+            }
+            match at(z, j) {
+                b',' => {
+                    j += 1;
+                    continue;
+                }
+                b']' => break,
+                _ => {}
+            }
+            match self.translate(j) {
+                -4 => j = self.error_at + 1,
+                -3 => {
+                    j = self.error_at;
+                    break;
+                }
+                _ => return self.token(j, -1),
+            }
+        }
+        let size = self.out.len() - start;
+        change_payload_size(&mut self.out, this, size);
+        self.depth -= 1;
+        (j + 1) as isize
+    }
+
+    fn string(&mut self, i: usize) -> isize {
+        let z = self.z;
+        let delimiter = z[i];
+        if delimiter == b'\'' {
+            self.non_standard = true;
+        }
+        let mut kind = TEXT;
+        let mut j = i + 1;
+        loop {
+            let c = at(z, j);
+            if is_ok(c) {
+                j += 1;
+                continue;
+            }
+            if c == delimiter {
+                break;
+            }
+            if c == b'\\' {
+                j += 1;
+                let c = at(z, j);
+                if b"\"\\/bfnrt".contains(&c) && c != 0 || (c == b'u' && is_4hex(z, j + 1)) {
+                    if kind == TEXT {
+                        kind = TEXTJ;
+                    }
+                } else if c == b'\''
+                    || c == b'v'
+                    || c == b'\n'
+                    || (c == b'0' && !at(z, j + 1).is_ascii_digit())
+                    || (c == 0xe2 && at(z, j + 1) == 0x80 && matches!(at(z, j + 2), 0xa8 | 0xa9))
+                    || (c == b'x' && is_2hex(z, j + 1))
+                {
+                    kind = TEXT5;
+                    self.non_standard = true;
+                } else if c == b'\r' {
+                    if at(z, j + 1) == b'\n' {
+                        j += 1;
+                    }
+                    kind = TEXT5;
+                    self.non_standard = true;
+                } else {
+                    return self.token(j, -1);
+                }
+            } else if c <= 0x1f {
+                if c == 0 {
+                    return self.token(j, -1);
+                }
+                kind = TEXT5;
+                self.non_standard = true;
+            } else if c == b'"' {
+                kind = TEXT5;
+            }
+            j += 1;
+        }
+        append_node(&mut self.out, kind, &z[i + 1..j]);
+        (j + 1) as isize
+    }
+
+    fn number(&mut self, i: usize) -> isize {
+        let z = self.z;
+        // 0x01 for JSON5 and 0x02 for a real, so INT + t is the type
+        let mut t = 0u8;
+        let c = z[i];
+        let mut hex_end = None;
+        if c == b'+' {
+            self.non_standard = true;
+        }
+        if c == b'.' {
+            if !at(z, i + 1).is_ascii_digit() {
+                return self.token(i, -1);
+            }
+            self.non_standard = true;
+            t = 0x03;
+        } else if c == b'0' {
+            if matches!(at(z, i + 1), b'x' | b'X') && at(z, i + 2).is_ascii_hexdigit() {
+                self.non_standard = true;
+                t = 0x01;
+                hex_end = Some(i + 3);
+            } else if at(z, i + 1).is_ascii_digit() {
+                return self.token(i + 1, -1);
+            }
+        } else if c == b'+' || c == b'-' {
+            if !at(z, i + 1).is_ascii_digit() {
+                if matches!(at(z, i + 1), b'I' | b'i')
+                    && starts_with_ignore_case(tail(z, i + 1), b"inf")
+                {
+                    self.non_standard = true;
+                    append_node(
+                        &mut self.out,
+                        FLOAT,
+                        if c == b'-' { b"-9e999" } else { b"9e999" },
+                    );
+                    let inity = starts_with_ignore_case(tail(z, i + 4), b"inity");
+                    return (i + if inity { 9 } else { 4 }) as isize;
+                }
+                if at(z, i + 1) != b'.' {
+                    return self.token(i, -1);
+                }
+                self.non_standard = true;
+                t |= 0x01;
+            } else if at(z, i + 1) == b'0' {
+                if at(z, i + 2).is_ascii_digit() {
+                    return self.token(i + 1, -1);
+                } else if matches!(at(z, i + 2), b'x' | b'X') && at(z, i + 3).is_ascii_hexdigit() {
+                    self.non_standard = true;
+                    t |= 0x01;
+                    hex_end = Some(i + 4);
+                }
+            }
+        }
+        let mut j;
+        if let Some(end) = hex_end {
+            j = end;
+            while at(z, j).is_ascii_hexdigit() {
+                j += 1;
+            }
+        } else {
+            let mut seen_e = false;
+            j = i + 1;
+            loop {
+                let c = at(z, j);
+                if c.is_ascii_digit() {
+                } else if c == b'.' {
+                    if t & 0x02 != 0 {
+                        return self.token(j, -1);
+                    }
+                    t |= 0x02;
+                } else if c == b'e' || c == b'E' {
+                    if z[j - 1] < b'0' {
+                        if z[j - 1] == b'.' && j >= i + 2 && z[j - 2].is_ascii_digit() {
+                            self.non_standard = true;
+                            t |= 0x01;
+                        } else {
+                            return self.token(j, -1);
+                        }
+                    }
+                    if seen_e {
+                        return self.token(j, -1);
+                    }
+                    t |= 0x02;
+                    seen_e = true;
+                    if matches!(at(z, j + 1), b'+' | b'-') {
+                        j += 1;
+                    }
+                    if !at(z, j + 1).is_ascii_digit() {
+                        return self.token(j, -1);
+                    }
+                } else {
+                    break;
+                }
+                j += 1;
+            }
+            if z[j - 1] < b'0' {
+                if z[j - 1] == b'.' && j >= i + 2 && z[j - 2].is_ascii_digit() {
+                    self.non_standard = true;
+                    t |= 0x01;
+                } else {
+                    return self.token(j, -1);
+                }
+            }
+        }
+        let start = if c == b'+' { i + 1 } else { i };
+        append_node(&mut self.out, INT + t, &z[start..j]);
+        j as isize
+    }
+}
+
+// jsonConvertTextToBlob(): the JSONB for JSON text and whether it used any
+// JSON5 extension, or None if the text is not JSON. The text ends at its
+// first NUL.
+fn parse_text(z: &[u8]) -> Option<(Vec<u8>, bool)> {
+    let z = &z[..z.iter().position(|c| *c == 0).unwrap_or(z.len())];
+    let mut parser = TextParser {
+        z,
+        out: Vec::new(),
+        depth: 0,
+        error_at: 0,
+        non_standard: false,
+    };
+    let i = parser.translate(0);
+    if i <= 0 {
+        return None;
+    }
+    let mut i = i as usize;
+    i += spaces(tail(z, i));
+    if i < z.len() {
+        i += json5_whitespace(&z[i..]);
+        if i < z.len() {
+            return None;
+        }
+        parser.non_standard = true;
+    }
+    Some((parser.out, parser.non_standard))
+}
+
+// jsonTranslateBlobToText(): appends the element at `i` as canonical JSON
+// text, returning the index just past it.
+fn render(blob: &[u8], i: usize, out: &mut Vec<u8>) -> Result<usize, Error> {
+    let (n, sz) = payload_size(blob, i);
+    if n == 0 {
+        return Err(malformed());
+    }
+    let payload = &blob[i + n..i + n + sz];
+    match blob[i] & 0x0f {
+        NULL => out.extend_from_slice(b"null"),
+        TRUE => out.extend_from_slice(b"true"),
+        FALSE => out.extend_from_slice(b"false"),
+        INT | FLOAT if sz == 0 => return Err(malformed()),
+        INT | FLOAT => out.extend_from_slice(payload),
+        INT5 => {
+            if sz == 0 {
+                return Err(malformed());
+            }
+            let mut k = 2;
+            if payload[0] == b'-' {
+                out.push(b'-');
+                k += 1;
+            } else if payload[0] == b'+' {
+                k += 1;
+            }
+            let mut u = 0u64;
+            let mut overflow = false;
+            for &c in tail(payload, k) {
+                if !c.is_ascii_hexdigit() {
+                    return Err(malformed());
+                } else if u >> 60 != 0 {
+                    overflow = true;
+                } else {
+                    u = u * 16 + hex_to_int(c) as u64;
+                }
+            }
+            if overflow {
+                out.extend_from_slice(b"9.0e999");
+            } else {
+                out.extend_from_slice(u.to_string().as_bytes());
+            }
+        }
+        FLOAT5 => {
+            if sz == 0 {
+                return Err(malformed());
+            }
+            let mut k = 0;
+            if payload[0] == b'-' {
+                out.push(b'-');
+                k += 1;
+            }
+            if at(payload, k) == b'.' {
+                out.push(b'0');
+            }
+            while k < sz {
+                out.push(payload[k]);
+                if payload[k] == b'.' && !at(payload, k + 1).is_ascii_digit() {
+                    out.push(b'0');
+                }
+                k += 1;
+            }
+        }
+        TEXT | TEXTJ => {
+            out.push(b'"');
+            out.extend_from_slice(payload);
+            out.push(b'"');
+        }
+        TEXT5 => {
+            out.push(b'"');
+            let mut z = payload;
+            while !z.is_empty() {
+                let k = z
+                    .iter()
+                    .position(|c| !is_ok(*c) && *c != b'\'')
+                    .unwrap_or(z.len());
+                out.extend_from_slice(&z[..k]);
+                z = &z[k..];
+                let c = match z.first() {
+                    Some(c) => *c,
+                    None => break,
+                };
+                if c == b'"' {
+                    out.extend_from_slice(b"\\\"");
+                    z = &z[1..];
+                    continue;
+                }
+                if c <= 0x1f {
+                    append_control(out, c);
+                    z = &z[1..];
+                    continue;
+                }
+                if z.len() < 2 {
+                    return Err(malformed());
+                }
+                let mut skip = 2;
+                match z[1] {
+                    b'\'' => out.push(b'\''),
+                    b'v' => out.extend_from_slice(b"\\u000b"),
+                    b'x' => {
+                        if z.len() < 4 {
+                            return Err(malformed());
+                        }
+                        out.extend_from_slice(b"\\u00");
+                        out.extend_from_slice(&z[2..4]);
+                        skip = 4;
+                    }
+                    b'0' => out.extend_from_slice(b"\\u0000"),
+                    b'\r' => {
+                        if z.len() > 2 && z[2] == b'\n' {
+                            skip = 3;
+                        }
+                    }
+                    b'\n' => {}
+                    0xe2 => {
+                        if z.len() < 4 || z[2] != 0x80 || !matches!(z[3], 0xa8 | 0xa9) {
+                            return Err(malformed());
+                        }
+                        skip = 4;
+                    }
+                    _ => out.extend_from_slice(&z[..2]),
+                }
+                z = &z[skip..];
+            }
+            out.push(b'"');
+        }
+        TEXTRAW => append_string(out, payload),
+        kind @ ARRAY | kind @ OBJECT => {
+            out.push(if kind == ARRAY { b'[' } else { b'{' });
+            let mut j = i + n;
+            let end = j + sz;
+            let mut count = 0;
+            while j < end {
+                j = render(blob, j, out)?;
+                out.push(if kind == OBJECT && count & 1 == 0 {
+                    b':'
+                } else {
+                    b','
+                });
+                count += 1;
+            }
+            if j > end || (kind == OBJECT && count & 1 != 0) {
+                return Err(malformed());
+            }
+            if sz > 0 {
+                out.pop();
+            }
+            out.push(if kind == ARRAY { b']' } else { b'}' });
+        }
+        _ => return Err(malformed()),
+    }
+    Ok(i + n + sz)
+}
+
+fn render_text(blob: &[u8], i: usize) -> Result<String, Error> {
+    let mut out = Vec::new();
+    render(blob, i, &mut out)?;
+    Ok(lossy(out))
+}
+
+// sqlite3AtoF() for a JSON number: the real, which must fill the text.
+fn real(text: &[u8]) -> Result<SqlValue, Error> {
+    std::str::from_utf8(text)
+        .ok()
+        .filter(|s| {
+            !s.bytes()
+                .any(|c| c.is_ascii_alphabetic() && c != b'e' && c != b'E')
+        })
+        .and_then(|s| s.parse::<f64>().ok())
+        .map(SqlValue::Real)
+        .ok_or_else(malformed)
+}
+
+// jsonReturnFromBlob(): the SQL value of the element at `i`. Arrays and
+// objects are JSON text, or JSONB if `binary`.
+fn sql_value(blob: &[u8], i: usize, binary: bool) -> Result<SqlValue, Error> {
+    let (n, sz) = payload_size(blob, i);
+    if n == 0 {
+        return Err(malformed());
+    }
+    let payload = &blob[i + n..i + n + sz];
+    Ok(match blob[i] & 0x0f {
+        NULL | TRUE | FALSE if sz != 0 => return Err(malformed()),
+        NULL => SqlValue::Null,
+        TRUE => SqlValue::Integer(1),
+        FALSE => SqlValue::Integer(0),
+        INT | INT5 => {
+            let (negative, digits) = match payload.split_first() {
+                None => return Err(malformed()),
+                Some((b'-', _)) if sz < 2 => return Err(malformed()),
+                Some((b'-', digits)) => (true, digits),
+                Some(_) => (false, payload),
+            };
+            if digits.len() > 1 && digits[0] == b'0' && matches!(digits[1], b'x' | b'X') {
+                let hex = &digits[2..];
+                let hex = &hex[hex.iter().take_while(|c| **c == b'0').count()..];
+                if hex.len() > 16 || !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(malformed());
+                }
+                let u = hex.iter().fold(0u64, |u, c| u << 4 | hex_to_int(*c) as u64);
+                match i64::try_from(u) {
+                    Ok(x) if negative => SqlValue::Integer(-x),
+                    Ok(x) => SqlValue::Integer(x),
+                    Err(_) if negative => SqlValue::Real(-(u as f64)),
+                    Err(_) => SqlValue::Real(u as f64),
+                }
+            } else {
+                if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                    return Err(malformed());
+                }
+                let text = std::str::from_utf8(digits).unwrap();
+                match text.parse::<i64>() {
+                    Ok(x) if negative => SqlValue::Integer(-x),
+                    Ok(x) => SqlValue::Integer(x),
+                    Err(_) if negative && text.trim_start_matches('0') == "9223372036854775808" => {
+                        SqlValue::Integer(i64::MIN)
+                    }
+                    Err(_) => real(payload)?,
+                }
+            }
+        }
+        FLOAT | FLOAT5 if sz == 0 => return Err(malformed()),
+        FLOAT | FLOAT5 => real(payload)?,
+        TEXT | TEXTRAW => SqlValue::Text(lossy(payload.to_vec())),
+        TEXTJ | TEXT5 => SqlValue::Text(lossy(unescape(payload))),
+        ARRAY | OBJECT if binary => SqlValue::Blob(blob[i..i + n + sz].to_vec()),
+        ARRAY | OBJECT => SqlValue::Text(render_text(blob, i)?),
+        _ => return Err(malformed()),
+    })
+}
+
+// The text of an argument up to its first NUL, or None for NULL.
+fn text(value: &SqlValue) -> Option<Vec<u8>> {
+    let mut text = match value {
+        SqlValue::Null => return None,
+        SqlValue::Blob(b) => b.clone(),
+        v => v.to_text()?.into_bytes(),
+    };
+    if let Some(nul) = text.iter().position(|c| *c == 0) {
+        text.truncate(nul);
+    }
+    Some(text)
+}
+
+// JSON text from another JSON function, which SQLite marks with its JSON
+// subtype, as JSONB so that aggregates embed it rather than quote it.
+pub fn embed(operand: Operand) -> SqlValue {
+    match operand.value {
+        SqlValue::Text(s) if operand.json => match parse_text(s.as_bytes()) {
+            Some((blob, _)) => SqlValue::Blob(blob),
+            None => SqlValue::Text(s),
+        },
+        value => value,
+    }
+}
+
+// jsonFunctionArgToBlob(): the JSONB of a value being inserted into JSON.
+// Text is a JSON string unless it is JSON text from another JSON function.
+fn value_to_jsonb(operand: &Operand) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    match &operand.value {
+        SqlValue::Null => out.push(NULL),
+        SqlValue::Blob(b) if is_jsonb(b) => out.extend_from_slice(b),
+        SqlValue::Blob(_) => return Err(cannot_hold_blob()),
+        SqlValue::Text(s) if operand.json => {
+            out = parse_text(s.as_bytes()).ok_or_else(malformed)?.0;
+        }
+        SqlValue::Text(s) => append_node(&mut out, TEXTRAW, s.as_bytes()),
+        SqlValue::Real(f) if f.is_nan() => out.push(NULL),
+        SqlValue::Real(f) if f.is_infinite() => {
+            append_node(&mut out, FLOAT, if *f > 0.0 { b"9e999" } else { b"-9e999" })
+        }
+        SqlValue::Real(f) => append_node(&mut out, FLOAT, format_real(*f).as_bytes()),
+        SqlValue::Integer(i) => append_node(&mut out, INT, i.to_string().as_bytes()),
+    }
+    Ok(out)
+}
+
+// jsonReturnString(): built JSON text as the result, which jsonb_*()
+// functions return as JSONB. The flag is SQLite's JSON subtype.
+pub fn text_result(text: Vec<u8>, binary: bool) -> Result<(SqlValue, bool), Error> {
+    if binary {
+        let (blob, _) = parse_text(&text).ok_or_else(malformed)?;
+        return Ok((SqlValue::Blob(blob), false));
+    }
+    Ok((SqlValue::Text(lossy(text)), true))
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Edit {
+    None,
+    Delete,
+    Replace,
+    Insert,
+    Set,
+}
+
+#[derive(Debug, PartialEq)]
+enum LookupError {
+    Malformed,
+    NotFound,
+    BadPath,
+}
+
+// A JSONB document being searched and edited.
+struct Jsonb {
+    blob: Vec<u8>,
+    // the edit to make at the end of a path, inserting `insert`
+    edit: Edit,
+    insert: Vec<u8>,
+    // by how much the blob has grown in the current edit
+    delta: isize,
+    // the label of the element last looked up, if it is an object member
+    label: usize,
+}
+
+impl Jsonb {
+    fn new(blob: Vec<u8>) -> Jsonb {
+        Jsonb {
+            blob,
+            edit: Edit::None,
+            insert: Vec::new(),
+            delta: 0,
+            label: 0,
+        }
+    }
+
+    fn payload_size(&self, i: usize) -> (usize, usize) {
+        payload_size_within(&self.blob, i, self.delta)
+    }
+
+    // jsonBlobEdit(): replaces `delete` bytes at `at` with `insert`.
+    fn splice(&mut self, at: usize, delete: usize, insert: &[u8]) {
+        let d = insert.len() as isize - delete as isize;
+        if (-8..0).contains(&d)
+            && !insert.is_empty()
+            && overwrite(&mut self.blob[at..at + delete], insert, (-d) as usize)
+        {
+            return;
+        }
+        let _ = self.blob.splice(at..at + delete, insert.iter().copied());
+        self.delta += d;
+    }
+
+    // Fixes the payload size of the container at `root` after its content
+    // grew by `delta`.
+    fn adjust_after_edit(&mut self, root: usize) {
+        let (_, sz) = header(&self.blob, root);
+        let size = (sz as isize + self.delta) as usize;
+        self.delta += change_payload_size(&mut self.blob, root, size);
+    }
+
+    // jsonLookupStep(): the index of the element at `path` below `root`,
+    // making `edit` there. `label` is the label of `root` if it is an object
+    // member.
+    fn lookup(&mut self, root: usize, path: &[u8], label: usize) -> Result<usize, LookupError> {
+        if path.is_empty() {
+            let mut root = root;
+            if self.edit != Edit::None {
+                let (n, sz) = self.payload_size(root);
+                let mut size = n + sz;
+                match self.edit {
+                    Edit::Delete => {
+                        if label > 0 {
+                            size += root - label;
+                            root = label;
+                        }
+                        self.splice(root, size, &[]);
+                    }
+                    Edit::Insert => {}
+                    _ => {
+                        let insert = std::mem::take(&mut self.insert);
+                        self.splice(root, size, &insert);
+                        self.insert = insert;
+                    }
+                }
+            }
+            self.label = label;
+            return Ok(root);
+        }
+        if path[0] == b'.' {
+            let path = &path[1..];
+            let (key, raw_key, rest) = if at(path, 0) == b'"' {
+                let mut i = 1;
+                while i < path.len() && path[i] != b'"' {
+                    if path[i] == b'\\' && i + 1 < path.len() {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                if i >= path.len() {
+                    return Err(LookupError::BadPath);
+                }
+                let key = &path[1..i];
+                (key, !key.contains(&b'\\'), &path[i + 1..])
+            } else {
+                let n = path
+                    .iter()
+                    .position(|c| *c == b'.' || *c == b'[')
+                    .unwrap_or(path.len());
+                if n == 0 {
+                    return Err(LookupError::BadPath);
+                }
+                (&path[..n], true, &path[n..])
+            };
+            if at(&self.blob, root) & 0x0f != OBJECT {
+                return Err(LookupError::NotFound);
+            }
+            let (n, sz) = self.payload_size(root);
+            let mut j = root + n;
+            let end = j + sz;
+            while j < end {
+                let x = self.blob[j] & 0x0f;
+                if !(TEXT..=TEXTRAW).contains(&x) {
+                    return Err(LookupError::Malformed);
+                }
+                let (n, sz) = self.payload_size(j);
+                if n == 0 {
+                    return Err(LookupError::Malformed);
+                }
+                let k = j + n;
+                if k + sz >= end {
+                    return Err(LookupError::Malformed);
+                }
+                let raw_label = x == TEXT || x == TEXTRAW;
+                if label_equal(key, raw_key, &self.blob[k..k + sz], raw_label) {
+                    let v = k + sz;
+                    if self.blob[v] & 0x0f > OBJECT {
+                        return Err(LookupError::Malformed);
+                    }
+                    let (n, sz) = self.payload_size(v);
+                    if n == 0 || v + n + sz > end {
+                        return Err(LookupError::Malformed);
+                    }
+                    let found = self.lookup(v, rest, j);
+                    if self.delta != 0 {
+                        self.adjust_after_edit(root);
+                    }
+                    return found;
+                }
+                j = k + sz;
+                if at(&self.blob, j) & 0x0f > OBJECT {
+                    return Err(LookupError::Malformed);
+                }
+                let (n, sz) = self.payload_size(j);
+                if n == 0 {
+                    return Err(LookupError::Malformed);
+                }
+                j += n + sz;
+            }
+            if j > end {
+                return Err(LookupError::Malformed);
+            }
+            if self.edit >= Edit::Insert {
+                let mut member = Vec::new();
+                append_node(&mut member, if raw_key { TEXTRAW } else { TEXT5 }, key);
+                let (found, value) = self.create_substructure(rest);
+                if found.is_ok() {
+                    member.extend_from_slice(&value);
+                    self.splice(j, 0, &member);
+                    self.adjust_after_edit(root);
+                }
+                return found;
+            }
+        } else if path[0] == b'[' {
+            if at(&self.blob, root) & 0x0f != ARRAY {
+                return Err(LookupError::NotFound);
+            }
+            let (n, sz) = self.payload_size(root);
+            let mut k = 0u32;
+            let mut i = 1;
+            while at(path, i).is_ascii_digit() {
+                k = k.wrapping_mul(10).wrapping_add((path[i] - b'0') as u32);
+                i += 1;
+            }
+            if i < 2 || at(path, i) != b']' {
+                if at(path, 1) != b'#' {
+                    return Err(LookupError::BadPath);
+                }
+                k = array_count(&self.blob, root);
+                i = 2;
+                if at(path, 2) == b'-' && at(path, 3).is_ascii_digit() {
+                    let mut back = 0u32;
+                    i = 3;
+                    while at(path, i).is_ascii_digit() {
+                        back = back.wrapping_mul(10).wrapping_add((path[i] - b'0') as u32);
+                        i += 1;
+                    }
+                    if back > k {
+                        return Err(LookupError::NotFound);
+                    }
+                    k -= back;
+                }
+                if at(path, i) != b']' {
+                    return Err(LookupError::BadPath);
+                }
+            }
+            let rest = &path[i + 1..];
+            let mut j = root + n;
+            let end = j + sz;
+            while j < end {
+                if k == 0 {
+                    let found = self.lookup(j, rest, 0);
+                    if self.delta != 0 {
+                        self.adjust_after_edit(root);
+                    }
+                    return found;
+                }
+                k -= 1;
+                let (n, sz) = self.payload_size(j);
+                if n == 0 {
+                    return Err(LookupError::Malformed);
+                }
+                j += n + sz;
+            }
+            if j > end {
+                return Err(LookupError::Malformed);
+            }
+            if k > 0 {
+                return Err(LookupError::NotFound);
+            }
+            if self.edit >= Edit::Insert {
+                let (found, value) = self.create_substructure(rest);
+                if found.is_ok() {
+                    self.splice(j, 0, &value);
+                }
+                if self.delta != 0 {
+                    self.adjust_after_edit(root);
+                }
+                return found;
+            }
+        } else {
+            return Err(LookupError::BadPath);
+        }
+        Err(LookupError::NotFound)
+    }
+
+    // The JSONB to insert where `rest` of a path does not exist yet: the
+    // value being inserted wrapped in the objects and arrays the path names.
+    fn create_substructure(&self, rest: &[u8]) -> (Result<usize, LookupError>, Vec<u8>) {
+        if rest.is_empty() {
+            return (Ok(0), self.insert.clone());
+        }
+        let mut sub = Jsonb::new(vec![if rest[0] == b'.' { OBJECT } else { ARRAY }]);
+        sub.edit = self.edit;
+        sub.insert = self.insert.clone();
+        let found = sub.lookup(0, rest, 0);
+        (found, sub.blob)
+    }
+
+    // jsonReturnParse(): the document as the result, JSON text with the JSON
+    // subtype or JSONB if `binary`.
+    fn result(self, binary: bool) -> Result<(SqlValue, bool), Error> {
+        if binary {
+            return Ok((SqlValue::Blob(self.blob), false));
+        }
+        Ok((SqlValue::Text(render_text(&self.blob, 0)?), true))
+    }
+}
+
+// jsonBlobOverwrite(): writes `insert` over `out`, which is `grow` bytes
+// longer, by enlarging the header of `insert` so that nothing after it has
+// to move. Returns false if no header size fits.
+fn overwrite(out: &mut [u8], insert: &[u8], grow: usize) -> bool {
+    if insert[0] & 0x0f <= FALSE {
+        return false;
+    }
+    let (header, old) = match insert[0] >> 4 {
+        12 if [1, 3, 7].contains(&grow) => (grow + 2, 2),
+        13 if grow == 2 || grow == 6 => (grow + 3, 3),
+        14 if grow == 4 => (9, 5),
+        0..=11 if [1, 2, 4, 8].contains(&grow) => (grow + 1, 1),
+        _ => return false,
+    };
+    out[0] = (insert[0] & 0x0f)
+        | match header {
+            2 => 0xc0,
+            3 => 0xd0,
+            5 => 0xe0,
+            _ => 0xf0,
+        };
+    let payload = &insert[old..];
+    out[header..header + payload.len()].copy_from_slice(payload);
+    let mut size = payload.len();
+    for b in out[1..header].iter_mut().rev() {
+        *b = size as u8;
+        size >>= 8;
+    }
+    true
+}
+
+// jsonParseFuncArg(): the JSONB of a JSON argument, or None for NULL. Blobs
+// that are not JSONB are read as JSON text, as SQLite does.
+fn document(value: &SqlValue) -> Result<Option<Jsonb>, Error> {
+    let text = match value {
+        SqlValue::Null => return Ok(None),
+        SqlValue::Blob(b) if is_jsonb(b) => return Ok(Some(Jsonb::new(b.clone()))),
+        SqlValue::Blob(b) => b.clone(),
+        v => v.to_text().unwrap_or_default().into_bytes(),
+    };
+    let (blob, _) = parse_text(&text).ok_or_else(malformed)?;
+    Ok(Some(Jsonb::new(blob)))
+}
+
+fn lookup_error(error: LookupError, path: &[u8]) -> Error {
+    match error {
+        LookupError::Malformed => malformed(),
+        _ => bad_path(path),
+    }
+}
+
+// The number of arguments each JSON function takes.
+pub fn arity(name: &str) -> Option<RangeInclusive<usize>> {
+    Some(match name {
+        "json" | "jsonb" | "json_quote" => 1..=1,
+        "json_array_length" | "json_type" | "json_valid" => 1..=2,
+        "->" | "->>" => 2..=2,
+        "json_array" | "jsonb_array" | "json_object" | "jsonb_object" | "json_extract"
+        | "jsonb_extract" | "json_remove" | "jsonb_remove" | "json_insert" | "jsonb_insert"
+        | "json_replace" | "jsonb_replace" | "json_set" | "jsonb_set" => 0..=usize::MAX,
+        _ => return None,
+    })
+}
+
+// Calls the JSON function `name`, whose arguments have been checked against
+// arity(). Also returns whether the result has SQLite's JSON subtype, that
+// is whether it is JSON text that other JSON functions embed as it is.
+pub fn call(name: &str, args: &[Operand]) -> Result<(SqlValue, bool), Error> {
+    let binary = name.starts_with("jsonb");
+    let null = Ok((SqlValue::Null, false));
+    match name.trim_start_matches("jsonb").trim_start_matches("json") {
+        "" | "_remove" => remove(args, binary),
+        "_array" => {
+            let mut out = vec![b'['];
+            for arg in args {
+                append_separator(&mut out);
+                append_value(&mut out, &arg.value, arg.json)?;
+            }
+            out.push(b']');
+            text_result(out, binary)
+        }
+        "_object" => {
+            if !args.len().is_multiple_of(2) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "json_object() requires an even number of arguments",
+                ));
+            }
+            let mut out = vec![b'{'];
+            for pair in args.chunks(2) {
+                let label = match &pair[0].value {
+                    SqlValue::Text(label) => label,
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "json_object() labels must be TEXT",
+                        ))
+                    }
+                };
+                append_separator(&mut out);
+                append_string(&mut out, label.as_bytes());
+                out.push(b':');
+                append_value(&mut out, &pair[1].value, pair[1].json)?;
+            }
+            out.push(b'}');
+            text_result(out, binary)
+        }
+        "_quote" => {
+            let mut out = Vec::new();
+            append_value(&mut out, &args[0].value, args[0].json)?;
+            text_result(out, false)
+        }
+        "_array_length" | "_type" => {
+            let mut doc = match document(&args[0].value)? {
+                Some(doc) => doc,
+                None => return null,
+            };
+            let mut i = 0;
+            if let Some(path) = args.get(1) {
+                let path = match text(&path.value) {
+                    Some(path) => path,
+                    None => return null,
+                };
+                let found = match path.first() {
+                    Some(b'$') => doc.lookup(0, &path[1..], 0),
+                    _ if name == "json_type" => return Err(bad_path(&path)),
+                    _ => doc.lookup(0, b"@", 0),
+                };
+                i = match found {
+                    Ok(i) => i,
+                    Err(LookupError::NotFound) => return null,
+                    Err(e) => return Err(lookup_error(e, &path)),
+                };
+            }
+            let kind = doc.blob[i] & 0x0f;
+            Ok((
+                if name == "json_type" {
+                    SqlValue::Text(TYPE_NAMES[kind as usize].to_string())
+                } else if kind == ARRAY {
+                    SqlValue::Integer(array_count(&doc.blob, i) as i64)
+                } else {
+                    SqlValue::Integer(0)
+                },
+                false,
+            ))
+        }
+        "_valid" => Ok((valid(args)?, false)),
+        "_extract" | "->" | "->>" => extract(name, args),
+        "_insert" => edit(args, Edit::Insert, "insert", binary),
+        "_replace" => edit(args, Edit::Replace, "replace", binary),
+        "_set" => edit(args, Edit::Set, "set", binary),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("no such function: {}", name),
+        )),
+    }
+}
+
+// json(), jsonb() and json_remove(): the document without the elements at
+// each path.
+fn remove(args: &[Operand], binary: bool) -> Result<(SqlValue, bool), Error> {
+    let mut doc = match args.first() {
+        Some(arg) => match document(&arg.value)? {
+            Some(doc) => doc,
+            None => return Ok((SqlValue::Null, false)),
+        },
+        None => return Ok((SqlValue::Null, false)),
+    };
+    for arg in &args[1..] {
+        let path = match text(&arg.value) {
+            Some(path) => path,
+            None => return Ok((SqlValue::Null, false)),
+        };
+        if path.first() != Some(&b'$') {
+            return Err(bad_path(&path));
+        }
+        if path.len() == 1 {
+            return Ok((SqlValue::Null, false));
+        }
+        doc.edit = Edit::Delete;
+        doc.delta = 0;
+        match doc.lookup(0, &path[1..], 0) {
+            Ok(_) | Err(LookupError::NotFound) => {}
+            Err(e) => return Err(lookup_error(e, &path)),
+        }
+    }
+    doc.result(binary)
+}
+
+// json_insert(), json_replace() and json_set(): the document with each value
+// put at the path before it.
+fn edit(args: &[Operand], edit: Edit, verb: &str, binary: bool) -> Result<(SqlValue, bool), Error> {
+    if args.is_empty() {
+        return Ok((SqlValue::Null, false));
+    }
+    if args.len().is_multiple_of(2) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("json_{}() needs an odd number of arguments", verb),
+        ));
+    }
+    let mut doc = match document(&args[0].value)? {
+        Some(doc) => doc,
+        None => return Ok((SqlValue::Null, false)),
+    };
+    for pair in args[1..].chunks(2) {
+        let path = match text(&pair[0].value) {
+            Some(path) => path,
+            None => continue,
+        };
+        if path.first() != Some(&b'$') {
+            return Err(bad_path(&path));
+        }
+        let insert = value_to_jsonb(&pair[1])?;
+        if path.len() == 1 {
+            if edit != Edit::Insert {
+                let len = doc.blob.len();
+                doc.splice(0, len, &insert);
+            }
+            continue;
+        }
+        doc.edit = edit;
+        doc.insert = insert;
+        doc.delta = 0;
+        match doc.lookup(0, &path[1..], 0) {
+            Ok(_) | Err(LookupError::NotFound) => {}
+            Err(e) => return Err(lookup_error(e, &path)),
+        }
+    }
+    doc.result(binary)
+}
+
+// json_extract() and the -> and ->> operators, which also take abbreviated
+// paths: a label, an array index or a bracketed array index.
+fn extract(name: &str, args: &[Operand]) -> Result<(SqlValue, bool), Error> {
+    let null = Ok((SqlValue::Null, false));
+    let binary = name == "jsonb_extract";
+    if args.len() < 2 {
+        return null;
+    }
+    let mut doc = match document(&args[0].value)? {
+        Some(doc) => doc,
+        None => return null,
+    };
+    let mut out = vec![b'['];
+    for arg in &args[1..] {
+        let path = match text(&arg.value) {
+            Some(path) => path,
+            None => return null,
+        };
+        let found = if path.first() == Some(&b'$') {
+            doc.lookup(0, &path[1..], 0)
+        } else if name.starts_with("->") {
+            let mut full = Vec::new();
+            if let SqlValue::Integer(_) = arg.value {
+                full.push(b'[');
+                if path[0] == b'-' {
+                    full.push(b'#');
+                }
+                full.extend_from_slice(&path);
+                full.push(b']');
+            } else if path.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_') {
+                full.push(b'.');
+                full.extend_from_slice(&path);
+            } else if path[0] == b'[' && path.len() >= 3 && path.ends_with(b"]") {
+                full.extend_from_slice(&path);
+            } else {
+                full.extend_from_slice(b".\"");
+                full.extend_from_slice(&path);
+                full.push(b'"');
+            }
+            doc.lookup(0, &full, 0)
+        } else {
+            return Err(bad_path(&path));
+        };
+        match found {
+            Ok(i) if args.len() == 2 => {
+                if name == "->" {
+                    return Ok((SqlValue::Text(render_text(&doc.blob, i)?), true));
+                }
+                let value = sql_value(&doc.blob, i, binary)?;
+                return Ok((value, name == "json_extract" && doc.blob[i] & 0x0f >= ARRAY));
+            }
+            Ok(i) => {
+                append_separator(&mut out);
+                render(&doc.blob, i, &mut out)?;
+            }
+            Err(LookupError::NotFound) if args.len() == 2 => return null,
+            Err(LookupError::NotFound) => {
+                append_separator(&mut out);
+                out.extend_from_slice(b"null");
+            }
+            Err(e) => return Err(lookup_error(e, &path)),
+        }
+    }
+    out.push(b']');
+    text_result(out, binary)
+}
+
+// json_valid(): the optional flags ask for 1 strict JSON text, 2 JSON5 text,
+// 4 JSONB that looks valid or 8 JSONB that is valid.
+fn valid(args: &[Operand]) -> Result<SqlValue, Error> {
+    let flags = match args.get(1) {
+        Some(flags) => {
+            let flags = scalar::integer(&flags.value);
+            if !(1..=15).contains(&flags) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "FLAGS parameter to json_valid() must be between 1 and 15",
+                ));
+            }
+            flags as u8
+        }
+        None => 1,
+    };
+    let text = match &args[0].value {
+        SqlValue::Null => return Ok(SqlValue::Null),
+        SqlValue::Blob(b) if is_jsonb(b) => {
+            return Ok(SqlValue::from_bool(if flags & 0x04 != 0 {
+                true
+            } else {
+                flags & 0x08 != 0 && validity_check(b, 0, b.len(), 1) == 0
+            }));
+        }
+        SqlValue::Blob(b) => b.clone(),
+        v => v.to_text().unwrap_or_default().into_bytes(),
+    };
+    if flags & 0x03 == 0 {
+        return Ok(SqlValue::from_bool(false));
+    }
+    Ok(SqlValue::from_bool(match parse_text(&text) {
+        Some((_, non_standard)) => flags & 0x02 != 0 || !non_standard,
+        None => false,
+    }))
+}
+
+// The table-valued functions json_each(), which walks the elements of an
+// array or object, and json_tree(), which walks a whole document.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TableFunction {
+    Each,
+    Tree,
+}
+
+// The columns of json_each() and json_tree(), without the hidden json and
+// root columns that hold the arguments.
+const COLUMNS: [&str; 8] = [
+    "key", "value", "type", "atom", "id", "parent", "fullkey", "path",
+];
+
+impl TableFunction {
+    pub fn from_name(name: &str) -> Option<TableFunction> {
+        match name.to_ascii_lowercase().as_str() {
+            "json_each" => Some(TableFunction::Each),
+            "json_tree" => Some(TableFunction::Tree),
+            _ => None,
+        }
+    }
+
+    pub fn columns(&self) -> &'static [&'static str] {
+        &COLUMNS
+    }
+
+    // Whether the column `column` of a row holds JSON text, which is when
+    // the value column is an array or object.
+    pub fn carries_json(column: usize, values: &[SqlValue]) -> bool {
+        column == 1
+            && matches!(values.get(2), Some(SqlValue::Text(t)) if t == "array" || t == "object")
+    }
+
+    // The rowid and columns of each row for the arguments, the JSON and
+    // optionally the path of the element to start from.
+    pub fn rows(&self, args: &[SqlValue]) -> Result<Vec<(i64, Vec<SqlValue>)>, Error> {
+        let name = match self {
+            TableFunction::Each => "json_each",
+            TableFunction::Tree => "json_tree",
+        };
+        if args.len() > 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("too many arguments on {}() - max 2", name),
+            ));
+        }
+        let doc = match args.first() {
+            Some(SqlValue::Blob(b)) if is_jsonb(b) => Jsonb::new(b.clone()),
+            Some(value) => match text(value) {
+                Some(text) => Jsonb::new(parse_text(&text).ok_or_else(malformed)?.0),
+                None => return Ok(Vec::new()),
+            },
+            None => return Ok(Vec::new()),
+        };
+        let mut walk = Walk {
+            doc,
+            recursive: *self == TableFunction::Tree,
+            i: 0,
+            end: 0,
+            container: 0,
+            parents: Vec::new(),
+            path: b"$".to_vec(),
+            root_length: 1,
+            rowid: 0,
+        };
+        let mut i = 0;
+        if let Some(root) = args.get(1) {
+            let root = match text(root) {
+                Some(root) => root,
+                None => return Ok(Vec::new()),
+            };
+            if root.first() != Some(&b'$') {
+                return Err(bad_path(&root));
+            }
+            if root.len() > 1 {
+                i = match walk.doc.lookup(0, &root[1..], 0) {
+                    Ok(i) => i,
+                    Err(LookupError::NotFound) => return Ok(Vec::new()),
+                    Err(_) => return Err(bad_path(&root)),
+                };
+                if walk.doc.label > 0 {
+                    walk.i = walk.doc.label;
+                    walk.container = OBJECT;
+                } else {
+                    walk.i = i;
+                    walk.container = ARRAY;
+                }
+            }
+            walk.root_length = root.len();
+            walk.path = root;
+        }
+        let (n, sz) = payload_size(&walk.doc.blob, i);
+        walk.end = i + n + sz;
+        let kind = at(&walk.doc.blob, i) & 0x0f;
+        if kind >= ARRAY && !walk.recursive {
+            walk.i = i + n;
+            walk.container = kind;
+            walk.parents.push(Parent {
+                head: walk.i,
+                value: i,
+                end: walk.end,
+                key: 0,
+                path_length: 0,
+            });
+        }
+        let mut rows = Vec::new();
+        while walk.i < walk.end {
+            rows.push((walk.rowid, walk.row()?));
+            walk.next()?;
+        }
+        Ok(rows)
+    }
+}
+
+// An array or object that json_each() or json_tree() is inside.
+struct Parent {
+    // the index of its label, or of itself if it has none
+    head: usize,
+    value: usize,
+    end: usize,
+    // the index of the current element, for arrays
+    key: i64,
+    // the length of the path up to it
+    path_length: usize,
+}
+
+// The cursor of json_each() and json_tree().
+struct Walk {
+    doc: Jsonb,
+    recursive: bool,
+    // the current element, or its label in an object
+    i: usize,
+    end: usize,
+    // the type of the container of the current element, or 0 for the root
+    container: u8,
+    parents: Vec<Parent>,
+    // the path of the current container
+    path: Vec<u8>,
+    root_length: usize,
+    rowid: i64,
+}
+
+impl Walk {
+    fn skip_label(&self) -> usize {
+        if self.container == OBJECT {
+            let (n, sz) = payload_size(&self.doc.blob, self.i);
+            self.i + n + sz
+        } else {
+            self.i
+        }
+    }
+
+    // Appends the name of the current element within its container.
+    fn append_path_name(&self, path: &mut Vec<u8>) {
+        if self.container == ARRAY {
+            let key = self.parents.last().map_or(0, |p| p.key);
+            path.extend_from_slice(format!("[{}]", key).as_bytes());
+            return;
+        }
+        let (n, sz) = payload_size(&self.doc.blob, self.i);
+        let z = &self.doc.blob[self.i + n..self.i + n + sz];
+        let quote = z.first().is_none_or(|c| !c.is_ascii_alphabetic())
+            || !z.iter().all(u8::is_ascii_alphanumeric);
+        path.push(b'.');
+        if quote {
+            path.push(b'"');
+        }
+        path.extend_from_slice(z);
+        if quote {
+            path.push(b'"');
+        }
+    }
+
+    // jsonEachPathLength(): the length of the path column. For the root row
+    // of json_tree() with a root path, the path is that of the root's
+    // container.
+    fn path_length(&mut self) -> usize {
+        let mut n = self.path.len();
+        if self.rowid == 0 && self.recursive && n >= 2 {
+            while n > 1 {
+                n -= 1;
+                if self.path[n] == b'[' || self.path[n] == b'.' {
+                    let prefix = self.path[1..n].to_vec();
+                    let x = match self.doc.lookup(0, &prefix, 0) {
+                        Ok(x) => x,
+                        Err(_) => continue,
+                    };
+                    if x + payload_size(&self.doc.blob, x).0 == self.i {
+                        break;
+                    }
+                }
+            }
+        }
+        n
+    }
+
+    fn key(&mut self) -> Result<SqlValue, Error> {
+        if let Some(parent) = self.parents.last() {
+            return if self.container == OBJECT {
+                sql_value(&self.doc.blob, self.i, false)
+            } else {
+                Ok(SqlValue::Integer(parent.key))
+            };
+        }
+        if self.root_length == 1 {
+            return Ok(SqlValue::Null);
+        }
+        let j = self.path_length();
+        let n = self.root_length - j;
+        let path = &self.path;
+        Ok(if n == 0 {
+            SqlValue::Null
+        } else if path[j] == b'[' {
+            let digits = tail(path, j + 1)
+                .iter()
+                .take(n - 1)
+                .take_while(|c| c.is_ascii_digit())
+                .fold(0i64, |x, c| {
+                    x.wrapping_mul(10).wrapping_add((c - b'0') as i64)
+                });
+            SqlValue::Integer(digits)
+        } else if at(path, j + 1) == b'"' {
+            SqlValue::Text(lossy(tail(path, j + 2)[..n.saturating_sub(3)].to_vec()))
+        } else {
+            SqlValue::Text(lossy(tail(path, j + 1)[..n - 1].to_vec()))
+        })
+    }
+
+    fn row(&mut self) -> Result<Vec<SqlValue>, Error> {
+        let i = self.skip_label();
+        let kind = at(&self.doc.blob, i) & 0x0f;
+        let key = self.key()?;
+        let value = sql_value(&self.doc.blob, i, false)?;
+        let atom = if kind < ARRAY {
+            value.clone()
+        } else {
+            SqlValue::Null
+        };
+        let parent = match self.parents.last() {
+            Some(parent) if self.recursive => SqlValue::Integer(parent.head as i64),
+            _ => SqlValue::Null,
+        };
+        let mut full_key = self.path.clone();
+        if !self.parents.is_empty() {
+            self.append_path_name(&mut full_key);
+        }
+        let path_length = self.path_length();
+        Ok(vec![
+            key,
+            value,
+            SqlValue::Text(TYPE_NAMES[kind as usize].to_string()),
+            atom,
+            SqlValue::Integer(self.i as i64),
+            parent,
+            SqlValue::Text(lossy(full_key)),
+            SqlValue::Text(lossy(self.path[..path_length].to_vec())),
+        ])
+    }
+
+    fn next(&mut self) -> Result<(), Error> {
+        let i = self.skip_label();
+        let (n, sz) = payload_size(&self.doc.blob, i);
+        if n == 0 {
+            return Err(malformed());
+        }
+        let kind = self.doc.blob[i] & 0x0f;
+        if self.recursive {
+            let mut level_change = false;
+            if kind == OBJECT || kind == ARRAY {
+                level_change = true;
+                let parent = Parent {
+                    head: self.i,
+                    value: i,
+                    end: i + n + sz,
+                    key: -1,
+                    path_length: self.path.len(),
+                };
+                if self.container != 0 && !self.parents.is_empty() {
+                    let mut path = std::mem::take(&mut self.path);
+                    self.append_path_name(&mut path);
+                    self.path = path;
+                }
+                self.parents.push(parent);
+                self.i = i + n;
+            } else {
+                self.i = i + n + sz;
+            }
+            while let Some(parent) = self.parents.last() {
+                if self.i < parent.end {
+                    break;
+                }
+                self.path.truncate(parent.path_length);
+                self.parents.pop();
+                level_change = true;
+            }
+            if level_change {
+                self.container = self
+                    .parents
+                    .last()
+                    .map_or(0, |p| self.doc.blob[p.value] & 0x0f);
+            }
+        } else {
+            self.i = i + n + sz;
+        }
+        if self.container == ARRAY {
+            if let Some(parent) = self.parents.last_mut() {
+                parent.key += 1;
+            }
+        }
+        self.rowid += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(name: &str, args: &[&str]) -> String {
+        let args: Vec<_> = args
+            .iter()
+            .map(|a| Operand::new(SqlValue::from(*a)))
+            .collect();
+        call(name, &args).unwrap().0.to_text().unwrap()
+    }
+
+    fn jsonb(text: &str) -> Vec<u8> {
+        parse_text(text.as_bytes()).unwrap().0
+    }
+
+    #[test]
+    fn test_parse_and_render() {
+        assert_eq!(
+            json("json", &[" [1, 2.5 ,\"x\", null,true] "]),
+            "[1,2.5,\"x\",null,true]"
+        );
+        assert_eq!(
+            json(
+                "json",
+                &["[1.0,1e5,.5,0x1F,Infinity,-Infinity,NaN,'x',{a:1,}]"]
+            ),
+            "[1.0,1e5,0.5,31,9e999,-9e999,null,\"x\",{\"a\":1}]"
+        );
+        assert_eq!(
+            json("json", &["\"\\v\\x41\\0\""]),
+            "\"\\u000b\\u0041\\u0000\""
+        );
+        assert_eq!(json("json", &["/* c */ {\"a\" : [] } // c"]), "{\"a\":[]}");
+        assert!(parse_text(b"[1,]x").is_none());
+        assert!(parse_text(b"01").is_none());
+        assert!(parse_text(b"").is_none());
+        assert!(parse_text(b"{\"a\" 1}").is_none());
+    }
+
+    #[test]
+    fn test_jsonb_encoding() {
+        assert_eq!(
+            jsonb("{\"a\":[1,2.5,\"x\",null,true]}"),
+            b"\xcc\x0d\x17a\xab\x131\x352.5\x17x\x00\x01"
+        );
+        assert_eq!(jsonb("\"\\v\""), b"\x29\\v");
+        let long = format!("[{}]", vec!["1"; 200].join(","));
+        let blob = jsonb(&long);
+        assert_eq!(&blob[..3], b"\xdb\x01\x90");
+        assert!(is_jsonb(&blob));
+        assert_eq!(render_text(&blob, 0).unwrap(), long);
+        assert!(!is_jsonb(b"[1]"));
+        assert!(!is_jsonb(b""));
+    }
+
+    #[test]
+    fn test_paths_and_edits() {
+        let doc = "{\"a\":{\"b\":[1,2,3]},\"c d\":4}";
+        assert_eq!(json("json_extract", &[doc, "$.a.b[#-1]"]), "3");
+        assert_eq!(json("json_extract", &[doc, "$.\"c d\"", "$.x"]), "[4,null]");
+        assert_eq!(json("->", &[doc, "a"]), "{\"b\":[1,2,3]}");
+        assert_eq!(json("->>", &[doc, "c d"]), "4");
+        assert_eq!(
+            json("json_set", &[doc, "$.a.b[#]", "x", "$.e[0].f", "y"]),
+            "{\"a\":{\"b\":[1,2,3,\"x\"]},\"c d\":4,\"e\":[{\"f\":\"y\"}]}"
+        );
+        assert_eq!(json("json_insert", &[doc, "$.a", "x"]), doc);
+        assert_eq!(
+            json("json_replace", &[doc, "$.a", "x", "$.z", "y"]),
+            "{\"a\":\"x\",\"c d\":4}"
+        );
+        assert_eq!(
+            json("json_remove", &[doc, "$.a.b[1]", "$.\"c d\""]),
+            "{\"a\":{\"b\":[1,3]}}"
+        );
+        let args = [
+            Operand::new(SqlValue::from(doc)),
+            Operand::new(SqlValue::from("a")),
+        ];
+        assert_eq!(
+            call("json_extract", &args).unwrap_err().to_string(),
+            "bad JSON path: 'a'"
+        );
+    }
+
+    #[test]
+    fn test_json_each_and_json_tree() {
+        let rows = |f: TableFunction, args: &[&str]| -> Vec<String> {
+            let args: Vec<_> = args.iter().map(|a| SqlValue::from(*a)).collect();
+            f.rows(&args)
+                .unwrap()
+                .into_iter()
+                .map(|(_, row)| {
+                    let row: Vec<_> = row
+                        .iter()
+                        .map(|v| v.to_text().unwrap_or_default())
+                        .collect();
+                    row.join("|")
+                })
+                .collect()
+        };
+        assert_eq!(
+            rows(TableFunction::Each, &["[1,{\"a\":[2]}]"]),
+            ["0|1|integer|1|1||$[0]|$", "1|{\"a\":[2]}|object||3||$[1]|$"]
+        );
+        assert_eq!(
+            rows(TableFunction::Tree, &["[1,{\"a b\":[2]}]"]),
+            [
+                "|[1,{\"a b\":[2]}]|array||0||$|$",
+                "0|1|integer|1|1|0|$[0]|$",
+                "1|{\"a b\":[2]}|object||3|0|$[1]|$",
+                "a b|[2]|array||4|3|$[1].\"a b\"|$[1]",
+                "0|2|integer|2|9|4|$[1].\"a b\"[0]|$[1].\"a b\"",
+            ]
+        );
+        assert_eq!(
+            rows(TableFunction::Tree, &["{\"a\":{\"x\":0,\"b\":1}}", "$.a.b"]),
+            ["a.b|1|integer|1|8||$.a.b|$"]
+        );
+    }
+}
@@ -1,16 +1,60 @@
+pub mod aggregate;
+pub mod analyzer;
+pub mod btree;
+pub mod connection;
+pub mod datetime;
 pub mod db_page;
+#[cfg(feature = "serde")]
+pub mod de;
+pub mod executor;
+pub mod expr;
+pub mod json;
+pub mod pager;
+pub mod planner;
+pub mod printf;
 pub mod record;
+pub mod scalar;
+pub mod schema;
+pub mod sorter;
 pub mod sql_parser;
 pub mod util;
 pub mod value;
 pub mod varint;
+pub mod vdbe;
+pub mod window;
+
+// lets the code of the derive macros name this crate from inside it too
+extern crate self as rsqlite;
+
+pub use connection::{
+    Connection, FromRow, MappedRows, Params, Row, RowIndex, Rows, Statement, ToRow, TypedRows,
+};
+#[cfg(feature = "derive")]
+pub use rsqlite_derive::{FromRow, ToRow};
+pub use value::SqlValue;
 
 #[cfg(test)]
 mod tests {
+    use crate::aggregate::Aggregate;
+    use crate::analyzer;
+    use crate::analyzer::PageKind;
+    use crate::connection::{Connection, Statement};
     use crate::db_page;
+    use crate::executor::{Executor, ResultSet};
+    use crate::pager::Pager;
+    use crate::planner::QueryPlan;
+    use crate::scalar::FunctionFlags;
+    use crate::schema::Schema;
+    use crate::sql_parser;
+    use crate::value::{format_real, SqlValue};
+    use crate::{named_params, params};
+    use std::cell::Cell;
     use std::fs::File;
+    use std::io;
     use std::io::Read;
     use std::path;
+    use std::process::Command;
+    use std::rc::Rc;
 
     fn get_test_db_file_path() -> path::PathBuf {
         path::PathBuf::from("test/sql/chinbook.db")
@@ -22,14 +66,126 @@ mod tests {
 
     fn get_test_db_file() -> File {
         let path = get_test_db_file_path();
-        let f = File::open(&path).unwrap();
-        f
+
+        File::open(&path).unwrap()
+    }
+
+    fn get_without_rowid_db_file_path() -> path::PathBuf {
+        path::PathBuf::from("test/sql/without_rowid.db")
+    }
+
+    fn run_query(path: path::PathBuf, sql: &str) -> ResultSet {
+        let pager = Pager::open(path).unwrap();
+        let schema = Schema::read(&pager).unwrap();
+        let statement = &sql_parser::parse_sql(sql).unwrap()[0];
+        Executor::new(&pager, &schema).execute(statement).unwrap()
+    }
+
+    fn query_error(path: path::PathBuf, sql: &str) -> String {
+        let pager = Pager::open(path).unwrap();
+        let schema = Schema::read(&pager).unwrap();
+        let statement = &sql_parser::parse_sql(sql).unwrap()[0];
+        Executor::new(&pager, &schema)
+            .execute(statement)
+            .unwrap_err()
+            .to_string()
+    }
+
+    // The rows the sqlite3 shell prints for `sql`, or None when it is not
+    // installed.
+    fn sqlite3_rows(path: path::PathBuf, sql: &str) -> Option<Vec<String>> {
+        let output = Command::new("sqlite3").arg(path).arg(sql).output().ok()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Some(stdout.lines().map(String::from).collect())
+    }
+
+    // Renders rows the way the sqlite3 shell prints them by default.
+    fn shell_rows(result: &ResultSet) -> Vec<String> {
+        result
+            .rows
+            .iter()
+            .map(|row| {
+                let values: Vec<String> = row
+                    .iter()
+                    .map(|v| v.to_text().unwrap_or_default())
+                    .collect();
+                values.join("|")
+            })
+            .collect()
+    }
+
+    fn text(v: &SqlValue) -> &str {
+        v.as_str().unwrap()
+    }
+
+    // A database in the temporary directory for one test, removed with its
+    // journal when dropped, even if an assertion of the test fails.
+    struct ScratchDb {
+        path: path::PathBuf,
+    }
+
+    impl ScratchDb {
+        fn path_for(name: &str) -> path::PathBuf {
+            std::env::temp_dir().join(format!("rsqlite-test-{}-{}.db", std::process::id(), name))
+        }
+
+        // A database built by running `sql` with sqlite3, or None when
+        // sqlite3 is not installed.
+        fn create(name: &str, sql: &str) -> Option<ScratchDb> {
+            let db = ScratchDb {
+                path: ScratchDb::path_for(name),
+            };
+            let _ = std::fs::remove_file(&db.path);
+            sqlite3_rows(db.path(), sql)?;
+            Some(db)
+        }
+
+        // A copy of a test database that the test may change.
+        fn copy(path: path::PathBuf, name: &str) -> ScratchDb {
+            let db = ScratchDb {
+                path: ScratchDb::path_for(name),
+            };
+            std::fs::copy(path, &db.path).unwrap();
+            db
+        }
+
+        fn path(&self) -> path::PathBuf {
+            self.path.clone()
+        }
+
+        // Checks that each query gives the rows sqlite3 gives, in the same
+        // order.
+        fn assert_queries_match(&self, queries: &[&str]) {
+            for sql in queries {
+                let expected = sqlite3_rows(self.path(), sql).unwrap();
+                assert_eq!(
+                    shell_rows(&run_query(self.path(), sql)),
+                    expected,
+                    "{}",
+                    sql
+                );
+            }
+        }
+    }
+
+    impl Drop for ScratchDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+            let mut journal = self.path.clone().into_os_string();
+            journal.push("-journal");
+            let _ = std::fs::remove_file(journal);
+        }
     }
 
     fn get_simple_db_file() -> File {
         let path = get_simple_db_file_path();
-        let f = File::open(&path).unwrap();
-        f
+
+        File::open(&path).unwrap()
     }
 
     #[test]
@@ -50,6 +206,2561 @@ mod tests {
         assert_eq!(header.size_of_db_in_pages, 224);
     }
 
+    #[test]
+    fn test_read_schema() {
+        let pager = Pager::open(get_test_db_file_path()).unwrap();
+        let schema = Schema::read(&pager).unwrap();
+        let track = schema.table("track").unwrap();
+        assert_eq!(track.root_page, 13);
+        assert_eq!(track.rowid_alias, Some(0));
+        let playlist_track = schema.table("PlaylistTrack").unwrap();
+        assert_eq!(playlist_track.rowid_alias, None);
+        let autoindex: Vec<_> = schema
+            .indexes_of("PlaylistTrack")
+            .filter(|i| i.name == "sqlite_autoindex_PlaylistTrack_1")
+            .collect();
+        assert_eq!(autoindex.len(), 1);
+        assert_eq!(autoindex[0].columns.len(), 2);
+    }
+
+    #[test]
+    fn test_select_by_rowid_alias() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT AlbumId, Title FROM Album WHERE AlbumId = 5",
+        );
+        assert_eq!(result.columns, vec!["AlbumId", "Title"]);
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0][0].as_i64(), Some(5));
+        assert_eq!(text(&result.rows[0][1]), "Big Ones");
+    }
+
+    #[test]
+    fn test_without_rowid_schema() {
+        let pager = Pager::open(get_without_rowid_db_file_path()).unwrap();
+        let schema = Schema::read(&pager).unwrap();
+        assert!(schema.table("country").unwrap().without_rowid);
+        assert!(schema.table("word").unwrap().without_rowid);
+        assert!(!schema.table("note").unwrap().without_rowid);
+        assert_eq!(schema.indexes.len(), 0);
+    }
+
+    #[test]
+    fn test_scan_without_rowid_table() {
+        let result = run_query(get_without_rowid_db_file_path(), "SELECT * FROM country");
+        assert_eq!(result.columns, vec!["code", "name", "population"]);
+        let codes: Vec<&str> = result.rows.iter().map(|r| text(&r[0])).collect();
+        assert_eq!(codes, vec!["BR", "DE", "FR", "JP"]);
+        assert_eq!(text(&result.rows[1][1]), "Germany");
+        assert!(result.rows[0][2].is_null());
+
+        let result = run_query(get_without_rowid_db_file_path(), "SELECT * FROM word");
+        assert_eq!(result.rows.len(), 3000);
+        // declared column order, not the stored (word, lang, pos) order
+        assert_eq!(text(&result.rows[0][0]), "fr");
+        assert_eq!(result.rows[0][1].as_i64(), Some(1));
+        assert_eq!(text(&result.rows[0][2]), "w00001");
+    }
+
+    #[test]
+    fn test_seek_without_rowid_table() {
+        let result = run_query(
+            get_without_rowid_db_file_path(),
+            "SELECT pos FROM word WHERE lang = 'en' AND word = 'w01500'",
+        );
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0][0].as_i64(), Some(1500));
+
+        let result = run_query(
+            get_without_rowid_db_file_path(),
+            "SELECT lang FROM word WHERE word = 'w02999'",
+        );
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(text(&result.rows[0][0]), "de");
+
+        let result = run_query(
+            get_without_rowid_db_file_path(),
+            "SELECT * FROM word WHERE word = 'nope'",
+        );
+        assert_eq!(result.rows.len(), 0);
+    }
+
+    #[test]
+    fn test_overflow_pages() {
+        let result = run_query(
+            get_without_rowid_db_file_path(),
+            "SELECT id, body FROM note",
+        );
+        let ids: Vec<i64> = result.rows.iter().map(|r| r[0].as_i64().unwrap()).collect();
+        assert_eq!(ids, vec![-3, 1, 2]);
+        let body = text(&result.rows[2][1]);
+        assert_eq!(body.len(), 8000);
+        assert!(body.starts_with("00010002"));
+        assert!(body.ends_with("19992000"));
+    }
+
+    #[test]
+    fn test_where_expressions() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT TrackId FROM Track WHERE Milliseconds BETWEEN 200000 AND 300000 \
+             AND (Composer LIKE '%angus%' OR Composer IS NULL)",
+        );
+        assert_eq!(result.rows.len(), 433);
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT * FROM Customer WHERE Company IS NULL AND Country IN ('USA', 'Canada')",
+        );
+        assert_eq!(result.rows.len(), 16);
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT * FROM Customer WHERE State = 'ca'",
+        );
+        assert_eq!(result.rows.len(), 0);
+
+        // the INTEGER affinity of the columns converts the text literals
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT TrackId FROM Track WHERE TrackId = '3'",
+        );
+        assert_eq!(result.rows.len(), 1);
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT t.TrackId FROM Track t WHERE t.AlbumId = '1'",
+        );
+        assert_eq!(result.rows.len(), 10);
+    }
+
+    #[test]
+    fn test_projection_expressions() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT Name, UnitPrice * 100, Milliseconds / 1000 AS seconds FROM Track \
+             WHERE TrackId = 3",
+        );
+        assert_eq!(result.columns, vec!["Name", "UnitPrice * 100", "seconds"]);
+        assert_eq!(text(&result.rows[0][0]), "Fast As a Shark");
+        assert_eq!(result.rows[0][1].as_f64(), Some(99.0));
+        assert_eq!(result.rows[0][2].as_i64(), Some(230));
+    }
+
+    fn column_text(result: &ResultSet, column: usize) -> Vec<&str> {
+        result.rows.iter().map(|r| text(&r[column])).collect()
+    }
+
+    fn column_i64(result: &ResultSet, column: usize) -> Vec<Option<i64>> {
+        result.rows.iter().map(|r| r[column].as_i64()).collect()
+    }
+
+    #[test]
+    fn test_order_by() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT Name, Composer FROM Track ORDER BY Composer DESC NULLS LAST, Name \
+             LIMIT 3 OFFSET 2",
+        );
+        assert_eq!(
+            column_text(&result, 0),
+            vec!["One Man's Meat", "Ramshackle Man", "Solitaire"]
+        );
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT FirstName, LastName FROM Customer ORDER BY 2 LIMIT 3",
+        );
+        assert_eq!(
+            column_text(&result, 1),
+            vec!["Almeida", "Barnett", "Bernard"]
+        );
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT Title t FROM Album ORDER BY t COLLATE NOCASE DESC LIMIT 2",
+        );
+        assert_eq!(column_text(&result, 0), vec!["Zooropa", "Worlds"]);
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT TrackId FROM Track WHERE AlbumId = 5 ORDER BY Milliseconds DESC LIMIT 4",
+        );
+        assert_eq!(
+            column_i64(&result, 0),
+            vec![Some(37), Some(30), Some(28), Some(24)]
+        );
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT TrackId, UnitPrice FROM Track ORDER BY UnitPrice DESC, TrackId LIMIT 2",
+        );
+        assert_eq!(column_i64(&result, 0), vec![Some(2819), Some(2820)]);
+    }
+
+    #[test]
+    fn test_order_by_index() {
+        // IFK_TrackAlbumId provides the order, ties stay in rowid order
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT AlbumId, TrackId FROM Track ORDER BY AlbumId LIMIT 3 OFFSET 14",
+        );
+        assert_eq!(column_i64(&result, 1), vec![Some(15), Some(16), Some(17)]);
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT ReportsTo, EmployeeId FROM Employee ORDER BY ReportsTo",
+        );
+        assert_eq!(
+            column_i64(&result, 0),
+            vec![
+                None,
+                Some(1),
+                Some(1),
+                Some(2),
+                Some(2),
+                Some(2),
+                Some(6),
+                Some(6)
+            ]
+        );
+        assert_eq!(column_i64(&result, 1)[..3], [Some(1), Some(2), Some(6)]);
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT AlbumId FROM Track ORDER BY AlbumId DESC LIMIT 1",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(347));
+    }
+
+    #[test]
+    fn test_order_by_primary_key_and_external_sort() {
+        let pager = Pager::open(get_without_rowid_db_file_path()).unwrap();
+        let schema = Schema::read(&pager).unwrap();
+        let mut executor = Executor::new(&pager, &schema);
+        let query = |executor: &Executor, sql: &str| {
+            executor
+                .execute(&sql_parser::parse_sql(sql).unwrap()[0])
+                .unwrap()
+        };
+
+        let result = query(&executor, "SELECT word FROM word ORDER BY word LIMIT 2");
+        assert_eq!(column_text(&result, 0), vec!["w00001", "w00002"]);
+        let result = query(
+            &executor,
+            "SELECT word FROM word WHERE word > 'w02990' ORDER BY word DESC LIMIT 2",
+        );
+        assert_eq!(column_text(&result, 0), vec!["w03000", "w02999"]);
+
+        // a small budget forces the sort to spill runs to disk
+        executor.set_sort_budget(4096);
+        let result = query(
+            &executor,
+            "SELECT pos, lang FROM word ORDER BY lang, pos DESC",
+        );
+        assert_eq!(result.rows.len(), 3000);
+        assert_eq!(text(&result.rows[0][1]), "de");
+        assert_eq!(result.rows[0][0].as_i64(), Some(2999));
+        assert_eq!(text(&result.rows[2999][1]), "fr");
+        assert_eq!(result.rows[2999][0].as_i64(), Some(1));
+
+        // and so does a small budget set on a connection
+        let mut connection = Connection::open(get_without_rowid_db_file_path()).unwrap();
+        connection.set_sort_budget(4096);
+        let mut rows = connection
+            .query("SELECT pos, lang FROM word ORDER BY lang, pos DESC", ())
+            .unwrap();
+        let first = rows.next().unwrap().unwrap();
+        assert_eq!(first.get::<_, String>(1).unwrap(), "de");
+        assert_eq!(first.get::<_, i64>(0).unwrap(), 2999);
+        assert_eq!(rows.count(), 2999);
+    }
+
+    fn real_text(v: &SqlValue) -> String {
+        format_real(v.as_f64().unwrap())
+    }
+
+    #[test]
+    fn test_group_by_having() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT GenreId, COUNT(*), AVG(Milliseconds) FROM Track GROUP BY GenreId \
+             HAVING COUNT(*) > 100",
+        );
+        assert_eq!(
+            column_i64(&result, 0),
+            vec![Some(1), Some(2), Some(3), Some(4), Some(7)]
+        );
+        assert_eq!(result.rows[0][1].as_i64(), Some(1297));
+        assert_eq!(real_text(&result.rows[0][2]), "283910.043176561");
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT Country, count(*) c FROM Customer GROUP BY 1 ORDER BY c DESC, Country LIMIT 3",
+        );
+        assert_eq!(column_text(&result, 0), vec!["USA", "Canada", "Brazil"]);
+        assert_eq!(column_i64(&result, 1), vec![Some(13), Some(8), Some(5)]);
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT BillingCountry, sum(Total) FROM Invoice GROUP BY BillingCountry \
+             ORDER BY 2 DESC LIMIT 2",
+        );
+        assert_eq!(column_text(&result, 0), vec!["USA", "Canada"]);
+        assert_eq!(real_text(&result.rows[0][1]), "523.06");
+
+        // NULL forms a group of its own
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT State, count(*) FROM Customer GROUP BY State LIMIT 2",
+        );
+        assert!(result.rows[0][0].is_null());
+        assert_eq!(column_i64(&result, 1), vec![Some(29), Some(1)]);
+    }
+
+    #[test]
+    fn test_aggregates() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*), count(Composer), count(DISTINCT Composer), sum(Milliseconds), \
+             total(UnitPrice), min(Name), max(Name) FROM Track",
+        );
+        let row = &result.rows[0];
+        assert_eq!(row[0].as_i64(), Some(3503));
+        assert_eq!(row[1].as_i64(), Some(2525));
+        assert_eq!(row[2].as_i64(), Some(852));
+        assert_eq!(row[3].as_i64(), Some(1378778040));
+        assert_eq!(real_text(&row[4]), "3680.97");
+        assert_eq!(text(&row[5]), "\"40\"");
+        assert_eq!(text(&row[6]), "Último Pau-De-Arara");
+
+        // bare columns come from the row max() picked
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT max(Total), InvoiceId, CustomerId FROM Invoice",
+        );
+        assert_eq!(real_text(&result.rows[0][0]), "25.86");
+        assert_eq!(result.rows[0][1].as_i64(), Some(404));
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*), sum(GenreId) FROM Track WHERE 0",
+        );
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0][0].as_i64(), Some(0));
+        assert!(result.rows[0][1].is_null());
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT group_concat(Name, '|') FROM Genre WHERE GenreId < 4",
+        );
+        assert_eq!(text(&result.rows[0][0]), "Rock|Jazz|Metal");
+    }
+
+    #[test]
+    fn test_count_star_fast_path() {
+        let result = run_query(get_test_db_file_path(), "SELECT count(*) FROM Track");
+        assert_eq!(result.rows[0][0].as_i64(), Some(3503));
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT COUNT(*) FROM PlaylistTrack",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(8715));
+        // WITHOUT ROWID tables keep entries in interior pages as well
+        let result = run_query(
+            get_without_rowid_db_file_path(),
+            "SELECT count(*) FROM word",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(3000));
+    }
+
+    #[test]
+    fn test_inner_and_cross_joins() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT t.Name, al.Title, ar.Name FROM Track t \
+             JOIN Album al ON t.AlbumId = al.AlbumId \
+             JOIN Artist ar ON ar.ArtistId = al.ArtistId \
+             WHERE t.TrackId IN (1, 100, 3000) ORDER BY t.TrackId",
+        );
+        assert_eq!(
+            column_text(&result, 0),
+            vec![
+                "For Those About To Rock (We Salute You)",
+                "Out Of Exile",
+                "God Part II"
+            ]
+        );
+        assert_eq!(column_text(&result, 2), vec!["AC/DC", "Audioslave", "U2"]);
+
+        // comma joins take their join condition from WHERE
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*) FROM Artist, Album WHERE Artist.ArtistId = Album.ArtistId",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(347));
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*) FROM Genre CROSS JOIN MediaType",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(125));
+
+        // the inner tables are reached through IFK_InvoiceLineTrackId and
+        // the Track rowid rather than by scanning them
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*) FROM Track t JOIN InvoiceLine il ON il.TrackId = t.TrackId",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(2240));
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*) FROM InvoiceLine il, Track t \
+             WHERE t.TrackId = il.TrackId AND t.GenreId = 1",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(835));
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*), sum(il.UnitPrice * il.Quantity) FROM Invoice i \
+             JOIN InvoiceLine il ON il.InvoiceId = i.InvoiceId \
+             JOIN Customer c ON c.CustomerId = i.CustomerId WHERE c.Country = 'Brazil'",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(190));
+        assert_eq!(real_text(&result.rows[0][1]), "190.1");
+
+        // the inner join's ON clause may use a table to its right
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*) FROM Playlist p JOIN PlaylistTrack pt \
+             ON pt.PlaylistId = p.PlaylistId AND t.TrackId = pt.TrackId \
+             JOIN Track t WHERE p.Name = 'Music'",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(6580));
+
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "SELECT ArtistId FROM Album, Artist"
+            ),
+            "ambiguous column name: ArtistId"
+        );
+    }
+
+    #[test]
+    fn test_left_joins() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*) FROM Artist LEFT JOIN Album USING (ArtistId) \
+             WHERE Album.AlbumId IS NULL",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(71));
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*) FROM Artist LEFT OUTER JOIN Album USING (ArtistId)",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(418));
+
+        // WHERE applies after the NULL rows are added
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*) FROM Artist LEFT JOIN Album \
+             ON Album.ArtistId = Artist.ArtistId WHERE Album.Title LIKE 'A%'",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(32));
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT e.FirstName, m.FirstName FROM Employee e \
+             LEFT JOIN Employee m ON e.ReportsTo = m.EmployeeId ORDER BY e.EmployeeId LIMIT 3",
+        );
+        assert_eq!(column_text(&result, 0), vec!["Andrew", "Nancy", "Jane"]);
+        assert!(result.rows[0][1].is_null());
+        assert_eq!(text(&result.rows[2][1]), "Nancy");
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT g.Name, count(t.TrackId) FROM Genre g LEFT JOIN Track t \
+             ON t.GenreId = g.GenreId AND t.Milliseconds > 1000000 \
+             GROUP BY g.GenreId ORDER BY 2 DESC, 1 LIMIT 4",
+        );
+        assert_eq!(
+            column_text(&result, 0),
+            vec!["TV Shows", "Drama", "Sci Fi & Fantasy", "Comedy"]
+        );
+        assert_eq!(
+            column_i64(&result, 1),
+            vec![Some(93), Some(62), Some(26), Some(17)]
+        );
+
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "SELECT count(*) FROM Album a LEFT JOIN Artist b \
+                 ON b.ArtistId = c.ArtistId JOIN Artist c"
+            ),
+            "ON clause references tables to its right"
+        );
+    }
+
+    #[test]
+    fn test_using_and_natural_joins() {
+        // the USING column appears once in `*` and needs no qualifier
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT * FROM Album JOIN Artist USING (ArtistId) WHERE AlbumId = 1",
+        );
+        assert_eq!(result.columns, vec!["AlbumId", "Title", "ArtistId", "Name"]);
+        assert_eq!(text(&result.rows[0][3]), "AC/DC");
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT ArtistId, Title FROM Album JOIN Artist USING (ArtistId) WHERE AlbumId = 1",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(1));
+
+        // Album and Artist only share ArtistId; Name is Artist's alone
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT Title, Name FROM Album NATURAL JOIN Artist ORDER BY AlbumId LIMIT 2",
+        );
+        assert_eq!(column_text(&result, 1), vec!["AC/DC", "Accept"]);
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT a.* FROM Artist a JOIN Album b ON a.ArtistId = b.ArtistId WHERE b.AlbumId = 1",
+        );
+        assert_eq!(result.columns, vec!["ArtistId", "Name"]);
+        assert_eq!(text(&result.rows[0][1]), "AC/DC");
+
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "SELECT * FROM Album JOIN Genre USING (ArtistId)"
+            ),
+            "cannot join using column ArtistId - column not present in both tables"
+        );
+    }
+
+    #[test]
+    fn test_scalar_and_correlated_subqueries() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT Name, (SELECT count(*) FROM Album WHERE Album.ArtistId = Artist.ArtistId) \
+             AS albums FROM Artist ORDER BY albums DESC, Name LIMIT 3",
+        );
+        assert_eq!(
+            column_text(&result, 0),
+            vec!["Iron Maiden", "Led Zeppelin", "Deep Purple"]
+        );
+        assert_eq!(column_i64(&result, 1), vec![Some(21), Some(14), Some(11)]);
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT c.FirstName, (SELECT sum(Total) FROM Invoice i \
+             WHERE i.CustomerId = c.CustomerId) FROM Customer c WHERE c.CustomerId <= 2",
+        );
+        assert_eq!(column_text(&result, 0), vec!["Luís", "Leonie"]);
+        assert_eq!(real_text(&result.rows[1][1]), "37.62");
+
+        // a subquery without rows is NULL, and SELECT needs no FROM
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT (SELECT max(Milliseconds) FROM Track), \
+             (SELECT Name FROM Genre WHERE GenreId = 99), (SELECT 1 + 1)",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(5286953));
+        assert!(result.rows[0][1].is_null());
+        assert_eq!(result.rows[0][2].as_i64(), Some(2));
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT Name FROM Track WHERE Milliseconds = (SELECT max(Milliseconds) FROM Track)",
+        );
+        assert_eq!(column_text(&result, 0), vec!["Occupation / Precipice"]);
+
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "SELECT (SELECT AlbumId, Title FROM Album)"
+            ),
+            "sub-select returns 2 columns - expected 1"
+        );
+    }
+
+    #[test]
+    fn test_in_and_exists_subqueries() {
+        let count = |sql: &str| run_query(get_test_db_file_path(), sql).rows[0][0].as_i64();
+        assert_eq!(
+            count("SELECT count(*) FROM Artist WHERE ArtistId IN (SELECT ArtistId FROM Album)"),
+            Some(204)
+        );
+        assert_eq!(
+            count("SELECT count(*) FROM Artist WHERE ArtistId NOT IN (SELECT ArtistId FROM Album)"),
+            Some(71)
+        );
+        // one Brazilian customer has no company, so NOT IN is never true
+        assert_eq!(
+            count(
+                "SELECT count(*) FROM Customer WHERE Company NOT IN \
+                 (SELECT Company FROM Customer WHERE Country = 'Brazil')"
+            ),
+            Some(0)
+        );
+        assert_eq!(
+            count(
+                "SELECT count(*) FROM Customer WHERE Company IN \
+                 (SELECT Company FROM Customer WHERE Country = 'Brazil')"
+            ),
+            Some(4)
+        );
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT NULL IN (SELECT 1 WHERE 0), NULL IN (SELECT 1), 2 NOT IN (SELECT NULL)",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(0));
+        assert!(result.rows[0][1].is_null());
+        assert!(result.rows[0][2].is_null());
+
+        assert_eq!(
+            count(
+                "SELECT count(*) FROM Genre g WHERE EXISTS (SELECT 1 FROM Track t \
+                 WHERE t.GenreId = g.GenreId AND t.Milliseconds > 2000000)"
+            ),
+            Some(5)
+        );
+        assert_eq!(
+            count(
+                "SELECT count(*) FROM Genre g WHERE NOT EXISTS \
+                 (SELECT * FROM Track t WHERE t.GenreId = g.GenreId)"
+            ),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_derived_tables() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT t.c, count(*) FROM (SELECT AlbumId, count(*) AS c FROM Track \
+             GROUP BY AlbumId) AS t GROUP BY t.c ORDER BY 2 DESC LIMIT 3",
+        );
+        assert_eq!(column_i64(&result, 0), vec![Some(1), Some(14), Some(12)]);
+        assert_eq!(column_i64(&result, 1), vec![Some(82), Some(34), Some(29)]);
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT a.Title, s.n FROM Album a JOIN (SELECT AlbumId, count(*) n FROM Track \
+             GROUP BY AlbumId) s ON s.AlbumId = a.AlbumId WHERE a.AlbumId < 4 ORDER BY a.AlbumId",
+        );
+        assert_eq!(column_i64(&result, 1), vec![Some(10), Some(1), Some(3)]);
+
+        // columns of a subquery keep the affinity of their expression
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(*) FROM (SELECT CAST(TrackId AS TEXT) AS id FROM Track) WHERE id = 10",
+        );
+        assert_eq!(result.rows[0][0].as_i64(), Some(1));
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT x, y FROM (SELECT GenreId, Name FROM Genre WHERE GenreId < 3) AS g(x, y)",
+        );
+        assert_eq!(column_text(&result, 1), vec!["Rock", "Jazz"]);
+    }
+
+    #[test]
+    fn test_common_table_expressions() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "WITH big AS (SELECT GenreId, count(*) AS n FROM Track GROUP BY GenreId), \
+             top AS (SELECT max(n) AS m FROM big) \
+             SELECT g.Name, big.n FROM big JOIN top ON big.n = top.m JOIN Genre g USING (GenreId)",
+        );
+        assert_eq!(column_text(&result, 0), vec!["Rock"]);
+        assert_eq!(column_i64(&result, 1), vec![Some(1297)]);
+
+        // a CTE used twice, with hints either way
+        for hint in &["", "MATERIALIZED", "NOT MATERIALIZED"] {
+            let result = run_query(
+                get_test_db_file_path(),
+                &format!(
+                    "WITH x(v) AS {} (SELECT 5) SELECT (SELECT v FROM x) + v FROM x",
+                    hint
+                ),
+            );
+            assert_eq!(result.rows[0][0].as_i64(), Some(10));
+        }
+
+        // a CTE may shadow a table of the schema
+        let result = run_query(
+            get_test_db_file_path(),
+            "WITH Genre AS (SELECT Name FROM Genre WHERE GenreId = 2) SELECT * FROM Genre",
+        );
+        assert_eq!(column_text(&result, 0), vec!["Jazz"]);
+
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "WITH a(x, y) AS (SELECT 1) SELECT * FROM a"
+            ),
+            "table a has 1 values for 2 columns"
+        );
+    }
+
+    #[test]
+    fn test_recursive_common_table_expressions() {
+        let org_chart = "WITH RECURSIVE chain(id, name, depth) AS (\
+             SELECT EmployeeId, FirstName, 0 FROM Employee WHERE ReportsTo IS NULL \
+             UNION ALL SELECT e.EmployeeId, e.FirstName, c.depth + 1 \
+             FROM Employee e JOIN chain c ON e.ReportsTo = c.id";
+        let result = run_query(
+            get_test_db_file_path(),
+            &format!(
+                "{}) SELECT name, depth FROM chain ORDER BY depth, id",
+                org_chart
+            ),
+        );
+        assert_eq!(
+            column_text(&result, 0),
+            vec!["Andrew", "Nancy", "Michael", "Jane", "Margaret", "Steve", "Robert", "Laura"]
+        );
+        assert_eq!(
+            column_i64(&result, 1),
+            vec![
+                Some(0),
+                Some(1),
+                Some(1),
+                Some(2),
+                Some(2),
+                Some(2),
+                Some(2),
+                Some(2)
+            ]
+        );
+
+        // ORDER BY in the CTE makes the queue depth first
+        let result = run_query(
+            get_test_db_file_path(),
+            &format!("{} ORDER BY 3 DESC) SELECT name FROM chain", org_chart),
+        );
+        assert_eq!(
+            column_text(&result, 0),
+            vec!["Andrew", "Nancy", "Jane", "Margaret", "Steve", "Michael", "Robert", "Laura"]
+        );
+
+        // LIMIT ends an otherwise endless recursion
+        let result = run_query(
+            get_test_db_file_path(),
+            "WITH RECURSIVE cnt(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM cnt \
+             LIMIT 3 OFFSET 2) SELECT x FROM cnt",
+        );
+        assert_eq!(column_i64(&result, 0), vec![Some(3), Some(4), Some(5)]);
+
+        // a table with no end of its own stops at the LIMIT of the query
+        // reading it
+        let result = run_query(
+            get_test_db_file_path(),
+            "WITH RECURSIVE r(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM r) \
+             SELECT n FROM r LIMIT 5",
+        );
+        assert_eq!(
+            column_i64(&result, 0),
+            vec![Some(1), Some(2), Some(3), Some(4), Some(5)]
+        );
+        let result = run_query(
+            get_test_db_file_path(),
+            "WITH RECURSIVE r(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM r LIMIT 10) \
+             SELECT n * 2 FROM r LIMIT 2 OFFSET 3",
+        );
+        assert_eq!(column_i64(&result, 0), vec![Some(8), Some(10)]);
+        // the rows reach the query as they are produced, so it can filter
+        // and join them and still stop
+        let result = run_query(
+            get_test_db_file_path(),
+            "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) \
+             SELECT x FROM c WHERE x > 3 LIMIT 2",
+        );
+        assert_eq!(column_i64(&result, 0), vec![Some(4), Some(5)]);
+        let result = run_query(
+            get_test_db_file_path(),
+            "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) \
+             SELECT DISTINCT g.Name FROM c JOIN Genre g ON g.GenreId = c.x % 2 + 1 LIMIT 2",
+        );
+        assert_eq!(column_text(&result, 0), vec!["Jazz", "Rock"]);
+
+        // and so does UNION, which drops rows already seen
+        let result = run_query(
+            get_test_db_file_path(),
+            "WITH RECURSIVE t(x) AS (SELECT 1 UNION SELECT x % 3 + 1 FROM t) SELECT x FROM t",
+        );
+        assert_eq!(column_i64(&result, 0), vec![Some(1), Some(2), Some(3)]);
+
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "WITH RECURSIVE t AS (SELECT * FROM t) SELECT * FROM t"
+            ),
+            "circular reference: t"
+        );
+    }
+
+    #[test]
+    fn test_compound_selects() {
+        // UNION drops duplicates, with 1 and 1.0 equal, and sorts the rest
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT 3 UNION SELECT 1 UNION SELECT 1.0 UNION SELECT '1'",
+        );
+        assert_eq!(
+            result.rows,
+            vec![
+                vec![SqlValue::Real(1.0)],
+                vec![SqlValue::Integer(3)],
+                vec![SqlValue::from("1")]
+            ]
+        );
+        // a collation on either side applies
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT 'a' UNION SELECT 'A' COLLATE NOCASE",
+        );
+        assert_eq!(column_text(&result, 0), vec!["A"]);
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT Name FROM Genre WHERE GenreId < 3 UNION ALL SELECT Name FROM MediaType \
+             WHERE MediaTypeId < 3 UNION ALL SELECT 'Jazz' ORDER BY Name DESC LIMIT 3 OFFSET 1",
+        );
+        assert_eq!(
+            column_text(&result, 0),
+            vec!["Protected AAC audio file", "MPEG audio file", "Jazz"]
+        );
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT GenreId FROM Genre EXCEPT SELECT GenreId FROM Track WHERE GenreId > 3",
+        );
+        assert_eq!(column_i64(&result, 0), vec![Some(1), Some(2), Some(3)]);
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT ArtistId FROM Album INTERSECT \
+             SELECT ArtistId FROM Artist WHERE Name LIKE 'A%' ORDER BY 1 DESC LIMIT 2",
+        );
+        assert_eq!(column_i64(&result, 0), vec![Some(260), Some(257)]);
+
+        // ORDER BY terms name result columns of any of the SELECTs
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT a.Title FROM Album a WHERE AlbumId < 3 UNION \
+             SELECT Name FROM Artist WHERE ArtistId < 3 ORDER BY Name COLLATE NOCASE",
+        );
+        assert_eq!(
+            column_text(&result, 0),
+            vec![
+                "AC/DC",
+                "Accept",
+                "Balls to the Wall",
+                "For Those About To Rock We Salute You"
+            ]
+        );
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT * FROM (VALUES (1, 'a'), (2, 'b') UNION SELECT 0, 'z') ORDER BY column2",
+        );
+        assert_eq!(column_i64(&result, 0), vec![Some(1), Some(2), Some(0)]);
+
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "SELECT 1 UNION ALL SELECT 2, 3"
+            ),
+            "SELECTs to the left and right of UNION ALL do not have the same number of result columns"
+        );
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "SELECT 1 AS x UNION SELECT 2 AS y ORDER BY z"
+            ),
+            "1st ORDER BY term does not match any column in the result set"
+        );
+    }
+
+    #[test]
+    fn test_window_functions() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT InvoiceId, row_number() OVER w, count(*) OVER w, lag(Total) OVER w, \
+             lead(Total, 2, 0) OVER w FROM Invoice WHERE CustomerId = 2 \
+             WINDOW w AS (ORDER BY InvoiceDate)",
+        );
+        assert_eq!(
+            column_i64(&result, 0),
+            vec![
+                Some(1),
+                Some(12),
+                Some(67),
+                Some(196),
+                Some(219),
+                Some(241),
+                Some(293)
+            ]
+        );
+        assert_eq!(
+            column_i64(&result, 1),
+            (1..=7).map(Some).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            column_i64(&result, 2),
+            (1..=7).map(Some).collect::<Vec<_>>()
+        );
+        assert_eq!(result.rows[0][3], SqlValue::Null);
+        assert_eq!(result.rows[1][3], SqlValue::Real(1.98));
+        assert_eq!(result.rows[4][4], SqlValue::Real(0.99));
+        assert_eq!(result.rows[5][4], SqlValue::Integer(0));
+
+        // peers share their rank and, by default, their frame
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT Milliseconds / 60000, rank() OVER o, dense_rank() OVER o, ntile(3) OVER o, \
+             sum(1) OVER o, count(*) OVER (o RANGE BETWEEN 1 PRECEDING AND 1 FOLLOWING \
+             EXCLUDE GROUP) FROM Track WHERE AlbumId = 1 \
+             WINDOW o AS (ORDER BY Milliseconds / 60000)",
+        );
+        let columns: Vec<_> = (0..6).map(|i| column_i64(&result, i)).collect();
+        assert_eq!(columns[1], [1, 1, 1, 1, 1, 1, 7, 7, 7, 10].map(Some));
+        assert_eq!(columns[2], [1, 1, 1, 1, 1, 1, 2, 2, 2, 3].map(Some));
+        assert_eq!(columns[3], [1, 1, 1, 1, 2, 2, 2, 3, 3, 3].map(Some));
+        assert_eq!(columns[4], [6, 6, 6, 6, 6, 6, 9, 9, 9, 10].map(Some));
+        assert_eq!(columns[5], [3, 3, 3, 3, 3, 3, 7, 7, 7, 3].map(Some));
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT group_concat(GenreId, '') OVER (ORDER BY GenreId \
+             ROWS BETWEEN 1 PRECEDING AND 1 FOLLOWING EXCLUDE CURRENT ROW), \
+             first_value(GenreId) OVER (ORDER BY GenreId DESC ROWS 2 PRECEDING), \
+             nth_value(GenreId, 2) OVER (GROUPS BETWEEN CURRENT ROW AND UNBOUNDED FOLLOWING) \
+             FROM Genre WHERE GenreId < 6",
+        );
+        assert_eq!(column_text(&result, 0), vec!["2", "13", "24", "35", "4"]);
+        assert_eq!(column_i64(&result, 1), [3, 4, 5, 5, 5].map(Some));
+        assert_eq!(column_i64(&result, 2), [2, 2, 2, 2, 2].map(Some));
+
+        // windows over groups see the aggregates of the group
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT BillingCountry, rank() OVER (ORDER BY sum(Total) DESC) FROM Invoice \
+             GROUP BY BillingCountry ORDER BY 2 LIMIT 3",
+        );
+        assert_eq!(column_text(&result, 0), vec!["USA", "Canada", "France"]);
+
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "SELECT GenreId FROM Genre WHERE row_number() OVER () > 2"
+            ),
+            "misuse of window function row_number()"
+        );
+        assert_eq!(
+            query_error(get_test_db_file_path(), "SELECT abs(1) OVER () FROM Genre"),
+            "abs() may not be used as a window function"
+        );
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "SELECT sum(GenreId) OVER (ORDER BY Name, GenreId RANGE 1 PRECEDING) FROM Genre"
+            ),
+            "RANGE with offset PRECEDING/FOLLOWING requires one ORDER BY expression"
+        );
+    }
+
+    #[test]
+    fn test_scalar_functions_match_sqlite3() {
+        let queries = [
+            "SELECT abs(Milliseconds - 300000), abs(-UnitPrice), abs(Composer), abs(NULL) \
+             FROM Track WHERE TrackId % 97 = 0",
+            "SELECT coalesce(Composer, 'unknown'), ifnull(Composer, GenreId), \
+             iif(Milliseconds > 300000, 'long', 'short'), \
+             iif(Bytes < 5000000, 1, Bytes < 9000000, 2, 3), iif(GenreId = 1, 'rock'), \
+             nullif(MediaTypeId, 1) FROM Track WHERE TrackId % 41 = 0",
+            "SELECT instr(Name, 'a'), instr(Name, ''), instr(Name, NULL), \
+             instr(CAST(Name AS BLOB), CAST('e' AS BLOB)), length(Name), length(ArtistId / 7.0), \
+             length(CAST(Name AS BLOB)), length(NULL) FROM Artist \
+             WHERE ArtistId % 53 = 0 OR glob('*[^ -~]*', Name)",
+            "SELECT lower(Name), upper(Name), ltrim(Name, 'TA'), rtrim(Name, 'se'), \
+             trim('  ' || Title || '  '), trim(Title, 'ATS'), ltrim(NULL) \
+             FROM Album JOIN Artist USING (ArtistId) WHERE AlbumId % 31 = 0",
+            "SELECT replace(Name, 'e', 'E'), replace(Name, '', 'x'), substr(Name, 3), \
+             substr(Name, -4, 2), substr(Name, 0, 3), substr(Name, 5, -3), \
+             substring(Name, -100, 102), hex(substr(CAST(Name AS BLOB), 2, 3)) \
+             FROM Artist WHERE ArtistId % 23 = 0",
+            "SELECT round(Total), round(Total, 1), round(Total * 1.015, 2), \
+             round(-Total / 3, 3), round(Total, NULL), round(BillingCity) \
+             FROM Invoice WHERE InvoiceId % 29 = 0",
+            "SELECT typeof(Composer), typeof(UnitPrice), hex(Name), hex(UnitPrice), hex(NULL), \
+             quote(Composer), quote(UnitPrice * 3), quote(Milliseconds / 7.0), \
+             quote(CAST(Name AS BLOB)), unhex(hex(Name)) = CAST(Name AS BLOB), \
+             unhex('4A-6f', '-'), unhex('4A6') FROM Track WHERE TrackId % 211 = 0",
+            "SELECT printf('%-20s|%5.1f|%08d|%x|%c', Name, UnitPrice, Milliseconds, Bytes, Name), \
+             format('%,d %.3e %g %q %Q %!.20g', Bytes, Milliseconds / 7.0, UnitPrice, Name, \
+             Composer, Bytes / 3.0), printf('%5.2s|%-+6d|%#o|%.0f', Name, GenreId, AlbumId, \
+             Milliseconds / 1000.0), printf(NULL), printf('%p|%#12p', Bytes, -Milliseconds) \
+             FROM Track WHERE TrackId % 113 = 0",
+            "SELECT char(unicode(Name), 65, 9786), unicode(Name), unicode('') \
+             FROM Artist WHERE ArtistId % 37 = 0",
+            "SELECT hex(zeroblob(GenreId % 4)), length(randomblob(GenreId)), typeof(random()), \
+             length(randomblob(-1)) FROM Genre",
+            "SELECT min(Milliseconds, Bytes, 250000), max(Name, Composer), max(UnitPrice, 1) \
+             FROM Track WHERE TrackId % 71 = 0",
+            "SELECT likelihood(Name, 0.5), likely(GenreId), unlikely(AlbumId), \
+             sign(Milliseconds - 250000), sign(Composer), sign(-UnitPrice), sign('-2.5') \
+             FROM Track WHERE TrackId % 89 = 0",
+        ];
+        for sql in &queries {
+            let expected = match sqlite3_rows(get_test_db_file_path(), sql) {
+                Some(rows) => rows,
+                None => return,
+            };
+            let result = run_query(get_test_db_file_path(), sql);
+            assert_eq!(shell_rows(&result), expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_date_functions_match_sqlite3() {
+        let queries = [
+            "SELECT strftime('%Y-%m', InvoiceDate) AS Month, count(*), sum(Total) \
+             FROM Invoice WHERE InvoiceDate >= date('2010-01-01', 'start of year') \
+             AND InvoiceDate < date('2010-01-01', '+6 months') GROUP BY Month",
+            "SELECT date(InvoiceDate), time(InvoiceDate, '+90 minutes'), \
+             datetime(InvoiceDate, 'start of month', '+1 month', '-1 day'), \
+             julianday(InvoiceDate), unixepoch(InvoiceDate), \
+             datetime(InvoiceDate, 'weekday 0', '-12:30:15.5', 'subsec') \
+             FROM Invoice WHERE InvoiceId % 37 = 0",
+            "SELECT strftime('%d %e %f %F %H %I %j %J %k %l %m %M %p %P %R %s %S %T', InvoiceDate), \
+             strftime('%u %w %U %V %W %G %g %Y %%', InvoiceDate, '+3 days') \
+             FROM Invoice WHERE InvoiceId % 41 = 0",
+            "SELECT LastName, timediff(HireDate, BirthDate), timediff(BirthDate, HireDate), \
+             date(HireDate, '+1 year', '+1 month', 'floor'), \
+             date(BirthDate, '-0001-02-03', 'ceiling') FROM Employee",
+            "SELECT datetime(unixepoch(InvoiceDate), 'unixepoch'), \
+             datetime(julianday(InvoiceDate), 'auto'), \
+             datetime(julianday(InvoiceDate), 'julianday', 'start of year') \
+             FROM Invoice WHERE InvoiceId % 53 = 0",
+            "SELECT date('2023-02-31'), date('2024-01-31', '+1 month'), \
+             datetime('2009-01-01 10:20 -05:00'), time('23:59:59.9999'), \
+             date('-0044-03-15', '+1 year'), date('nonsense'), date(NULL), \
+             strftime('%Q', '2009-01-01'), date('2009-01-01', '+1 fortnight')",
+        ];
+        for sql in &queries {
+            let expected = match sqlite3_rows(get_test_db_file_path(), sql) {
+                Some(rows) => rows,
+                None => return,
+            };
+            let result = run_query(get_test_db_file_path(), sql);
+            assert_eq!(shell_rows(&result), expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_json_functions_match_sqlite3() {
+        let queries = [
+            "SELECT json_object('id', TrackId, 'name', Name, 'price', UnitPrice, \
+             'composer', Composer, 'tags', json_array(GenreId, MediaTypeId)) \
+             FROM Track WHERE TrackId % 151 = 0",
+            "SELECT json_group_array(Name), json_group_object(Name, AlbumId) \
+             FROM Album JOIN Artist USING (ArtistId) WHERE ArtistId < 6 GROUP BY ArtistId",
+            "SELECT e ->> '$.name', e -> '$.tags', e -> 'tags' ->> 1, json_extract(e, '$.tags[#-1]', \
+             '$.missing'), json_type(e, '$.price'), json_array_length(e, '$.tags') \
+             FROM (SELECT json_object('name', Name, 'price', UnitPrice, \
+             'tags', json_array(GenreId, MediaTypeId)) AS e FROM Track WHERE TrackId % 173 = 0)",
+            "SELECT json_set(e, '$.name', upper(e ->> 'name'), '$.tags[#]', 0), \
+             json_insert(e, '$.price', 0, '$.new', json('[true,null]')), \
+             json_replace(e, '$.tags[0]', 'x'), json_remove(e, '$.tags', '$.none') \
+             FROM (SELECT json_object('name', Name, 'price', UnitPrice, \
+             'tags', json_array(GenreId)) AS e FROM Track WHERE TrackId % 307 = 0)",
+            "SELECT hex(b), json(b), b -> '$.a', json_valid(b, 8), json_valid(json(b)), \
+             hex(jsonb_set(b, '$.a', 1)), json_extract(b, '$.a') \
+             FROM (SELECT jsonb_object('a', ArtistId, 'n', Name) AS b FROM Artist \
+             WHERE ArtistId % 67 = 0)",
+            "SELECT j.key, j.value, j.type, j.atom, j.id, j.parent, j.fullkey, j.path \
+             FROM Genre, json_each(json_object('id', GenreId, 'name', Name)) AS j \
+             WHERE GenreId < 4",
+            "SELECT key, value, type, id, parent, fullkey, path FROM json_tree( \
+             '{\"a\":[1,2.5,{\"b\":null}],\"c d\":\"x\",\"e\":true}')",
+            "SELECT json('{a:0x1F, \"b\":[.5,+1,Infinity,NaN,], c:''\\x41''}'), \
+             json_valid('{a:1}'), json_valid('{a:1}', 2), json_quote(Name) \
+             FROM MediaType",
+        ];
+        for sql in &queries {
+            let expected = match sqlite3_rows(get_test_db_file_path(), sql) {
+                Some(rows) => rows,
+                None => return,
+            };
+            let result = run_query(get_test_db_file_path(), sql);
+            assert_eq!(shell_rows(&result), expected, "{}", sql);
+        }
+        assert_eq!(
+            query_error(get_test_db_file_path(), "SELECT json_extract('{}', 'a')"),
+            "bad JSON path: 'a'"
+        );
+        assert_eq!(
+            query_error(get_test_db_file_path(), "SELECT json('[1,')"),
+            "malformed JSON"
+        );
+    }
+
+    fn get_collation_db_file_path() -> path::PathBuf {
+        path::PathBuf::from("test/sql/collation.db")
+    }
+
+    #[test]
+    fn test_collations_match_sqlite3() {
+        let queries = [
+            "SELECT id, name FROM person WHERE name = 'user0100'",
+            "SELECT id FROM person WHERE name = 'user0100' COLLATE BINARY",
+            "SELECT id FROM person WHERE email = 'mail0100@example.com'",
+            "SELECT id FROM person WHERE email = 'MAIL0100@EXAMPLE.COM' COLLATE NOCASE",
+            "SELECT id, quote(code) FROM person WHERE code = 'c0100 '",
+            "SELECT * FROM tag WHERE name = 'RUST'",
+            "SELECT name FROM person ORDER BY name LIMIT 5",
+            "SELECT email FROM person ORDER BY email COLLATE NOCASE DESC, id LIMIT 3",
+            "SELECT t.name, count(*) FROM tag t JOIN person p ON p.name = t.name || '0001' \
+             GROUP BY t.name",
+            "SELECT email COLLATE NOCASE, count(*) FROM person GROUP BY 1 LIMIT 5",
+            "SELECT min('B', 'a' COLLATE NOCASE), max('b' COLLATE NOCASE, 'A', NULL), \
+             max('b', 'A' COLLATE NOCASE), min(3, 'a', x'00')",
+            "SELECT min(name, 'USER0100'), max(email, 'MAIL' COLLATE NOCASE), \
+             max(upper(email), email) FROM person WHERE id < 4",
+            "SELECT name COLLATE BINARY, count(*) FROM person GROUP BY 1 LIMIT 5",
+            "SELECT id FROM person WHERE name IN ('USER0100', 'user0101', 'User0100')",
+            "SELECT id FROM person WHERE email IN ('mail0100@example.com', 'x' COLLATE NOCASE)",
+        ];
+        for sql in &queries {
+            let expected = match sqlite3_rows(get_collation_db_file_path(), sql) {
+                Some(rows) => rows,
+                None => return,
+            };
+            let result = run_query(get_collation_db_file_path(), sql);
+            assert_eq!(shell_rows(&result), expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_index_seek_uses_index_collation() {
+        let pager = Pager::open(get_collation_db_file_path()).unwrap();
+        let schema = Schema::read(&pager).unwrap();
+        let executor = Executor::new(&pager, &schema);
+        let table = schema.table("person").unwrap();
+        let index = schema
+            .indexes_of("person")
+            .find(|index| index.name == "person_email")
+            .unwrap();
+        let key = vec![SqlValue::Text("MAIL1234@example.COM".to_string())];
+        let ids: Vec<i64> = executor
+            .index_seek(table, index, key)
+            .unwrap()
+            .map(|row| row.unwrap().row_id.unwrap())
+            .collect();
+        assert_eq!(ids, vec![2468, 2469]);
+    }
+
+    // The lines the sqlite3 shell prints for EXPLAIN QUERY PLAN, and ours.
+    fn query_plans(path: path::PathBuf, sql: &str) -> Option<(Vec<String>, Vec<String>)> {
+        let sql = format!("EXPLAIN QUERY PLAN {}", sql);
+        let expected = sqlite3_rows(path.clone(), &sql)?;
+        let plan = QueryPlan::from_rows(&run_query(path, &sql).rows);
+        Some((
+            plan.to_string().lines().map(String::from).collect(),
+            expected,
+        ))
+    }
+
+    #[test]
+    fn test_query_plans_match_sqlite3() {
+        let queries = [
+            "SELECT * FROM Track",
+            "SELECT AlbumId FROM Track",
+            "SELECT * FROM Track WHERE TrackId = 5",
+            "SELECT * FROM Track WHERE TrackId > 5 AND TrackId < 100",
+            "SELECT * FROM Track WHERE rowid BETWEEN 5 AND 10",
+            "SELECT * FROM Track WHERE AlbumId = 5",
+            "SELECT TrackId FROM Track WHERE AlbumId > 5",
+            "SELECT Name FROM Track WHERE GenreId = 1 AND AlbumId = 3",
+            "SELECT * FROM Track ORDER BY AlbumId",
+            "SELECT * FROM Track ORDER BY Name",
+            "SELECT * FROM Track WHERE AlbumId = 5 ORDER BY TrackId",
+            "SELECT * FROM Track WHERE TrackId IN (1, 2, 3)",
+            "SELECT TrackId FROM Track WHERE AlbumId IN (2, 1) ORDER BY AlbumId",
+            "SELECT * FROM Track t JOIN Album a ON t.AlbumId IN (a.AlbumId, a.AlbumId + 1) \
+             WHERE a.AlbumId = 3",
+            "SELECT * FROM PlaylistTrack WHERE PlaylistId = 1",
+            "SELECT * FROM Track t JOIN Album a ON a.AlbumId = t.AlbumId",
+            "SELECT * FROM Track t JOIN Album a ON a.AlbumId = t.AlbumId WHERE a.Title = 'x'",
+            "SELECT t.Name FROM Track t JOIN Genre g ON g.GenreId = t.GenreId \
+             WHERE g.Name = 'Rock'",
+            "SELECT * FROM InvoiceLine l JOIN Track t ON t.TrackId = l.TrackId \
+             WHERE l.InvoiceId = 5",
+            "SELECT * FROM Employee e JOIN Customer c ON c.SupportRepId = e.EmployeeId",
+            "SELECT * FROM Album a LEFT JOIN Track t ON t.AlbumId = a.AlbumId",
+            "SELECT AlbumId, count(*) FROM Track GROUP BY AlbumId",
+            "SELECT AlbumId, sum(Milliseconds) FROM Track GROUP BY AlbumId",
+            "SELECT GenreId, count(*) FROM Track WHERE AlbumId = 3 GROUP BY GenreId",
+            "SELECT a.Title, count(*) FROM Album a JOIN Track t ON t.AlbumId = a.AlbumId \
+             GROUP BY a.AlbumId",
+            "SELECT (SELECT Title FROM Album WHERE AlbumId = t.AlbumId) FROM Track t \
+             ORDER BY Name",
+            "SELECT 1",
+            "VALUES (1), (2)",
+            "SELECT 1 UNION SELECT 2 UNION ALL SELECT 3",
+            "SELECT Name FROM Genre EXCEPT SELECT Name FROM MediaType",
+            "WITH c AS MATERIALIZED (SELECT * FROM Genre) SELECT * FROM c",
+            "WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 5) \
+             SELECT * FROM n",
+            "SELECT key FROM json_each('[1, 2]')",
+        ];
+        for sql in &queries {
+            if let Some((plan, expected)) = query_plans(get_test_db_file_path(), sql) {
+                assert_eq!(plan, expected, "{}", sql);
+            }
+        }
+        for sql in &[
+            "SELECT * FROM country WHERE code > 'de'",
+            "SELECT * FROM word WHERE word = 'a' AND lang = 'en'",
+            "SELECT * FROM word WHERE word > 'a' AND word < 'c'",
+            "SELECT * FROM word WHERE word IN ('b', 'a') AND lang = 'en'",
+            "SELECT * FROM word ORDER BY word",
+            "SELECT * FROM note WHERE id < 3",
+        ] {
+            if let Some((plan, expected)) = query_plans(get_without_rowid_db_file_path(), sql) {
+                assert_eq!(plan, expected, "{}", sql);
+            }
+        }
+        // only keys in the collation of an index find its entries
+        for sql in &[
+            "SELECT id FROM person WHERE name = 'user0100' COLLATE BINARY",
+            "SELECT id FROM person WHERE email = 'MAIL0100@EXAMPLE.COM' COLLATE NOCASE",
+            "SELECT email FROM person ORDER BY email COLLATE NOCASE, id LIMIT 3",
+            "SELECT email COLLATE NOCASE, count(*) FROM person GROUP BY 1",
+            "SELECT name COLLATE BINARY, count(*) FROM person GROUP BY 1",
+            "SELECT * FROM person WHERE name > 'user0100' AND name < 'user0200'",
+            "SELECT * FROM person WHERE email > 'a' COLLATE NOCASE",
+            "SELECT id FROM person WHERE name IN ('USER0100', 'user0101')",
+            "SELECT id FROM person WHERE email IN ('MAIL0100@example.com', 'x')",
+        ] {
+            if let Some((plan, expected)) = query_plans(get_collation_db_file_path(), sql) {
+                assert_eq!(plan, expected, "{}", sql);
+            }
+        }
+
+        // sqlite3 also filters the rows of an IN subquery through a bloom
+        // filter first, which we do not
+        let sql = "SELECT Name FROM Track WHERE AlbumId IN (SELECT AlbumId FROM Album \
+                   WHERE ArtistId = 1)";
+        if let Some((plan, expected)) = query_plans(get_test_db_file_path(), sql) {
+            assert_eq!(
+                plan,
+                [
+                    "QUERY PLAN",
+                    "|--SEARCH Track USING INDEX IFK_TrackAlbumId (AlbumId=?)",
+                    "`--LIST SUBQUERY 1",
+                    "   `--SEARCH Album USING COVERING INDEX IFK_AlbumArtistId (ArtistId=?)",
+                ]
+            );
+            assert_eq!(expected[1], plan[1]);
+        }
+    }
+
+    #[test]
+    fn test_planned_queries_match_sqlite3() {
+        let queries = [
+            "SELECT TrackId, Name FROM Track WHERE TrackId > 3495",
+            "SELECT TrackId FROM Track WHERE TrackId >= 10.5 AND TrackId <= 12.5",
+            "SELECT TrackId FROM Track WHERE TrackId BETWEEN '7' AND 9",
+            "SELECT TrackId FROM Track WHERE TrackId < 'x' AND TrackId > 3500",
+            "SELECT TrackId, AlbumId FROM Track WHERE AlbumId BETWEEN 3 AND 4",
+            "SELECT TrackId, AlbumId FROM Track WHERE AlbumId > 346 ORDER BY AlbumId",
+            "SELECT count(*), sum(AlbumId) FROM Track WHERE AlbumId < 10",
+            "SELECT AlbumId, sum(Milliseconds), min(Name) FROM Track GROUP BY AlbumId",
+            "SELECT TrackId FROM Track WHERE AlbumId = 5 ORDER BY TrackId",
+            "SELECT PlaylistId, TrackId FROM PlaylistTrack WHERE PlaylistId = 3 \
+             AND TrackId > 3400",
+            "SELECT ar.Name, al.Title, t.Name FROM Artist ar \
+             JOIN Album al ON al.ArtistId = ar.ArtistId \
+             JOIN Track t ON t.AlbumId = al.AlbumId WHERE ar.Name = 'AC/DC'",
+            "SELECT c.LastName, count(*) FROM Customer c \
+             JOIN Invoice i ON i.CustomerId = c.CustomerId \
+             JOIN InvoiceLine l ON l.InvoiceId = i.InvoiceId \
+             WHERE c.Country = 'Brazil' GROUP BY c.CustomerId",
+            "SELECT p.Name, t.Name FROM Playlist p \
+             JOIN PlaylistTrack pt ON pt.PlaylistId = p.PlaylistId \
+             JOIN Track t ON t.TrackId = pt.TrackId WHERE p.PlaylistId = 3 ORDER BY t.Name",
+            "SELECT e.LastName, c.LastName FROM Employee e \
+             JOIN Customer c ON c.SupportRepId = e.EmployeeId ORDER BY c.CustomerId",
+            "SELECT TrackId FROM Track WHERE TrackId IN (3, 1, 2, 2, NULL, 9999)",
+            "SELECT TrackId FROM Track WHERE TrackId IN ('2', 3.0, 'x') ORDER BY TrackId",
+            "SELECT TrackId FROM Track WHERE TrackId IN (3, 1, 2) ORDER BY TrackId DESC",
+            "SELECT TrackId, AlbumId FROM Track WHERE AlbumId IN (2, 1, '3') ORDER BY AlbumId",
+            "SELECT Name FROM Track WHERE AlbumId IN (SELECT AlbumId FROM Album \
+             WHERE ArtistId = 1)",
+            "SELECT a.Title, t.Name FROM Album a \
+             JOIN Track t ON t.AlbumId IN (a.AlbumId, a.AlbumId + 1) WHERE a.AlbumId < 4",
+            "SELECT count(*) FROM Track WHERE AlbumId IN (NULL) OR TrackId IN (1, 5)",
+        ];
+        for sql in &queries {
+            let mut expected = match sqlite3_rows(get_test_db_file_path(), sql) {
+                Some(rows) => rows,
+                None => return,
+            };
+            let mut rows = shell_rows(&run_query(get_test_db_file_path(), sql));
+            // without ORDER BY, the order of the rows depends on the plan
+            if !sql.contains("ORDER BY") {
+                expected.sort();
+                rows.sort();
+            }
+            assert_eq!(rows, expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_result_aliases_match_sqlite3() {
+        let queries = [
+            "SELECT CustomerId, sum(Total) s FROM Invoice GROUP BY CustomerId HAVING s > 45",
+            "SELECT Total * 2 AS d FROM Invoice WHERE d > 40",
+            "SELECT TrackId AS t, Name FROM Track WHERE t < 4 OR t > 3500",
+            // a column of the table comes before an alias of the same name
+            "SELECT Total AS InvoiceId FROM Invoice WHERE InvoiceId < 3",
+            "SELECT GenreId g, count(*) AS n FROM Track GROUP BY g HAVING n < 20 AND g > 20",
+        ];
+        for sql in &queries {
+            let mut expected = match sqlite3_rows(get_test_db_file_path(), sql) {
+                Some(rows) => rows,
+                None => return,
+            };
+            let mut rows = shell_rows(&run_query(get_test_db_file_path(), sql));
+            expected.sort();
+            rows.sort();
+            assert_eq!(rows, expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_explain() {
+        let result = run_query(
+            get_test_db_file_path(),
+            "EXPLAIN SELECT Name FROM Track WHERE Milliseconds > 300000 LIMIT 2",
+        );
+        assert_eq!(
+            result.columns,
+            ["addr", "opcode", "p1", "p2", "p3", "p4", "p5", "comment"]
+        );
+        let opcodes: Vec<&str> = result.rows.iter().map(|row| text(&row[1])).collect();
+        assert_eq!(
+            opcodes,
+            [
+                "Init",
+                "Integer",
+                "IfNot",
+                "OpenRead",
+                "Rewind",
+                "Column",
+                "Integer",
+                "Gt",
+                "IfNot",
+                "Column",
+                "ResultRow",
+                "DecrJumpZero",
+                "Next",
+                "Halt"
+            ]
+        );
+        for (addr, row) in result.rows.iter().enumerate() {
+            assert_eq!(row[0], SqlValue::Integer(addr as i64));
+        }
+        let open = &result.rows[3];
+        assert_eq!(open[3], SqlValue::Integer(13));
+        assert_eq!(text(&open[5]), "Track");
+        // the loop goes back to the first instruction after Rewind
+        assert_eq!(result.rows[12][3], SqlValue::Integer(5));
+        assert_eq!(text(&result.rows[9][7]), "Track.Name");
+
+        let result = run_query(
+            get_test_db_file_path(),
+            "EXPLAIN SELECT count(*), max(Total) FROM Invoice",
+        );
+        let steps: Vec<String> = result
+            .rows
+            .iter()
+            .filter(|row| text(&row[1]).starts_with("Agg"))
+            .map(|row| format!("{} {}", text(&row[1]), text(&row[5])))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                "AggStep count(0)",
+                "AggStep max(1)",
+                "AggFinal count(0)",
+                "AggFinal max(1)"
+            ]
+        );
+
+        // DISTINCT keeps the rows it returned in a temporary set
+        let result = run_query(
+            get_test_db_file_path(),
+            "EXPLAIN SELECT DISTINCT Country FROM Customer LIMIT 3",
+        );
+        let opcodes: Vec<&str> = result.rows.iter().map(|row| text(&row[1])).collect();
+        let open = opcodes
+            .iter()
+            .position(|&op| op == "OpenEphemeral")
+            .unwrap();
+        assert_eq!(text(&result.rows[open][5]), "k(1,B)");
+        let found = opcodes.iter().position(|&op| op == "Found").unwrap();
+        assert_eq!(
+            opcodes[found - 1..found + 3],
+            ["Column", "Found", "IdxInsert", "ResultRow"]
+        );
+
+        // GROUP BY sorts the rows, and produces the row of a group by a
+        // subroutine when the next row starts another
+        let result = run_query(
+            get_test_db_file_path(),
+            "EXPLAIN SELECT BillingCountry, count(*) FROM Invoice GROUP BY BillingCountry",
+        );
+        let opcodes: Vec<&str> = result.rows.iter().map(|row| text(&row[1])).collect();
+        let open = opcodes.iter().position(|&op| op == "SorterOpen").unwrap();
+        assert_eq!(text(&result.rows[open][5]), "k(1,B)");
+        let data = opcodes.iter().position(|&op| op == "SorterData").unwrap();
+        assert_eq!(
+            opcodes[data..data + 5],
+            ["SorterData", "IfNot", "Compare", "Jump", "Gosub"]
+        );
+        assert!(opcodes.contains(&"SorterInsert") && opcodes.contains(&"Return"));
+
+        // ORDER BY returns the rows of a sorter, whose keys come first
+        let result = run_query(
+            get_test_db_file_path(),
+            "EXPLAIN SELECT Name FROM Genre ORDER BY Name DESC LIMIT 3",
+        );
+        let opcodes: Vec<&str> = result.rows.iter().map(|row| text(&row[1])).collect();
+        let open = opcodes.iter().position(|&op| op == "SorterOpen").unwrap();
+        assert_eq!(text(&result.rows[open][5]), "k(1,-B)");
+        let sort = opcodes.iter().position(|&op| op == "SorterSort").unwrap();
+        assert_eq!(
+            opcodes[sort..sort + 5],
+            [
+                "SorterSort",
+                "SorterData",
+                "ResultRow",
+                "DecrJumpZero",
+                "SorterNext"
+            ]
+        );
+
+        // a seek takes its keys from registers, and an index that does not
+        // cover the table points the table at the row of each entry
+        let result = run_query(
+            get_test_db_file_path(),
+            "EXPLAIN SELECT TrackId, Name FROM Track WHERE AlbumId = 5",
+        );
+        let opcodes: Vec<&str> = result.rows.iter().map(|row| text(&row[1])).collect();
+        let seek = opcodes.iter().position(|&op| op == "SeekGE").unwrap();
+        assert_eq!(text(&result.rows[seek - 1][1]), "Integer");
+        assert_eq!(result.rows[seek][4], result.rows[seek - 1][3]);
+        assert_eq!(text(&result.rows[seek][5]), "1");
+        assert_eq!(text(&result.rows[seek + 1][1]), "DeferredSeek");
+        let rowid = opcodes.iter().position(|&op| op == "IdxRowid").unwrap();
+        assert_eq!(text(&result.rows[rowid][7]), "Track.TrackId");
+
+        // a LEFT JOIN without a match goes on with a row of NULLs
+        let result = run_query(
+            get_test_db_file_path(),
+            "EXPLAIN SELECT a.Title, t.Name FROM Album a \
+             LEFT JOIN Track t ON t.AlbumId = a.AlbumId",
+        );
+        let opcodes: Vec<&str> = result.rows.iter().map(|row| text(&row[1])).collect();
+        let null = opcodes.iter().position(|&op| op == "NullRow").unwrap();
+        assert_eq!(opcodes[null - 1], "IfPos");
+
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "EXPLAIN SELECT row_number() OVER () FROM Genre"
+            ),
+            "unsupported: EXPLAIN of a statement that is not compiled"
+        );
+    }
+
+    #[test]
+    fn test_compiled_queries_match_sqlite3() {
+        let queries = [
+            "SELECT NULL AND 0, 0 AND NULL, NULL AND 1, NULL OR 1, NULL OR 0, NOT NULL",
+            "SELECT 1 WHERE 0",
+            "SELECT 1 + 2, 'a' || 'b', 7 / 2, 7 % 3, 7.0 / 2",
+            "SELECT Name FROM Track WHERE Composer IS NULL AND GenreId = 7",
+            "SELECT Name FROM Track WHERE Composer IS NULL OR Milliseconds < 5000",
+            "SELECT Name, Bytes / 1024 FROM Track WHERE TrackId > 3490 LIMIT 3 OFFSET 2",
+            "SELECT Name FROM Track LIMIT 0",
+            "SELECT Name FROM Track LIMIT 5 OFFSET 5000",
+            "SELECT GenreId FROM Genre LIMIT -1 OFFSET 23",
+            "SELECT GenreId FROM Genre LIMIT +2 OFFSET -3",
+            "SELECT Name FROM Track WHERE TrackId IN (7, 3, 5) AND AlbumId IN (1, 2)",
+            "SELECT upper(Name), length(Name) FROM Genre WHERE GenreId < 5",
+            "SELECT count(*), sum(Milliseconds), min(Name) FROM Track WHERE 0",
+            "SELECT count(*), avg(Total) * 2 FROM Invoice LIMIT 1 OFFSET 1",
+            "SELECT count(*) FROM Track",
+            "SELECT count(*) FROM Track LIMIT 0",
+            "SELECT count(DISTINCT AlbumId), total(UnitPrice) FROM Track",
+            "SELECT group_concat(Name, ';') FROM Genre WHERE GenreId > 20",
+            "SELECT a.Title, t.Name FROM Album a JOIN Track t ON t.AlbumId = a.AlbumId \
+             WHERE a.ArtistId = 1 AND t.Milliseconds > 300000",
+            "SELECT c.FirstName, i.Total FROM Customer c, Invoice i \
+             WHERE i.CustomerId = c.CustomerId AND i.Total > 20",
+            "SELECT Name FROM Artist WHERE ArtistId = '5'",
+            "SELECT Name, (SELECT count(*) FROM Album WHERE ArtistId = ar.ArtistId) \
+             FROM Artist ar WHERE ArtistId < 10",
+            "SELECT DISTINCT Country FROM Customer LIMIT 5 OFFSET 3",
+            "SELECT DISTINCT c.Country, i.BillingCountry FROM Customer c, Invoice i \
+             WHERE i.CustomerId = c.CustomerId",
+            "SELECT DISTINCT count(*) FROM Track",
+            "SELECT GenreId, count(*), max(Milliseconds) FROM Track GROUP BY GenreId",
+            "SELECT BillingCountry, sum(Total) FROM Invoice GROUP BY BillingCountry \
+             HAVING count(*) > 20",
+            "SELECT Name, max(Milliseconds) FROM Track GROUP BY AlbumId HAVING AlbumId < 5",
+            "SELECT Composer, Name FROM Track WHERE Composer LIKE 'A%' GROUP BY Composer",
+            "SELECT count(*) FROM Track GROUP BY MediaTypeId HAVING 0",
+            "SELECT ar.Name, count(al.AlbumId) FROM Artist ar \
+             LEFT JOIN Album al ON al.ArtistId = ar.ArtistId GROUP BY ar.ArtistId",
+            "SELECT ar.Name, al.Title FROM Artist ar \
+             LEFT JOIN Album al ON al.ArtistId = ar.ArtistId WHERE ar.ArtistId > 270",
+            "SELECT ar.Name FROM Artist ar LEFT JOIN Album al ON al.ArtistId = ar.ArtistId \
+             WHERE al.AlbumId IS NULL",
+            "SELECT TrackId, Name, Milliseconds FROM Track WHERE AlbumId = 5",
+            "SELECT Name FROM Track WHERE AlbumId IN (3, 4) AND GenreId = 1",
+            "SELECT AlbumId, count(*) FROM Track WHERE AlbumId BETWEEN 10 AND 12 GROUP BY AlbumId",
+            "SELECT TrackId FROM Track WHERE GenreId = 25",
+            "SELECT a.Title, t.Name FROM Album a JOIN Track t ON t.AlbumId = a.AlbumId \
+             WHERE a.AlbumId IN (SELECT AlbumId FROM Album WHERE ArtistId = 2)",
+        ];
+        for sql in &queries {
+            let mut expected = match sqlite3_rows(get_test_db_file_path(), sql) {
+                Some(rows) => rows,
+                None => return,
+            };
+            let mut rows = shell_rows(&run_query(get_test_db_file_path(), sql));
+            expected.sort();
+            rows.sort();
+            assert_eq!(rows, expected, "{}", sql);
+        }
+
+        // ORDER BY, whose rows are compared in order
+        let queries = [
+            "SELECT Name FROM Genre ORDER BY Name DESC",
+            "SELECT Name, Milliseconds FROM Track WHERE AlbumId < 4 \
+             ORDER BY Milliseconds DESC LIMIT 5 OFFSET 2",
+            "SELECT DISTINCT Country FROM Customer ORDER BY Country LIMIT 4",
+            "SELECT BillingCountry, count(*) AS n FROM Invoice GROUP BY BillingCountry \
+             ORDER BY n DESC, BillingCountry LIMIT 6",
+            "SELECT GenreId, sum(Milliseconds) FROM Track GROUP BY GenreId ORDER BY 2",
+            "SELECT Name FROM Track WHERE AlbumId = 5 ORDER BY upper(Name)",
+        ];
+        for sql in &queries {
+            let expected = match sqlite3_rows(get_test_db_file_path(), sql) {
+                Some(rows) => rows,
+                None => return,
+            };
+            let rows = shell_rows(&run_query(get_test_db_file_path(), sql));
+            assert_eq!(rows, expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_compiled_index_reads_match_sqlite3() {
+        let sql = "CREATE TABLE t (a INTEGER PRIMARY KEY, b REAL, c TEXT COLLATE NOCASE, d);\
+                   CREATE INDEX t_b ON t (b);\
+                   CREATE INDEX t_cd ON t (c, d);\
+                   INSERT INTO t VALUES (1, 1.0, 'x', 1), (2, 2.5, 'X', 2), (3, NULL, 'y', 3),\
+                   (4, 3.0, NULL, 4), (5, 2.0, 'z', NULL);\
+                   CREATE TABLE w (k TEXT, n INTEGER, v REAL, PRIMARY KEY (k, n)) WITHOUT ROWID;\
+                   CREATE INDEX w_v ON w (v);\
+                   INSERT INTO w VALUES ('a', 1, 1.0), ('a', 2, 1.5), ('b', 1, 2.0), ('c', 3, NULL);\
+                   WITH RECURSIVE s(x) AS (SELECT 10 UNION ALL SELECT x + 1 FROM s WHERE x < 2000)\
+                   INSERT INTO t SELECT x, x * 0.5, 'r', x FROM s;\
+                   WITH RECURSIVE s(x) AS (SELECT 10 UNION ALL SELECT x + 1 FROM s WHERE x < 2000)\
+                   INSERT INTO w SELECT 'r', x, x * 0.5 FROM s;";
+        // enough rows that the indexes are worth reading
+        let db = match ScratchDb::create("index-reads", sql) {
+            Some(db) => db,
+            None => return,
+        };
+        db.assert_queries_match(&[
+            "SELECT b FROM t WHERE b > 1 AND b < 8",
+            "SELECT a, b FROM t WHERE b >= 2.0 AND b < 9 ORDER BY b",
+            "SELECT b, typeof(b), rowid FROM t WHERE b < 2.5",
+            "SELECT c, d, a FROM t WHERE c = 'x'",
+            "SELECT c, count(*), max(b) FROM t GROUP BY c",
+            "SELECT a, d FROM t WHERE c IN ('X', 'z') ORDER BY a",
+            "SELECT v, k, n FROM w WHERE v > 1 AND v < 10",
+            "SELECT k, n, typeof(v) FROM w WHERE v = 2",
+            "SELECT k, count(*), sum(v) FROM w GROUP BY k",
+            "SELECT t.a, w.k, w.n FROM t LEFT JOIN w ON w.v = t.b WHERE t.a < 20 ORDER BY t.a",
+        ]);
+    }
+
+    #[test]
+    fn test_distinct_aggregates_use_collations() {
+        let sql = "CREATE TABLE t (name TEXT COLLATE NOCASE, x TEXT);\
+                   INSERT INTO t VALUES ('a', 'a'), ('A', 'A'), ('b', 'b '), ('B', 'b'), (NULL, NULL);";
+        let db = match ScratchDb::create("distinct", sql) {
+            Some(db) => db,
+            None => return,
+        };
+        db.assert_queries_match(&[
+            "SELECT count(DISTINCT name), count(DISTINCT x) FROM t",
+            "SELECT count(DISTINCT x COLLATE NOCASE), count(DISTINCT x COLLATE RTRIM) FROM t",
+            "SELECT count(DISTINCT name COLLATE BINARY) FROM t",
+            "SELECT group_concat(DISTINCT name) FROM t",
+            "SELECT x, count(DISTINCT name) FROM t GROUP BY x",
+        ]);
+        let result = run_query(
+            get_test_db_file_path(),
+            "SELECT count(DISTINCT x COLLATE NOCASE) FROM (SELECT 'a' AS x UNION ALL SELECT 'A')",
+        );
+        assert_eq!(column_i64(&result, 0), vec![Some(1)]);
+    }
+
+    #[test]
+    fn test_select_distinct() {
+        let queries = [
+            "SELECT DISTINCT GenreId FROM Track",
+            "SELECT DISTINCT AlbumId, GenreId FROM Track WHERE AlbumId < 20",
+            "SELECT DISTINCT Composer FROM Track WHERE AlbumId < 30 ORDER BY Composer",
+            "SELECT DISTINCT MediaTypeId FROM Track ORDER BY 1 DESC LIMIT 2 OFFSET 1",
+            "SELECT DISTINCT upper(substr(Name, 1, 1)) FROM Artist",
+            "SELECT DISTINCT count(*) FROM Track GROUP BY AlbumId",
+            "SELECT DISTINCT * FROM (SELECT 1 AS a UNION ALL SELECT 1.0 UNION ALL SELECT '1')",
+            "SELECT DISTINCT x COLLATE NOCASE FROM (SELECT 'a' AS x UNION ALL SELECT 'A')",
+        ];
+        for sql in &queries {
+            let expected = match sqlite3_rows(get_test_db_file_path(), sql) {
+                Some(rows) => rows,
+                None => return,
+            };
+            assert_eq!(
+                shell_rows(&run_query(get_test_db_file_path(), sql)),
+                expected,
+                "{}",
+                sql
+            );
+        }
+
+        // a column's own collation decides which values are the same
+        let sql = "CREATE TABLE t (name TEXT COLLATE NOCASE, x TEXT);\
+                   INSERT INTO t VALUES ('a', 'a'), ('A', 'A'), ('b', 'b'), (NULL, NULL);";
+        if let Some(db) = ScratchDb::create("select-distinct", sql) {
+            db.assert_queries_match(&[
+                "SELECT DISTINCT name FROM t",
+                "SELECT DISTINCT x FROM t",
+                "SELECT DISTINCT name, x FROM t",
+                "SELECT count(*) FROM (SELECT DISTINCT name FROM t)",
+            ]);
+        }
+    }
+
+    #[test]
+    fn test_pattern_operators_match_sqlite3() {
+        let queries = [
+            "SELECT Name FROM Artist WHERE Name GLOB 'A[cd]*'",
+            "SELECT count(*) FROM Track WHERE Name NOT GLOB '*[aeiou]*'",
+            "SELECT Name FROM Genre WHERE NOT Name GLOB '*' || 'o*' AND GenreId < 20",
+            "SELECT Name GLOB 'R*', Name NOT GLOB 'r*' FROM Genre WHERE GenreId < 4",
+            "SELECT Title FROM Album WHERE Title LIKE '%!%%' ESCAPE '!'",
+            "SELECT Title FROM Album WHERE Title LIKE '%\\_%' ESCAPE '\\'",
+            "SELECT count(*) FROM Track WHERE Name NOT LIKE '%a!_%' ESCAPE '!'",
+            "SELECT 'a_c' LIKE 'a' || 'x_c' ESCAPE 'x', 'abc' LIKE 'axbc' ESCAPE 'x'",
+        ];
+        for sql in &queries {
+            let mut expected = match sqlite3_rows(get_test_db_file_path(), sql) {
+                Some(rows) => rows,
+                None => return,
+            };
+            let mut rows = shell_rows(&run_query(get_test_db_file_path(), sql));
+            expected.sort();
+            rows.sort();
+            assert_eq!(rows, expected, "{}", sql);
+        }
+
+        assert_eq!(
+            query_error(
+                get_test_db_file_path(),
+                "SELECT Name FROM Genre WHERE Name LIKE 'a' ESCAPE 'xy'"
+            ),
+            "ESCAPE expression must be a single character"
+        );
+    }
+
+    #[test]
+    fn test_narrow_reads_of_wide_rows() {
+        // with 512 byte pages the long values spill onto overflow pages
+        let mut sql = "PRAGMA page_size = 512;\
+                       CREATE TABLE wide (id INTEGER PRIMARY KEY, a, long, b);\
+                       CREATE TABLE keyed (k TEXT PRIMARY KEY, long, v) WITHOUT ROWID;\
+                       CREATE INDEX wide_b ON wide (b);\
+                       CREATE TABLE spill (a, long, b);\
+                       INSERT INTO spill VALUES (1, zeroblob(2000), 2);"
+            .to_string();
+        for i in 0..60 {
+            sql += &format!(
+                "INSERT INTO wide (a, long, b) VALUES ({0}, printf('%.*c', {1}, 'x'), {0} % 7);\
+                 INSERT INTO keyed VALUES ('k{0}', printf('%.*c', {1}, 'y'), {0});",
+                i,
+                i * 37
+            );
+        }
+        let db = match ScratchDb::create("wide", &sql) {
+            Some(db) => db,
+            None => return,
+        };
+        db.assert_queries_match(&[
+            "SELECT a FROM wide",
+            "SELECT a, b FROM wide WHERE id BETWEEN 10 AND 20",
+            "SELECT id, length(long) FROM wide WHERE b = 3",
+            "SELECT count(*), sum(b) FROM wide",
+            "SELECT v FROM keyed WHERE k > 'k5'",
+            "SELECT k, length(long) FROM keyed WHERE k = 'k42'",
+            "SELECT w.a, k.v FROM wide w JOIN keyed k ON k.v = w.id WHERE w.b = 1",
+        ]);
+        let path = db.path();
+
+        // point the overflow chain of the spilled blob at a page that does
+        // not exist: only reading the blob, or the column after it, notices
+        let (offset, usable_size) = {
+            let pager = Pager::open(&path).unwrap();
+            let schema = Schema::read(&pager).unwrap();
+            let root = pager
+                .get_page(schema.table("spill").unwrap().root_page)
+                .unwrap();
+            let layout = root.get_cell_layout(0, pager.usable_size());
+            let page_no = layout.first_overflow_page.unwrap();
+            (u64::from(page_no - 1) * 512, pager.usable_size())
+        };
+        assert_eq!(usable_size, 512);
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        io::Seek::seek(&mut file, io::SeekFrom::Start(offset)).unwrap();
+        io::Write::write_all(&mut file, &u32::MAX.to_be_bytes()).unwrap();
+        drop(file);
+        let rows = run_query(path.clone(), "SELECT a, typeof(a) FROM spill");
+        assert_eq!(shell_rows(&rows), vec!["1|integer"]);
+        for sql in &["SELECT long FROM spill", "SELECT b FROM spill"] {
+            assert!(
+                query_error(path.clone(), sql).contains("out of range"),
+                "{}",
+                sql
+            );
+        }
+    }
+
+    #[test]
+    fn test_text_that_is_not_utf8() {
+        let sql = "CREATE TABLE t (id INTEGER PRIMARY KEY, x TEXT);\
+                   CREATE INDEX t_x ON t (x);\
+                   INSERT INTO t VALUES (1, CAST(x'ff61' AS TEXT)), (2, 'b');";
+        let db = match ScratchDb::create("not-utf8", sql) {
+            Some(db) => db,
+            None => return,
+        };
+        db.assert_queries_match(&[
+            "SELECT x, typeof(x) FROM t",
+            "SELECT id FROM t WHERE x > 'a' ORDER BY x",
+        ]);
+        let result = run_query(db.path(), "SELECT x FROM t WHERE id = 1");
+        assert_eq!(text(&result.rows[0][0]), "\u{fffd}a");
+    }
+
+    #[test]
+    fn test_real_columns_read_as_reals() {
+        // SQLite stores integral values of REAL columns as integers
+        let sql = "CREATE TABLE t (id INTEGER PRIMARY KEY, x REAL, f FLOAT, d DOUBLE, i INT);\
+                   CREATE INDEX t_x ON t (x);\
+                   CREATE TABLE k (r REAL PRIMARY KEY, v) WITHOUT ROWID;\
+                   INSERT INTO t VALUES (1, 5, -3, 0, 5), (2, 2.5, 1e3, '7', 7), (3, NULL, 4, 8, '9');\
+                   INSERT INTO k VALUES (1, 'a'), (2.5, 'b');";
+        let db = match ScratchDb::create("real", sql) {
+            Some(db) => db,
+            None => return,
+        };
+        db.assert_queries_match(&[
+            "SELECT x, x / 2, typeof(x), f, typeof(f), d / 16, typeof(d), i / 2, typeof(i) FROM t",
+            "SELECT x, typeof(x) FROM t WHERE x > 1",
+            "SELECT x FROM t ORDER BY x",
+            "SELECT r, r / 2, typeof(r), v FROM k",
+            "SELECT v FROM k WHERE r = 1",
+        ]);
+        let result = run_query(db.path(), "SELECT x, x / 2, typeof(x) FROM t WHERE id = 1");
+        assert!(matches!(result.rows[0][0], SqlValue::Real(x) if x == 5.0));
+        assert!(matches!(result.rows[0][1], SqlValue::Real(x) if x == 2.5));
+        assert_eq!(result.rows[0][2].as_str(), Some("real"));
+    }
+
+    #[test]
+    fn test_added_columns_read_as_defaults() {
+        let mut sql = "CREATE TABLE t (id INTEGER PRIMARY KEY, a);\
+                       CREATE TABLE k (a TEXT PRIMARY KEY, b) WITHOUT ROWID;\
+                       INSERT INTO t (a) VALUES ('old'), (NULL);\
+                       INSERT INTO k VALUES ('x', 1), ('y', 2);"
+            .to_string();
+        for column in &[
+            "n INTEGER DEFAULT '7'",
+            "r REAL DEFAULT 5",
+            "s TEXT DEFAULT 1.50",
+            "w DEFAULT word",
+            "x DEFAULT x'00ff'",
+            "m DEFAULT (-2)",
+            "z",
+        ] {
+            sql += &format!("ALTER TABLE t ADD COLUMN {};", column);
+            sql += &format!("ALTER TABLE k ADD COLUMN {};", column);
+        }
+        sql += "INSERT INTO t (a, n, r, z) VALUES ('new', 1, 2.5, 2);\
+                INSERT INTO k (a, b, r, s) VALUES ('z', 3, 0.5, 'set');\
+                CREATE INDEX t_n ON t (n);";
+        let db = match ScratchDb::create("added", &sql) {
+            Some(db) => db,
+            None => return,
+        };
+        db.assert_queries_match(&[
+            "SELECT id, a, n, r, s, w, m, z FROM t",
+            "SELECT id, typeof(n), typeof(r), typeof(s), s, hex(x) FROM t",
+            "SELECT id, z FROM t WHERE n = 7",
+            "SELECT id FROM t WHERE id = 2 AND m = -2",
+            "SELECT a, b, n, r, s, w, hex(x), m, z FROM k",
+            "SELECT a, typeof(s), w FROM k WHERE a = 'y'",
+            "SELECT count(*) FROM t JOIN k ON k.n = t.n",
+        ]);
+
+        // rows read whole get the defaults too
+        let pager = Pager::open(db.path()).unwrap();
+        let schema = Schema::read(&pager).unwrap();
+        let executor = Executor::new(&pager, &schema);
+        let rows: Vec<Vec<SqlValue>> = executor
+            .scan(schema.table("t").unwrap())
+            .map(|row| row.unwrap().values)
+            .collect();
+        assert_eq!(rows[0][0], SqlValue::Integer(1));
+        assert_eq!(rows[0][2], SqlValue::Integer(7));
+        assert_eq!(rows[1][3], SqlValue::Real(5.0));
+        assert_eq!(rows[2][2], SqlValue::Integer(1));
+    }
+
+    #[test]
+    fn test_page_usage_matches_dbstat() {
+        let sql = "PRAGMA page_size = 1024; PRAGMA auto_vacuum = INCREMENTAL;\
+                   CREATE TABLE t (a, b); CREATE INDEX t_b ON t (b);\
+                   WITH RECURSIVE c(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM c WHERE i < 600)\
+                   INSERT INTO t SELECT i, printf('%.*c', i % 700, 'z') FROM c;\
+                   DELETE FROM t WHERE a % 3 = 0;";
+        let db = match ScratchDb::create("usage", sql) {
+            Some(db) => db,
+            None => return,
+        };
+        let path = db.path();
+        let dbstat = "SELECT name, count(*), sum(pagetype = 'leaf'), sum(pagetype = 'overflow'), \
+                      sum(unused), sum(payload) FROM dbstat GROUP BY name";
+        let expected = match Command::new("sqlite3").arg(&path).arg(dbstat).output() {
+            // dbstat is left out of some builds of sqlite3
+            Ok(output) if output.status.success() => String::from_utf8(output.stdout).unwrap(),
+            _ => return,
+        };
+        let freelist_count = sqlite3_rows(path.clone(), "PRAGMA freelist_count").unwrap();
+        let page_count = sqlite3_rows(path.clone(), "PRAGMA page_count").unwrap();
+
+        let usage = Connection::open(&path).unwrap().page_usage().unwrap();
+        let mut trees: Vec<String> = usage
+            .trees
+            .iter()
+            .map(|tree| {
+                let name = match tree.name.as_str() {
+                    "sqlite_master" => "sqlite_schema",
+                    name => name,
+                };
+                format!(
+                    "{}|{}|{}|{}|{}|{}",
+                    name,
+                    tree.pages(),
+                    tree.leaf_pages,
+                    tree.overflow_pages,
+                    tree.unused_bytes,
+                    tree.payload_bytes
+                )
+            })
+            .collect();
+        let mut expected: Vec<&str> = expected.lines().collect();
+        trees.sort();
+        expected.sort();
+        assert_eq!(trees, expected);
+        let free = usage.count(PageKind::FreelistTrunk) + usage.count(PageKind::FreelistLeaf);
+        assert_eq!(vec![free.to_string()], freelist_count);
+        assert_eq!(vec![usage.pages.len().to_string()], page_count);
+        assert!(usage.count(PageKind::PointerMap) > 1);
+        assert_eq!(usage.count(PageKind::Unreachable), 0);
+        assert_eq!(usage.page(2).unwrap().kind, PageKind::PointerMap);
+        let root = usage.trees[1].root_page;
+        assert_eq!(usage.page(root).unwrap().tree, Some(1));
+
+        // pages the header no longer lists on the freelist are lost
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        io::Seek::seek(&mut file, io::SeekFrom::Start(32)).unwrap();
+        io::Write::write_all(&mut file, &[0; 8]).unwrap();
+        drop(file);
+        let pager = Pager::open(&path).unwrap();
+        let usage = analyzer::page_usage(&pager).unwrap();
+        assert!(usage.freelist.trunks.is_empty());
+        assert_eq!(usage.count(PageKind::Unreachable), free);
+        let report = usage.to_string();
+        assert!(report.contains("*** t_b (index on t) ***"), "{}", report);
+    }
+
+    // Runs ANALYZE with rsqlite on one copy of a database and with sqlite3
+    // on another, and checks that both record the same statistics in a
+    // database that is still intact.
+    fn check_analyze(path: path::PathBuf, name: &str) {
+        let ours = ScratchDb::copy(path.clone(), &format!("{}-ours", name));
+        let theirs = ScratchDb::copy(path, &format!("{}-theirs", name));
+        let stat1 = "SELECT * FROM sqlite_stat1 ORDER BY tbl, idx";
+        if sqlite3_rows(theirs.path(), "ANALYZE").is_some() {
+            let expected = sqlite3_rows(theirs.path(), stat1).unwrap();
+            for sql in &["ANALYZE", "ANALYZE", "ANALYZE main"] {
+                Connection::open(ours.path())
+                    .unwrap()
+                    .execute(sql, ())
+                    .unwrap();
+                assert_eq!(sqlite3_rows(ours.path(), stat1).unwrap(), expected);
+                assert_eq!(
+                    sqlite3_rows(ours.path(), "PRAGMA integrity_check").unwrap(),
+                    vec!["ok"]
+                );
+            }
+            let rows = shell_rows(&run_query(ours.path(), stat1));
+            assert_eq!(rows, expected);
+        }
+    }
+
+    #[test]
+    fn test_analyze_matches_sqlite3() {
+        check_analyze(get_test_db_file_path(), "chinbook");
+        check_analyze(get_without_rowid_db_file_path(), "without-rowid");
+    }
+
+    #[test]
+    fn test_analyze_small_pages() {
+        // with 512 byte pages sqlite_master and sqlite_stat1 take several
+        // levels of pages, and long CREATE TABLE statements overflow
+        let mut sql = "PRAGMA page_size = 512;".to_string();
+        for i in 0..200 {
+            let columns: Vec<String> = (0..if i % 50 == 0 { 40 } else { 3 })
+                .map(|c| format!("c{} INTEGER", c))
+                .collect();
+            sql += &format!(
+                "CREATE TABLE t{} (id INTEGER PRIMARY KEY, {});",
+                i,
+                columns.join(", ")
+            );
+            sql += &format!("CREATE INDEX i{} ON t{} (c0, c1);", i, i);
+            for k in 0..i % 20 {
+                sql += &format!("INSERT INTO t{} (c0, c1) VALUES ({}, {});", i, k % 7, k % 3);
+            }
+        }
+        if let Some(db) = ScratchDb::create("small", &sql) {
+            check_analyze(db.path(), "small");
+        }
+    }
+
+    #[test]
+    fn test_connection_sees_changes_by_others() {
+        let db = match ScratchDb::create("changed", "CREATE TABLE a(x); INSERT INTO a VALUES (1);")
+        {
+            Some(db) => db,
+            None => return,
+        };
+        let connection = Connection::open(db.path()).unwrap();
+        let count = |sql: &str| connection.query_row(sql, (), |row| row.get::<_, i64>(0));
+        assert_eq!(count("SELECT count(*) FROM a").unwrap(), 1);
+
+        // enough rows that the table grows past the pages read so far
+        sqlite3_rows(
+            db.path(),
+            "INSERT INTO a SELECT value FROM generate_series(2, 2000); \
+             CREATE TABLE b(y); INSERT INTO b VALUES ('new');",
+        )
+        .unwrap();
+        assert_eq!(count("SELECT count(*) FROM a").unwrap(), 2000);
+        assert_eq!(count("SELECT max(x) FROM a").unwrap(), 2000);
+        let y: String = connection
+            .query_row("SELECT y FROM b", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(y, "new");
+    }
+
+    #[test]
+    fn test_analyze_single_table() {
+        let db = ScratchDb::copy(get_test_db_file_path(), "single-table");
+        let connection = Connection::open(db.path()).unwrap();
+        connection.execute("ANALYZE Track", ()).unwrap();
+        connection
+            .execute("ANALYZE main.IFK_AlbumArtistId", ())
+            .unwrap();
+        // ANALYZE only replaces the rows of what it analyzed
+        connection.execute("ANALYZE IFK_TrackGenreId", ()).unwrap();
+        // the connection that created sqlite_stat1 sees it at once
+        let rows: Vec<(String, String, String)> = connection
+            .query_map("SELECT * FROM sqlite_stat1 ORDER BY idx", (), |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected = [
+            ("Album", "IFK_AlbumArtistId", "347 2"),
+            ("Track", "IFK_TrackAlbumId", "3503 11"),
+            ("Track", "IFK_TrackGenreId", "3503 141"),
+            ("Track", "IFK_TrackMediaTypeId", "3503 701"),
+        ];
+        let expected: Vec<(String, String, String)> = expected
+            .iter()
+            .map(|(t, i, s)| (t.to_string(), i.to_string(), s.to_string()))
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn test_analyze_errors() {
+        let db = ScratchDb::copy(get_collation_db_file_path(), "analyze-errors");
+        let path = db.path();
+        assert_eq!(
+            query_error(path.clone(), "ANALYZE nosuch"),
+            "no such table: nosuch"
+        );
+        assert_eq!(
+            query_error(path.clone(), "ANALYZE temp.person"),
+            "unknown database temp"
+        );
+        // the index on city uses a collation that is not registered
+        assert_eq!(
+            query_error(path.clone(), "ANALYZE city"),
+            "no such collation sequence: reverse"
+        );
+        let pager = Pager::new(File::open(&path).unwrap()).unwrap();
+        let schema = Schema::read(&pager).unwrap();
+        let statement = &sql_parser::parse_sql("ANALYZE person").unwrap()[0];
+        let error = Executor::new(&pager, &schema)
+            .execute(statement)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "attempt to write a readonly database");
+    }
+
+    #[test]
+    fn test_analyze_waits_for_sqlite3_locks() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::process::Stdio;
+
+        let db = ScratchDb::copy(get_test_db_file_path(), "locked");
+        let mut shell = match Command::new("sqlite3")
+            .arg(db.path())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Ok(shell) => shell,
+            Err(_) => return,
+        };
+        let mut input = shell.stdin.take().unwrap();
+        let mut output = BufReader::new(shell.stdout.take().unwrap());
+        // runs SQL in the shell and waits for it to be done
+        let mut run = |sql: &str| {
+            writeln!(input, "{} SELECT 'done';", sql).unwrap();
+            let mut line = String::new();
+            while line.trim() != "done" {
+                line.clear();
+                assert!(output.read_line(&mut line).unwrap() > 0);
+            }
+        };
+
+        // a reader keeps the write from being committed, and a writer keeps
+        // it from starting
+        for begin in &["BEGIN; SELECT count(*) > 0 FROM Genre;", "BEGIN IMMEDIATE;"] {
+            run(begin);
+            assert_eq!(
+                query_error(db.path(), "ANALYZE Genre"),
+                "database is locked",
+                "{}",
+                begin
+            );
+            run("COMMIT;");
+        }
+        let mut journal = db.path().into_os_string();
+        journal.push("-journal");
+        assert!(!path::Path::new(&journal).exists());
+        assert_eq!(
+            sqlite3_rows(
+                db.path(),
+                "SELECT count(*) FROM sqlite_master WHERE name = 'sqlite_stat1'"
+            )
+            .unwrap(),
+            ["0"]
+        );
+
+        drop(input);
+        shell.wait().unwrap();
+        run_query(db.path(), "ANALYZE Genre");
+        assert_eq!(
+            sqlite3_rows(db.path(), "SELECT tbl FROM sqlite_stat1").unwrap(),
+            ["Genre"]
+        );
+    }
+
+    #[test]
+    fn test_planner_uses_analyzed_statistics() {
+        // without statistics either index of Track looks as good, while
+        // sqlite_stat1 shows that an album has fewer tracks than a media type
+        let sql = "SELECT * FROM Track WHERE MediaTypeId = 1 AND AlbumId = 1";
+        let db = ScratchDb::copy(get_test_db_file_path(), "planner-statistics");
+        let pager = Pager::open(db.path()).unwrap();
+        let schema = Schema::read(&pager).unwrap();
+        let executor = Executor::new(&pager, &schema);
+        let plan = |executor: &Executor| {
+            let statement = &sql_parser::parse_sql(sql).unwrap()[0];
+            executor.query_plan(statement).unwrap().to_string()
+        };
+        assert!(plan(&executor).contains("IFK_TrackMediaTypeId"));
+        let statement = &sql_parser::parse_sql("ANALYZE").unwrap()[0];
+        executor.execute(statement).unwrap();
+        assert!(plan(&executor).contains("IFK_TrackAlbumId"));
+
+        // statistics written by SQLite itself
+        let db = ScratchDb::copy(get_test_db_file_path(), "sqlite3-statistics");
+        if sqlite3_rows(db.path(), "ANALYZE").is_some() {
+            let (plan, expected) = query_plans(db.path(), sql).unwrap();
+            assert_eq!(plan, expected);
+            assert!(plan[1].contains("IFK_TrackAlbumId"));
+        }
+    }
+
+    #[test]
+    fn test_registered_collation() {
+        let pager = Pager::open(get_collation_db_file_path()).unwrap();
+        let schema = Schema::read(&pager).unwrap();
+        let mut executor = Executor::new(&pager, &schema);
+        executor.register_collation("reverse", |a: &str, b: &str| b.cmp(a));
+        let query = |sql: &str| {
+            let statement = &sql_parser::parse_sql(sql).unwrap()[0];
+            executor.execute(statement).unwrap()
+        };
+        // city_name is ordered by the registered collation
+        let result = query("SELECT name FROM city ORDER BY name");
+        assert_eq!(
+            column_text(&result, 0),
+            ["Tokyo", "Paris", "Lyon", "Berlin"]
+        );
+        let result = query("SELECT country FROM city WHERE name = 'Lyon'");
+        assert_eq!(column_text(&result, 0), ["FR"]);
+        let result = query("SELECT 'a' < 'b' COLLATE reverse, max(name COLLATE reverse) FROM city");
+        assert_eq!(result.rows[0][0].as_i64(), Some(0));
+        assert_eq!(text(&result.rows[0][1]), "Berlin");
+
+        assert_eq!(
+            query_error(get_collation_db_file_path(), "SELECT 'a' COLLATE reverse"),
+            "no such collation sequence: reverse"
+        );
+    }
+
+    // The longest text among the values of a group.
+    struct Longest;
+
+    impl Aggregate for Longest {
+        type State = Option<String>;
+
+        fn init(&self) -> Option<String> {
+            None
+        }
+
+        fn step(&self, state: &mut Option<String>, args: &[SqlValue]) -> Result<(), io::Error> {
+            if let Some(text) = args[0].to_text() {
+                if state.as_ref().is_none_or(|s| text.len() > s.len()) {
+                    *state = Some(text);
+                }
+            }
+            Ok(())
+        }
+
+        fn finalize(&self, state: &Option<String>) -> Result<SqlValue, io::Error> {
+            Ok(state.clone().map_or(SqlValue::Null, SqlValue::Text))
+        }
+    }
+
+    #[test]
+    fn test_registered_functions() {
+        let pager = Pager::open(get_test_db_file_path()).unwrap();
+        let schema = Schema::read(&pager).unwrap();
+        let mut executor = Executor::new(&pager, &schema);
+        let deterministic = FunctionFlags {
+            deterministic: true,
+            innocuous: true,
+        };
+        executor.register_scalar("slug", Some(1), deterministic, |args| {
+            Ok(match args[0].to_text() {
+                Some(text) => SqlValue::Text(text.to_lowercase().replace(' ', "-")),
+                None => SqlValue::Null,
+            })
+        });
+        executor.register_scalar("fail", None, deterministic, |args| {
+            Err(io::Error::other(format!(
+                "failed with {} arguments",
+                args.len()
+            )))
+        });
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        executor.register_scalar("next_id", Some(0), FunctionFlags::default(), move |_| {
+            counter.set(counter.get() + 1);
+            Ok(SqlValue::Integer(counter.get()))
+        });
+        executor.register_aggregate("longest", Some(1), deterministic, Longest);
+        let query = |sql: &str| {
+            let statement = &sql_parser::parse_sql(sql).unwrap()[0];
+            executor.execute(statement)
+        };
+        let same_rows = |sql: &str, sqlite_sql: &str| {
+            let expected = match sqlite3_rows(get_test_db_file_path(), sqlite_sql) {
+                Some(rows) => rows,
+                None => return,
+            };
+            assert_eq!(shell_rows(&query(sql).unwrap()), expected, "{}", sql);
+        };
+
+        same_rows(
+            "SELECT TrackId, SLUG(Name) FROM Track WHERE slug(Name) LIKE 'b%' LIMIT 5",
+            "SELECT TrackId, lower(replace(Name, ' ', '-')) FROM Track \
+             WHERE Name LIKE 'b%' LIMIT 5",
+        );
+        same_rows(
+            "SELECT AlbumId, longest(Name), count(*) FROM Track WHERE AlbumId < 6 \
+             GROUP BY AlbumId HAVING longest(Name) <> ''",
+            "SELECT AlbumId, (SELECT Name FROM Track t WHERE t.AlbumId = Track.AlbumId \
+             ORDER BY length(Name) DESC, TrackId LIMIT 1), count(*) FROM Track \
+             WHERE AlbumId < 6 GROUP BY AlbumId",
+        );
+        same_rows(
+            "SELECT TrackId, longest(Name) OVER (ORDER BY TrackId ROWS 1 PRECEDING) \
+             FROM Track WHERE TrackId < 5",
+            "SELECT TrackId, CASE WHEN length(lag(Name) OVER w) >= length(Name) \
+             THEN lag(Name) OVER w ELSE Name END FROM Track WHERE TrackId < 5 \
+             WINDOW w AS (ORDER BY TrackId)",
+        );
+
+        // a function that is not deterministic is called for every row
+        // rather than once to look up a row; Name keeps the rows in rowid
+        // order, which no index covering TrackId alone would
+        let result = query("SELECT count(Name) FROM Track WHERE TrackId = next_id()").unwrap();
+        assert_eq!(result.rows[0][0].as_i64(), Some(3503));
+        assert_eq!(calls.get(), 3503);
+
+        let error = |sql: &str| query(sql).unwrap_err().to_string();
+        assert_eq!(error("SELECT fail(1, 2)"), "failed with 2 arguments");
+        assert_eq!(
+            error("SELECT slug(Name, 1) FROM Genre"),
+            "wrong number of arguments to function slug()"
+        );
+        assert_eq!(
+            error("SELECT longest(Name, 1) FROM Genre"),
+            "wrong number of arguments to function longest()"
+        );
+        assert_eq!(
+            query_error(get_test_db_file_path(), "SELECT slug('A')"),
+            "no such function: slug"
+        );
+    }
+
+    #[test]
+    fn test_prepared_statements() {
+        let connection = Connection::open(get_test_db_file_path()).unwrap();
+        let mut statement = connection
+            .prepare("SELECT ArtistId, Name FROM Artist WHERE ArtistId = ?1 OR Name = :name")
+            .unwrap();
+        assert_eq!(statement.parameter_count(), 2);
+        assert_eq!(statement.parameter_index(":name"), Some(2));
+        let names = |statement: &mut Statement| -> Vec<String> {
+            statement
+                .query_map((), |row| row.get(1))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+
+        statement.bind(1, 1).unwrap();
+        assert_eq!(names(&mut statement), vec!["AC/DC"]);
+        statement.bind_named(":name", "Aerosmith").unwrap();
+        assert_eq!(names(&mut statement), vec!["AC/DC", "Aerosmith"]);
+        statement.clear_bindings();
+        assert!(names(&mut statement).is_empty());
+
+        // a bound value is never read as SQL
+        statement.bind_named(":name", "x' OR '1' = '1").unwrap();
+        assert!(names(&mut statement).is_empty());
+
+        assert!(statement.bind(3, 1).is_err());
+        assert_eq!(
+            statement.bind_named("@name", 1).unwrap_err().to_string(),
+            "no such parameter: @name"
+        );
+
+        // parameters also seek, limit and name columns
+        let mut statement = connection
+            .prepare("SELECT ?, $x, Name FROM Track WHERE AlbumId = @a LIMIT ? OFFSET ?")
+            .unwrap();
+        let rows = statement.query(params!["?", None::<i64>, 3, 2, 1]).unwrap();
+        assert_eq!(rows.columns(), ["?", "$x", "Name"]);
+        let rows: Vec<Vec<SqlValue>> = rows.map(|row| row.unwrap().into_values()).collect();
+        let result = ResultSet {
+            columns: Vec::new(),
+            rows,
+        };
+        assert_eq!(
+            shell_rows(&result),
+            vec!["?||Restless and Wild", "?||Princess of the Dawn"]
+        );
+    }
+
+    #[test]
+    fn test_connection() {
+        let mut connection = Connection::open(get_test_db_file_path()).unwrap();
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        connection.register_scalar("next_id", Some(0), FunctionFlags::default(), move |_| {
+            counter.set(counter.get() + 1);
+            Ok(SqlValue::Integer(counter.get()))
+        });
+
+        let count: i64 = connection
+            .query_row("SELECT count(*) FROM Track WHERE AlbumId = ?", [1], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 10);
+
+        let tracks: Vec<(i64, String, Option<String>)> = connection
+            .query_map(
+                "SELECT TrackId, Name, Composer AS composer FROM Track \
+                 WHERE TrackId IN (:a, :b) ORDER BY Name DESC",
+                named_params! { ":a": 1, ":b": 2 },
+                |row| Ok((row.get(0)?, row.get("name")?, row.get("Composer")?)),
+            )
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            tracks,
+            vec![
+                (
+                    1,
+                    "For Those About To Rock (We Salute You)".to_string(),
+                    Some("Angus Young, Malcolm Young, Brian Johnson".to_string())
+                ),
+                (2, "Balls to the Wall".to_string(), None),
+            ]
+        );
+
+        // rows are read as they are asked for
+        let rows = connection.query("SELECT next_id() FROM Track", ()).unwrap();
+        let first: Vec<i64> = rows
+            .take(3)
+            .map(|row| row.unwrap().get(0).unwrap())
+            .collect();
+        assert_eq!(first, vec![1, 2, 3]);
+        assert_eq!(calls.get(), 3);
+
+        let row = connection
+            .query_row("SELECT Name, Milliseconds FROM Track", (), |row| {
+                Ok(row.clone())
+            })
+            .unwrap();
+        assert_eq!(row.get::<_, f64>("Milliseconds").unwrap(), 343719.0);
+        let error = |result: Result<i64, io::Error>| result.unwrap_err().to_string();
+        assert_eq!(error(row.get(0)), "cannot convert text value to i64");
+        assert_eq!(error(row.get(2)), "column index out of range: 2");
+        assert_eq!(error(row.get("Composer")), "no such column: Composer");
+
+        let error = connection
+            .query_row("SELECT 1 FROM Track WHERE TrackId = 0", (), |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            connection
+                .query("SELECT ?", [1, 2])
+                .err()
+                .unwrap()
+                .to_string(),
+            "wrong number of parameters: 2 given, 1 expected"
+        );
+        assert_eq!(connection.execute("SELECT 1", ()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_streamed_rows_match_materialized() {
+        let connection = Connection::open(get_test_db_file_path()).unwrap();
+        for sql in [
+            "SELECT TrackId, Name FROM Track WHERE GenreId = 5 LIMIT 7 OFFSET 3",
+            "SELECT a.Title, t.Name FROM Album a JOIN Track t ON t.AlbumId = a.AlbumId \
+             WHERE a.ArtistId = 1 LIMIT 12 OFFSET 5",
+            "SELECT ar.Name, al.Title FROM Artist ar LEFT JOIN Album al \
+             ON al.ArtistId = ar.ArtistId WHERE ar.ArtistId BETWEEN 20 AND 30",
+            "SELECT Name FROM Artist ORDER BY ArtistId DESC LIMIT 4",
+            "SELECT Name FROM Artist ORDER BY Name LIMIT 4",
+            "SELECT GenreId, count(*) FROM Track GROUP BY GenreId",
+        ] {
+            let rows: Vec<Vec<SqlValue>> = connection
+                .query(sql, ())
+                .unwrap()
+                .map(|row| row.unwrap().into_values())
+                .collect();
+            assert_eq!(
+                rows,
+                run_query(get_test_db_file_path(), sql).rows,
+                "{}",
+                sql
+            );
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_query_as() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Track {
+            track_id: i64,
+            name: String,
+            album_id: Option<i64>,
+            composer: Option<String>,
+            milliseconds: u32,
+            unit_price: f64,
+        }
+
+        let connection = Connection::open(get_test_db_file_path()).unwrap();
+        let tracks: Vec<Track> = connection.query_as("SELECT * FROM Track").unwrap();
+        assert_eq!(tracks.len(), 3503);
+        assert_eq!(
+            tracks[1],
+            Track {
+                track_id: 2,
+                name: "Balls to the Wall".into(),
+                album_id: Some(2),
+                composer: None,
+                milliseconds: 342562,
+                unit_price: 0.99,
+            }
+        );
+        let names: Vec<(String, i64)> = connection
+            .prepare(
+                "SELECT g.Name, count(*) FROM Genre g JOIN Track USING (GenreId) \
+                      WHERE GenreId < ? GROUP BY g.Name ORDER BY g.Name",
+            )
+            .unwrap()
+            .query_as([3])
+            .unwrap();
+        assert_eq!(
+            names,
+            vec![("Jazz".to_string(), 130), ("Rock".to_string(), 1297)]
+        );
+    }
+
+    #[derive(Debug, Default, PartialEq, rsqlite_derive::FromRow, rsqlite_derive::ToRow)]
+    struct Price {
+        #[rsqlite(rename = "UnitPrice")]
+        amount: f64,
+    }
+
+    #[derive(Debug, PartialEq, rsqlite_derive::FromRow, rsqlite_derive::ToRow)]
+    #[rsqlite(rename_all = "PascalCase")]
+    struct Track {
+        track_id: i64,
+        name: String,
+        composer: Option<String>,
+        #[rsqlite(flatten)]
+        price: Price,
+        #[rsqlite(default)]
+        plays: i64,
+        #[rsqlite(skip)]
+        note: String,
+    }
+
+    #[test]
+    fn test_derived_rows() {
+        use crate::{FromRow, ToRow};
+
+        let connection = Connection::open(get_test_db_file_path()).unwrap();
+        let tracks: Vec<Track> = connection
+            .query(
+                "SELECT TrackId, Name, Composer, UnitPrice FROM Track \
+                 WHERE AlbumId = ? OR TrackId = 3",
+                [2],
+            )
+            .unwrap()
+            .typed()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let balls = Track {
+            track_id: 2,
+            name: "Balls to the Wall".into(),
+            composer: None,
+            price: Price { amount: 0.99 },
+            plays: 0,
+            note: String::new(),
+        };
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0], balls);
+        assert_eq!(
+            tracks[1].composer.as_deref(),
+            Some("F. Baltes, S. Kaufman, U. Dirkscneider & W. Hoffman")
+        );
+
+        let error = connection
+            .query_row("SELECT TrackId FROM Track", (), Track::from_row)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no such column: Name");
+        let price = connection
+            .query_row("SELECT 1.5 AS unitprice", (), Price::from_row)
+            .unwrap();
+        assert_eq!(price, Price { amount: 1.5 });
+
+        // the fields bind parameters by name, or in order
+        let columns: Vec<&str> = balls.to_row().iter().map(|(c, _)| *c).collect();
+        assert_eq!(
+            columns,
+            ["TrackId", "Name", "Composer", "UnitPrice", "Plays"]
+        );
+        let echo = |sql: &str| {
+            connection
+                .query_row(sql, &balls, |row| Ok(row.clone().into_values()))
+                .unwrap()
+        };
+        assert_eq!(
+            echo("SELECT :Name, $TrackId, @UnitPrice, :Unknown"),
+            vec![
+                SqlValue::Text("Balls to the Wall".into()),
+                SqlValue::Integer(2),
+                SqlValue::Real(0.99),
+                SqlValue::Null,
+            ]
+        );
+        assert_eq!(echo("SELECT ?, ?, ?, ?, ?").len(), 5);
+        let copy = connection
+            .query_row(
+                "SELECT ? AS TrackId, ? AS Name, ? AS Composer, ? AS UnitPrice, ? AS Plays",
+                &balls,
+                Track::from_row,
+            )
+            .unwrap();
+        assert_eq!(copy, balls);
+    }
+
     #[test]
     fn test_get_db_page() {
         let mut f = get_simple_db_file();
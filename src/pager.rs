@@ -0,0 +1,595 @@
+use crate::db_page::{CellLayout, DBHeader, DBPage};
+use crate::util;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Pages are dropped from the cache wholesale once it grows past this size.
+const MAX_CACHED_PAGES: usize = 2048;
+
+// The start of a rollback journal, and the size of its header, which is
+// padded to a sector of this size.
+const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+const JOURNAL_SECTOR_SIZE: usize = 512;
+
+// The page holding the byte at this offset is left unused, as SQLite keeps
+// it for its file locks: a writer locks the pending byte while it waits for
+// readers to finish, the reserved byte while it prepares its changes, and
+// the shared range, which readers lock for reading, while it writes.
+const PENDING_BYTE: u64 = 0x4000_0000;
+const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+const SHARED_SIZE: u64 = 510;
+
+// Reads pages from a database file and keeps recently used ones in memory.
+// Pages are handed out as `Rc`s so several cursors can walk the file at once.
+pub struct Pager {
+    file: RefCell<File>,
+    // Set when the file was opened by path, which changing it needs, as the
+    // file is only opened for writing then.
+    path: Option<PathBuf>,
+    header: RefCell<DBHeader>,
+    cache: RefCell<HashMap<u32, Rc<DBPage>>>,
+}
+
+impl Pager {
+    // Opens a database file for reading. A transaction opens it again for
+    // writing.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Pager, Error> {
+        let path = path.as_ref();
+        let mut pager = Pager::new(File::open(path)?)?;
+        pager.path = Some(path.to_path_buf());
+        Ok(pager)
+    }
+
+    pub fn new(mut f: File) -> Result<Pager, Error> {
+        let header = read_header(&mut f)?;
+        Ok(Pager {
+            file: RefCell::new(f),
+            path: None,
+            header: RefCell::new(header),
+            cache: RefCell::new(HashMap::new()),
+        })
+    }
+
+    // Forgets the cached pages and reads the header again if another
+    // connection has written the file since, as its change counter tells.
+    pub fn refresh(&self) -> Result<(), Error> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(24))?;
+        let mut counter = [0; 4];
+        file.read_exact(&mut counter)?;
+        if u32::from_be_bytes(counter) != self.header.borrow().change_counter {
+            *self.header.borrow_mut() = read_header(&mut file)?;
+            self.cache.borrow_mut().clear();
+        }
+        Ok(())
+    }
+
+    pub fn page_size(&self) -> usize {
+        usize::from(self.header.borrow().page_size_in_bytes)
+    }
+
+    pub fn usable_size(&self) -> usize {
+        self.header.borrow().usable_size()
+    }
+
+    pub fn page_count(&self) -> u32 {
+        self.header.borrow().size_of_db_in_pages
+    }
+
+    // The page SQLite keeps for its file locks, which holds no data.
+    pub fn lock_byte_page(&self) -> u32 {
+        (PENDING_BYTE / self.page_size() as u64) as u32 + 1
+    }
+
+    // The number SQLite bumps whenever sqlite_master changes, so that
+    // readers know to read the schema again.
+    pub fn schema_cookie(&self) -> Result<u32, Error> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(40))?;
+        let mut cookie = [0; 4];
+        file.read_exact(&mut cookie)?;
+        Ok(u32::from_be_bytes(cookie))
+    }
+
+    // Whether the database keeps pointer-map pages, which auto-vacuum and
+    // incremental-vacuum databases do.
+    pub fn is_auto_vacuum(&self) -> Result<bool, Error> {
+        Ok(self.read_raw_page(1)?[52..56] != [0; 4])
+    }
+
+    // Whether a page of an auto-vacuum database is a pointer-map page. The
+    // first one is page 2, and each is followed by the pages it maps.
+    pub fn is_pointer_map_page(&self, page_no: u32) -> bool {
+        if page_no < 2 {
+            return false;
+        }
+        let pages_per_map = self.usable_size() as u32 / 5 + 1;
+        let mut map_page = (page_no - 2) / pages_per_map * pages_per_map + 2;
+        if map_page == self.lock_byte_page() {
+            map_page += 1;
+        }
+        map_page == page_no
+    }
+
+    // Reads the freelist from the first trunk page the header names. Each
+    // trunk page lists leaf pages, which hold nothing, and names the next
+    // trunk page.
+    pub fn freelist(&self) -> Result<Freelist, Error> {
+        let corrupt = || Error::new(ErrorKind::InvalidData, "the freelist is corrupt");
+        let first = self.read_raw_page(1)?;
+        let mut next = util::as_u32_be(&first[32..36].try_into().unwrap());
+        let max_leaves = self.usable_size() / 4 - 2;
+        let mut freelist = Freelist::default();
+        while next != 0 {
+            // a chain longer than the file loops
+            if next > self.page_count()
+                || freelist.trunks.len() + freelist.leaves.len() >= self.page_count() as usize
+            {
+                return Err(corrupt());
+            }
+            let raw = self.read_raw_page(next)?;
+            let count = util::as_u32_be(&raw[4..8].try_into().unwrap()) as usize;
+            if count > max_leaves {
+                return Err(corrupt());
+            }
+            freelist.trunks.push(next);
+            for i in 0..count {
+                let leaf = util::as_u32_be(&raw[8 + i * 4..12 + i * 4].try_into().unwrap());
+                if leaf == 0 || leaf > self.page_count() {
+                    return Err(corrupt());
+                }
+                freelist.leaves.push(leaf);
+            }
+            next = util::as_u32_be(&raw[0..4].try_into().unwrap());
+        }
+        Ok(freelist)
+    }
+
+    pub fn get_page(&self, page_no: u32) -> Result<Rc<DBPage>, Error> {
+        if page_no == 0 || page_no > self.page_count() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("page {} is out of range", page_no),
+            ));
+        }
+        if let Some(page) = self.cache.borrow().get(&page_no) {
+            return Ok(Rc::clone(page));
+        }
+
+        let page = Rc::new(DBPage::read_page(
+            &mut self.file.borrow_mut(),
+            &self.header.borrow(),
+            page_no,
+        )?);
+        let mut cache = self.cache.borrow_mut();
+        if cache.len() >= MAX_CACHED_PAGES {
+            cache.clear();
+        }
+        cache.insert(page_no, Rc::clone(&page));
+        Ok(page)
+    }
+
+    // Reads a page without interpreting it as a b-tree page, as needed for
+    // overflow pages.
+    pub fn read_raw_page(&self, page_no: u32) -> Result<Vec<u8>, Error> {
+        if page_no == 0 || page_no > self.page_count() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("page {} is out of range", page_no),
+            ));
+        }
+        DBPage::raw_read(
+            &mut self.file.borrow_mut(),
+            self.page_offset(page_no),
+            self.header.borrow().page_size_in_bytes,
+        )
+    }
+
+    fn page_offset(&self, page_no: u32) -> u64 {
+        u64::from(page_no - 1) * self.page_size() as u64
+    }
+
+    // Returns the full payload of a cell, following its overflow chain if
+    // the payload does not fit on the page.
+    pub fn read_payload(&self, page: &DBPage, layout: &CellLayout) -> Result<Vec<u8>, Error> {
+        self.payload(page, layout)
+            .bytes(0..layout.payload_size)
+            .map(Cow::into_owned)
+    }
+
+    // The payload of a cell, without reading any of its overflow pages yet.
+    pub fn payload<'p>(&'p self, page: &'p DBPage, layout: &CellLayout) -> Payload<'p> {
+        let start = layout.payload_start;
+        Payload {
+            pager: self,
+            local: &page.raw_bytes[start..start + layout.local_size],
+            size: layout.payload_size,
+            overflow: RefCell::new((Vec::new(), layout.first_overflow_page)),
+        }
+    }
+}
+
+// The pages that are not in use, as the freelist lists them.
+#[derive(Debug, Default, Clone)]
+pub struct Freelist {
+    pub trunks: Vec<u32>,
+    pub leaves: Vec<u32>,
+}
+
+// The payload of a cell, read lazily. The part stored on the cell's page is
+// borrowed from it, and overflow pages are only read once bytes on them are
+// asked for, as far along the chain as those bytes go.
+pub struct Payload<'p> {
+    pager: &'p Pager,
+    local: &'p [u8],
+    size: usize,
+    // The overflow bytes read so far, and the page holding the next ones.
+    overflow: RefCell<(Vec<u8>, Option<u32>)>,
+}
+
+impl<'p> Payload<'p> {
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn bytes(&self, range: Range<usize>) -> Result<Cow<'p, [u8]>, Error> {
+        if range.start > range.end || range.end > self.size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "read past the end of the payload",
+            ));
+        }
+        if range.end <= self.local.len() {
+            return Ok(Cow::Borrowed(&self.local[range]));
+        }
+
+        let end = range.end - self.local.len();
+        let mut overflow = self.overflow.borrow_mut();
+        let (read, next_page) = &mut *overflow;
+        let overflow_capacity = self.pager.usable_size() - 4;
+        while read.len() < end {
+            let page_no = next_page.ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "overflow chain ended before the end of the payload",
+                )
+            })?;
+            let raw = self.pager.read_raw_page(page_no)?;
+            let remaining = self.size - self.local.len() - read.len();
+            read.extend_from_slice(&raw[4..4 + remaining.min(overflow_capacity)]);
+            *next_page = match util::as_u32_be(&raw[0..4].try_into().unwrap()) {
+                0 => None,
+                n => Some(n),
+            };
+        }
+
+        let mut bytes = Vec::with_capacity(range.len());
+        if range.start < self.local.len() {
+            bytes.extend_from_slice(&self.local[range.start..]);
+        }
+        bytes.extend_from_slice(&read[range.start.saturating_sub(self.local.len())..end]);
+        Ok(Cow::Owned(bytes))
+    }
+}
+
+// Changes to the pages of a database, which are kept in memory until they
+// are all written at once. The transaction holds SQLite's RESERVED lock on
+// the file, so no other connection starts writing meanwhile, and takes the
+// EXCLUSIVE lock to write, which waits for no reader.
+pub struct Transaction<'a> {
+    pager: &'a Pager,
+    lock: FileLock,
+    pages: BTreeMap<u32, Vec<u8>>,
+    page_count: u32,
+    // Pages that are no longer used, which new pages are taken from first
+    // and the rest of which go to the freelist.
+    free: BTreeSet<u32>,
+    schema_changed: bool,
+}
+
+impl Pager {
+    // Starts changing the database. Databases in WAL mode, auto-vacuum
+    // databases, which would need their pointer maps kept up to date, and
+    // databases with a journal left behind by an interrupted write cannot
+    // be changed, nor can a database another connection is writing, which
+    // is "database is locked".
+    pub fn begin(&self) -> Result<Transaction<'_>, Error> {
+        let readonly = || {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                "attempt to write a readonly database",
+            )
+        };
+        let path = self.path.as_ref().ok_or_else(readonly)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|_| readonly())?;
+        let lock = FileLock::shared(file)?;
+        lock.reserve()?;
+        // another connection may have written the file since it was read
+        self.refresh()?;
+        let first = self.read_raw_page(1)?;
+        if first[18] == 2 || first[19] == 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "unsupported: writing a database in WAL mode",
+            ));
+        }
+        if first[52..56] != [0; 4] {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "unsupported: writing an auto-vacuum database",
+            ));
+        }
+        // a writer holding the RESERVED lock would have its journal, but with
+        // the lock held here a journal is one left by an interrupted write
+        if journal_path(path).exists() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the database has a hot journal, which SQLite rolls back when it opens it",
+            ));
+        }
+        Ok(Transaction {
+            pager: self,
+            lock,
+            pages: BTreeMap::new(),
+            page_count: self.page_count(),
+            free: BTreeSet::new(),
+            schema_changed: false,
+        })
+    }
+}
+
+impl<'a> Transaction<'a> {
+    pub fn pager(&self) -> &'a Pager {
+        self.pager
+    }
+
+    // Finds a page for new content, a freed one if there is one, or else
+    // one past the end of the file.
+    pub fn allocate(&mut self) -> u32 {
+        if let Some(page_no) = self.free.iter().next().copied() {
+            self.free.remove(&page_no);
+            return page_no;
+        }
+        self.page_count += 1;
+        if self.page_count == self.pager.lock_byte_page() {
+            self.page_count += 1;
+        }
+        self.page_count
+    }
+
+    pub fn free(&mut self, page_no: u32) {
+        self.pages.remove(&page_no);
+        self.free.insert(page_no);
+    }
+
+    // Sets the full content of a page. The first 100 bytes of page 1 are
+    // the database header, which the transaction fills in itself.
+    pub fn write(&mut self, page_no: u32, page: Vec<u8>) {
+        self.pages.insert(page_no, page);
+    }
+
+    // Marks sqlite_master as changed, which tells other connections to read
+    // the schema again.
+    pub fn schema_changed(&mut self) {
+        self.schema_changed = true;
+    }
+
+    // Writes the changed pages to the file. The pages they replace go to a
+    // rollback journal first, in SQLite's format, so that SQLite restores
+    // them if the write is cut short; the journal is deleted once the new
+    // pages are safely written.
+    pub fn commit(mut self) -> Result<(), Error> {
+        let pager = self.pager;
+        let usable_size = pager.usable_size();
+        let mut first = pager.read_raw_page(1)?;
+        let mut header = first[..100].to_vec();
+
+        // freed pages become freelist trunk pages, each listing as many of
+        // the others as SQLite puts on one, in front of the existing ones
+        let mut trunk = util::as_u32_be(&header[32..36].try_into().unwrap());
+        let mut free_count = util::as_u32_be(&header[36..40].try_into().unwrap());
+        let free: Vec<u32> = self.free.iter().copied().collect();
+        for chunk in free.chunks(usable_size / 4 - 7).rev() {
+            let mut page = vec![0; pager.page_size()];
+            page[0..4].copy_from_slice(&trunk.to_be_bytes());
+            page[4..8].copy_from_slice(&(chunk.len() as u32 - 1).to_be_bytes());
+            for (i, leaf) in chunk[1..].iter().enumerate() {
+                page[8 + i * 4..12 + i * 4].copy_from_slice(&leaf.to_be_bytes());
+            }
+            self.pages.insert(chunk[0], page);
+            trunk = chunk[0];
+            free_count += chunk.len() as u32;
+        }
+
+        let change_counter = util::as_u32_be(&header[24..28].try_into().unwrap()).wrapping_add(1);
+        header[24..28].copy_from_slice(&change_counter.to_be_bytes());
+        header[28..32].copy_from_slice(&self.page_count.to_be_bytes());
+        header[32..36].copy_from_slice(&trunk.to_be_bytes());
+        header[36..40].copy_from_slice(&free_count.to_be_bytes());
+        if self.schema_changed {
+            let cookie = util::as_u32_be(&header[40..44].try_into().unwrap()).wrapping_add(1);
+            header[40..44].copy_from_slice(&cookie.to_be_bytes());
+        }
+        header[92..96].copy_from_slice(&change_counter.to_be_bytes());
+        if let Some(page) = self.pages.get(&1) {
+            first = page.clone();
+        }
+        first[..100].copy_from_slice(&header);
+        self.pages.insert(1, first);
+
+        self.lock.exclusive()?;
+        let path = pager.path.as_ref().unwrap();
+        let journal_path = journal_path(path);
+        self.write_journal(&journal_path)?;
+        let file = &mut self.lock.file;
+        for (page_no, page) in &self.pages {
+            file.seek(SeekFrom::Start(pager.page_offset(*page_no)))?;
+            file.write_all(page)?;
+        }
+        file.sync_all()?;
+        fs::remove_file(&journal_path)?;
+
+        pager.cache.borrow_mut().clear();
+        let mut pager_header = pager.header.borrow_mut();
+        pager_header.size_of_db_in_pages = self.page_count;
+        pager_header.change_counter = change_counter;
+        pager_header.version_valid_for = change_counter;
+        Ok(())
+    }
+
+    fn write_journal(&self, path: &Path) -> Result<(), Error> {
+        let pager = self.pager;
+        let original: Vec<u32> = self
+            .pages
+            .keys()
+            .copied()
+            .filter(|page_no| *page_no <= pager.page_count())
+            .collect();
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+
+        let mut journal = vec![0; JOURNAL_SECTOR_SIZE];
+        journal[0..8].copy_from_slice(&JOURNAL_MAGIC);
+        journal[8..12].copy_from_slice(&(original.len() as u32).to_be_bytes());
+        journal[12..16].copy_from_slice(&nonce.to_be_bytes());
+        journal[16..20].copy_from_slice(&pager.page_count().to_be_bytes());
+        journal[20..24].copy_from_slice(&(JOURNAL_SECTOR_SIZE as u32).to_be_bytes());
+        journal[24..28].copy_from_slice(&(pager.page_size() as u32).to_be_bytes());
+        for page_no in original {
+            let page = pager.read_raw_page(page_no)?;
+            // the checksum adds up every 200th byte, counted from the end
+            let checksum = (1..)
+                .map(|i| pager.page_size() as isize - 200 * i)
+                .take_while(|offset| *offset > 0)
+                .fold(nonce, |sum, offset| {
+                    sum.wrapping_add(page[offset as usize] as u32)
+                });
+            journal.extend_from_slice(&page_no.to_be_bytes());
+            journal.extend_from_slice(&page);
+            journal.extend_from_slice(&checksum.to_be_bytes());
+        }
+
+        let mut file = File::create(path)?;
+        file.write_all(&journal)?;
+        file.sync_all()
+    }
+}
+
+// A database file opened for writing, with SQLite's POSIX advisory locks
+// taken on it, which are released when it is dropped. The locks belong to
+// the process, so they keep other processes out but not other connections
+// of this one.
+struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    // Takes the SHARED lock, which readers hold. The pending byte is
+    // locked meanwhile, so that a writer waiting for readers to finish
+    // keeps new ones out.
+    fn shared(file: File) -> Result<FileLock, Error> {
+        let lock = FileLock { file };
+        lock.set(LockKind::Read, PENDING_BYTE, 1)?;
+        let shared = lock.set(LockKind::Read, SHARED_FIRST, SHARED_SIZE);
+        lock.set(LockKind::Unlock, PENDING_BYTE, 1)?;
+        shared.map(|()| lock)
+    }
+
+    // Takes the RESERVED lock, which only one writer holds at a time.
+    fn reserve(&self) -> Result<(), Error> {
+        self.set(LockKind::Write, RESERVED_BYTE, 1)
+    }
+
+    // Takes the EXCLUSIVE lock, which no reader may hold along with it.
+    fn exclusive(&self) -> Result<(), Error> {
+        self.set(LockKind::Write, PENDING_BYTE, 1)?;
+        self.set(LockKind::Write, SHARED_FIRST, SHARED_SIZE)
+    }
+
+    #[cfg(unix)]
+    fn set(&self, kind: LockKind, start: u64, len: u64) -> Result<(), Error> {
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: flock is a plain struct, for which zeroes are valid
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = match kind {
+            LockKind::Read => libc::F_RDLCK,
+            LockKind::Write => libc::F_WRLCK,
+            LockKind::Unlock => libc::F_UNLCK,
+        } as libc::c_short;
+        lock.l_whence = libc::SEEK_SET as libc::c_short;
+        lock.l_start = start as libc::off_t;
+        lock.l_len = len as libc::off_t;
+        // SAFETY: F_SETLK only reads the flock it is given
+        if unsafe { libc::fcntl(self.file.as_raw_fd(), libc::F_SETLK, &lock) } == 0 {
+            return Ok(());
+        }
+        let error = Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EACCES) => {
+                Err(Error::new(ErrorKind::WouldBlock, "database is locked"))
+            }
+            _ => Err(error),
+        }
+    }
+
+    // Without POSIX locks, other connections could not be kept out, so the
+    // file is not written at all.
+    #[cfg(not(unix))]
+    fn set(&self, _kind: LockKind, _start: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "unsupported: writing a database without file locks",
+        ))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.set(LockKind::Unlock, PENDING_BYTE, 2 + SHARED_SIZE);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum LockKind {
+    Read,
+    Write,
+    Unlock,
+}
+
+fn read_header(f: &mut File) -> Result<DBHeader, Error> {
+    f.seek(SeekFrom::Start(0))?;
+    let mut header = DBHeader::from(f)?;
+    // files written by very old versions of SQLite leave the page count
+    // unset, and versions that do not know it leave it stale
+    if header.size_of_db_in_pages == 0 || header.version_valid_for != header.change_counter {
+        let length = f.metadata()?.len();
+        header.size_of_db_in_pages = (length / u64::from(header.page_size_in_bytes)) as u32;
+    }
+    Ok(header)
+}
+
+fn journal_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push("-journal");
+    PathBuf::from(name)
+}
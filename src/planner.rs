@@ -0,0 +1,617 @@
+use crate::btree::{tree_size, BTreeCursor, TreeSize};
+use crate::expr::{compare_values, Collation};
+use crate::pager::Pager;
+use crate::schema::{Index, Schema, Table};
+use crate::value::SqlValue;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Error;
+use std::rc::Rc;
+
+// The executor plans a query by estimating what each way of finding the rows
+// of each table costs, in pages read: a scan reads every leaf page of a
+// b-tree, a seek reads one page per level of it and then as many leaf pages
+// as the entries it visits fill. Row counts come from `sqlite_stat1` when
+// ANALYZE has written it, and otherwise from the size of the b-trees.
+
+// Looking at a row or an index entry, relative to reading a page.
+const ROW_COST: f64 = 0.25;
+// Looking up a row by rowid or primary key costs this much per level of the
+// table b-tree, whose pages are mostly cached by then.
+const LOOKUP_COST: f64 = 0.25;
+// Sorting costs this much per row and per halving of the rows.
+const SORT_COST: f64 = 0.25;
+// The rows a table-valued function is assumed to produce.
+const FUNCTION_ROWS: f64 = 25.0;
+// The values an IN subquery is assumed to give, as SQLite assumes.
+pub const SUBQUERY_VALUES: usize = 25;
+// How many rows of an index share a value of its first columns when there
+// are no statistics, as SQLite assumes: 10 for the first column, then fewer
+// for each further one down to 5.
+const DEFAULT_ROWS_PER_KEY: [f64; 5] = [10.0, 9.0, 8.0, 7.0, 6.0];
+// The share of rows a bound of a range leaves, and the share a condition
+// that is not used to find the rows lets through.
+const RANGE_SELECTIVITY: f64 = 0.25;
+const EQUALITY_SELECTIVITY: f64 = 0.25;
+const FILTER_SELECTIVITY: f64 = 0.75;
+
+// How the rows of one table of a join are found.
+#[derive(Debug, Clone)]
+pub enum Access<'a> {
+    // All rows, in rowid order, or primary key order for WITHOUT ROWID
+    // tables.
+    Scan,
+    // The row with a given rowid.
+    RowId,
+    // The rows with any of the rowids an IN list gives, of which there are
+    // this many.
+    RowIds(usize),
+    // The rows whose rowid lies in a range.
+    RowIdRange {
+        lower: bool,
+        upper: bool,
+    },
+    // The rows of a WITHOUT ROWID table by the leading columns of its
+    // primary key.
+    PrimaryKey(KeyRange),
+    // The entries of an index, which give the rows they point at, or the
+    // columns of the rows themselves if the index covers all the query
+    // needs.
+    Index {
+        index: &'a Index,
+        range: KeyRange,
+        covering: bool,
+    },
+}
+
+// The part of a b-tree a seek visits: the entries whose first `equal` key
+// columns equal a value, and whose next column is bounded from below or
+// above. Without any of them, the whole b-tree. When IN lists give some of
+// the `equal` columns their values, the seek is repeated for each of the
+// `in_values` keys they make, and is not otherwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KeyRange {
+    pub equal: usize,
+    pub lower: bool,
+    pub upper: bool,
+    pub in_values: usize,
+}
+
+impl KeyRange {
+    pub fn is_full(&self) -> bool {
+        self.equal == 0 && !self.lower && !self.upper
+    }
+
+    // The share of rows the bounds leave.
+    fn selectivity(&self) -> f64 {
+        RANGE_SELECTIVITY.powi(self.lower as i32 + self.upper as i32)
+    }
+}
+
+// The estimated cost of finding the rows of a table once, and how many
+// rows that gives.
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+    pub cost: f64,
+    pub rows: f64,
+}
+
+impl Estimate {
+    // Narrows the rows down by conditions that are checked on them, `equal`
+    // of which compare a column with `=`.
+    pub fn filtered(self, equal: usize, other: usize) -> Estimate {
+        Estimate {
+            cost: self.cost,
+            rows: self.rows
+                * EQUALITY_SELECTIVITY.powi(equal as i32)
+                * FILTER_SELECTIVITY.powi(other as i32),
+        }
+    }
+}
+
+// Sorting the result of a query.
+pub fn sort_cost(rows: f64) -> f64 {
+    rows * rows.max(2.0).log2() * SORT_COST
+}
+
+// Reading rows a subquery or common table expression in FROM already
+// produced.
+pub fn materialized(rows: usize) -> Estimate {
+    Estimate {
+        cost: rows as f64 * ROW_COST,
+        rows: rows as f64,
+    }
+}
+
+pub fn table_function() -> Estimate {
+    Estimate {
+        cost: FUNCTION_ROWS * ROW_COST,
+        rows: FUNCTION_ROWS,
+    }
+}
+
+// The rows of `sqlite_stat1` by table and index name in lower case, each
+// the number of rows followed by the rows per distinct value of the first
+// one, two and more columns of the index.
+type Stat1 = HashMap<(String, String), Vec<f64>>;
+
+// The sizes of the tables and indexes of a database, which are measured
+// the first time a query needs them.
+pub struct Statistics<'a> {
+    pager: &'a Pager,
+    schema: &'a Schema,
+    sizes: RefCell<HashMap<u32, TreeSize>>,
+    stat1: RefCell<Option<Rc<Stat1>>>,
+}
+
+impl<'a> Statistics<'a> {
+    pub fn new(pager: &'a Pager, schema: &'a Schema) -> Statistics<'a> {
+        Statistics {
+            pager,
+            schema,
+            sizes: RefCell::new(HashMap::new()),
+            stat1: RefCell::new(None),
+        }
+    }
+
+    pub fn tree_size(&self, root_page: u32) -> Result<TreeSize, Error> {
+        if let Some(size) = self.sizes.borrow().get(&root_page) {
+            return Ok(*size);
+        }
+        let size = tree_size(self.pager, root_page)?;
+        self.sizes.borrow_mut().insert(root_page, size);
+        Ok(size)
+    }
+
+    fn stat1(&self) -> Result<Rc<Stat1>, Error> {
+        if let Some(stat1) = self.stat1.borrow().as_ref() {
+            return Ok(stat1.clone());
+        }
+        let mut stat1 = HashMap::new();
+        if let Some(root_page) = self.stat1_root_page()? {
+            for entry in BTreeCursor::new(self.pager, root_page) {
+                let values = entry?.record.into_values();
+                let text = |i: usize| values.get(i).and_then(|v| SqlValue::from(v).to_text());
+                let (table_name, index_name, stat) = match (text(0), text(1), text(2)) {
+                    (Some(t), i, Some(s)) => (t, i.unwrap_or_default(), s),
+                    _ => continue,
+                };
+                stat1.insert(
+                    (table_name.to_lowercase(), index_name.to_lowercase()),
+                    parse_stat(&stat),
+                );
+            }
+        }
+        let stat1 = Rc::new(stat1);
+        *self.stat1.borrow_mut() = Some(stat1.clone());
+        Ok(stat1)
+    }
+
+    // Where `sqlite_stat1` is, which ANALYZE may have created after the
+    // schema was read.
+    pub fn stat1_root_page(&self) -> Result<Option<u32>, Error> {
+        if let Some(table) = self.schema.table("sqlite_stat1") {
+            return Ok(Some(table.root_page));
+        }
+        for entry in BTreeCursor::new(self.pager, 1) {
+            let entry = entry?;
+            let values = entry.record.values();
+            let text = |i: usize| values.get(i).and_then(|v| v.as_str());
+            if text(0) == Some("table") && text(1) == Some("sqlite_stat1") {
+                return Ok(values.get(3).and_then(|v| v.as_i64()).map(|p| p as u32));
+            }
+        }
+        Ok(None)
+    }
+
+    // Forgets what was measured and read so far, after ANALYZE changed the
+    // statistics.
+    pub fn forget(&self) {
+        self.sizes.borrow_mut().clear();
+        *self.stat1.borrow_mut() = None;
+    }
+
+    // The statistics ANALYZE recorded for an index, or for a table when
+    // `index` is None. The primary key of a WITHOUT ROWID table goes by the
+    // name of the table.
+    fn stat(&self, table: &Table, index: Option<&Index>) -> Result<Option<Vec<f64>>, Error> {
+        let index_name = match index {
+            Some(index) => index.name.to_lowercase(),
+            None if table.without_rowid => table.name.to_lowercase(),
+            None => String::new(),
+        };
+        let key = (table.name.to_lowercase(), index_name);
+        Ok(self.stat1()?.get(&key).cloned())
+    }
+
+    // The number of rows of a table: that of any of its rows in
+    // `sqlite_stat1`, or else what its b-tree holds.
+    pub fn table_rows(&self, table: &Table) -> Result<f64, Error> {
+        let stat1 = self.stat1()?;
+        let name = table.name.to_lowercase();
+        let analyzed = stat1
+            .iter()
+            .find(|((t, _), stat)| *t == name && !stat.is_empty())
+            .map(|(_, stat)| stat[0]);
+        match analyzed {
+            Some(rows) => Ok(rows),
+            None => Ok(self.tree_size(table.root_page)?.entries as f64),
+        }
+    }
+
+    // How many rows share a value of the first `columns` columns of an
+    // index, or of the primary key of a WITHOUT ROWID table when `index` is
+    // None.
+    pub fn rows_per_key(
+        &self,
+        table: &Table,
+        index: Option<&Index>,
+        columns: usize,
+    ) -> Result<f64, Error> {
+        let rows = self.table_rows(table)?;
+        if columns == 0 {
+            return Ok(rows);
+        }
+        if let Some(stat) = self.stat(table, index)? {
+            if let Some(per_key) = stat.get(columns) {
+                return Ok(*per_key);
+            }
+        }
+        let (key_columns, unique) = match index {
+            Some(index) => (index.columns.len(), index.unique),
+            None => (table.primary_key.len(), true),
+        };
+        if unique && columns >= key_columns {
+            return Ok(1.0);
+        }
+        let default = DEFAULT_ROWS_PER_KEY
+            .get(columns - 1)
+            .copied()
+            .unwrap_or(5.0);
+        Ok(default.min(rows).max(1.0))
+    }
+
+    // Reading every row of a table.
+    pub fn scan(&self, table: &Table) -> Result<Estimate, Error> {
+        let rows = self.table_rows(table)?;
+        let size = self.tree_size(table.root_page)?;
+        Ok(Estimate {
+            cost: size.leaf_pages as f64 + rows * ROW_COST,
+            rows,
+        })
+    }
+
+    // Finding the rows of a table one way, before any conditions that the
+    // access leaves to be checked.
+    pub fn estimate(&self, table: &Table, access: &Access) -> Result<Estimate, Error> {
+        let table_size = self.tree_size(table.root_page)?;
+        let seeks = match access {
+            Access::PrimaryKey(range) | Access::Index { range, .. } => range.in_values.max(1),
+            _ => 1,
+        } as f64;
+        let (tree, rows, lookups) = match access {
+            Access::Scan => return self.scan(table),
+            Access::RowId => {
+                return Ok(Estimate {
+                    cost: table_size.depth as f64 * LOOKUP_COST + ROW_COST,
+                    rows: 1.0,
+                })
+            }
+            Access::RowIds(values) => {
+                let values = *values as f64;
+                return Ok(Estimate {
+                    cost: values * (table_size.depth as f64 * LOOKUP_COST + ROW_COST),
+                    rows: values,
+                });
+            }
+            Access::RowIdRange { lower, upper } => {
+                let range = KeyRange {
+                    lower: *lower,
+                    upper: *upper,
+                    ..KeyRange::default()
+                };
+                let rows = self.table_rows(table)? * range.selectivity();
+                (table_size, rows, false)
+            }
+            Access::PrimaryKey(range) => {
+                let rows =
+                    self.rows_per_key(table, None, range.equal)? * range.selectivity() * seeks;
+                (table_size, rows, false)
+            }
+            Access::Index {
+                index,
+                range,
+                covering,
+            } => {
+                let rows = self.rows_per_key(table, Some(index), range.equal)?
+                    * range.selectivity()
+                    * seeks;
+                (self.tree_size(index.root_page)?, rows, !covering)
+            }
+        };
+        let pages = rows / tree.entries_per_page();
+        let mut cost = tree.depth as f64 * seeks + pages + rows * ROW_COST;
+        if lookups {
+            cost += rows * table_size.depth as f64 * LOOKUP_COST;
+        }
+        Ok(Estimate { cost, rows })
+    }
+}
+
+// Reads the numbers at the start of a `stat` column of `sqlite_stat1`,
+// which may be followed by words such as `unordered` or `sz=N`.
+fn parse_stat(stat: &str) -> Vec<f64> {
+    stat.split_whitespace()
+        .map_while(|word| word.parse::<u64>().ok())
+        .map(|n| n as f64)
+        .collect()
+}
+
+// Counts the entries of an index b-tree, or of the b-tree of a WITHOUT
+// ROWID table, and how many distinct values the first one, two and more of
+// their key columns take under the collations of those columns, with NULLs
+// equal to each other. ANALYZE records these.
+pub fn key_counts(
+    pager: &Pager,
+    root_page: u32,
+    collations: &[Collation],
+) -> Result<(u64, Vec<u64>), Error> {
+    let mut entries = 0;
+    let mut distinct = vec![0; collations.len()];
+    let mut previous: Option<Vec<SqlValue>> = None;
+    for entry in BTreeCursor::new(pager, root_page) {
+        let record = entry?.record;
+        let key: Vec<SqlValue> = (0..collations.len())
+            .map(|i| {
+                record
+                    .values()
+                    .get(i)
+                    .map_or(SqlValue::Null, SqlValue::from)
+            })
+            .collect();
+        // the first column in which the key differs from the one before it
+        let differs = match &previous {
+            Some(previous) => (0..key.len())
+                .find(|&i| compare_values(&previous[i], &key[i], &collations[i]) != Ordering::Equal)
+                .unwrap_or(key.len()),
+            None => 0,
+        };
+        for count in &mut distinct[differs..] {
+            *count += 1;
+        }
+        entries += 1;
+        previous = Some(key);
+    }
+    Ok((entries, distinct))
+}
+
+// The `stat` column of `sqlite_stat1` as SQLite writes it: the number of
+// entries, then how many entries share each value of the first one, two
+// and more key columns on average, rounded up, and down to 1 when nearly
+// all the values are distinct.
+pub fn format_stat(entries: u64, distinct: &[u64]) -> String {
+    let mut stat = entries.to_string();
+    for count in distinct {
+        let mut per_key = entries.div_ceil(*count);
+        if per_key == 2 && entries * 10 <= count * 11 {
+            per_key = 1;
+        }
+        stat.push_str(&format!(" {}", per_key));
+    }
+    stat
+}
+
+// How EXPLAIN QUERY PLAN describes finding the rows of a table, as SQLite
+// words it.
+pub fn describe(name: &str, table: &Table, access: &Access) -> String {
+    let column = |c: Option<usize>| match c {
+        Some(c) => table.columns[c].name.clone(),
+        None => "<expr>".to_string(),
+    };
+    match access {
+        Access::Scan => format!("SCAN {}", name),
+        Access::RowId | Access::RowIds(_) => {
+            format!("SEARCH {} USING INTEGER PRIMARY KEY (rowid=?)", name)
+        }
+        Access::RowIdRange { lower, upper } => {
+            let terms = range_terms(&[], "rowid", *lower, *upper);
+            format!("SEARCH {} USING INTEGER PRIMARY KEY ({})", name, terms)
+        }
+        Access::PrimaryKey(range) => {
+            let columns: Vec<String> = table
+                .primary_key
+                .iter()
+                .take(range.equal + 1)
+                .map(|c| column(Some(*c)))
+                .collect();
+            let terms = key_terms(&columns, range);
+            format!("SEARCH {} USING PRIMARY KEY ({})", name, terms)
+        }
+        Access::Index {
+            index,
+            range,
+            covering,
+        } => {
+            let kind = if *covering { "COVERING INDEX" } else { "INDEX" };
+            if range.is_full() {
+                format!("SCAN {} USING {} {}", name, kind, index.name)
+            } else {
+                let columns: Vec<String> = index
+                    .columns
+                    .iter()
+                    .take(range.equal + 1)
+                    .map(|ic| column(ic.column))
+                    .collect();
+                let terms = key_terms(&columns, range);
+                format!("SEARCH {} USING {} {} ({})", name, kind, index.name, terms)
+            }
+        }
+    }
+}
+
+// `a=? AND b=? AND c>? AND c<?` for the key columns a seek constrains.
+fn key_terms(columns: &[String], range: &KeyRange) -> String {
+    let equal = &columns[..range.equal.min(columns.len())];
+    match columns.get(range.equal) {
+        Some(next) if range.lower || range.upper => {
+            range_terms(equal, next, range.lower, range.upper)
+        }
+        _ => range_terms(equal, "", false, false),
+    }
+}
+
+fn range_terms(equal: &[String], column: &str, lower: bool, upper: bool) -> String {
+    let mut terms: Vec<String> = equal.iter().map(|c| format!("{}=?", c)).collect();
+    if lower {
+        terms.push(format!("{}>?", column));
+    }
+    if upper {
+        terms.push(format!("{}<?", column));
+    }
+    terms.join(" AND ")
+}
+
+// The rows of EXPLAIN QUERY PLAN: the steps of a query's plan, each under
+// the step it is part of, where 0 is the query itself.
+#[derive(Debug, Default)]
+pub struct QueryPlan {
+    pub steps: Vec<(usize, usize, String)>,
+    // Subqueries in expressions are numbered through the whole statement.
+    subqueries: usize,
+}
+
+impl QueryPlan {
+    // Adds a step and returns its id.
+    pub fn add(&mut self, parent: usize, detail: String) -> usize {
+        let id = self.steps.len() + 1;
+        self.steps.push((id, parent, detail));
+        id
+    }
+
+    pub fn next_subquery(&mut self) -> usize {
+        self.subqueries += 1;
+        self.subqueries
+    }
+
+    pub fn columns() -> Vec<String> {
+        ["id", "parent", "notused", "detail"]
+            .iter()
+            .map(|c| c.to_string())
+            .collect()
+    }
+
+    // The rows of EXPLAIN QUERY PLAN, one per step.
+    pub fn rows(&self) -> Vec<Vec<SqlValue>> {
+        self.steps
+            .iter()
+            .map(|(id, parent, detail)| {
+                vec![
+                    SqlValue::Integer(*id as i64),
+                    SqlValue::Integer(*parent as i64),
+                    SqlValue::Integer(0),
+                    SqlValue::Text(detail.clone()),
+                ]
+            })
+            .collect()
+    }
+
+    // Turns the rows of EXPLAIN QUERY PLAN, `id`, `parent`, `notused` and
+    // `detail`, back into a plan.
+    pub fn from_rows(rows: &[Vec<SqlValue>]) -> QueryPlan {
+        let mut ids = HashMap::new();
+        let mut plan = QueryPlan::default();
+        for row in rows {
+            let id = row.first().and_then(SqlValue::as_i64).unwrap_or(0);
+            let parent = row.get(1).and_then(SqlValue::as_i64).unwrap_or(0);
+            let detail = row.get(3).and_then(SqlValue::to_text).unwrap_or_default();
+            let parent = ids.get(&parent).copied().unwrap_or(0);
+            ids.insert(id, plan.add(parent, detail));
+        }
+        plan
+    }
+
+    fn write_children(&self, f: &mut fmt::Formatter, parent: usize, indent: &str) -> fmt::Result {
+        let children: Vec<&(usize, usize, String)> =
+            self.steps.iter().filter(|(_, p, _)| *p == parent).collect();
+        for (i, (id, _, detail)) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            writeln!(
+                f,
+                "{}{}{}",
+                indent,
+                if last { "`--" } else { "|--" },
+                detail
+            )?;
+            let indent = format!("{}{}", indent, if last { "   " } else { "|  " });
+            self.write_children(f, *id, &indent)?;
+        }
+        Ok(())
+    }
+}
+
+// The tree the sqlite3 shell prints for EXPLAIN QUERY PLAN.
+impl fmt::Display for QueryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "QUERY PLAN")?;
+        self.write_children(f, 0, "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        assert_eq!(parse_stat("3503 11 2"), vec![3503.0, 11.0, 2.0]);
+        assert_eq!(parse_stat("100 4 unordered sz=12"), vec![100.0, 4.0]);
+        assert!(parse_stat("").is_empty());
+    }
+
+    #[test]
+    fn test_format_stat() {
+        assert_eq!(format_stat(3503, &[347]), "3503 11");
+        assert_eq!(format_stat(8715, &[14, 8715]), "8715 623 1");
+        // 11 entries with 10 distinct values share each one twice, rounded
+        // up, which SQLite writes as 1
+        assert_eq!(format_stat(11, &[10, 6]), "11 1 2");
+        assert_eq!(parse_stat(&format_stat(100, &[50])), vec![100.0, 2.0]);
+    }
+
+    #[test]
+    fn test_query_plan_tree() {
+        let mut plan = QueryPlan::default();
+        let compound = plan.add(0, "COMPOUND QUERY".to_string());
+        let left = plan.add(compound, "LEFT-MOST SUBQUERY".to_string());
+        plan.add(left, "SCAN a".to_string());
+        let union = plan.add(compound, "UNION USING TEMP B-TREE".to_string());
+        plan.add(union, "SCAN b".to_string());
+        plan.add(0, "USE TEMP B-TREE FOR ORDER BY".to_string());
+        let expected = "QUERY PLAN
+|--COMPOUND QUERY
+|  |--LEFT-MOST SUBQUERY
+|  |  `--SCAN a
+|  `--UNION USING TEMP B-TREE
+|     `--SCAN b
+`--USE TEMP B-TREE FOR ORDER BY
+";
+        assert_eq!(plan.to_string(), expected);
+
+        let rows: Vec<Vec<SqlValue>> = plan
+            .steps
+            .iter()
+            .map(|(id, parent, detail)| {
+                vec![
+                    SqlValue::Integer(*id as i64 * 10),
+                    SqlValue::Integer(*parent as i64 * 10),
+                    SqlValue::Integer(0),
+                    SqlValue::Text(detail.clone()),
+                ]
+            })
+            .collect();
+        assert_eq!(QueryPlan::from_rows(&rows).to_string(), expected);
+        assert_eq!(QueryPlan::from_rows(&plan.rows()).steps, plan.steps);
+    }
+}
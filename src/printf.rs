@@ -0,0 +1,385 @@
+use crate::scalar::{integer, real};
+use crate::value::{DecimalDigits, SqlValue};
+
+// SQLite's printf(), see https://www.sqlite.org/printf.html. Missing
+// arguments count as NULL, and an unknown conversion ends the output.
+pub fn format(format: &str, args: &[SqlValue]) -> String {
+    let mut args = args.iter();
+    let mut next = || args.next().cloned().unwrap_or(SqlValue::Null);
+    let format = format.as_bytes();
+    let mut out: Vec<u8> = Vec::with_capacity(format.len());
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' {
+            let end = format[i..]
+                .iter()
+                .position(|c| *c == b'%')
+                .map_or(format.len(), |p| i + p);
+            out.extend_from_slice(&format[i..end]);
+            i = end;
+            continue;
+        }
+        i += 1;
+        let mut spec = Spec::default();
+        while i < format.len() {
+            match format[i] {
+                b'-' => spec.left_justify = true,
+                b'+' => spec.prefix = Some(b'+'),
+                b' ' => spec.prefix = spec.prefix.or(Some(b' ')),
+                b'#' => spec.alternate_form = true,
+                b'!' => spec.alternate_form2 = true,
+                b'0' => spec.zero_pad = true,
+                b',' => spec.thousands = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        if format.get(i) == Some(&b'*') {
+            let width = integer(&next());
+            if width < 0 {
+                spec.left_justify = true;
+            }
+            spec.width = width.unsigned_abs().min(i32::MAX as u64) as usize;
+            i += 1;
+        } else {
+            while let Some(d) = format.get(i).filter(|c| c.is_ascii_digit()) {
+                spec.width = (spec.width * 10 + (d - b'0') as usize).min(i32::MAX as usize);
+                i += 1;
+            }
+        }
+        if format.get(i) == Some(&b'.') {
+            i += 1;
+            let mut precision = 0;
+            if format.get(i) == Some(&b'*') {
+                precision = integer(&next()).unsigned_abs().min(i32::MAX as u64) as usize;
+                i += 1;
+            } else {
+                while let Some(d) = format.get(i).filter(|c| c.is_ascii_digit()) {
+                    precision = (precision * 10 + (d - b'0') as usize).min(i32::MAX as usize);
+                    i += 1;
+                }
+            }
+            spec.precision = Some(precision);
+        }
+        while format.get(i) == Some(&b'l') {
+            i += 1;
+        }
+        let conversion = match format.get(i) {
+            Some(c) => *c,
+            None => break,
+        };
+        i += 1;
+        let converted = match conversion {
+            b'%' => b"%".to_vec(),
+            b'd' | b'i' => spec.radix(integer(&next()), true, 10, b"", false),
+            b'u' => spec.radix(integer(&next()), false, 10, b"", false),
+            b'x' => spec.radix(integer(&next()), false, 16, b"0x", false),
+            b'X' => spec.radix(integer(&next()), false, 16, b"0X", true),
+            // a pointer, which SQLite prints as upper case hexadecimal
+            b'p' => spec.radix(integer(&next()), false, 16, b"0x", true),
+            b'o' => spec.radix(integer(&next()), false, 8, b"0", false),
+            b'f' | b'e' | b'E' | b'g' | b'G' => spec.real(real(&next()), conversion),
+            b's' | b'z' => spec.text(next().to_text().unwrap_or_default()),
+            b'q' | b'Q' | b'w' => spec.escaped(next(), conversion),
+            b'c' => spec.character(next()),
+            b'n' => Vec::new(),
+            _ => break,
+        };
+        spec.pad(&mut out, converted);
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[derive(Default)]
+struct Spec {
+    left_justify: bool,
+    prefix: Option<u8>,
+    alternate_form: bool,
+    alternate_form2: bool,
+    zero_pad: bool,
+    thousands: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    // Pads a conversion with spaces to the field width.
+    fn pad(&self, out: &mut Vec<u8>, converted: Vec<u8>) {
+        let padding = self.width.saturating_sub(converted.len());
+        if !self.left_justify {
+            out.resize(out.len() + padding, b' ');
+        }
+        out.extend(converted);
+        if self.left_justify {
+            out.resize(out.len() + padding, b' ');
+        }
+    }
+
+    fn radix(&self, value: i64, signed: bool, base: u64, prefix: &[u8], upper: bool) -> Vec<u8> {
+        let (mut magnitude, sign) = if signed && value < 0 {
+            (value.unsigned_abs(), Some(b'-'))
+        } else if signed {
+            (value as u64, self.prefix)
+        } else {
+            (value as u64, None)
+        };
+        let alternate_form = self.alternate_form && magnitude != 0;
+        let mut precision = self.precision.unwrap_or(0);
+        if self.zero_pad {
+            precision = precision.max(self.width.saturating_sub(sign.is_some() as usize));
+        }
+        let mut digits = Vec::new();
+        loop {
+            digits.push(b"0123456789abcdef"[(magnitude % base) as usize]);
+            magnitude /= base;
+            if magnitude == 0 {
+                break;
+            }
+        }
+        while digits.len() < precision {
+            digits.push(b'0');
+        }
+        digits.reverse();
+        if upper {
+            digits.make_ascii_uppercase();
+        }
+        if self.thousands && base == 10 {
+            digits = group_thousands(&digits);
+        }
+        let mut converted = Vec::with_capacity(digits.len() + 3);
+        converted.extend(sign);
+        if alternate_form {
+            converted.extend_from_slice(prefix);
+        }
+        converted.extend(digits);
+        converted
+    }
+
+    fn real(&self, value: f64, conversion: u8) -> Vec<u8> {
+        let mut precision = self.precision.unwrap_or(6);
+        let upper = conversion.is_ascii_uppercase();
+        let generic = conversion.eq_ignore_ascii_case(&b'g');
+        if generic && precision == 0 {
+            precision = 1;
+        }
+        let round = match conversion {
+            b'f' => -(precision as i64),
+            b'g' | b'G' => precision as i64,
+            _ => precision as i64 + 1,
+        };
+        let decoded = DecimalDigits::new(value, round, if self.alternate_form2 { 26 } else { 16 });
+        // with `#`, %f shows a negative value that rounds to zero as 0
+        let shows_zero = conversion == b'f' && decoded.point <= round;
+        let sign = if !decoded.negative {
+            self.prefix
+        } else if self.alternate_form && self.prefix.is_none() && shows_zero {
+            None
+        } else {
+            Some(b'-')
+        };
+        if value.is_nan() {
+            return b"NaN".to_vec();
+        }
+        if value.is_infinite() {
+            let mut converted = sign.into_iter().collect::<Vec<_>>();
+            converted.extend_from_slice(if self.zero_pad { b"9.0e999" } else { b"Inf" });
+            return converted;
+        }
+
+        let mut exponent = decoded.point - 1;
+        let mut exponential = conversion.eq_ignore_ascii_case(&b'e');
+        let remove_trailing_zeros;
+        if generic {
+            precision -= 1;
+            remove_trailing_zeros = !self.alternate_form;
+            if exponent < -4 || exponent > precision as i64 {
+                exponential = true;
+            } else {
+                precision = (precision as i64 - exponent) as usize;
+            }
+        } else {
+            remove_trailing_zeros = self.alternate_form2;
+        }
+        let mut e2 = if exponential { 0 } else { decoded.point - 1 };
+        let digits = &decoded.digits;
+        let mut j = 0;
+        let mut next_digit = || {
+            j += 1;
+            digits.get(j - 1).copied().unwrap_or(b'0')
+        };
+        let mut converted = Vec::new();
+        converted.extend(sign);
+        if e2 < 0 {
+            converted.push(b'0');
+        } else {
+            while e2 >= 0 {
+                converted.push(next_digit());
+                if self.thousands && e2 % 3 == 0 && e2 > 1 {
+                    converted.push(b',');
+                }
+                e2 -= 1;
+            }
+        }
+        let decimal_point = precision > 0 || self.alternate_form || self.alternate_form2;
+        if decimal_point {
+            converted.push(b'.');
+        }
+        e2 += 1;
+        while e2 < 0 && precision > 0 {
+            converted.push(b'0');
+            precision -= 1;
+            e2 += 1;
+        }
+        while precision > 0 {
+            converted.push(next_digit());
+            precision -= 1;
+        }
+        if remove_trailing_zeros && decimal_point {
+            while converted.last() == Some(&b'0') {
+                converted.pop();
+            }
+            if converted.last() == Some(&b'.') {
+                if self.alternate_form2 {
+                    converted.push(b'0');
+                } else {
+                    converted.pop();
+                }
+            }
+        }
+        if exponential {
+            exponent = decoded.point - 1;
+            converted.push(if upper { b'E' } else { b'e' });
+            converted.push(if exponent < 0 { b'-' } else { b'+' });
+            let exponent = exponent.unsigned_abs();
+            if exponent >= 100 {
+                converted.push(b'0' + (exponent / 100) as u8);
+            }
+            converted.push(b'0' + (exponent / 10 % 10) as u8);
+            converted.push(b'0' + (exponent % 10) as u8);
+        }
+        // zeros go between the sign and the digits
+        if self.zero_pad && !self.left_justify && converted.len() < self.width {
+            let at = sign.is_some() as usize;
+            let zeros = self.width - converted.len();
+            converted.splice(at..at, std::iter::repeat_n(b'0', zeros));
+        }
+        converted
+    }
+
+    // Text ends at the first NUL character. The precision limits its length
+    // in bytes, or in characters with the `!` flag.
+    fn text(&self, text: String) -> Vec<u8> {
+        let mut bytes = text.into_bytes();
+        if let Some(nul) = bytes.iter().position(|b| *b == 0) {
+            bytes.truncate(nul);
+        }
+        if let Some(precision) = self.precision {
+            let end = if self.alternate_form2 {
+                character_boundary(&bytes, precision)
+            } else {
+                precision.min(bytes.len())
+            };
+            bytes.truncate(end);
+        }
+        bytes
+    }
+
+    // %q doubles single quotes, %Q also quotes the text and shows NULL as
+    // such, and %w doubles double quotes.
+    fn escaped(&self, value: SqlValue, conversion: u8) -> Vec<u8> {
+        let text = match (value.to_text(), conversion) {
+            (Some(text), _) => text,
+            (None, b'Q') => return b"NULL".to_vec(),
+            (None, _) => "(NULL)".to_string(),
+        };
+        let text = self.text(text);
+        let quote = if conversion == b'w' { b'"' } else { b'\'' };
+        let mut escaped = Vec::with_capacity(text.len() + 2);
+        if conversion == b'Q' {
+            escaped.push(quote);
+        }
+        for c in text {
+            escaped.push(c);
+            if c == quote {
+                escaped.push(c);
+            }
+        }
+        if conversion == b'Q' {
+            escaped.push(quote);
+        }
+        escaped
+    }
+
+    // %c shows the first character of the text, repeated as many times as
+    // the precision says.
+    fn character(&self, value: SqlValue) -> Vec<u8> {
+        let c = value
+            .to_text()
+            .and_then(|s| s.chars().next())
+            .unwrap_or('\0');
+        let mut buffer = [0; 4];
+        c.encode_utf8(&mut buffer)
+            .repeat(self.precision.unwrap_or(1).max(1))
+            .into_bytes()
+    }
+}
+
+// The byte length of the first `n` characters of UTF-8 text.
+fn character_boundary(bytes: &[u8], n: usize) -> usize {
+    let mut characters = 0;
+    for (i, b) in bytes.iter().enumerate() {
+        if b & 0xc0 != 0x80 {
+            if characters == n {
+                return i;
+            }
+            characters += 1;
+        }
+    }
+    bytes.len()
+}
+
+fn group_thousands(digits: &[u8]) -> Vec<u8> {
+    let mut grouped = Vec::with_capacity(digits.len() * 4 / 3);
+    for (i, d) in digits.iter().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(b',');
+        }
+        grouped.push(*d);
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let args = [
+            SqlValue::Integer(1234567),
+            SqlValue::Real(-2.5016),
+            SqlValue::from("it's"),
+            SqlValue::Null,
+        ];
+        assert_eq!(
+            format("%,d|%08.3f|%q|%Q|%5.2s|", &args),
+            "1,234,567|-002.502|it''s|NULL|     |"
+        );
+        let real = |f: &str, r: f64| format(f, &[SqlValue::Real(r)]);
+        assert_eq!(real("%e", 12345.678), "1.234568e+04");
+        assert_eq!(real("%g", 0.0001234), "0.0001234");
+        assert_eq!(real("%G", 1e-10), "1E-10");
+        assert_eq!(real("%!.3g", 100.0), "100.0");
+        assert_eq!(real("%.20f", 0.1), "0.10000000000000000000");
+        assert_eq!(real("%.2f", 2.675), "2.67");
+        assert_eq!(
+            format("%#x %5d%%", &[SqlValue::Integer(255)]),
+            "0xff     0%"
+        );
+        assert_eq!(format("%z %y", &[SqlValue::from("a")]), "a ");
+        assert_eq!(
+            format("%p|%#p|%-5p|%p", &[255, 255, 10, -1].map(SqlValue::Integer)),
+            "FF|0xFF|A    |FFFFFFFFFFFFFFFF"
+        );
+    }
+}
@@ -1,5 +1,9 @@
+use crate::pager::Payload;
 use crate::value;
+use crate::value::SqlValue;
 use crate::varint;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
 
 #[derive(Debug)]
 pub struct Record(Vec<value::Value>);
@@ -27,4 +31,160 @@ impl Record {
         }
         Some(Record(result))
     }
+
+    pub fn values(&self) -> &[value::Value] {
+        &self.0
+    }
+
+    pub fn into_values(self) -> Vec<value::Value> {
+        self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Encodes values in the record format, picking the smallest serial type
+    // for each of them.
+    pub fn encode(values: &[SqlValue]) -> Vec<u8> {
+        let mut header = Vec::new();
+        let mut body = Vec::new();
+        for value in values {
+            let serial_type = match value {
+                SqlValue::Null => 0,
+                SqlValue::Integer(0) => 8,
+                SqlValue::Integer(1) => 9,
+                SqlValue::Integer(i) => {
+                    let (serial_type, length) = match *i {
+                        -0x80..=0x7f => (1, 1),
+                        -0x8000..=0x7fff => (2, 2),
+                        -0x80_0000..=0x7f_ffff => (3, 3),
+                        -0x8000_0000..=0x7fff_ffff => (4, 4),
+                        -0x8000_0000_0000..=0x7fff_ffff_ffff => (5, 6),
+                        _ => (6, 8),
+                    };
+                    body.extend_from_slice(&i.to_be_bytes()[8 - length..]);
+                    serial_type
+                }
+                SqlValue::Real(f) => {
+                    body.extend_from_slice(&f.to_be_bytes());
+                    7
+                }
+                SqlValue::Text(s) => {
+                    body.extend_from_slice(s.as_bytes());
+                    s.len() as i64 * 2 + 13
+                }
+                SqlValue::Blob(b) => {
+                    body.extend_from_slice(b);
+                    b.len() as i64 * 2 + 12
+                }
+            };
+            header.extend(varint::serialize_to_varint(serial_type));
+        }
+
+        // the header length counts the varint holding it
+        let mut header_length = header.len() + 1;
+        while varint::serialize_to_varint(header_length as i64).len() + header.len()
+            != header_length
+        {
+            header_length += 1;
+        }
+        let mut record = varint::serialize_to_varint(header_length as i64);
+        record.extend(header);
+        record.extend(body);
+        record
+    }
+}
+
+// A record whose header has been parsed but whose values are only decoded
+// when asked for, so that reading a few columns of a wide row leaves the
+// rest, and any overflow pages holding them, untouched.
+pub struct LazyRecord<'p> {
+    payload: Payload<'p>,
+    serial_types: Vec<u64>,
+    // Where the value of each column starts in the payload, and where the
+    // last one ends.
+    offsets: Vec<usize>,
+}
+
+impl<'p> LazyRecord<'p> {
+    pub fn new(payload: Payload<'p>) -> Result<LazyRecord<'p>, Error> {
+        let malformed = || Error::new(ErrorKind::InvalidData, "malformed record");
+        let size = payload.len();
+        let (header_length, length_size) = varint::read_varint(&payload.bytes(0..size.min(9))?);
+        let header_length = usize::try_from(header_length).map_err(|_| malformed())?;
+        if header_length < length_size || header_length > size {
+            return Err(malformed());
+        }
+
+        let header = payload.bytes(0..header_length)?;
+        let mut serial_types = Vec::new();
+        let mut offsets = vec![header_length];
+        let mut cursor = length_size;
+        while cursor < header_length {
+            let (serial_type, length) = varint::read_varint(&header[cursor..]);
+            if serial_type < 0 || serial_type == 10 || serial_type == 11 {
+                return Err(malformed());
+            }
+            let end = offsets.last().unwrap() + value::Value::consume(serial_type as u64);
+            if end > size {
+                return Err(malformed());
+            }
+            serial_types.push(serial_type as u64);
+            offsets.push(end);
+            cursor += length;
+        }
+        Ok(LazyRecord {
+            payload,
+            serial_types,
+            offsets,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.serial_types.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.serial_types.is_empty()
+    }
+
+    pub fn value(&self, i: usize) -> Result<value::Value, Error> {
+        let serial_type = *self.serial_types.get(i).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("record has no column {}", i),
+            )
+        })?;
+        let bytes = self.payload.bytes(self.offsets[i]..self.offsets[i + 1])?;
+        Ok(value::Value::new(serial_type, &bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_round_trip() {
+        let values = vec![
+            SqlValue::Null,
+            SqlValue::Integer(0),
+            SqlValue::Integer(1),
+            SqlValue::Integer(-200),
+            SqlValue::Integer(1 << 40),
+            SqlValue::Integer(i64::MIN),
+            SqlValue::Real(-1.5),
+            SqlValue::Text("x".repeat(100)),
+            SqlValue::Blob(vec![0, 1, 2]),
+        ];
+        let record = Record::from_cell_bytes(&Record::encode(&values)).unwrap();
+        let decoded: Vec<SqlValue> = record.into_values().into_iter().map(From::from).collect();
+        assert_eq!(decoded, values);
+        assert!(decoded[7].as_str().is_some());
+    }
 }
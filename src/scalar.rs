@@ -0,0 +1,491 @@
+use crate::datetime;
+use crate::expr::{cast, compare_values, escape_character, glob, like, Collation};
+use crate::printf;
+use crate::value::{format_real, parse_numeric_exact, Affinity, SqlValue};
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error, ErrorKind};
+use std::ops::RangeInclusive;
+
+// The largest string or blob a function may produce, SQLite's default
+// SQLITE_MAX_LENGTH.
+const MAX_LENGTH: i64 = 1_000_000_000;
+
+// A scalar function defined by the application.
+pub type ScalarFunction = dyn Fn(&[SqlValue]) -> Result<SqlValue, Error>;
+
+// What the application promises about a function it registers, as with
+// SQLITE_DETERMINISTIC and SQLITE_INNOCUOUS. A deterministic function
+// always gives the same result for the same arguments; an innocuous one has
+// no side effects and reveals nothing beyond its arguments.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FunctionFlags {
+    pub deterministic: bool,
+    pub innocuous: bool,
+}
+
+pub fn wrong_number_of_arguments(name: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("wrong number of arguments to function {}()", name),
+    )
+}
+
+fn too_big() -> Error {
+    Error::new(ErrorKind::InvalidData, "string or blob too big")
+}
+
+// The number of arguments each function takes.
+fn arity(name: &str) -> Option<RangeInclusive<usize>> {
+    Some(match name {
+        "random" => 0..=0,
+        "abs" | "length" | "lower" | "upper" | "typeof" | "hex" | "quote" | "unicode"
+        | "zeroblob" | "randomblob" | "likely" | "unlikely" | "sign" => 1..=1,
+        "ltrim" | "rtrim" | "trim" | "round" | "unhex" => 1..=2,
+        "ifnull" | "instr" | "nullif" | "likelihood" | "glob" => 2..=2,
+        "substr" | "substring" | "like" => 2..=3,
+        "replace" => 3..=3,
+        "coalesce" | "iif" | "min" | "max" => 2..=usize::MAX,
+        "printf" | "format" | "char" => 0..=usize::MAX,
+        _ => return datetime::arity(name),
+    })
+}
+
+// The multi-argument min() or max(), comparing text with `collation`. Any
+// NULL argument makes the result NULL.
+pub fn extreme(name: &str, args: &[SqlValue], collation: &Collation) -> SqlValue {
+    if args.iter().any(SqlValue::is_null) {
+        return SqlValue::Null;
+    }
+    let wanted = if name == "min" {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    };
+    let mut best = &args[0];
+    for v in &args[1..] {
+        if compare_values(v, best, collation) == wanted {
+            best = v;
+        }
+    }
+    best.clone()
+}
+
+// Calls the built-in scalar function `name`, which must be in lower case,
+// or returns None if there is no such function.
+pub fn call(name: &str, args: &[SqlValue]) -> Option<Result<SqlValue, Error>> {
+    let arity = arity(name)?;
+    if !arity.contains(&args.len()) {
+        return Some(Err(wrong_number_of_arguments(name)));
+    }
+    Some(call_checked(name, args))
+}
+
+fn call_checked(name: &str, args: &[SqlValue]) -> Result<SqlValue, Error> {
+    Ok(match name {
+        "abs" => match &args[0] {
+            SqlValue::Null => SqlValue::Null,
+            SqlValue::Integer(i) => SqlValue::Integer(
+                i.checked_abs()
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "integer overflow"))?,
+            ),
+            v => SqlValue::Real(real(v).abs()),
+        },
+        "coalesce" | "ifnull" => args
+            .iter()
+            .find(|v| !v.is_null())
+            .cloned()
+            .unwrap_or(SqlValue::Null),
+        // iif(c1, v1, c2, v2, ..., else) picks the value of the first true
+        // condition, like CASE
+        "iif" => {
+            let mut pairs = args.chunks_exact(2);
+            let chosen = pairs.find(|pair| is_true(&pair[0])).map(|pair| &pair[1]);
+            chosen
+                .or_else(|| pairs.remainder().first())
+                .cloned()
+                .unwrap_or(SqlValue::Null)
+        }
+        "instr" => instr(&args[0], &args[1]),
+        "length" => match &args[0] {
+            SqlValue::Null => SqlValue::Null,
+            SqlValue::Blob(b) => SqlValue::Integer(b.len() as i64),
+            // text ends at the first NUL character
+            v => {
+                let text = v.to_text().unwrap();
+                let end = text.find('\0').unwrap_or(text.len());
+                SqlValue::Integer(text[..end].chars().count() as i64)
+            }
+        },
+        "lower" => map_text(&args[0], |s| s.to_ascii_lowercase()),
+        "upper" => map_text(&args[0], |s| s.to_ascii_uppercase()),
+        "ltrim" | "rtrim" | "trim" => {
+            let (text, characters) = match (text(&args[0]), args.get(1).map(text)) {
+                (Some(text), None) => (text, vec![' ']),
+                (Some(text), Some(Some(characters))) => (text, characters.chars().collect()),
+                _ => return Ok(SqlValue::Null),
+            };
+            let trim = |c: char| characters.contains(&c);
+            SqlValue::Text(match name {
+                "ltrim" => text.trim_start_matches(trim).to_string(),
+                "rtrim" => text.trim_end_matches(trim).to_string(),
+                _ => text.trim_matches(trim).to_string(),
+            })
+        }
+        "replace" => match (text(&args[0]), text(&args[1])) {
+            (Some(text), Some(pattern)) if pattern.is_empty() => SqlValue::Text(text),
+            (Some(text), Some(pattern)) => match self::text(&args[2]) {
+                Some(replacement) => {
+                    let replaced = text.replace(&pattern, &replacement);
+                    if replaced.len() as i64 > MAX_LENGTH {
+                        return Err(too_big());
+                    }
+                    SqlValue::Text(replaced)
+                }
+                None => SqlValue::Null,
+            },
+            _ => SqlValue::Null,
+        },
+        "substr" | "substring" => substr(args),
+        "round" => round(args),
+        "typeof" => SqlValue::from(args[0].type_name()),
+        "hex" => {
+            let bytes = bytes(&args[0]).unwrap_or_default();
+            SqlValue::Text(bytes.iter().map(|b| format!("{:02X}", b)).collect())
+        }
+        "unhex" => unhex(args),
+        "quote" => SqlValue::Text(quote(&args[0])),
+        "nullif" => {
+            if args[0].compare(&args[1]) == Ordering::Equal {
+                SqlValue::Null
+            } else {
+                args[0].clone()
+            }
+        }
+        "printf" | "format" => match args.first().and_then(text) {
+            Some(format) => SqlValue::Text(printf::format(&format, &args[1..])),
+            None => SqlValue::Null,
+        },
+        "char" => SqlValue::Text(
+            args.iter()
+                .map(|v| {
+                    let code = integer(v);
+                    u32::try_from(code)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .unwrap_or('\u{fffd}')
+                })
+                .collect(),
+        ),
+        "unicode" => match text(&args[0]).and_then(|s| s.chars().next()) {
+            Some(c) => SqlValue::Integer(c as i64),
+            None => SqlValue::Null,
+        },
+        "zeroblob" => {
+            let length = integer(&args[0]).max(0);
+            if length > MAX_LENGTH {
+                return Err(too_big());
+            }
+            SqlValue::Blob(vec![0; length as usize])
+        }
+        "randomblob" => {
+            let length = integer(&args[0]).max(1);
+            if length > MAX_LENGTH {
+                return Err(too_big());
+            }
+            let mut blob = Vec::with_capacity(length as usize);
+            while blob.len() < length as usize {
+                blob.extend_from_slice(&random().to_le_bytes());
+            }
+            blob.truncate(length as usize);
+            SqlValue::Blob(blob)
+        }
+        "random" => SqlValue::Integer(random() as i64),
+        "min" | "max" => extreme(name, args, &Collation::Binary),
+        "likely" | "unlikely" => args[0].clone(),
+        "likelihood" => {
+            let probability = match &args[1] {
+                SqlValue::Integer(i) => Some(*i as f64),
+                SqlValue::Real(f) => Some(*f),
+                _ => None,
+            };
+            if !probability.is_some_and(|p| (0.0..=1.0).contains(&p)) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "second argument to likelihood() must be a constant between 0.0 and 1.0",
+                ));
+            }
+            args[0].clone()
+        }
+        // only numbers and text that looks like one have a sign
+        "sign" => {
+            let number = match &args[0] {
+                SqlValue::Integer(_) | SqlValue::Real(_) => Some(args[0].clone()),
+                SqlValue::Text(s) => parse_numeric_exact(s),
+                _ => None,
+            };
+            match number.and_then(|n| n.to_f64()) {
+                Some(f) if f > 0.0 => SqlValue::Integer(1),
+                Some(f) if f < 0.0 => SqlValue::Integer(-1),
+                Some(_) => SqlValue::Integer(0),
+                None => SqlValue::Null,
+            }
+        }
+        // the functions behind `y GLOB x` and `y LIKE x ESCAPE z`
+        "glob" => match (text(&args[0]), text(&args[1])) {
+            (Some(p), Some(s)) => SqlValue::from_bool(glob(&p, &s)),
+            _ => SqlValue::Null,
+        },
+        "like" => {
+            let escape = match args.get(2) {
+                Some(SqlValue::Null) => return Ok(SqlValue::Null),
+                Some(e) => Some(escape_character(e)?),
+                None => None,
+            };
+            match (text(&args[0]), text(&args[1])) {
+                (Some(p), Some(s)) => SqlValue::from_bool(like(&p, &s, escape)),
+                _ => SqlValue::Null,
+            }
+        }
+        _ => return datetime::call(name, args),
+    })
+}
+
+// sqlite3_value_text(): the value as text, or None for NULL.
+fn text(value: &SqlValue) -> Option<String> {
+    value.to_text()
+}
+
+// sqlite3_value_blob(): the bytes of a blob, or those of the value as text.
+fn bytes(value: &SqlValue) -> Option<Vec<u8>> {
+    match value {
+        SqlValue::Blob(b) => Some(b.clone()),
+        v => v.to_text().map(String::into_bytes),
+    }
+}
+
+// sqlite3_value_int64(): reals are truncated and text gives its leading
+// integer, NULL is 0.
+pub fn integer(value: &SqlValue) -> i64 {
+    cast(value.clone(), Affinity::Integer).as_i64().unwrap_or(0)
+}
+
+// sqlite3_value_double(): text gives its numeric prefix, NULL is 0.0.
+pub fn real(value: &SqlValue) -> f64 {
+    value.to_f64().unwrap_or(0.0)
+}
+
+fn is_true(value: &SqlValue) -> bool {
+    !value.is_null() && real(value) != 0.0
+}
+
+fn map_text(value: &SqlValue, f: impl Fn(&str) -> String) -> SqlValue {
+    match text(value) {
+        Some(s) => SqlValue::Text(f(&s)),
+        None => SqlValue::Null,
+    }
+}
+
+// instr() counts characters, unless both arguments are blobs.
+fn instr(haystack: &SqlValue, needle: &SqlValue) -> SqlValue {
+    if haystack.is_null() || needle.is_null() {
+        return SqlValue::Null;
+    }
+    let position = match (haystack, needle) {
+        (SqlValue::Blob(h), SqlValue::Blob(n)) => {
+            if n.is_empty() {
+                Some(0)
+            } else {
+                h.windows(n.len()).position(|w| w == n.as_slice())
+            }
+        }
+        _ => {
+            let (h, n) = (text(haystack).unwrap(), text(needle).unwrap());
+            h.find(&n).map(|i| h[..i].chars().count())
+        }
+    };
+    SqlValue::Integer(position.map_or(0, |p| p as i64 + 1))
+}
+
+// substr(X, Y, Z): Y counts from 1, or from the end when negative, and a
+// negative Z takes the characters before Y instead of after it.
+fn substr(args: &[SqlValue]) -> SqlValue {
+    if args.iter().any(SqlValue::is_null) {
+        return SqlValue::Null;
+    }
+    let chars: Vec<char> = match &args[0] {
+        SqlValue::Blob(_) => Vec::new(),
+        v => text(v).unwrap().chars().collect(),
+    };
+    let length = match &args[0] {
+        SqlValue::Blob(b) => b.len(),
+        _ => chars.len(),
+    } as i64;
+    let mut start = integer(&args[1]);
+    let mut count = match args.get(2) {
+        Some(count) => integer(count),
+        None => MAX_LENGTH,
+    };
+    let negative_count = count < 0;
+    if negative_count {
+        count = count.saturating_neg();
+    }
+    if start < 0 {
+        start += length;
+        if start < 0 {
+            count += start;
+            start = 0;
+        }
+    } else if start > 0 {
+        start -= 1;
+    } else if count > 0 {
+        count -= 1;
+    }
+    if negative_count {
+        start -= count;
+        if start < 0 {
+            count += start;
+            start = 0;
+        }
+    }
+    let start = start.min(length) as usize;
+    let end = start + count.clamp(0, length - start as i64) as usize;
+    match &args[0] {
+        SqlValue::Blob(b) => SqlValue::Blob(b[start..end].to_vec()),
+        _ => SqlValue::Text(chars[start..end].iter().collect()),
+    }
+}
+
+// round(X, N) rounds half away from zero to at most 30 decimal places, and
+// always returns a real.
+fn round(args: &[SqlValue]) -> SqlValue {
+    if args.iter().any(SqlValue::is_null) {
+        return SqlValue::Null;
+    }
+    let digits = args.get(1).map_or(0, integer).clamp(0, 30) as usize;
+    let r = real(&args[0]);
+    // larger values have no fractional part
+    if !(-4503599627370496.0..=4503599627370496.0).contains(&r) {
+        return SqlValue::Real(r);
+    }
+    if digits == 0 {
+        let rounded = (r + if r < 0.0 { -0.5 } else { 0.5 }) as i64;
+        return SqlValue::Real(rounded as f64);
+    }
+    let rounded = printf::format(
+        "%!.*f",
+        &[SqlValue::Integer(digits as i64), args[0].clone()],
+    );
+    SqlValue::Real(rounded.parse().unwrap_or(r))
+}
+
+// unhex(X, Y) decodes pairs of hex digits, which may be separated by the
+// characters of Y. Anything else makes the result NULL.
+fn unhex(args: &[SqlValue]) -> SqlValue {
+    let (hex, separators) = match (text(&args[0]), args.get(1).map(text)) {
+        (Some(hex), None) => (hex, String::new()),
+        (Some(hex), Some(Some(separators))) => (hex, separators),
+        _ => return SqlValue::Null,
+    };
+    let mut blob = Vec::new();
+    let mut chars = hex.chars();
+    while let Some(c) = chars.next() {
+        if !c.is_ascii_hexdigit() {
+            if separators.contains(c) {
+                continue;
+            }
+            return SqlValue::Null;
+        }
+        match chars.next().and_then(|d| d.to_digit(16)) {
+            Some(d) => blob.push((c.to_digit(16).unwrap() * 16 + d) as u8),
+            None => return SqlValue::Null,
+        }
+    }
+    SqlValue::Blob(blob)
+}
+
+// The value as an SQL literal. Reals get enough digits to read back as the
+// same value.
+pub fn quote(value: &SqlValue) -> String {
+    match value {
+        SqlValue::Null => "NULL".to_string(),
+        SqlValue::Integer(i) => i.to_string(),
+        SqlValue::Real(f) => {
+            let short = format_real(*f);
+            if short.parse::<f64>().ok() == Some(*f) {
+                short
+            } else {
+                printf::format("%!0.20e", std::slice::from_ref(value))
+            }
+        }
+        // text ends at the first NUL character
+        SqlValue::Text(s) => {
+            let s = s.split('\0').next().unwrap();
+            format!("'{}'", s.replace('\'', "''"))
+        }
+        SqlValue::Blob(b) => {
+            let hex: String = b.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("X'{}'", hex)
+        }
+    }
+}
+
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, args: &[SqlValue]) -> SqlValue {
+        super::call(name, args).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_substr() {
+        let hello = SqlValue::from("hello");
+        let substr = |start: i64, count: Option<i64>| {
+            let mut args = vec![hello.clone(), SqlValue::Integer(start)];
+            args.extend(count.map(SqlValue::Integer));
+            call("substr", &args)
+        };
+        assert_eq!(substr(2, None), SqlValue::from("ello"));
+        assert_eq!(substr(-3, Some(2)), SqlValue::from("ll"));
+        assert_eq!(substr(0, Some(2)), SqlValue::from("h"));
+        assert_eq!(substr(3, Some(-2)), SqlValue::from("he"));
+        assert_eq!(substr(-7, Some(3)), SqlValue::from("h"));
+        assert_eq!(substr(9, Some(3)), SqlValue::from(""));
+        let blob = SqlValue::Blob(vec![1, 2, 3]);
+        assert_eq!(
+            call("substr", &[blob, SqlValue::Integer(-2)]),
+            SqlValue::Blob(vec![2, 3])
+        );
+    }
+
+    #[test]
+    fn test_argument_counts() {
+        assert!(super::call("no_such_function", &[]).is_none());
+        assert_eq!(
+            super::call("abs", &[]).unwrap().unwrap_err().to_string(),
+            "wrong number of arguments to function abs()"
+        );
+        assert!(super::call("abs", &[SqlValue::Integer(i64::MIN)])
+            .unwrap()
+            .is_err());
+        assert!(call("iif", &[SqlValue::Integer(0), SqlValue::Integer(1)]).is_null());
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(&SqlValue::Real(0.1)), "0.1");
+        assert_eq!(
+            quote(&SqlValue::Real(0.1 + 0.2)),
+            "3.000000000000000445e-01"
+        );
+        assert_eq!(quote(&SqlValue::from("it's")), "'it''s'");
+        assert_eq!(quote(&SqlValue::Blob(vec![0x0a, 0xff])), "X'0AFF'");
+    }
+}
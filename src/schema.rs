@@ -0,0 +1,872 @@
+use crate::btree::BTreeCursor;
+use crate::expr;
+use crate::pager::Pager;
+use crate::record::LazyRecord;
+use crate::sql_parser;
+use crate::value::{Affinity, SqlValue, Value};
+use sqlparser::ast::Expr;
+use sqlparser::dialect::SQLiteDialect;
+use sqlparser::parser::Parser;
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::io::{Error, ErrorKind};
+
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    pub declared_type: String,
+    pub not_null: bool,
+    pub collation: Option<String>,
+    // The value of a constant DEFAULT, which rows stored before the column
+    // was added with ALTER TABLE read as.
+    pub default: Option<SqlValue>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub name: String,
+    pub root_page: u32,
+    pub columns: Vec<Column>,
+    pub primary_key: Vec<usize>,
+    // Whether each primary key column was declared DESC, which reverses its
+    // order in the b-tree of a WITHOUT ROWID table.
+    pub primary_key_descending: Vec<bool>,
+    pub without_rowid: bool,
+    // Set when the table has an INTEGER PRIMARY KEY column, which is stored
+    // as the rowid of the cell rather than in the record.
+    pub rowid_alias: Option<usize>,
+    // PRIMARY KEY and UNIQUE constraints in the order SQLite numbers their
+    // automatic indexes.
+    unique_constraints: Vec<Vec<usize>>,
+    // The columns with REAL affinity, whose integers are stored as such to
+    // save space and read back as reals.
+    real_columns: Vec<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexColumn {
+    // None for expressions, which cannot be used to look up columns.
+    pub column: Option<usize>,
+    pub descending: bool,
+    pub collation: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Index {
+    pub name: String,
+    pub table_name: String,
+    pub root_page: u32,
+    pub columns: Vec<IndexColumn>,
+    pub unique: bool,
+    pub partial: bool,
+}
+
+#[derive(Debug)]
+pub struct Schema {
+    pub tables: Vec<Table>,
+    pub indexes: Vec<Index>,
+}
+
+impl Table {
+    // A table that only exists while a query runs, such as a subquery in
+    // FROM. It has no b-tree and no rowid.
+    pub fn transient(name: String, columns: Vec<Column>) -> Table {
+        Table {
+            name,
+            root_page: 0,
+            columns,
+            primary_key: Vec::new(),
+            primary_key_descending: Vec::new(),
+            without_rowid: true,
+            rowid_alias: None,
+            unique_constraints: Vec::new(),
+            real_columns: Vec::new(),
+        }
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    // Maps each position of a stored record to the column it holds. Rowid
+    // tables store columns in declaration order, while WITHOUT ROWID tables
+    // store the primary key columns first, followed by the remaining columns.
+    pub fn record_layout(&self) -> Vec<usize> {
+        if !self.without_rowid {
+            return (0..self.columns.len()).collect();
+        }
+        let mut layout = self.primary_key.clone();
+        for i in 0..self.columns.len() {
+            if !self.primary_key.contains(&i) {
+                layout.push(i);
+            }
+        }
+        layout
+    }
+
+    // Turns a stored record back into a row in declaration order, putting
+    // the rowid in place of an INTEGER PRIMARY KEY column. Rows stored
+    // before columns were added with ALTER TABLE lack those columns, which
+    // read as their DEFAULT.
+    pub fn row_from_record(&self, row_id: Option<i64>, values: Vec<Value>) -> Vec<SqlValue> {
+        let mut row = self.defaults();
+        for (value, column) in values.into_iter().zip(self.record_layout()) {
+            row[column] = self.stored_value(column, SqlValue::from(value));
+        }
+        if let (Some(alias), Some(row_id)) = (self.rowid_alias, row_id) {
+            row[alias] = SqlValue::Integer(row_id);
+        }
+        row
+    }
+
+    // Like `row_from_record`, but decodes only the columns flagged in
+    // `columns`, the others keeping their defaults. Without flags every
+    // column is decoded.
+    pub fn row_from_lazy_record(
+        &self,
+        row_id: Option<i64>,
+        record: &LazyRecord,
+        columns: Option<&[bool]>,
+    ) -> Result<Vec<SqlValue>, Error> {
+        let mut row = self.defaults();
+        for (i, column) in self.record_layout().into_iter().enumerate() {
+            if i >= record.len() {
+                break;
+            }
+            if Some(column) == self.rowid_alias && row_id.is_some() {
+                continue;
+            }
+            if columns.is_none_or(|columns| columns[column]) {
+                row[column] = self.stored_value(column, SqlValue::from(record.value(i)?));
+            }
+        }
+        if let (Some(alias), Some(row_id)) = (self.rowid_alias, row_id) {
+            row[alias] = SqlValue::Integer(row_id);
+        }
+        Ok(row)
+    }
+
+    // The values of a row none of whose columns are stored.
+    // A value of `column` as read from a record or an index.
+    pub fn stored_value(&self, column: usize, value: SqlValue) -> SqlValue {
+        match value {
+            SqlValue::Integer(i) if self.is_real(column) => SqlValue::Real(i as f64),
+            value => value,
+        }
+    }
+
+    // Whether `column` has REAL affinity, whose whole values records and
+    // indexes store as integers.
+    pub fn is_real(&self, column: usize) -> bool {
+        self.real_columns.get(column) == Some(&true)
+    }
+
+    fn defaults(&self) -> Vec<SqlValue> {
+        self.columns
+            .iter()
+            .map(|c| c.default.clone().unwrap_or(SqlValue::Null))
+            .collect()
+    }
+}
+
+impl Schema {
+    pub fn read(pager: &Pager) -> Result<Schema, Error> {
+        let mut tables = vec![sqlite_master()];
+        let mut indexes = Vec::new();
+        let mut index_entries = Vec::new();
+
+        for entry in BTreeCursor::new(pager, 1) {
+            let entry = entry?;
+            let values = entry.record.values();
+            let text = |i: usize| values.get(i).and_then(|v| v.as_str()).unwrap_or("");
+            let root_page = values.get(3).and_then(|v| v.as_i64()).unwrap_or(0) as u32;
+            let (kind, name, table_name, sql) = (text(0), text(1), text(2), text(4));
+            match kind {
+                "table" => {
+                    if sql.is_empty() {
+                        continue;
+                    }
+                    let mut table = parse_create_table(sql)?;
+                    table.name = name.to_string();
+                    table.root_page = root_page;
+                    tables.push(table);
+                }
+                "index" => index_entries.push((
+                    name.to_string(),
+                    table_name.to_string(),
+                    root_page,
+                    sql.to_string(),
+                )),
+                _ => (),
+            }
+        }
+
+        for (name, table_name, root_page, sql) in index_entries {
+            let table = match tables
+                .iter()
+                .find(|t| t.name.eq_ignore_ascii_case(&table_name))
+            {
+                Some(t) => t,
+                None => continue,
+            };
+            let index = if sql.is_empty() {
+                autoindex(table, &name, root_page)?
+            } else {
+                let mut index = parse_create_index(&sql, table)?;
+                index.name = name;
+                index.root_page = root_page;
+                index
+            };
+            indexes.push(index);
+        }
+
+        Ok(Schema { tables, indexes })
+    }
+
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        if name.eq_ignore_ascii_case("sqlite_schema") {
+            return self.table("sqlite_master");
+        }
+        self.tables
+            .iter()
+            .find(|t| t.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn indexes_of<'a>(&'a self, table_name: &'a str) -> impl Iterator<Item = &'a Index> {
+        self.indexes
+            .iter()
+            .filter(move |i| i.table_name.eq_ignore_ascii_case(table_name))
+    }
+}
+
+fn sqlite_master() -> Table {
+    let column = |name: &str, declared_type: &str| Column {
+        name: name.to_string(),
+        declared_type: declared_type.to_string(),
+        not_null: false,
+        collation: None,
+        default: None,
+    };
+    Table {
+        name: "sqlite_master".to_string(),
+        root_page: 1,
+        columns: vec![
+            column("type", "text"),
+            column("name", "text"),
+            column("tbl_name", "text"),
+            column("rootpage", "int"),
+            column("sql", "text"),
+        ],
+        primary_key: Vec::new(),
+        primary_key_descending: Vec::new(),
+        without_rowid: false,
+        rowid_alias: None,
+        unique_constraints: Vec::new(),
+        real_columns: Vec::new(),
+    }
+}
+
+// Indexes created for PRIMARY KEY and UNIQUE constraints have no SQL; their
+// name `sqlite_autoindex_<table>_<N>` says which constraint they belong to.
+fn autoindex(table: &Table, name: &str, root_page: u32) -> Result<Index, Error> {
+    let constraint = name
+        .rsplit('_')
+        .next()
+        .and_then(|n| n.parse::<usize>().ok())
+        .and_then(|n| table.unique_constraints.get(n.wrapping_sub(1)))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("cannot find the constraint for {}", name),
+            )
+        })?;
+    Ok(Index {
+        name: name.to_string(),
+        table_name: table.name.clone(),
+        root_page,
+        columns: constraint
+            .iter()
+            .map(|c| IndexColumn {
+                column: Some(*c),
+                descending: false,
+                collation: table.columns[*c].collation.clone(),
+            })
+            .collect(),
+        unique: true,
+        partial: false,
+    })
+}
+
+fn invalid_schema(message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("malformed schema: {}", message),
+    )
+}
+
+// A small recursive-descent reader for the CREATE statements stored in
+// sqlite_master. SQLite accepts far more in column definitions than
+// sqlparser does (e.g. `NVARCHAR(160)`), so the statements are read
+// token by token instead, skipping whatever does not matter for reading
+// the file.
+struct DdlParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl DdlParser {
+    fn new(sql: &str) -> Result<DdlParser, Error> {
+        let dialect = SQLiteDialect {};
+        let mut tokens = Tokenizer::new(&dialect, sql)
+            .tokenize()
+            .map_err(|e| invalid_schema(&format!("{:?}", e)))?;
+        sql_parser::join_exponents(&mut tokens);
+        let tokens = tokens
+            .into_iter()
+            .filter(|t| !matches!(t, Token::Whitespace(_)))
+            .collect();
+        Ok(DdlParser { tokens, pos: 0 })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_keyword(token: Option<&Token>, keyword: &str) -> bool {
+        match token {
+            Some(Token::Word(w)) => {
+                w.quote_style.is_none() && w.value.eq_ignore_ascii_case(keyword)
+            }
+            _ => false,
+        }
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        DdlParser::is_keyword(self.peek(), keyword)
+    }
+
+    fn parse_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_keyword(keyword) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_keywords(&mut self, keywords: &[&str]) -> bool {
+        let matched = keywords
+            .iter()
+            .enumerate()
+            .all(|(i, k)| DdlParser::is_keyword(self.tokens.get(self.pos + i), k));
+        if matched {
+            self.pos += keywords.len();
+        }
+        matched
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), Error> {
+        if self.parse_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(invalid_schema(&format!("expected {}", keyword)))
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String, Error> {
+        match self.next() {
+            Some(Token::Word(w)) => Ok(w.value),
+            Some(Token::SingleQuotedString(s)) => Ok(s),
+            _ => Err(invalid_schema("expected a name")),
+        }
+    }
+
+    // Reads `[schema.]name`, dropping the schema.
+    fn parse_object_name(&mut self) -> Result<String, Error> {
+        let mut name = self.parse_name()?;
+        if let Some(Token::Period) = self.peek() {
+            self.pos += 1;
+            name = self.parse_name()?;
+        }
+        Ok(name)
+    }
+
+    // Consumes a parenthesized group and returns the tokens inside it.
+    fn parse_parenthesized(&mut self) -> Result<Vec<Token>, Error> {
+        match self.next() {
+            Some(Token::LParen) => (),
+            _ => return Err(invalid_schema("expected (")),
+        }
+        let start = self.pos;
+        let mut depth = 1;
+        while let Some(token) = self.next() {
+            match token {
+                Token::LParen => depth += 1,
+                Token::RParen => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(self.tokens[start..self.pos - 1].to_vec());
+                    }
+                }
+                _ => (),
+            }
+        }
+        Err(invalid_schema("unbalanced parentheses"))
+    }
+
+    fn at_end(&self) -> bool {
+        matches!(
+            self.peek(),
+            None | Some(Token::EOF) | Some(Token::SemiColon)
+        )
+    }
+}
+
+// Splits tokens at the commas that are not nested inside parentheses.
+fn split_top_level(tokens: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut items = vec![Vec::new()];
+    let mut depth = 0;
+    for token in tokens {
+        match token {
+            Token::LParen => depth += 1,
+            Token::RParen => depth -= 1,
+            Token::Comma if depth == 0 => {
+                items.push(Vec::new());
+                continue;
+            }
+            _ => (),
+        }
+        items.last_mut().unwrap().push(token);
+    }
+    items.retain(|i| !i.is_empty());
+    items
+}
+
+const COLUMN_CONSTRAINT_KEYWORDS: [&str; 11] = [
+    "CONSTRAINT",
+    "PRIMARY",
+    "NOT",
+    "NULL",
+    "UNIQUE",
+    "CHECK",
+    "DEFAULT",
+    "COLLATE",
+    "REFERENCES",
+    "GENERATED",
+    "AS",
+];
+
+fn is_column_constraint_start(token: Option<&Token>) -> bool {
+    COLUMN_CONSTRAINT_KEYWORDS
+        .iter()
+        .any(|k| DdlParser::is_keyword(token, k))
+}
+
+pub fn parse_create_table(sql: &str) -> Result<Table, Error> {
+    let mut p = DdlParser::new(sql)?;
+    p.expect_keyword("CREATE")?;
+    if !p.parse_keyword("TEMP") {
+        p.parse_keyword("TEMPORARY");
+    }
+    p.expect_keyword("TABLE")?;
+    p.parse_keywords(&["IF", "NOT", "EXISTS"]);
+    let name = p.parse_object_name()?;
+    let body = p.parse_parenthesized()?;
+
+    let mut table = Table {
+        name,
+        root_page: 0,
+        columns: Vec::new(),
+        primary_key: Vec::new(),
+        primary_key_descending: Vec::new(),
+        without_rowid: false,
+        rowid_alias: None,
+        unique_constraints: Vec::new(),
+        real_columns: Vec::new(),
+    };
+    let mut primary_key_desc = false;
+    let mut table_constraints = Vec::new();
+
+    for item in split_top_level(body) {
+        let first = item.first();
+        let is_table_constraint = ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"]
+            .iter()
+            .any(|k| DdlParser::is_keyword(first, k));
+        if is_table_constraint {
+            table_constraints.push(item);
+        } else {
+            let mut p = DdlParser {
+                tokens: item,
+                pos: 0,
+            };
+            primary_key_desc |= parse_column_def(&mut p, &mut table)?;
+        }
+    }
+    for item in table_constraints {
+        let mut p = DdlParser {
+            tokens: item,
+            pos: 0,
+        };
+        parse_table_constraint(&mut p, &mut table)?;
+    }
+
+    while !p.at_end() {
+        if p.parse_keywords(&["WITHOUT", "ROWID"]) {
+            table.without_rowid = true;
+        } else {
+            p.next();
+        }
+    }
+
+    if !table.without_rowid && table.primary_key.len() == 1 && !primary_key_desc {
+        let column = &table.columns[table.primary_key[0]];
+        if column.declared_type.eq_ignore_ascii_case("INTEGER") {
+            table.rowid_alias = Some(table.primary_key[0]);
+        }
+    }
+    // Neither an INTEGER PRIMARY KEY nor the key of a WITHOUT ROWID table
+    // gets an automatic index.
+    if table.rowid_alias.is_some() || table.without_rowid {
+        let primary_key = table.primary_key.clone();
+        if let Some(i) = table
+            .unique_constraints
+            .iter()
+            .position(|c| *c == primary_key)
+        {
+            table.unique_constraints.remove(i);
+        }
+    }
+    table.real_columns = table
+        .columns
+        .iter()
+        .map(|c| Affinity::from_declared_type(&c.declared_type) == Affinity::Real)
+        .collect();
+    Ok(table)
+}
+
+// Parses one column definition into `table`. Returns whether the column was
+// declared `PRIMARY KEY DESC`, which keeps it from becoming a rowid alias.
+fn parse_column_def(p: &mut DdlParser, table: &mut Table) -> Result<bool, Error> {
+    let index = table.columns.len();
+    let name = p.parse_name()?;
+
+    let mut type_words = Vec::new();
+    while let Some(Token::Word(_)) = p.peek() {
+        if is_column_constraint_start(p.peek()) {
+            break;
+        }
+        if let Some(Token::Word(w)) = p.next() {
+            type_words.push(w.value);
+        }
+    }
+    let mut declared_type = type_words.join(" ");
+    if let Some(Token::LParen) = p.peek() {
+        let arguments = p.parse_parenthesized()?;
+        let arguments: Vec<String> = arguments.iter().map(|t| t.to_string()).collect();
+        declared_type.push('(');
+        declared_type.push_str(&arguments.join(""));
+        declared_type.push(')');
+    }
+
+    table.columns.push(Column {
+        name,
+        declared_type,
+        not_null: false,
+        collation: None,
+        default: None,
+    });
+
+    let mut primary_key_desc = false;
+    while !p.at_end() {
+        if p.parse_keyword("CONSTRAINT") {
+            p.parse_name()?;
+        } else if p.parse_keywords(&["PRIMARY", "KEY"]) {
+            table.primary_key = vec![index];
+            table.unique_constraints.push(vec![index]);
+            if p.parse_keyword("DESC") {
+                primary_key_desc = true;
+            }
+            table.primary_key_descending = vec![primary_key_desc];
+        } else if p.parse_keywords(&["NOT", "NULL"]) {
+            table.columns[index].not_null = true;
+        } else if p.parse_keyword("UNIQUE") {
+            table.unique_constraints.push(vec![index]);
+        } else if p.parse_keyword("CHECK") {
+            p.parse_parenthesized()?;
+        } else if p.parse_keyword("DEFAULT") {
+            let affinity = Affinity::from_declared_type(&table.columns[index].declared_type);
+            table.columns[index].default = parse_default_value(p, affinity)?;
+        } else if p.parse_keyword("COLLATE") {
+            table.columns[index].collation = Some(p.parse_name()?);
+        } else if p.parse_keyword("REFERENCES") {
+            skip_foreign_key_clause(p)?;
+        } else if p.parse_keyword("GENERATED") || p.parse_keyword("AS") {
+            p.parse_keyword("ALWAYS");
+            p.parse_keyword("AS");
+            if let Some(Token::LParen) = p.peek() {
+                p.parse_parenthesized()?;
+            }
+        } else if let Some(Token::LParen) = p.peek() {
+            p.parse_parenthesized()?;
+        } else {
+            // NULL, ON CONFLICT clauses, AUTOINCREMENT, ASC, STORED, ...
+            p.next();
+        }
+    }
+    Ok(primary_key_desc)
+}
+
+// Reads the value after DEFAULT, a literal, a signed number or either in
+// parentheses, converted to the affinity of the column. Numbers are taken
+// as written, so a TEXT column keeps `1.50` as it is. Only constant values
+// are kept, as a default such as CURRENT_TIME cannot be given to a column
+// added later. A bare name is taken as a string, as SQLite does.
+fn parse_default_value(p: &mut DdlParser, affinity: Affinity) -> Result<Option<SqlValue>, Error> {
+    let tokens = match p.peek() {
+        Some(Token::LParen) => p.parse_parenthesized()?,
+        Some(Token::Plus) | Some(Token::Minus) => p.next().into_iter().chain(p.next()).collect(),
+        _ => p.next().into_iter().collect(),
+    };
+    let number = match tokens.as_slice() {
+        [Token::Number(n, _)] | [Token::Plus, Token::Number(n, _)] => Some(n.clone()),
+        [Token::Minus, Token::Number(n, _)] => Some(format!("-{}", n)),
+        _ => None,
+    };
+    if let Some(number) = number {
+        let affinity = match affinity {
+            Affinity::Blob => Affinity::Numeric,
+            affinity => affinity,
+        };
+        return Ok(Some(SqlValue::Text(number).apply_affinity(affinity)));
+    }
+
+    let dialect = SQLiteDialect {};
+    let value = match Parser::new(tokens, &dialect).parse_expr() {
+        Ok(Expr::Identifier(ident)) => {
+            let keyword = ident.value.to_ascii_lowercase();
+            if ["current_time", "current_date", "current_timestamp"].contains(&keyword.as_str()) {
+                return Ok(None);
+            }
+            SqlValue::Text(ident.value)
+        }
+        Ok(Expr::Value(literal)) => match expr::literal_value(&literal) {
+            Ok(value) => value,
+            Err(_) => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(value.apply_affinity(affinity)))
+}
+
+fn skip_foreign_key_clause(p: &mut DdlParser) -> Result<(), Error> {
+    p.parse_object_name()?;
+    if let Some(Token::LParen) = p.peek() {
+        p.parse_parenthesized()?;
+    }
+    loop {
+        if p.parse_keyword("ON") {
+            p.next(); // DELETE or UPDATE
+            if !(p.parse_keywords(&["SET", "NULL"])
+                || p.parse_keywords(&["SET", "DEFAULT"])
+                || p.parse_keywords(&["NO", "ACTION"]))
+            {
+                p.next(); // CASCADE or RESTRICT
+            }
+        } else if p.parse_keyword("MATCH") {
+            p.parse_name()?;
+        } else if p.parse_keywords(&["NOT", "DEFERRABLE"]) || p.parse_keyword("DEFERRABLE") {
+            if p.parse_keyword("INITIALLY") {
+                p.next();
+            }
+        } else {
+            return Ok(());
+        }
+    }
+}
+
+fn parse_table_constraint(p: &mut DdlParser, table: &mut Table) -> Result<(), Error> {
+    if p.parse_keyword("CONSTRAINT") {
+        p.parse_name()?;
+    }
+    if p.parse_keywords(&["PRIMARY", "KEY"]) {
+        let columns = parse_indexed_columns(p, table)?;
+        table.primary_key_descending = columns
+            .iter()
+            .filter(|c| c.column.is_some())
+            .map(|c| c.descending)
+            .collect();
+        let columns: Vec<usize> = columns.iter().filter_map(|c| c.column).collect();
+        table.primary_key = columns.clone();
+        table.unique_constraints.push(columns);
+    } else if p.parse_keyword("UNIQUE") {
+        let columns = parse_indexed_columns(p, table)?;
+        table
+            .unique_constraints
+            .push(columns.iter().filter_map(|c| c.column).collect());
+    }
+    // CHECK and FOREIGN KEY constraints do not affect how rows are stored
+    Ok(())
+}
+
+fn parse_indexed_columns(p: &mut DdlParser, table: &Table) -> Result<Vec<IndexColumn>, Error> {
+    let body = p.parse_parenthesized()?;
+    let mut columns = Vec::new();
+    for item in split_top_level(body) {
+        let mut p = DdlParser {
+            tokens: item,
+            pos: 0,
+        };
+        let column = match (p.tokens.first(), p.tokens.get(1)) {
+            (Some(Token::Word(w)), next)
+                if next.is_none()
+                    || DdlParser::is_keyword(next, "COLLATE")
+                    || DdlParser::is_keyword(next, "ASC")
+                    || DdlParser::is_keyword(next, "DESC") =>
+            {
+                p.pos = 1;
+                table.column_index(&w.value)
+            }
+            _ => {
+                // an expression; skip to the trailing COLLATE / ASC / DESC
+                while !p.at_end()
+                    && !p.peek_keyword("COLLATE")
+                    && !p.peek_keyword("ASC")
+                    && !p.peek_keyword("DESC")
+                {
+                    if let Some(Token::LParen) = p.peek() {
+                        p.parse_parenthesized()?;
+                    } else {
+                        p.next();
+                    }
+                }
+                None
+            }
+        };
+        let mut collation = column.and_then(|c| table.columns[c].collation.clone());
+        let mut descending = false;
+        while !p.at_end() {
+            if p.parse_keyword("COLLATE") {
+                collation = Some(p.parse_name()?);
+            } else if p.parse_keyword("DESC") {
+                descending = true;
+            } else {
+                p.next();
+            }
+        }
+        columns.push(IndexColumn {
+            column,
+            descending,
+            collation,
+        });
+    }
+    Ok(columns)
+}
+
+pub fn parse_create_index(sql: &str, table: &Table) -> Result<Index, Error> {
+    let mut p = DdlParser::new(sql)?;
+    p.expect_keyword("CREATE")?;
+    let unique = p.parse_keyword("UNIQUE");
+    p.expect_keyword("INDEX")?;
+    p.parse_keywords(&["IF", "NOT", "EXISTS"]);
+    let name = p.parse_object_name()?;
+    p.expect_keyword("ON")?;
+    let table_name = p.parse_object_name()?;
+    let columns = parse_indexed_columns(&mut p, table)?;
+    let partial = p.parse_keyword("WHERE");
+
+    Ok(Index {
+        name,
+        table_name,
+        root_page: 0,
+        columns,
+        unique,
+        partial,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_chinbook_table() {
+        let table = parse_create_table(
+            "CREATE TABLE [Album]
+            (
+                [AlbumId] INTEGER  NOT NULL,
+                [Title] NVARCHAR(160)  NOT NULL,
+                [ArtistId] INTEGER  NOT NULL,
+                CONSTRAINT [PK_Album] PRIMARY KEY  ([AlbumId]),
+                FOREIGN KEY ([ArtistId]) REFERENCES [Artist] ([ArtistId])
+                    ON DELETE NO ACTION ON UPDATE NO ACTION
+            )",
+        )
+        .unwrap();
+        assert_eq!(table.name, "Album");
+        assert_eq!(table.columns.len(), 3);
+        assert_eq!(table.columns[1].declared_type, "NVARCHAR(160)");
+        assert!(table.columns[1].not_null);
+        assert_eq!(table.primary_key, vec![0]);
+        assert_eq!(table.rowid_alias, Some(0));
+        assert!(!table.without_rowid);
+    }
+
+    #[test]
+    fn parse_without_rowid_table() {
+        let table = parse_create_table(
+            "CREATE TABLE t(a TEXT, b INT DEFAULT -1, c TEXT COLLATE NOCASE, \
+             PRIMARY KEY (c, a)) WITHOUT ROWID",
+        )
+        .unwrap();
+        assert!(table.without_rowid);
+        assert_eq!(table.rowid_alias, None);
+        assert_eq!(table.primary_key, vec![2, 0]);
+        assert_eq!(table.record_layout(), vec![2, 0, 1]);
+        assert_eq!(table.columns[2].collation.as_deref(), Some("NOCASE"));
+    }
+
+    #[test]
+    fn parse_column_defaults() {
+        let table = parse_create_table(
+            "CREATE TABLE t(a INTEGER DEFAULT '7', b TEXT DEFAULT 1.50, c REAL DEFAULT -2, \
+             d DEFAULT abc, e DEFAULT x'ff', f DEFAULT (+3), g DEFAULT CURRENT_TIME, h, \
+             i TEXT DEFAULT 1e2, j TEXT DEFAULT -1.5e-3)",
+        )
+        .unwrap();
+        let defaults: Vec<Option<SqlValue>> =
+            table.columns.iter().map(|c| c.default.clone()).collect();
+        assert_eq!(
+            defaults,
+            vec![
+                Some(SqlValue::Integer(7)),
+                Some(SqlValue::Text("1.50".to_string())),
+                Some(SqlValue::Real(-2.0)),
+                Some(SqlValue::Text("abc".to_string())),
+                Some(SqlValue::Blob(vec![0xff])),
+                Some(SqlValue::Integer(3)),
+                None,
+                None,
+                Some(SqlValue::Text("1e2".to_string())),
+                Some(SqlValue::Text("-1.5e-3".to_string())),
+            ]
+        );
+        let row = table.row_from_record(Some(1), vec![Value::One]);
+        assert_eq!(row[0], SqlValue::Integer(1));
+        assert_eq!(row[2], SqlValue::Real(-2.0));
+        assert_eq!(row[7], SqlValue::Null);
+    }
+
+    #[test]
+    fn primary_key_desc_is_not_a_rowid_alias() {
+        let table = parse_create_table("CREATE TABLE t(id INTEGER PRIMARY KEY DESC, x)").unwrap();
+        assert_eq!(table.rowid_alias, None);
+        assert_eq!(table.columns[1].declared_type, "");
+    }
+}
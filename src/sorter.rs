@@ -0,0 +1,329 @@
+use crate::expr::{compare_values, Collation};
+use crate::record::Record;
+use crate::value::SqlValue;
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+// Default amount of memory a sort may use before spilling sorted runs to disk.
+pub const DEFAULT_SORT_BUDGET: usize = 8 * 1024 * 1024;
+
+// How one key column of a sorted row is ordered.
+#[derive(Debug, Clone)]
+pub struct SortKey {
+    pub descending: bool,
+    pub nulls_first: bool,
+    pub collation: Collation,
+}
+
+impl SortKey {
+    // SQLite's default: NULLs are the smallest values, so they come first in
+    // ascending order and last in descending order.
+    pub fn new(descending: bool, collation: Collation) -> SortKey {
+        SortKey {
+            descending,
+            nulls_first: !descending,
+            collation,
+        }
+    }
+}
+
+// Compares two rows whose leading values are the sort keys.
+pub fn compare_rows(keys: &[SortKey], a: &[SqlValue], b: &[SqlValue]) -> Ordering {
+    for (i, key) in keys.iter().enumerate() {
+        let ordering = match (a[i].is_null(), b[i].is_null()) {
+            (true, true) => Ordering::Equal,
+            (true, false) if key.nulls_first => Ordering::Less,
+            (true, false) => Ordering::Greater,
+            (false, true) if key.nulls_first => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) => {
+                let ordering = compare_values(&a[i], &b[i], &key.collation);
+                if key.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+// Sorts rows in memory as long as they fit in the memory budget. Beyond that
+// each batch is sorted and written to a temporary file as a run, and the runs
+// are merged when the rows are read back. Rows with equal keys keep the order
+// they were added in.
+pub struct Sorter {
+    keys: Vec<SortKey>,
+    budget: usize,
+    rows: Vec<Vec<SqlValue>>,
+    memory: usize,
+    runs: Vec<Run>,
+}
+
+impl Sorter {
+    pub fn new(keys: Vec<SortKey>, budget: usize) -> Sorter {
+        Sorter {
+            keys,
+            budget,
+            rows: Vec::new(),
+            memory: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    // Adds a row, which starts with one value for each sort key.
+    pub fn push(&mut self, row: Vec<SqlValue>) -> Result<(), Error> {
+        self.memory += row_size(&row);
+        self.rows.push(row);
+        if self.memory > self.budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    pub fn spilled_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    pub fn finish(mut self) -> Result<SortedRows, Error> {
+        if self.runs.is_empty() {
+            self.sort();
+            return Ok(SortedRows(Source::Memory(self.rows.into_iter())));
+        }
+        if !self.rows.is_empty() {
+            self.spill()?;
+        }
+        let mut heads = Vec::with_capacity(self.runs.len());
+        for run in &mut self.runs {
+            heads.push(run.read_row()?);
+        }
+        Ok(SortedRows(Source::Merge {
+            keys: self.keys,
+            runs: self.runs,
+            heads,
+        }))
+    }
+
+    fn sort(&mut self) {
+        let keys = &self.keys;
+        self.rows.sort_by(|a, b| compare_rows(keys, a, b));
+    }
+
+    fn spill(&mut self) -> Result<(), Error> {
+        self.sort();
+        let mut run = Run::create()?;
+        {
+            let mut writer = BufWriter::new(&run.file);
+            for row in self.rows.drain(..) {
+                let record = Record::encode(&row);
+                writer.write_all(&(record.len() as u32).to_be_bytes())?;
+                writer.write_all(&record)?;
+            }
+            writer.flush()?;
+        }
+        run.rewind()?;
+        self.runs.push(run);
+        self.memory = 0;
+        Ok(())
+    }
+}
+
+// Rough number of bytes a row occupies in memory.
+fn row_size(row: &[SqlValue]) -> usize {
+    row.iter()
+        .map(|v| {
+            std::mem::size_of::<SqlValue>()
+                + match v {
+                    SqlValue::Text(s) => s.len(),
+                    SqlValue::Blob(b) => b.len(),
+                    _ => 0,
+                }
+        })
+        .sum()
+}
+
+static RUN_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// A sorted run in a temporary file, removed again when the run is dropped.
+struct Run {
+    path: PathBuf,
+    file: File,
+    reader: Option<BufReader<File>>,
+}
+
+impl Run {
+    fn create() -> Result<Run, Error> {
+        let path = std::env::temp_dir().join(format!(
+            "rsqlite-sort-{}-{}",
+            std::process::id(),
+            RUN_COUNTER.fetch_add(1, AtomicOrdering::Relaxed)
+        ));
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Run {
+            path,
+            file,
+            reader: None,
+        })
+    }
+
+    fn rewind(&mut self) -> Result<(), Error> {
+        let mut file = self.file.try_clone()?;
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(0))?;
+        self.reader = Some(BufReader::new(file));
+        Ok(())
+    }
+
+    fn read_row(&mut self) -> Result<Option<Vec<SqlValue>>, Error> {
+        let reader = self.reader.as_mut().unwrap();
+        let mut length = [0u8; 4];
+        match reader.read_exact(&mut length) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut record = vec![0u8; u32::from_be_bytes(length) as usize];
+        reader.read_exact(&mut record)?;
+        let record = Record::from_cell_bytes(&record)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "malformed sort run"))?;
+        Ok(Some(
+            record.into_values().into_iter().map(From::from).collect(),
+        ))
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// The rows of a finished sort, in order.
+pub struct SortedRows(Source);
+
+enum Source {
+    Memory(std::vec::IntoIter<Vec<SqlValue>>),
+    Merge {
+        keys: Vec<SortKey>,
+        runs: Vec<Run>,
+        // The next row of each run.
+        heads: Vec<Option<Vec<SqlValue>>>,
+    },
+}
+
+impl Iterator for SortedRows {
+    type Item = Result<Vec<SqlValue>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            Source::Memory(rows) => rows.next().map(Ok),
+            Source::Merge { keys, runs, heads } => {
+                // earlier runs win ties, which keeps the sort stable
+                let mut smallest: Option<usize> = None;
+                for (i, head) in heads.iter().enumerate() {
+                    if let Some(row) = head {
+                        let is_smaller = match smallest {
+                            Some(s) => {
+                                compare_rows(keys, row, heads[s].as_ref().unwrap())
+                                    == Ordering::Less
+                            }
+                            None => true,
+                        };
+                        if is_smaller {
+                            smallest = Some(i);
+                        }
+                    }
+                }
+                let i = smallest?;
+                let next = match runs[i].read_row() {
+                    Ok(next) => next,
+                    Err(e) => return Some(Err(e)),
+                };
+                std::mem::replace(&mut heads[i], next).map(Ok)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sort(keys: Vec<SortKey>, budget: usize, rows: Vec<Vec<SqlValue>>) -> Vec<Vec<SqlValue>> {
+        let mut sorter = Sorter::new(keys, budget);
+        for row in rows {
+            sorter.push(row).unwrap();
+        }
+        sorter.finish().unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_sort_in_memory() {
+        let rows = vec![
+            vec![SqlValue::from("b")],
+            vec![SqlValue::Null],
+            vec![SqlValue::from("A")],
+            vec![SqlValue::Integer(3)],
+        ];
+        let sorted = sort(
+            vec![SortKey::new(false, Collation::Binary)],
+            DEFAULT_SORT_BUDGET,
+            rows.clone(),
+        );
+        assert_eq!(
+            sorted,
+            vec![
+                rows[1].clone(),
+                rows[3].clone(),
+                rows[2].clone(),
+                rows[0].clone()
+            ]
+        );
+
+        let mut key = SortKey::new(true, Collation::NoCase);
+        key.nulls_first = true;
+        let sorted = sort(vec![key], DEFAULT_SORT_BUDGET, rows.clone());
+        assert_eq!(
+            sorted,
+            vec![
+                rows[1].clone(),
+                rows[0].clone(),
+                rows[2].clone(),
+                rows[3].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sort_spills_and_merges() {
+        let rows: Vec<Vec<SqlValue>> = (0..1000)
+            .map(|i| vec![SqlValue::Integer((i * 7919) % 100), SqlValue::Integer(i)])
+            .collect();
+        let keys = vec![SortKey::new(false, Collation::Binary)];
+
+        let mut sorter = Sorter::new(keys.clone(), 4096);
+        for row in rows.clone() {
+            sorter.push(row).unwrap();
+        }
+        assert!(sorter.spilled_runs() > 1);
+        let sorted: Vec<Vec<SqlValue>> = sorter.finish().unwrap().map(Result::unwrap).collect();
+
+        let expected = sort(keys, DEFAULT_SORT_BUDGET, rows);
+        assert_eq!(sorted.len(), 1000);
+        // the merge is stable, just like the in-memory sort
+        for (a, b) in sorted.iter().zip(expected.iter()) {
+            assert_eq!(a[0], b[0]);
+            assert_eq!(a[1].as_i64(), b[1].as_i64());
+        }
+    }
+}
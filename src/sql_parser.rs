@@ -1,8 +1,1227 @@
 pub use sqlparser::ast::Statement;
+use sqlparser::ast::{BinaryOperator, Cte, Expr, Value, WindowSpec};
+use sqlparser::dialect::keywords::Keyword;
 use sqlparser::dialect::SQLiteDialect;
 use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer, Whitespace};
+
+// sqlparser does not know a few parts of SQLite's syntax, so the tokens are
+// rewritten into something it does parse before they are handed to it:
+//
+// - The MATERIALIZED and NOT MATERIALIZED hints of common table expressions
+//   are moved behind the CTE's query into the `FROM <name>` slot of `Cte`,
+//   which SQLite has no use for otherwise, and read back with
+//   `materialization_hint`.
+// - Windows named in a WINDOW clause are replaced by their definitions.
+// - The EXCLUDE clause of a window frame becomes a marker in front of the
+//   PARTITION BY terms, read back with `frame_exclusion`.
+// - Numbers with an exponent, such as 1.5e-3, are joined back together.
+// - substring(X, Y, Z) is an ordinary function, not the SQL standard's
+//   SUBSTRING(X FROM Y FOR Z).
+// - The JSON operators `X -> Y` and `X ->> Y` become `X || marker || Y`,
+//   which has the same precedence and associativity, read back with `arrow`.
+// - `X GLOB Y` becomes `X LIKE marker || Y` and `X LIKE Y ESCAPE Z`
+//   becomes `X LIKE Y || marker || Z`, with Y and Z in parentheses when they
+//   have operators of their own, read back with `pattern`.
+// - The parameters `?`, `?NNN`, `:name`, `@name` and `$name` become markers
+//   holding their number and text, read back with `parameter`.
+// - EXPLAIN QUERY PLAN becomes EXPLAIN VERBOSE, read back with
+//   `query_plan`.
+// - LIMIT and OFFSET take only an unsigned number, so a sign in front of
+//   it, as in SQLite's `LIMIT -1 OFFSET N`, is joined to the number.
+// - ANALYZE [name] becomes Hive's ANALYZE TABLE name, naming the database
+//   `main` when no name is given.
+const MATERIALIZED: &str = "MATERIALIZED";
+const NOT_MATERIALIZED: &str = "NOT_MATERIALIZED";
+
+const EXCLUDE_CURRENT_ROW: &str = "\0EXCLUDE CURRENT ROW";
+const EXCLUDE_GROUP: &str = "\0EXCLUDE GROUP";
+const EXCLUDE_TIES: &str = "\0EXCLUDE TIES";
+
+const ARROW: &str = "\0->";
+const DOUBLE_ARROW: &str = "\0->>";
+
+const GLOB: &str = "\0GLOB";
+const ESCAPE: &str = "\0ESCAPE";
+
+const PARAMETER: &str = "\0?";
+
+// The largest number a parameter may have, as in SQLite.
+pub const MAX_PARAMETER: usize = 32766;
 
 pub fn parse_sql(sql: &str) -> Result<std::vec::Vec<Statement>, ParserError> {
+    Ok(parse_sql_with_parameters(sql)?.0)
+}
+
+// Like `parse_sql`, but also returns the names of the parameters by number,
+// starting at ?1. Parameters written as a bare `?` have no name.
+pub fn parse_sql_with_parameters(
+    sql: &str,
+) -> Result<(Vec<Statement>, Vec<Option<String>>), ParserError> {
     let dialect = SQLiteDialect {};
-    Parser::parse_sql(&dialect, sql)
+    let mut tokens = Tokenizer::new(&dialect, sql).tokenize()?;
+    join_exponents(&mut tokens);
+    sign_limits(&mut tokens);
+    move_materialization_hints(&mut tokens);
+    expand_named_windows(&mut tokens)?;
+    mark_frame_exclusions(&mut tokens);
+    unreserve_function_names(&mut tokens);
+    mark_arrows(&mut tokens);
+    mark_query_plans(&mut tokens);
+    add_analyze_targets(&mut tokens);
+    let parameters = mark_parameters(&mut tokens)?;
+    mark_pattern_operators(&mut tokens);
+
+    // as in `Parser::parse_sql`
+    let mut parser = Parser::new(tokens, &dialect);
+    let mut statements = Vec::new();
+    let mut expecting_statement_delimiter = false;
+    loop {
+        while parser.consume_token(&Token::SemiColon) {
+            expecting_statement_delimiter = false;
+        }
+        if parser.peek_token() == Token::EOF {
+            break;
+        }
+        if expecting_statement_delimiter {
+            return Err(ParserError::ParserError(format!(
+                "Expected end of statement, found: {}",
+                parser.peek_token()
+            )));
+        }
+        statements.push(parser.parse_statement()?);
+        expecting_statement_delimiter = true;
+    }
+    Ok((statements, parameters))
+}
+
+// Whether a common table expression asked to be materialized, or asked not
+// to be, or left it to the executor.
+pub fn materialization_hint(cte: &Cte) -> Option<bool> {
+    match cte.from.as_ref().map(|ident| ident.value.as_str()) {
+        Some(MATERIALIZED) => Some(true),
+        Some(NOT_MATERIALIZED) => Some(false),
+        _ => None,
+    }
+}
+
+// The statement an EXPLAIN QUERY PLAN statement describes, if `statement`
+// is one.
+pub fn query_plan(statement: &Statement) -> Option<&Statement> {
+    match statement {
+        Statement::Explain {
+            analyze: false,
+            verbose: true,
+            statement,
+        } => Some(statement),
+        _ => None,
+    }
+}
+
+// The statement a plain EXPLAIN statement lists the program of, if
+// `statement` is one.
+pub fn explain(statement: &Statement) -> Option<&Statement> {
+    match statement {
+        Statement::Explain {
+            analyze: false,
+            verbose: false,
+            statement,
+        } => Some(statement),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameExclusion {
+    NoOthers,
+    CurrentRow,
+    Group,
+    Ties,
+}
+
+// The EXCLUDE clause of a window, and its actual PARTITION BY terms.
+pub fn frame_exclusion(window: &WindowSpec) -> (FrameExclusion, &[Expr]) {
+    if let Some(Expr::Value(Value::SingleQuotedString(s))) = window.partition_by.first() {
+        let exclusion = match s.as_str() {
+            EXCLUDE_CURRENT_ROW => Some(FrameExclusion::CurrentRow),
+            EXCLUDE_GROUP => Some(FrameExclusion::Group),
+            EXCLUDE_TIES => Some(FrameExclusion::Ties),
+            _ => None,
+        };
+        if let Some(exclusion) = exclusion {
+            return (exclusion, &window.partition_by[1..]);
+        }
+    }
+    (FrameExclusion::NoOthers, &window.partition_by)
+}
+
+// The JSON operator `->` or `->>` and its left operand, if `expr` is one.
+// Its right operand is that of `expr`.
+pub fn arrow(expr: &Expr) -> Option<(&'static str, &Expr)> {
+    if let Expr::BinaryOp {
+        left,
+        op: BinaryOperator::StringConcat,
+        ..
+    } = expr
+    {
+        if let Expr::BinaryOp {
+            left,
+            op: BinaryOperator::StringConcat,
+            right,
+        } = left.as_ref()
+        {
+            match right.as_ref() {
+                Expr::Value(Value::SingleQuotedString(s)) if s == ARROW => {
+                    return Some(("->", left))
+                }
+                Expr::Value(Value::SingleQuotedString(s)) if s == DOUBLE_ARROW => {
+                    return Some(("->>", left))
+                }
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+// What the right operand of a LIKE operator matches the left one against.
+#[derive(Debug, PartialEq)]
+pub enum Pattern<'e> {
+    // A LIKE pattern, and the expression for its escape character.
+    Like(&'e Expr, Option<&'e Expr>),
+    Glob(&'e Expr),
+}
+
+pub fn pattern(right: &Expr) -> Pattern<'_> {
+    let marker = |expr: &Expr, marker: &str| matches!(expr, Expr::Value(Value::SingleQuotedString(s)) if s == marker);
+    if let Expr::BinaryOp {
+        left,
+        op: BinaryOperator::StringConcat,
+        right: operand,
+    } = right
+    {
+        if marker(left, GLOB) {
+            return Pattern::Glob(operand);
+        }
+        if let Expr::BinaryOp {
+            left: pattern,
+            op: BinaryOperator::StringConcat,
+            right: escape,
+        } = left.as_ref()
+        {
+            if marker(escape, ESCAPE) {
+                return Pattern::Like(pattern, Some(operand));
+            }
+        }
+    }
+    Pattern::Like(right, None)
+}
+
+// The number of the parameter `expr` is, if it is one.
+pub fn parameter(expr: &Expr) -> Option<usize> {
+    match expr {
+        Expr::Value(Value::SingleQuotedString(s)) | Expr::Value(Value::Number(s, _)) => {
+            let (number, _) = s.strip_prefix(PARAMETER)?.split_once('\0')?;
+            number.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+// An expression as SQL text, with the JSON operators and the parameters
+// written as they were.
+pub fn display(expr: &Expr) -> String {
+    let text = expr
+        .to_string()
+        .replace(&format!(" || '{}' || ", DOUBLE_ARROW), " ->> ")
+        .replace(&format!(" || '{}' || ", ARROW), " -> ")
+        .replace(&format!(" || '{}' || ", ESCAPE), " ESCAPE ")
+        .replace(&format!("LIKE '{}' || ", GLOB), "GLOB ");
+    let marker = format!("'{}", PARAMETER);
+    let mut parts = text.split(&marker);
+    let mut displayed = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        // the number, then the parameter as written and the closing quote
+        let written = part.split_once('\0').map_or(part, |(_, written)| written);
+        let end = written.find('\'').unwrap_or(written.len());
+        displayed.push_str(&written[..end]);
+        displayed.push_str(&written[(end + 1).min(written.len())..]);
+    }
+    displayed
+}
+
+fn is_keyword(token: &Token, keyword: Keyword) -> bool {
+    matches!(token, Token::Word(w) if w.keyword == keyword && w.quote_style.is_none())
+}
+
+// Matches words sqlparser has no keyword for.
+fn is_word(token: &Token, word: &str) -> bool {
+    matches!(token, Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(word))
+}
+
+// The position of the first token from `i` on that is not whitespace.
+fn skip_whitespace(tokens: &[Token], mut i: usize) -> usize {
+    while i < tokens.len() && matches!(tokens[i], Token::Whitespace(_)) {
+        i += 1;
+    }
+    i
+}
+
+// The position of the parenthesis closing the one at `open`.
+fn closing_paren(tokens: &[Token], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        match token {
+            Token::LParen => depth += 1,
+            Token::RParen => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+fn space() -> Token {
+    Token::Whitespace(Whitespace::Space)
+}
+
+// Rewrites `AS [NOT] MATERIALIZED (query)` into `AS (query) FROM [NOT_]
+// MATERIALIZED`.
+fn move_materialization_hints(tokens: &mut Vec<Token>) {
+    let mut i = 0;
+    while i < tokens.len() {
+        if !is_keyword(&tokens[i], Keyword::AS) {
+            i += 1;
+            continue;
+        }
+        let mut j = skip_whitespace(tokens, i + 1);
+        let mut hint = MATERIALIZED;
+        if j < tokens.len() && is_keyword(&tokens[j], Keyword::NOT) {
+            hint = NOT_MATERIALIZED;
+            j = skip_whitespace(tokens, j + 1);
+        }
+        if j == tokens.len() || !is_keyword(&tokens[j], Keyword::MATERIALIZED) {
+            i += 1;
+            continue;
+        }
+        let open = skip_whitespace(tokens, j + 1);
+        let close = match tokens.get(open) {
+            Some(Token::LParen) => closing_paren(tokens, open),
+            _ => None,
+        };
+        if let Some(close) = close {
+            let suffix = [
+                space(),
+                Token::make_keyword("FROM"),
+                space(),
+                Token::make_word(hint, None),
+            ];
+            tokens.splice(close + 1..close + 1, suffix);
+            tokens.drain(i + 1..open);
+        }
+        i += 1;
+    }
+}
+
+fn no_such_window(name: &Token) -> ParserError {
+    let name = match name {
+        Token::Word(w) => w.value.clone(),
+        token => token.to_string(),
+    };
+    ParserError::ParserError(format!("no such window: {}", name))
+}
+
+// Whether a window specification in parentheses starts with the name of
+// another window rather than one of its clauses.
+fn is_window_name(token: &Token) -> bool {
+    match token {
+        Token::Word(w) => {
+            ![
+                Keyword::PARTITION,
+                Keyword::ORDER,
+                Keyword::ROWS,
+                Keyword::RANGE,
+                Keyword::GROUPS,
+            ]
+            .contains(&w.keyword)
+                && !is_word(token, "EXCLUDE")
+        }
+        _ => false,
+    }
+}
+
+type Windows = Vec<(String, Vec<Token>)>;
+
+fn window_definition<'w>(windows: &'w Windows, name: &Token) -> Result<&'w [Token], ParserError> {
+    if let Token::Word(w) = name {
+        if let Some((_, body)) = windows
+            .iter()
+            .rev()
+            .find(|(n, _)| n.eq_ignore_ascii_case(&w.value))
+        {
+            return Ok(body);
+        }
+    }
+    Err(no_such_window(name))
+}
+
+// Replaces `OVER name` and `OVER (name ...)` with the definitions of the
+// WINDOW clause of their SELECT, and removes the clause.
+fn expand_named_windows(tokens: &mut Vec<Token>) -> Result<(), ParserError> {
+    while let Some(clause) = (0..tokens.len()).find(|i| {
+        let name = skip_whitespace(tokens, i + 1);
+        let as_ = skip_whitespace(tokens, name + 1);
+        is_keyword(&tokens[*i], Keyword::WINDOW)
+            && matches!(tokens.get(name), Some(Token::Word(_)))
+            && tokens.get(as_).is_some_and(|t| is_keyword(t, Keyword::AS))
+    }) {
+        // name AS (definition), ...
+        let mut windows = Windows::new();
+        let mut end = clause + 1;
+        loop {
+            let name = skip_whitespace(tokens, end);
+            let open = skip_whitespace(tokens, skip_whitespace(tokens, name + 1) + 1);
+            let close = match tokens.get(open) {
+                Some(Token::LParen) => closing_paren(tokens, open),
+                _ => None,
+            }
+            .ok_or_else(|| ParserError::ParserError("malformed WINDOW clause".into()))?;
+            let mut body = tokens[open + 1..close].to_vec();
+            let first = skip_whitespace(&body, 0);
+            if first < body.len() && is_window_name(&body[first]) {
+                let base = window_definition(&windows, &body[first])?.to_vec();
+                body.splice(..=first, base);
+            }
+            if let Token::Word(w) = &tokens[name] {
+                windows.push((w.value.clone(), body));
+            }
+            end = skip_whitespace(tokens, close + 1);
+            if tokens.get(end) != Some(&Token::Comma) {
+                break;
+            }
+            end += 1;
+        }
+
+        // the clause belongs to the last SELECT before it at the same
+        // depth, which ends at the closing parenthesis, a compound operator
+        // or the end of the statement
+        let mut depth = 0;
+        let mut depths = Vec::with_capacity(clause);
+        for token in &tokens[..clause] {
+            match token {
+                Token::LParen => depth += 1,
+                Token::RParen => depth -= 1,
+                _ => (),
+            }
+            depths.push(depth);
+        }
+        let start = (0..clause)
+            .rev()
+            .find(|i| depths[*i] == depth && is_keyword(&tokens[*i], Keyword::SELECT))
+            .unwrap_or(0);
+        let mut stop = tokens.len();
+        let mut d = depth;
+        for (i, token) in tokens.iter().enumerate().skip(end) {
+            let ends_select = match token {
+                Token::LParen => {
+                    d += 1;
+                    false
+                }
+                Token::RParen => {
+                    d -= 1;
+                    d < depth
+                }
+                Token::SemiColon => true,
+                t => {
+                    d == depth
+                        && [Keyword::UNION, Keyword::INTERSECT, Keyword::EXCEPT]
+                            .iter()
+                            .any(|k| is_keyword(t, *k))
+                }
+            };
+            if ends_select {
+                stop = i;
+                break;
+            }
+        }
+
+        let mut expanded = tokens[..start].to_vec();
+        let mut d = depth;
+        // the depth of a subquery being passed over, whose windows are its
+        // own
+        let mut subquery: Option<i32> = None;
+        let mut i = start;
+        while i < stop {
+            if i == clause {
+                i = end;
+                continue;
+            }
+            let token = &tokens[i];
+            match token {
+                Token::LParen => d += 1,
+                Token::RParen => {
+                    d -= 1;
+                    if subquery.is_some_and(|s| d < s) {
+                        subquery = None;
+                    }
+                }
+                t if i > start && is_keyword(t, Keyword::SELECT) && subquery.is_none() => {
+                    subquery = Some(d)
+                }
+                t if is_keyword(t, Keyword::OVER) && subquery.is_none() => {
+                    let next = skip_whitespace(tokens, i + 1);
+                    match tokens.get(next) {
+                        Some(Token::LParen) => {
+                            let first = skip_whitespace(tokens, next + 1);
+                            if first < tokens.len() && is_window_name(&tokens[first]) {
+                                expanded.extend_from_slice(&tokens[i..=next]);
+                                expanded.extend_from_slice(window_definition(
+                                    &windows,
+                                    &tokens[first],
+                                )?);
+                                expanded.push(space());
+                                d += 1;
+                                i = first + 1;
+                                continue;
+                            }
+                        }
+                        Some(name @ Token::Word(_)) => {
+                            expanded.extend_from_slice(&tokens[i..next]);
+                            expanded.push(Token::LParen);
+                            expanded.extend_from_slice(window_definition(&windows, name)?);
+                            expanded.push(Token::RParen);
+                            i = next + 1;
+                            continue;
+                        }
+                        _ => (),
+                    }
+                }
+                _ => (),
+            }
+            expanded.push(token.clone());
+            i += 1;
+        }
+        expanded.extend_from_slice(&tokens[stop..]);
+        *tokens = expanded;
+    }
+
+    // whatever is left names windows no WINDOW clause defines
+    for (i, token) in tokens.iter().enumerate() {
+        if is_keyword(token, Keyword::OVER) {
+            let mut next = skip_whitespace(tokens, i + 1);
+            if tokens.get(next) == Some(&Token::LParen) {
+                next = skip_whitespace(tokens, next + 1);
+            }
+            if next < tokens.len() && is_window_name(&tokens[next]) {
+                return Err(no_such_window(&tokens[next]));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Replaces `EXCLUDE ...` at the end of a window specification with a marker
+// at the front of its PARTITION BY terms.
+fn mark_frame_exclusions(tokens: &mut Vec<Token>) {
+    let mut i = 0;
+    while i < tokens.len() {
+        let open = skip_whitespace(tokens, i + 1);
+        if !is_keyword(&tokens[i], Keyword::OVER) || tokens.get(open) != Some(&Token::LParen) {
+            i += 1;
+            continue;
+        }
+        let close = match closing_paren(tokens, open) {
+            Some(close) => close,
+            None => break,
+        };
+        let exclude = (open + 1..close).find(|j| is_word(&tokens[*j], "EXCLUDE"));
+        if let Some(exclude) = exclude {
+            let words: Vec<&Token> = tokens[exclude + 1..close]
+                .iter()
+                .filter(|t| !matches!(t, Token::Whitespace(_)))
+                .collect();
+            let marker = match words.as_slice() {
+                [no, others] if is_keyword(no, Keyword::NO) && is_word(others, "OTHERS") => {
+                    Some(None)
+                }
+                [current, row]
+                    if is_keyword(current, Keyword::CURRENT) && is_keyword(row, Keyword::ROW) =>
+                {
+                    Some(Some(EXCLUDE_CURRENT_ROW))
+                }
+                [group] if is_keyword(group, Keyword::GROUP) => Some(Some(EXCLUDE_GROUP)),
+                [ties] if is_keyword(ties, Keyword::TIES) => Some(Some(EXCLUDE_TIES)),
+                // left for the parser to complain about
+                _ => None,
+            };
+            if let Some(marker) = marker {
+                tokens.drain(exclude..close);
+                if let Some(marker) = marker {
+                    let marker = Token::SingleQuotedString(marker.to_string());
+                    let partition =
+                        (open + 1..exclude).find(|j| is_keyword(&tokens[*j], Keyword::PARTITION));
+                    match partition {
+                        Some(partition) => {
+                            let by = skip_whitespace(tokens, partition + 1);
+                            tokens.splice(by + 1..by + 1, [space(), marker, Token::Comma]);
+                        }
+                        None => {
+                            let partition = [
+                                Token::make_keyword("PARTITION"),
+                                space(),
+                                Token::make_keyword("BY"),
+                                space(),
+                                marker,
+                                space(),
+                            ];
+                            tokens.splice(open + 1..open + 1, partition);
+                        }
+                    }
+                }
+            }
+        }
+        i = open + 1;
+    }
+}
+
+// The tokenizer ends a number at the `e` of its exponent, which leaves the
+// exponent as a word, or as the word `e`, a sign and a number.
+pub(crate) fn join_exponents(tokens: &mut Vec<Token>) {
+    let mut i = 0;
+    while i + 1 < tokens.len() {
+        let exponent = match (&tokens[i], &tokens[i + 1]) {
+            (Token::Number(_, false), Token::Word(w)) if w.quote_style.is_none() => {
+                let (e, digits) = w.value.split_at(1.min(w.value.len()));
+                if !e.eq_ignore_ascii_case("e") || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    None
+                } else if !digits.is_empty() {
+                    Some((w.value.clone(), 2))
+                } else {
+                    match (tokens.get(i + 2), tokens.get(i + 3)) {
+                        (Some(Token::Plus), Some(Token::Number(n, false))) => {
+                            Some((format!("{}+{}", e, n), 4))
+                        }
+                        (Some(Token::Minus), Some(Token::Number(n, false))) => {
+                            Some((format!("{}-{}", e, n), 4))
+                        }
+                        _ => None,
+                    }
+                }
+            }
+            _ => None,
+        };
+        if let Some((exponent, length)) = exponent {
+            if let Token::Number(n, _) = &mut tokens[i] {
+                n.push_str(&exponent);
+            }
+            tokens.drain(i + 1..i + length);
+        }
+        i += 1;
+    }
+}
+
+// Joins the sign of a negative or positive LIMIT or OFFSET to its number.
+fn sign_limits(tokens: &mut Vec<Token>) {
+    let mut i = 0;
+    while i < tokens.len() {
+        if is_keyword(&tokens[i], Keyword::LIMIT) || is_keyword(&tokens[i], Keyword::OFFSET) {
+            let sign = skip_whitespace(tokens, i + 1);
+            let number = skip_whitespace(tokens, sign + 1);
+            let minus = match tokens.get(sign) {
+                Some(Token::Minus) => true,
+                Some(Token::Plus) => false,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            if let Some(Token::Number(n, _)) = tokens.get_mut(number) {
+                if minus {
+                    n.insert(0, '-');
+                }
+                tokens.drain(sign..number);
+            }
+        }
+        i += 1;
+    }
+}
+
+// Turns the SUBSTRING keyword into a plain name where it is called.
+fn unreserve_function_names(tokens: &mut [Token]) {
+    for i in 0..tokens.len() {
+        let open = skip_whitespace(tokens, i + 1);
+        if is_keyword(&tokens[i], Keyword::SUBSTRING) && tokens.get(open) == Some(&Token::LParen) {
+            if let Token::Word(word) = &mut tokens[i] {
+                word.keyword = Keyword::NoKeyword;
+            }
+        }
+    }
+}
+
+// Replaces the JSON operators, which the tokenizer splits into `-` and `>`
+// or `>>`, with `|| marker ||`.
+fn mark_arrows(tokens: &mut Vec<Token>) {
+    let mut i = 0;
+    while i + 1 < tokens.len() {
+        let marker = match (&tokens[i], &tokens[i + 1]) {
+            (Token::Minus, Token::Gt) => ARROW,
+            (Token::Minus, Token::ShiftRight) => DOUBLE_ARROW,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        let marker = [
+            Token::StringConcat,
+            Token::SingleQuotedString(marker.to_string()),
+            Token::StringConcat,
+        ];
+        tokens.splice(i..i + 2, marker);
+        i += 3;
+    }
+}
+
+// Rewrites the GLOB operator and the ESCAPE clause of LIKE into LIKE
+// operators `pattern` recognizes.
+fn mark_pattern_operators(tokens: &mut Vec<Token>) {
+    let mut i = 0;
+    while i < tokens.len() {
+        if is_word(&tokens[i], "GLOB") && follows_operand(tokens, i) {
+            tokens[i] = Token::make_keyword("LIKE");
+            let pattern = skip_whitespace(tokens, i + 1);
+            let (end, compound) = operand_end(tokens, pattern);
+            if compound {
+                group(tokens, pattern, end);
+            }
+            let marker = [
+                Token::SingleQuotedString(GLOB.to_string()),
+                space(),
+                Token::StringConcat,
+                space(),
+            ];
+            tokens.splice(pattern..pattern, marker);
+        } else if is_keyword(&tokens[i], Keyword::LIKE) {
+            let pattern = skip_whitespace(tokens, i + 1);
+            let (end, compound) = operand_end(tokens, pattern);
+            let escape = skip_whitespace(tokens, end);
+            if escape < tokens.len() && is_keyword(&tokens[escape], Keyword::ESCAPE) {
+                let character = skip_whitespace(tokens, escape + 1);
+                let (character_end, character_compound) = operand_end(tokens, character);
+                // from the end back, so the positions before stay put
+                if character_compound {
+                    group(tokens, character, character_end);
+                }
+                let marker = [
+                    space(),
+                    Token::StringConcat,
+                    space(),
+                    Token::SingleQuotedString(ESCAPE.to_string()),
+                    space(),
+                    Token::StringConcat,
+                    space(),
+                ];
+                tokens.splice(end..character, marker);
+                if compound {
+                    group(tokens, pattern, end);
+                }
+            }
+        }
+        i += 1;
+    }
+}
+
+// Whether the word at `i` comes after an operand, which makes a GLOB there
+// the operator rather than the glob() function. `X NOT GLOB Y` looks past
+// the NOT.
+fn follows_operand(tokens: &[Token], i: usize) -> bool {
+    let before = |i: usize| {
+        tokens[..i]
+            .iter()
+            .rposition(|t| !matches!(t, Token::Whitespace(_)))
+    };
+    let mut previous = before(i);
+    if let Some(not) = previous.filter(|p| is_keyword(&tokens[*p], Keyword::NOT)) {
+        previous = before(not);
+    }
+    match previous.map(|p| &tokens[p]) {
+        Some(Token::Word(w)) => {
+            w.quote_style.is_some()
+                || !matches!(
+                    w.keyword,
+                    Keyword::SELECT
+                        | Keyword::WHERE
+                        | Keyword::AND
+                        | Keyword::OR
+                        | Keyword::NOT
+                        | Keyword::ON
+                        | Keyword::CASE
+                        | Keyword::WHEN
+                        | Keyword::THEN
+                        | Keyword::ELSE
+                        | Keyword::BY
+                        | Keyword::HAVING
+                        | Keyword::DISTINCT
+                        | Keyword::ALL
+                        | Keyword::SET
+                        | Keyword::IS
+                        | Keyword::LIKE
+                        | Keyword::BETWEEN
+                        | Keyword::ESCAPE
+                )
+        }
+        Some(Token::RParen)
+        | Some(Token::Number(..))
+        | Some(Token::SingleQuotedString(_))
+        | Some(Token::NationalStringLiteral(_))
+        | Some(Token::HexStringLiteral(_)) => true,
+        _ => false,
+    }
+}
+
+// The end of the operand starting at `start`, as far as the parser reads
+// the right operand of LIKE: up to the first operator binding less tightly
+// than `+` or `||`. Also whether the operand has operators of its own.
+fn operand_end(tokens: &[Token], start: usize) -> (usize, bool) {
+    let mut i = start;
+    let mut end = start;
+    let mut compound = false;
+    loop {
+        i = skip_whitespace(tokens, i);
+        while matches!(
+            tokens.get(i),
+            Some(Token::Plus) | Some(Token::Minus) | Some(Token::Tilde)
+        ) {
+            i = skip_whitespace(tokens, i + 1);
+        }
+        match tokens.get(i) {
+            None => return (end, compound),
+            Some(Token::LParen) => {
+                i = closing_paren(tokens, i).map_or(tokens.len(), |close| close + 1)
+            }
+            Some(token) if is_keyword(token, Keyword::CASE) => i = case_end(tokens, i),
+            Some(Token::Word(_)) => {
+                i += 1;
+                // a qualified name, or a function call
+                loop {
+                    let next = skip_whitespace(tokens, i);
+                    match (tokens.get(next), tokens.get(next + 1)) {
+                        (Some(Token::Period), Some(Token::Word(_))) => i = next + 2,
+                        (Some(Token::LParen), _) => {
+                            i = closing_paren(tokens, next).map_or(tokens.len(), |close| close + 1)
+                        }
+                        _ => break,
+                    }
+                }
+            }
+            Some(_) => i += 1,
+        }
+        end = i;
+        let mut next = skip_whitespace(tokens, i);
+        if next < tokens.len() && is_keyword(&tokens[next], Keyword::COLLATE) {
+            end = skip_whitespace(tokens, next + 1) + 1;
+            next = skip_whitespace(tokens, end);
+        }
+        match tokens.get(next) {
+            Some(Token::Plus)
+            | Some(Token::Minus)
+            | Some(Token::Mult)
+            | Some(Token::Div)
+            | Some(Token::Mod)
+            | Some(Token::StringConcat)
+            | Some(Token::Pipe)
+            | Some(Token::Caret)
+            | Some(Token::Ampersand)
+            | Some(Token::ShiftLeft)
+            | Some(Token::ShiftRight) => {
+                compound = true;
+                i = next + 1;
+            }
+            _ => return (end.min(tokens.len()), compound),
+        }
+    }
+}
+
+// The position after the END closing the CASE at `case`.
+fn case_end(tokens: &[Token], case: usize) -> usize {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(case) {
+        if is_keyword(token, Keyword::CASE) {
+            depth += 1;
+        } else if is_keyword(token, Keyword::END) {
+            depth -= 1;
+            if depth == 0 {
+                return i + 1;
+            }
+        }
+    }
+    tokens.len()
+}
+
+// Puts the tokens from `start` to `end` in parentheses.
+fn group(tokens: &mut Vec<Token>, start: usize, end: usize) {
+    tokens.insert(end, Token::RParen);
+    tokens.insert(start, Token::LParen);
+}
+
+// Rewrites `EXPLAIN QUERY PLAN` into `EXPLAIN VERBOSE`.
+fn mark_query_plans(tokens: &mut Vec<Token>) {
+    let mut i = 0;
+    while i < tokens.len() {
+        if is_keyword(&tokens[i], Keyword::EXPLAIN) {
+            let query = skip_whitespace(tokens, i + 1);
+            let plan = skip_whitespace(tokens, query + 1);
+            if plan < tokens.len()
+                && is_word(&tokens[query], "QUERY")
+                && is_word(&tokens[plan], "PLAN")
+            {
+                tokens.splice(query..=plan, [Token::make_keyword("VERBOSE")]);
+            }
+        }
+        i += 1;
+    }
+}
+
+// Rewrites `ANALYZE [name]` at the start of a statement into `ANALYZE
+// TABLE name`.
+fn add_analyze_targets(tokens: &mut Vec<Token>) {
+    let mut statement_start = true;
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i] {
+            Token::Whitespace(_) => (),
+            Token::SemiColon => statement_start = true,
+            token => {
+                if statement_start && is_keyword(token, Keyword::ANALYZE) {
+                    let next = skip_whitespace(tokens, i + 1);
+                    if matches!(tokens.get(next), None | Some(Token::SemiColon)) {
+                        let main = Token::make_word("main", None);
+                        tokens.insert(i + 1, main);
+                        tokens.insert(i + 1, Token::Whitespace(Whitespace::Space));
+                    }
+                    tokens.insert(i + 1, Token::make_keyword("TABLE"));
+                    tokens.insert(i + 1, Token::Whitespace(Whitespace::Space));
+                }
+                statement_start = false;
+            }
+        }
+        i += 1;
+    }
+}
+
+// Replaces the parameters with markers and numbers them as SQLite does: a
+// bare `?` is one more than the largest number so far, and a name keeps the
+// number it got where it first appeared.
+fn mark_parameters(tokens: &mut Vec<Token>) -> Result<Vec<Option<String>>, ParserError> {
+    let mut parameters: Vec<Option<String>> = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let (written, length) = match (&tokens[i], tokens.get(i + 1)) {
+            (Token::Char('?'), Some(Token::Number(n, false))) => (format!("?{}", n), 2),
+            (Token::Char('?'), _) => ("?".to_string(), 1),
+            (Token::Colon, Some(Token::Word(w))) | (Token::AtSign, Some(Token::Word(w)))
+                if w.quote_style.is_none() =>
+            {
+                (format!("{}{}", tokens[i], w.value), 2)
+            }
+            (Token::Colon, Some(Token::Number(n, false)))
+            | (Token::AtSign, Some(Token::Number(n, false))) => (format!("{}{}", tokens[i], n), 2),
+            (Token::Word(w), _) if w.quote_style.is_none() && w.value.starts_with('$') => {
+                (w.value.clone(), 1)
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        let number = if written == "?" {
+            parameters.push(None);
+            parameters.len()
+        } else if let Some(digits) = written.strip_prefix('?') {
+            let number = digits
+                .parse()
+                .ok()
+                .filter(|n| (1..=MAX_PARAMETER).contains(n))
+                .ok_or_else(|| {
+                    ParserError::ParserError(format!(
+                        "variable number must be between ?1 and ?{}",
+                        MAX_PARAMETER
+                    ))
+                })?;
+            if parameters.len() < number {
+                parameters.resize(number, None);
+            }
+            parameters[number - 1] = Some(written.clone());
+            number
+        } else {
+            match parameters
+                .iter()
+                .position(|name| name.as_deref() == Some(written.as_str()))
+            {
+                Some(i) => i + 1,
+                None => {
+                    parameters.push(Some(written.clone()));
+                    parameters.len()
+                }
+            }
+        };
+        if parameters.len() > MAX_PARAMETER {
+            return Err(ParserError::ParserError("too many SQL variables".into()));
+        }
+        let marker = format!("{}{}\0{}", PARAMETER, number, written);
+        // sqlparser only takes a number after LIMIT and OFFSET
+        let previous = tokens[..i]
+            .iter()
+            .rev()
+            .find(|t| !matches!(t, Token::Whitespace(_)));
+        let marker = match previous {
+            Some(t) if is_keyword(t, Keyword::LIMIT) || is_keyword(t, Keyword::OFFSET) => {
+                Token::Number(marker, false)
+            }
+            _ => Token::SingleQuotedString(marker),
+        };
+        tokens.splice(i..i + length, [marker]);
+        i += 1;
+    }
+    Ok(parameters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::ast::{Query, SelectItem, SetExpr};
+
+    fn query(sql: &str) -> Box<Query> {
+        match parse_sql(sql).unwrap().remove(0) {
+            Statement::Query(query) => query,
+            _ => unreachable!(),
+        }
+    }
+
+    fn projection(query: &Query) -> Vec<String> {
+        match &query.body {
+            SetExpr::Select(select) => select
+                .projection
+                .iter()
+                .map(|item| item.to_string())
+                .collect(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_materialization_hints() {
+        let q = query(
+            "WITH a AS MATERIALIZED (SELECT 'it''s' AS \"x y\"), \
+             b AS NOT MATERIALIZED (SELECT (1)), c AS (SELECT 2) \
+             SELECT * FROM a, b, c",
+        );
+        let ctes = &q.with.as_ref().unwrap().cte_tables;
+        let hints: Vec<Option<bool>> = ctes.iter().map(materialization_hint).collect();
+        assert_eq!(hints, vec![Some(true), Some(false), None]);
+        assert_eq!(ctes[0].query.to_string(), "SELECT 'it''s' AS \"x y\"");
+        assert_eq!(ctes[1].query.to_string(), "SELECT (1)");
+
+        // a column may still be called materialized
+        let q = query("SELECT 1 AS materialized");
+        assert!(q.with.is_none());
+    }
+
+    #[test]
+    fn test_named_windows() {
+        let q = query(
+            "SELECT sum(x) OVER w, max(x) OVER (v ROWS 1 PRECEDING), \
+             (SELECT count(*) OVER w FROM u WINDOW w AS ()) FROM t \
+             WINDOW w AS (PARTITION BY y), v AS (w ORDER BY z) ORDER BY x",
+        );
+        assert_eq!(
+            projection(&q),
+            vec![
+                "sum(x) OVER (PARTITION BY y)",
+                "max(x) OVER (PARTITION BY y ORDER BY z ROWS 1 PRECEDING)",
+                "(SELECT count(*) OVER () FROM u)",
+            ]
+        );
+        assert_eq!(q.order_by.len(), 1);
+
+        let error = parse_sql("SELECT sum(x) OVER v FROM t WINDOW w AS ()").unwrap_err();
+        assert_eq!(error, ParserError::ParserError("no such window: v".into()));
+        assert!(parse_sql("SELECT sum(x) OVER (w) FROM t").is_err());
+    }
+
+    #[test]
+    fn test_frame_exclusions() {
+        let q = query(
+            "SELECT sum(x) OVER (PARTITION BY y ROWS 1 PRECEDING EXCLUDE CURRENT ROW), \
+             sum(x) OVER (ORDER BY y EXCLUDE TIES), sum(x) OVER (EXCLUDE NO OTHERS), \
+             sum(x) OVER w FROM t WINDOW w AS (GROUPS CURRENT ROW EXCLUDE GROUP)",
+        );
+        let windows: Vec<WindowSpec> = match &q.body {
+            SetExpr::Select(select) => select
+                .projection
+                .iter()
+                .map(|item| match item {
+                    SelectItem::UnnamedExpr(Expr::Function(f)) => f.over.clone().unwrap(),
+                    _ => unreachable!(),
+                })
+                .collect(),
+            _ => unreachable!(),
+        };
+        let exclusions: Vec<(FrameExclusion, usize)> = windows
+            .iter()
+            .map(|w| {
+                let (exclusion, partition_by) = frame_exclusion(w);
+                (exclusion, partition_by.len())
+            })
+            .collect();
+        assert_eq!(
+            exclusions,
+            vec![
+                (FrameExclusion::CurrentRow, 1),
+                (FrameExclusion::Ties, 0),
+                (FrameExclusion::NoOthers, 0),
+                (FrameExclusion::Group, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_arrows() {
+        let q = query("SELECT j -> '$.a' ->> 0, j->'b' || 'c' FROM t");
+        let items: Vec<Expr> = match &q.body {
+            SetExpr::Select(select) => select
+                .projection
+                .iter()
+                .map(|item| match item {
+                    SelectItem::UnnamedExpr(e) => e.clone(),
+                    _ => unreachable!(),
+                })
+                .collect(),
+            _ => unreachable!(),
+        };
+        let (op, left) = arrow(&items[0]).unwrap();
+        assert_eq!(op, "->>");
+        assert_eq!(arrow(left).unwrap().0, "->");
+        assert_eq!(display(&items[0]), "j -> '$.a' ->> 0");
+        assert!(arrow(&items[1]).is_none());
+        assert_eq!(display(&items[1]), "j -> 'b' || 'c'");
+    }
+
+    #[test]
+    fn test_pattern_operators() {
+        let q = query(
+            "SELECT x GLOB 'a*', x NOT GLOB y || '*' COLLATE nocase, \
+             x LIKE p ESCAPE '!', x NOT LIKE 'a' || p ESCAPE e || '', glob(x, y) FROM t",
+        );
+        let items: Vec<Expr> = match &q.body {
+            SetExpr::Select(select) => select
+                .projection
+                .iter()
+                .map(|item| match item {
+                    SelectItem::UnnamedExpr(e) => e.clone(),
+                    _ => unreachable!(),
+                })
+                .collect(),
+            _ => unreachable!(),
+        };
+        let right = |e: &Expr| match e {
+            Expr::BinaryOp { right, .. } => right.as_ref().clone(),
+            _ => unreachable!(),
+        };
+        let text = |e: &Expr| e.to_string();
+        let patterns: Vec<String> = items[..4]
+            .iter()
+            .map(|item| match pattern(&right(item)) {
+                Pattern::Glob(p) => format!("glob {}", text(p)),
+                Pattern::Like(p, None) => format!("like {}", text(p)),
+                Pattern::Like(p, Some(e)) => format!("like {} escape {}", text(p), text(e)),
+            })
+            .collect();
+        assert_eq!(
+            patterns,
+            vec![
+                "glob 'a*'",
+                "glob (y || '*' COLLATE nocase)",
+                "like p escape '!'",
+                "like ('a' || p) escape (e || '')",
+            ]
+        );
+        assert_eq!(display(&items[0]), "x GLOB 'a*'");
+        assert_eq!(display(&items[2]), "x LIKE p ESCAPE '!'");
+        assert_eq!(display(&items[4]), "glob(x, y)");
+    }
+
+    #[test]
+    fn test_parameters() {
+        let (statements, names) =
+            parse_sql_with_parameters("SELECT ? + ?3, :a, @b, $c || :a, ?").unwrap();
+        assert_eq!(
+            names,
+            vec![
+                None,
+                None,
+                Some("?3".to_string()),
+                Some(":a".to_string()),
+                Some("@b".to_string()),
+                Some("$c".to_string()),
+                None
+            ]
+        );
+        let q = match &statements[0] {
+            Statement::Query(query) => query,
+            _ => unreachable!(),
+        };
+        let items: Vec<String> = match &q.body {
+            SetExpr::Select(select) => select
+                .projection
+                .iter()
+                .map(|item| match item {
+                    SelectItem::UnnamedExpr(e) => display(e),
+                    _ => unreachable!(),
+                })
+                .collect(),
+            _ => unreachable!(),
+        };
+        assert_eq!(items, vec!["? + ?3", ":a", "@b", "$c || :a", "?"]);
+        let numbers: Vec<Option<usize>> = match &q.body {
+            SetExpr::Select(select) => select
+                .projection
+                .iter()
+                .map(|item| match item {
+                    SelectItem::UnnamedExpr(e) => parameter(e),
+                    _ => unreachable!(),
+                })
+                .collect(),
+            _ => unreachable!(),
+        };
+        assert_eq!(numbers, vec![None, Some(4), Some(5), None, Some(7)]);
+        assert!(parse_sql("SELECT ?0").is_err());
+    }
+
+    #[test]
+    fn test_query_plans() {
+        let statements = parse_sql("EXPLAIN QUERY PLAN SELECT 1; EXPLAIN SELECT 2").unwrap();
+        let described = query_plan(&statements[0]).unwrap();
+        assert_eq!(described.to_string(), "SELECT 1");
+        assert!(query_plan(&statements[1]).is_none());
+        assert!(query_plan(described).is_none());
+        assert!(explain(&statements[0]).is_none());
+        assert_eq!(explain(&statements[1]).unwrap().to_string(), "SELECT 2");
+    }
+
+    #[test]
+    fn test_analyze() {
+        let statements = parse_sql("ANALYZE; analyze main.Track; SELECT 1").unwrap();
+        let targets: Vec<String> = statements
+            .iter()
+            .filter_map(|statement| match statement {
+                Statement::Analyze { table_name, .. } => Some(table_name.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(targets, vec!["main", "main.Track"]);
+    }
 }
@@ -1,7 +1,10 @@
 use crate::util;
-use std::convert::TryInto;
+use std::cmp::Ordering;
+use std::convert::{TryFrom, TryInto};
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Null,
     Int8([u8; 1]),
@@ -14,17 +17,16 @@ pub enum Value {
     Zero,
     One,
     SQLiteString(String),
+    Blob(Vec<u8>),
 }
 
 impl Value {
     pub fn consume(serial_type: u64) -> usize {
         if serial_type >= 12 {
-            if serial_type % 2 == 0 {
+            if serial_type.is_multiple_of(2) {
                 return ((serial_type - 12) / 2) as usize;
             }
-            if serial_type % 2 == 1 {
-                return ((serial_type - 13) / 2) as usize;
-            }
+            return ((serial_type - 13) / 2) as usize;
         }
         match serial_type {
             0x00 => 0,
@@ -42,18 +44,15 @@ impl Value {
     }
     pub fn new(serial_type: u64, value: &[u8]) -> Value {
         if serial_type >= 12 {
-            if serial_type % 2 == 0 {
-                panic!("invalid serial_type: {:?}", serial_type);
-            }
-            if serial_type % 2 == 1 {
-                let length = ((serial_type - 13) / 2) as usize;
-                let s = std::str::from_utf8(&value[..length]);
-                if let Ok(ss) = s {
-                    return Value::SQLiteString(ss.to_string());
-                } else {
-                    panic!("correputed string bytes {:?}", &value[..length]);
-                }
+            if serial_type.is_multiple_of(2) {
+                let length = ((serial_type - 12) / 2) as usize;
+                return Value::Blob(value[..length].to_vec());
             }
+            let length = ((serial_type - 13) / 2) as usize;
+            // SQLite stores whatever bytes it was given as TEXT, so bytes
+            // that are not UTF-8 become replacement characters
+            let text = String::from_utf8_lossy(&value[..length]);
+            return Value::SQLiteString(text.into_owned());
         }
 
         match serial_type {
@@ -73,7 +72,16 @@ impl Value {
 
     #[allow(clippy::all)]
     pub fn as_i64(&self) -> Option<i64> {
-        let mut array: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
+        // stored integers are big-endian two's complement, so sign-extend
+        let negative = match self {
+            Value::Int8(value) => value[0] >= 0x80,
+            Value::Int16(value) => value[0] >= 0x80,
+            Value::Int24(value) => value[0] >= 0x80,
+            Value::Int32(value) => value[0] >= 0x80,
+            Value::Int48(value) => value[0] >= 0x80,
+            _ => false,
+        };
+        let mut array: [u8; 8] = if negative { [0xff; 8] } else { [0; 8] };
         match self {
             Value::Int8(value) => {
                 array[7] = value[0];
@@ -111,6 +119,10 @@ impl Value {
                 array[6] = value[6];
                 array[7] = value[7];
             }
+            Value::Zero => {}
+            Value::One => {
+                array[7] = 1;
+            }
             __ => return None,
         };
         return Some(i64::from_be_bytes(array));
@@ -122,26 +134,718 @@ impl Value {
             _ => None,
         }
     }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::SQLiteString(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
 }
 
-impl PartialEq for Value {
-    fn eq(&self, other: &Value) -> bool {
+// A value as SQL sees it, whatever encoding the record used to store it.
+#[derive(Debug, Clone)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl From<&Value> for SqlValue {
+    fn from(value: &Value) -> SqlValue {
+        match value {
+            Value::Null => SqlValue::Null,
+            Value::Float64(f) => SqlValue::Real(*f),
+            Value::SQLiteString(s) => SqlValue::Text(s.clone()),
+            Value::Blob(b) => SqlValue::Blob(b.clone()),
+            v => SqlValue::Integer(v.as_i64().unwrap()),
+        }
+    }
+}
+
+impl From<Value> for SqlValue {
+    fn from(value: Value) -> SqlValue {
+        match value {
+            Value::SQLiteString(s) => SqlValue::Text(s),
+            Value::Blob(b) => SqlValue::Blob(b),
+            v => SqlValue::from(&v),
+        }
+    }
+}
+
+impl SqlValue {
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            SqlValue::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            SqlValue::Real(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            SqlValue::Text(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_blob(&self) -> Option<&[u8]> {
+        match self {
+            SqlValue::Blob(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, SqlValue::Null)
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(self, SqlValue::Integer(_))
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, SqlValue::Integer(_) | SqlValue::Real(_))
+    }
+
+    pub fn from_bool(b: bool) -> SqlValue {
+        SqlValue::Integer(b as i64)
+    }
+
+    // The storage class name reported by typeof().
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            SqlValue::Null => "null",
+            SqlValue::Integer(_) => "integer",
+            SqlValue::Real(_) => "real",
+            SqlValue::Text(_) => "text",
+            SqlValue::Blob(_) => "blob",
+        }
+    }
+
+    // Renders the value the way SQLite converts it to TEXT.
+    pub fn to_text(&self) -> Option<String> {
+        match self {
+            SqlValue::Null => None,
+            SqlValue::Integer(i) => Some(i.to_string()),
+            SqlValue::Real(f) => Some(format_real(*f)),
+            SqlValue::Text(s) => Some(s.clone()),
+            SqlValue::Blob(b) => Some(String::from_utf8_lossy(b).into_owned()),
+        }
+    }
+
+    // The numeric value used by arithmetic: TEXT and BLOB values contribute
+    // their longest numeric prefix, or 0 if there is none.
+    pub fn to_numeric(&self) -> SqlValue {
+        match self {
+            SqlValue::Text(s) => parse_numeric_prefix(s),
+            SqlValue::Blob(b) => parse_numeric_prefix(&String::from_utf8_lossy(b)),
+            v => v.clone(),
+        }
+    }
+
+    pub fn to_f64(&self) -> Option<f64> {
+        match self.to_numeric() {
+            SqlValue::Integer(i) => Some(i as f64),
+            SqlValue::Real(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    // Converts the value as if it were stored in a column with the given
+    // affinity, see https://www.sqlite.org/datatype3.html#type_affinity
+    pub fn apply_affinity(self, affinity: Affinity) -> SqlValue {
+        match affinity {
+            Affinity::Blob => self,
+            Affinity::Text => {
+                if self.is_numeric() {
+                    SqlValue::Text(self.to_text().unwrap())
+                } else {
+                    self
+                }
+            }
+            Affinity::Numeric | Affinity::Integer => match &self {
+                SqlValue::Text(s) => parse_numeric_exact(s).unwrap_or(self),
+                SqlValue::Real(f) if affinity == Affinity::Integer => {
+                    if f.fract() == 0.0 && f.abs() < 9.2e18 {
+                        SqlValue::Integer(*f as i64)
+                    } else {
+                        self
+                    }
+                }
+                _ => self,
+            },
+            Affinity::Real => match &self {
+                SqlValue::Text(s) => match parse_numeric_exact(s) {
+                    Some(v) => SqlValue::Real(v.to_f64().unwrap()),
+                    None => self,
+                },
+                SqlValue::Integer(i) => SqlValue::Real(*i as f64),
+                _ => self,
+            },
+        }
+    }
+
+    // Orders two values the way SQLite orders keys in an index b-tree with the
+    // BINARY collation: NULL < INTEGER/REAL < TEXT < BLOB.
+    pub fn compare(&self, other: &SqlValue) -> Ordering {
+        fn class(v: &SqlValue) -> u8 {
+            match v {
+                SqlValue::Null => 0,
+                SqlValue::Integer(_) | SqlValue::Real(_) => 1,
+                SqlValue::Text(_) => 2,
+                SqlValue::Blob(_) => 3,
+            }
+        }
+
         match (self, other) {
-            (Value::Null, Value::Null) => true,
-            (Value::Int8(a), Value::Int8(b)) => a == b,
-            (Value::Int16(a), Value::Int16(b)) => a == b,
-            (Value::Int24(a), Value::Int24(b)) => a == b,
-            (Value::Int32(a), Value::Int32(b)) => a == b,
-            (Value::Int48(a), Value::Int48(b)) => a == b,
-            (Value::Int64(a), Value::Int64(b)) => a == b,
-            (Value::Float64(a), Value::Float64(b)) => a == b,
-            (Value::Zero, Value::Zero) => true,
-            (Value::One, Value::One) => true,
-            _ => false,
+            (SqlValue::Integer(a), SqlValue::Integer(b)) => a.cmp(b),
+            (SqlValue::Real(a), SqlValue::Real(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+            (SqlValue::Real(a), SqlValue::Integer(b)) => compare_f64_i64(*a, *b),
+            (SqlValue::Integer(a), SqlValue::Real(b)) => compare_f64_i64(*b, *a).reverse(),
+            (SqlValue::Text(a), SqlValue::Text(b)) => a.cmp(b),
+            (SqlValue::Blob(a), SqlValue::Blob(b)) => a.cmp(b),
+            (a, b) => class(a).cmp(&class(b)),
+        }
+    }
+}
+
+// Equality and ordering follow `compare`, so 1 and 1.0 are the same value,
+// as they are for GROUP BY, DISTINCT and index keys.
+impl PartialEq for SqlValue {
+    fn eq(&self, other: &SqlValue) -> bool {
+        self.compare(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SqlValue {}
+
+impl PartialOrd for SqlValue {
+    fn partial_cmp(&self, other: &SqlValue) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SqlValue {
+    fn cmp(&self, other: &SqlValue) -> Ordering {
+        self.compare(other)
+    }
+}
+
+impl Hash for SqlValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            SqlValue::Null => 0u8.hash(state),
+            SqlValue::Integer(i) => {
+                1u8.hash(state);
+                i.hash(state);
+            }
+            SqlValue::Real(f) => {
+                1u8.hash(state);
+                // reals equal to an integer must hash like that integer
+                if f.fract() == 0.0 && *f >= -9223372036854775808.0 && *f < 9223372036854775808.0 {
+                    (*f as i64).hash(state);
+                } else {
+                    f.to_bits().hash(state);
+                }
+            }
+            SqlValue::Text(s) => {
+                2u8.hash(state);
+                s.hash(state);
+            }
+            SqlValue::Blob(b) => {
+                3u8.hash(state);
+                b.hash(state);
+            }
         }
     }
 }
 
+macro_rules! from_integer {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for SqlValue {
+                fn from(i: $t) -> SqlValue {
+                    SqlValue::Integer(i as i64)
+                }
+            }
+        )*
+    };
+}
+
+from_integer!(i8, i16, i32, i64, u8, u16, u32);
+
+impl From<bool> for SqlValue {
+    fn from(b: bool) -> SqlValue {
+        SqlValue::from_bool(b)
+    }
+}
+
+impl From<f64> for SqlValue {
+    fn from(f: f64) -> SqlValue {
+        if f.is_nan() {
+            SqlValue::Null
+        } else {
+            SqlValue::Real(f)
+        }
+    }
+}
+
+impl From<f32> for SqlValue {
+    fn from(f: f32) -> SqlValue {
+        SqlValue::from(f as f64)
+    }
+}
+
+impl From<String> for SqlValue {
+    fn from(s: String) -> SqlValue {
+        SqlValue::Text(s)
+    }
+}
+
+impl From<&str> for SqlValue {
+    fn from(s: &str) -> SqlValue {
+        SqlValue::Text(s.to_string())
+    }
+}
+
+impl From<Vec<u8>> for SqlValue {
+    fn from(b: Vec<u8>) -> SqlValue {
+        SqlValue::Blob(b)
+    }
+}
+
+impl From<&[u8]> for SqlValue {
+    fn from(b: &[u8]) -> SqlValue {
+        SqlValue::Blob(b.to_vec())
+    }
+}
+
+impl<T: Into<SqlValue>> From<Option<T>> for SqlValue {
+    fn from(value: Option<T>) -> SqlValue {
+        match value {
+            Some(value) => value.into(),
+            None => SqlValue::Null,
+        }
+    }
+}
+
+fn conversion_error(value: &SqlValue, target: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("cannot convert {} value to {}", value.type_name(), target),
+    )
+}
+
+// Integers convert to any integer type they fit in. Like the column accessors
+// of the C API, no conversion between storage classes is attempted, except
+// that integers are accepted where a real is expected.
+macro_rules! try_from_value {
+    ($($t:ty),*) => {
+        $(
+            impl TryFrom<SqlValue> for $t {
+                type Error = Error;
+
+                fn try_from(value: SqlValue) -> Result<$t, Error> {
+                    match value {
+                        SqlValue::Integer(i) => <$t>::try_from(i).map_err(|_| {
+                            Error::new(
+                                ErrorKind::InvalidData,
+                                format!("integer {} out of range for {}", i, stringify!($t)),
+                            )
+                        }),
+                        v => Err(conversion_error(&v, stringify!($t))),
+                    }
+                }
+            }
+
+            impl TryFrom<SqlValue> for Option<$t> {
+                type Error = Error;
+
+                fn try_from(value: SqlValue) -> Result<Option<$t>, Error> {
+                    match value {
+                        SqlValue::Null => Ok(None),
+                        v => <$t>::try_from(v).map(Some),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+try_from_value!(i8, i16, i32, i64, u8, u16, u32, u64, usize);
+
+macro_rules! try_from_value_with {
+    ($t:ty, $convert:expr) => {
+        impl TryFrom<SqlValue> for $t {
+            type Error = Error;
+
+            fn try_from(value: SqlValue) -> Result<$t, Error> {
+                let convert: fn(SqlValue) -> Result<$t, SqlValue> = $convert;
+                convert(value).map_err(|v| conversion_error(&v, stringify!($t)))
+            }
+        }
+
+        impl TryFrom<SqlValue> for Option<$t> {
+            type Error = Error;
+
+            fn try_from(value: SqlValue) -> Result<Option<$t>, Error> {
+                match value {
+                    SqlValue::Null => Ok(None),
+                    v => <$t>::try_from(v).map(Some),
+                }
+            }
+        }
+    };
+}
+
+try_from_value_with!(f64, |v| match v {
+    SqlValue::Real(f) => Ok(f),
+    SqlValue::Integer(i) => Ok(i as f64),
+    v => Err(v),
+});
+try_from_value_with!(bool, |v| match v {
+    SqlValue::Integer(i) => Ok(i != 0),
+    v => Err(v),
+});
+try_from_value_with!(String, |v| match v {
+    SqlValue::Text(s) => Ok(s),
+    v => Err(v),
+});
+try_from_value_with!(Vec<u8>, |v| match v {
+    SqlValue::Blob(b) => Ok(b),
+    SqlValue::Text(s) => Ok(s.into_bytes()),
+    v => Err(v),
+});
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Affinity {
+    Text,
+    Numeric,
+    Integer,
+    Real,
+    Blob,
+}
+
+impl Affinity {
+    // Determines the affinity of a declared column type using the rules of
+    // https://www.sqlite.org/datatype3.html#determination_of_column_affinity
+    pub fn from_declared_type(declared_type: &str) -> Affinity {
+        let t = declared_type.to_ascii_uppercase();
+        if t.contains("INT") {
+            Affinity::Integer
+        } else if t.contains("CHAR") || t.contains("CLOB") || t.contains("TEXT") {
+            Affinity::Text
+        } else if t.contains("BLOB") || t.is_empty() {
+            Affinity::Blob
+        } else if t.contains("REAL") || t.contains("FLOA") || t.contains("DOUB") {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, Affinity::Numeric | Affinity::Integer | Affinity::Real)
+    }
+
+    // A declared type that has this affinity.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Affinity::Text => "TEXT",
+            Affinity::Numeric => "NUMERIC",
+            Affinity::Integer => "INTEGER",
+            Affinity::Real => "REAL",
+            Affinity::Blob => "",
+        }
+    }
+}
+
+// The decimal digits of a real, as SQLite's sqlite3FpDecode() finds them:
+// about 19 significant digits from double-double arithmetic, rounded half
+// up to `round` significant digits, or to -`round` digits after the decimal
+// point when `round` is not positive, but never more than `max_digits`.
+// Trailing zeros are dropped.
+pub struct DecimalDigits {
+    pub negative: bool,
+    pub digits: Vec<u8>,
+    // The position of the decimal point relative to the first digit.
+    pub point: i64,
+}
+
+impl DecimalDigits {
+    // the constants are SQLite's
+    #[allow(clippy::excessive_precision)]
+    pub fn new(value: f64, round: i64, max_digits: usize) -> DecimalDigits {
+        let negative = value < 0.0;
+        if value == 0.0 || !value.is_finite() {
+            return DecimalDigits {
+                negative,
+                digits: b"0".to_vec(),
+                point: 1,
+            };
+        }
+        // scale the value to 19 digits before the decimal point
+        let mut exponent = 0;
+        let mut r = (value.abs(), 0.0);
+        if r.0 > 9.223372036854774784e+18 {
+            while r.0 > 9.223372036854774784e+118 {
+                exponent += 100;
+                dekker_multiply(&mut r, 1.0e-100, -1.99918998026028836196e-117);
+            }
+            while r.0 > 9.223372036854774784e+28 {
+                exponent += 10;
+                dekker_multiply(&mut r, 1.0e-10, -3.6432197315497741579e-27);
+            }
+            while r.0 > 9.223372036854774784e+18 {
+                exponent += 1;
+                dekker_multiply(&mut r, 1.0e-01, -5.5511151231257827021e-18);
+            }
+        } else {
+            while r.0 < 9.223372036854774784e-83 {
+                exponent -= 100;
+                dekker_multiply(&mut r, 1.0e+100, -1.5902891109759918046e+83);
+            }
+            while r.0 < 9.223372036854774784e+07 {
+                exponent -= 10;
+                dekker_multiply(&mut r, 1.0e+10, 0.0);
+            }
+            while r.0 < 9.22337203685477478e+17 {
+                exponent -= 1;
+                dekker_multiply(&mut r, 1.0e+01, 0.0);
+            }
+        }
+        let v = if r.1 < 0.0 {
+            (r.0 as u64).wrapping_sub((-r.1) as u64)
+        } else {
+            (r.0 as u64).wrapping_add(r.1 as u64)
+        };
+        let mut digits = v.to_string().into_bytes();
+        let mut point = digits.len() as i64 + exponent;
+
+        let mut round = if round <= 0 { point - round } else { round };
+        if round == 0 && digits[0] >= b'5' {
+            digits.insert(0, b'0');
+            point += 1;
+            round = 1;
+        }
+        if round > 0 && (round < digits.len() as i64 || digits.len() > max_digits) {
+            let round = (round as usize).min(max_digits);
+            let carry = digits[round] >= b'5';
+            digits.truncate(round);
+            if carry {
+                let mut j = round;
+                loop {
+                    if j == 0 {
+                        digits.insert(0, b'1');
+                        point += 1;
+                        break;
+                    }
+                    j -= 1;
+                    if digits[j] == b'9' {
+                        digits[j] = b'0';
+                    } else {
+                        digits[j] += 1;
+                        break;
+                    }
+                }
+            }
+        }
+        while digits.len() > 1 && digits.last() == Some(&b'0') {
+            digits.pop();
+        }
+        DecimalDigits {
+            negative,
+            digits,
+            point,
+        }
+    }
+}
+
+// Multiplies the double-double `x` by `y + yy`.
+fn dekker_multiply(x: &mut (f64, f64), y: f64, yy: f64) {
+    let split = |f: f64| f64::from_bits(f.to_bits() & 0xffff_ffff_fc00_0000);
+    let hx = split(x.0);
+    let tx = x.0 - hx;
+    let hy = split(y);
+    let ty = y - hy;
+    let p = hx * hy;
+    let q = hx * ty + tx * hy;
+    let c = p + q;
+    let cc = p - c + q + tx * ty;
+    let cc = x.0 * yy + x.1 * y + cc;
+    x.0 = c + cc;
+    x.1 = c - x.0 + cc;
+}
+
+// Formats a real like SQLite's "%!.15g": 15 significant digits, always with
+// a decimal point, switching to exponent notation for large and small values.
+pub fn format_real(f: f64) -> String {
+    if f.is_nan() {
+        return "NaN".to_string();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "Inf" } else { "-Inf" }.to_string();
+    }
+    if f == 0.0 {
+        return "0.0".to_string();
+    }
+
+    let decoded = DecimalDigits::new(f, 15, 26);
+    let exponent = decoded.point - 1;
+    let digits = String::from_utf8(decoded.digits).unwrap();
+    let digits = digits.as_str();
+    let sign = if f < 0.0 { "-" } else { "" };
+
+    if !(-4..15).contains(&exponent) {
+        let fraction = if digits.len() > 1 { &digits[1..] } else { "0" };
+        let exponent_sign = if exponent < 0 { '-' } else { '+' };
+        return format!(
+            "{}{}.{}e{}{:02}",
+            sign,
+            &digits[..1],
+            fraction,
+            exponent_sign,
+            exponent.abs()
+        );
+    }
+    if exponent < 0 {
+        let zeros = "0".repeat((-exponent - 1) as usize);
+        return format!("{}0.{}{}", sign, zeros, digits);
+    }
+    let integer_length = exponent as usize + 1;
+    if digits.len() <= integer_length {
+        let zeros = "0".repeat(integer_length - digits.len());
+        format!("{}{}{}.0", sign, digits, zeros)
+    } else {
+        format!(
+            "{}{}.{}",
+            sign,
+            &digits[..integer_length],
+            &digits[integer_length..]
+        )
+    }
+}
+
+// Length of the longest prefix of `s` that looks like a number, and
+// whether that prefix is an integer.
+fn numeric_prefix_length(s: &str) -> (usize, bool) {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    if i < bytes.len() && (bytes[i] == b'+' || bytes[i] == b'-') {
+        i += 1;
+    }
+    let digits_start = i;
+    while i < bytes.len() && bytes[i].is_ascii_digit() {
+        i += 1;
+    }
+    let mut integer_digits = i - digits_start;
+    let mut is_integer = true;
+    if i < bytes.len() && bytes[i] == b'.' {
+        let mut j = i + 1;
+        while j < bytes.len() && bytes[j].is_ascii_digit() {
+            j += 1;
+        }
+        if integer_digits > 0 || j > i + 1 {
+            integer_digits += j - i - 1;
+            i = j;
+            is_integer = false;
+        }
+    }
+    if integer_digits == 0 {
+        return (0, true);
+    }
+    if i < bytes.len() && (bytes[i] == b'e' || bytes[i] == b'E') {
+        let mut j = i + 1;
+        if j < bytes.len() && (bytes[j] == b'+' || bytes[j] == b'-') {
+            j += 1;
+        }
+        let exponent_start = j;
+        while j < bytes.len() && bytes[j].is_ascii_digit() {
+            j += 1;
+        }
+        if j > exponent_start {
+            i = j;
+            is_integer = false;
+        }
+    }
+    (i, is_integer)
+}
+
+fn parse_number(s: &str, is_integer: bool) -> SqlValue {
+    if is_integer {
+        if let Ok(i) = s.parse::<i64>() {
+            return SqlValue::Integer(i);
+        }
+    }
+    SqlValue::Real(s.parse::<f64>().unwrap_or(0.0))
+}
+
+// Converts the longest numeric prefix of `s`, ignoring leading spaces, or
+// returns 0 if `s` does not start with a number.
+pub fn parse_numeric_prefix(s: &str) -> SqlValue {
+    let s = s.trim_start();
+    let (length, is_integer) = numeric_prefix_length(s);
+    if length == 0 {
+        return SqlValue::Integer(0);
+    }
+    parse_number(&s[..length], is_integer)
+}
+
+// Converts `s` only if the whole text, apart from surrounding spaces, is a
+// well-formed number. Reals with an exact integer value become integers.
+pub fn parse_numeric_exact(s: &str) -> Option<SqlValue> {
+    let s = s.trim();
+    let (length, is_integer) = numeric_prefix_length(s);
+    if length == 0 || length != s.len() {
+        return None;
+    }
+    match parse_number(s, is_integer) {
+        SqlValue::Real(f) if f.fract() == 0.0 && f.abs() < 9.2e18 => {
+            Some(SqlValue::Integer(f as i64))
+        }
+        v => Some(v),
+    }
+}
+
+// Compares a real with an integer without losing precision on large integers.
+fn compare_f64_i64(f: f64, i: i64) -> Ordering {
+    if f.is_nan() {
+        return Ordering::Less;
+    }
+    if f < -9223372036854775808.0 {
+        return Ordering::Less;
+    }
+    if f >= 9223372036854775808.0 {
+        return Ordering::Greater;
+    }
+    let truncated = f as i64;
+    match truncated.cmp(&i) {
+        Ordering::Equal => f.partial_cmp(&(truncated as f64)).unwrap(),
+        o => o,
+    }
+}
+
+// Storage classes are compared by the value they hold, so the different
+// integer encodings of 5 are equal.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        SqlValue::from(self) == SqlValue::from(other)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -151,4 +855,148 @@ mod tests {
         let value: [u8; 0] = [];
         assert_eq!(Value::new(0, &value), Value::Null);
     }
+
+    #[test]
+    fn test_blob() {
+        let value: [u8; 3] = [1, 2, 3];
+        match Value::new(18, &value) {
+            Value::Blob(b) => assert_eq!(b, vec![1, 2, 3]),
+            v => panic!("expected blob, got {:?}", v),
+        }
+        assert_eq!(Value::consume(18), 3);
+    }
+
+    #[test]
+    fn test_format_real() {
+        assert_eq!(format_real(0.1), "0.1");
+        assert_eq!(format_real(3.0), "3.0");
+        assert_eq!(format_real(-2.5), "-2.5");
+        assert_eq!(format_real(1.0 / 3.0), "0.333333333333333");
+        assert_eq!(format_real(1e20), "1.0e+20");
+        assert_eq!(format_real(1e15), "1.0e+15");
+        assert_eq!(format_real(1e-5), "1.0e-05");
+        assert_eq!(format_real(0.0001), "0.0001");
+        assert_eq!(format_real(123456789012345678.0), "1.23456789012346e+17");
+    }
+
+    #[test]
+    fn test_numeric_conversions() {
+        assert_eq!(parse_numeric_prefix(" 12abc").as_i64(), Some(12));
+        assert_eq!(parse_numeric_prefix("abc").as_i64(), Some(0));
+        assert_eq!(parse_numeric_prefix("3.5e2xyz").as_f64(), Some(350.0));
+        assert_eq!(parse_numeric_exact(" 12 ").unwrap().as_i64(), Some(12));
+        assert_eq!(parse_numeric_exact("1e3").unwrap().as_i64(), Some(1000));
+        assert!(parse_numeric_exact("12abc").is_none());
+        assert!(parse_numeric_exact(".").is_none());
+
+        let text = SqlValue::from("5");
+        assert_eq!(text.clone().apply_affinity(Affinity::Integer), 5.into());
+        assert!(text.apply_affinity(Affinity::Blob).as_str().is_some());
+        assert_eq!(
+            SqlValue::Integer(7).apply_affinity(Affinity::Text).as_str(),
+            Some("7")
+        );
+        assert!(SqlValue::Integer(7)
+            .apply_affinity(Affinity::Real)
+            .as_f64()
+            .is_some());
+    }
+
+    #[test]
+    fn test_affinity_from_declared_type() {
+        assert_eq!(Affinity::from_declared_type("INTEGER"), Affinity::Integer);
+        assert_eq!(
+            Affinity::from_declared_type("NVARCHAR(160)"),
+            Affinity::Text
+        );
+        assert_eq!(
+            Affinity::from_declared_type("NUMERIC(10,2)"),
+            Affinity::Numeric
+        );
+        assert_eq!(Affinity::from_declared_type("DOUBLE"), Affinity::Real);
+        assert_eq!(Affinity::from_declared_type(""), Affinity::Blob);
+        assert_eq!(Affinity::from_declared_type("DATETIME"), Affinity::Numeric);
+    }
+
+    #[test]
+    fn test_negative_integers() {
+        assert_eq!(Value::Int8([0xff]).as_i64(), Some(-1));
+        assert_eq!(Value::Int24([0xff, 0xff, 0xfe]).as_i64(), Some(-2));
+        assert_eq!(Value::Int16([0x7f, 0xff]).as_i64(), Some(32767));
+    }
+
+    #[test]
+    fn test_storage_classes_normalize() {
+        assert_eq!(Value::Int8([5]), Value::Int16([0, 5]));
+        assert_eq!(Value::Zero, Value::Int8([0]));
+        assert_eq!(
+            Value::SQLiteString("a".to_string()),
+            Value::SQLiteString("a".to_string())
+        );
+        assert_eq!(SqlValue::from(Value::One), SqlValue::Integer(1));
+        assert_eq!(
+            SqlValue::from(Value::Int48([0xff; 6])),
+            SqlValue::Integer(-1)
+        );
+        assert_eq!(SqlValue::from(Value::Float64(0.5)), SqlValue::Real(0.5));
+    }
+
+    #[test]
+    fn test_total_ordering() {
+        let mut values = vec![
+            SqlValue::Blob(vec![0]),
+            SqlValue::from("1"),
+            SqlValue::Real(5.5),
+            SqlValue::Integer(5),
+            SqlValue::Null,
+            SqlValue::Integer(i64::MAX),
+            SqlValue::Real(-0.5),
+        ];
+        values.sort();
+        assert_eq!(
+            values,
+            vec![
+                SqlValue::Null,
+                SqlValue::Real(-0.5),
+                SqlValue::Integer(5),
+                SqlValue::Real(5.5),
+                SqlValue::Integer(i64::MAX),
+                SqlValue::from("1"),
+                SqlValue::Blob(vec![0]),
+            ]
+        );
+        assert_eq!(SqlValue::Integer(1), SqlValue::Real(1.0));
+        assert!(SqlValue::Integer(i64::MAX) < SqlValue::Real(9223372036854775808.0));
+
+        let distinct: std::collections::HashSet<SqlValue> = vec![
+            SqlValue::Integer(1),
+            SqlValue::Real(1.0),
+            SqlValue::from("1"),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(distinct.len(), 2);
+    }
+
+    #[test]
+    fn test_try_from() {
+        assert_eq!(i64::try_from(SqlValue::Integer(-3)).unwrap(), -3);
+        assert!(u8::try_from(SqlValue::Integer(300)).is_err());
+        assert!(i64::try_from(SqlValue::Real(1.0)).is_err());
+        assert_eq!(f64::try_from(SqlValue::Integer(2)).unwrap(), 2.0);
+        assert!(bool::try_from(SqlValue::Integer(2)).unwrap());
+        assert_eq!(String::try_from(SqlValue::from("x")).unwrap(), "x");
+        assert!(String::try_from(SqlValue::Null).is_err());
+        assert_eq!(Option::<String>::try_from(SqlValue::Null).unwrap(), None);
+        assert_eq!(
+            Option::<i32>::try_from(SqlValue::Integer(4)).unwrap(),
+            Some(4)
+        );
+        assert_eq!(
+            Vec::<u8>::try_from(SqlValue::Blob(vec![1, 2])).unwrap(),
+            vec![1, 2]
+        );
+        assert_eq!(SqlValue::from(None::<i64>), SqlValue::Null);
+        assert_eq!(SqlValue::from(f64::NAN), SqlValue::Null);
+    }
 }
@@ -58,28 +58,28 @@ mod tests {
     use super::*;
     #[test]
     fn read_single_byte_varint() {
-        assert_eq!((1, 1), read_varint(&vec![0b00000001]));
-        assert_eq!((3, 1), read_varint(&vec![0b00000011]));
-        assert_eq!((7, 1), read_varint(&vec![0b00000111]));
-        assert_eq!((15, 1), read_varint(&vec![0b00001111]));
+        assert_eq!((1, 1), read_varint(&[0b00000001]));
+        assert_eq!((3, 1), read_varint(&[0b00000011]));
+        assert_eq!((7, 1), read_varint(&[0b00000111]));
+        assert_eq!((15, 1), read_varint(&[0b00001111]));
     }
 
     #[test]
     fn read_two_byte_varint() {
-        assert_eq!((128, 2), read_varint(&vec![0b10000001, 0b00000000]));
-        assert_eq!((129, 2), read_varint(&vec![0b10000001, 0b00000001]));
-        assert_eq!((255, 2), read_varint(&vec![0b10000001, 0b01111111]));
+        assert_eq!((128, 2), read_varint(&[0b10000001, 0b00000000]));
+        assert_eq!((129, 2), read_varint(&[0b10000001, 0b00000001]));
+        assert_eq!((255, 2), read_varint(&[0b10000001, 0b01111111]));
     }
 
     #[test]
     fn read_nine_byte_varint() {
-        assert_eq!((-1, 9), read_varint(&vec![0xff; 9]));
+        assert_eq!((-1, 9), read_varint(&[0xff; 9]));
     }
 
     #[test]
     fn read_varint_in_longer_bytes() {
-        assert_eq!((1, 1), read_varint(&vec![0x01; 10]));
-        assert_eq!((-1, 9), read_varint(&vec![0xff; 10]));
+        assert_eq!((1, 1), read_varint(&[0x01; 10]));
+        assert_eq!((-1, 9), read_varint(&[0xff; 10]));
     }
 
     #[test]
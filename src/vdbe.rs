@@ -0,0 +1,1233 @@
+use crate::aggregate;
+use crate::aggregate::Accumulator;
+use crate::executor::{Rows, TableRow};
+use crate::expr;
+use crate::expr::{Collation, Functions, GroupKey, Operand};
+use crate::sorter::{SortKey, SortedRows, Sorter};
+use crate::sql_parser;
+use crate::value::{format_real, Affinity, SqlValue};
+use crate::window;
+use sqlparser::ast;
+use sqlparser::ast::{BinaryOperator, Expr, FunctionArg, Ident, UnaryOperator};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Error;
+use std::rc::Rc;
+
+// Statements are compiled into programs for a register machine modelled on
+// SQLite's VDBE: cursors walk the rows of the tables of a join in nested
+// loops of Rewind or Seek and Next, registers hold the values expressions
+// compute, and ResultRow hands a row to the caller, which resumes the
+// program for the next one. As in SQLite, jumps go to an address in P2,
+// and registers are numbered from 1.
+//
+// Unlike SQLite's, comparisons store their result in a register rather
+// than jump, and a seek hands the keys in its registers to the executor,
+// which finds the rows within them the way the query plan says. A table
+// found through an index is read by a cursor on the index entries, and for
+// the columns the entries lack, by one on the table that DeferredSeek
+// points at the row of the entry. Expressions without opcodes of their
+// own, such as CASE or subqueries, are left to the tree-walking evaluator
+// by Evaluate. A sorter keeps the rows the cursors were on with each row it
+// sorts, and SorterData puts them back, so that the groups of GROUP BY read
+// their columns as the loops over the tables do.
+//
+// Every SELECT is compiled but those with window functions, which need all
+// their rows before they produce the first one: the executor runs them by
+// walking their syntax tree, and EXPLAIN reports them as not compiled.
+// EXPLAIN QUERY PLAN describes both kinds.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    // Starts the program by jumping to P2.
+    Init,
+    Goto,
+    Halt,
+    // Opens cursor P1 on the b-tree whose root page is P2, named in P4.
+    OpenRead,
+    // Opens cursor P1 on rows no b-tree holds, those of the subquery, common
+    // table or table-valued function named in P4.
+    OpenRows,
+    // Positions cursor P1 on the first of its rows, and jumps to P2 if there
+    // are none: all rows for Rewind, and for SeekRowid and SeekGE the rows
+    // within the keys of the P4 registers from P3, as the plan uses them.
+    // VFilter gives the P4 registers from P3 to a table-valued function as
+    // its arguments.
+    Rewind,
+    SeekRowid,
+    SeekGE,
+    VFilter,
+    // Moves cursor P1 to its next row and jumps to P2, unless there is none.
+    Next,
+    // Points table cursor P3 at the row the entry of index cursor P1 names.
+    // The row is only looked up once a column of it is read.
+    DeferredSeek,
+    // Puts cursor P1 on a row of NULLs, for a LEFT JOIN without a match.
+    NullRow,
+    // Opens cursor P1 on a temporary set of rows of P2 columns, which P4
+    // says the collations of. Found jumps to P2 if the P4 registers from P3
+    // are a row of the set, and IdxInsert adds the P3 registers from P2.
+    OpenEphemeral,
+    Found,
+    IdxInsert,
+    // Opens cursor P1 on a sorter whose rows start with P2 keys, which P4
+    // says the order of. SorterInsert adds a row of the P3 registers from P2
+    // followed by the rows cursors 0 to P5 are on. SorterSort sorts the rows
+    // and jumps to P2 if there are none, and SorterNext moves to the next
+    // one and jumps to P2 unless there is none. SorterData reads the P3
+    // registers from P2 back from the current row, and puts cursors 0 to P5
+    // back on their rows.
+    SorterOpen,
+    SorterInsert,
+    SorterSort,
+    SorterData,
+    SorterNext,
+    // Keeps the rows the cursors are on in slot P1, or puts them back there.
+    // The rows of a group for its bare columns are kept this way.
+    SaveRows,
+    RestoreRows,
+    // Adds the number of rows of the table of cursor P1 to aggregate P2.
+    Count,
+    // Reads column P2 of the row of cursor P1 into register P3. P4 holds
+    // the column's affinity and collation.
+    Column,
+    // Reads the rowid of the row of cursor P1 into register P2, which
+    // IdxRowid takes from an index entry.
+    Rowid,
+    IdxRowid,
+    // Makes the integer in register P1 a real, for a REAL column an index
+    // stores as an integer.
+    RealAffinity,
+    // Loads a constant into register P2: the integer P1, or the value P4.
+    Integer,
+    Int64,
+    Real,
+    String8,
+    Blob,
+    Null,
+    // Loads parameter ?P1 into register P2.
+    Variable,
+    Copy,
+    // Registers P3 = P1 op P2, NULL if either is NULL for comparisons and
+    // arithmetic, with SQLite's three-valued logic for And and Or.
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Concat,
+    // Register P2 = NOT P1, P1 IS NULL and P1 IS NOT NULL.
+    Not,
+    IsNull,
+    NotNull,
+    // Jumps to P2 if register P1 is true, or false for IfNot. A NULL jumps
+    // if P3 is not 0.
+    If,
+    IfNot,
+    // If register P1 is positive, subtracts P3 from it and jumps to P2.
+    IfPos,
+    // Subtracts 1 from register P1 and jumps to P2 if that makes it 0.
+    DecrJumpZero,
+    // Compares the P3 registers from P1 with those from P2, with the
+    // collations of P4, NULLs being equal. Jump then goes to P1, P2 or P3
+    // as the first were less, equal or greater.
+    Compare,
+    Jump,
+    // Stores the address of the next instruction in register P1 and jumps
+    // to P2, where a subroutine starts that Return P1 ends.
+    Gosub,
+    Return,
+    // Register P3 = the scalar function P4 of the P5 registers from P2.
+    Function,
+    // Register P3 = the expression P4, evaluated by the tree-walking
+    // evaluator against the current rows of the cursors.
+    Evaluate,
+    // Steps aggregate P3 with the P5 registers from P2 as its arguments.
+    // Register P1, unless 0, is set to whether min() or max() took the value
+    // of the row.
+    AggStep,
+    // Register P3 = the result of aggregate P1.
+    AggFinal,
+    // Starts aggregate P1 over, for the next group.
+    AggReset,
+    // Produces a result row of the P2 registers from P1.
+    ResultRow,
+}
+
+#[derive(Debug, Clone)]
+pub enum P4 {
+    None,
+    Int64(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+    Column(Affinity, Option<Collation>),
+    Function(ast::Function),
+    Expr(Expr),
+    Name(String),
+    // The collations of the columns of a temporary set or a comparison.
+    KeyInfo(Rc<[Collation]>),
+    // The order of the keys of a sorter.
+    SortKeys(Rc<[SortKey]>),
+}
+
+impl fmt::Display for P4 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            P4::None => Ok(()),
+            P4::Int64(i) => write!(f, "{}", i),
+            P4::Real(r) => f.write_str(&format_real(*r)),
+            P4::Text(s) | P4::Name(s) => f.write_str(s),
+            P4::Blob(b) => {
+                f.write_str("x'")?;
+                for byte in b {
+                    write!(f, "{:02X}", byte)?;
+                }
+                f.write_str("'")
+            }
+            P4::Column(_, collation) => match collation {
+                Some(collation) => write!(f, "({})", collation.name()),
+                None => Ok(()),
+            },
+            P4::Function(function) => {
+                let args = if aggregate::is_count_star(function) {
+                    0
+                } else {
+                    function.args.len()
+                };
+                write!(f, "{}({})", function.name, args)
+            }
+            P4::Expr(expr) => f.write_str(&sql_parser::display(expr)),
+            // as SQLite writes them, B being BINARY and - descending
+            P4::KeyInfo(collations) => {
+                write!(f, "k({}", collations.len())?;
+                for collation in collations.iter() {
+                    write_collation(f, "", collation)?;
+                }
+                f.write_str(")")
+            }
+            P4::SortKeys(keys) => {
+                write!(f, "k({}", keys.len())?;
+                for key in keys.iter() {
+                    let order = if key.descending { "-" } else { "" };
+                    write_collation(f, order, &key.collation)?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+fn write_collation(f: &mut fmt::Formatter, order: &str, collation: &Collation) -> fmt::Result {
+    match collation {
+        Collation::Binary => write!(f, ",{}B", order),
+        collation => write!(f, ",{}{}", order, collation.name()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Op {
+    pub opcode: Opcode,
+    pub p1: i32,
+    pub p2: i32,
+    pub p3: i32,
+    pub p4: P4,
+    pub p5: u16,
+    pub comment: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub ops: Vec<Op>,
+    registers: usize,
+    cursors: usize,
+    // The aggregates as they start, before their first row.
+    accumulators: Vec<Accumulator>,
+}
+
+impl Program {
+    pub fn columns() -> Vec<String> {
+        ["addr", "opcode", "p1", "p2", "p3", "p4", "p5", "comment"]
+            .iter()
+            .map(|c| c.to_string())
+            .collect()
+    }
+
+    // The rows of EXPLAIN, one per instruction.
+    pub fn rows(&self) -> Vec<Vec<SqlValue>> {
+        self.ops
+            .iter()
+            .enumerate()
+            .map(|(addr, op)| {
+                let p4 = op.p4.to_string();
+                vec![
+                    SqlValue::Integer(addr as i64),
+                    SqlValue::Text(format!("{:?}", op.opcode)),
+                    SqlValue::Integer(op.p1 as i64),
+                    SqlValue::Integer(op.p2 as i64),
+                    SqlValue::Integer(op.p3 as i64),
+                    if p4.is_empty() {
+                        SqlValue::Null
+                    } else {
+                        SqlValue::Text(p4)
+                    },
+                    SqlValue::Integer(op.p5 as i64),
+                    if op.comment.is_empty() {
+                        SqlValue::Null
+                    } else {
+                        SqlValue::Text(op.comment.clone())
+                    },
+                ]
+            })
+            .collect()
+    }
+}
+
+// A column an expression names, as the compiler resolved it.
+pub struct ColumnRef {
+    pub cursor: usize,
+    // None for the rowid.
+    pub column: Option<usize>,
+    pub affinity: Affinity,
+    pub collation: Option<Collation>,
+    pub name: String,
+    // Whether the cursor is on index entries, which hold the rowid as a
+    // column, and REAL columns as integers when their value is whole.
+    pub index: bool,
+    pub real: bool,
+}
+
+// What the names in the expressions of a statement stand for.
+pub trait Names {
+    // The column a name refers to. None leaves the name to Evaluate, which
+    // finds it in an enclosing query, or reports it as unknown.
+    fn column(&self, name: &[Ident]) -> Option<ColumnRef>;
+
+    // The register holding the result of an aggregate function, once the
+    // rows are all seen.
+    fn aggregate(&self, function: &ast::Function) -> Option<usize>;
+
+    fn functions(&self) -> &Functions;
+}
+
+// Builds a program one instruction at a time. Forward jumps are emitted
+// with a P2 of 0 and pointed at their target once it is known.
+#[derive(Default)]
+pub struct Builder {
+    ops: Vec<Op>,
+    registers: usize,
+    cursors: usize,
+    accumulators: Vec<Accumulator>,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        // register 0 stays unused, so that an operand of 0 names none
+        Builder {
+            registers: 1,
+            ..Builder::default()
+        }
+    }
+
+    // Appends an instruction and returns its address.
+    pub fn emit(&mut self, opcode: Opcode, p1: usize, p2: usize, p3: usize) -> usize {
+        self.ops.push(Op {
+            opcode,
+            p1: p1 as i32,
+            p2: p2 as i32,
+            p3: p3 as i32,
+            p4: P4::None,
+            p5: 0,
+            comment: String::new(),
+        });
+        self.ops.len() - 1
+    }
+
+    // The instruction at an address, to set its P4, P5 or comment.
+    pub fn op(&mut self, addr: usize) -> &mut Op {
+        &mut self.ops[addr]
+    }
+
+    // The address of the next instruction.
+    pub fn address(&self) -> usize {
+        self.ops.len()
+    }
+
+    // Points the jump at `addr` to the next instruction.
+    pub fn jump_here(&mut self, addr: usize) {
+        self.ops[addr].p2 = self.ops.len() as i32;
+    }
+
+    // Allocates `n` consecutive registers and returns the first.
+    pub fn registers(&mut self, n: usize) -> usize {
+        self.registers += n;
+        self.registers - n
+    }
+
+    pub fn register(&mut self) -> usize {
+        self.registers(1)
+    }
+
+    pub fn cursor(&mut self) -> usize {
+        self.cursors += 1;
+        self.cursors - 1
+    }
+
+    // Adds an aggregate and returns its number.
+    pub fn accumulator(&mut self, accumulator: Accumulator) -> usize {
+        self.accumulators.push(accumulator);
+        self.accumulators.len() - 1
+    }
+
+    pub fn integer(&mut self, value: i64, target: usize) {
+        match i32::try_from(value) {
+            Ok(i) => {
+                let addr = self.emit(Opcode::Integer, 0, target, 0);
+                self.ops[addr].p1 = i;
+            }
+            Err(_) => {
+                let addr = self.emit(Opcode::Int64, 0, target, 0);
+                self.ops[addr].p4 = P4::Int64(value);
+            }
+        }
+    }
+
+    // Emits the instructions computing `expr` into register `target`.
+    pub fn expression(&mut self, expr: &Expr, names: &dyn Names, target: usize) {
+        match expr {
+            Expr::Identifier(ident) if ident.quote_style.is_some() || !is_current(&ident.value) => {
+                self.column(expr, std::slice::from_ref(ident), names, target)
+            }
+            Expr::CompoundIdentifier(idents) => self.column(expr, idents, names, target),
+            Expr::Nested(expr) => self.expression(expr, names, target),
+            Expr::Value(literal) => match sql_parser::parameter(expr) {
+                Some(number) => {
+                    self.emit(Opcode::Variable, number, target, 0);
+                }
+                None => match expr::literal_value(literal) {
+                    Ok(value) => self.constant(value, target),
+                    Err(_) => self.evaluate(expr, target),
+                },
+            },
+            Expr::IsNull(operand) | Expr::IsNotNull(operand) => {
+                let r = self.register();
+                self.expression(operand, names, r);
+                let opcode = match expr {
+                    Expr::IsNull(_) => Opcode::IsNull,
+                    _ => Opcode::NotNull,
+                };
+                self.emit(opcode, r, target, 0);
+            }
+            Expr::UnaryOp {
+                op: UnaryOperator::Not,
+                expr: operand,
+            } => {
+                let r = self.register();
+                self.expression(operand, names, r);
+                self.emit(Opcode::Not, r, target, 0);
+            }
+            Expr::BinaryOp { left, op, right } if sql_parser::arrow(expr).is_none() => {
+                let opcode = match op {
+                    BinaryOperator::And => Opcode::And,
+                    BinaryOperator::Or => Opcode::Or,
+                    BinaryOperator::Eq => Opcode::Eq,
+                    BinaryOperator::NotEq => Opcode::Ne,
+                    BinaryOperator::Lt => Opcode::Lt,
+                    BinaryOperator::LtEq => Opcode::Le,
+                    BinaryOperator::Gt => Opcode::Gt,
+                    BinaryOperator::GtEq => Opcode::Ge,
+                    BinaryOperator::Plus => Opcode::Add,
+                    BinaryOperator::Minus => Opcode::Subtract,
+                    BinaryOperator::Multiply => Opcode::Multiply,
+                    BinaryOperator::Divide => Opcode::Divide,
+                    BinaryOperator::Modulus => Opcode::Remainder,
+                    BinaryOperator::StringConcat => Opcode::Concat,
+                    _ => return self.evaluate(expr, target),
+                };
+                let l = self.registers(2);
+                self.expression(left, names, l);
+                // AND and OR only look at their right side when the left
+                // one does not decide the result
+                let skip = match opcode {
+                    Opcode::And => {
+                        self.integer(0, target);
+                        Some(self.emit(Opcode::IfNot, l, 0, 0))
+                    }
+                    Opcode::Or => {
+                        self.integer(1, target);
+                        Some(self.emit(Opcode::If, l, 0, 0))
+                    }
+                    _ => None,
+                };
+                self.expression(right, names, l + 1);
+                self.emit(opcode, l, l + 1, target);
+                if let Some(skip) = skip {
+                    self.jump_here(skip);
+                }
+            }
+            Expr::Function(function) => self.function(expr, function, names, target),
+            _ => self.evaluate(expr, target),
+        }
+    }
+
+    fn column(&mut self, expr: &Expr, name: &[Ident], names: &dyn Names, target: usize) {
+        let column = match names.column(name) {
+            Some(column) => column,
+            None => return self.evaluate(expr, target),
+        };
+        let addr = match column.column {
+            Some(i) => {
+                let addr = self.emit(Opcode::Column, column.cursor, i, target);
+                self.ops[addr].p4 = P4::Column(column.affinity, column.collation);
+                addr
+            }
+            None if column.index => self.emit(Opcode::IdxRowid, column.cursor, target, 0),
+            None => self.emit(Opcode::Rowid, column.cursor, target, 0),
+        };
+        self.ops[addr].comment = column.name;
+        if column.real {
+            self.emit(Opcode::RealAffinity, target, 0, 0);
+        }
+    }
+
+    fn function(
+        &mut self,
+        expr: &Expr,
+        function: &ast::Function,
+        names: &dyn Names,
+        target: usize,
+    ) {
+        if function.over.is_some()
+            || window::is_window_function(function)
+            || names.functions().is_aggregate(function)
+        {
+            match names.aggregate(function) {
+                Some(r) => {
+                    self.emit(Opcode::Copy, r, target, 0);
+                }
+                // which is an error, reported when it is evaluated
+                None => self.evaluate(expr, target),
+            }
+            return;
+        }
+        let args = self.arguments(function, names);
+        let addr = self.emit(Opcode::Function, 0, args, target);
+        self.ops[addr].p4 = P4::Function(function.clone());
+        self.ops[addr].p5 = function.args.len() as u16;
+    }
+
+    // Computes the arguments of a function call into consecutive registers
+    // and returns the first.
+    pub fn arguments(&mut self, function: &ast::Function, names: &dyn Names) -> usize {
+        let first = self.registers(function.args.len());
+        for (i, arg) in function.args.iter().enumerate() {
+            match arg {
+                FunctionArg::Unnamed(arg) | FunctionArg::Named { arg, .. } => {
+                    self.expression(arg, names, first + i)
+                }
+            }
+        }
+        first
+    }
+
+    fn constant(&mut self, value: SqlValue, target: usize) {
+        match value {
+            SqlValue::Null => {
+                self.emit(Opcode::Null, 0, target, 0);
+            }
+            SqlValue::Integer(i) => self.integer(i, target),
+            SqlValue::Real(r) => {
+                let addr = self.emit(Opcode::Real, 0, target, 0);
+                self.ops[addr].p4 = P4::Real(r);
+            }
+            SqlValue::Text(s) => {
+                let addr = self.emit(Opcode::String8, 0, target, 0);
+                self.ops[addr].p4 = P4::Text(s);
+            }
+            SqlValue::Blob(b) => {
+                let addr = self.emit(Opcode::Blob, 0, target, 0);
+                self.ops[addr].p4 = P4::Blob(b);
+            }
+        }
+    }
+
+    fn evaluate(&mut self, expr: &Expr, target: usize) {
+        let addr = self.emit(Opcode::Evaluate, 0, 0, target);
+        self.ops[addr].p4 = P4::Expr(expr.clone());
+    }
+
+    pub fn finish(self) -> Program {
+        Program {
+            ops: self.ops,
+            registers: self.registers,
+            cursors: self.cursors,
+            accumulators: self.accumulators,
+        }
+    }
+}
+
+// Whether a bare name is CURRENT_TIME, CURRENT_DATE or CURRENT_TIMESTAMP,
+// which are values rather than columns.
+fn is_current(name: &str) -> bool {
+    ["current_time", "current_date", "current_timestamp"]
+        .iter()
+        .any(|n| n.eq_ignore_ascii_case(name))
+}
+
+// What a running program needs from the executor that compiled it.
+pub trait Environment<'a> {
+    // The rows of the table of a cursor, found the way the plan says within
+    // the keys of a seek, or those a table-valued function produces for its
+    // arguments. Rewind gives no keys, and reads all the rows.
+    fn open(&self, cursor: usize, keys: Option<&[Operand]>) -> Result<Rows<'a>, Error>;
+
+    // The row of the table of a cursor that an index entry names.
+    fn seek(&self, cursor: usize, entry: &TableRow) -> Result<TableRow, Error>;
+
+    // The number of rows of the table of a cursor.
+    fn count(&self, cursor: usize) -> Result<i64, Error>;
+
+    // Evaluates an expression against the rows the cursors are on, and the
+    // results of the aggregates.
+    fn evaluate(
+        &self,
+        expr: &Expr,
+        rows: &[Option<TableRow>],
+        aggregates: &[SqlValue],
+    ) -> Result<Operand, Error>;
+
+    fn parameter(&self, number: usize) -> SqlValue;
+
+    fn functions(&self) -> &Functions;
+
+    // The memory a sorter may use before it writes rows to disk.
+    fn sort_budget(&self) -> usize;
+}
+
+// The state of a program being run.
+pub struct Vm<'a> {
+    pc: usize,
+    registers: Vec<Operand>,
+    cursors: Vec<Option<Rows<'a>>>,
+    // The row each cursor is on.
+    rows: Vec<Option<TableRow>>,
+    // For the table cursors DeferredSeek pointed at the row of an index
+    // entry, the index cursor, until the row is looked up.
+    deferred: Vec<Option<usize>>,
+    accumulators: Vec<Accumulator>,
+    // The results of the aggregates, once AggFinal has computed them.
+    aggregates: Vec<SqlValue>,
+    // The rows of the temporary sets by cursor.
+    sets: Vec<Option<RowSet>>,
+    sorters: Vec<Option<SorterCursor>>,
+    // The rows SaveRows kept, by slot.
+    saved: Vec<Option<SavedRows>>,
+    // The result of the last Compare.
+    compare: Ordering,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(program: &Program) -> Vm<'a> {
+        Vm {
+            pc: 0,
+            registers: vec![Operand::new(SqlValue::Null); program.registers],
+            cursors: (0..program.cursors).map(|_| None).collect(),
+            rows: vec![None; program.cursors],
+            deferred: vec![None; program.cursors],
+            accumulators: program.accumulators.clone(),
+            aggregates: vec![SqlValue::Null; program.accumulators.len()],
+            sets: vec![None; program.cursors],
+            sorters: (0..program.cursors).map(|_| None).collect(),
+            saved: Vec::new(),
+            compare: Ordering::Equal,
+        }
+    }
+
+    // Runs the program until it produces a row, or returns None once it
+    // halts.
+    pub fn step(
+        &mut self,
+        program: &Program,
+        env: &dyn Environment<'a>,
+    ) -> Result<Option<Vec<SqlValue>>, Error> {
+        loop {
+            let op = &program.ops[self.pc];
+            let (p1, p2, p3) = (op.p1 as usize, op.p2 as usize, op.p3 as usize);
+            self.pc += 1;
+            match op.opcode {
+                Opcode::Init | Opcode::Goto => self.pc = p2,
+                Opcode::Halt => {
+                    // stays here, so that it keeps returning None
+                    self.pc -= 1;
+                    return Ok(None);
+                }
+                Opcode::OpenRead | Opcode::OpenRows => {
+                    self.cursors[p1] = None;
+                    self.rows[p1] = None;
+                    self.deferred[p1] = None;
+                }
+                Opcode::OpenEphemeral => {
+                    let collations = match &op.p4 {
+                        P4::KeyInfo(collations) => collations.clone(),
+                        _ => unreachable!(),
+                    };
+                    self.sets[p1] = Some((collations, BTreeSet::new()));
+                }
+                Opcode::Found => {
+                    let n = match op.p4 {
+                        P4::Int64(n) => n as usize,
+                        _ => unreachable!(),
+                    };
+                    let (collations, set) = self.sets[p1].as_ref().unwrap();
+                    if set.contains(&self.key(p3, n, collations)) {
+                        self.pc = p2;
+                    }
+                }
+                Opcode::IdxInsert => {
+                    let (collations, _) = self.sets[p1].as_ref().unwrap();
+                    let key = self.key(p2, p3, collations);
+                    self.sets[p1].as_mut().unwrap().1.insert(key);
+                }
+                Opcode::Rewind | Opcode::SeekRowid | Opcode::SeekGE | Opcode::VFilter => {
+                    let keys = match op.p4 {
+                        P4::Int64(n) => Some(&self.registers[p3..p3 + n as usize]),
+                        _ => None,
+                    };
+                    let mut rows = env.open(p1, keys)?;
+                    self.rows[p1] = rows.next().transpose()?;
+                    match self.rows[p1] {
+                        Some(_) => self.cursors[p1] = Some(rows),
+                        None => {
+                            self.cursors[p1] = None;
+                            self.pc = p2;
+                        }
+                    }
+                }
+                Opcode::Next => {
+                    let next = match &mut self.cursors[p1] {
+                        Some(rows) => rows.next().transpose()?,
+                        None => None,
+                    };
+                    match next {
+                        Some(row) => {
+                            self.rows[p1] = Some(row);
+                            self.pc = p2;
+                        }
+                        None => {
+                            self.rows[p1] = None;
+                            self.cursors[p1] = None;
+                        }
+                    }
+                }
+                Opcode::DeferredSeek => {
+                    self.rows[p3] = None;
+                    self.deferred[p3] = Some(p1);
+                }
+                Opcode::NullRow => {
+                    self.rows[p1] = None;
+                    self.deferred[p1] = None;
+                }
+                Opcode::SorterOpen => {
+                    let keys = match &op.p4 {
+                        P4::SortKeys(keys) => keys.to_vec(),
+                        _ => unreachable!(),
+                    };
+                    let sorter = Sorter::new(keys, env.sort_budget());
+                    self.sorters[p1] = Some(SorterCursor::Filling(sorter));
+                }
+                Opcode::SorterInsert => {
+                    let mut row: Vec<SqlValue> = self.registers[p2..p2 + p3]
+                        .iter()
+                        .map(|r| r.value.clone())
+                        .collect();
+                    for cursor in 0..op.p5 as usize {
+                        self.write_row(cursor, &mut row);
+                    }
+                    match &mut self.sorters[p1] {
+                        Some(SorterCursor::Filling(sorter)) => sorter.push(row)?,
+                        _ => unreachable!(),
+                    }
+                }
+                Opcode::SorterSort => {
+                    let mut rows = match self.sorters[p1].take() {
+                        Some(SorterCursor::Filling(sorter)) => sorter.finish()?,
+                        _ => unreachable!(),
+                    };
+                    match rows.next().transpose()? {
+                        Some(row) => self.sorters[p1] = Some(SorterCursor::Sorted(rows, row)),
+                        None => self.pc = p2,
+                    }
+                }
+                Opcode::SorterNext => {
+                    if let Some(SorterCursor::Sorted(rows, current)) = &mut self.sorters[p1] {
+                        match rows.next().transpose()? {
+                            Some(row) => {
+                                *current = row;
+                                self.pc = p2;
+                            }
+                            None => self.sorters[p1] = None,
+                        }
+                    }
+                }
+                Opcode::SorterData => {
+                    let row = match &self.sorters[p1] {
+                        Some(SorterCursor::Sorted(_, row)) => row.clone(),
+                        _ => unreachable!(),
+                    };
+                    for (i, value) in row[..p3].iter().enumerate() {
+                        self.registers[p2 + i] = Operand::new(value.clone());
+                    }
+                    self.read_rows(op.p5 as usize, &row[p3..]);
+                }
+                Opcode::SaveRows => {
+                    if self.saved.len() <= p1 {
+                        self.saved.resize(p1 + 1, None);
+                    }
+                    self.saved[p1] = Some((self.rows.clone(), self.deferred.clone()));
+                }
+                Opcode::RestoreRows => match self.saved.get(p1).cloned().flatten() {
+                    Some((rows, deferred)) => {
+                        self.rows = rows;
+                        self.deferred = deferred;
+                    }
+                    None => {
+                        self.rows.iter_mut().for_each(|row| *row = None);
+                        self.deferred.iter_mut().for_each(|index| *index = None);
+                    }
+                },
+                Opcode::Count => self.accumulators[p2].add_rows(env.count(p1)?),
+                Opcode::Column => {
+                    self.seek(p1, env)?;
+                    let value = match &self.rows[p1] {
+                        Some(row) => row.values[p2].clone(),
+                        None => SqlValue::Null,
+                    };
+                    self.registers[p3] = match &op.p4 {
+                        P4::Column(affinity, collation) => {
+                            Operand::column(value, *affinity, collation.clone())
+                        }
+                        _ => Operand::new(value),
+                    };
+                }
+                Opcode::Rowid | Opcode::IdxRowid => {
+                    if op.opcode == Opcode::Rowid {
+                        self.seek(p1, env)?;
+                    }
+                    let row_id = self.rows[p1].as_ref().and_then(|row| row.row_id);
+                    let value = row_id.map(SqlValue::Integer).unwrap_or(SqlValue::Null);
+                    self.registers[p2] = Operand::column(value, Affinity::Integer, None);
+                }
+                Opcode::RealAffinity => {
+                    if let SqlValue::Integer(i) = self.registers[p1].value {
+                        self.registers[p1].value = SqlValue::Real(i as f64);
+                    }
+                }
+                Opcode::Integer => {
+                    self.registers[p2] = Operand::new(SqlValue::Integer(op.p1 as i64))
+                }
+                Opcode::Int64 | Opcode::Real | Opcode::String8 | Opcode::Blob => {
+                    let value = match &op.p4 {
+                        P4::Int64(i) => SqlValue::Integer(*i),
+                        P4::Real(r) => SqlValue::Real(*r),
+                        P4::Text(s) => SqlValue::Text(s.clone()),
+                        P4::Blob(b) => SqlValue::Blob(b.clone()),
+                        _ => SqlValue::Null,
+                    };
+                    self.registers[p2] = Operand::new(value);
+                }
+                Opcode::Null => self.registers[p2] = Operand::new(SqlValue::Null),
+                Opcode::Variable => self.registers[p2] = Operand::new(env.parameter(p1)),
+                Opcode::Copy => self.registers[p2] = self.registers[p1].clone(),
+                Opcode::Eq | Opcode::Ne | Opcode::Lt | Opcode::Le | Opcode::Gt | Opcode::Ge => {
+                    let order = expr::compare(&self.registers[p1], &self.registers[p2]);
+                    let result = order.map(|o| match op.opcode {
+                        Opcode::Eq => o == Ordering::Equal,
+                        Opcode::Ne => o != Ordering::Equal,
+                        Opcode::Lt => o == Ordering::Less,
+                        Opcode::Le => o != Ordering::Greater,
+                        Opcode::Gt => o == Ordering::Greater,
+                        _ => o != Ordering::Less,
+                    });
+                    self.registers[p3] = Operand::new(from_truth(result));
+                }
+                Opcode::And | Opcode::Or => {
+                    let l = expr::truth(&self.registers[p1].value);
+                    let r = expr::truth(&self.registers[p2].value);
+                    let result = match (op.opcode, l, r) {
+                        (Opcode::And, Some(false), _) | (Opcode::And, _, Some(false)) => {
+                            Some(false)
+                        }
+                        (Opcode::Or, Some(true), _) | (Opcode::Or, _, Some(true)) => Some(true),
+                        (_, Some(l), Some(_)) => Some(l),
+                        _ => None,
+                    };
+                    self.registers[p3] = Operand::new(from_truth(result));
+                }
+                Opcode::Add
+                | Opcode::Subtract
+                | Opcode::Multiply
+                | Opcode::Divide
+                | Opcode::Remainder => {
+                    let operator = match op.opcode {
+                        Opcode::Add => BinaryOperator::Plus,
+                        Opcode::Subtract => BinaryOperator::Minus,
+                        Opcode::Multiply => BinaryOperator::Multiply,
+                        Opcode::Divide => BinaryOperator::Divide,
+                        _ => BinaryOperator::Modulus,
+                    };
+                    let value = expr::arithmetic(
+                        &operator,
+                        &self.registers[p1].value,
+                        &self.registers[p2].value,
+                    );
+                    self.registers[p3] = Operand::new(value);
+                }
+                Opcode::Concat => {
+                    let l = self.registers[p1].value.to_text();
+                    let r = self.registers[p2].value.to_text();
+                    let value = match (l, r) {
+                        (Some(l), Some(r)) => SqlValue::Text(l + &r),
+                        _ => SqlValue::Null,
+                    };
+                    self.registers[p3] = Operand::new(value);
+                }
+                Opcode::Not => {
+                    let value = expr::truth(&self.registers[p1].value).map(|b| !b);
+                    self.registers[p2] = Operand::new(from_truth(value));
+                }
+                Opcode::IsNull | Opcode::NotNull => {
+                    let null = self.registers[p1].value.is_null();
+                    let value = SqlValue::from_bool(null == (op.opcode == Opcode::IsNull));
+                    self.registers[p2] = Operand::new(value);
+                }
+                Opcode::If | Opcode::IfNot => {
+                    let jump = match expr::truth(&self.registers[p1].value) {
+                        Some(b) => b == (op.opcode == Opcode::If),
+                        None => p3 != 0,
+                    };
+                    if jump {
+                        self.pc = p2;
+                    }
+                }
+                Opcode::IfPos => {
+                    if let SqlValue::Integer(i) = &mut self.registers[p1].value {
+                        if *i > 0 {
+                            *i -= p3 as i64;
+                            self.pc = p2;
+                        }
+                    }
+                }
+                Opcode::DecrJumpZero => {
+                    if let SqlValue::Integer(i) = &mut self.registers[p1].value {
+                        *i -= 1;
+                        if *i == 0 {
+                            self.pc = p2;
+                        }
+                    }
+                }
+                Opcode::Compare => {
+                    let collations = match &op.p4 {
+                        P4::KeyInfo(collations) => collations,
+                        _ => unreachable!(),
+                    };
+                    let a = self.key(p1, p3, collations);
+                    self.compare = a.cmp(&self.key(p2, p3, collations));
+                }
+                Opcode::Jump => {
+                    self.pc = match self.compare {
+                        Ordering::Less => p1,
+                        Ordering::Equal => p2,
+                        Ordering::Greater => p3,
+                    }
+                }
+                Opcode::Gosub => {
+                    self.registers[p1] = Operand::new(SqlValue::Integer(self.pc as i64));
+                    self.pc = p2;
+                }
+                Opcode::Return => {
+                    if let SqlValue::Integer(addr) = self.registers[p1].value {
+                        self.pc = addr as usize;
+                    }
+                }
+                Opcode::Function => {
+                    let function = match &op.p4 {
+                        P4::Function(function) => function,
+                        _ => unreachable!(),
+                    };
+                    let args = self.registers[p2..p2 + op.p5 as usize].to_vec();
+                    self.registers[p3] = expr::call_scalar(function, args, Some(env.functions()))?;
+                }
+                Opcode::Evaluate => {
+                    let expr = match &op.p4 {
+                        P4::Expr(expr) => expr,
+                        _ => unreachable!(),
+                    };
+                    for cursor in 0..self.rows.len() {
+                        self.seek(cursor, env)?;
+                    }
+                    self.registers[p3] = env.evaluate(expr, &self.rows, &self.aggregates)?;
+                }
+                Opcode::AggStep => {
+                    let function = match &op.p4 {
+                        P4::Function(function) => function,
+                        _ => unreachable!(),
+                    };
+                    let args = self.registers[p2..p2 + op.p5 as usize].to_vec();
+                    let args = aggregate::argument_values(function, args);
+                    let took_value = self.accumulators[p3].step(&args);
+                    if p1 != 0 {
+                        self.registers[p1] = Operand::new(SqlValue::from_bool(took_value));
+                    }
+                }
+                Opcode::AggFinal => {
+                    let value = self.accumulators[p1].finish()?;
+                    let json = match &op.p4 {
+                        P4::Function(function) => aggregate::is_json(function),
+                        _ => false,
+                    };
+                    self.aggregates[p1] = value.clone();
+                    self.registers[p3] = Operand {
+                        json: json && matches!(value, SqlValue::Text(_)),
+                        ..Operand::new(value)
+                    };
+                }
+                Opcode::AggReset => self.accumulators[p1] = program.accumulators[p1].clone(),
+                Opcode::ResultRow => {
+                    let row = self.registers[p1..p1 + p2]
+                        .iter()
+                        .map(|r| r.value.clone())
+                        .collect();
+                    return Ok(Some(row));
+                }
+            }
+        }
+    }
+}
+
+// A temporary set of rows, with the collations of its columns.
+type RowSet = (Rc<[Collation]>, BTreeSet<GroupKey>);
+
+// The rows of the cursors and their deferred seeks, as SaveRows keeps them.
+type SavedRows = (Vec<Option<TableRow>>, Vec<Option<usize>>);
+
+enum SorterCursor {
+    Filling(Sorter),
+    // The sorted rows after the current one, and the current one.
+    Sorted(SortedRows, Vec<SqlValue>),
+}
+
+impl<'a> Vm<'a> {
+    // The `n` registers from `first` as a row of a temporary set.
+    fn key(&self, first: usize, n: usize, collations: &Rc<[Collation]>) -> GroupKey {
+        let values = self.registers[first..first + n]
+            .iter()
+            .map(|r| r.value.clone())
+            .collect();
+        GroupKey::new(values, collations.clone())
+    }
+
+    // Looks up the row a deferred seek points the cursor at.
+    fn seek(&mut self, cursor: usize, env: &dyn Environment<'a>) -> Result<(), Error> {
+        if let Some(index) = self.deferred[cursor].take() {
+            self.rows[cursor] = match &self.rows[index] {
+                Some(entry) => Some(env.seek(cursor, entry)?),
+                None => None,
+            };
+        }
+        Ok(())
+    }
+
+    // Adds the row of a cursor to a row of a sorter: NULL for no row, -1
+    // and the index cursor for a deferred seek, and otherwise the number of
+    // columns, the rowid and the columns.
+    fn write_row(&self, cursor: usize, values: &mut Vec<SqlValue>) {
+        if let Some(index) = self.deferred[cursor] {
+            values.push(SqlValue::Integer(-1));
+            values.push(SqlValue::Integer(index as i64));
+            return;
+        }
+        match &self.rows[cursor] {
+            Some(row) => {
+                values.push(SqlValue::Integer(row.values.len() as i64));
+                values.push(row.row_id.map_or(SqlValue::Null, SqlValue::Integer));
+                values.extend(row.values.iter().cloned());
+            }
+            None => values.push(SqlValue::Null),
+        }
+    }
+
+    // Puts the first `cursors` cursors back on the rows `write_row` wrote.
+    fn read_rows(&mut self, cursors: usize, mut values: &[SqlValue]) {
+        for cursor in 0..cursors {
+            let (row, deferred, rest) = match values {
+                [SqlValue::Integer(-1), SqlValue::Integer(index), rest @ ..] => {
+                    (None, Some(*index as usize), rest)
+                }
+                [SqlValue::Integer(n), row_id, rest @ ..] => {
+                    let (columns, rest) = rest.split_at(*n as usize);
+                    let row = TableRow {
+                        row_id: row_id.as_i64(),
+                        values: columns.to_vec(),
+                    };
+                    (Some(row), None, rest)
+                }
+                [_, rest @ ..] => (None, None, rest),
+                [] => unreachable!(),
+            };
+            self.rows[cursor] = row;
+            self.deferred[cursor] = deferred;
+            values = rest;
+        }
+    }
+}
+
+fn from_truth(truth: Option<bool>) -> SqlValue {
+    match truth {
+        Some(b) => SqlValue::from_bool(b),
+        None => SqlValue::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::EmptyScope;
+
+    struct NoNames(Functions);
+
+    impl Names for NoNames {
+        fn column(&self, _name: &[Ident]) -> Option<ColumnRef> {
+            None
+        }
+
+        fn aggregate(&self, _function: &ast::Function) -> Option<usize> {
+            None
+        }
+
+        fn functions(&self) -> &Functions {
+            &self.0
+        }
+    }
+
+    struct NoTables(Functions);
+
+    impl<'a> Environment<'a> for NoTables {
+        fn open(&self, _cursor: usize, _keys: Option<&[Operand]>) -> Result<Rows<'a>, Error> {
+            unreachable!()
+        }
+
+        fn seek(&self, _cursor: usize, _entry: &TableRow) -> Result<TableRow, Error> {
+            unreachable!()
+        }
+
+        fn count(&self, _cursor: usize) -> Result<i64, Error> {
+            unreachable!()
+        }
+
+        fn evaluate(
+            &self,
+            expr: &Expr,
+            _rows: &[Option<TableRow>],
+            _aggregates: &[SqlValue],
+        ) -> Result<Operand, Error> {
+            expr::evaluate_operand(expr, &EmptyScope)
+        }
+
+        fn parameter(&self, _number: usize) -> SqlValue {
+            SqlValue::Null
+        }
+
+        fn functions(&self) -> &Functions {
+            &self.0
+        }
+
+        fn sort_budget(&self) -> usize {
+            unreachable!()
+        }
+    }
+
+    fn parse(sql: &str) -> Expr {
+        match &sql_parser::parse_sql(&format!("SELECT {}", sql)).unwrap()[0] {
+            sql_parser::Statement::Query(query) => match &query.body {
+                ast::SetExpr::Select(select) => match &select.projection[0] {
+                    ast::SelectItem::UnnamedExpr(expr) => expr.clone(),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_expressions_match_evaluator() {
+        for sql in &[
+            "1 + 2 * 3",
+            "7 / 2",
+            "7 % 0",
+            "9223372036854775807 + 1",
+            "'a' || 1 || NULL",
+            "1 < 2",
+            "'10' = 10",
+            "NULL = NULL",
+            "NULL AND 0",
+            "0 AND NULL",
+            "NULL AND 1",
+            "NULL OR 1",
+            "1 OR NULL",
+            "NULL OR 0",
+            "NOT NULL",
+            "NOT 0.5",
+            "NULL IS NULL",
+            "1 IS NOT NULL",
+            "length('abc') + abs(-2)",
+            "json_array(1, 'a')",
+            "CASE WHEN 1 THEN 'x' END",
+            "x'00FF'",
+            "2.5",
+        ] {
+            let expr = parse(sql);
+            let expected = expr::evaluate(&expr, &EmptyScope).unwrap();
+
+            let names = NoNames(Functions::new());
+            let mut b = Builder::new();
+            let r = b.register();
+            b.expression(&expr, &names, r);
+            b.emit(Opcode::ResultRow, r, 1, 0);
+            b.emit(Opcode::Halt, 0, 0, 0);
+            let program = b.finish();
+            let environment = NoTables(Functions::new());
+            let mut vm = Vm::new(&program);
+            let row = vm.step(&program, &environment).unwrap().unwrap();
+            assert_eq!(row, vec![expected], "{}", sql);
+            assert!(vm.step(&program, &environment).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        // the right side of AND is not evaluated once the left one is false,
+        // so the unknown function is never called
+        let expr = parse("0 AND nosuchfunction()");
+        let names = NoNames(Functions::new());
+        let mut b = Builder::new();
+        let r = b.register();
+        b.expression(&expr, &names, r);
+        b.emit(Opcode::ResultRow, r, 1, 0);
+        let program = b.finish();
+        let row = Vm::new(&program)
+            .step(&program, &NoTables(Functions::new()))
+            .unwrap();
+        assert_eq!(row, Some(vec![SqlValue::Integer(0)]));
+    }
+}
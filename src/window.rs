@@ -0,0 +1,427 @@
+use crate::aggregate::Accumulator;
+use crate::expr::{Collation, Functions};
+use crate::sorter::{compare_rows, SortKey};
+use crate::sql_parser::{frame_exclusion, FrameExclusion};
+use crate::value::{Affinity, SqlValue};
+use sqlparser::ast;
+use sqlparser::ast::{WindowFrameBound, WindowFrameUnits};
+use std::cmp::Ordering;
+use std::io::{Error, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    RowNumber,
+    Rank,
+    DenseRank,
+    PercentRank,
+    CumeDist,
+    Ntile,
+    Lag,
+    Lead,
+    FirstValue,
+    LastValue,
+    NthValue,
+}
+
+fn kind_of(function: &ast::Function) -> Option<Kind> {
+    let name = function.name.to_string().to_ascii_lowercase();
+    Some(match name.as_str() {
+        "row_number" => Kind::RowNumber,
+        "rank" => Kind::Rank,
+        "dense_rank" => Kind::DenseRank,
+        "percent_rank" => Kind::PercentRank,
+        "cume_dist" => Kind::CumeDist,
+        "ntile" => Kind::Ntile,
+        "lag" => Kind::Lag,
+        "lead" => Kind::Lead,
+        "first_value" => Kind::FirstValue,
+        "last_value" => Kind::LastValue,
+        "nth_value" => Kind::NthValue,
+        _ => return None,
+    })
+}
+
+// Whether the function only exists as a window function, as opposed to the
+// aggregates that also work with OVER.
+pub fn is_window_function(function: &ast::Function) -> bool {
+    kind_of(function).is_some()
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+// Checks a call with OVER before any rows are looked at.
+pub fn validate(function: &ast::Function, functions: &Functions) -> Result<(), Error> {
+    let args = function.args.len();
+    let valid = match kind_of(function) {
+        Some(Kind::RowNumber)
+        | Some(Kind::Rank)
+        | Some(Kind::DenseRank)
+        | Some(Kind::PercentRank)
+        | Some(Kind::CumeDist) => args == 0,
+        Some(Kind::Ntile) | Some(Kind::FirstValue) | Some(Kind::LastValue) => args == 1,
+        Some(Kind::Lag) | Some(Kind::Lead) => (1..=3).contains(&args),
+        Some(Kind::NthValue) => args == 2,
+        // aggregates check their arguments themselves
+        None => {
+            Accumulator::new(function, Collation::Binary, functions).map_err(|_| {
+                invalid(format!(
+                    "{}() may not be used as a window function",
+                    function.name
+                ))
+            })?;
+            true
+        }
+    };
+    if !valid {
+        return Err(invalid(format!(
+            "wrong number of arguments to function {}()",
+            function.name
+        )));
+    }
+    if function.distinct {
+        return Err(invalid(
+            "DISTINCT is not supported for window functions".to_string(),
+        ));
+    }
+    let window = function.over.as_ref().unwrap();
+    if let Some(frame) = &window.window_frame {
+        let end = frame
+            .end_bound
+            .as_ref()
+            .unwrap_or(&WindowFrameBound::CurrentRow);
+        let unsupported = matches!(
+            (&frame.start_bound, end),
+            (WindowFrameBound::Following(None), _)
+                | (_, WindowFrameBound::Preceding(None))
+                | (WindowFrameBound::CurrentRow, WindowFrameBound::Preceding(_))
+                | (
+                    WindowFrameBound::Following(_),
+                    WindowFrameBound::Preceding(_)
+                )
+                | (WindowFrameBound::Following(_), WindowFrameBound::CurrentRow)
+        );
+        if unsupported {
+            return Err(invalid("unsupported frame specification".to_string()));
+        }
+        let has_offset = |bound: &WindowFrameBound| {
+            matches!(
+                bound,
+                WindowFrameBound::Preceding(Some(_)) | WindowFrameBound::Following(Some(_))
+            )
+        };
+        if frame.units == WindowFrameUnits::Range
+            && (has_offset(&frame.start_bound) || has_offset(end))
+            && window.order_by.len() != 1
+        {
+            return Err(invalid(
+                "RANGE with offset PRECEDING/FOLLOWING requires one ORDER BY expression"
+                    .to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// A row of a partition: the values of the window's ORDER BY terms and of
+// the function's arguments.
+pub struct WindowRow {
+    pub order: Vec<SqlValue>,
+    pub args: Vec<SqlValue>,
+}
+
+// The rows of a partition in window order, split into groups of peers,
+// which have equal ORDER BY values.
+struct Partition<'p> {
+    rows: &'p [WindowRow],
+    keys: &'p [SortKey],
+    // The group of peers of each row.
+    group: Vec<usize>,
+    // Where each group starts, followed by the number of rows.
+    starts: Vec<usize>,
+}
+
+impl<'p> Partition<'p> {
+    fn new(rows: &'p [WindowRow], keys: &'p [SortKey]) -> Partition<'p> {
+        let mut group = Vec::with_capacity(rows.len());
+        let mut starts = Vec::new();
+        for (i, row) in rows.iter().enumerate() {
+            if i == 0 || compare_rows(keys, &rows[i - 1].order, &row.order) != Ordering::Equal {
+                starts.push(i);
+            }
+            group.push(starts.len() - 1);
+        }
+        starts.push(rows.len());
+        Partition {
+            rows,
+            keys,
+            group,
+            starts,
+        }
+    }
+
+    fn groups(&self) -> usize {
+        self.starts.len() - 1
+    }
+
+    // The first row of the frame of row `i` for a start bound, or the row
+    // after its last one for an end bound.
+    fn bound(
+        &self,
+        units: &WindowFrameUnits,
+        bound: &WindowFrameBound,
+        i: usize,
+        end: bool,
+    ) -> usize {
+        let n = self.rows.len();
+        let g = self.group[i];
+        let group_bound = |g: usize| {
+            if g >= self.groups() {
+                n
+            } else if end {
+                self.starts[g + 1]
+            } else {
+                self.starts[g]
+            }
+        };
+        match (units, bound) {
+            (_, WindowFrameBound::Preceding(None)) => 0,
+            (_, WindowFrameBound::Following(None)) => n,
+            (WindowFrameUnits::Rows, WindowFrameBound::CurrentRow) => i + end as usize,
+            (WindowFrameUnits::Rows, WindowFrameBound::Preceding(Some(k))) => {
+                (i + end as usize).saturating_sub(*k as usize)
+            }
+            (WindowFrameUnits::Rows, WindowFrameBound::Following(Some(k))) => {
+                (i + end as usize).saturating_add(*k as usize).min(n)
+            }
+            (_, WindowFrameBound::CurrentRow) => group_bound(g),
+            (WindowFrameUnits::Groups, WindowFrameBound::Preceding(Some(k))) => {
+                match g.checked_sub(*k as usize) {
+                    Some(g) => group_bound(g),
+                    None => 0,
+                }
+            }
+            (WindowFrameUnits::Groups, WindowFrameBound::Following(Some(k))) => {
+                group_bound(g.saturating_add(*k as usize))
+            }
+            (WindowFrameUnits::Range, WindowFrameBound::Preceding(Some(k))) => {
+                self.range_bound(i, -(*k as f64), end)
+            }
+            (WindowFrameUnits::Range, WindowFrameBound::Following(Some(k))) => {
+                self.range_bound(i, *k as f64, end)
+            }
+        }
+    }
+
+    // The value of the single ORDER BY term of a RANGE frame with offsets,
+    // negated for a descending order so that the rows ascend.
+    fn range_value(&self, i: usize) -> Option<f64> {
+        let value = match &self.rows[i].order[0] {
+            SqlValue::Integer(v) => *v as f64,
+            SqlValue::Real(v) => *v,
+            _ => return None,
+        };
+        Some(if self.keys[0].descending {
+            -value
+        } else {
+            value
+        })
+    }
+
+    // A bound `offset` away from the ORDER BY value of row `i`. Rows whose
+    // value is NULL, or not a number, only have their peers in range.
+    fn range_bound(&self, i: usize, offset: f64, end: bool) -> usize {
+        let target = match self.range_value(i) {
+            Some(value) => value + offset,
+            None => {
+                let g = self.group[i];
+                return if end {
+                    self.starts[g + 1]
+                } else {
+                    self.starts[g]
+                };
+            }
+        };
+        // the rows with numbers are next to each other
+        let numbers: Vec<usize> = (0..self.rows.len())
+            .filter(|j| self.range_value(*j).is_some())
+            .collect();
+        let (first, last) = (numbers[0], numbers[numbers.len() - 1] + 1);
+        let values: Vec<f64> = (first..last)
+            .map(|j| self.range_value(j).unwrap())
+            .collect();
+        if end {
+            first + values.partition_point(|v| *v <= target)
+        } else {
+            first + values.partition_point(|v| *v < target)
+        }
+    }
+}
+
+fn positive_integer(value: &SqlValue) -> Option<i64> {
+    match value.clone().apply_affinity(Affinity::Integer) {
+        SqlValue::Integer(i) if i > 0 => Some(i),
+        _ => None,
+    }
+}
+
+// Evaluates a function with OVER for the rows of one partition, given in
+// the order of the window, and returns its value for each of them.
+// `collation` is that of the first argument, for min() and max().
+pub fn evaluate(
+    function: &ast::Function,
+    keys: &[SortKey],
+    collation: Collation,
+    functions: &Functions,
+    rows: &[WindowRow],
+) -> Result<Vec<SqlValue>, Error> {
+    let window = function.over.as_ref().unwrap();
+    let partition = &Partition::new(rows, keys);
+    let n = rows.len();
+    let mut values = Vec::with_capacity(n);
+
+    // without a frame, a row sees the rows up to its last peer
+    let (units, start, end) = match &window.window_frame {
+        Some(frame) => (
+            &frame.units,
+            &frame.start_bound,
+            frame
+                .end_bound
+                .as_ref()
+                .unwrap_or(&WindowFrameBound::CurrentRow),
+        ),
+        None => (
+            &WindowFrameUnits::Range,
+            &WindowFrameBound::Preceding(None),
+            &WindowFrameBound::CurrentRow,
+        ),
+    };
+    let (exclusion, _) = frame_exclusion(window);
+    let bounds = |i: usize| {
+        let first = partition.bound(units, start, i, false);
+        (first, partition.bound(units, end, i, true).max(first))
+    };
+    let frame = |i: usize| {
+        let (first, last) = bounds(i);
+        let g = partition.group[i];
+        (first..last).filter(move |j| match exclusion {
+            FrameExclusion::NoOthers => true,
+            FrameExclusion::CurrentRow => *j != i,
+            FrameExclusion::Group => partition.group[*j] != g,
+            FrameExclusion::Ties => *j == i || partition.group[*j] != g,
+        })
+    };
+
+    let kind = match kind_of(function) {
+        Some(kind) => kind,
+        None => {
+            let growing = *start == WindowFrameBound::Preceding(None);
+            let empty = Accumulator::new(function, collation, functions)?;
+            if exclusion != FrameExclusion::NoOthers || !(growing || empty.can_inverse()) {
+                for i in 0..n {
+                    let mut accumulator = empty.clone();
+                    for j in frame(i) {
+                        accumulator.step(&rows[j].args);
+                    }
+                    values.push(accumulator.finish()?);
+                }
+            } else {
+                sliding_aggregate(empty, rows, bounds, &mut values)?;
+            }
+            return Ok(values);
+        }
+    };
+    for i in 0..n {
+        let g = partition.group[i];
+        let args = &rows[i].args;
+        values.push(match kind {
+            Kind::RowNumber => SqlValue::Integer(i as i64 + 1),
+            Kind::Rank => SqlValue::Integer(partition.starts[g] as i64 + 1),
+            Kind::DenseRank => SqlValue::Integer(g as i64 + 1),
+            Kind::PercentRank if n > 1 => {
+                SqlValue::Real(partition.starts[g] as f64 / (n - 1) as f64)
+            }
+            Kind::PercentRank => SqlValue::Real(0.0),
+            Kind::CumeDist => SqlValue::Real(partition.starts[g + 1] as f64 / n as f64),
+            Kind::Ntile => {
+                let buckets = positive_integer(&args[0]).ok_or_else(|| {
+                    invalid("argument of ntile must be a positive integer".to_string())
+                })? as usize;
+                // the first n % buckets buckets have one row more
+                let size = n / buckets;
+                let large = n % buckets;
+                let bucket = if i < large * (size + 1) {
+                    i / (size + 1)
+                } else {
+                    large + (i - large * (size + 1)) / size
+                };
+                SqlValue::Integer(bucket as i64 + 1)
+            }
+            Kind::Lag | Kind::Lead => {
+                let offset = match args.get(1) {
+                    Some(offset) => match offset.clone().apply_affinity(Affinity::Integer) {
+                        SqlValue::Integer(offset) => Some(offset),
+                        _ => None,
+                    },
+                    None => Some(1),
+                };
+                let offset = if kind == Kind::Lag {
+                    offset.and_then(|o| o.checked_neg())
+                } else {
+                    offset
+                };
+                let row = offset
+                    .and_then(|o| (i as i64).checked_add(o))
+                    .filter(|j| (0..n as i64).contains(j));
+                match row {
+                    Some(j) => rows[j as usize].args[0].clone(),
+                    None => args.get(2).cloned().unwrap_or(SqlValue::Null),
+                }
+            }
+            Kind::FirstValue => frame(i)
+                .next()
+                .map_or(SqlValue::Null, |j| rows[j].args[0].clone()),
+            Kind::LastValue => frame(i)
+                .next_back()
+                .map_or(SqlValue::Null, |j| rows[j].args[0].clone()),
+            Kind::NthValue => {
+                let nth = positive_integer(&args[1]).ok_or_else(|| {
+                    invalid("second argument to nth_value must be a positive integer".to_string())
+                })?;
+                frame(i)
+                    .nth(nth as usize - 1)
+                    .map_or(SqlValue::Null, |j| rows[j].args[0].clone())
+            }
+        });
+    }
+    Ok(values)
+}
+
+// An aggregate over frames that move on through the partition. Frames
+// that start at the start of the partition only grow, and a single
+// accumulator takes in their rows as they do. Other frames also leave rows
+// behind, which the accumulator takes out again.
+fn sliding_aggregate(
+    mut accumulator: Accumulator,
+    rows: &[WindowRow],
+    bounds: impl Fn(usize) -> (usize, usize),
+    values: &mut Vec<SqlValue>,
+) -> Result<(), Error> {
+    // the rows from `first` to `last` are in the accumulator
+    let (mut first, mut last) = (0, 0);
+    for i in 0..rows.len() {
+        let (start, end) = bounds(i);
+        let end = end.max(last);
+        for row in &rows[last..end] {
+            accumulator.step(&row.args);
+        }
+        last = end;
+        for row in &rows[first..start.max(first)] {
+            accumulator.inverse(&row.args);
+        }
+        first = start.max(first);
+        values.push(accumulator.finish()?);
+    }
+    Ok(())
+}
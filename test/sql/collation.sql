@@ -0,0 +1,28 @@
+-- Columns and indexes with collations. `person` has enough rows for its
+-- indexes to need interior pages, so a seek comparing with the wrong
+-- collation ends up on the wrong page. `city` uses `reverse`, which orders
+-- text backwards and is not built in: load this file through a connection
+-- that registers it, e.g. Python's sqlite3 with create_collation.
+CREATE TABLE person (
+    id INTEGER PRIMARY KEY,
+    name TEXT COLLATE NOCASE,
+    email TEXT,
+    code TEXT COLLATE RTRIM
+);
+CREATE INDEX person_name ON person (name);
+CREATE INDEX person_email ON person (email COLLATE NOCASE);
+CREATE INDEX person_code ON person (code);
+
+WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 3000)
+INSERT INTO person SELECT i,
+    CASE i % 3 WHEN 0 THEN 'user' WHEN 1 THEN 'User' ELSE 'USER' END || printf('%04d', i / 3),
+    CASE i % 2 WHEN 0 THEN 'mail' ELSE 'MAIL' END || printf('%04d@example.com', i / 2),
+    printf('c%04d', i / 2) || CASE i % 2 WHEN 0 THEN '' ELSE '  ' END
+    FROM n;
+
+CREATE TABLE tag (name TEXT COLLATE NOCASE PRIMARY KEY, uses INTEGER) WITHOUT ROWID;
+INSERT INTO tag VALUES ('Rust', 3), ('sql', 5), ('BTree', 1);
+
+CREATE TABLE city (name TEXT COLLATE reverse, country TEXT);
+CREATE INDEX city_name ON city (name);
+INSERT INTO city VALUES ('Paris', 'FR'), ('Berlin', 'DE'), ('Tokyo', 'JP'), ('Lyon', 'FR');
@@ -0,0 +1,29 @@
+-- Lookup tables stored as index b-trees. `word` has enough rows to need
+-- interior pages, and one of the `note` rows spills onto overflow pages.
+CREATE TABLE country (
+    code TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    population INTEGER
+) WITHOUT ROWID;
+
+INSERT INTO country VALUES ('FR', 'France', 68000000);
+INSERT INTO country VALUES ('DE', 'Germany', 84000000);
+INSERT INTO country VALUES ('JP', 'Japan', 125000000);
+INSERT INTO country VALUES ('BR', 'Brazil', NULL);
+
+CREATE TABLE word (
+    lang TEXT,
+    pos INTEGER,
+    word TEXT,
+    PRIMARY KEY (word, lang)
+) WITHOUT ROWID;
+
+WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 3000)
+INSERT INTO word SELECT CASE i % 3 WHEN 0 THEN 'en' WHEN 1 THEN 'fr' ELSE 'de' END,
+    i, printf('w%05d', i) FROM n;
+
+CREATE TABLE note (id INTEGER PRIMARY KEY, body TEXT);
+INSERT INTO note VALUES (1, 'short');
+INSERT INTO note VALUES (2, (WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000)
+    SELECT group_concat(printf('%04d', i), '') FROM n));
+INSERT INTO note VALUES (-3, 'negative');